@@ -0,0 +1,47 @@
+use log::info;
+use scx_stats::prelude::*;
+use std::env::args;
+use std::io::Read;
+
+fn main() {
+    simple_logger::SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .env()
+        .init()
+        .unwrap();
+
+    std::assert!(
+        args().len() >= 3,
+        "Usage: proxy UNIX_SOCKET_PATH NAME=SOURCE_SOCKET_PATH..."
+    );
+    let path = args().nth(1).unwrap();
+
+    let mut proxy = StatsProxy::new();
+    for src in args().skip(2) {
+        let (name, src_path) = src
+            .split_once('=')
+            .expect("sources must be in NAME=SOURCE_SOCKET_PATH form");
+        proxy = proxy.add_source(name, src_path);
+    }
+
+    let sdata = proxy.server_data().unwrap();
+    info!("stats_meta:");
+    sdata.describe_meta(&mut std::io::stderr(), None).unwrap();
+
+    // The exporters can be enabled with the environment variables, e.g.
+    // SCX_STATS_PROMETHEUS_ADDR, to export the merged stats.
+    let server = StatsServer::new(sdata)
+        .set_path(&path)
+        .set_sched_name("scx_stats_proxy")
+        .launch()
+        .unwrap();
+
+    info!("Proxy listening. Run `client {:?}`.", &path);
+    if let Some(addr) = server.prometheus_addr() {
+        info!("Prometheus metrics available at http://{}/metrics", addr);
+    }
+    info!("Press any key to exit.");
+
+    let mut buf: [u8; 1] = [0];
+    let _ = std::io::stdin().read(&mut buf);
+}
@@ -0,0 +1,86 @@
+use log::info;
+use scx_stats::prelude::*;
+use std::env::args;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn main() {
+    simple_logger::SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .env()
+        .init()
+        .unwrap();
+
+    std::assert!(
+        (3..=5).contains(&args().len()),
+        "Usage: record UNIX_SOCKET_PATH OUTPUT_CSV [INTERVAL_MS [FIELDS]]"
+    );
+    let path = args().nth(1).unwrap();
+    let output = args().nth(2).unwrap();
+    let intv_ms = args().nth(3).unwrap_or("1000".into());
+    let fields = args().nth(4);
+
+    // Only CSV is supported. Reject other formats instead of writing CSV
+    // into a file which claims to be something else.
+    let out_path = std::path::PathBuf::from(&output);
+    if let Some(ext) = out_path.extension().filter(|ext| *ext != "csv") {
+        panic!(
+            "Unsupported output format {:?}, only CSV is supported, convert with e.g. \
+             polars.read_csv(\"stats.csv\").write_parquet(\"stats.parquet\")",
+            ext
+        );
+    }
+
+    let mut client = StatsClient::new().set_path(&path).connect().unwrap();
+    let hello = client.hello().unwrap();
+    info!("Recording from {:?} (protocol v{})", &path, hello.version);
+
+    let mut req_args = vec![];
+    if let Some(fields) = fields {
+        req_args.push(("fields".into(), fields));
+    }
+
+    // New fields start a new file, e.g. stats.1.csv after stats.csv.
+    type Out = std::io::BufWriter<Box<dyn std::io::Write>>;
+    let mut writer: StatsCsvWriter<Out> = match output.as_str() {
+        "-" => StatsCsvWriter::new(std::io::BufWriter::new(Box::new(std::io::stdout()))),
+        _ => StatsCsvWriter::segmented(move |idx| {
+            let seg_path = match idx {
+                0 => out_path.clone(),
+                idx => out_path.with_extension(format!("{}.csv", idx)),
+            };
+            info!("Writing to {:?}", &seg_path);
+            let file = std::fs::File::create(&seg_path)?;
+            Ok(std::io::BufWriter::new(
+                Box::new(file) as Box<dyn std::io::Write>
+            ))
+        })
+        .unwrap(),
+    };
+    let now = || {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64()
+    };
+
+    // Let the server pace the updates if supported, poll otherwise.
+    let res: anyhow::Result<()> = if hello.has_cap("stats_subscribe") {
+        req_args.push(("interval_ms".into(), intv_ms));
+        client
+            .subscribe::<serde_json::Value>(req_args)
+            .and_then(|mut stats| loop {
+                writer.write(now(), &stats)?;
+                stats = client.next_update()?;
+            })
+    } else {
+        let intv = Duration::from_millis(intv_ms.parse().unwrap());
+        loop {
+            match client.request::<serde_json::Value>("stats", req_args.clone()) {
+                Ok(stats) => writer.write(now(), &stats).unwrap(),
+                Err(e) => break Err(e),
+            }
+            std::thread::sleep(intv);
+        }
+    };
+    info!("Recording stopped ({})", res.unwrap_err());
+}
@@ -41,7 +41,10 @@ fn main() {
         ]),
     };
 
-    std::assert_eq!(args().len(), 2, "Usage: server UNIX_SOCKET_PATH");
+    std::assert!(
+        args().len() == 2 || args().len() == 3,
+        "Usage: server UNIX_SOCKET_PATH [PROMETHEUS_ADDR]"
+    );
     let path = args().nth(1).unwrap();
 
     // If communication from the stats generating closure is not necessary,
@@ -65,10 +68,11 @@ fn main() {
     info!("stats_meta:");
     sdata.describe_meta(&mut std::io::stderr(), None).unwrap();
 
-    let server = StatsServer::<ThreadId, String>::new(sdata)
-        .set_path(&path)
-        .launch()
-        .unwrap();
+    let mut server = StatsServer::<ThreadId, String>::new(sdata).set_path(&path);
+    if let Some(addr) = args().nth(2) {
+        server = server.set_prometheus_addr(&addr);
+    }
+    let server = server.launch().unwrap();
 
     debug!("Doing unnecessary server channel handling");
     let (tx, rx) = server.channels();
@@ -83,6 +87,9 @@ fn main() {
 
     info!("Server listening. Run `client {:?}`.", &path);
     info!("Use `socat - UNIX-CONNECT:{:?}` for raw connection.", &path);
+    if let Some(addr) = server.prometheus_addr() {
+        info!("Prometheus metrics available at http://{}/metrics", addr);
+    }
     info!("Press any key to exit.");
 
     let mut buf: [u8; 1] = [0];
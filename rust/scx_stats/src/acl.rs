@@ -0,0 +1,143 @@
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+/// Credentials of the process on the other end of a stats connection.
+#[derive(Clone, Debug)]
+pub(crate) struct PeerCred {
+    pub(crate) pid: i32,
+    pub(crate) uid: u32,
+    pub(crate) gids: BTreeSet<u32>,
+}
+
+impl PeerCred {
+    /// Read the peer credentials with SO_PEERCRED. SO_PEERCRED only reports
+    /// the primary group, so the supplementary groups are read from
+    /// /proc/PID/status. If the peer is already gone, only the primary group
+    /// is used.
+    pub(crate) fn new(stream: &UnixStream) -> Result<Self> {
+        let mut ucred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+        // SAFETY: @ucred and @len are valid and sized for SO_PEERCRED.
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut ucred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            bail!("SO_PEERCRED failed ({})", std::io::Error::last_os_error());
+        }
+
+        let mut gids = BTreeSet::from([ucred.gid]);
+        if let Ok(status) = std::fs::read_to_string(format!("/proc/{}/status", ucred.pid)) {
+            if let Some(line) = status.lines().find(|l| l.starts_with("Groups:")) {
+                gids.extend(
+                    line["Groups:".len()..]
+                        .split_whitespace()
+                        .filter_map(|v| v.parse::<u32>().ok()),
+                );
+            }
+        }
+
+        Ok(Self {
+            pid: ucred.pid,
+            uid: ucred.uid,
+            gids,
+        })
+    }
+}
+
+/// Allowlist of UIDs and GIDs. Everyone is allowed until restricted.
+/// Remote peers, i.e. TCP and vsock, are denied unless explicitly allowed.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct StatsAcl {
+    restricted: bool,
+    uids: BTreeSet<u32>,
+    gids: BTreeSet<u32>,
+    remote: bool,
+}
+
+impl StatsAcl {
+    pub(crate) fn allow_uids(&mut self, uids: &[u32]) {
+        self.restricted = true;
+        self.uids.extend(uids);
+    }
+
+    pub(crate) fn allow_gids(&mut self, gids: &[u32]) {
+        self.restricted = true;
+        self.gids.extend(gids);
+    }
+
+    pub(crate) fn allow_remote(&mut self) {
+        self.remote = true;
+    }
+
+    pub(crate) fn allows_remote(&self) -> bool {
+        self.remote
+    }
+
+    /// The server's own UID is always allowed so that the in-process
+    /// exporters keep working. Peers connected over TCP or vsock don't have
+    /// credentials and are only allowed if remote access was explicitly
+    /// allowed, regardless of the UID and GID allowlists.
+    pub(crate) fn allows(&self, cred: Option<&PeerCred>) -> bool {
+        let cred = match cred {
+            Some(v) => v,
+            None => return self.remote,
+        };
+        if !self.restricted {
+            return true;
+        }
+        // SAFETY: geteuid() can't fail.
+        cred.uid == unsafe { libc::geteuid() }
+            || self.uids.contains(&cred.uid)
+            || !self.gids.is_disjoint(&cred.gids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerCred, StatsAcl};
+    use std::collections::BTreeSet;
+
+    fn cred(uid: u32, gids: &[u32]) -> PeerCred {
+        PeerCred {
+            pid: 1,
+            uid,
+            gids: BTreeSet::from_iter(gids.iter().copied()),
+        }
+    }
+
+    #[test]
+    fn test_acl() {
+        // SAFETY: geteuid() can't fail.
+        let own = unsafe { libc::geteuid() };
+        let other = own.wrapping_add(1);
+
+        let mut acl = StatsAcl::default();
+        assert!(acl.allows(Some(&cred(other, &[]))));
+        assert!(!acl.allows(None));
+
+        acl.allow_gids(&[100]);
+        assert!(acl.allows(Some(&cred(own, &[]))));
+        assert!(acl.allows(Some(&cred(other, &[1, 100]))));
+        assert!(!acl.allows(Some(&cred(other, &[1]))));
+        assert!(!acl.allows(None));
+
+        acl.allow_uids(&[other]);
+        assert!(acl.allows(Some(&cred(other, &[1]))));
+
+        acl.allow_remote();
+        assert!(acl.allows(None));
+    }
+}
@@ -1,9 +1,10 @@
-use crate::{StatsErrno, StatsRequest, StatsResponse};
+use crate::transport::StatsStream;
+use crate::{StatsAddr, StatsEncoding};
+use crate::{StatsErrno, StatsHello, StatsRequest, StatsResponse, STATS_PROTO_VERSION};
 use anyhow::{anyhow, bail, Result};
 use log::trace;
 use serde::Deserialize;
-use std::io::{BufRead, BufReader, Write};
-use std::os::unix::net::UnixStream;
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
 
 pub struct StatsClient {
@@ -11,9 +12,12 @@ pub struct StatsClient {
     sched_path: PathBuf,
     stats_path: PathBuf,
     path: Option<PathBuf>,
+    addr: Option<StatsAddr>,
+    want_enc: StatsEncoding,
 
-    stream: Option<UnixStream>,
-    reader: Option<BufReader<UnixStream>>,
+    stream: Option<StatsStream>,
+    reader: Option<BufReader<StatsStream>>,
+    enc: StatsEncoding,
 }
 
 impl StatsClient {
@@ -23,9 +27,12 @@ impl StatsClient {
             sched_path: PathBuf::from("root"),
             stats_path: PathBuf::from("stats"),
             path: None,
+            addr: None,
+            want_enc: StatsEncoding::Json,
 
             stream: None,
             reader: None,
+            enc: StatsEncoding::Json,
         }
     }
 
@@ -49,15 +56,44 @@ impl StatsClient {
         self
     }
 
+    /// Connect to `addr`, e.g. a TCP or vsock address of a server in a
+    /// container or VM, instead of the UNIX domain socket.
+    pub fn set_addr(mut self, addr: StatsAddr) -> Self {
+        self.addr = Some(addr);
+        self
+    }
+
+    /// Use `enc` once connected if the server supports it. Binary
+    /// encodings are negotiated with "hello" by `connect()`. Servers
+    /// without the support stay in JSON, which can be checked with
+    /// `encoding()`.
+    pub fn set_encoding(mut self, enc: StatsEncoding) -> Self {
+        self.want_enc = enc;
+        self
+    }
+
+    /// The encoding currently in use.
+    pub fn encoding(&self) -> StatsEncoding {
+        self.enc
+    }
+
     pub fn connect(mut self) -> Result<Self> {
         if self.path.is_none() {
             self.path = Some(self.base_path.join(&self.sched_path).join(&self.stats_path));
         }
-        let path = &self.path.as_ref().unwrap();
+        let addr = match &self.addr {
+            Some(v) => v.clone(),
+            None => StatsAddr::Unix(self.path.clone().unwrap()),
+        };
 
-        let stream = UnixStream::connect(path)?;
+        let stream = StatsStream::connect(&addr)?;
         self.stream = Some(stream.try_clone()?);
         self.reader = Some(BufReader::new(stream));
+        self.enc = StatsEncoding::Json;
+
+        if self.want_enc != StatsEncoding::Json {
+            self.hello()?;
+        }
         Ok(self)
     }
 
@@ -69,14 +105,22 @@ impl StatsClient {
             bail!("not connected");
         }
 
-        let req = serde_json::to_string(&req)? + "\n";
-        trace!("Sending: {}", req.trim());
-        self.stream.as_ref().unwrap().write_all(req.as_bytes())?;
+        let req = serde_json::to_value(req)?;
+        trace!("Sending: {}", &req);
+        self.enc.write(self.stream.as_mut().unwrap(), &req)?;
+
+        self.receive()
+    }
 
-        let mut line = String::new();
-        self.reader.as_mut().unwrap().read_line(&mut line)?;
-        trace!("Received: {}", line.trim());
-        let mut resp: StatsResponse = serde_json::from_str(&line)?;
+    fn receive<T>(&mut self) -> Result<T>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        let mut resp: StatsResponse = match self.enc.read(self.reader.as_mut().unwrap())? {
+            Some(v) => v?,
+            None => bail!("connection closed"),
+        };
+        trace!("Received: {}", serde_json::to_string(&resp)?);
 
         let (errno, resp) = (
             resp.errno,
@@ -96,4 +140,53 @@ impl StatsClient {
     {
         self.send_request(&StatsRequest::new(req, args))
     }
+
+    /// Exchange the protocol versions and capabilities with the server.
+    /// Servers which predate the handshake fail the request with EINVAL, in
+    /// which case `StatsHello::legacy()` is returned. The client should
+    /// stick to the requests and features listed in the capabilities and
+    /// can compare the schema hash against a cached one to tell whether the
+    /// stats definitions changed. The encoding set with `set_encoding()` is
+    /// negotiated too.
+    pub fn hello(&mut self) -> Result<StatsHello> {
+        let mut args = vec![("version".into(), STATS_PROTO_VERSION.to_string())];
+        if self.want_enc != self.enc {
+            args.push(("encoding".into(), self.want_enc.name().into()));
+        }
+        match self.request::<StatsHello>("hello", args) {
+            Ok(v) => {
+                if let Some(enc) = v.encoding.as_ref().and_then(|v| StatsEncoding::parse(v)) {
+                    self.enc = enc;
+                }
+                Ok(v)
+            }
+            Err(e) => match e.downcast_ref::<StatsErrno>() {
+                Some(errno) if errno.0 == libc::EINVAL => Ok(StatsHello::legacy()),
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Subscribe to the stats with the "stats_subscribe" request and return
+    /// the first response. The server keeps sending the stats every
+    /// "interval_ms" argument milliseconds, which can be received with
+    /// `next_update()`. The subscription lasts until the connection is
+    /// closed.
+    pub fn subscribe<T>(&mut self, args: Vec<(String, String)>) -> Result<T>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        self.send_request(&StatsRequest::new("stats_subscribe", args))
+    }
+
+    /// Wait for and return the next update of the subscription.
+    pub fn next_update<T>(&mut self) -> Result<T>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        if self.reader.is_none() {
+            bail!("not connected");
+        }
+        self.receive()
+    }
 }
@@ -0,0 +1,175 @@
+use crate::{StatsData, StatsErrno, StatsKind, StatsMeta};
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// How the counter fields, the numeric fields with `_om_type = "counter"`,
+/// are reported when the "counters" request argument is set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsCounterMode {
+    /// The increment since the previous read.
+    Delta,
+    /// The increment since the previous read divided by the elapsed
+    /// seconds.
+    Rate,
+}
+
+impl StatsCounterMode {
+    pub fn parse(mode: &str) -> Result<Self> {
+        match mode {
+            "delta" => Ok(Self::Delta),
+            "rate" => Ok(Self::Rate),
+            v => Err(anyhow!("invalid counters mode {:?}", v).context(StatsErrno(libc::EINVAL))),
+        }
+    }
+
+    /// Parse the "counters" argument of a request. Returns None if the
+    /// argument is not specified.
+    pub fn from_args(args: &BTreeMap<String, String>) -> Result<Option<Self>> {
+        args.get("counters").map(|v| Self::parse(v)).transpose()
+    }
+}
+
+/// Converts the counter fields of successive stats snapshots into deltas or
+/// rates. The server keeps one per connection for requests with the
+/// "counters" argument. Clients of servers without the "counters"
+/// capability can use it directly along with the metadata from
+/// "stats_meta".
+///
+/// A counter which went backwards is assumed to have been reset, e.g. by a
+/// scheduler restart, and restarted from zero, so that the delta is the
+/// current value. Counters which don't have a previous value, e.g. on the
+/// first read, are reported as zero.
+#[derive(Default)]
+pub struct StatsCounters {
+    last: Option<(Value, Instant)>,
+}
+
+impl StatsCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Convert the counter fields of `value`, the stats of the struct `top`
+    /// read at `at`, against the previous snapshot according to `mode`.
+    pub fn update(
+        &mut self,
+        meta: &BTreeMap<String, StatsMeta>,
+        top: &str,
+        mode: StatsCounterMode,
+        value: Value,
+        at: Instant,
+    ) -> Result<Value> {
+        let mut out = value.clone();
+        let (prev, secs) = match self.last.as_ref() {
+            Some((prev, last_at)) => (Some(prev), at.duration_since(*last_at).as_secs_f64()),
+            None => (None, 0.0),
+        };
+
+        Derive { meta, mode, secs }.strukt(top, &mut out, prev)?;
+
+        self.last = Some((value, at));
+        Ok(out)
+    }
+}
+
+struct Derive<'a> {
+    meta: &'a BTreeMap<String, StatsMeta>,
+    mode: StatsCounterMode,
+    secs: f64,
+}
+
+impl Derive<'_> {
+    fn strukt(&self, sname: &str, cur: &mut Value, prev: Option<&Value>) -> Result<()> {
+        let smeta = self
+            .meta
+            .get(sname)
+            .ok_or_else(|| anyhow!("unknown stats meta name {}", sname))?;
+        let obj = match cur.as_object_mut() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        for (fname, field) in smeta.fields.iter() {
+            let fcur = match obj.get_mut(fname) {
+                Some(v) => v,
+                None => continue,
+            };
+            let fprev = prev.and_then(|v| v.get(fname));
+            let is_counter = field
+                .attrs
+                .user
+                .get("_om_type")
+                .is_some_and(|v| v == "counter");
+
+            match &field.data {
+                StatsData::Datum(kind) => self.kind(kind, is_counter, fcur, fprev)?,
+                StatsData::Array(kind) => {
+                    if let Some(arr) = fcur.as_array_mut() {
+                        for (idx, v) in arr.iter_mut().enumerate() {
+                            self.kind(kind, is_counter, v, fprev.and_then(|p| p.get(idx)))?;
+                        }
+                    }
+                }
+                StatsData::Dict { key: _, datum } => {
+                    if let Some(dict) = fcur.as_object_mut() {
+                        for (key, v) in dict.iter_mut() {
+                            self.kind(datum, is_counter, v, fprev.and_then(|p| p.get(key)))?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn kind(
+        &self,
+        kind: &StatsKind,
+        is_counter: bool,
+        cur: &mut Value,
+        prev: Option<&Value>,
+    ) -> Result<()> {
+        match kind {
+            StatsKind::Struct(inner) => self.strukt(inner, cur, prev),
+            StatsKind::I64 | StatsKind::U64 | StatsKind::Float if is_counter => {
+                if let Some(v) = self.counter(cur, prev) {
+                    *cur = v;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn counter(&self, cur: &Value, prev: Option<&Value>) -> Option<Value> {
+        let prev = match prev {
+            Some(v) => v,
+            None => {
+                return match (self.mode, cur.is_f64()) {
+                    (StatsCounterMode::Delta, false) => Some(0.into()),
+                    _ => Some(0.0.into()),
+                }
+            }
+        };
+
+        // Stay in integers for integer counters to avoid losing precision.
+        let delta = match (cur.as_u64(), prev.as_u64(), cur.as_i64(), prev.as_i64()) {
+            (Some(c), Some(p), _, _) => Value::from(if c >= p { c - p } else { c }),
+            (_, _, Some(c), Some(p)) => Value::from(if c >= p { c.saturating_sub(p) } else { c }),
+            _ => {
+                let (c, p) = (cur.as_f64()?, prev.as_f64()?);
+                Value::from(if c >= p { c - p } else { c })
+            }
+        };
+
+        match self.mode {
+            StatsCounterMode::Delta => Some(delta),
+            StatsCounterMode::Rate if self.secs > 0.0 => {
+                Some(Value::from(delta.as_f64()? / self.secs))
+            }
+            StatsCounterMode::Rate => Some(0.0.into()),
+        }
+    }
+}
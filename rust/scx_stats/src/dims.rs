@@ -0,0 +1,163 @@
+use crate::{StatsData, StatsErrno, StatsKind, StatsMeta};
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Delta encodes the dimension fields, the dicts of structs with the `dim`
+/// attribute, of successive stats snapshots. Dimensions such as per-cgroup
+/// stats can have a lot of members most of which don't change between
+/// reads, e.g. idle cgroups. When the "dims" request argument is "delta",
+/// the server keeps one per connection and only sends what changed since
+/// the previous response on the connection:
+///
+/// - Unchanged members are left out.
+///
+/// - Changed members only carry the changed fields. Nested objects are
+///   encoded the same way while other values are sent whole.
+///
+/// - Removed members and fields are set to null.
+///
+/// This is the JSON merge patch (RFC 7386) of the previous member. The
+/// other fields are always sent whole. The first response on a connection
+/// is a full snapshot. Clients reconstruct the full stats by feeding every
+/// response in order to `decode()` with the metadata from "stats_meta".
+/// Note that null values inside the members can't be told apart from
+/// removals and are dropped.
+#[derive(Default)]
+pub struct StatsDims {
+    last: Option<Value>,
+}
+
+impl StatsDims {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the "dims" argument of a request asks for delta encoding.
+    pub fn delta_from_args(args: &BTreeMap<String, String>) -> Result<bool> {
+        match args.get("dims").map(|v| v.as_str()) {
+            None | Some("full") => Ok(false),
+            Some("delta") => Ok(true),
+            Some(v) => Err(anyhow!("invalid dims mode {:?}", v).context(StatsErrno(libc::EINVAL))),
+        }
+    }
+
+    /// Delta encode `value`, the stats of the struct `top`, against the
+    /// previous value.
+    pub fn encode(
+        &mut self,
+        meta: &BTreeMap<String, StatsMeta>,
+        top: &str,
+        value: Value,
+    ) -> Result<Value> {
+        let mut out = value.clone();
+        if let Some(prev) = self.last.as_ref() {
+            Walk { meta, encode: true }.strukt(top, &mut out, prev)?;
+        }
+        self.last = Some(value);
+        Ok(out)
+    }
+
+    /// Apply `delta`, a response encoded by `encode()`, to the previous
+    /// value and return the full stats of the struct `top`.
+    pub fn decode(
+        &mut self,
+        meta: &BTreeMap<String, StatsMeta>,
+        top: &str,
+        delta: Value,
+    ) -> Result<Value> {
+        let mut out = delta;
+        if let Some(prev) = self.last.as_ref() {
+            Walk {
+                meta,
+                encode: false,
+            }
+            .strukt(top, &mut out, prev)?;
+        }
+        self.last = Some(out.clone());
+        Ok(out)
+    }
+}
+
+struct Walk<'a> {
+    meta: &'a BTreeMap<String, StatsMeta>,
+    encode: bool,
+}
+
+impl Walk<'_> {
+    fn strukt(&self, sname: &str, cur: &mut Value, prev: &Value) -> Result<()> {
+        let smeta = self
+            .meta
+            .get(sname)
+            .ok_or_else(|| anyhow!("unknown stats meta name {}", sname))?;
+        let obj = match cur.as_object_mut() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        for (fname, field) in smeta.fields.iter() {
+            let (fcur, fprev) = match (obj.get_mut(fname), prev.get(fname)) {
+                (Some(c), Some(p)) => (c, p),
+                _ => continue,
+            };
+
+            match &field.data {
+                StatsData::Datum(StatsKind::Struct(inner)) => self.strukt(inner, fcur, fprev)?,
+                StatsData::Dict { .. } if field.attrs.dim.is_some() => {
+                    *fcur = match self.encode {
+                        true => diff(fprev, fcur).unwrap_or_else(|| Value::Object(Map::new())),
+                        false => patch(fprev, fcur),
+                    };
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The merge patch which turns `prev` into `cur`, None if they're equal.
+fn diff(prev: &Value, cur: &Value) -> Option<Value> {
+    match (prev.as_object(), cur.as_object()) {
+        (Some(pobj), Some(cobj)) => {
+            let mut out = Map::new();
+            for (key, cval) in cobj.iter() {
+                match pobj.get(key) {
+                    Some(pval) => {
+                        if let Some(v) = diff(pval, cval) {
+                            out.insert(key.clone(), v);
+                        }
+                    }
+                    None => {
+                        out.insert(key.clone(), cval.clone());
+                    }
+                }
+            }
+            for key in pobj.keys().filter(|k| !cobj.contains_key(*k)) {
+                out.insert(key.clone(), Value::Null);
+            }
+            (!out.is_empty()).then_some(Value::Object(out))
+        }
+        _ if prev == cur => None,
+        _ => Some(cur.clone()),
+    }
+}
+
+/// Apply the merge patch `delta` to `prev`.
+fn patch(prev: &Value, delta: &Value) -> Value {
+    match delta.as_object() {
+        Some(dobj) => {
+            let mut out = prev.as_object().cloned().unwrap_or_default();
+            for (key, dval) in dobj.iter() {
+                if dval.is_null() {
+                    out.remove(key);
+                } else {
+                    let v = patch(out.get(key).unwrap_or(&Value::Null), dval);
+                    out.insert(key.clone(), v);
+                }
+            }
+            Value::Object(out)
+        }
+        None => delta.clone(),
+    }
+}
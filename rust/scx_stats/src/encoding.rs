@@ -0,0 +1,436 @@
+use anyhow::{anyhow, bail, Result};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Number, Value};
+use std::io::{BufRead, Read, Write};
+
+// Messages nest much less deeply. Bounds the recursion on bogus input.
+const CBOR_MAX_DEPTH: usize = 128;
+// Strings up to this length are read directly. Longer ones are read
+// incrementally so that a bogus length can't trigger a huge allocation.
+const CBOR_DIRECT_READ_MAX: u64 = 1 << 20;
+
+/// Wire encoding of the requests and responses. Connections start in JSON
+/// lines and can switch to a binary encoding with the "encoding" argument
+/// of the "hello" request. See `StatsClient::set_encoding()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StatsEncoding {
+    /// One JSON object per line.
+    #[default]
+    Json,
+    /// Concatenated CBOR (RFC 8949) items, which are more compact and
+    /// cheaper to encode and decode than JSON, especially with a lot of
+    /// numbers, e.g. per-CPU stats streamed at a high frequency.
+    Cbor,
+}
+
+impl StatsEncoding {
+    pub fn parse(encoding: &str) -> Option<Self> {
+        match encoding {
+            "json" => Some(Self::Json),
+            "cbor" => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Cbor => "cbor",
+        }
+    }
+
+    pub(crate) fn write<W: Write>(&self, w: &mut W, msg: &Value) -> Result<()> {
+        match self {
+            Self::Json => {
+                let line = serde_json::to_string(msg)? + "\n";
+                w.write_all(line.as_bytes())?;
+            }
+            Self::Cbor => {
+                let mut buf = vec![];
+                cbor_encode(msg, &mut buf);
+                w.write_all(&buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the next message. Returns None if the peer closed the
+    /// connection. The outer error is fatal to the connection while the
+    /// inner one only fails the message, e.g. a malformed JSON line. As
+    /// CBOR items can't be resynchronized, all CBOR errors are fatal.
+    pub(crate) fn read<T: DeserializeOwned, R: BufRead>(
+        &self,
+        r: &mut R,
+    ) -> Result<Option<Result<T>>> {
+        match self {
+            Self::Json => {
+                let mut line = String::new();
+                if r.read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                Ok(Some(serde_json::from_str(&line).map_err(|e| e.into())))
+            }
+            Self::Cbor => {
+                if r.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let val = cbor_decode(r, 0)?;
+                Ok(Some(serde_json::from_value(val).map_err(|e| e.into())))
+            }
+        }
+    }
+}
+
+fn cbor_head(major: u8, arg: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    match arg {
+        0..=23 => out.push(major | arg as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, arg as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(arg as u16).to_be_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(arg as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&arg.to_be_bytes());
+        }
+    }
+}
+
+/// Encode `val` with definite lengths and integers in the shortest form.
+/// Floats are always encoded in double precision.
+fn cbor_encode(val: &Value, out: &mut Vec<u8>) {
+    match val {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Number(num) => {
+            if let Some(v) = num.as_u64() {
+                cbor_head(0, v, out);
+            } else if let Some(v) = num.as_i64() {
+                cbor_head(1, !(v as u64), out);
+            } else {
+                out.push(0xfb);
+                out.extend_from_slice(&num.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            cbor_head(3, s.len() as u64, out);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(arr) => {
+            cbor_head(4, arr.len() as u64, out);
+            for v in arr.iter() {
+                cbor_encode(v, out);
+            }
+        }
+        Value::Object(obj) => {
+            cbor_head(5, obj.len() as u64, out);
+            for (k, v) in obj.iter() {
+                cbor_head(3, k.len() as u64, out);
+                out.extend_from_slice(k.as_bytes());
+                cbor_encode(v, out);
+            }
+        }
+    }
+}
+
+fn cbor_read<const N: usize, R: Read>(r: &mut R) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn cbor_read_bytes<R: Read>(r: &mut R, len: u64) -> Result<Vec<u8>> {
+    if len <= CBOR_DIRECT_READ_MAX {
+        let mut buf = vec![0u8; len as usize];
+        r.read_exact(&mut buf)?;
+        return Ok(buf);
+    }
+    let mut buf = vec![];
+    if r.take(len).read_to_end(&mut buf)? as u64 != len {
+        bail!("CBOR: truncated string");
+    }
+    Ok(buf)
+}
+
+/// IEEE 754 half precision to f64.
+fn f16_to_f64(half: u16) -> f64 {
+    let exp = (half >> 10) & 0x1f;
+    let mant = (half & 0x3ff) as f64;
+    let val = match exp {
+        0 => mant * 2f64.powi(-24),
+        31 if mant == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + mant / 1024.0) * 2f64.powi(exp as i32 - 15),
+    };
+    match half & 0x8000 {
+        0 => val,
+        _ => -val,
+    }
+}
+
+/// Decode a CBOR item into a Value. Accepts what's needed to interoperate
+/// with other encoders, e.g. half and single precision floats and tags,
+/// but not indefinite lengths. Non-string map keys are converted to
+/// strings and non-finite floats to null.
+fn cbor_decode<R: Read>(r: &mut R, depth: usize) -> Result<Value> {
+    if depth > CBOR_MAX_DEPTH {
+        bail!("CBOR: nested too deep");
+    }
+
+    let [initial] = cbor_read::<1, _>(r)?;
+    let (major, info) = (initial >> 5, initial & 0x1f);
+
+    if major == 7 {
+        let float = match info {
+            20 => return Ok(Value::Bool(false)),
+            21 => return Ok(Value::Bool(true)),
+            22 | 23 => return Ok(Value::Null),
+            25 => f16_to_f64(u16::from_be_bytes(cbor_read(r)?)),
+            26 => f32::from_be_bytes(cbor_read(r)?) as f64,
+            27 => f64::from_be_bytes(cbor_read(r)?),
+            v => bail!("CBOR: unsupported simple value {}", v),
+        };
+        return Ok(Number::from_f64(float).map_or(Value::Null, Value::Number));
+    }
+
+    let arg = match info {
+        0..=23 => info as u64,
+        24 => u8::from_be_bytes(cbor_read(r)?) as u64,
+        25 => u16::from_be_bytes(cbor_read(r)?) as u64,
+        26 => u32::from_be_bytes(cbor_read(r)?) as u64,
+        27 => u64::from_be_bytes(cbor_read(r)?),
+        v => bail!("CBOR: unsupported additional info {}", v),
+    };
+
+    Ok(match major {
+        0 => Value::from(arg),
+        1 => match i64::try_from(arg) {
+            Ok(v) => Value::from(-1 - v),
+            Err(_) => Value::from(-1.0 - arg as f64),
+        },
+        2 => Value::Array(
+            cbor_read_bytes(r, arg)?
+                .into_iter()
+                .map(Value::from)
+                .collect(),
+        ),
+        3 => Value::String(
+            String::from_utf8(cbor_read_bytes(r, arg)?).map_err(|e| anyhow!("CBOR: {}", e))?,
+        ),
+        4 => {
+            let mut arr = Vec::with_capacity(arg.min(4096) as usize);
+            for _ in 0..arg {
+                arr.push(cbor_decode(r, depth + 1)?);
+            }
+            Value::Array(arr)
+        }
+        5 => {
+            let mut obj = Map::new();
+            for _ in 0..arg {
+                let key = match cbor_decode(r, depth + 1)? {
+                    Value::String(v) => v,
+                    v => v.to_string(),
+                };
+                obj.insert(key, cbor_decode(r, depth + 1)?);
+            }
+            Value::Object(obj)
+        }
+        // Tags are ignored.
+        _ => cbor_decode(r, depth + 1)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cbor_decode, cbor_encode, StatsEncoding, CBOR_MAX_DEPTH};
+    use serde_json::{json, Value};
+
+    fn round_trip(val: &Value) -> Value {
+        let mut buf = vec![];
+        cbor_encode(val, &mut buf);
+        let mut r = buf.as_slice();
+        let out = cbor_decode(&mut r, 0).unwrap();
+        assert!(r.is_empty(), "trailing bytes after {}", val);
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Value> {
+        cbor_decode(&mut &bytes[..], 0)
+    }
+
+    #[test]
+    fn test_cbor_ints() {
+        for v in [
+            0u64,
+            1,
+            23,
+            24,
+            255,
+            256,
+            65535,
+            65536,
+            u32::MAX as u64,
+            u64::MAX,
+        ] {
+            assert_eq!(round_trip(&json!(v)), json!(v));
+        }
+        for v in [
+            -1i64,
+            -24,
+            -25,
+            -256,
+            -257,
+            -65537,
+            i32::MIN as i64,
+            i64::MIN,
+        ] {
+            assert_eq!(round_trip(&json!(v)), json!(v));
+        }
+
+        // Shortest forms from RFC 8949 Appendix A.
+        let enc = |v: Value| {
+            let mut buf = vec![];
+            cbor_encode(&v, &mut buf);
+            buf
+        };
+        assert_eq!(enc(json!(23)), [0x17]);
+        assert_eq!(enc(json!(24)), [0x18, 0x18]);
+        assert_eq!(enc(json!(1000)), [0x19, 0x03, 0xe8]);
+        assert_eq!(enc(json!(-1)), [0x20]);
+        assert_eq!(enc(json!(-100)), [0x38, 0x63]);
+        assert_eq!(enc(json!(-1000)), [0x39, 0x03, 0xe7]);
+
+        // Negative integers below i64::MIN don't fit and become floats.
+        let v = decode(&[0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).unwrap();
+        assert_eq!(v.as_f64(), Some(-18446744073709551616.0));
+    }
+
+    #[test]
+    fn test_cbor_floats() {
+        for v in [
+            0.5,
+            -0.5,
+            1.0,
+            -0.0,
+            1.1,
+            1e300,
+            -4.1,
+            f64::MIN_POSITIVE,
+            f64::MAX,
+        ] {
+            let out = round_trip(&json!(v));
+            assert!(out.is_f64(), "{} decoded as {}", v, out);
+            assert_eq!(out.as_f64().unwrap().to_bits(), v.to_bits());
+        }
+
+        // Half and single precision from other encoders.
+        assert_eq!(decode(&[0xf9, 0x3c, 0x00]).unwrap(), json!(1.0));
+        assert_eq!(decode(&[0xf9, 0xc4, 0x00]).unwrap(), json!(-4.0));
+        assert_eq!(
+            decode(&[0xf9, 0x00, 0x01]).unwrap(),
+            json!(5.960464477539063e-8)
+        );
+        assert_eq!(
+            decode(&[0xfa, 0x47, 0xc3, 0x50, 0x00]).unwrap(),
+            json!(100000.0)
+        );
+        // Non-finite floats can't be represented in JSON.
+        assert_eq!(decode(&[0xf9, 0x7c, 0x00]).unwrap(), Value::Null);
+        assert_eq!(decode(&[0xf9, 0x7e, 0x00]).unwrap(), Value::Null);
+        assert_eq!(
+            decode(&[0xfb, 0xff, 0xf0, 0, 0, 0, 0, 0, 0]).unwrap(),
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn test_cbor_nested() {
+        let val = json!({
+            "req": "stats",
+            "args": {
+                "resp": {
+                    "at": 12345,
+                    "load": 0.75,
+                    "name": "dom \u{1F600} \"0\"",
+                    "doms_dict": {"0": {"events": -3, "cpus": [0, 1, 2]}, "1": {}},
+                    "empty": [],
+                    "flags": [true, false, null],
+                },
+            },
+        });
+        assert_eq!(round_trip(&val), val);
+
+        let long = json!({"s": "x".repeat(100000), "a": vec![7; 5000]});
+        assert_eq!(round_trip(&long), long);
+
+        // Non-string keys and tags from other encoders.
+        assert_eq!(decode(&[0xa1, 0x01, 0x02]).unwrap(), json!({"1": 2}));
+        assert_eq!(
+            decode(&[0xc1, 0x1a, 0x51, 0x4b, 0x67, 0xb0]).unwrap(),
+            json!(1363896240)
+        );
+        // Byte strings become arrays of numbers.
+        assert_eq!(decode(&[0x42, 0x01, 0x02]).unwrap(), json!([1, 2]));
+    }
+
+    #[test]
+    fn test_cbor_truncated() {
+        let val = json!({
+            "args": {"resp": {"at": 1u64 << 40, "load": -0.5, "name": "dom0", "cpus": [0, 300]}},
+        });
+        let mut buf = vec![];
+        cbor_encode(&val, &mut buf);
+        for len in 0..buf.len() {
+            assert!(decode(&buf[..len]).is_err(), "truncated to {} bytes", len);
+        }
+        assert_eq!(decode(&buf).unwrap(), val);
+
+        // Bogus lengths must not allocate or loop forever.
+        let huge_str = [0x7b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, b'a'];
+        assert!(decode(&huge_str).is_err());
+        let huge_arr = [0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+        assert!(decode(&huge_arr).is_err());
+    }
+
+    #[test]
+    fn test_cbor_invalid() {
+        // Too deeply nested arrays.
+        let nested = vec![0x81; CBOR_MAX_DEPTH + 2];
+        assert!(decode(&nested).is_err());
+        // Indefinite lengths, reserved additional info and simple values.
+        assert!(decode(&[0x9f, 0x01, 0xff]).is_err());
+        assert!(decode(&[0x1c]).is_err());
+        assert!(decode(&[0xf0]).is_err());
+        // Invalid UTF-8.
+        assert!(decode(&[0x62, 0xc3, 0x28]).is_err());
+    }
+
+    #[test]
+    fn test_encoding_read_write() {
+        for enc in [StatsEncoding::Json, StatsEncoding::Cbor] {
+            let msgs = [
+                json!({"req": "hello"}),
+                json!({"errno": -1, "args": {"x": 1.5}}),
+            ];
+            let mut buf = vec![];
+            for msg in msgs.iter() {
+                enc.write(&mut buf, msg).unwrap();
+            }
+
+            let mut r = std::io::BufReader::new(buf.as_slice());
+            for msg in msgs.iter() {
+                let val: Value = enc.read(&mut r).unwrap().unwrap().unwrap();
+                assert_eq!(&val, msg);
+            }
+            assert!(enc.read::<Value, _>(&mut r).unwrap().is_none());
+            assert_eq!(StatsEncoding::parse(enc.name()), Some(enc));
+        }
+        assert_eq!(StatsEncoding::parse("msgpack"), None);
+    }
+}
@@ -0,0 +1,213 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Selects a subset of the fields of stats. Built from a comma-separated
+/// list of dot-separated field paths, e.g. "at,doms_dict.*.events", where
+/// "*" matches all members of a dict or array. A path selects the whole
+/// subtree under it.
+///
+/// The server applies the filter given in the "fields" argument of "stats"
+/// and "stats_subscribe" requests to the stats returned by the reader
+/// before sending the response. This trims the response but the reader
+/// still computes and serializes all the stats.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatsFilter {
+    all: bool,
+    children: BTreeMap<String, StatsFilter>,
+}
+
+impl StatsFilter {
+    /// Build a filter from `spec`. An empty `spec` selects everything.
+    pub fn parse(spec: &str) -> Self {
+        let mut filter = Self::default();
+        let mut empty = true;
+
+        for path in spec.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            empty = false;
+            let mut node = &mut filter;
+            for key in path.split('.') {
+                node = node.children.entry(key.to_string()).or_default();
+            }
+            node.all = true;
+        }
+
+        filter.all = empty;
+        filter
+    }
+
+    /// Build a filter from the "fields" argument of a request. Returns None
+    /// if the argument is not specified.
+    pub fn from_args(args: &BTreeMap<String, String>) -> Option<Self> {
+        args.get("fields").map(|v| Self::parse(v))
+    }
+
+    fn matching<'a>(&'a self, key: &str) -> impl Iterator<Item = &'a StatsFilter> {
+        self.children
+            .get(key)
+            .into_iter()
+            .chain(self.children.get("*"))
+    }
+
+    /// Return a copy of `value` with only the selected fields.
+    pub fn apply(&self, value: &Value) -> Option<Value> {
+        if self.all {
+            return Some(value.clone());
+        }
+
+        match value {
+            Value::Object(obj) => {
+                let mut out = Map::new();
+                for (key, val) in obj.iter() {
+                    for child in self.matching(key) {
+                        if let Some(v) = child.apply(val) {
+                            merge(out.entry(key.clone()).or_insert(Value::Null), v);
+                        }
+                    }
+                }
+                Some(Value::Object(out))
+            }
+            Value::Array(arr) => {
+                let out = arr
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(idx, val)| {
+                        let mut out = None;
+                        for child in self.matching(&idx.to_string()) {
+                            if let Some(v) = child.apply(val) {
+                                merge(out.get_or_insert(Value::Null), v);
+                            }
+                        }
+                        out
+                    })
+                    .collect();
+                Some(Value::Array(out))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Merge `src` into `dst`. Used when a field is selected by multiple paths,
+/// e.g. both "doms_dict.*.events" and "doms_dict.0.pressure".
+fn merge(dst: &mut Value, src: Value) {
+    match (dst, src) {
+        (Value::Object(dst), Value::Object(src)) => {
+            for (key, val) in src.into_iter() {
+                merge(dst.entry(key).or_insert(Value::Null), val);
+            }
+        }
+        (dst, src) => {
+            if dst.is_null() || !src.is_null() {
+                *dst = src;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn stats() -> Value {
+        json!({
+            "at": 1.5,
+            "cpu_busy": 42.0,
+            "doms_dict": {
+                "0": {"events": 10, "pressure": 0.1, "load": {"avg": 1, "max": 2}},
+                "1": {"events": 20, "pressure": 0.2, "load": {"avg": 3, "max": 4}}
+            },
+            "llcs": [{"id": 0, "util": 5}, {"id": 1, "util": 6}]
+        })
+    }
+
+    fn apply(spec: &str) -> Value {
+        StatsFilter::parse(spec).apply(&stats()).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(StatsFilter::parse(""), StatsFilter::parse(" , "));
+        assert_eq!(apply(""), stats());
+        assert_eq!(
+            StatsFilter::parse("at, cpu_busy"),
+            StatsFilter::parse("cpu_busy,at")
+        );
+        assert_eq!(StatsFilter::from_args(&BTreeMap::new()), None);
+        let args = BTreeMap::from([("fields".to_string(), "at".to_string())]);
+        assert_eq!(
+            StatsFilter::from_args(&args),
+            Some(StatsFilter::parse("at"))
+        );
+    }
+
+    #[test]
+    fn test_nested() {
+        assert_eq!(apply("at"), json!({"at": 1.5}));
+        assert_eq!(
+            apply("doms_dict.0.load.avg"),
+            json!({"doms_dict": {"0": {"load": {"avg": 1}}}})
+        );
+        // A path selects the whole subtree.
+        assert_eq!(
+            apply("doms_dict.1.load"),
+            json!({"doms_dict": {"1": {"load": {"avg": 3, "max": 4}}}})
+        );
+        assert_eq!(apply("llcs.1.util"), json!({"llcs": [{"util": 6}]}));
+    }
+
+    #[test]
+    fn test_wildcard() {
+        assert_eq!(
+            apply("doms_dict.*.events"),
+            json!({"doms_dict": {"0": {"events": 10}, "1": {"events": 20}}})
+        );
+        assert_eq!(apply("llcs.*.id"), json!({"llcs": [{"id": 0}, {"id": 1}]}));
+        assert_eq!(
+            apply("*.0"),
+            json!({"doms_dict": {"0": stats()["doms_dict"]["0"]}, "llcs": [stats()["llcs"][0]]})
+        );
+    }
+
+    #[test]
+    fn test_no_match() {
+        assert_eq!(apply("nope"), json!({}));
+        assert_eq!(apply("doms_dict.7.events"), json!({"doms_dict": {}}));
+        assert_eq!(apply("llcs.9"), json!({"llcs": []}));
+        // Descending into a scalar selects nothing.
+        assert_eq!(apply("at.x"), json!({}));
+        assert_eq!(StatsFilter::parse("x").apply(&json!(1)), None);
+    }
+
+    #[test]
+    fn test_overlapping() {
+        assert_eq!(
+            apply("doms_dict.*.events,doms_dict.0.pressure"),
+            json!({
+                "doms_dict": {
+                    "0": {"events": 10, "pressure": 0.1},
+                    "1": {"events": 20}
+                }
+            })
+        );
+        // The wider path wins over the narrower one.
+        assert_eq!(
+            apply("doms_dict.0.load.avg,doms_dict.0"),
+            apply("doms_dict.0")
+        );
+        assert_eq!(
+            apply("doms_dict.*.load.max,doms_dict.1.load.avg"),
+            json!({
+                "doms_dict": {
+                    "0": {"load": {"max": 2}},
+                    "1": {"load": {"avg": 3, "max": 4}}
+                }
+            })
+        );
+        assert_eq!(
+            apply("llcs.*.id,llcs.1.util"),
+            json!({"llcs": [{"id": 0}, {"id": 1, "util": 6}]})
+        );
+        assert_eq!(apply("at,at,at"), apply("at"));
+    }
+}
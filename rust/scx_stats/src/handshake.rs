@@ -0,0 +1,110 @@
+use crate::{StatsData, StatsKind, StatsMeta};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Version of the stats protocol. Bumped when requests or responses change
+/// incompatibly or new requests are added. Servers which predate the
+/// handshake are reported as version 0.
+pub const STATS_PROTO_VERSION: u32 = 1;
+
+/// Response to the "hello" request.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StatsHello {
+    /// Protocol version of the server.
+    pub version: u32,
+    /// Requests and features supported by the server, e.g. "stats_query".
+    #[serde(default)]
+    pub caps: Vec<String>,
+    /// Schema hash of the top-level stats struct if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_hash: Option<String>,
+    /// Encoding the connection switched to after the response, if the
+    /// "encoding" argument was given and is supported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+impl StatsHello {
+    /// What a server which doesn't understand "hello" supports.
+    pub fn legacy() -> Self {
+        Self {
+            version: 0,
+            caps: vec!["stats".into(), "stats_meta".into()],
+            schema_hash: None,
+            encoding: None,
+        }
+    }
+
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.iter().any(|v| v == cap)
+    }
+}
+
+/// 64bit FNV-1a. The schema hashes must be stable across builds and Rust
+/// versions, which rules out std's DefaultHasher.
+fn fnv1a64(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn schema_hash_inner(
+    meta: &BTreeMap<String, StatsMeta>,
+    name: &str,
+    hashes: &mut BTreeMap<String, u64>,
+    nesting: &mut BTreeSet<String>,
+) -> Result<Option<u64>> {
+    if let Some(hash) = hashes.get(name) {
+        return Ok(Some(*hash));
+    }
+    // Unknown and looping structs are rejected by verify_meta() if
+    // reachable from the top-level struct. Skip them otherwise.
+    let m = match meta.get(name) {
+        Some(v) if nesting.insert(name.into()) => v,
+        _ => return Ok(None),
+    };
+
+    let mut stripped = m.clone();
+    stripped.schema_hash = None;
+    let mut hash = fnv1a64(
+        0xcbf29ce484222325,
+        serde_json::to_string(&stripped)?.as_bytes(),
+    );
+
+    // Fold in the nested structs so that the hash changes when any struct
+    // reachable from this one changes.
+    for field in m.fields.values() {
+        let inner = match &field.data {
+            StatsData::Datum(StatsKind::Struct(inner))
+            | StatsData::Array(StatsKind::Struct(inner))
+            | StatsData::Dict {
+                key: _,
+                datum: StatsKind::Struct(inner),
+            } => inner,
+            _ => continue,
+        };
+        if let Some(inner_hash) = schema_hash_inner(meta, inner, hashes, nesting)? {
+            hash = fnv1a64(hash, &inner_hash.to_le_bytes());
+        }
+    }
+
+    nesting.remove(name);
+    hashes.insert(name.into(), hash);
+    Ok(Some(hash))
+}
+
+/// Set the `schema_hash` of all structs in `meta`. The server does this on
+/// launch. Clients can do the same with the metadata of the stats structs
+/// they were built with and compare the hashes against the server's.
+pub fn set_schema_hashes(meta: &mut BTreeMap<String, StatsMeta>) -> Result<()> {
+    let mut hashes = BTreeMap::new();
+    let names: Vec<String> = meta.keys().cloned().collect();
+    for name in names.iter() {
+        schema_hash_inner(meta, name, &mut hashes, &mut BTreeSet::new())?;
+    }
+    for (name, m) in meta.iter_mut() {
+        m.schema_hash = hashes.get(name).map(|v| format!("{:016x}", v));
+    }
+    Ok(())
+}
@@ -0,0 +1,305 @@
+use serde::{Deserialize, Serialize};
+
+/// Log2-bucketed histogram, e.g. of latencies in nsecs. Bucket 0 counts
+/// zeros and bucket N counts the values in `[2^(N-1), 2^N)`. Trailing empty
+/// buckets are not stored, which keeps the wire encoding compact.
+///
+/// Formatting with `{}` prints the percentile summary on a single line and
+/// `{:#}` prints the buckets as ASCII bars.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsHistogram {
+    pub count: u64,
+    pub sum: u64,
+    pub min: u64,
+    pub max: u64,
+    #[serde(default)]
+    pub buckets: Vec<u64>,
+}
+
+impl StatsHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index of the bucket `val` belongs to.
+    pub fn bucket_of(val: u64) -> usize {
+        (u64::BITS - val.leading_zeros()) as usize
+    }
+
+    /// The largest value which belongs to the bucket at `idx`.
+    pub fn bucket_max(idx: usize) -> u64 {
+        match idx {
+            0 => 0,
+            idx if idx >= u64::BITS as usize => u64::MAX,
+            idx => (1 << idx) - 1,
+        }
+    }
+
+    /// The smallest value which belongs to the bucket at `idx`.
+    pub fn bucket_min(idx: usize) -> u64 {
+        match idx {
+            0 => 0,
+            idx => Self::bucket_max(idx - 1) + 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn record(&mut self, val: u64) {
+        self.record_n(val, 1)
+    }
+
+    /// Record `nr` occurrences of `val`.
+    pub fn record_n(&mut self, val: u64, nr: u64) {
+        if nr == 0 {
+            return;
+        }
+
+        let idx = Self::bucket_of(val);
+        if self.buckets.len() <= idx {
+            self.buckets.resize(idx + 1, 0);
+        }
+        self.buckets[idx] = self.buckets[idx].saturating_add(nr);
+
+        if self.count == 0 {
+            (self.min, self.max) = (val, val);
+        } else {
+            (self.min, self.max) = (self.min.min(val), self.max.max(val));
+        }
+        self.count = self.count.saturating_add(nr);
+        self.sum = self.sum.saturating_add(val.saturating_mul(nr));
+    }
+
+    /// Add all values recorded in `other`.
+    pub fn merge(&mut self, other: &Self) {
+        if other.count == 0 {
+            return;
+        }
+
+        if self.buckets.len() < other.buckets.len() {
+            self.buckets.resize(other.buckets.len(), 0);
+        }
+        for (dst, src) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *dst = dst.saturating_add(*src);
+        }
+
+        if self.count == 0 {
+            (self.min, self.max) = (other.min, other.max);
+        } else {
+            (self.min, self.max) = (self.min.min(other.min), self.max.max(other.max));
+        }
+        self.count = self.count.saturating_add(other.count);
+        self.sum = self.sum.saturating_add(other.sum);
+    }
+
+    pub fn mean(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            cnt => self.sum as f64 / cnt as f64,
+        }
+    }
+
+    /// Estimate the `pct`th percentile, `pct` being in `[0.0, 100.0]`. The
+    /// value is interpolated linearly inside the bucket which contains the
+    /// percentile and clamped to the recorded min and max, which are what
+    /// the 0th and the 100th percentiles are.
+    pub fn percentile(&self, pct: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        if pct <= 0.0 {
+            return self.min as f64;
+        }
+
+        let rank = ((pct / 100.0).clamp(0.0, 1.0) * self.count as f64)
+            .ceil()
+            .max(1.0);
+        let mut seen = 0u64;
+        for (idx, &cnt) in self.buckets.iter().enumerate() {
+            if cnt == 0 || (seen.saturating_add(cnt) as f64) < rank {
+                seen = seen.saturating_add(cnt);
+                continue;
+            }
+            let (lo, hi) = (Self::bucket_min(idx) as f64, Self::bucket_max(idx) as f64);
+            let frac = (rank - seen as f64) / cnt as f64;
+            return (lo + (hi - lo) * frac).clamp(self.min as f64, self.max as f64);
+        }
+        self.max as f64
+    }
+
+    pub fn percentiles(&self) -> StatsPercentiles {
+        StatsPercentiles {
+            count: self.count,
+            avg: self.mean(),
+            p50: self.percentile(50.0),
+            p99: self.percentile(99.0),
+            p999: self.percentile(99.9),
+            max: self.max as f64,
+        }
+    }
+}
+
+impl std::fmt::Display for StatsHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if !f.alternate() {
+            return write!(f, "{}", self.percentiles());
+        }
+
+        let first = self.buckets.iter().position(|&v| v > 0);
+        let last = self.buckets.iter().rposition(|&v| v > 0);
+        let (first, last) = match (first, last) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return writeln!(f, "(empty)"),
+        };
+
+        const BAR_WIDTH: u64 = 40;
+        let peak = self.buckets.iter().max().copied().unwrap_or(1);
+        let width = Self::bucket_max(last).to_string().len();
+        let cwidth = peak.to_string().len();
+
+        for idx in first..=last {
+            let cnt = self.buckets[idx];
+            writeln!(
+                f,
+                "[{:>w$}, {:>w$}] {:>cw$} |{}",
+                Self::bucket_min(idx),
+                Self::bucket_max(idx),
+                cnt,
+                "#".repeat((cnt.saturating_mul(BAR_WIDTH)).div_ceil(peak) as usize),
+                w = width,
+                cw = cwidth,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Percentile summary of a distribution. Can be reported on its own when
+/// the scheduler computes the percentiles itself or be derived from a
+/// `StatsHistogram` with `StatsHistogram::percentiles()`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsPercentiles {
+    pub count: u64,
+    pub avg: f64,
+    pub p50: f64,
+    pub p99: f64,
+    pub p999: f64,
+    pub max: f64,
+}
+
+impl StatsPercentiles {
+    /// The quantiles and their values in the OpenMetrics summary order.
+    pub fn quantiles(&self) -> [(f64, f64); 3] {
+        [(0.5, self.p50), (0.99, self.p99), (0.999, self.p999)]
+    }
+}
+
+impl std::fmt::Display for StatsPercentiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "cnt={} avg={:.1} p50={:.1} p99={:.1} p999={:.1} max={:.1}",
+            self.count, self.avg, self.p50, self.p99, self.p999, self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hist_of(vals: &[u64]) -> StatsHistogram {
+        let mut hist = StatsHistogram::new();
+        for &val in vals.iter() {
+            hist.record(val);
+        }
+        hist
+    }
+
+    #[test]
+    fn test_empty() {
+        let hist = StatsHistogram::new();
+        assert!(hist.is_empty());
+        assert_eq!(hist.mean(), 0.0);
+        assert_eq!(hist.percentile(0.0), 0.0);
+        assert_eq!(hist.percentile(50.0), 0.0);
+        assert_eq!(hist.percentile(100.0), 0.0);
+        assert_eq!(hist.percentiles(), StatsPercentiles::default());
+        assert_eq!(format!("{:#}", hist), "(empty)\n");
+
+        let mut hist = hist_of(&[3, 5]);
+        hist.record_n(7, 0);
+        hist.merge(&StatsHistogram::new());
+        assert_eq!(hist, hist_of(&[3, 5]));
+    }
+
+    #[test]
+    fn test_single_bucket() {
+        // All in [64, 128).
+        let hist = hist_of(&[70, 80, 90, 100]);
+        assert_eq!(hist.buckets, vec![0, 0, 0, 0, 0, 0, 0, 4]);
+        assert_eq!(
+            (hist.count, hist.sum, hist.min, hist.max),
+            (4, 340, 70, 100)
+        );
+        assert_eq!(hist.mean(), 85.0);
+        // Interpolated between 64 and 127 and clamped to [70, 100].
+        assert_eq!(hist.percentile(50.0), 64.0 + 63.0 * 0.5);
+        assert_eq!(hist.percentile(1.0), 64.0 + 63.0 * 0.25);
+        assert_eq!(hist.percentile(99.0), 100.0);
+        assert_eq!(hist.percentile(0.0), 70.0);
+        assert_eq!(hist.percentile(100.0), 100.0);
+
+        let hist = hist_of(&[0, 0]);
+        assert_eq!(hist.buckets, vec![2]);
+        assert_eq!(hist.percentile(50.0), 0.0);
+        assert_eq!(hist.percentile(100.0), 0.0);
+    }
+
+    #[test]
+    fn test_p0_p100() {
+        let hist = hist_of(&[1, 10, 100, 1000]);
+        assert_eq!(hist.percentile(0.0), 1.0);
+        assert_eq!(hist.percentile(100.0), 1000.0);
+        // Out of range percentiles are clamped.
+        assert_eq!(hist.percentile(-5.0), 1.0);
+        assert_eq!(hist.percentile(200.0), 1000.0);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut hist = hist_of(&[1, 2, 3]);
+        hist.merge(&hist_of(&[1000, 2000]));
+        assert_eq!(hist, hist_of(&[1, 2, 3, 1000, 2000]));
+        assert_eq!((hist.count, hist.min, hist.max), (5, 1, 2000));
+        assert_eq!(hist.percentile(0.0), 1.0);
+        assert_eq!(hist.percentile(100.0), 2000.0);
+
+        // Into an empty one, min must come from the other.
+        let mut hist = StatsHistogram::new();
+        hist.merge(&hist_of(&[5, 9]));
+        assert_eq!(hist, hist_of(&[5, 9]));
+    }
+
+    #[test]
+    fn test_saturation() {
+        let mut hist = StatsHistogram::new();
+        hist.record_n(u64::MAX, u64::MAX);
+        hist.record(u64::MAX);
+        assert_eq!(hist.count, u64::MAX);
+        assert_eq!(hist.sum, u64::MAX);
+        assert_eq!(hist.buckets[StatsHistogram::bucket_of(u64::MAX)], u64::MAX);
+
+        let other = hist.clone();
+        hist.merge(&other);
+        assert_eq!(hist.count, u64::MAX);
+        assert_eq!(hist.buckets[64], u64::MAX);
+        assert_eq!(hist.percentile(100.0), u64::MAX as f64);
+
+        let mut hist = hist_of(&[1]);
+        hist.record_n(2, u64::MAX);
+        assert_eq!(hist.percentile(100.0), 2.0);
+    }
+}
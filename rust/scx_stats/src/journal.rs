@@ -0,0 +1,339 @@
+use crate::flatten_stats;
+use crate::periodic::{run_periodic, LazyStatsClient};
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use serde_json::Value;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+/// journald's native protocol socket.
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// MESSAGE_ID of the periodic stats snapshots, e.g. for `journalctl
+/// MESSAGE_ID=03092d5bc20d409d921dc1cf9cd545f6`.
+pub const JOURNAL_MSGID_STATS: &str = "03092d5bc20d409d921dc1cf9cd545f6";
+/// MESSAGE_ID of the entries logged when a scheduler is attached.
+pub const JOURNAL_MSGID_ATTACH: &str = "04d9a685c0d14c18b913fd290bdabbd5";
+/// MESSAGE_ID of the entries logged when a scheduler exits or is detached,
+/// along with the exit info.
+pub const JOURNAL_MSGID_EXIT: &str = "f25c471b1b994d43af31e13a51be8e01";
+
+// journald limits the number of fields per entry. Keep well below it and
+// leave room for the trusted fields journald adds.
+const JOURNAL_MAX_STATS_FIELDS: usize = 512;
+
+/// syslog priority of error entries.
+pub const JOURNAL_PRIO_ERR: u32 = 3;
+/// syslog priority of informational entries.
+pub const JOURNAL_PRIO_INFO: u32 = 6;
+
+/// Convert `name` into a valid journal field name, which can only contain
+/// uppercase letters, digits and underscores and can't start with an
+/// underscore or a digit.
+pub fn journal_field_name(name: &str) -> String {
+    let mut out: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' => c.to_ascii_uppercase(),
+            'A'..='Z' | '0'..='9' => c,
+            _ => '_',
+        })
+        .collect();
+    if !out.starts_with(|c: char| c.is_ascii_uppercase()) {
+        out.insert(0, 'X');
+    }
+    out.truncate(64);
+    out
+}
+
+/// Client of the journald native protocol.
+pub struct StatsJournal {
+    sock: UnixDatagram,
+}
+
+impl StatsJournal {
+    pub fn connect() -> Result<Self> {
+        Self::connect_path(JOURNAL_SOCKET)
+    }
+
+    pub fn connect_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let sock = UnixDatagram::unbound()?;
+        sock.connect(path.as_ref())
+            .with_context(|| format!("connecting to {:?}", path.as_ref()))?;
+        Ok(Self { sock })
+    }
+
+    /// Whether the lifecycle events should be logged. True when running as
+    /// a systemd service with the output connected to the journal or when
+    /// SCX_STATS_JOURNAL is set.
+    pub fn enabled() -> bool {
+        std::env::var_os("JOURNAL_STREAM").is_some()
+            || std::env::var_os("SCX_STATS_JOURNAL").is_some()
+    }
+
+    /// Send an entry consisting of `fields`. The field names must be valid,
+    /// see `journal_field_name()`.
+    pub fn send<K: AsRef<str>, V: AsRef<str>>(&self, fields: &[(K, V)]) -> Result<()> {
+        let mut buf = vec![];
+        for (key, val) in fields.iter() {
+            let (key, val) = (key.as_ref().as_bytes(), val.as_ref().as_bytes());
+            buf.extend_from_slice(key);
+            // Values with newlines must be sent in the binary form.
+            if val.contains(&b'\n') {
+                buf.push(b'\n');
+                buf.extend_from_slice(&(val.len() as u64).to_le_bytes());
+            } else {
+                buf.push(b'=');
+            }
+            buf.extend_from_slice(val);
+            buf.push(b'\n');
+        }
+        match self.sock.send(&buf) {
+            Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => self.send_memfd(&buf),
+            res => Ok(res.map(|_| ())?),
+        }
+    }
+
+    /// Entries which don't fit in a datagram are passed to journald in a
+    /// sealed memfd.
+    fn send_memfd(&self, buf: &[u8]) -> Result<()> {
+        // SAFETY: The name is a valid C string.
+        let fd =
+            unsafe { libc::memfd_create(c"scx_stats_journal".as_ptr(), libc::MFD_ALLOW_SEALING) };
+        if fd < 0 {
+            bail!("memfd_create failed ({})", std::io::Error::last_os_error());
+        }
+        // SAFETY: @fd was just created and isn't owned by anything else.
+        let memfd = unsafe { OwnedFd::from_raw_fd(fd) };
+        std::fs::File::from(memfd.try_clone()?).write_all(buf)?;
+
+        // SAFETY: @fd is a valid memfd. journald requires the seals.
+        if unsafe {
+            libc::fcntl(
+                fd,
+                libc::F_ADD_SEALS,
+                libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL,
+            )
+        } < 0
+        {
+            bail!("sealing memfd failed ({})", std::io::Error::last_os_error());
+        }
+
+        // SAFETY: CMSG_SPACE() is a pure calculation.
+        let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+        let mut cmsg_buf = vec![0u8; space];
+        // SAFETY: @msg points to @cmsg_buf which is sized for a single fd
+        // and outlives sendmsg().
+        let ret = unsafe {
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = space as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+            libc::sendmsg(self.sock.as_raw_fd(), &msg, 0)
+        };
+        if ret < 0 {
+            bail!("sending memfd failed ({})", std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Log a scheduler lifecycle event with `msgid`, e.g. JOURNAL_MSGID_ATTACH,
+/// if `StatsJournal::enabled()`. Failures are ignored as the journal is
+/// best-effort.
+pub fn journal_event(msgid: &str, priority: u32, message: &str, fields: &[(String, String)]) {
+    if !StatsJournal::enabled() {
+        return;
+    }
+
+    let mut entry = vec![
+        ("MESSAGE_ID".to_string(), msgid.to_string()),
+        ("MESSAGE".to_string(), message.to_string()),
+        ("PRIORITY".to_string(), priority.to_string()),
+    ];
+    entry.extend(fields.iter().cloned());
+
+    if let Err(e) = StatsJournal::connect().and_then(|j| j.send(&entry)) {
+        debug!("failed to log {:?} to the journal ({:?})", message, &e);
+    }
+}
+
+/// Periodically logs the stats of a StatsServer as journal entries. Like
+/// the other exporters, the stats are read over the UNIX domain socket as a
+/// single long-lived client.
+pub(crate) struct JournalExporter {
+    interval: Duration,
+    ident: String,
+    exit: Arc<AtomicBool>,
+    client: LazyStatsClient,
+    journal: Option<StatsJournal>,
+    warned: bool,
+}
+
+impl JournalExporter {
+    pub(crate) fn launch(
+        interval: Duration,
+        stats_path: &Path,
+        ident: &str,
+        exit: Arc<AtomicBool>,
+    ) -> Result<()> {
+        if interval.is_zero() {
+            bail!("journal interval must be positive");
+        }
+        let exporter = Self {
+            interval,
+            ident: ident.to_string(),
+            exit,
+            client: LazyStatsClient::new(stats_path),
+            journal: None,
+            warned: false,
+        };
+
+        spawn(move || exporter.run());
+        Ok(())
+    }
+
+    /// Each snapshot carries the whole stats as JSON in SCX_STATS and the
+    /// flattened numeric fields as SCX_STAT_PATH, e.g.
+    /// SCX_STAT_DOMS_DICT_0_EVENTS, so that they can be filtered on.
+    fn entry(&mut self, stats: &Value) -> Vec<(String, String)> {
+        let mut entry = vec![
+            ("MESSAGE_ID".to_string(), JOURNAL_MSGID_STATS.to_string()),
+            ("MESSAGE".to_string(), format!("{} stats", &self.ident)),
+            ("PRIORITY".to_string(), JOURNAL_PRIO_INFO.to_string()),
+            ("SYSLOG_IDENTIFIER".to_string(), self.ident.clone()),
+            ("SCX_STATS".to_string(), stats.to_string()),
+        ];
+
+        let flat: Vec<(String, String)> = flatten_stats(stats)
+            .into_iter()
+            .filter(|(_, v)| v.is_number())
+            .map(|(k, v)| {
+                (
+                    journal_field_name(&format!("SCX_STAT_{}", k)),
+                    v.to_string(),
+                )
+            })
+            .collect();
+        if flat.len() > JOURNAL_MAX_STATS_FIELDS && !self.warned {
+            warn!(
+                "{} stats fields exceed the journal limit, only logging the JSON",
+                flat.len()
+            );
+            self.warned = true;
+        }
+        if flat.len() <= JOURNAL_MAX_STATS_FIELDS {
+            entry.extend(flat);
+        }
+        entry
+    }
+
+    fn export(&mut self) -> Result<()> {
+        let stats: Value = self.client.with(|c| c.request("stats", vec![]))?;
+
+        if self.journal.is_none() {
+            self.journal = Some(StatsJournal::connect()?);
+        }
+        let entry = self.entry(&stats);
+        if let Err(e) = self.journal.as_ref().unwrap().send(&entry) {
+            self.journal = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn run(mut self) {
+        let exit = self.exit.clone();
+        run_periodic::<()>(Instant::now() + self.interval, self.interval, &exit, || {
+            if let Err(e) = self.export() {
+                warn!("failed to log stats to the journal ({:?})", e);
+            }
+            ControlFlow::Continue(())
+        });
+        debug!("journal exporter exiting");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{journal_field_name, JournalExporter, StatsJournal, JOURNAL_MSGID_STATS};
+    use crate::periodic::LazyStatsClient;
+    use serde_json::json;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_journal_field_name() {
+        assert_eq!(
+            journal_field_name("doms_dict.0.events"),
+            "DOMS_DICT_0_EVENTS"
+        );
+        assert_eq!(journal_field_name("_private"), "X_PRIVATE");
+        assert_eq!(journal_field_name("0abc"), "X0ABC");
+        assert_eq!(journal_field_name(&"a".repeat(100)).len(), 64);
+    }
+
+    #[test]
+    fn test_journal_entry() {
+        let mut exporter = JournalExporter {
+            interval: Duration::from_secs(1),
+            ident: "scx_test".into(),
+            exit: Arc::new(AtomicBool::new(false)),
+            client: LazyStatsClient::new(Path::new("/nonexistent")),
+            journal: None,
+            warned: false,
+        };
+        let stats = json!({"at": 12, "doms_dict": {"0": {"events": 3, "name": "dom0"}}});
+        let entry = exporter.entry(&stats);
+        let get = |key: &str| {
+            entry
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+
+        assert_eq!(get("MESSAGE_ID"), Some(JOURNAL_MSGID_STATS));
+        assert_eq!(get("MESSAGE"), Some("scx_test stats"));
+        assert_eq!(get("SYSLOG_IDENTIFIER"), Some("scx_test"));
+        assert_eq!(get("SCX_STATS"), Some(stats.to_string().as_str()));
+        assert_eq!(get("SCX_STAT_AT"), Some("12"));
+        assert_eq!(get("SCX_STAT_DOMS_DICT_0_EVENTS"), Some("3"));
+        // Only numeric fields are flattened.
+        assert_eq!(get("SCX_STAT_DOMS_DICT_0_NAME"), None);
+    }
+
+    #[test]
+    fn test_journal_send() {
+        let path = std::env::temp_dir().join(format!("scx_stats_journal_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        let journal = StatsJournal::connect_path(&path).unwrap();
+        journal
+            .send(&[("MESSAGE", "hello"), ("SCX_STATS", "a\nb")])
+            .unwrap();
+        let mut buf = [0u8; 4096];
+        let len = server.recv(&mut buf).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        // Values with newlines are sent with their length in LE u64.
+        let mut expected = b"MESSAGE=hello\nSCX_STATS\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(&buf[..len], expected.as_slice());
+    }
+}
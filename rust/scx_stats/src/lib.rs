@@ -6,15 +6,63 @@ pub use stats::{
     StatsStructAttrs,
 };
 
+mod counters;
+pub use counters::{StatsCounterMode, StatsCounters};
+
+mod dims;
+pub use dims::StatsDims;
+
+mod units;
+pub use units::stats_unit_scale;
+
+mod filter;
+pub use filter::StatsFilter;
+
+mod encoding;
+pub use encoding::StatsEncoding;
+
+mod handshake;
+pub use handshake::{set_schema_hashes, StatsHello, STATS_PROTO_VERSION};
+
+mod histogram;
+pub use histogram::{StatsHistogram, StatsPercentiles};
+
 mod server;
 pub use server::{
     StatsCloser, StatsErrno, StatsOpener, StatsOps, StatsReader, StatsReaderSend, StatsReaderSync,
     StatsRequest, StatsResponse, StatsServer, StatsServerData, ToJson,
 };
 
+mod transport;
+pub use transport::StatsAddr;
+
 mod client;
 pub use client::StatsClient;
 
+mod openmetrics;
+pub use openmetrics::{collect_om_metrics, write_prometheus, OmKind, OmMetric, OmSample, OmValue};
+
+mod journal;
+pub use journal::{
+    journal_event, journal_field_name, StatsJournal, JOURNAL_MSGID_ATTACH, JOURNAL_MSGID_EXIT,
+    JOURNAL_MSGID_STATS, JOURNAL_PRIO_ERR, JOURNAL_PRIO_INFO, JOURNAL_SOCKET,
+};
+
+mod acl;
+mod otlp;
+mod periodic;
+mod prometheus;
+mod push;
+
+mod proxy;
+pub use proxy::StatsProxy;
+
+mod record;
+pub use record::{flatten_stats, StatsCsvWriter};
+
+mod retention;
+pub use retention::StatsSnapshot;
+
 pub mod prelude {
     pub use crate::*;
 }
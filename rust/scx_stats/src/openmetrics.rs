@@ -0,0 +1,434 @@
+use crate::StatsPercentiles;
+use crate::{StatsClient, StatsData, StatsFieldAttrs, StatsHistogram, StatsKind, StatsMeta};
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// The value of an OpenMetrics sample. Histograms and summaries carry the
+/// whole distribution which is expanded by the exporters.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OmValue {
+    Number(f64),
+    Histogram(StatsHistogram),
+    Summary(StatsPercentiles),
+}
+
+/// A single value of an OpenMetrics metric along with its labels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OmSample {
+    pub labels: Vec<(String, String)>,
+    pub value: OmValue,
+}
+
+/// The type of an OpenMetrics metric. Numeric fields are gauges unless
+/// specified otherwise with the `_om_type` field attribute. Histogram and
+/// percentiles fields are histograms and summaries respectively.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OmKind {
+    Gauge,
+    Counter,
+    Histogram,
+    Summary,
+}
+
+impl OmKind {
+    fn new(om_type: Option<&String>) -> Result<Self> {
+        match om_type.map(|v| v.as_str()) {
+            None | Some("gauge") => Ok(Self::Gauge),
+            Some("counter") => Ok(Self::Counter),
+            Some(v) => bail!("unknown _om_type {:?}", v),
+        }
+    }
+}
+
+impl std::fmt::Display for OmKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Gauge => write!(f, "gauge"),
+            Self::Counter => write!(f, "counter"),
+            Self::Histogram => write!(f, "histogram"),
+            Self::Summary => write!(f, "summary"),
+        }
+    }
+}
+
+/// An OpenMetrics metric collected from stats. All samples share the same
+/// name, type, description and unit and are distinguished by their labels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OmMetric {
+    pub name: String,
+    pub kind: OmKind,
+    pub desc: String,
+    pub unit: Option<String>,
+    pub samples: Vec<OmSample>,
+}
+
+struct OmCollector<'a> {
+    meta: &'a BTreeMap<String, StatsMeta>,
+    metrics: BTreeMap<String, OmMetric>,
+}
+
+impl OmCollector<'_> {
+    fn add(
+        &mut self,
+        name: String,
+        kind: OmKind,
+        attrs: &StatsFieldAttrs,
+        labels: &[(String, String)],
+        value: OmValue,
+    ) {
+        let metric = self
+            .metrics
+            .entry(name.clone())
+            .or_insert_with(|| OmMetric {
+                name,
+                kind,
+                desc: attrs.desc.clone().unwrap_or_default(),
+                unit: attrs.unit.clone(),
+                samples: vec![],
+            });
+        metric.samples.push(OmSample {
+            labels: labels.to_vec(),
+            value,
+        });
+    }
+
+    fn collect(&mut self, sname: &str, stats: &Value, labels: &[(String, String)]) -> Result<()> {
+        let meta = self.meta;
+        let smeta = meta
+            .get(sname)
+            .ok_or_else(|| anyhow!("unknown stats meta name {}", sname))?;
+        let prefix = smeta
+            .attrs
+            .user
+            .get("_om_prefix")
+            .map(|v| v.as_str())
+            .unwrap_or("");
+        let obj = match stats.as_object() {
+            Some(v) => v,
+            None => bail!("{} stats is not an object", sname),
+        };
+
+        for (fname, field) in smeta.fields.iter() {
+            if field.attrs.user.contains_key("_om_skip") {
+                continue;
+            }
+            let fstats = match obj.get(fname) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let name = om_name(&format!("{}{}", prefix, fname));
+            let attrs = &field.attrs;
+
+            match &field.data {
+                StatsData::Datum(StatsKind::I64 | StatsKind::U64 | StatsKind::Float) => {
+                    if let Some(v) = fstats.as_f64() {
+                        let kind = OmKind::new(field.attrs.user.get("_om_type"))?;
+                        self.add(name, kind, attrs, labels, OmValue::Number(v));
+                    }
+                }
+                StatsData::Datum(StatsKind::Histogram) => {
+                    let hist = serde_json::from_value(fstats.clone())?;
+                    self.add(
+                        name,
+                        OmKind::Histogram,
+                        attrs,
+                        labels,
+                        OmValue::Histogram(hist),
+                    );
+                }
+                StatsData::Datum(StatsKind::Percentiles) => {
+                    let pcts = serde_json::from_value(fstats.clone())?;
+                    self.add(name, OmKind::Summary, attrs, labels, OmValue::Summary(pcts));
+                }
+                StatsData::Datum(StatsKind::Struct(inner)) => {
+                    // _om_label on a struct field labels the nested metrics
+                    // with the field name, e.g. the per-source fields of
+                    // StatsProxy.
+                    match field.attrs.user.get("_om_label") {
+                        Some(label) => {
+                            let mut flabels = labels.to_vec();
+                            flabels.push((om_name(label), fname.clone()));
+                            self.collect(inner, fstats, &flabels)?
+                        }
+                        None => self.collect(inner, fstats, labels)?,
+                    }
+                }
+                StatsData::Dict {
+                    key: _,
+                    datum: StatsKind::Struct(inner),
+                } => {
+                    // _om_label distinguishes the members of the dict by
+                    // pointing to the dict keys. Dimensions are labeled
+                    // with the dimension name by default.
+                    let label = match meta
+                        .get(inner)
+                        .and_then(|m| m.attrs.user.get("_om_label"))
+                        .or(field.attrs.dim.as_ref())
+                    {
+                        Some(v) => om_name(v),
+                        None => bail!(
+                            "{}.{} is nested inside but {} does not have _om_label",
+                            sname,
+                            fname,
+                            inner
+                        ),
+                    };
+                    if let Some(dict) = fstats.as_object() {
+                        for (key, dstats) in dict.iter() {
+                            let mut dlabels = labels.to_vec();
+                            dlabels.push((label.clone(), key.clone()));
+                            self.collect(inner, dstats, &dlabels)?;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert `name` into a valid OpenMetrics metric or label name.
+fn om_name(name: &str) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            'a'..='z' | 'A'..='Z' | '_' => c,
+            '0'..='9' if i > 0 => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn om_escape(val: &str, escape_quote: bool) -> String {
+    let mut out = String::with_capacity(val.len());
+    for c in val.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '"' if escape_quote => out.push_str("\\\""),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn om_value(val: f64) -> String {
+    match val {
+        v if v.is_nan() => "NaN".into(),
+        v if v == f64::INFINITY => "+Inf".into(),
+        v if v == f64::NEG_INFINITY => "-Inf".into(),
+        v => v.to_string(),
+    }
+}
+
+/// Collect OpenMetrics metrics from `stats`, an instance of the `top` stats
+/// struct, following the same conventions as
+/// scripts/scxstats_to_openmetrics.py:
+///
+/// - Numeric fields become gauges named `_om_prefix` of the containing
+///   struct followed by the field name.
+///
+/// - Structs nested in dicts are descended into with the dict keys as the
+///   values of the label named by the `_om_label` of the nested struct.
+///
+/// - Fields with `_om_type="counter"` are reported as counters instead.
+///
+/// - Histogram and percentiles fields become histograms and summaries.
+///
+/// - Fields with `_om_skip` and fields of other types are skipped.
+pub fn collect_om_metrics(
+    meta: &BTreeMap<String, StatsMeta>,
+    top: &str,
+    stats: &Value,
+) -> Result<Vec<OmMetric>> {
+    let mut collector = OmCollector {
+        meta,
+        metrics: BTreeMap::new(),
+    };
+    collector.collect(top, stats, &[])?;
+    Ok(collector.metrics.into_values().collect())
+}
+
+/// Request the metadata and the top-level stats through `client` and collect
+/// OpenMetrics metrics from them. Used by the exporters which read the
+/// stats over the UNIX domain socket.
+pub(crate) fn fetch_om_metrics(client: &mut StatsClient) -> Result<Vec<OmMetric>> {
+    let meta: BTreeMap<String, StatsMeta> = client.request("stats_meta", vec![])?;
+    let top = meta
+        .values()
+        .find(|m| m.attrs.top.is_some())
+        .ok_or_else(|| anyhow!("top-level stats metadata missing"))?
+        .name
+        .clone();
+    let stats: Value = client.request("stats", vec![])?;
+    collect_om_metrics(&meta, &top, &stats)
+}
+
+fn prom_labels(labels: &[(String, String)], extra: Option<(&str, String)>) -> String {
+    let mut out: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", om_name(k), om_escape(v, true)))
+        .collect();
+    if let Some((k, v)) = extra {
+        out.push(format!("{}=\"{}\"", k, v));
+    }
+    match out.is_empty() {
+        true => "".into(),
+        false => format!("{{{}}}", out.join(",")),
+    }
+}
+
+/// Write `metrics` in the Prometheus text exposition format. `labels` are
+/// added to all samples, e.g. to identify the scheduler.
+pub fn write_prometheus<W: Write>(
+    w: &mut W,
+    metrics: &[OmMetric],
+    labels: &[(String, String)],
+) -> Result<()> {
+    for metric in metrics.iter() {
+        let name = &metric.name;
+        // The text format doesn't have a place for units. Note them in the
+        // help instead, e.g. "a latency (ns)".
+        let help = match &metric.unit {
+            Some(unit) if metric.desc.is_empty() => format!("({})", unit),
+            Some(unit) => format!("{} ({})", &metric.desc, unit),
+            None => metric.desc.clone(),
+        };
+        if !help.is_empty() {
+            writeln!(w, "# HELP {} {}", name, om_escape(&help, false))?;
+        }
+        writeln!(w, "# TYPE {} {}", name, metric.kind)?;
+        for sample in metric.samples.iter() {
+            let all: Vec<(String, String)> =
+                labels.iter().chain(sample.labels.iter()).cloned().collect();
+            let lbl = |extra| prom_labels(&all, extra);
+
+            match &sample.value {
+                OmValue::Number(v) => writeln!(w, "{}{} {}", name, lbl(None), om_value(*v))?,
+                OmValue::Histogram(hist) => {
+                    let mut cumul = 0;
+                    for (idx, cnt) in hist.buckets.iter().enumerate() {
+                        cumul += cnt;
+                        let le = StatsHistogram::bucket_max(idx).to_string();
+                        writeln!(w, "{}_bucket{} {}", name, lbl(Some(("le", le))), cumul)?;
+                    }
+                    let le = Some(("le", "+Inf".to_string()));
+                    writeln!(w, "{}_bucket{} {}", name, lbl(le), hist.count)?;
+                    writeln!(w, "{}_sum{} {}", name, lbl(None), hist.sum)?;
+                    writeln!(w, "{}_count{} {}", name, lbl(None), hist.count)?;
+                }
+                OmValue::Summary(pcts) => {
+                    for (q, v) in pcts.quantiles() {
+                        let quantile = Some(("quantile", q.to_string()));
+                        writeln!(w, "{}{} {}", name, lbl(quantile), om_value(v))?;
+                    }
+                    let sum = pcts.avg * pcts.count as f64;
+                    writeln!(w, "{}_sum{} {}", name, lbl(None), om_value(sum))?;
+                    writeln!(w, "{}_count{} {}", name, lbl(None), pcts.count)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_prometheus, OmKind, OmMetric, OmSample, OmValue};
+    use crate::{StatsHistogram, StatsPercentiles};
+
+    #[test]
+    fn test_write_prometheus() {
+        let mut hist = StatsHistogram::new();
+        hist.record_n(2, 3);
+        let pcts = StatsPercentiles {
+            count: 2,
+            avg: 1.5,
+            p50: 1.0,
+            p99: 2.0,
+            p999: 2.0,
+            max: 2.0,
+        };
+        let metric = |name: &str, kind, desc: &str, unit: Option<&str>, samples| OmMetric {
+            name: name.into(),
+            kind,
+            desc: desc.into(),
+            unit: unit.map(String::from),
+            samples,
+        };
+        let sample = |labels: Vec<(&str, &str)>, value| OmSample {
+            labels: labels
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            value,
+        };
+        let metrics = vec![
+            metric(
+                "load",
+                OmKind::Gauge,
+                "load\nof a \"domain\"",
+                Some("ratio"),
+                vec![
+                    sample(vec![("dom", "0")], OmValue::Number(0.5)),
+                    sample(vec![("dom", "a\"b")], OmValue::Number(f64::NAN)),
+                ],
+            ),
+            metric(
+                "events",
+                OmKind::Counter,
+                "",
+                None,
+                vec![sample(vec![], OmValue::Number(f64::INFINITY))],
+            ),
+            metric(
+                "lat",
+                OmKind::Histogram,
+                "",
+                Some("ns"),
+                vec![sample(vec![], OmValue::Histogram(hist))],
+            ),
+            metric(
+                "wait",
+                OmKind::Summary,
+                "wait",
+                None,
+                vec![sample(vec![], OmValue::Summary(pcts))],
+            ),
+        ];
+
+        let mut buf = vec![];
+        let labels = vec![("sched".to_string(), "rusty".to_string())];
+        write_prometheus(&mut buf, &metrics, &labels).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            concat!(
+                "# HELP load load\\nof a \"domain\" (ratio)\n",
+                "# TYPE load gauge\n",
+                "load{sched=\"rusty\",dom=\"0\"} 0.5\n",
+                "load{sched=\"rusty\",dom=\"a\\\"b\"} NaN\n",
+                "# TYPE events counter\n",
+                "events{sched=\"rusty\"} +Inf\n",
+                "# HELP lat (ns)\n",
+                "# TYPE lat histogram\n",
+                "lat_bucket{sched=\"rusty\",le=\"0\"} 0\n",
+                "lat_bucket{sched=\"rusty\",le=\"1\"} 0\n",
+                "lat_bucket{sched=\"rusty\",le=\"3\"} 3\n",
+                "lat_bucket{sched=\"rusty\",le=\"+Inf\"} 3\n",
+                "lat_sum{sched=\"rusty\"} 6\n",
+                "lat_count{sched=\"rusty\"} 3\n",
+                "# HELP wait wait\n",
+                "# TYPE wait summary\n",
+                "wait{sched=\"rusty\",quantile=\"0.5\"} 1\n",
+                "wait{sched=\"rusty\",quantile=\"0.99\"} 2\n",
+                "wait{sched=\"rusty\",quantile=\"0.999\"} 2\n",
+                "wait_sum{sched=\"rusty\"} 3\n",
+                "wait_count{sched=\"rusty\"} 2\n",
+            )
+        );
+    }
+}
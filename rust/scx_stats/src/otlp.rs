@@ -0,0 +1,378 @@
+use crate::openmetrics::fetch_om_metrics;
+use crate::periodic::{run_periodic, LazyStatsClient};
+use crate::{OmKind, OmMetric, OmValue, StatsHistogram};
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::spawn;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The collector endpoint in the form of "http://HOST:PORT[/PATH]". If PATH
+/// is omitted, the standard OTLP/HTTP metrics path is used.
+#[derive(Clone, Debug)]
+struct OtlpEndpoint {
+    host: String,
+    path: String,
+}
+
+impl OtlpEndpoint {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = match url.strip_prefix("http://") {
+            Some(v) => v,
+            None => bail!("OTLP endpoint {:?} is not an http:// URL", url),
+        };
+        let (host, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            bail!("OTLP endpoint {:?} doesn't have a host", url);
+        }
+        let host = match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{}:4318", host),
+        };
+        let path = match path {
+            "" | "/" => "/v1/metrics".to_string(),
+            v => v.to_string(),
+        };
+        Ok(Self { host, path })
+    }
+}
+
+/// OTLP units follow UCUM, which differs from the stats units for bytes and
+/// ratios.
+fn otlp_unit(unit: &str) -> String {
+    match unit {
+        "ratio" => "1".into(),
+        u if u.ends_with('B') => format!("{}y", u),
+        u => u.into(),
+    }
+}
+
+fn unix_nanos() -> String {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn otlp_attrs<'a>(attrs: impl Iterator<Item = &'a (String, String)>) -> Value {
+    attrs
+        .map(|(k, v)| json!({"key": k, "value": {"stringValue": v}}))
+        .collect()
+}
+
+/// Fill in the value fields of an OTLP data point. 64bit integers are
+/// encoded as strings as required by the protobuf JSON mapping.
+fn otlp_point_value(point: &mut Value, value: &OmValue) {
+    match value {
+        OmValue::Number(v) => point["asDouble"] = json!(v),
+        OmValue::Histogram(hist) => {
+            // OTLP buckets are bounded by explicit upper bounds with an
+            // extra overflow bucket at the end.
+            let nr = hist.buckets.len();
+            let bounds: Vec<f64> = (0..nr)
+                .map(|idx| StatsHistogram::bucket_max(idx) as f64)
+                .collect();
+            let counts: Vec<String> = hist
+                .buckets
+                .iter()
+                .chain(std::iter::once(&0))
+                .map(|v| v.to_string())
+                .collect();
+            point["count"] = json!(hist.count.to_string());
+            point["sum"] = json!(hist.sum as f64);
+            point["bucketCounts"] = json!(counts);
+            point["explicitBounds"] = json!(bounds);
+            if hist.count > 0 {
+                point["min"] = json!(hist.min as f64);
+                point["max"] = json!(hist.max as f64);
+            }
+        }
+        OmValue::Summary(pcts) => {
+            let quantiles: Vec<Value> = pcts
+                .quantiles()
+                .iter()
+                .map(|(q, v)| json!({"quantile": q, "value": v}))
+                .collect();
+            point["count"] = json!(pcts.count.to_string());
+            point["sum"] = json!(pcts.avg * pcts.count as f64);
+            point["quantileValues"] = json!(quantiles);
+        }
+    }
+}
+
+/// Build an OTLP ExportMetricsServiceRequest in the protobuf JSON encoding.
+fn otlp_request(
+    metrics: &[OmMetric],
+    resource: &[(String, String)],
+    start_ns: &str,
+    now_ns: &str,
+) -> Value {
+    let metrics: Vec<Value> = metrics
+        .iter()
+        .map(|metric| {
+            let points: Vec<Value> = metric
+                .samples
+                .iter()
+                .map(|sample| {
+                    let mut point = json!({
+                        "attributes": otlp_attrs(sample.labels.iter()),
+                        "startTimeUnixNano": start_ns,
+                        "timeUnixNano": now_ns,
+                    });
+                    otlp_point_value(&mut point, &sample.value);
+                    point
+                })
+                .collect();
+            let mut out = json!({"name": metric.name, "description": metric.desc});
+            if let Some(unit) = &metric.unit {
+                out["unit"] = json!(otlp_unit(unit));
+            }
+            match metric.kind {
+                OmKind::Gauge => out["gauge"] = json!({"dataPoints": points}),
+                // AGGREGATION_TEMPORALITY_CUMULATIVE
+                OmKind::Counter => {
+                    out["sum"] = json!({
+                        "dataPoints": points,
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                    })
+                }
+                OmKind::Histogram => {
+                    out["histogram"] = json!({
+                        "dataPoints": points,
+                        "aggregationTemporality": 2,
+                    })
+                }
+                OmKind::Summary => out["summary"] = json!({"dataPoints": points}),
+            }
+            out
+        })
+        .collect();
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {"attributes": otlp_attrs(resource.iter())},
+            "scopeMetrics": [{
+                "scope": {"name": "scx_stats", "version": env!("CARGO_PKG_VERSION")},
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+/// Periodically pushes the stats of a StatsServer to an OpenTelemetry
+/// collector using OTLP/HTTP with the JSON encoding. Like
+/// PrometheusExporter, the stats are read over the UNIX domain socket as a
+/// single long-lived client.
+pub(crate) struct OtlpExporter {
+    endpoint: OtlpEndpoint,
+    interval: Duration,
+    resource: Vec<(String, String)>,
+    exit: Arc<AtomicBool>,
+    client: LazyStatsClient,
+    start_ns: String,
+}
+
+impl OtlpExporter {
+    pub(crate) fn launch(
+        endpoint: &str,
+        interval: Duration,
+        stats_path: &Path,
+        resource: Vec<(String, String)>,
+        exit: Arc<AtomicBool>,
+    ) -> Result<()> {
+        if interval.is_zero() {
+            bail!("OTLP export interval must be positive");
+        }
+        let exporter = Self {
+            endpoint: OtlpEndpoint::parse(endpoint)?,
+            interval,
+            resource,
+            exit,
+            client: LazyStatsClient::new(stats_path),
+            start_ns: unix_nanos(),
+        };
+
+        spawn(move || exporter.run());
+        Ok(())
+    }
+
+    fn post(&self, body: &str) -> Result<()> {
+        let mut stream = TcpStream::connect(&self.endpoint.host)
+            .with_context(|| format!("connecting to OTLP endpoint {}", &self.endpoint.host))?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            &self.endpoint.path,
+            &self.endpoint.host,
+            body.len(),
+            body
+        )?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status)?;
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => {
+                let mut rest = String::new();
+                let _ = reader.take(4096).read_to_string(&mut rest);
+                bail!("OTLP export failed: {} ({})", status.trim(), rest.trim())
+            }
+        }
+    }
+
+    fn export(&mut self) -> Result<()> {
+        let metrics = self.client.with(fetch_om_metrics)?;
+        let req = otlp_request(&metrics, &self.resource, &self.start_ns, &unix_nanos());
+        self.post(&serde_json::to_string(&req)?)
+    }
+
+    fn run(mut self) {
+        let exit = self.exit.clone();
+        run_periodic::<()>(Instant::now() + self.interval, self.interval, &exit, || {
+            if let Err(e) = self.export() {
+                warn!("OTLP export errored ({:?})", e);
+            }
+            ControlFlow::Continue(())
+        });
+        debug!("OTLP exporter exiting");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{otlp_request, otlp_unit, OtlpEndpoint};
+    use crate::{OmKind, OmMetric, OmSample, OmValue, StatsHistogram, StatsPercentiles};
+    use serde_json::json;
+
+    #[test]
+    fn test_otlp_endpoint() {
+        let ep = OtlpEndpoint::parse("http://collector").unwrap();
+        assert_eq!(
+            (ep.host.as_str(), ep.path.as_str()),
+            ("collector:4318", "/v1/metrics")
+        );
+        let ep = OtlpEndpoint::parse("http://collector:1234/").unwrap();
+        assert_eq!(
+            (ep.host.as_str(), ep.path.as_str()),
+            ("collector:1234", "/v1/metrics")
+        );
+        let ep = OtlpEndpoint::parse("http://collector:1234/otlp/metrics").unwrap();
+        assert_eq!(ep.path, "/otlp/metrics");
+
+        assert!(OtlpEndpoint::parse("https://collector").is_err());
+        assert!(OtlpEndpoint::parse("http:///v1/metrics").is_err());
+    }
+
+    #[test]
+    fn test_otlp_unit() {
+        assert_eq!(otlp_unit("ratio"), "1");
+        assert_eq!(otlp_unit("MiB"), "MiBy");
+        assert_eq!(otlp_unit("ns"), "ns");
+    }
+
+    #[test]
+    fn test_otlp_request() {
+        let mut hist = StatsHistogram::new();
+        hist.record_n(2, 3);
+        let pcts = StatsPercentiles {
+            count: 4,
+            avg: 2.5,
+            p50: 2.0,
+            p99: 4.0,
+            p999: 4.0,
+            max: 4.0,
+        };
+        let metric = |name: &str, kind, unit: Option<&str>, value| OmMetric {
+            name: name.into(),
+            kind,
+            desc: format!("{} desc", name),
+            unit: unit.map(String::from),
+            samples: vec![OmSample {
+                labels: vec![("dom".into(), "0".into())],
+                value,
+            }],
+        };
+        let metrics = vec![
+            metric("load", OmKind::Gauge, Some("ratio"), OmValue::Number(0.5)),
+            metric("events", OmKind::Counter, None, OmValue::Number(10.0)),
+            metric(
+                "lat",
+                OmKind::Histogram,
+                Some("ns"),
+                OmValue::Histogram(hist),
+            ),
+            metric("wait", OmKind::Summary, None, OmValue::Summary(pcts)),
+        ];
+        let resource = vec![("service.name".to_string(), "scx_rusty".to_string())];
+        let req = otlp_request(&metrics, &resource, "100", "200");
+
+        let rm = &req["resourceMetrics"][0];
+        assert_eq!(
+            rm["resource"]["attributes"],
+            json!([{"key": "service.name", "value": {"stringValue": "scx_rusty"}}])
+        );
+        let out = &rm["scopeMetrics"][0]["metrics"];
+        let attrs = json!([{"key": "dom", "value": {"stringValue": "0"}}]);
+
+        assert_eq!(out[0]["name"], "load");
+        assert_eq!(out[0]["description"], "load desc");
+        assert_eq!(out[0]["unit"], "1");
+        assert_eq!(
+            out[0]["gauge"]["dataPoints"],
+            json!([{
+                "attributes": attrs,
+                "startTimeUnixNano": "100",
+                "timeUnixNano": "200",
+                "asDouble": 0.5,
+            }])
+        );
+
+        assert!(out[1].get("unit").is_none());
+        assert_eq!(out[1]["sum"]["aggregationTemporality"], 2);
+        assert_eq!(out[1]["sum"]["isMonotonic"], true);
+        assert_eq!(out[1]["sum"]["dataPoints"][0]["asDouble"], 10.0);
+
+        // 2 is in bucket 2 whose upper bound is 3. The counts have an extra
+        // overflow bucket.
+        let point = &out[2]["histogram"]["dataPoints"][0];
+        assert_eq!(point["count"], "3");
+        assert_eq!(point["sum"], 6.0);
+        assert_eq!(point["explicitBounds"], json!([0.0, 1.0, 3.0]));
+        assert_eq!(point["bucketCounts"], json!(["0", "0", "3", "0"]));
+        assert_eq!(
+            (point["min"].clone(), point["max"].clone()),
+            (json!(2.0), json!(2.0))
+        );
+
+        let point = &out[3]["summary"]["dataPoints"][0];
+        assert_eq!(point["count"], "4");
+        assert_eq!(point["sum"], 10.0);
+        assert_eq!(
+            point["quantileValues"],
+            json!([
+                {"quantile": 0.5, "value": 2.0},
+                {"quantile": 0.99, "value": 4.0},
+                {"quantile": 0.999, "value": 4.0},
+            ])
+        );
+    }
+}
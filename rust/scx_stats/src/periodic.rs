@@ -0,0 +1,194 @@
+use crate::StatsClient;
+use anyhow::Result;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How long to sleep at most between checks of the exit flag.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// StatsClient used by the exporters and other clients running inside the
+/// server process. It connects on the first use and drops the connection
+/// on errors so that the next use reconnects.
+pub(crate) struct LazyStatsClient {
+    path: PathBuf,
+    client: Option<StatsClient>,
+}
+
+impl LazyStatsClient {
+    pub(crate) fn new(path: &Path) -> Self {
+        Self {
+            path: path.into(),
+            client: None,
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// Call `f` with the connected client.
+    pub(crate) fn with<T>(&mut self, f: impl FnOnce(&mut StatsClient) -> Result<T>) -> Result<T> {
+        if self.client.is_none() {
+            self.client = Some(StatsClient::new().set_path(&self.path).connect()?);
+        }
+        let res = f(self.client.as_mut().unwrap());
+        if res.is_err() {
+            // Reconnect on the next use.
+            self.client = None;
+        }
+        res
+    }
+}
+
+/// Call `tick` at `first` and then every `interval` until either `exit` is
+/// set, in which case None is returned, or `tick` breaks with a value.
+/// Periods which are missed because `tick` took too long are skipped
+/// rather than caught up on.
+pub(crate) fn run_periodic<B>(
+    first: Instant,
+    interval: Duration,
+    exit: &AtomicBool,
+    mut tick: impl FnMut() -> ControlFlow<B>,
+) -> Option<B> {
+    let mut next = first;
+    loop {
+        // Sleep in short steps so that exit is noticed promptly.
+        loop {
+            if exit.load(Ordering::Relaxed) {
+                return None;
+            }
+            let now = Instant::now();
+            if now >= next {
+                break;
+            }
+            sleep((next - now).min(EXIT_POLL_INTERVAL));
+        }
+        next = (next + interval).max(Instant::now());
+
+        if let ControlFlow::Break(v) = tick() {
+            return Some(v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_periodic, LazyStatsClient};
+    use crate::{StatsServer, StatsServerData};
+    use anyhow::anyhow;
+    use serde_json::Value;
+    use std::ops::ControlFlow;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_run_periodic_break() {
+        let exit = AtomicBool::new(false);
+        let start = Instant::now();
+        let mut ticks = vec![];
+        let res = run_periodic(start, Duration::from_millis(10), &exit, || {
+            ticks.push(Instant::now());
+            match ticks.len() {
+                3 => ControlFlow::Break("done"),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+        assert_eq!(res, Some("done"));
+        assert_eq!(ticks.len(), 3);
+        assert!(ticks[2] - start >= Duration::from_millis(20));
+        assert!(ticks
+            .windows(2)
+            .all(|w| w[1] - w[0] >= Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn test_run_periodic_exit() {
+        // Exit set before the first tick is due.
+        let exit = AtomicBool::new(true);
+        let res: Option<()> = run_periodic(Instant::now(), Duration::ZERO, &exit, || {
+            panic!("ticked after exit")
+        });
+        assert_eq!(res, None);
+
+        // Exit set while waiting for a long period is noticed promptly.
+        let exit = Arc::new(AtomicBool::new(false));
+        let setter = {
+            let exit = exit.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                exit.store(true, Ordering::Relaxed);
+            })
+        };
+        let start = Instant::now();
+        let mut nr_ticks = 0;
+        let res: Option<()> = run_periodic(start, Duration::from_secs(3600), &exit, || {
+            nr_ticks += 1;
+            ControlFlow::Continue(())
+        });
+        setter.join().unwrap();
+        assert_eq!(res, None);
+        assert_eq!(nr_ticks, 1);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_run_periodic_skips_missed() {
+        let exit = AtomicBool::new(false);
+        let mut nr_ticks = 0;
+        let start = Instant::now();
+        run_periodic(start, Duration::from_millis(5), &exit, || {
+            nr_ticks += 1;
+            if nr_ticks == 1 {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            match nr_ticks {
+                4 => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+        // The period which was due during the slow tick runs right after it
+        // and the following ones are paced again. Without skipping, all the
+        // missed periods would run back to back.
+        assert!(start.elapsed() >= Duration::from_millis(55));
+    }
+
+    #[test]
+    fn test_lazy_client_reconnects() {
+        let mut client = LazyStatsClient::new(Path::new("/nonexistent/scx_stats"));
+        assert!(client.with(|_| Ok(())).is_err());
+        assert!(!client.is_connected());
+
+        let path = std::env::temp_dir().join(format!("scx_stats_lazy_{}", std::process::id()));
+        let data = StatsServerData::<(), ()>::new()
+            .add_stats("top", Box::new(|_, _| Ok(serde_json::json!({"a": 1}))));
+        let _server = StatsServer::<(), ()>::new(data)
+            .set_path(&path)
+            .launch()
+            .unwrap();
+
+        let mut client = LazyStatsClient::new(&path);
+        let stats: Value = client.with(|c| c.request("stats", vec![])).unwrap();
+        assert_eq!(stats["a"], 1);
+        assert!(client.is_connected());
+
+        // Errors drop the connection and the next use reconnects.
+        assert!(client
+            .with(|_| -> anyhow::Result<()> { Err(anyhow!("failed")) })
+            .is_err());
+        assert!(!client.is_connected());
+        let stats: Value = client.with(|c| c.request("stats", vec![])).unwrap();
+        assert_eq!(stats["a"], 1);
+        assert!(client.is_connected());
+        assert_eq!(client.path(), path.as_path());
+    }
+}
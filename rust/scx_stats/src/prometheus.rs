@@ -0,0 +1,146 @@
+use crate::openmetrics::fetch_om_metrics;
+use crate::periodic::LazyStatsClient;
+use crate::write_prometheus;
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::spawn;
+use std::time::Duration;
+
+/// Minimal HTTP endpoint which serves the stats of a StatsServer in the
+/// Prometheus text format on `/metrics`. It talks to the StatsServer over
+/// its UNIX domain socket like any other client, so the stats readers see
+/// it as a single long-lived client and interval-based stats such as rates
+/// are computed between scrapes.
+pub(crate) struct PrometheusExporter {
+    listener: TcpListener,
+    labels: Vec<(String, String)>,
+    exit: Arc<AtomicBool>,
+    client: LazyStatsClient,
+}
+
+impl PrometheusExporter {
+    pub(crate) fn launch(
+        addr: &str,
+        stats_path: &Path,
+        labels: Vec<(String, String)>,
+        exit: Arc<AtomicBool>,
+    ) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("binding Prometheus endpoint to {:?}", addr))?;
+        let local_addr = listener.local_addr()?;
+        let exporter = Self {
+            listener,
+            labels,
+            exit,
+            client: LazyStatsClient::new(stats_path),
+        };
+
+        spawn(move || exporter.listen());
+        Ok(local_addr)
+    }
+
+    fn scrape(&mut self) -> Result<String> {
+        let metrics = self.client.with(fetch_om_metrics)?;
+
+        let mut buf = vec![];
+        write_prometheus(&mut buf, &metrics, &self.labels)?;
+        Ok(String::from_utf8(buf)?)
+    }
+
+    fn handle(&mut self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        let mut req_line = String::new();
+        reader.read_line(&mut req_line)?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+
+        let mut req = req_line.split_whitespace();
+        let (method, target) = (req.next().unwrap_or(""), req.next().unwrap_or(""));
+        let (status, body) = match (method, target.split('?').next().unwrap()) {
+            ("GET", "/metrics") => match self.scrape() {
+                Ok(v) => ("200 OK", v),
+                Err(e) => ("500 Internal Server Error", format!("{:?}\n", &e)),
+            },
+            ("GET", _) => ("404 Not Found", "Not Found\n".into()),
+            _ => ("405 Method Not Allowed", "Method Not Allowed\n".into()),
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\n\
+             Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        Ok(())
+    }
+
+    fn listen(mut self) {
+        loop {
+            let conn = self.listener.accept();
+            if self.exit.load(Ordering::Relaxed) {
+                debug!("Prometheus endpoint exiting");
+                break;
+            }
+            match conn {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.handle(stream) {
+                        warn!("Prometheus request errored ({})", e);
+                    }
+                }
+                Err(e) => warn!("failed to accept Prometheus connection ({})", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrometheusExporter;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_prometheus_http() {
+        let exit = Arc::new(AtomicBool::new(false));
+        let addr = PrometheusExporter::launch(
+            "127.0.0.1:0",
+            Path::new("/nonexistent/scx_stats"),
+            vec![],
+            exit,
+        )
+        .unwrap();
+
+        let get = |req: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "{}\r\nHost: localhost\r\n\r\n", req).unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).unwrap();
+            resp
+        };
+
+        assert!(get("GET /foo HTTP/1.1").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get("POST /metrics HTTP/1.1").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        // The stats socket doesn't exist.
+        let resp = get("GET /metrics?x=1 HTTP/1.1");
+        assert!(resp.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(resp.contains("Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::periodic::LazyStatsClient;
+use crate::{StatsClient, StatsData, StatsField, StatsFieldAttrs, StatsKind, StatsMeta};
+use crate::{StatsOps, StatsServerData, StatsStructAttrs};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, warn};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the top-level struct of the merged stats.
+const PROXY_TOP: &str = "StatsProxy";
+
+struct ProxySource {
+    name: String,
+    client: LazyStatsClient,
+    failed: bool,
+}
+
+impl ProxySource {
+    fn read(&mut self, args: &BTreeMap<String, String>) -> Result<Value> {
+        // "fields" is applied by the proxy server on the merged stats.
+        let args = args
+            .iter()
+            .filter(|(k, _)| !["target", "fields"].contains(&k.as_str()))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        self.client.with(|c| c.request("stats", args))
+    }
+}
+
+/// Merges the stats of multiple StatsServers, e.g. per-VM or per-partition
+/// schedulers, so that they can be collected from a single socket. The
+/// merged stats have a field for each source which contains the stats of
+/// the source's top-level struct. The fields are marked with `_om_label =
+/// "source"`, so that the exported metrics are labeled with the source
+/// names.
+///
+/// `StatsProxy` builds the `StatsServerData` to be served by a regular
+/// StatsServer, which means that the exporters, subscriptions and access
+/// control work the same as with a scheduler's own server:
+///
+/// ```ignore
+/// let data = StatsProxy::new()
+///     .add_source("vm0", "/var/run/scx/vm0/stats")
+///     .add_source("vm1", "/var/run/scx/vm1/stats")
+///     .server_data()?;
+/// let server = StatsServer::new(data).set_path("/var/run/scx/proxy/stats").launch()?;
+/// ```
+///
+/// Each connection to the proxy opens its own connections to the sources,
+/// so that stateful readers in the sources, e.g. ones reporting the deltas
+/// since the last read, behave the same as when connecting directly.
+/// Sources which can't be read are left out of the merged stats and
+/// reconnected on the next read.
+#[derive(Default)]
+pub struct StatsProxy {
+    sources: Vec<(String, PathBuf)>,
+}
+
+impl StatsProxy {
+    pub fn new() -> Self {
+        Self { sources: vec![] }
+    }
+
+    /// Add the StatsServer listening on `path` as `name`.
+    pub fn add_source<P: AsRef<Path>>(mut self, name: &str, path: P) -> Self {
+        self.sources
+            .push((name.to_string(), PathBuf::from(path.as_ref())));
+        self
+    }
+
+    /// Rename the structs of the source `name` to "NAME:STRUCT" so that the
+    /// sources' structs can't collide and return the name of the source's
+    /// top-level struct.
+    fn merge_meta(
+        name: &str,
+        src_meta: BTreeMap<String, StatsMeta>,
+        meta: &mut BTreeMap<String, StatsMeta>,
+    ) -> Result<String> {
+        let rename = |sname: &str| format!("{}:{}", name, sname);
+        let rename_kind = |kind: &mut StatsKind| {
+            if let StatsKind::Struct(inner) = kind {
+                *inner = rename(inner);
+            }
+        };
+
+        let mut top = None;
+        for (sname, mut m) in src_meta.into_iter() {
+            if m.attrs.top.take().is_some() && top.is_none() {
+                top = Some(rename(&sname));
+            }
+            m.name = rename(&sname);
+            m.schema_hash = None;
+            for field in m.fields.values_mut() {
+                match &mut field.data {
+                    StatsData::Datum(kind) | StatsData::Array(kind) => rename_kind(kind),
+                    StatsData::Dict { key, datum } => {
+                        rename_kind(key);
+                        rename_kind(datum);
+                    }
+                }
+            }
+            meta.insert(m.name.clone(), m);
+        }
+
+        top.ok_or_else(|| anyhow!("{} doesn't have top-level stats", name))
+    }
+
+    /// Fetch the stats metadata from all sources and build the data for the
+    /// proxy StatsServer. All sources must be reachable.
+    pub fn server_data(&self) -> Result<StatsServerData<(), ()>> {
+        let mut meta = BTreeMap::new();
+        let mut top_fields = BTreeMap::new();
+
+        for (name, path) in self.sources.iter() {
+            if name.is_empty() || name.contains(['.', ',', '*', ':']) {
+                bail!("invalid stats proxy source name {:?}", name);
+            }
+            if top_fields.contains_key(name) {
+                bail!("duplicate stats proxy source name {:?}", name);
+            }
+
+            let src_meta: BTreeMap<String, StatsMeta> = StatsClient::new()
+                .set_path(path)
+                .connect()
+                .and_then(|mut client| client.request("stats_meta", vec![]))
+                .with_context(|| format!("fetching stats meta of {} from {:?}", name, path))?;
+            let src_top = Self::merge_meta(name, src_meta, &mut meta)?;
+
+            top_fields.insert(
+                name.clone(),
+                StatsField {
+                    data: StatsData::Datum(StatsKind::Struct(src_top)),
+                    attrs: StatsFieldAttrs {
+                        desc: Some(format!("stats of {}", path.display())),
+                        user: BTreeMap::from([("_om_label".into(), "source".into())]),
+                        ..Default::default()
+                    },
+                },
+            );
+        }
+
+        let mut data = StatsServerData::new().add_meta(StatsMeta {
+            name: PROXY_TOP.into(),
+            attrs: StatsStructAttrs {
+                top: Some("true".into()),
+                desc: Some("merged stats of the proxied servers".into()),
+                ..Default::default()
+            },
+            fields: top_fields,
+            schema_hash: None,
+        });
+        for (_, m) in meta.into_iter() {
+            data = data.add_meta(m);
+        }
+
+        let sources = self.sources.clone();
+        Ok(data.add_ops(
+            "top",
+            StatsOps {
+                open: Box::new(move |_| {
+                    let mut sources: Vec<ProxySource> = sources
+                        .iter()
+                        .map(|(name, path)| ProxySource {
+                            name: name.clone(),
+                            client: LazyStatsClient::new(path),
+                            failed: false,
+                        })
+                        .collect();
+
+                    Ok(Box::new(move |args, _| {
+                        let mut merged = Map::new();
+                        for src in sources.iter_mut() {
+                            match src.read(args) {
+                                Ok(v) => {
+                                    if src.failed {
+                                        debug!("stats proxy: {} recovered", &src.name);
+                                        src.failed = false;
+                                    }
+                                    merged.insert(src.name.clone(), v);
+                                }
+                                Err(e) => {
+                                    // Warn once per outage.
+                                    if !src.failed {
+                                        warn!(
+                                            "stats proxy: failed to read {} from {:?} ({:?})",
+                                            &src.name,
+                                            src.client.path(),
+                                            e
+                                        );
+                                        src.failed = true;
+                                    }
+                                }
+                            }
+                        }
+                        Ok(Value::Object(merged))
+                    }))
+                }),
+                close: None,
+            },
+        ))
+    }
+}
@@ -0,0 +1,405 @@
+use crate::openmetrics::fetch_om_metrics;
+use crate::periodic::{run_periodic, LazyStatsClient};
+use crate::{OmKind, OmMetric, OmValue};
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::spawn;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Keep UDP datagrams within the common Ethernet MTU.
+const UDP_MAX_PAYLOAD: usize = 1432;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PushFormat {
+    Statsd,
+    Graphite,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PushTransport {
+    Udp,
+    Tcp,
+}
+
+/// Push target in the form of "FORMAT[+TRANSPORT]://HOST:PORT". FORMAT is
+/// either "statsd" or "graphite" and TRANSPORT "udp" or "tcp". statsd
+/// defaults to UDP and graphite to TCP.
+#[derive(Clone, Debug)]
+struct PushTarget {
+    format: PushFormat,
+    transport: PushTransport,
+    addr: String,
+}
+
+impl PushTarget {
+    fn parse(target: &str) -> Result<Self> {
+        let (scheme, addr) = match target.split_once("://") {
+            Some(v) => v,
+            None => bail!("push target {:?} is not in FORMAT://HOST:PORT form", target),
+        };
+        let (format, transport) = match scheme.split_once('+') {
+            Some((f, t)) => (f, Some(t)),
+            None => (scheme, None),
+        };
+        let format = match format {
+            "statsd" => PushFormat::Statsd,
+            "graphite" => PushFormat::Graphite,
+            v => bail!("unknown push format {:?}", v),
+        };
+        let transport = match (transport, format) {
+            (Some("udp"), _) | (None, PushFormat::Statsd) => PushTransport::Udp,
+            (Some("tcp"), _) | (None, PushFormat::Graphite) => PushTransport::Tcp,
+            (Some(v), _) => bail!("unknown push transport {:?}", v),
+        };
+        if addr.is_empty() {
+            bail!("push target {:?} doesn't have an address", target);
+        }
+        Ok(Self {
+            format,
+            transport,
+            addr: addr.to_string(),
+        })
+    }
+}
+
+/// Convert `name` into a path component which is safe for both statsd and
+/// graphite.
+fn push_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Sanitize the components of `prefix` and terminate it with "." so that it
+/// can be prepended to metric paths. Empty components are dropped and an
+/// empty prefix stays empty.
+fn push_prefix(prefix: &str) -> String {
+    prefix
+        .split('.')
+        .filter(|comp| !comp.is_empty())
+        .map(|comp| push_name(comp) + ".")
+        .collect()
+}
+
+/// Flatten `metrics` into (path, value, is_counter) tuples. Labels are
+/// appended to the metric name as ".LABEL.VALUE" components as neither
+/// protocol supports labels universally. Histograms and percentiles are
+/// expanded into their percentile summaries.
+fn flatten(prefix: &str, metrics: &[OmMetric]) -> Vec<(String, f64, bool)> {
+    let mut out = vec![];
+    for metric in metrics.iter() {
+        for sample in metric.samples.iter() {
+            let mut path = format!("{}{}", prefix, push_name(&metric.name));
+            for (k, v) in sample.labels.iter() {
+                path += &format!(".{}.{}", push_name(k), push_name(v));
+            }
+
+            let pcts = match &sample.value {
+                OmValue::Number(v) => {
+                    let is_counter = metric.kind == OmKind::Counter;
+                    out.push((path, *v, is_counter));
+                    continue;
+                }
+                OmValue::Histogram(hist) => hist.percentiles(),
+                OmValue::Summary(pcts) => pcts.clone(),
+            };
+            for (key, v) in [
+                ("count", pcts.count as f64),
+                ("avg", pcts.avg),
+                ("p50", pcts.p50),
+                ("p99", pcts.p99),
+                ("p999", pcts.p999),
+                ("max", pcts.max),
+            ] {
+                out.push((format!("{}.{}", &path, key), v, false));
+            }
+        }
+    }
+    out
+}
+
+/// Periodically pushes the stats of a StatsServer to a statsd or graphite
+/// server. Like the other exporters, the stats are read over the UNIX
+/// domain socket as a single long-lived client.
+pub(crate) struct StatsPusher {
+    target: PushTarget,
+    prefix: String,
+    interval: Duration,
+    exit: Arc<AtomicBool>,
+    client: LazyStatsClient,
+    tcp: Option<TcpStream>,
+    udp: Option<UdpSocket>,
+    // Last values of statsd counters to calculate the increments from.
+    last_counters: BTreeMap<String, f64>,
+}
+
+impl StatsPusher {
+    pub(crate) fn launch(
+        target: &str,
+        prefix: &str,
+        interval: Duration,
+        stats_path: &Path,
+        exit: Arc<AtomicBool>,
+    ) -> Result<()> {
+        if interval.is_zero() {
+            bail!("push interval must be positive");
+        }
+        let pusher = Self {
+            target: PushTarget::parse(target)?,
+            prefix: push_prefix(prefix),
+            interval,
+            exit,
+            client: LazyStatsClient::new(stats_path),
+            tcp: None,
+            udp: None,
+            last_counters: BTreeMap::new(),
+        };
+
+        spawn(move || pusher.run());
+        Ok(())
+    }
+
+    fn format_lines(&mut self, metrics: &[OmMetric]) -> Vec<String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut lines = vec![];
+        for (path, val, is_counter) in flatten(&self.prefix, metrics) {
+            match self.target.format {
+                PushFormat::Graphite => lines.push(format!("{} {} {}", path, val, now)),
+                PushFormat::Statsd if is_counter => {
+                    // statsd counters are increments. Skip the first value
+                    // and restart from zero if the counter went backwards.
+                    if let Some(last) = self.last_counters.insert(path.clone(), val) {
+                        let delta = match val >= last {
+                            true => val - last,
+                            false => val,
+                        };
+                        lines.push(format!("{}:{}|c", path, delta));
+                    }
+                }
+                PushFormat::Statsd => lines.push(format!("{}:{}|g", path, val)),
+            }
+        }
+        lines
+    }
+
+    fn send(&mut self, lines: &[String]) -> Result<()> {
+        match self.target.transport {
+            PushTransport::Tcp => {
+                if self.tcp.is_none() {
+                    let stream = TcpStream::connect(&self.target.addr)
+                        .with_context(|| format!("connecting to {}", &self.target.addr))?;
+                    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+                    self.tcp = Some(stream);
+                }
+                let mut buf = lines.join("\n");
+                buf.push('\n');
+                if let Err(e) = self.tcp.as_mut().unwrap().write_all(buf.as_bytes()) {
+                    // Reconnect on the next push.
+                    self.tcp = None;
+                    Err(e)?;
+                }
+            }
+            PushTransport::Udp => {
+                if self.udp.is_none() {
+                    let sock = UdpSocket::bind(match self.target.addr.starts_with('[') {
+                        true => "[::]:0",
+                        false => "0.0.0.0:0",
+                    })?;
+                    sock.connect(&self.target.addr)
+                        .with_context(|| format!("connecting to {}", &self.target.addr))?;
+                    self.udp = Some(sock);
+                }
+                let sock = self.udp.as_ref().unwrap();
+
+                // Pack as many lines as possible into each datagram.
+                let mut buf = String::new();
+                for line in lines.iter() {
+                    if !buf.is_empty() && buf.len() + line.len() + 1 > UDP_MAX_PAYLOAD {
+                        sock.send(buf.as_bytes())?;
+                        buf.clear();
+                    }
+                    if !buf.is_empty() {
+                        buf.push('\n');
+                    }
+                    buf += line;
+                }
+                if !buf.is_empty() {
+                    sock.send(buf.as_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self) -> Result<()> {
+        let metrics = self.client.with(fetch_om_metrics)?;
+        let lines = self.format_lines(&metrics);
+        self.send(&lines)
+    }
+
+    fn run(mut self) {
+        let exit = self.exit.clone();
+        run_periodic::<()>(Instant::now() + self.interval, self.interval, &exit, || {
+            if let Err(e) = self.push() {
+                warn!("failed to push stats to {} ({:?})", &self.target.addr, e);
+            }
+            ControlFlow::Continue(())
+        });
+        debug!("stats pusher exiting");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{push_prefix, PushFormat, PushTarget, PushTransport, StatsPusher};
+    use crate::periodic::LazyStatsClient;
+    use crate::{OmKind, OmMetric, OmSample, OmValue};
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn metric(name: &str, kind: OmKind, samples: Vec<(&str, f64)>) -> OmMetric {
+        OmMetric {
+            name: name.into(),
+            kind,
+            desc: String::new(),
+            unit: None,
+            samples: samples
+                .into_iter()
+                .map(|(dom, v)| OmSample {
+                    labels: match dom {
+                        "" => vec![],
+                        dom => vec![("dom".into(), dom.into())],
+                    },
+                    value: OmValue::Number(v),
+                })
+                .collect(),
+        }
+    }
+
+    fn pusher(target: &str, prefix: &str) -> StatsPusher {
+        StatsPusher {
+            target: PushTarget::parse(target).unwrap(),
+            prefix: push_prefix(prefix),
+            interval: Duration::from_secs(1),
+            exit: Arc::new(AtomicBool::new(false)),
+            client: LazyStatsClient::new(Path::new("/nonexistent")),
+            tcp: None,
+            udp: None,
+            last_counters: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_push_target() {
+        let target = PushTarget::parse("statsd://localhost:8125").unwrap();
+        assert_eq!(target.format, PushFormat::Statsd);
+        assert_eq!(target.transport, PushTransport::Udp);
+        assert_eq!(target.addr, "localhost:8125");
+
+        let target = PushTarget::parse("graphite://localhost:2003").unwrap();
+        assert_eq!(target.transport, PushTransport::Tcp);
+        let target = PushTarget::parse("statsd+tcp://localhost:8125").unwrap();
+        assert_eq!(target.transport, PushTransport::Tcp);
+
+        assert!(PushTarget::parse("localhost:8125").is_err());
+        assert!(PushTarget::parse("influx://localhost:8125").is_err());
+        assert!(PushTarget::parse("statsd+sctp://localhost:8125").is_err());
+        assert!(PushTarget::parse("statsd://").is_err());
+    }
+
+    #[test]
+    fn test_push_prefix() {
+        assert_eq!(push_prefix(""), "");
+        assert_eq!(push_prefix("scx"), "scx.");
+        assert_eq!(push_prefix("scx.host 1"), "scx.host_1.");
+        assert_eq!(push_prefix(".scx..rusty."), "scx.rusty.");
+    }
+
+    #[test]
+    fn test_push_statsd() {
+        let mut pusher = pusher("statsd://localhost:8125", "");
+        let metrics = |events| {
+            vec![
+                metric("load", OmKind::Gauge, vec![("0", 1.5)]),
+                metric("events", OmKind::Counter, vec![("a.b", events)]),
+            ]
+        };
+
+        // Counters are only sent from the second push on, as increments.
+        assert_eq!(
+            pusher.format_lines(&metrics(10.0)),
+            vec!["load.dom.0:1.5|g"]
+        );
+        assert_eq!(
+            pusher.format_lines(&metrics(15.0)),
+            vec!["load.dom.0:1.5|g", "events.dom.a_b:5|c"]
+        );
+        // A counter which went backwards restarted from zero.
+        assert_eq!(
+            pusher.format_lines(&metrics(3.0)),
+            vec!["load.dom.0:1.5|g", "events.dom.a_b:3|c"]
+        );
+    }
+
+    #[test]
+    fn test_push_graphite() {
+        let mut pusher = pusher("graphite://localhost:2003", "scx.rusty");
+        let mut hist = crate::StatsHistogram::new();
+        hist.record_n(100, 4);
+        let metrics = vec![
+            metric("events", OmKind::Counter, vec![("", 10.0)]),
+            OmMetric {
+                name: "lat".into(),
+                kind: OmKind::Histogram,
+                desc: String::new(),
+                unit: None,
+                samples: vec![OmSample {
+                    labels: vec![],
+                    value: OmValue::Histogram(hist),
+                }],
+            },
+        ];
+
+        let lines = pusher.format_lines(&metrics);
+        let paths: Vec<&str> = lines
+            .iter()
+            .map(|line| {
+                let fields: Vec<&str> = line.split(' ').collect();
+                assert_eq!(fields.len(), 3);
+                assert!(fields[2].parse::<u64>().is_ok());
+                fields[0]
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "scx.rusty.events",
+                "scx.rusty.lat.count",
+                "scx.rusty.lat.avg",
+                "scx.rusty.lat.p50",
+                "scx.rusty.lat.p99",
+                "scx.rusty.lat.p999",
+                "scx.rusty.lat.max",
+            ]
+        );
+        assert!(lines[0].starts_with("scx.rusty.events 10 "));
+        assert!(lines[1].starts_with("scx.rusty.lat.count 4 "));
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::Result;
+use log::warn;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+fn flatten_into(prefix: &str, value: &Value, out: &mut BTreeMap<String, Value>) {
+    let join = |key: &str| match prefix.is_empty() {
+        true => key.to_string(),
+        false => format!("{}.{}", prefix, key),
+    };
+
+    match value {
+        Value::Object(obj) => {
+            for (key, val) in obj.iter() {
+                flatten_into(&join(key), val, out);
+            }
+        }
+        Value::Array(arr) => {
+            for (idx, val) in arr.iter().enumerate() {
+                flatten_into(&join(&idx.to_string()), val, out);
+            }
+        }
+        v => {
+            out.insert(prefix.to_string(), v.clone());
+        }
+    }
+}
+
+/// Flatten `value` into a map from dot-separated field paths, e.g.
+/// "doms_dict.0.events", to the leaf values.
+pub fn flatten_stats(value: &Value) -> BTreeMap<String, Value> {
+    let mut out = BTreeMap::new();
+    flatten_into("", value, &mut out);
+    out
+}
+
+fn csv_field(val: &Value) -> String {
+    let s = match val {
+        Value::Null => return "".into(),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+    match s.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s,
+    }
+}
+
+type SegmentOpener<W> = Box<dyn FnMut(usize) -> Result<W>>;
+
+/// Writes stats snapshots as CSV rows for offline analysis, e.g. with
+/// pandas or Polars. Each row starts with the "timestamp" column, seconds
+/// since the UNIX epoch, followed by a column per flattened field.
+///
+/// The columns are determined by the first snapshot. Fields which are
+/// missing in later snapshots are left empty. As a CSV header can't be
+/// extended once written, fields which appear later, e.g. new dict keys,
+/// are handled depending on how the writer was created:
+///
+/// - `StatsCsvWriter::new()` drops them with a warning.
+///
+/// - `StatsCsvWriter::segmented()` starts a new segment, e.g. a new file,
+///   whose header has the columns of the previous segment plus the new
+///   fields. The segments can be concatenated, e.g. with
+///   `pandas.concat(map(pandas.read_csv, files))`.
+///
+/// Use the "fields" request argument to limit the recorded fields.
+pub struct StatsCsvWriter<W: Write> {
+    w: W,
+    open_segment: Option<SegmentOpener<W>>,
+    nr_segments: usize,
+    columns: Option<Vec<String>>,
+    warned: bool,
+}
+
+impl<W: Write> StatsCsvWriter<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            open_segment: None,
+            nr_segments: 1,
+            columns: None,
+            warned: false,
+        }
+    }
+
+    /// Create a writer which writes to the segments opened by `open`. It's
+    /// called with 0 for the first segment and with the next index each
+    /// time new fields appear.
+    pub fn segmented(mut open: impl FnMut(usize) -> Result<W> + 'static) -> Result<Self> {
+        Ok(Self {
+            w: open(0)?,
+            open_segment: Some(Box::new(open)),
+            nr_segments: 1,
+            columns: None,
+            warned: false,
+        })
+    }
+
+    /// The number of segments written so far, always 1 for writers created
+    /// with `StatsCsvWriter::new()`.
+    pub fn nr_segments(&self) -> usize {
+        self.nr_segments
+    }
+
+    fn write_header(&mut self, columns: Vec<String>) -> Result<()> {
+        let header: Vec<String> = std::iter::once("timestamp".to_string())
+            .chain(columns.iter().map(|c| csv_field(&Value::String(c.clone()))))
+            .collect();
+        writeln!(self.w, "{}", header.join(","))?;
+        self.columns = Some(columns);
+        Ok(())
+    }
+
+    /// Append the snapshot `value` taken at `at` seconds since the UNIX
+    /// epoch. The header is written along with the first snapshot of each
+    /// segment.
+    pub fn write(&mut self, at: f64, value: &Value) -> Result<()> {
+        let flat = flatten_stats(value);
+
+        match self.columns.as_ref() {
+            None => self.write_header(flat.keys().cloned().collect())?,
+            Some(columns) if flat.keys().any(|k| columns.binary_search(k).is_err()) => {
+                match self.open_segment.as_mut() {
+                    Some(open) => {
+                        let mut columns = columns.clone();
+                        columns.extend(flat.keys().cloned());
+                        columns.sort();
+                        columns.dedup();
+
+                        self.w.flush()?;
+                        self.w = open(self.nr_segments)?;
+                        self.nr_segments += 1;
+                        self.write_header(columns)?;
+                    }
+                    None if !self.warned => {
+                        warn!(
+                            "new stats fields appeared after the CSV header was written, dropping"
+                        );
+                        self.warned = true;
+                    }
+                    None => {}
+                }
+            }
+            Some(_) => {}
+        }
+        let columns = self.columns.as_ref().unwrap();
+
+        let row: Vec<String> = std::iter::once(format!("{:.6}", at))
+            .chain(
+                columns
+                    .iter()
+                    .map(|c| flat.get(c).map(csv_field).unwrap_or_default()),
+            )
+            .collect();
+        writeln!(self.w, "{}", row.join(","))?;
+        self.w.flush()?;
+        Ok(())
+    }
+
+    /// Returns the current segment.
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StatsCsvWriter;
+    use serde_json::json;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Segment(Rc<RefCell<Vec<u8>>>);
+
+    impl std::io::Write for Segment {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_csv_new_fields_dropped() {
+        let mut writer = StatsCsvWriter::new(vec![]);
+        writer.write(1.0, &json!({"a": 1, "d": {"0": 2}})).unwrap();
+        writer.write(2.0, &json!({"a": 3, "d": {"1": 4}})).unwrap();
+        assert_eq!(writer.nr_segments(), 1);
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "timestamp,a,d.0\n1.000000,1,2\n2.000000,3,\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_new_fields_segmented() {
+        let segments: Rc<RefCell<Vec<Segment>>> = Default::default();
+        let opened = segments.clone();
+        let mut writer = StatsCsvWriter::segmented(move |idx| {
+            assert_eq!(idx, opened.borrow().len());
+            let seg = Segment::default();
+            opened.borrow_mut().push(seg.clone());
+            Ok(seg)
+        })
+        .unwrap();
+
+        writer.write(1.0, &json!({"a": 1, "d": {"0": 2}})).unwrap();
+        writer.write(2.0, &json!({"a": 3, "d": {"0": 4}})).unwrap();
+        writer
+            .write(3.0, &json!({"a": 5, "d": {"1": "x,y"}}))
+            .unwrap();
+        writer.write(4.0, &json!({"a": 6, "d": {"0": 7}})).unwrap();
+        assert_eq!(writer.nr_segments(), 2);
+
+        let segments: Vec<String> = segments
+            .borrow()
+            .iter()
+            .map(|seg| String::from_utf8(seg.0.borrow().clone()).unwrap())
+            .collect();
+        assert_eq!(
+            segments,
+            vec![
+                "timestamp,a,d.0\n1.000000,1,2\n2.000000,3,4\n",
+                "timestamp,a,d.0,d.1\n3.000000,5,,\"x,y\"\n4.000000,6,7,\n",
+            ]
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use crate::periodic::{run_periodic, LazyStatsClient};
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A retained stats value along with the time it was read at in seconds
+/// since the UNIX epoch. This is the element type of the "stats_query"
+/// response.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub at: f64,
+    pub value: Value,
+}
+
+/// Ring of the top-level stats snapshots taken during the last `period`.
+pub(crate) struct StatsRetention {
+    period: Duration,
+    snapshots: VecDeque<StatsSnapshot>,
+}
+
+fn unix_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Convert a dot-separated field path, e.g. "doms_dict.0.events", into a
+/// JSON pointer. Paths starting with '/' are taken as JSON pointers as-is.
+fn field_pointer(field: &str) -> String {
+    match field.starts_with('/') {
+        true => field.to_string(),
+        false => field
+            .split('.')
+            .map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1")))
+            .collect(),
+    }
+}
+
+impl StatsRetention {
+    pub(crate) fn new(period: Duration) -> Self {
+        Self {
+            period,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, value: Value) {
+        self.push_at(unix_secs(), value)
+    }
+
+    fn push_at(&mut self, at: f64, value: Value) {
+        let oldest = at - self.period.as_secs_f64();
+        while self.snapshots.front().is_some_and(|s| s.at < oldest) {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(StatsSnapshot { at, value });
+    }
+
+    /// Handle a "stats_query" request. All arguments are optional:
+    ///
+    /// - "field": Path to the field to report, either dot-separated or a JSON
+    ///   pointer. The whole snapshots are reported if not specified.
+    ///
+    /// - "from" and "to": Time range in seconds since the UNIX epoch.
+    pub(crate) fn query(&self, args: &BTreeMap<String, String>) -> Result<Vec<StatsSnapshot>> {
+        let parse_time = |key: &str| -> Result<Option<f64>> {
+            match args.get(key) {
+                Some(v) => {
+                    Ok(Some(v.parse::<f64>().map_err(|e| {
+                        anyhow!("invalid {:?} {:?} ({})", key, v, e)
+                    })?))
+                }
+                None => Ok(None),
+            }
+        };
+        let from = parse_time("from")?.unwrap_or(f64::MIN);
+        let to = parse_time("to")?.unwrap_or(f64::MAX);
+        let pointer = args.get("field").map(|v| field_pointer(v));
+
+        Ok(self
+            .snapshots
+            .iter()
+            .filter(|s| s.at >= from && s.at <= to)
+            .filter_map(|s| {
+                let value = match &pointer {
+                    Some(ptr) => s.value.pointer(ptr)?.clone(),
+                    None => s.value.clone(),
+                };
+                Some(StatsSnapshot { at: s.at, value })
+            })
+            .collect())
+    }
+}
+
+/// Periodically reads the top-level stats over the UNIX domain socket and
+/// records them in the StatsRetention. Like the exporters, the recorder is
+/// a single long-lived client of the StatsServer.
+pub(crate) struct StatsRecorder {
+    retention: Arc<Mutex<StatsRetention>>,
+    interval: Duration,
+    exit: Arc<AtomicBool>,
+    client: LazyStatsClient,
+}
+
+impl StatsRecorder {
+    pub(crate) fn launch(
+        retention: Arc<Mutex<StatsRetention>>,
+        interval: Duration,
+        stats_path: &Path,
+        exit: Arc<AtomicBool>,
+    ) {
+        let recorder = Self {
+            retention,
+            interval,
+            exit,
+            client: LazyStatsClient::new(stats_path),
+        };
+
+        spawn(move || recorder.run());
+    }
+
+    fn record(&mut self) -> Result<()> {
+        let stats: Value = self.client.with(|c| c.request("stats", vec![]))?;
+        self.retention.lock().unwrap().push(stats);
+        Ok(())
+    }
+
+    fn run(mut self) {
+        let exit = self.exit.clone();
+        run_periodic::<()>(Instant::now(), self.interval, &exit, || {
+            if let Err(e) = self.record() {
+                warn!("failed to record stats ({:?})", e);
+            }
+            ControlFlow::Continue(())
+        });
+        debug!("stats recorder exiting");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn args(kvs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        kvs.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn retention() -> StatsRetention {
+        let mut ret = StatsRetention::new(Duration::from_secs(60));
+        for at in [100, 110, 120] {
+            ret.push_at(
+                at as f64,
+                json!({"at": at, "doms_dict": {"0": {"events": at / 10}}, "a/b": at}),
+            );
+        }
+        ret
+    }
+
+    fn ats(snaps: &[StatsSnapshot]) -> Vec<f64> {
+        snaps.iter().map(|s| s.at).collect()
+    }
+
+    #[test]
+    fn test_query_range() {
+        let ret = retention();
+        assert_eq!(
+            ats(&ret.query(&args(&[])).unwrap()),
+            vec![100.0, 110.0, 120.0]
+        );
+        assert_eq!(
+            ats(&ret.query(&args(&[("from", "110")])).unwrap()),
+            vec![110.0, 120.0]
+        );
+        assert_eq!(
+            ats(&ret.query(&args(&[("to", "110")])).unwrap()),
+            vec![100.0, 110.0]
+        );
+        assert_eq!(
+            ats(&ret.query(&args(&[("from", "105"), ("to", "115")])).unwrap()),
+            vec![110.0]
+        );
+        assert!(ret.query(&args(&[("from", "130")])).unwrap().is_empty());
+        assert!(ret.query(&args(&[("from", "soon")])).is_err());
+        assert!(ret.query(&args(&[("to", "")])).is_err());
+    }
+
+    #[test]
+    fn test_query_field() {
+        let ret = retention();
+        let values = |field: &str| -> Vec<Value> {
+            ret.query(&args(&[("field", field)]))
+                .unwrap()
+                .into_iter()
+                .map(|s| s.value)
+                .collect()
+        };
+
+        assert_eq!(values("at"), vec![json!(100), json!(110), json!(120)]);
+        assert_eq!(
+            values("doms_dict.0.events"),
+            vec![json!(10), json!(11), json!(12)]
+        );
+        assert_eq!(values("/doms_dict/0/events"), values("doms_dict.0.events"));
+        // '/' in dot-separated paths is part of the key.
+        assert_eq!(values("a/b"), vec![json!(100), json!(110), json!(120)]);
+        assert_eq!(values("doms_dict.0"), values("/doms_dict/0"));
+        // Snapshots without the field are skipped.
+        assert!(values("doms_dict.1.events").is_empty());
+        assert!(values("nope").is_empty());
+
+        let snaps = ret
+            .query(&args(&[("field", "at"), ("from", "115")]))
+            .unwrap();
+        assert_eq!(ats(&snaps), vec![120.0]);
+        assert_eq!(snaps[0].value, json!(120));
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut ret = retention();
+
+        // 100 is exactly a period before and still kept.
+        ret.push_at(160.0, json!({}));
+        assert_eq!(
+            ats(&ret.query(&args(&[])).unwrap()),
+            vec![100.0, 110.0, 120.0, 160.0]
+        );
+
+        ret.push_at(175.0, json!({}));
+        assert_eq!(
+            ats(&ret.query(&args(&[])).unwrap()),
+            vec![120.0, 160.0, 175.0]
+        );
+
+        ret.push_at(1000.0, json!({}));
+        assert_eq!(ats(&ret.query(&args(&[])).unwrap()), vec![1000.0]);
+    }
+}
@@ -1,17 +1,32 @@
-use crate::StatsClient;
+use crate::acl::{PeerCred, StatsAcl};
+use crate::counters::{StatsCounterMode, StatsCounters};
+use crate::dims::StatsDims;
+use crate::handshake::set_schema_hashes;
+use crate::journal::JournalExporter;
+use crate::otlp::OtlpExporter;
+use crate::periodic::run_periodic;
+use crate::prometheus::PrometheusExporter;
+use crate::push::StatsPusher;
+use crate::retention::{StatsRecorder, StatsRetention};
+use crate::transport::{StatsListener, StatsStream};
 use crate::{Meta, StatsData, StatsKind, StatsMeta};
+use crate::{StatsAddr, StatsClient, StatsEncoding, StatsFilter, StatsHello, STATS_PROTO_VERSION};
 use anyhow::{anyhow, bail, Context, Result};
 use crossbeam::channel::{unbounded, Receiver, RecvError, Select, Sender};
 use log::{debug, error, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::{BTreeMap, BTreeSet};
-use std::io::{BufRead, BufReader, Write};
-use std::os::unix::net::{UnixListener, UnixStream};
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::ops::ControlFlow;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+const SUBSCRIBE_MIN_INTV_MS: u64 = 10;
 
 pub trait StatsReader<Req, Res>:
     FnMut(&BTreeMap<String, String>, (&Sender<Req>, &Receiver<Res>)) -> Result<Value>
@@ -80,12 +95,18 @@ struct StatsOpenOps<Req, Res> {
             ChannelPair<Req, Res>,
         ),
     >,
+    // Previous snapshots of the targets read with the "counters" argument.
+    counters: BTreeMap<String, StatsCounters>,
+    // Previous responses of the targets read with "dims" set to "delta".
+    dims: BTreeMap<String, StatsDims>,
 }
 
 impl<Req, Res> StatsOpenOps<Req, Res> {
     fn new() -> Self {
         Self {
             map: BTreeMap::new(),
+            counters: BTreeMap::new(),
+            dims: BTreeMap::new(),
         }
     }
 }
@@ -122,6 +143,19 @@ pub struct StatsResponse {
     pub args: BTreeMap<String, Value>,
 }
 
+impl StatsResponse {
+    /// Convert into a Value without copying the response.
+    pub(crate) fn into_value(self) -> Value {
+        let mut obj = serde_json::Map::new();
+        obj.insert("errno".into(), self.errno.into());
+        obj.insert(
+            "args".into(),
+            Value::Object(self.args.into_iter().collect()),
+        );
+        Value::Object(obj)
+    }
+}
+
 pub struct StatsErrno(pub i32);
 
 impl std::fmt::Display for StatsErrno {
@@ -166,6 +200,8 @@ impl<Req, Res> Clone for ChannelPair<Req, Res> {
     }
 }
 
+type StatsControl<Req, Res> = Arc<Mutex<Box<dyn StatsReaderSend<Req, Res>>>>;
+
 pub struct StatsServerData<Req, Res>
 where
     Req: Send + 'static,
@@ -174,6 +210,10 @@ where
     top: Option<String>,
     meta: BTreeMap<String, StatsMeta>,
     ops: BTreeMap<String, Arc<Mutex<StatsOps<Req, Res>>>>,
+    retention: Option<Arc<Mutex<StatsRetention>>>,
+    controls: BTreeMap<String, StatsControl<Req, Res>>,
+    acl: StatsAcl,
+    req_acls: BTreeMap<String, StatsAcl>,
 }
 
 impl<Req, Res> StatsServerData<Req, Res>
@@ -186,6 +226,10 @@ where
             top: None,
             meta: BTreeMap::new(),
             ops: BTreeMap::new(),
+            retention: None,
+            controls: BTreeMap::new(),
+            acl: StatsAcl::default(),
+            req_acls: BTreeMap::new(),
         }
     }
 
@@ -218,6 +262,22 @@ where
         self.add_ops(name, ops)
     }
 
+    /// Add the control request `req`, e.g. to change the scheduler's
+    /// settings. Unlike the stats targets, `handle` is only called by
+    /// requests named `req` and not by "stats" or "stats_subscribe". Only
+    /// root and the server's own UID are allowed by default, which can be
+    /// extended with `StatsServer::restrict_req()`. Remote peers are always
+    /// denied.
+    pub fn add_control(mut self, req: &str, handle: Box<dyn StatsReaderSend<Req, Res>>) -> Self {
+        self.controls
+            .insert(req.to_string(), Arc::new(Mutex::new(handle)));
+        self.req_acls
+            .entry(req.to_string())
+            .or_default()
+            .allow_uids(&[0]);
+        self
+    }
+
     fn visit_meta_inner(
         &self,
         name: &str,
@@ -241,7 +301,8 @@ where
 
         for (fname, field) in m.fields.iter() {
             match &field.data {
-                StatsData::Array(StatsKind::Struct(inner)) => {
+                StatsData::Datum(StatsKind::Struct(inner))
+                | StatsData::Array(StatsKind::Struct(inner)) => {
                     self.visit_meta_inner(inner, visit, nesting, visited)?
                 }
                 StatsData::Dict {
@@ -335,6 +396,9 @@ where
                 if let Some(desc) = &f.attrs.desc {
                     write!(w, " : {}", desc)?;
                 }
+                if let Some(unit) = &f.attrs.unit {
+                    write!(w, " [{}]", unit)?;
+                }
                 writeln!(w, "")?;
             }
             Ok(())
@@ -347,7 +411,7 @@ where
     Req: Send + 'static,
     Res: Send + 'static,
 {
-    listener: UnixListener,
+    listeners: Vec<StatsListener>,
     data: Arc<Mutex<StatsServerData<Req, Res>>>,
     inner_ch: ChannelPair<Req, Res>,
     exit: Arc<AtomicBool>,
@@ -359,13 +423,13 @@ where
     Res: Send + 'static,
 {
     fn new(
-        listener: UnixListener,
+        listeners: Vec<StatsListener>,
         data: Arc<Mutex<StatsServerData<Req, Res>>>,
         inner_ch: ChannelPair<Req, Res>,
         exit: Arc<AtomicBool>,
     ) -> Self {
         Self {
-            listener,
+            listeners,
             data,
             inner_ch,
             exit,
@@ -384,82 +448,296 @@ where
         })
     }
 
+    fn read_stats(
+        req: &StatsRequest,
+        data: &Arc<Mutex<StatsServerData<Req, Res>>>,
+        ch: &ChannelPair<Req, Res>,
+        open_ops: &mut StatsOpenOps<Req, Res>,
+    ) -> Result<Value> {
+        let target = match req.args.get("target") {
+            Some(v) => v,
+            None => "top",
+        };
+
+        let ops = match data.lock().unwrap().ops.get(target) {
+            Some(v) => v.clone(),
+            None => {
+                Err(anyhow!("unknown stat target {:?}", req).context(StatsErrno(libc::EINVAL)))?
+            }
+        };
+
+        if !open_ops.map.contains_key(target) {
+            let read = (ops.lock().unwrap().open)((&ch.req, &ch.res))?;
+            open_ops
+                .map
+                .insert(target.into(), (ops.clone(), read, ch.clone()));
+        }
+
+        let read = &mut open_ops.map.get_mut(target).unwrap().1;
+
+        let mut resp = read(&req.args, (&ch.req, &ch.res))?;
+
+        if let Some(mode) = StatsCounterMode::from_args(&req.args)? {
+            let data = data.lock().unwrap();
+            let top = Self::target_meta_name(&data, target, "counters")?;
+            let counters = open_ops.counters.entry(target.into()).or_default();
+            resp = counters.update(&data.meta, top, mode, resp, Instant::now())?;
+        }
+
+        // Only send the requested fields.
+        if let Some(filter) = StatsFilter::from_args(&req.args) {
+            resp = filter.apply(&resp).unwrap_or(Value::Null);
+        }
+
+        if StatsDims::delta_from_args(&req.args)? {
+            let data = data.lock().unwrap();
+            let top = Self::target_meta_name(&data, target, "dims")?;
+            let dims = open_ops.dims.entry(target.into()).or_default();
+            resp = dims.encode(&data.meta, top, resp)?;
+        }
+
+        Ok(resp)
+    }
+
+    /// The stats struct of `target`. Only known for "top" which is
+    /// required by the arguments which need the metadata, e.g. "counters".
+    fn target_meta_name<'a>(
+        data: &'a StatsServerData<Req, Res>,
+        target: &str,
+        arg: &str,
+    ) -> Result<&'a str> {
+        match (target, data.top.as_ref()) {
+            ("top", Some(v)) => Ok(v),
+            _ => Err(anyhow!("{} not supported for target {:?}", arg, target)
+                .context(StatsErrno(libc::EINVAL))),
+        }
+    }
+
     fn handle_request(
-        line: String,
+        req: StatsRequest,
         data: &Arc<Mutex<StatsServerData<Req, Res>>>,
         ch: &ChannelPair<Req, Res>,
         open_ops: &mut StatsOpenOps<Req, Res>,
+        sub: &mut Option<(StatsRequest, Duration)>,
+        switch_enc: &mut Option<StatsEncoding>,
+        cred: Option<&PeerCred>,
     ) -> Result<StatsResponse> {
-        let req: StatsRequest = serde_json::from_str(&line)?;
+        if let Some(acl) = data.lock().unwrap().req_acls.get(&req.req) {
+            if !acl.allows(cred) {
+                let who = match cred {
+                    Some(v) => format!("uid {}", v.uid),
+                    None => "remote peers".into(),
+                };
+                Err(anyhow!("{:?} not allowed for {}", &req.req, who)
+                    .context(StatsErrno(libc::EACCES)))?;
+            }
+        }
 
         match req.req.as_str() {
-            "stats" => {
-                let target = match req.args.get("target") {
-                    Some(v) => v,
-                    None => "top",
+            "hello" => {
+                let data = data.lock().unwrap();
+                let mut caps: Vec<String> = ["hello", "stats", "stats_meta", "stats_subscribe"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect();
+                if data.retention.is_some() {
+                    caps.push("stats_query".into());
+                }
+                caps.extend(data.controls.keys().cloned());
+                caps.push("fields".into());
+                caps.push("counters".into());
+                caps.push("dims".into());
+                caps.push("encoding".into());
+
+                // Unknown encodings are ignored and the connection stays in
+                // JSON, so that clients can ask for newer encodings without
+                // worrying about older servers.
+                let encoding = req
+                    .args
+                    .get("encoding")
+                    .and_then(|v| StatsEncoding::parse(v));
+                *switch_enc = encoding;
+
+                let schema_hash = data
+                    .top
+                    .as_ref()
+                    .and_then(|top| data.meta.get(top))
+                    .and_then(|m| m.schema_hash.clone());
+
+                Self::build_resp(
+                    0,
+                    &StatsHello {
+                        version: STATS_PROTO_VERSION,
+                        caps,
+                        schema_hash,
+                        encoding: encoding.map(|v| v.name().into()),
+                    },
+                )
+            }
+            "stats" => Self::build_resp(0, &Self::read_stats(&req, data, ch, open_ops)?),
+            "stats_subscribe" => {
+                let intv_ms = match req.args.get("interval_ms") {
+                    Some(v) => v.parse::<u64>().map_err(|e| {
+                        anyhow!("invalid interval_ms {:?} ({})", v, e)
+                            .context(StatsErrno(libc::EINVAL))
+                    })?,
+                    None => 1000,
                 };
-
-                let ops =
-                    match data.lock().unwrap().ops.get(target) {
-                        Some(v) => v.clone(),
-                        None => Err(anyhow!("unknown stat target {:?}", req)
-                            .context(StatsErrno(libc::EINVAL)))?,
-                    };
-
-                if !open_ops.map.contains_key(target) {
-                    let read = (ops.lock().unwrap().open)((&ch.req, &ch.res))?;
-                    open_ops
-                        .map
-                        .insert(target.into(), (ops.clone(), read, ch.clone()));
+                if intv_ms < SUBSCRIBE_MIN_INTV_MS {
+                    Err(anyhow!("interval_ms must be >= {}", SUBSCRIBE_MIN_INTV_MS)
+                        .context(StatsErrno(libc::EINVAL)))?;
                 }
 
-                let read = &mut open_ops.map.get_mut(target).unwrap().1;
-
-                let resp = read(&req.args, (&ch.req, &ch.res))?;
-
+                let resp = Self::read_stats(&req, data, ch, open_ops)?;
+                *sub = Some((req, Duration::from_millis(intv_ms)));
                 Self::build_resp(0, &resp)
             }
             "stats_meta" => Ok(Self::build_resp(0, &data.lock().unwrap().meta)?),
-            req => Err(anyhow!("unknown command {:?}", req).context(StatsErrno(libc::EINVAL)))?,
+            "stats_query" => {
+                let retention = match data.lock().unwrap().retention.as_ref() {
+                    Some(v) => v.clone(),
+                    None => Err(anyhow!("stats retention not enabled")
+                        .context(StatsErrno(libc::EOPNOTSUPP)))?,
+                };
+                let resp = retention.lock().unwrap().query(&req.args)?;
+                Self::build_resp(0, &resp)
+            }
+            name => {
+                let handle = match data.lock().unwrap().controls.get(name) {
+                    Some(v) => v.clone(),
+                    None => {
+                        Err(anyhow!("unknown command {:?}", name).context(StatsErrno(libc::EINVAL)))?
+                    }
+                };
+                let resp = handle.lock().unwrap()(&req.args, (&ch.req, &ch.res))?;
+                Self::build_resp(0, &resp)
+            }
         }
     }
 
     fn serve(
-        mut stream: UnixStream,
+        mut stream: StatsStream,
         data: Arc<Mutex<StatsServerData<Req, Res>>>,
         inner_ch: ChannelPair<Req, Res>,
         exit: Arc<AtomicBool>,
     ) -> Result<()> {
+        let cred = stream.peer_cred()?;
+        if !data.lock().unwrap().acl.allows(cred.as_ref()) {
+            match &cred {
+                Some(v) => warn!(
+                    "rejecting stats connection from pid {} uid {}",
+                    v.pid, v.uid
+                ),
+                None => warn!("rejecting remote stats connection"),
+            }
+            let resp = Err(anyhow!("access denied").context(StatsErrno(libc::EACCES)));
+            return Self::write_resp(&mut stream, StatsEncoding::Json, resp);
+        }
+
         let mut stream_reader = BufReader::new(stream.try_clone()?);
         let mut open_ops = StatsOpenOps::new();
+        let mut sub = None;
+        let mut enc = StatsEncoding::Json;
+        let mut switch_enc = None;
 
         loop {
-            let mut line = String::new();
-            stream_reader.read_line(&mut line)?;
-            if line.is_empty() {
-                return Ok(());
-            }
+            let req = match enc.read::<StatsRequest, _>(&mut stream_reader)? {
+                Some(v) => v,
+                None => return Ok(()),
+            };
             if exit.load(Ordering::Relaxed) {
                 debug!("server exiting due to exit");
                 return Ok(());
             }
 
-            let resp = match Self::handle_request(line, &data, &inner_ch, &mut open_ops) {
-                Ok(v) => v,
-                Err(e) => {
-                    let errno = match e.downcast_ref::<StatsErrno>() {
-                        Some(e) if e.0 != 0 => e.0,
-                        _ => libc::EINVAL,
-                    };
-                    Self::build_resp(errno, &format!("{:?}", &e))?
-                }
-            };
+            let resp = req.and_then(|req| {
+                Self::handle_request(
+                    req,
+                    &data,
+                    &inner_ch,
+                    &mut open_ops,
+                    &mut sub,
+                    &mut switch_enc,
+                    cred.as_ref(),
+                )
+            });
+            Self::write_resp(&mut stream, enc, resp)?;
 
-            let output = serde_json::to_string(&resp)? + "\n";
-            stream.write_all(output.as_bytes())?;
+            // The response to "hello" is in the old encoding.
+            if let Some(v) = switch_enc.take() {
+                enc = v;
+            }
+
+            if let Some(sub) = sub.take() {
+                return Self::stream_stats(
+                    stream,
+                    enc,
+                    sub,
+                    &data,
+                    &inner_ch,
+                    &mut open_ops,
+                    &exit,
+                );
+            }
         }
     }
 
+    fn write_resp(
+        stream: &mut StatsStream,
+        enc: StatsEncoding,
+        resp: Result<StatsResponse>,
+    ) -> Result<()> {
+        let resp = match resp {
+            Ok(v) => v,
+            Err(e) => {
+                let errno = match e.downcast_ref::<StatsErrno>() {
+                    Some(e) if e.0 != 0 => e.0,
+                    _ => libc::EINVAL,
+                };
+                Self::build_resp(errno, &format!("{:?}", &e))?
+            }
+        };
+
+        enc.write(stream, &resp.into_value())
+    }
+
+    /// Keep sending the stats requested by "stats_subscribe" every `intv`
+    /// until the client closes the connection.
+    fn stream_stats(
+        mut stream: StatsStream,
+        enc: StatsEncoding,
+        (req, intv): (StatsRequest, Duration),
+        data: &Arc<Mutex<StatsServerData<Req, Res>>>,
+        ch: &ChannelPair<Req, Res>,
+        open_ops: &mut StatsOpenOps<Req, Res>,
+        exit: &Arc<AtomicBool>,
+    ) -> Result<()> {
+        let res = run_periodic(Instant::now() + intv, intv, exit, || {
+            let resp =
+                Self::read_stats(&req, data, ch, open_ops).and_then(|v| Self::build_resp(0, &v));
+            match Self::write_resp(&mut stream, enc, resp) {
+                Ok(()) => ControlFlow::Continue(()),
+                Err(e) => match e.downcast_ref::<std::io::Error>() {
+                    Some(e)
+                        if [
+                            std::io::ErrorKind::BrokenPipe,
+                            std::io::ErrorKind::ConnectionReset,
+                        ]
+                        .contains(&e.kind()) =>
+                    {
+                        debug!("subscriber disconnected");
+                        ControlFlow::Break(Ok(()))
+                    }
+                    _ => ControlFlow::Break(Err(e)),
+                },
+            }
+        });
+        res.unwrap_or_else(|| {
+            debug!("subscription exiting due to exit");
+            Ok(())
+        })
+    }
+
     fn proxy(inner_ch: ChannelPair<Req, Res>, add_res: Receiver<ChannelPair<Res, Req>>) {
         let mut chs_cursor = 0;
         let mut chs = BTreeMap::<u64, ChannelPair<Res, Req>>::new();
@@ -547,31 +825,34 @@ where
 
         spawn(move || Self::proxy(inner_ch_copy, add_res));
 
-        for stream in self.listener.incoming() {
-            if self.exit.load(Ordering::Relaxed) {
-                debug!("listener exiting");
-                break;
-            }
-            match stream {
-                Ok(stream) => {
-                    let data = self.data.clone();
-                    let exit = self.exit.clone();
-
-                    let (req_pair, res_pair) = ChannelPair::<Req, Res>::bidi();
-                    match add_req.send(res_pair) {
-                        Ok(()) => debug!("sent new channel to proxy"),
-                        Err(e) => warn!("StatsServer::proxy() failed ({})", e),
-                    }
-
-                    spawn(move || {
-                        if let Err(e) = Self::serve(stream, data, req_pair, exit) {
-                            warn!("stat communication errored ({})", e);
+        // Poll in short steps so that exit is noticed promptly.
+        while !self.exit.load(Ordering::Relaxed) {
+            for stream in StatsListener::accept_any(&self.listeners, Duration::from_millis(100)) {
+                if self.exit.load(Ordering::Relaxed) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let data = self.data.clone();
+                        let exit = self.exit.clone();
+
+                        let (req_pair, res_pair) = ChannelPair::<Req, Res>::bidi();
+                        match add_req.send(res_pair) {
+                            Ok(()) => debug!("sent new channel to proxy"),
+                            Err(e) => warn!("StatsServer::proxy() failed ({})", e),
                         }
-                    });
+
+                        spawn(move || {
+                            if let Err(e) = Self::serve(stream, data, req_pair, exit) {
+                                warn!("stat communication errored ({})", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("failed to accept stat connection ({})", e),
                 }
-                Err(e) => warn!("failed to accept stat connection ({})", e),
             }
         }
+        debug!("listener exiting");
     }
 }
 
@@ -584,6 +865,19 @@ where
     sched_path: PathBuf,
     stats_path: PathBuf,
     path: Option<PathBuf>,
+    sched_name: Option<String>,
+    sched_version: Option<String>,
+    prometheus_addr: Option<String>,
+    prometheus_local_addr: Option<SocketAddr>,
+    otlp_endpoint: Option<String>,
+    otlp_interval: Duration,
+    retention_period: Option<Duration>,
+    retention_interval: Duration,
+    push_target: Option<String>,
+    push_prefix: Option<String>,
+    push_interval: Duration,
+    journal_interval: Option<Duration>,
+    addrs: Vec<StatsAddr>,
 
     data: Arc<Mutex<StatsServerData<Req, Res>>>,
 
@@ -605,6 +899,19 @@ where
             sched_path: PathBuf::from("root"),
             stats_path: PathBuf::from("stats"),
             path: None,
+            sched_name: None,
+            sched_version: None,
+            prometheus_addr: None,
+            prometheus_local_addr: None,
+            otlp_endpoint: None,
+            otlp_interval: Duration::from_secs(10),
+            retention_period: None,
+            retention_interval: Duration::from_secs(1),
+            push_target: None,
+            push_prefix: None,
+            push_interval: Duration::from_secs(10),
+            journal_interval: None,
+            addrs: vec![],
             data: Arc::new(Mutex::new(data)),
             outer_ch: och,
             inner_ch: Some(ich),
@@ -632,8 +939,191 @@ where
         self
     }
 
+    /// Also serve the stats on `addr`, either TCP or vsock, e.g. to allow
+    /// access from outside a container or from the host of a VM. The
+    /// addresses can also be specified in the comma-separated
+    /// `SCX_STATS_LISTEN_ADDRS` environment variable, e.g.
+    /// "tcp:127.0.0.1:9091,vsock:any:9091".
+    ///
+    /// Other than the UNIX domain socket, TCP and vsock peers don't have
+    /// credentials and can't be restricted with `allow_uids()` and friends.
+    /// They're denied unless `allow_remote()` is called or
+    /// `SCX_STATS_ALLOW_REMOTE=1` is set, and the addresses aren't bound
+    /// otherwise.
+    pub fn add_listen_addr(mut self, addr: StatsAddr) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Allow peers connected over TCP and vsock. They can't be
+    /// authenticated, so anyone who can reach the listen addresses is
+    /// allowed regardless of `allow_uids()` and `allow_gids()`. Requests
+    /// restricted with `restrict_req()` stay denied. Only bind to trusted
+    /// networks.
+    pub fn allow_remote(self) -> Self {
+        self.data.lock().unwrap().acl.allow_remote();
+        self
+    }
+
+    /// Only allow connections from `uids`. Can be combined with
+    /// `allow_gids()`, in which case peers matching either are allowed. The
+    /// server's own UID is always allowed. If neither is set, everyone who
+    /// can access the socket is allowed.
+    pub fn allow_uids(self, uids: &[u32]) -> Self {
+        self.data.lock().unwrap().acl.allow_uids(uids);
+        self
+    }
+
+    /// Only allow connections from peers in `gids`, either as the primary
+    /// or a supplementary group. See `allow_uids()`.
+    pub fn allow_gids(self, gids: &[u32]) -> Self {
+        self.data.lock().unwrap().acl.allow_gids(gids);
+        self
+    }
+
+    /// Further restrict the request `req`, e.g. "stats_query", to `uids`
+    /// and `gids` on top of the connection-level allowlist. If both are
+    /// empty, only the server's own UID is allowed. Denied requests fail
+    /// with EACCES.
+    pub fn restrict_req(self, req: &str, uids: &[u32], gids: &[u32]) -> Self {
+        {
+            let mut data = self.data.lock().unwrap();
+            let acl = data.req_acls.entry(req.to_string()).or_default();
+            acl.allow_uids(uids);
+            acl.allow_gids(gids);
+        }
+        self
+    }
+
+    /// Set the scheduler name reported in the `scheduler` label of exported
+    /// metrics. Defaults to the executable name.
+    pub fn set_sched_name(mut self, name: &str) -> Self {
+        self.sched_name = Some(name.to_string());
+        self
+    }
+
+    /// Set the scheduler version reported in the `service.version` resource
+    /// attribute of OTLP exports.
+    pub fn set_sched_version(mut self, version: &str) -> Self {
+        self.sched_version = Some(version.to_string());
+        self
+    }
+
+    /// Serve the stats in the Prometheus text format over HTTP on `addr`,
+    /// e.g. "0.0.0.0:9100", at `/metrics`. If not set, the address is read
+    /// from the `SCX_STATS_PROMETHEUS_ADDR` environment variable. The
+    /// address the endpoint is bound to can be read with
+    /// `prometheus_addr()` after launch.
+    pub fn set_prometheus_addr(mut self, addr: &str) -> Self {
+        self.prometheus_addr = Some(addr.to_string());
+        self
+    }
+
+    pub fn prometheus_addr(&self) -> Option<SocketAddr> {
+        self.prometheus_local_addr
+    }
+
+    /// Push the stats to the OpenTelemetry collector at `endpoint`, e.g.
+    /// "http://localhost:4318", using OTLP/HTTP with the JSON encoding. If
+    /// not set, the endpoint is read from the `SCX_STATS_OTLP_ENDPOINT`
+    /// environment variable.
+    pub fn set_otlp_endpoint(mut self, endpoint: &str) -> Self {
+        self.otlp_endpoint = Some(endpoint.to_string());
+        self
+    }
+
+    /// Set the interval between OTLP exports. Defaults to 10 seconds.
+    pub fn set_otlp_interval(mut self, interval: Duration) -> Self {
+        self.otlp_interval = interval;
+        self
+    }
+
+    /// Push the stats to a statsd or graphite server. `target` is in the
+    /// form of "FORMAT[+TRANSPORT]://HOST:PORT" where FORMAT is either
+    /// "statsd" or "graphite" and TRANSPORT either "udp" or "tcp", e.g.
+    /// "statsd://localhost:8125" or "graphite+tcp://localhost:2003". statsd
+    /// defaults to UDP and graphite to TCP. If not set, the target is read
+    /// from the `SCX_STATS_PUSH_TARGET` environment variable.
+    pub fn set_push_target(mut self, target: &str) -> Self {
+        self.push_target = Some(target.to_string());
+        self
+    }
+
+    /// Set the dot-separated prefix of the pushed metric names. Defaults to
+    /// "scx.SCHED_NAME".
+    pub fn set_push_prefix(mut self, prefix: &str) -> Self {
+        self.push_prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Set the interval between pushes. Defaults to 10 seconds.
+    pub fn set_push_interval(mut self, interval: Duration) -> Self {
+        self.push_interval = interval;
+        self
+    }
+
+    /// Log the stats to the journal every `interval` as entries with
+    /// MESSAGE_ID=JOURNAL_MSGID_STATS. If not set, the interval is read in
+    /// milliseconds from the `SCX_STATS_JOURNAL_INTERVAL_MS` environment
+    /// variable.
+    pub fn set_journal_interval(mut self, interval: Duration) -> Self {
+        self.journal_interval = Some(interval);
+        self
+    }
+
+    /// Keep the top-level stats read during the last `period` in memory so
+    /// that clients can query the history with the "stats_query" request,
+    /// e.g. to draw graphs after attaching late.
+    pub fn set_retention(mut self, period: Duration) -> Self {
+        self.retention_period = Some(period);
+        self
+    }
+
+    /// Set the interval between the retained snapshots. Defaults to 1
+    /// second.
+    pub fn set_retention_interval(mut self, interval: Duration) -> Self {
+        self.retention_interval = interval;
+        self
+    }
+
+    fn sched_name(&self) -> String {
+        match &self.sched_name {
+            Some(v) => v.clone(),
+            None => std::env::current_exe()
+                .ok()
+                .and_then(|p| p.file_name().map(|v| v.to_string_lossy().to_string()))
+                .unwrap_or_else(|| "unknown".into()),
+        }
+    }
+
+    fn om_labels(&self) -> Vec<(String, String)> {
+        vec![("scheduler".into(), self.sched_name())]
+    }
+
+    fn otlp_resource(&self) -> Vec<(String, String)> {
+        let mut resource = vec![("service.name".into(), self.sched_name())];
+        if let Some(version) = &self.sched_version {
+            resource.push(("service.version".into(), version.clone()));
+        }
+
+        let mut buf = [0u8; 256];
+        // SAFETY: gethostname() NUL-terminates within the buffer on success.
+        if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } == 0 {
+            let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+            resource.push((
+                "host.name".into(),
+                String::from_utf8_lossy(&buf[..len]).to_string(),
+            ));
+        }
+        resource
+    }
+
     pub fn launch(mut self) -> Result<Self> {
-        self.data.lock().unwrap().verify_meta()?;
+        {
+            let mut data = self.data.lock().unwrap();
+            data.verify_meta()?;
+            set_schema_hashes(&mut data.meta)?;
+        }
 
         if self.path.is_none() {
             self.path = Some(self.base_path.join(&self.sched_path).join(&self.stats_path));
@@ -651,17 +1141,115 @@ where
             }
         }
 
-        let listener =
-            UnixListener::bind(path).with_context(|| format!("creating UNIX socket {:?}", path))?;
+        let mut listeners = vec![StatsListener::bind(&StatsAddr::Unix(path.into()))?];
+        let mut addrs = self.addrs.clone();
+        if let Ok(v) = std::env::var("SCX_STATS_LISTEN_ADDRS") {
+            for addr in v.split(',').filter(|v| !v.is_empty()) {
+                addrs.push(addr.parse()?);
+            }
+        }
+        if std::env::var("SCX_STATS_ALLOW_REMOTE").is_ok_and(|v| v == "1") {
+            self.data.lock().unwrap().acl.allow_remote();
+        }
+        let allow_remote = self.data.lock().unwrap().acl.allows_remote();
+        for addr in addrs.iter() {
+            if !allow_remote && !matches!(addr, StatsAddr::Unix(_)) {
+                warn!(
+                    "Not listening on {} as remote peers aren't allowed, see SCX_STATS_ALLOW_REMOTE",
+                    addr
+                );
+                continue;
+            }
+            listeners.push(StatsListener::bind(addr)?);
+        }
 
         let inner = StatsServerInner::new(
-            listener,
+            listeners,
             self.data.clone(),
             self.inner_ch.take().unwrap(),
             self.exit.clone(),
         );
 
         spawn(move || inner.listen());
+
+        if let Some(period) = self.retention_period {
+            if self.retention_interval.is_zero() {
+                bail!("stats retention interval must be positive");
+            }
+            let retention = Arc::new(Mutex::new(StatsRetention::new(period)));
+            self.data.lock().unwrap().retention = Some(retention.clone());
+            StatsRecorder::launch(
+                retention,
+                self.retention_interval,
+                self.path.as_ref().unwrap(),
+                self.exit.clone(),
+            );
+        }
+
+        let prometheus_addr = self
+            .prometheus_addr
+            .clone()
+            .or_else(|| std::env::var("SCX_STATS_PROMETHEUS_ADDR").ok());
+        if let Some(addr) = prometheus_addr {
+            let path = self.path.clone().unwrap();
+            self.prometheus_local_addr = Some(PrometheusExporter::launch(
+                &addr,
+                &path,
+                self.om_labels(),
+                self.exit.clone(),
+            )?);
+        }
+
+        let otlp_endpoint = self
+            .otlp_endpoint
+            .clone()
+            .or_else(|| std::env::var("SCX_STATS_OTLP_ENDPOINT").ok());
+        if let Some(endpoint) = otlp_endpoint {
+            OtlpExporter::launch(
+                &endpoint,
+                self.otlp_interval,
+                self.path.as_ref().unwrap(),
+                self.otlp_resource(),
+                self.exit.clone(),
+            )?;
+        }
+
+        let push_target = self
+            .push_target
+            .clone()
+            .or_else(|| std::env::var("SCX_STATS_PUSH_TARGET").ok());
+        if let Some(target) = push_target {
+            let prefix = match &self.push_prefix {
+                Some(v) => v.clone(),
+                None => format!("scx.{}", self.sched_name()),
+            };
+            StatsPusher::launch(
+                &target,
+                &prefix,
+                self.push_interval,
+                self.path.as_ref().unwrap(),
+                self.exit.clone(),
+            )?;
+        }
+
+        let journal_interval = match self.journal_interval {
+            Some(v) => Some(v),
+            None => match std::env::var("SCX_STATS_JOURNAL_INTERVAL_MS") {
+                Ok(v) => Some(Duration::from_millis(v.parse().with_context(|| {
+                    format!("invalid SCX_STATS_JOURNAL_INTERVAL_MS {:?}", &v)
+                })?)),
+                Err(_) => None,
+            },
+        };
+        if let Some(interval) = journal_interval {
+            JournalExporter::launch(
+                interval,
+                self.path.as_ref().unwrap(),
+                &self.sched_name(),
+                self.exit.clone(),
+            )?;
+        }
+
         Ok(self)
     }
 
@@ -680,6 +1268,9 @@ where
         if let Some(path) = self.path.as_ref() {
             let _ = StatsClient::new().set_path(path).connect();
         }
+        if let Some(addr) = self.prometheus_local_addr.as_ref() {
+            let _ = TcpStream::connect(addr);
+        }
     }
 }
 
@@ -695,3 +1286,52 @@ where
         Ok(serde_json::to_value(self)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{StatsClient, StatsServer, StatsServerData};
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_control_req() {
+        let path = std::env::temp_dir().join(format!("scx_stats_control_{}", std::process::id()));
+        let nr_set = Arc::new(AtomicU64::new(0));
+        let data = {
+            let nr_set = nr_set.clone();
+            StatsServerData::<(), ()>::new()
+                .add_stats("top", Box::new(|_, _| Ok(serde_json::json!({"a": 1}))))
+                .add_control(
+                    "set",
+                    Box::new(move |args, _| {
+                        nr_set.fetch_add(1, Ordering::Relaxed);
+                        Ok(serde_json::to_value(args)?)
+                    }),
+                )
+        };
+        let _server = StatsServer::<(), ()>::new(data)
+            .set_path(&path)
+            .launch()
+            .unwrap();
+
+        let mut client = StatsClient::new().set_path(&path).connect().unwrap();
+        let hello = client.hello().unwrap();
+        assert!(hello.caps.iter().any(|v| v == "set"));
+
+        // The server's own UID is allowed.
+        let resp: Value = client
+            .request("set", vec![("k".into(), "v".into())])
+            .unwrap();
+        assert_eq!(resp["k"], "v");
+        assert_eq!(nr_set.load(Ordering::Relaxed), 1);
+
+        // Controls aren't stats targets.
+        for req in ["stats", "stats_subscribe"] {
+            let res: anyhow::Result<Value> =
+                client.request(req, vec![("target".into(), "set".into())]);
+            assert!(res.is_err());
+        }
+        assert_eq!(nr_set.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::stats_unit_scale;
+use proc_macro2::Span;
 use quote::ToTokens;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
@@ -18,6 +20,10 @@ pub enum StatsKind {
     Float,
     #[serde(rename = "string")]
     String,
+    #[serde(rename = "histogram")]
+    Histogram,
+    #[serde(rename = "percentiles")]
+    Percentiles,
     #[serde(rename = "struct")]
     Struct(String),
 }
@@ -36,6 +42,16 @@ impl StatsKind {
                         _ => {}
                     }
                 }
+                // Also match the qualified paths, e.g. scx_stats::StatsHistogram.
+                if let Some(last) = path.segments.last() {
+                    if last.arguments.is_empty() {
+                        match last.ident.to_string().as_str() {
+                            "StatsHistogram" => return Ok(Self::Histogram),
+                            "StatsPercentiles" => return Ok(Self::Percentiles),
+                            _ => {}
+                        }
+                    }
+                }
                 let name = path.to_token_stream().to_string();
                 paths.insert(name.to_string(), path.clone());
                 return Ok(Self::Struct(name));
@@ -57,6 +73,8 @@ impl std::fmt::Display for StatsKind {
             Self::U64 => write!(f, "u64"),
             Self::Float => write!(f, "float"),
             Self::String => write!(f, "string"),
+            Self::Histogram => write!(f, "histogram"),
+            Self::Percentiles => write!(f, "percentiles"),
             Self::Struct(name) => write!(f, "{}", name),
         }
     }
@@ -176,6 +194,16 @@ impl StatsData {
         }
         Ok(Self::Datum(kind))
     }
+
+    pub fn is_struct_dict(&self) -> bool {
+        matches!(
+            self,
+            Self::Dict {
+                key: _,
+                datum: StatsKind::Struct(_)
+            }
+        )
+    }
 }
 
 impl std::fmt::Display for StatsData {
@@ -192,6 +220,10 @@ impl std::fmt::Display for StatsData {
 pub enum StatsAttr {
     Top,
     Desc(String),
+    LongDesc(String),
+    Unit(String),
+    DisplayUnit(String),
+    Dim(String),
     User(String, String),
 }
 
@@ -210,6 +242,22 @@ impl Parse for StatsAttrVec {
                     input.parse::<Token!(=)>()?;
                     attrs.push(StatsAttr::Desc(input.parse::<LitStr>()?.value()))
                 }
+                "long_desc" => {
+                    input.parse::<Token!(=)>()?;
+                    attrs.push(StatsAttr::LongDesc(input.parse::<LitStr>()?.value()))
+                }
+                "unit" => {
+                    input.parse::<Token!(=)>()?;
+                    attrs.push(StatsAttr::Unit(input.parse::<LitStr>()?.value()))
+                }
+                "display_unit" => {
+                    input.parse::<Token!(=)>()?;
+                    attrs.push(StatsAttr::DisplayUnit(input.parse::<LitStr>()?.value()))
+                }
+                "dim" => {
+                    input.parse::<Token!(=)>()?;
+                    attrs.push(StatsAttr::Dim(input.parse::<LitStr>()?.value()))
+                }
                 key if key.starts_with("_") => {
                     let val = match input.peek(Token!(=)) {
                         true => {
@@ -237,6 +285,20 @@ impl Parse for StatsAttrVec {
 pub struct StatsFieldAttrs {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub desc: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub long_desc: Option<String>,
+    /// Unit of the value, e.g. "ns", "B", "%" or "ops/s".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// Unit the value is preferably displayed in, e.g. "ms" for a "ns"
+    /// value. Must be convertible from `unit`, see `stats_unit_scale()`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_unit: Option<String>,
+    /// Marks a dict of structs as a dimension, e.g. "cgroup" for a dict
+    /// keyed by cgroup paths, which is delta encoded with the "dims"
+    /// request argument. See `StatsDims`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dim: Option<String>,
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub user: BTreeMap<String, String>,
 }
@@ -251,6 +313,10 @@ impl StatsFieldAttrs {
                 for elem in vec.attrs.into_iter() {
                     match elem {
                         StatsAttr::Desc(v) => fattrs.desc = Some(v),
+                        StatsAttr::LongDesc(v) => fattrs.long_desc = Some(v),
+                        StatsAttr::Unit(v) => fattrs.unit = Some(v),
+                        StatsAttr::DisplayUnit(v) => fattrs.display_unit = Some(v),
+                        StatsAttr::Dim(v) => fattrs.dim = Some(v),
                         StatsAttr::User(k, v) => {
                             fattrs.user.insert(k, v);
                         }
@@ -263,6 +329,21 @@ impl StatsFieldAttrs {
             }
         }
 
+        if let Some(display_unit) = &fattrs.display_unit {
+            let span = attrs
+                .first()
+                .map(|a| a.span())
+                .unwrap_or_else(Span::call_site);
+            match &fattrs.unit {
+                Some(unit) if stats_unit_scale(unit, display_unit).is_some() => {}
+                Some(unit) => Err(Error::new(
+                    span,
+                    format!("scx_stats: Can't display {:?} in {:?}", unit, display_unit),
+                ))?,
+                None => Err(Error::new(span, "scx_stats: display_unit without unit"))?,
+            }
+        }
+
         Ok(fattrs)
     }
 }
@@ -277,12 +358,19 @@ pub struct StatsField {
 
 impl StatsField {
     pub fn new(field: &Field, paths: &mut BTreeMap<String, Path>) -> syn::Result<(String, Self)> {
+        let data = StatsData::new(&field.ty, paths)?;
+        let attrs = StatsFieldAttrs::new(&field.attrs)?;
+
+        if attrs.dim.is_some() && !data.is_struct_dict() {
+            Err(Error::new(
+                field.ty.span(),
+                "scx_stats: dim must be on a dict of structs",
+            ))?;
+        }
+
         Ok((
             field.ident.as_ref().unwrap().to_string(),
-            Self {
-                data: StatsData::new(&field.ty, paths)?,
-                attrs: StatsFieldAttrs::new(&field.attrs)?,
-            },
+            Self { data, attrs },
         ))
     }
 }
@@ -293,6 +381,8 @@ pub struct StatsStructAttrs {
     pub top: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub desc: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub long_desc: Option<String>,
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub user: BTreeMap<String, String>,
 }
@@ -308,9 +398,14 @@ impl StatsStructAttrs {
                     match elem {
                         StatsAttr::Top => sattrs.top = Some("true".into()),
                         StatsAttr::Desc(v) => sattrs.desc = Some(v),
+                        StatsAttr::LongDesc(v) => sattrs.long_desc = Some(v),
                         StatsAttr::User(k, v) => {
                             sattrs.user.insert(k, v);
                         }
+                        v => Err(Error::new(
+                            attr.span(),
+                            format!("Not a struct attribute: {:?}", v),
+                        ))?,
                     }
                 }
             }
@@ -326,6 +421,11 @@ pub struct StatsMeta {
     #[serde(flatten)]
     pub attrs: StatsStructAttrs,
     pub fields: BTreeMap<String, StatsField>,
+    /// Hash of the struct and all structs nested in it, filled in by the
+    /// server. Clients can compare it against the hash of the definition
+    /// they were built with to detect schema changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_hash: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -355,6 +455,7 @@ impl Parse for StatsMetaAux {
                 name: item_struct.ident.to_string(),
                 attrs,
                 fields,
+                schema_hash: None,
             },
             ident: item_struct.ident,
             paths,
@@ -0,0 +1,291 @@
+use crate::acl::PeerCred;
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Address of a stats server. The UNIX domain socket is always served.
+/// TCP and vsock can be added to allow access from outside a container or
+/// from the host of a VM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StatsAddr {
+    /// "unix:PATH"
+    Unix(PathBuf),
+    /// "tcp:HOST:PORT"
+    Tcp(String),
+    /// "vsock:CID:PORT" where CID can be "any" when listening.
+    Vsock { cid: u32, port: u32 },
+}
+
+impl std::str::FromStr for StatsAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(addr: &str) -> Result<Self> {
+        match addr.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(path.into())),
+            Some(("tcp", hp)) if !hp.is_empty() => Ok(Self::Tcp(hp.into())),
+            Some(("vsock", cp)) => {
+                let parse = |(cid, port): (&str, &str)| {
+                    let cid = match cid {
+                        "any" => libc::VMADDR_CID_ANY,
+                        v => v.parse().ok()?,
+                    };
+                    Some((cid, port.parse().ok()?))
+                };
+                match cp.split_once(':').and_then(parse) {
+                    Some((cid, port)) => Ok(Self::Vsock { cid, port }),
+                    None => bail!("invalid vsock address {:?}, expected vsock:CID:PORT", addr),
+                }
+            }
+            _ => bail!(
+                "invalid stats address {:?}, expected unix:, tcp: or vsock:",
+                addr
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for StatsAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Tcp(hp) => write!(f, "tcp:{}", hp),
+            Self::Vsock { cid, port } if *cid == libc::VMADDR_CID_ANY => {
+                write!(f, "vsock:any:{}", port)
+            }
+            Self::Vsock { cid, port } => write!(f, "vsock:{}:{}", cid, port),
+        }
+    }
+}
+
+fn vsock_addr(cid: u32, port: u32) -> libc::sockaddr_vm {
+    // SAFETY: sockaddr_vm is plain data and all zeros is valid.
+    let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = cid;
+    addr.svm_port = port;
+    addr
+}
+
+fn vsock_socket() -> Result<OwnedFd> {
+    // SAFETY: Plain syscall.
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        bail!(
+            "creating vsock socket failed ({})",
+            std::io::Error::last_os_error()
+        );
+    }
+    // SAFETY: @fd was just created and isn't owned by anything else.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Connected vsock stream. std doesn't support AF_VSOCK, so the socket is
+/// driven through a File which does plain read(2) and write(2).
+pub(crate) struct VsockStream(File);
+
+impl VsockStream {
+    fn connect(cid: u32, port: u32) -> Result<Self> {
+        let sock = vsock_socket()?;
+        let addr = vsock_addr(cid, port);
+        // SAFETY: @addr is a valid sockaddr_vm of the passed size.
+        let ret = unsafe {
+            libc::connect(
+                sock.as_raw_fd(),
+                &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            Err(std::io::Error::last_os_error())
+                .with_context(|| format!("connecting to vsock:{}:{}", cid, port))?;
+        }
+        Ok(Self(File::from(sock)))
+    }
+}
+
+/// A connected stats stream over any of the supported transports.
+pub(crate) enum StatsStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+    Vsock(VsockStream),
+}
+
+impl StatsStream {
+    pub(crate) fn connect(addr: &StatsAddr) -> Result<Self> {
+        Ok(match addr {
+            StatsAddr::Unix(path) => Self::Unix(UnixStream::connect(path)?),
+            StatsAddr::Tcp(hp) => {
+                let stream = TcpStream::connect(hp)?;
+                stream.set_nodelay(true)?;
+                Self::Tcp(stream)
+            }
+            StatsAddr::Vsock { cid, port } => Self::Vsock(VsockStream::connect(*cid, *port)?),
+        })
+    }
+
+    pub(crate) fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(match self {
+            Self::Unix(v) => Self::Unix(v.try_clone()?),
+            Self::Tcp(v) => Self::Tcp(v.try_clone()?),
+            Self::Vsock(v) => Self::Vsock(VsockStream(v.0.try_clone()?)),
+        })
+    }
+
+    /// Credentials of the peer. Only available over the UNIX domain socket.
+    pub(crate) fn peer_cred(&self) -> Result<Option<PeerCred>> {
+        match self {
+            Self::Unix(v) => Ok(Some(PeerCred::new(v)?)),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Read for StatsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Unix(v) => v.read(buf),
+            Self::Tcp(v) => v.read(buf),
+            Self::Vsock(v) => v.0.read(buf),
+        }
+    }
+}
+
+impl Write for StatsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Unix(v) => v.write(buf),
+            Self::Tcp(v) => v.write(buf),
+            Self::Vsock(v) => v.0.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Unix(v) => v.flush(),
+            Self::Tcp(v) => v.flush(),
+            Self::Vsock(v) => v.0.flush(),
+        }
+    }
+}
+
+/// A listening socket of a StatsServer.
+pub(crate) enum StatsListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+    Vsock(OwnedFd),
+}
+
+impl StatsListener {
+    /// Listen on `addr`. UNIX domain sockets are bound by the server which
+    /// also manages the socket file.
+    pub(crate) fn bind(addr: &StatsAddr) -> Result<Self> {
+        match addr {
+            StatsAddr::Unix(path) => {
+                Ok(Self::Unix(UnixListener::bind(path).with_context(|| {
+                    format!("creating UNIX socket {:?}", path)
+                })?))
+            }
+            StatsAddr::Tcp(hp) => Ok(Self::Tcp(
+                TcpListener::bind(hp).with_context(|| format!("binding TCP address {}", hp))?,
+            )),
+            StatsAddr::Vsock { cid, port } => {
+                let sock = vsock_socket()?;
+                let addr = vsock_addr(*cid, *port);
+                // SAFETY: @addr is a valid sockaddr_vm of the passed size.
+                let ret = unsafe {
+                    libc::bind(
+                        sock.as_raw_fd(),
+                        &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                        std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+                    )
+                };
+                // SAFETY: Plain syscall on a valid socket.
+                if ret < 0 || unsafe { libc::listen(sock.as_raw_fd(), 128) } < 0 {
+                    Err(std::io::Error::last_os_error())
+                        .with_context(|| format!("listening on vsock:{}:{}", cid, port))?;
+                }
+                Ok(Self::Vsock(sock))
+            }
+        }
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        match self {
+            Self::Unix(v) => v.as_raw_fd(),
+            Self::Tcp(v) => v.as_raw_fd(),
+            Self::Vsock(v) => v.as_raw_fd(),
+        }
+    }
+
+    fn accept(&self) -> Result<StatsStream> {
+        match self {
+            Self::Unix(v) => Ok(StatsStream::Unix(v.accept()?.0)),
+            Self::Tcp(v) => {
+                let stream = v.accept()?.0;
+                stream.set_nodelay(true)?;
+                Ok(StatsStream::Tcp(stream))
+            }
+            Self::Vsock(v) => {
+                // SAFETY: Plain syscall on a valid listening socket.
+                let fd = unsafe {
+                    libc::accept4(
+                        v.as_raw_fd(),
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                        libc::SOCK_CLOEXEC,
+                    )
+                };
+                if fd < 0 {
+                    Err(anyhow!("{}", std::io::Error::last_os_error()))?;
+                }
+                // SAFETY: @fd was just accepted and isn't owned by anything
+                // else.
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                Ok(StatsStream::Vsock(VsockStream(File::from(fd))))
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for connections on `listeners` and accept them.
+    pub(crate) fn accept_any(
+        listeners: &[StatsListener],
+        timeout: Duration,
+    ) -> Vec<Result<StatsStream>> {
+        let mut pfds: Vec<libc::pollfd> = listeners
+            .iter()
+            .map(|l| libc::pollfd {
+                fd: l.raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+
+        // SAFETY: @pfds is a valid array of pollfds of the passed length.
+        let ret = unsafe {
+            libc::poll(
+                pfds.as_mut_ptr(),
+                pfds.len() as libc::nfds_t,
+                timeout.as_millis() as libc::c_int,
+            )
+        };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return vec![];
+            }
+            return vec![Err(err.into())];
+        }
+
+        listeners
+            .iter()
+            .zip(pfds.iter())
+            .filter(|(_, pfd)| pfd.revents != 0)
+            .map(|(l, _)| l.accept())
+            .collect()
+    }
+}
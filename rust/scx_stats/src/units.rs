@@ -0,0 +1,48 @@
+use crate::StatsFieldAttrs;
+
+/// Units which can be converted into each other along with their sizes in
+/// the base unit of the family.
+const UNIT_FAMILIES: &[&[(&str, f64)]] = &[
+    &[("ns", 1.0), ("us", 1e3), ("ms", 1e6), ("s", 1e9)],
+    &[
+        ("B", 1.0),
+        ("KiB", 1024.0),
+        ("MiB", 1024.0 * 1024.0),
+        ("GiB", 1024.0 * 1024.0 * 1024.0),
+        ("kB", 1e3),
+        ("MB", 1e6),
+        ("GB", 1e9),
+    ],
+    &[("ratio", 1.0), ("%", 0.01)],
+];
+
+/// Return the factor to multiply a value in `from` with to convert it into
+/// `to`, e.g. 1e-6 from "ns" to "ms". Returns None if the units aren't
+/// convertible. Any unit is convertible to itself.
+pub fn stats_unit_scale(from: &str, to: &str) -> Option<f64> {
+    if from == to {
+        return Some(1.0);
+    }
+    for family in UNIT_FAMILIES.iter() {
+        let size = |unit| family.iter().find(|(u, _)| *u == unit).map(|(_, v)| *v);
+        if let (Some(f), Some(t)) = (size(from), size(to)) {
+            return Some(f / t);
+        }
+    }
+    None
+}
+
+impl StatsFieldAttrs {
+    /// Convert `val` into the preferred display unit and return it along
+    /// with the unit it's in, so that generic frontends can render the
+    /// field without knowing the scheduler.
+    pub fn display_value(&self, val: f64) -> (f64, Option<&str>) {
+        match (&self.unit, &self.display_unit) {
+            (Some(unit), Some(disp)) => match stats_unit_scale(unit, disp) {
+                Some(scale) => (val * scale, Some(disp)),
+                None => (val, Some(unit)),
+            },
+            (unit, _) => (val, unit.as_deref()),
+        }
+    }
+}
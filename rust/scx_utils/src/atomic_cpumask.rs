@@ -0,0 +1,203 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2.
+
+//! # Atomic Cpumask
+//!
+//! `AtomicCpumask` is a cpumask which can be shared between threads, e.g. an
+//! idle CPU mask updated by the scheduling loop and read by a stats thread,
+//! without a mutex. Setting, clearing and testing individual CPUs are single
+//! atomic operations. `snapshot()` reads the mask word by word, so it's not
+//! an atomic view of the whole mask while it's being updated.
+//!
+//!```
+//!     use scx_utils::AtomicCpumask;
+//!     use std::sync::Arc;
+//!     let idle = Arc::new(AtomicCpumask::new());
+//!     idle.set_cpu(0).unwrap();
+//!
+//!     let stats_idle = idle.clone();
+//!     std::thread::spawn(move || assert!(stats_idle.snapshot().test_cpu(0)))
+//!         .join()
+//!         .unwrap();
+//!
+//!     assert!(idle.test_and_clear_cpu(0).unwrap());
+//!     assert!(!idle.test_and_clear_cpu(0).unwrap());
+//!```
+
+use crate::Cpumask;
+use crate::NR_CPU_IDS;
+use anyhow::bail;
+use anyhow::Result;
+use bitvec::prelude::*;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+#[derive(Debug)]
+pub struct AtomicCpumask {
+    words: Vec<AtomicU64>,
+    len: usize,
+}
+
+impl AtomicCpumask {
+    /// Build an empty AtomicCpumask sized for the host.
+    pub fn new() -> Self {
+        Self::with_len(*NR_CPU_IDS)
+    }
+
+    /// Build an empty AtomicCpumask which can hold at least `@len` CPUs.
+    pub fn with_len(len: usize) -> Self {
+        let len = len.max(*NR_CPU_IDS);
+        Self {
+            words: (0..len.div_ceil(64)).map(|_| AtomicU64::new(0)).collect(),
+            len,
+        }
+    }
+
+    fn check_cpu(&self, cpu: usize) -> Result<()> {
+        if cpu >= self.len {
+            bail!("Invalid CPU {} passed, max {}", cpu, self.len);
+        }
+        Ok(())
+    }
+
+    fn word_bit(cpu: usize) -> (usize, u64) {
+        (cpu / 64, 1 << (cpu % 64))
+    }
+
+    /// The total size of the AtomicCpumask.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return true if the AtomicCpumask has no bit set, false otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|w| w.load(Ordering::Acquire) == 0)
+    }
+
+    /// Set a bit in the AtomicCpumask. Returns an error if the specified
+    /// CPU exceeds the size of the AtomicCpumask.
+    pub fn set_cpu(&self, cpu: usize) -> Result<()> {
+        self.test_and_set_cpu(cpu).map(|_| ())
+    }
+
+    /// Clear a bit from the AtomicCpumask. Returns an error if the
+    /// specified CPU exceeds the size of the AtomicCpumask.
+    pub fn clear_cpu(&self, cpu: usize) -> Result<()> {
+        self.test_and_clear_cpu(cpu).map(|_| ())
+    }
+
+    /// Set a bit in the AtomicCpumask and return whether it was already
+    /// set.
+    pub fn test_and_set_cpu(&self, cpu: usize) -> Result<bool> {
+        self.check_cpu(cpu)?;
+        let (word, bit) = Self::word_bit(cpu);
+        Ok(self.words[word].fetch_or(bit, Ordering::AcqRel) & bit != 0)
+    }
+
+    /// Clear a bit from the AtomicCpumask and return whether it was set.
+    /// This can be used to claim a CPU, e.g. an idle one, from multiple
+    /// threads without racing.
+    pub fn test_and_clear_cpu(&self, cpu: usize) -> Result<bool> {
+        self.check_cpu(cpu)?;
+        let (word, bit) = Self::word_bit(cpu);
+        Ok(self.words[word].fetch_and(!bit, Ordering::AcqRel) & bit != 0)
+    }
+
+    /// Test whether the specified CPU bit is set in the AtomicCpumask. If
+    /// the CPU exceeds the size of the AtomicCpumask, false is returned.
+    pub fn test_cpu(&self, cpu: usize) -> bool {
+        let (word, bit) = Self::word_bit(cpu);
+        match self.words.get(word) {
+            Some(word) => cpu < self.len && word.load(Ordering::Acquire) & bit != 0,
+            None => false,
+        }
+    }
+
+    /// Count the number of bits set in the AtomicCpumask.
+    pub fn weight(&self) -> usize {
+        self.words
+            .iter()
+            .map(|w| w.load(Ordering::Acquire).count_ones() as usize)
+            .sum()
+    }
+
+    /// Clear all bits in the AtomicCpumask.
+    pub fn clear_all(&self) {
+        for word in self.words.iter() {
+            word.store(0, Ordering::Release);
+        }
+    }
+
+    /// Copy the current state into a Cpumask.
+    pub fn snapshot(&self) -> Cpumask {
+        let words = self
+            .words
+            .iter()
+            .map(|w| w.load(Ordering::Acquire))
+            .collect::<Vec<u64>>();
+        let mut mask = BitVec::<u64, Lsb0>::from_vec(words);
+        mask.truncate(self.len);
+        Cpumask::from_bitvec(mask)
+    }
+
+    /// Overwrite the AtomicCpumask with `@mask`. CPUs of `@mask` beyond
+    /// the size of the AtomicCpumask are dropped.
+    pub fn store(&self, mask: &Cpumask) {
+        let raw = mask.as_raw_slice();
+        let nr_valid = mask.len().min(self.len);
+        for (idx, word) in self.words.iter().enumerate() {
+            // Don't copy stray bits past the end of either mask.
+            let val = match nr_valid.saturating_sub(idx * 64) {
+                0 => 0,
+                nr @ 1..64 => raw[idx] & ((1 << nr) - 1),
+                _ => raw[idx],
+            };
+            word.store(val, Ordering::Release);
+        }
+    }
+}
+
+impl Default for AtomicCpumask {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&Cpumask> for AtomicCpumask {
+    fn from(mask: &Cpumask) -> Self {
+        let new = Self::with_len(mask.len());
+        new.store(mask);
+        new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_atomic_cpumask() {
+        let mask = AtomicCpumask::with_len(130);
+        assert!(mask.is_empty());
+        assert!(!mask.test_and_set_cpu(129).unwrap());
+        assert!(mask.test_and_set_cpu(129).unwrap());
+        mask.set_cpu(3).unwrap();
+        assert!(mask.set_cpu(mask.len()).is_err());
+        assert_eq!(mask.weight(), 2);
+
+        let snap = mask.snapshot();
+        assert_eq!(snap.len(), mask.len());
+        assert_eq!(snap.iter().collect::<Vec<_>>(), vec![3, 129]);
+
+        let mut full = Cpumask::with_len(256);
+        full.set_all();
+        mask.store(&full);
+        assert_eq!(mask.weight(), mask.len());
+
+        mask.clear_all();
+        mask.store(&snap);
+        assert_eq!(AtomicCpumask::from(&snap).snapshot(), mask.snapshot());
+    }
+}
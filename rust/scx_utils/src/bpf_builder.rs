@@ -4,16 +4,161 @@
 // GNU General Public License version 2.
 
 use crate::clang_info::ClangInfo;
+use crate::VmlinuxHVersion;
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
-use glob::glob;
 use libbpf_cargo::SkeletonBuilder;
 use libbpf_rs::Linker;
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::env;
+use std::ffi::CString;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// Byte order of the BPF objects. See `BpfBuilder::set_endian()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BpfEndian {
+    Little,
+    Big,
+}
+
+impl BpfEndian {
+    fn cflag(&self) -> &'static str {
+        match self {
+            BpfEndian::Little => "-mlittle-endian",
+            BpfEndian::Big => "-mbig-endian",
+        }
+    }
+}
+
+/// How to treat the existing files when installing the bundled headers
+/// with `BpfBuilder::install_bpf_h()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Replace the existing files.
+    Always,
+    /// Keep the existing files.
+    Never,
+    /// Keep the existing files which were modified after they were
+    /// installed, or weren't installed by `install_bpf_h()` at all. The
+    /// installed files are tracked by their content hashes in a manifest
+    /// in the destination directory as the bundled mtimes are fixed.
+    SkipIfNewer,
+}
+
+/// BPF build profile. See `BpfBuilder::set_profile()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BpfProfile {
+    Release,
+    Debug,
+}
+
+impl BpfProfile {
+    fn cflags(&self) -> &'static [&'static str] {
+        match self {
+            BpfProfile::Release => &[],
+            BpfProfile::Debug => &["-DDEBUG", "-DSCX_BPF_DEBUG=1", "-fno-inline-functions"],
+        }
+    }
+}
+
+impl std::str::FromStr for BpfProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "release" => Ok(BpfProfile::Release),
+            "debug" => Ok(BpfProfile::Debug),
+            _ => bail!(
+                "Unknown BPF profile {:?}, should be \"release\" or \"debug\"",
+                s
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct IntfOpts {
+    allowlist: Vec<String>,
+    blocklist: Vec<String>,
+    derives: Vec<String>,
+    derive_default: bool,
+    derive_partialeq: bool,
+    enum_style: bindgen::EnumVariation,
+}
+
+impl Default for IntfOpts {
+    fn default() -> Self {
+        Self {
+            allowlist: vec![],
+            blocklist: vec![],
+            derives: vec![],
+            derive_default: false,
+            derive_partialeq: false,
+            enum_style: bindgen::EnumVariation::Consts,
+        }
+    }
+}
+
+type SkelHookFn = dyn Fn(&str, String) -> Result<String> + Send + Sync;
+
+/// A skeleton post-processing hook. See `BpfBuilder::add_skel_hook()`.
+struct SkelHook(Box<SkelHookFn>);
+
+impl std::fmt::Debug for SkelHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("SkelHook")
+    }
+}
+
+/// A kernel feature to probe for with `BpfBuilder::enable_features()`.
+#[derive(Clone, Debug)]
+enum FeatureProbe {
+    Kfunc(String),
+    StructField(String, String),
+    EnumValue(String, String),
+}
+
+/// The name, size and named field offsets of a struct in the intf.h
+/// bindings.
+type IntfLayout = (String, usize, Vec<(String, usize)>);
+
+/// Adds the extra derives to structs. Unions and enums are skipped as
+/// common derives such as `serde::Serialize` can't handle them.
+#[derive(Debug)]
+struct IntfDerives(Vec<String>);
+
+impl bindgen::callbacks::ParseCallbacks for IntfDerives {
+    fn add_derives(&self, info: &bindgen::callbacks::DeriveInfo<'_>) -> Vec<String> {
+        match info.kind {
+            bindgen::callbacks::TypeKind::Struct => self.0.clone(),
+            _ => vec![],
+        }
+    }
+}
+
+/// A BPF skeleton to be built by `BpfBuilder::gen_bpf_skels()`. `input` is
+/// the `.bpf.c` file and `name` is the name of the skeleton.
+#[derive(Clone, Debug)]
+pub struct SkelSpec {
+    pub input: String,
+    pub name: String,
+}
+
+impl SkelSpec {
+    pub fn new(input: &str, name: &str) -> Self {
+        Self {
+            input: input.into(),
+            name: name.into(),
+        }
+    }
+}
 
 #[derive(Debug)]
 /// # Build helpers for sched_ext schedulers with Rust userspace component
@@ -47,11 +192,24 @@ use std::path::PathBuf;
 /// This is a source of ugliness and we are hoping to address it by
 /// improving `libbpf-cargo` in the future.
 ///
+/// The sizes and field offsets of the structs in the header, as laid out
+/// for userspace, are emitted into the bindings as `SCX_INTF_LAYOUTS`.
+/// `scx_utils::intf_layout_check!()` compares them against the BTF of the
+/// BPF object to refuse loading one whose view of the shared structs
+/// differs from userspace's.
+///
 /// 3. *BPF compilation and generation of the skeleton and its bindings*
 ///
 /// If enabled with `.enable_skel()`, the input `.bpf.c` file is compiled
 /// and its skeleton and bindings are generated using `libbpf-cargo`.
 ///
+/// Large BPF components can be split into multiple translation units by
+/// adding the extra `.bpf.c` files with `.add_source()`. Each file is
+/// compiled into its own object and the objects are statically linked
+/// with the BPF linker into the object the skeleton is generated from.
+/// Other than `static` ones, global functions and variables are shared
+/// across the objects and can be accessed with `extern` declarations.
+///
 /// ## An Example
 ///
 /// This section shows how `BpfBuilder` can be used in an example project.
@@ -127,6 +285,14 @@ use std::path::PathBuf;
 /// include!(concat!(env!("OUT_DIR"), "/bpf_skel.rs"));
 /// ```
 ///
+/// ## Reproducible Builds
+///
+/// The BPF objects and the generated skeletons don't depend on the build
+/// directories. `OUT_DIR`, the crate directory and the external `vmlinux.h`
+/// directory are remapped in the debug info with `-ffile-prefix-map`. The
+/// objects are compiled and linked in the sorted order of the sources, and
+/// the bundled headers are archived with normalized timestamps.
+///
 /// ## Compiler Flags and Environment Variables
 ///
 /// BPF being its own CPU architecture and independent runtime environment,
@@ -141,11 +307,44 @@ use std::path::PathBuf;
 /// ```
 ///
 /// While the automatic settings should work most of the time, there can be
-/// times when overriding them is necessary. The following environment
-/// variables can be used to customize the build environment.
+/// times when overriding them is necessary. The build environment can be
+/// customized programmatically with the following methods. As the settings
+/// are per `BpfBuilder` instance, a single `build.rs` can build multiple
+/// BPF components with different settings.
+///
+/// - `.set_clang()`: The clang command to use.
+///
+/// - `.set_gcc()`: Compile with the GCC BPF backend instead of clang.
+///
+/// - `.set_target()`, `.set_sysroot()`: The userspace target and sysroot
+///   to build for when cross-compiling. The `__TARGET_ARCH_*` define and
+///   `vmlinux.h` are chosen to match the target. (Default: cargo `TARGET`)
+///
+/// - `.set_cflags()`: Replace all compiler flags, equivalent to `BPF_CFLAGS`.
+///
+/// - `.add_cflags()`: Append extra compiler flags.
+///
+/// - `.set_endian()`: Build little (`bpfel`) or big (`bpfeb`) endian BPF
+///   objects. (Default: the endianness of the userspace target)
+///
+/// - `.add_include_dir()`: Add a header search path which is searched
+///   before the automatic ones.
+///
+/// - `.set_profile()`: Build release or debug BPF objects.
+///   (Default: `BpfProfile::Release`)
+///
+/// - `.vmlinux_h_from_path()`, `.vmlinux_h_from_btf()`: Use an external
+///   `vmlinux.h` or generate one from BTF instead of the bundled one.
+///
+/// The following environment variables can also be used to customize the
+/// build environment. They provide the defaults which the above methods
+/// override.
 ///
 /// - `BPF_CLANG`: The clang command to use. (Default: `clang`)
 ///
+/// - `BPF_GCC`: Compile with the specified GCC BPF backend instead of
+///   clang. See `.set_gcc()`.
+///
 /// - `BPF_CFLAGS`: Compiler flags to use when building BPF source code. If
 ///   specified, the flags from this variable are the only flags passed to
 ///   the compiler. `BpfBuilder` won't generate any flags including `-I`
@@ -163,6 +362,42 @@ use std::path::PathBuf;
 ///   searched only if the target header file can't be found in the
 ///   automatic header paths.
 ///
+/// - `BPF_VMLINUX_BTF`: Generate `vmlinux.h` from the specified BTF file,
+///   e.g. `/sys/kernel/btf/vmlinux`, using `bpftool` instead of using the
+///   bundled one. See `.vmlinux_h_from_btf()`.
+///
+/// - `BPF_VMLINUX_H`: Use the specified `vmlinux.h` file, or the directory
+///   containing it, instead of the bundled one. This takes precedence over
+///   `BPF_VMLINUX_BTF`. See `.vmlinux_h_from_path()`.
+///
+/// - `BPFTOOL`: The bpftool command to use. (Default: `bpftool`)
+///
+/// - `BPF_CACHE_DIR`: Cache compiled BPF objects in the specified
+///   directory, which can be shared across crates and clean builds. The
+///   objects are keyed on the hash of the source files, cflags, clang
+///   version and the bundled headers with `OUT_DIR` and the crate
+///   directory masked out. See `.set_cache_dir()`.
+///
+/// - `BPF_COMPILE_COMMANDS_DIR`: Write `compile_commands.json` for the
+///   BPF sources into the specified directory, e.g. the source tree, for
+///   clangd and other tools. See `.set_compile_commands_dir()`.
+///
+/// - `BPF_PROFILE`: The build profile, `release` or `debug`. See
+///   `.set_profile()`.
+///
+/// - `BPF_FEATURES_BTF`: The kernel BTF file to probe for the features.
+///   See `.enable_features()`.
+///
+/// - `BPF_KEEP_INTERMEDIATES`: If set, keep the preprocessed source, LLVM
+///   IR, disassembly and layout of the BPF objects in `OUT_DIR`. See
+///   `.keep_intermediates()`.
+///
+/// - `BPF_INSN_BUDGET`: Warn about BPF programs with more instructions.
+///   See `.set_insn_budget()`.
+///
+/// - `LLVM_OBJDUMP`: The llvm-objdump command to use for disassembly.
+///   (Default: `llvm-objdump`)
+///
 /// - `RUSTFLAGS`: This is a generic `cargo` flag and can be useful for
 ///   specifying extra linker flags.
 ///
@@ -184,21 +419,247 @@ use std::path::PathBuf;
 /// ```
 pub struct BpfBuilder {
     clang: ClangInfo,
+    gcc: Option<(String, String)>,
     cflags: Vec<String>,
+    custom_cflags: bool,
+    added_cflags: Vec<String>,
+    include_dirs: Vec<String>,
+    endian: Option<BpfEndian>,
+    profile: BpfProfile,
+    vmlinux_btf: Option<String>,
+    vmlinux_h: Option<String>,
+    vmlinux_h_dir: Option<String>,
+    cache_dir: Option<String>,
+    compile_commands_dir: Option<String>,
+    min_core_btf_inputs: Vec<String>,
+    core_btf_dirs: Vec<String>,
+    compress_skel: bool,
+    skel_hooks: Vec<SkelHook>,
+    keep_intermediates: bool,
+    insn_budget: Option<u64>,
     out_dir: PathBuf,
     sources: BTreeSet<String>,
 
     intf_input_output: Option<(String, String)>,
+    intf_opts: IntfOpts,
+    features_output: Option<String>,
+    features_btf: Option<String>,
+    feature_probes: Vec<FeatureProbe>,
     skel_input_name: Option<(String, String)>,
 }
 
 impl BpfBuilder {
-    const BPF_H_TAR: &'static [u8] = include_bytes!(concat!(env!("OUT_DIR"), "/bpf_h.tar"));
+    const BPF_H_TAR_ZST: &'static [u8] = include_bytes!(concat!(env!("OUT_DIR"), "/bpf_h.tar.zst"));
+
+    /// The bundled headers are zstd compressed and inflated while being
+    /// read.
+    fn bpf_h_tar() -> Result<tar::Archive<impl std::io::Read>> {
+        Ok(tar::Archive::new(zstd::stream::Decoder::new(
+            Self::BPF_H_TAR_ZST,
+        )?))
+    }
 
-    fn install_bpf_h<P: AsRef<Path>>(dest: P) -> Result<()> {
-        let mut ar = tar::Archive::new(Self::BPF_H_TAR);
-        ar.unpack(dest)?;
-        Ok(())
+    /// Install the bundled headers into `@dest` and return the installed
+    /// files. Only the files whose paths in the bundle start with one of
+    /// `@prefixes`, e.g. `scx/` or `arch/x86/`, are installed. All files
+    /// are installed if `@prefixes` is empty. Existing files are handled
+    /// according to `@policy`. Files which are already identical are never
+    /// rewritten.
+    pub fn install_bpf_h<P: AsRef<Path>>(
+        dest: P,
+        prefixes: &[&str],
+        policy: OverwritePolicy,
+    ) -> Result<Vec<PathBuf>> {
+        let dest = dest.as_ref();
+        let mut ar = Self::bpf_h_tar()?;
+        let mut installed = vec![];
+
+        std::fs::create_dir_all(dest)?;
+
+        let manifest_path = dest.join(Self::BPF_H_MANIFEST);
+        let mut manifest = Self::read_bpf_h_manifest(&manifest_path);
+        let manifest_orig = manifest.clone();
+
+        for entry in ar.entries()? {
+            let mut entry = entry?;
+            let rel = entry.path()?.to_path_buf();
+            let rel = rel.strip_prefix("./").unwrap_or(&rel).to_path_buf();
+            if rel.as_os_str().is_empty() {
+                continue;
+            }
+
+            let matched = prefixes.is_empty()
+                || prefixes.iter().any(|prefix| {
+                    let rel = rel.to_string_lossy();
+                    rel.starts_with(prefix) || prefix.starts_with(&format!("{}/", rel))
+                });
+            if !matched {
+                continue;
+            }
+
+            let path = dest.join(&rel);
+            let kind = entry.header().entry_type();
+            if kind.is_dir() {
+                entry.unpack_in(dest)?;
+                continue;
+            }
+
+            if let Ok(meta) = std::fs::symlink_metadata(&path) {
+                match policy {
+                    OverwritePolicy::Always => {}
+                    OverwritePolicy::Never => continue,
+                    OverwritePolicy::SkipIfNewer => {
+                        let key = rel.to_string_lossy();
+                        let cur = Self::bpf_h_installed_hash(&path, &meta);
+                        if cur.is_none() || cur != manifest.get(key.as_ref()).copied() {
+                            continue;
+                        }
+                    }
+                }
+            }
+
+            let key = rel.to_string_lossy().to_string();
+            if kind.is_file() {
+                let mut data = vec![];
+                std::io::Read::read_to_end(&mut entry, &mut data)?;
+                manifest.insert(key, Self::content_hash(&data));
+                if std::fs::read(&path).is_ok_and(|cur| cur == data) {
+                    continue;
+                }
+                if std::fs::symlink_metadata(&path).is_ok() {
+                    std::fs::remove_file(&path)?;
+                }
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                std::fs::write(&path, &data)
+                    .with_context(|| format!("Failed to install {:?}", &path))?;
+            } else if kind.is_symlink() {
+                let target = entry.link_name()?.map(|v| v.into_owned());
+                if let Some(target) = &target {
+                    manifest.insert(
+                        key,
+                        Self::content_hash(target.as_os_str().as_encoded_bytes()),
+                    );
+                }
+                if std::fs::read_link(&path).ok() == target {
+                    continue;
+                }
+                entry.unpack_in(dest)?;
+            } else {
+                entry.unpack_in(dest)?;
+            }
+            installed.push(path);
+        }
+
+        if manifest != manifest_orig {
+            let text: String = manifest
+                .iter()
+                .map(|(path, hash)| format!("{:016x} {}\n", hash, path))
+                .collect();
+            std::fs::write(&manifest_path, text)
+                .with_context(|| format!("Failed to write {:?}", &manifest_path))?;
+        }
+
+        Ok(installed)
+    }
+
+    /// Records the content hashes of the files installed by
+    /// `install_bpf_h()` for `OverwritePolicy::SkipIfNewer`.
+    const BPF_H_MANIFEST: &'static str = ".scx_utils-bpf_h.manifest";
+
+    fn read_bpf_h_manifest(path: &Path) -> BTreeMap<String, u64> {
+        let text = std::fs::read_to_string(path).unwrap_or_default();
+        text.lines()
+            .filter_map(|line| {
+                let (hash, path) = line.split_once(' ')?;
+                Some((path.to_string(), u64::from_str_radix(hash, 16).ok()?))
+            })
+            .collect()
+    }
+
+    /// FNV-1a, which unlike `DefaultHasher` is stable across toolchains as
+    /// needed for the persistent manifest.
+    fn content_hash(data: &[u8]) -> u64 {
+        data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    /// The hash of the existing file at `@path` to compare against the
+    /// manifest, the link target for symlinks.
+    fn bpf_h_installed_hash(path: &Path, meta: &std::fs::Metadata) -> Option<u64> {
+        if meta.is_symlink() {
+            let target = std::fs::read_link(path).ok()?;
+            Some(Self::content_hash(target.as_os_str().as_encoded_bytes()))
+        } else if meta.is_file() {
+            Some(Self::content_hash(&std::fs::read(path).ok()?))
+        } else {
+            None
+        }
+    }
+
+    /// Install the libbpf API headers into `@out_dir`. libbpf-cargo adds
+    /// them from a randomly named temp directory which ends up in the debug
+    /// info. Install them where the path can be remapped so that they take
+    /// precedence.
+    fn install_libbpf_h(out_dir: &Path) -> Result<String> {
+        let dir = out_dir.join("scx_utils-libbpf_h");
+        std::fs::create_dir_all(dir.join("bpf"))?;
+        for (name, contents) in libbpf_rs::libbpf_sys::API_HEADERS.iter() {
+            std::fs::write(dir.join("bpf").join(name), contents)?;
+        }
+        Ok(dir
+            .to_str()
+            .ok_or(anyhow!("{:?} can't be converted to str", &dir))?
+            .to_string())
+    }
+
+    /// The version of the `vmlinux.h` bundled for the kernel target arch
+    /// `@arch`, e.g. `x86` or `arm64`.
+    pub fn vmlinux_h_version(arch: &str) -> Result<VmlinuxHVersion> {
+        let mut ar = Self::bpf_h_tar()?;
+        let link = format!("arch/{}/vmlinux.h", arch);
+
+        for entry in ar.entries()? {
+            let entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+            if path.trim_start_matches("./") != link {
+                continue;
+            }
+            let target = entry
+                .link_name()?
+                .ok_or(anyhow!("Bundled {:?} is not a symlink", &link))?;
+            let name = target.file_name().ok_or(anyhow!(
+                "Invalid {:?} symlink target {:?}",
+                &link,
+                &target
+            ))?;
+            return VmlinuxHVersion::from_file_name(arch, &name.to_string_lossy());
+        }
+
+        bail!(
+            "vmlinux.h for {:?} is not bundled, available archs are {:?}",
+            arch,
+            Self::vmlinux_h_archs()?
+        );
+    }
+
+    /// Kernel target archs which have `vmlinux.h` bundled.
+    pub fn vmlinux_h_archs() -> Result<BTreeSet<String>> {
+        let mut ar = Self::bpf_h_tar()?;
+        let mut archs = BTreeSet::new();
+
+        for entry in ar.entries()? {
+            let path = entry?.path()?.to_string_lossy().to_string();
+            let path = path.trim_start_matches("./");
+            if let Some(arch) = path
+                .strip_prefix("arch/")
+                .and_then(|x| x.strip_suffix("/vmlinux.h"))
+            {
+                archs.insert(arch.to_string());
+            }
+        }
+
+        Ok(archs)
     }
 
     fn determine_cflags<P>(clang: &ClangInfo, out_dir: P) -> Result<Vec<String>>
@@ -214,8 +675,10 @@ impl BpfBuilder {
                 &out_dir
             ))?
             .to_string();
-        Self::install_bpf_h(&bpf_h)?;
+        Self::install_bpf_h(&bpf_h, &[], OverwritePolicy::Always)?;
+        let libbpf_h = Self::install_libbpf_h(out_dir.as_ref())?;
 
+        let kernel_target = clang.kernel_target()?;
         let mut cflags = Vec::<String>::new();
 
         cflags.append(&mut match env::var("BPF_BASE_CFLAGS") {
@@ -228,13 +691,10 @@ impl BpfBuilder {
             _ => vec![],
         });
 
-        cflags.push(format!(
-            "-I{}/arch/{}",
-            &bpf_h,
-            &clang.kernel_target().unwrap()
-        ));
+        cflags.push(format!("-I{}/arch/{}", &bpf_h, &kernel_target));
         cflags.push(format!("-I{}", &bpf_h));
         cflags.push(format!("-I{}/bpf-compat", &bpf_h));
+        cflags.push(format!("-I{}", &libbpf_h));
 
         cflags.append(&mut match env::var("BPF_EXTRA_CFLAGS_POST_INCL") {
             Ok(v) => v.split_whitespace().map(|x| x.into()).collect(),
@@ -251,22 +711,384 @@ impl BpfBuilder {
         let out_dir = PathBuf::from(env::var("OUT_DIR")?);
 
         let clang = ClangInfo::new()?;
-        let cflags = match env::var("BPF_CFLAGS") {
-            Ok(v) => v.split_whitespace().map(|x| x.into()).collect(),
-            _ => Self::determine_cflags(&clang, &out_dir)?,
+        let (cflags, custom_cflags) = match env::var("BPF_CFLAGS") {
+            Ok(v) => (v.split_whitespace().map(|x| x.into()).collect(), true),
+            _ => (Self::determine_cflags(&clang, &out_dir)?, false),
         };
 
         println!("scx_utils:clang={:?} {:?}", &clang, &cflags);
 
-        Ok(Self {
+        let mut builder = Self {
             clang,
+            gcc: None,
             cflags,
+            custom_cflags,
+            added_cflags: vec![],
+            include_dirs: vec![],
+            endian: None,
+            profile: match env::var("BPF_PROFILE") {
+                Ok(v) => v.parse()?,
+                _ => BpfProfile::Release,
+            },
+            vmlinux_btf: None,
+            vmlinux_h: None,
+            vmlinux_h_dir: None,
+            cache_dir: env::var("BPF_CACHE_DIR").ok(),
+            compile_commands_dir: env::var("BPF_COMPILE_COMMANDS_DIR").ok(),
+            min_core_btf_inputs: vec![],
+            core_btf_dirs: vec![],
+            compress_skel: false,
+            skel_hooks: vec![],
+            keep_intermediates: env::var("BPF_KEEP_INTERMEDIATES").is_ok(),
+            insn_budget: match env::var("BPF_INSN_BUDGET") {
+                Ok(v) => Some(
+                    v.parse()
+                        .with_context(|| format!("Invalid BPF_INSN_BUDGET {:?}", &v))?,
+                ),
+                _ => None,
+            },
             out_dir,
 
             sources: BTreeSet::new(),
             intf_input_output: None,
+            intf_opts: IntfOpts::default(),
+            features_output: None,
+            features_btf: env::var("BPF_FEATURES_BTF").ok(),
+            feature_probes: vec![],
             skel_input_name: None,
-        })
+        };
+
+        if let Ok(gcc) = env::var("BPF_GCC") {
+            builder.set_gcc(&gcc)?;
+        }
+
+        if let Ok(path) = env::var("BPF_VMLINUX_H") {
+            builder.vmlinux_h_from_path(&path)?;
+        } else if let Ok(btf) = env::var("BPF_VMLINUX_BTF") {
+            builder.vmlinux_h_from_btf(&btf)?;
+        }
+
+        Ok(builder)
+    }
+
+    fn check_compiler(&self) -> Result<()> {
+        match self.gcc {
+            Some(_) => Ok(()),
+            None => self.clang.check_bpf_ver(),
+        }
+    }
+
+    /// Compile the BPF sources with the GCC BPF backend `@gcc`, e.g.
+    /// `bpf-unknown-none-gcc`, instead of clang. clang is still used to
+    /// determine the target and the system include paths. The cflags are
+    /// translated where gcc differs. Note that bindgen always uses libclang.
+    pub fn set_gcc(&mut self, gcc: &str) -> Result<&mut Self> {
+        let output = Command::new(gcc)
+            .arg("-dumpmachine")
+            .output()
+            .with_context(|| format!("Failed to run \"{} -dumpmachine\"", gcc))?;
+        let machine = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() || !machine.starts_with("bpf") {
+            bail!("{:?} doesn't target BPF (machine={:?})", gcc, &machine);
+        }
+
+        let output = Command::new(gcc)
+            .arg("-dumpfullversion")
+            .output()
+            .with_context(|| format!("Failed to run \"{} -dumpfullversion\"", gcc))?;
+        let ver = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if version_compare::compare(&ver, "14") == Ok(version_compare::Cmp::Lt) {
+            bail!(
+                "gcc < 14 lacks BPF CO-RE support required by sched_ext ({:?} ver={:?})",
+                gcc,
+                &ver
+            );
+        }
+
+        println!("scx_utils:gcc={:?} ver={:?}", gcc, &ver);
+        self.gcc = Some((gcc.into(), ver));
+        Ok(self)
+    }
+
+    /// Translate clang BPF cflags for gcc. gcc emits BTF with `-gbtf` and
+    /// CO-RE relocations with `-mco-re` while clang does both with `-g`.
+    fn gcc_cflags(cflags: &[String]) -> Vec<String> {
+        let mut out = vec![];
+        let mut iter = cflags.iter();
+        while let Some(flag) = iter.next() {
+            match flag.as_str() {
+                "-target" => {
+                    iter.next();
+                }
+                "-g" => out.extend(["-gbtf".into(), "-mco-re".into()]),
+                f if f.starts_with("--target=") => {}
+                f => out.push(f.into()),
+            }
+        }
+        out
+    }
+
+    /// The compiler command and the arguments to compile BPF with `@cflags`
+    /// excluding the input and output.
+    fn compiler_cmd(&self, cflags: &[String]) -> (String, Vec<String>) {
+        // Match the flags libbpf-cargo compiles the objects with.
+        let mut args: Vec<String> = cflags.to_vec();
+        args.extend(["-fno-stack-protector".into(), "-g".into(), "-O2".into()]);
+        match &self.gcc {
+            Some((gcc, _)) => (gcc.clone(), Self::gcc_cflags(&args)),
+            None => {
+                args.extend(["-target".into(), self.bpf_target().into()]);
+                (self.clang.clang.clone(), args)
+            }
+        }
+    }
+
+    /// Don't let an arch without its own bundled vmlinux.h silently fall
+    /// back to the top-level one which is for a different arch.
+    fn check_vmlinux_h(&self) -> Result<()> {
+        if self.custom_cflags || self.vmlinux_h_dir.is_some() {
+            return Ok(());
+        }
+
+        let kernel_target = self.clang.kernel_target()?;
+        if !self
+            .out_dir
+            .join("scx_utils-bpf_h")
+            .join("arch")
+            .join(&kernel_target)
+            .join("vmlinux.h")
+            .exists()
+        {
+            bail!(
+                "vmlinux.h for {:?} is not bundled, available archs are {:?}, \
+                 use BPF_VMLINUX_H or BPF_VMLINUX_BTF to provide one",
+                &kernel_target,
+                Self::vmlinux_h_archs()?
+            );
+        }
+        Ok(())
+    }
+
+    /// Search `@dir` for `vmlinux.h` before everything else, replacing the
+    /// previous override if any.
+    fn override_vmlinux_h_dir(&mut self, dir: &Path) -> Result<()> {
+        let dir = dir
+            .to_str()
+            .ok_or(anyhow!("{:?} can't be converted to str", dir))?
+            .to_string();
+        if let Some(prev) = self.vmlinux_h_dir.take() {
+            self.include_dirs.retain(|x| x != &prev);
+        }
+        self.include_dirs.retain(|x| x != &dir);
+        self.include_dirs.insert(0, dir.clone());
+        self.vmlinux_h_dir = Some(dir);
+        Ok(())
+    }
+
+    /// Use the external `vmlinux.h` at `@path` instead of the bundled one,
+    /// e.g. the one generated for the distro kernel. `@path` can be either
+    /// the header file, which doesn't have to be named `vmlinux.h`, or the
+    /// directory containing `vmlinux.h`. This also allows building for
+    /// archs which don't have `vmlinux.h` bundled.
+    pub fn vmlinux_h_from_path(&mut self, path: &str) -> Result<&mut Self> {
+        let abs = std::fs::canonicalize(path)
+            .with_context(|| format!("Failed to find vmlinux.h {:?}", path))?;
+
+        let dir = if abs.is_dir() {
+            if !abs.join("vmlinux.h").exists() {
+                bail!("{:?} doesn't contain vmlinux.h", path);
+            }
+            abs
+        } else {
+            // Wrap the file so that it can be included as vmlinux.h.
+            let vmlinux_h = self.out_dir.join("scx_utils-vmlinux_h").join("vmlinux.h");
+            let dir = vmlinux_h.parent().unwrap().to_path_buf();
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(&vmlinux_h, format!("#include {:?}\n", abs))?;
+            dir
+        };
+
+        self.override_vmlinux_h_dir(&dir)?;
+        self.vmlinux_btf = None;
+        self.vmlinux_h = Some(path.into());
+        Ok(self)
+    }
+
+    fn vmlinux_btf_h(&self) -> PathBuf {
+        self.out_dir.join("scx_utils-vmlinux_btf").join("vmlinux.h")
+    }
+
+    /// Generate `vmlinux.h` from the BTF file `@btf` using `bpftool` and
+    /// use it instead of the bundled one. `/sys/kernel/btf/vmlinux` can be
+    /// used to build against the running kernel. The `bpftool` command can
+    /// be overridden with the `BPFTOOL` environment variable.
+    pub fn vmlinux_h_from_btf(&mut self, btf: &str) -> Result<&mut Self> {
+        let bpftool = env::var("BPFTOOL").unwrap_or("bpftool".into());
+        let vmlinux_h = self.vmlinux_btf_h();
+        let dir = vmlinux_h.parent().unwrap().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let output = Command::new(&bpftool)
+            .args(["btf", "dump", "file", btf, "format", "c"])
+            .output()
+            .with_context(|| format!("Failed to run \"{} btf dump file {}\"", &bpftool, btf))?;
+        if !output.status.success() {
+            bail!(
+                "\"{} btf dump file {}\" failed ({}): {}",
+                &bpftool,
+                btf,
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        std::fs::write(&vmlinux_h, &output.stdout)?;
+
+        self.override_vmlinux_h_dir(&dir)?;
+        self.vmlinux_h = None;
+        self.vmlinux_btf = Some(btf.into());
+        Ok(self)
+    }
+
+    fn reprobe_clang(
+        &mut self,
+        clang: &str,
+        target: Option<String>,
+        sysroot: Option<String>,
+    ) -> Result<&mut Self> {
+        self.clang = ClangInfo::with_target(clang, target, sysroot)?;
+        if !self.custom_cflags {
+            self.cflags = Self::determine_cflags(&self.clang, &self.out_dir)?;
+        }
+
+        println!("scx_utils:clang={:?} {:?}", &self.clang, &self.cflags);
+        Ok(self)
+    }
+
+    /// Use `@clang` as the clang command instead of the one specified by
+    /// `BPF_CLANG`. Unless the cflags have been overridden, they are
+    /// re-determined for the new compiler. The flags added with
+    /// `add_cflags()` are kept.
+    pub fn set_clang(&mut self, clang: &str) -> Result<&mut Self> {
+        let (target, sysroot) = (self.clang.target.clone(), self.clang.sysroot.clone());
+        self.reprobe_clang(clang, target, sysroot)
+    }
+
+    /// Build for the userspace target `@target` instead of the cargo
+    /// `TARGET`. `@target` can be either a target triple or an
+    /// architecture name such as `aarch64`. The `__TARGET_ARCH_*` define
+    /// and the `vmlinux.h` are selected accordingly unless the cflags have
+    /// been overridden.
+    pub fn set_target(&mut self, target: &str) -> Result<&mut Self> {
+        let (clang, sysroot) = (self.clang.clang.clone(), self.clang.sysroot.clone());
+        self.reprobe_clang(&clang, Some(target.into()), sysroot)?;
+        self.clang.kernel_target()?;
+        Ok(self)
+    }
+
+    /// Use `@sysroot` as the root directory for the system headers of the
+    /// userspace target when cross-compiling.
+    pub fn set_sysroot(&mut self, sysroot: &str) -> Result<&mut Self> {
+        let (clang, target) = (self.clang.clang.clone(), self.clang.target.clone());
+        self.reprobe_clang(&clang, target, Some(sysroot.into()))
+    }
+
+    /// Replace all compiler flags with `@cflags`. As with `BPF_CFLAGS`, no
+    /// flags are generated automatically including the `-I` flags for the
+    /// common header files.
+    pub fn set_cflags<T: AsRef<str>>(&mut self, cflags: &[T]) -> &mut Self {
+        self.cflags = cflags.iter().map(|x| x.as_ref().into()).collect();
+        self.custom_cflags = true;
+        self.added_cflags.clear();
+        self
+    }
+
+    /// Append `@cflags` to the compiler flags. The added flags stay after
+    /// the automatic ones even if those are re-determined later.
+    pub fn add_cflags<T: AsRef<str>>(&mut self, cflags: &[T]) -> &mut Self {
+        self.added_cflags
+            .extend(cflags.iter().map(|x| x.as_ref().to_string()));
+        self
+    }
+
+    /// Add `@dir` to the header search path. The directories added with
+    /// this method are searched in the order they are added and before the
+    /// automatic header paths.
+    pub fn add_include_dir(&mut self, dir: &str) -> &mut Self {
+        self.include_dirs.push(dir.into());
+        self
+    }
+
+    /// Build little or big endian BPF objects. By default, the endianness
+    /// of the userspace target is used, so this is only necessary if the
+    /// BPF objects are to be loaded on a machine with different endianness.
+    pub fn set_endian(&mut self, endian: BpfEndian) -> &mut Self {
+        self.endian = Some(endian);
+        self
+    }
+
+    /// Build the BPF objects with `@profile`. `BpfProfile::Debug` defines
+    /// `DEBUG` and `SCX_BPF_DEBUG`, which enables `scx_dbg_printk()` in
+    /// `scx/common.bpf.h`, and disables inlining of functions which aren't
+    /// marked `always_inline` so that the verifier log and BTF line info
+    /// map more directly to the source.
+    pub fn set_profile(&mut self, profile: BpfProfile) -> &mut Self {
+        self.profile = profile;
+        self
+    }
+
+    fn cflags(&self) -> Vec<String> {
+        let mut cflags: Vec<String> = self
+            .include_dirs
+            .iter()
+            .map(|dir| format!("-I{}", dir))
+            .chain(self.cflags.iter().cloned())
+            .chain(self.added_cflags.iter().cloned())
+            .collect();
+
+        if let Some(endian) = self.endian {
+            cflags.retain(|x| x != "-mlittle-endian" && x != "-mbig-endian");
+            cflags.push(endian.cflag().into());
+        }
+        cflags.extend(self.profile.cflags().iter().map(|x| x.to_string()));
+        cflags.extend(self.prefix_map_cflags());
+        cflags
+    }
+
+    /// Remap the build-specific absolute paths in the debug info and
+    /// `__FILE__` so that the BPF objects, and thus the skeletons, can be
+    /// reproduced bit-for-bit regardless of the build directories.
+    fn prefix_map_cflags(&self) -> Vec<String> {
+        let mut maps = vec![(self.out_dir.clone(), "/scx_utils-out".to_string())];
+        if let Ok(dir) = env::var("CARGO_MANIFEST_DIR") {
+            maps.push((PathBuf::from(dir), ".".into()));
+        }
+        if let Some(path) = &self.vmlinux_h {
+            if let Ok(path) = std::fs::canonicalize(path) {
+                let dir = match path.is_dir() {
+                    true => path,
+                    false => path.parent().unwrap().to_path_buf(),
+                };
+                maps.push((dir, "/scx_utils-vmlinux_h".into()));
+            }
+        }
+
+        maps.into_iter()
+            .map(|(from, to)| format!("-ffile-prefix-map={}={}", from.to_string_lossy(), to))
+            .collect()
+    }
+
+    /// The clang target for the BPF objects, `bpfel` or `bpfeb`, as
+    /// selected by the endian cflags. `bpf` means host endianness.
+    fn bpf_target(&self) -> &'static str {
+        match self.cflags().iter().rev().find_map(|x| match x.as_str() {
+            "-mlittle-endian" => Some(BpfEndian::Little),
+            "-mbig-endian" => Some(BpfEndian::Big),
+            _ => None,
+        }) {
+            Some(BpfEndian::Little) => "bpfel",
+            Some(BpfEndian::Big) => "bpfeb",
+            None => "bpf",
+        }
     }
 
     /// Enable generation of header bindings using `bindgen`. `@input` is
@@ -278,6 +1100,49 @@ impl BpfBuilder {
         self
     }
 
+    /// Only generate header bindings for the items matching the regex
+    /// `@pattern` and their dependencies. Can be called multiple times.
+    pub fn intf_allowlist(&mut self, pattern: &str) -> &mut Self {
+        self.intf_opts.allowlist.push(pattern.into());
+        self
+    }
+
+    /// Don't generate header bindings for the items matching the regex
+    /// `@pattern`. Can be called multiple times.
+    pub fn intf_blocklist(&mut self, pattern: &str) -> &mut Self {
+        self.intf_opts.blocklist.push(pattern.into());
+        self
+    }
+
+    /// Add `#[derive(@derive)]`, e.g. `serde::Serialize`, to all structs in
+    /// the header bindings. The crate including the bindings must be able
+    /// to resolve the derive macro.
+    pub fn intf_add_derive(&mut self, derive: &str) -> &mut Self {
+        self.intf_opts.derives.push(derive.into());
+        self
+    }
+
+    /// Whether to derive `Default` for the header bindings where possible.
+    /// (Default: `false`)
+    pub fn intf_derive_default(&mut self, enable: bool) -> &mut Self {
+        self.intf_opts.derive_default = enable;
+        self
+    }
+
+    /// Whether to derive `PartialEq` for the header bindings where
+    /// possible. (Default: `false`)
+    pub fn intf_derive_partialeq(&mut self, enable: bool) -> &mut Self {
+        self.intf_opts.derive_partialeq = enable;
+        self
+    }
+
+    /// How C enums are translated in the header bindings. (Default:
+    /// `bindgen::EnumVariation::Consts`)
+    pub fn intf_enum_style(&mut self, style: bindgen::EnumVariation) -> &mut Self {
+        self.intf_opts.enum_style = style;
+        self
+    }
+
     /// Enable compilation of BPF code and generation of the skeleton and
     /// its Rust bindings. `@input` is the `.bpf.c` file containing the BPF
     /// source code and `@output` is the `.rs` file to be generated.
@@ -304,79 +1169,284 @@ impl BpfBuilder {
             None => return Ok(()),
         };
 
+        let opts = &self.intf_opts;
+
         // The bindgen::Builder is the main entry point to bindgen, and lets
         // you build up options for the resulting bindings.
-        let bindings = bindgen::Builder::default()
+        let mut builder = bindgen::Builder::default()
             // Should run clang with the same -I options as BPF compilation.
             .clang_args(
-                self.cflags
+                self.cflags()
                     .iter()
-                    .chain(["-target".into(), "bpf".into()].iter()),
+                    .chain(["-target".into(), self.bpf_target().into()].iter()),
             )
             // The input header we would like to generate bindings for.
             .header(input)
             // Tell cargo to invalidate the built crate whenever any of the
             // included header files changed.
             .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
-            .generate()
-            .context("Unable to generate bindings")?;
+            .derive_default(opts.derive_default)
+            .derive_partialeq(opts.derive_partialeq)
+            .default_enum_style(opts.enum_style);
+
+        for pattern in opts.allowlist.iter() {
+            builder = builder.allowlist_item(pattern);
+        }
+        for pattern in opts.blocklist.iter() {
+            builder = builder.blocklist_item(pattern);
+        }
+        if !opts.derives.is_empty() {
+            builder = builder.parse_callbacks(Box::new(IntfDerives(opts.derives.clone())));
+        }
+
+        let bindings = builder.generate().context("Unable to generate bindings")?;
+        let mut text = bindings.to_string();
+
+        text += &format!(
+            "\n/// Struct layouts in {:?}, see `scx_utils::intf_layout_check!()`.\n\
+             pub const SCX_INTF_LAYOUTS: &[(&str, usize, &[(&str, usize)])] = &[\n",
+            input
+        );
+        for (name, size, fields) in Self::intf_layouts(&bindings.to_string()) {
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|(field, off)| format!("({:?}, {})", field, off))
+                .collect();
+            text += &format!("    ({:?}, {}, &[{}]),\n", name, size, fields.join(", "));
+        }
+        text += "];\n";
 
-        bindings
-            .write_to_file(self.out_dir.join(output))
-            .context("Couldn't write bindings")
+        std::fs::write(self.out_dir.join(output), text).context("Couldn't write bindings")
     }
 
-    pub fn add_source(&mut self, input: &str) -> &mut Self {
-        self.sources.insert(input.into());
-        self
+    /// Extract the struct layouts from the compile-time layout tests in
+    /// the generated bindings, which assert the size and the named field
+    /// offsets of each struct and union. Anonymous members and bitfields
+    /// aren't covered, neither are bindings generated without the tests.
+    fn intf_layouts(bindings: &str) -> Vec<IntfLayout> {
+        // The assertions may be wrapped by the formatter, e.g.
+        // ["Offset of field: foo::bar"][::std::mem::offset_of!(foo, bar) - 8usize];
+        let text = bindings.split_whitespace().collect::<Vec<_>>().join(" ");
+        let mut layouts: Vec<IntfLayout> = vec![];
+
+        for chunk in text.split("[\"").skip(1) {
+            let Some((msg, rest)) = chunk.split_once("\"]") else {
+                continue;
+            };
+            let Some(val) = rest
+                .split_once("- ")
+                .and_then(|(_, val)| val.split_once("usize]"))
+                .and_then(|(val, _)| val.trim().parse::<usize>().ok())
+            else {
+                continue;
+            };
+
+            if let Some(name) = msg.strip_prefix("Size of ") {
+                if !name.contains("__bindgen_ty_") {
+                    layouts.push((name.to_string(), val, vec![]));
+                }
+            } else if let Some(path) = msg.strip_prefix("Offset of field: ") {
+                let Some((name, field)) = path.split_once("::") else {
+                    continue;
+                };
+                if let Some(layout) = layouts.iter_mut().rev().find(|l| l.0 == name) {
+                    layout.2.push((field.to_string(), val));
+                }
+            }
+        }
+        layouts
     }
 
-    pub fn compile_link_gen(&mut self) -> Result<()> {
-        let input = match &self.skel_input_name {
-            Some((name, _)) => name,
-            None => return Ok(()),
-        };
+    /// Enable generation of `@output`, a `.rs` file of constants which
+    /// describe the kernel features probed with the `.probe_*()` methods,
+    /// so that the scheduler can branch on them instead of duplicating the
+    /// compat checks. The kernel BTF set with `.set_features_btf()` is
+    /// probed. It defaults to the BTF `vmlinux.h` is generated from, if
+    /// any, and then `/sys/kernel/btf/vmlinux`.
+    ///
+    /// For each probe, the following constant is generated with the names
+    /// converted to upper case:
+    ///
+    /// - `.probe_kfunc()`: `KFUNC_{NAME}: bool`
+    /// - `.probe_struct_field()`: `STRUCT_{TYPE}_{FIELD}: bool`
+    /// - `.probe_enum()`: `ENUM_{NAME}: Option<u64>`
+    pub fn enable_features(&mut self, output: &str) -> &mut Self {
+        self.features_output = Some(output.into());
+        self
+    }
 
-        // Better to hardcode the name because it gets embedded in
-        // all the skeleton struct/member definitions.
-        let linkobj = self.out_dir.join("bpf.bpf.o");
-        let mut linker = Linker::new(&linkobj)?;
+    /// Probe the kernel BTF file `@btf` for the features instead of the
+    /// default one. See `.enable_features()`.
+    pub fn set_features_btf(&mut self, btf: &str) -> &mut Self {
+        self.features_btf = Some(btf.into());
+        self
+    }
 
-        for filename in self.sources.iter() {
-            let name = Path::new(filename).file_name().unwrap().to_str().unwrap();
-            let obj = self.out_dir.join(name.replace(".bpf.c", ".bpf.o"));
+    /// Probe whether the kernel has kfunc `@name`.
+    pub fn probe_kfunc(&mut self, name: &str) -> &mut Self {
+        self.feature_probes.push(FeatureProbe::Kfunc(name.into()));
+        self
+    }
 
-            let output = SkeletonBuilder::new()
-                .debug(true)
-                .source(filename)
-                .obj(&obj)
-                .clang(&self.clang.clang)
-                .clang_args(&self.cflags)
-                .build()?;
+    /// Probe whether `struct @type_name` has `@field`.
+    pub fn probe_struct_field(&mut self, type_name: &str, field: &str) -> &mut Self {
+        self.feature_probes
+            .push(FeatureProbe::StructField(type_name.into(), field.into()));
+        self
+    }
 
-            for line in String::from_utf8_lossy(output.stderr()).lines() {
-                println!("cargo:warning={}", line);
-            }
+    /// Probe the value of `@name` in `enum @type_name`.
+    pub fn probe_enum(&mut self, type_name: &str, name: &str) -> &mut Self {
+        self.feature_probes
+            .push(FeatureProbe::EnumValue(type_name.into(), name.into()));
+        self
+    }
 
-            linker.add_file(&obj)?;
+    fn features_btf(&self) -> String {
+        match (&self.features_btf, &self.vmlinux_btf) {
+            (Some(btf), _) | (None, Some(btf)) => btf.clone(),
+            (None, None) => "/sys/kernel/btf/vmlinux".into(),
         }
+    }
 
-        linker.link()?;
+    fn gen_features(&self) -> Result<()> {
+        let output = match &self.features_output {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let path = self.features_btf();
+        let btf = crate::core_relo::OwnedBtf::parse(Path::new(&path), false)?;
+        let btf = btf.btf();
+        let const_name = |parts: &[&str]| -> String {
+            parts
+                .join("_")
+                .chars()
+                .map(|c| match c.is_ascii_alphanumeric() {
+                    true => c.to_ascii_uppercase(),
+                    false => '_',
+                })
+                .collect()
+        };
+
+        let mut code = format!(
+            "// Generated by scx_utils::BpfBuilder from {:?}, do not edit.\n\n",
+            &path
+        );
+        for probe in self.feature_probes.iter() {
+            code += &match probe {
+                FeatureProbe::Kfunc(name) => {
+                    let cname = CString::new(name.as_str())?;
+                    let tid = unsafe {
+                        libbpf_rs::libbpf_sys::btf__find_by_name_kind(
+                            btf,
+                            cname.as_ptr(),
+                            libbpf_rs::libbpf_sys::BTF_KIND_FUNC,
+                        )
+                    };
+                    format!(
+                        "pub const {}: bool = {};\n",
+                        const_name(&["KFUNC", name]),
+                        tid >= 0
+                    )
+                }
+                FeatureProbe::StructField(type_name, field) => format!(
+                    "pub const {}: bool = {};\n",
+                    const_name(&["STRUCT", type_name, field]),
+                    crate::compat::struct_has_field_in(btf, type_name, field).unwrap_or(false)
+                ),
+                FeatureProbe::EnumValue(type_name, name) => format!(
+                    "pub const {}: Option<u64> = {:?};\n",
+                    const_name(&["ENUM", name]),
+                    crate::compat::read_enum_in(btf, type_name, name).ok()
+                ),
+            };
+        }
+
+        std::fs::write(self.out_dir.join(output), code)
+            .with_context(|| format!("Couldn't write {:?}", output))
+    }
+
+    /// Add `@input` as an additional `.bpf.c` file to be compiled and
+    /// statically linked into the skeleton enabled with `.enable_skel()`.
+    pub fn add_source(&mut self, input: &str) -> &mut Self {
+        self.sources.insert(input.into());
+        self
+    }
+
+    /// Compile the `.enable_skel()` input and all the `.add_source()`
+    /// files into separate objects, link them into a single object and
+    /// generate the skeleton and the enabled bindings from it.
+    pub fn compile_link_gen(&self) -> Result<()> {
+        let (input, skel_name) = match &self.skel_input_name {
+            Some(pair) => pair,
+            None => return Ok(()),
+        };
+        self.check_compiler()?;
+        self.check_vmlinux_h()?;
+        let cflags = self.cflags();
+
+        // The object name gets embedded in all the skeleton struct/member
+        // definitions, so name it after the skeleton.
+        let linkobj = self.out_dir.join(format!("{}.bpf.o", skel_name));
+        let mut linker = Linker::new(&linkobj)?;
+        let mut objs = vec![];
 
+        for filename in self.sources.iter() {
+            let name = Path::new(filename).file_name().unwrap().to_str().unwrap();
+            let obj = self.out_dir.join(name.replace(".bpf.c", ".bpf.o"));
+            if obj == linkobj {
+                bail!(
+                    "Object for {:?} conflicts with the linked object {:?}",
+                    filename,
+                    &linkobj
+                );
+            }
+            objs.push((filename.as_str(), obj));
+        }
+
+        // The BPF sources may include the layout hash header generated
+        // along with the bindings.
+        self.gen_features()?;
         self.bindgen_bpf_intf()?;
 
-        let skel_path = self.out_dir.join("bpf_skel.rs");
+        self.run_jobs(&objs, |(filename, obj)| {
+            self.compile_obj(filename, obj, &cflags)
+        })?;
+        self.write_compile_commands(
+            &objs
+                .iter()
+                .map(|(f, o)| (*f, o.as_path()))
+                .collect::<Vec<_>>(),
+            &cflags,
+        )?;
+
+        for (_, obj) in objs.iter() {
+            linker.add_file(obj)?;
+        }
+
+        linker.link()?;
+        self.keep_obj_intermediates(&linkobj);
+        self.check_insn_budget(&linkobj)?;
+        self.write_min_core_btfs(skel_name, &linkobj)?;
+        self.check_core_relos(&linkobj)?;
+
+        let skel_path = self.out_dir.join(format!("{}_skel.rs", skel_name));
 
         SkeletonBuilder::new()
             .obj(&linkobj)
             .clang(&self.clang.clang)
-            .clang_args(&self.cflags)
+            .clang_args(&cflags)
             .generate(&skel_path)?;
+        self.compress_skel_data(&linkobj, &skel_path)?;
+        self.post_process_skel(skel_name, &skel_path)?;
 
         let mut deps = BTreeSet::new();
-        self.add_src_deps(&mut deps, input)?;
-        for filename in self.sources.iter() {
+        self.input_insert_deps(&mut deps);
+        deps.insert(input.to_string());
+        for (filename, obj) in objs.iter() {
             deps.insert(filename.to_string());
+            self.add_obj_deps(&mut deps, obj)?;
         }
 
         self.gen_cargo_reruns(Some(&deps))?;
@@ -384,6 +1454,782 @@ impl BpfBuilder {
         Ok(())
     }
 
+    /// Embed the BPF object in the generated skeleton zstd compressed. It's
+    /// inflated when the skeleton is opened for the first time. This can
+    /// significantly reduce the binary size at the cost of a small delay
+    /// when loading. The user crate should depend on `scx_utils`.
+    pub fn compress_skel(&mut self, enable: bool) -> &mut Self {
+        self.compress_skel = enable;
+        self
+    }
+
+    /// Register `@hook` to post-process the generated skeletons. `@hook` is
+    /// called with the skeleton name and the generated Rust source and
+    /// returns the source to be written, e.g. with `pub use` re-exports or
+    /// typed map accessors appended. Hooks are called in the registration
+    /// order after compression if enabled.
+    pub fn add_skel_hook<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&str, String) -> Result<String> + Send + Sync + 'static,
+    {
+        self.skel_hooks.push(SkelHook(Box::new(hook)));
+        self
+    }
+
+    fn post_process_skel(&self, name: &str, skel_path: &Path) -> Result<()> {
+        if self.skel_hooks.is_empty() {
+            return Ok(());
+        }
+
+        let mut skel = std::fs::read_to_string(skel_path)?;
+        for hook in self.skel_hooks.iter() {
+            skel = (hook.0)(name, skel)
+                .with_context(|| format!("Skeleton hook failed for {:?}", name))?;
+        }
+        std::fs::write(skel_path, skel)?;
+        Ok(())
+    }
+
+    /// Rewrite the skeleton at `@skel_path` generated from `@obj` so that it
+    /// embeds the zstd compressed object instead.
+    fn compress_skel_data(&self, obj: &Path, skel_path: &Path) -> Result<()> {
+        if !self.compress_skel {
+            return Ok(());
+        }
+
+        let zst_path = obj.with_extension("o.zst");
+        let data = std::fs::read(obj)?;
+        std::fs::write(&zst_path, zstd::encode_all(&data[..], 19)?)?;
+
+        let skel = std::fs::read_to_string(skel_path)?;
+        let out = Self::compress_skel_text(&skel, &zst_path)
+            .with_context(|| format!("Failed to compress {:?}", skel_path))?;
+        std::fs::write(skel_path, out)?;
+        Ok(())
+    }
+
+    /// Replace the `DATA` item, the BPF object libbpf-cargo embeds in the
+    /// skeleton `@skel`, with one which inflates `@zst_path` on first use.
+    /// The item is indented inside `mod imp` and rustfmt wraps its array
+    /// across many lines, so the whole item up to the `;` is replaced.
+    fn compress_skel_text(skel: &str, zst_path: &Path) -> Result<String> {
+        const DATA_DECL: &str = "const DATA: &[u8] = ";
+        const DATA_USE: &str = "ObjectSkeletonConfigBuilder::new(DATA)";
+
+        let start = skel
+            .find(DATA_DECL)
+            .ok_or(anyhow!("Failed to find the object data"))?;
+        let body = start + DATA_DECL.len();
+        let end = skel[body..]
+            .find(';')
+            .map(|off| body + off)
+            .ok_or(anyhow!("Failed to find the end of the object data"))?;
+        if !skel[body..end]
+            .chars()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_whitespace() || "&[],".contains(c))
+        {
+            bail!("Unexpected object data format");
+        }
+        if !skel.contains(DATA_USE) {
+            bail!("Failed to find the object data user");
+        }
+
+        let line_start = skel[..start].rfind('\n').map(|off| off + 1).unwrap_or(0);
+        let indent = &skel[line_start..start];
+        let data = format!(
+            "static DATA_INFLATED: std::sync::OnceLock<Vec<u8>> = std::sync::OnceLock::new();\n\
+             {}static DATA: std::sync::LazyLock<&'static [u8]> = std::sync::LazyLock::new(|| \
+             scx_utils::misc::inflate_skel_data(&DATA_INFLATED, include_bytes!({:?})));",
+            indent,
+            zst_path.to_string_lossy()
+        );
+
+        let out = format!("{}{}{}", &skel[..start], data, &skel[end + 1..]);
+        Ok(out.replace(DATA_USE, "ObjectSkeletonConfigBuilder::new(*DATA)"))
+    }
+
+    /// Use `@dir` to cache compiled BPF objects. See the struct
+    /// documentation for details.
+    pub fn set_cache_dir(&mut self, dir: &str) -> &mut Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// The per-crate directories which show up in the cflags and
+    /// dependency paths, and the placeholders they're replaced with so that
+    /// the cache can be shared across crates. `OUT_DIR` comes first as it
+    /// may be nested in the crate directory.
+    fn cache_path_maps(&self) -> Vec<(String, &'static str)> {
+        let mut maps = vec![(
+            self.out_dir.to_string_lossy().to_string(),
+            "/scx_utils-cache-out",
+        )];
+        if let Ok(dir) = env::var("CARGO_MANIFEST_DIR") {
+            maps.push((dir, "/scx_utils-cache-crate"));
+        }
+        maps
+    }
+
+    fn strip_crate_paths(&self, s: &str) -> String {
+        let mut s = s.to_string();
+        for (from, to) in self.cache_path_maps() {
+            s = s.replace(&from, to);
+        }
+        s
+    }
+
+    fn restore_crate_paths(&self, s: &str) -> String {
+        let mut s = s.to_string();
+        for (from, to) in self.cache_path_maps() {
+            s = s.replace(to, &from);
+        }
+        s
+    }
+
+    /// The key of the object built from `@source` in the object cache,
+    /// which persists across builds and thus uses `content_hash()`. Each
+    /// input is length prefixed so that adjacent ones can't run together.
+    fn obj_cache_key(&self, source: &str, cflags: &[String]) -> Result<u64> {
+        let mut key: Vec<u8> = vec![];
+        let mut add = |data: &[u8]| {
+            key.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            key.extend_from_slice(data);
+        };
+
+        add(env!("CARGO_PKG_VERSION").as_bytes());
+        add(Self::BPF_H_TAR_ZST);
+        add(self.clang.clang.as_bytes());
+        add(self.clang.ver.as_bytes());
+        if let Some((gcc, ver)) = self.gcc.as_ref() {
+            add(gcc.as_bytes());
+            add(ver.as_bytes());
+        }
+        for cflag in cflags.iter() {
+            add(self.strip_crate_paths(cflag).as_bytes());
+        }
+        add(self.strip_crate_paths(source).as_bytes());
+
+        // The dependency file is generated while compiling, which is too
+        // late for the cache lookup. Ask the compiler to list the
+        // dependencies instead. This covers the bundled and generated
+        // headers too.
+        let (cmd, args) = self.compiler_cmd(cflags);
+        let output = Command::new(&cmd)
+            .args(args)
+            .args(["-M", source])
+            .output()
+            .with_context(|| format!("Failed to scan dependencies of {:?}", source))?;
+        if !output.status.success() {
+            bail!(
+                "Failed to scan dependencies of {:?} ({})",
+                source,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        let deps: BTreeSet<String> = Self::parse_dep_file(&String::from_utf8_lossy(&output.stdout))
+            .into_iter()
+            .collect();
+        for dep in deps.iter() {
+            add(self.strip_crate_paths(dep).as_bytes());
+            add(&std::fs::read(dep).with_context(|| format!("Failed to read {:?}", dep))?);
+        }
+
+        Ok(Self::content_hash(&key))
+    }
+
+    /// The dependency file clang generates with `-MD` when compiling `@obj`.
+    fn dep_file(obj: &Path) -> PathBuf {
+        obj.with_extension("o.d")
+    }
+
+    /// Parse the make rule in a dependency file generated by clang and
+    /// return the prerequisites.
+    fn parse_dep_file(text: &str) -> Vec<String> {
+        let text = text.replace("\\\n", " ");
+        let prereqs = match text.split_once(": ") {
+            Some((_, prereqs)) => prereqs,
+            None => return vec![],
+        };
+
+        let mut deps = vec![];
+        let mut dep = String::new();
+        let mut chars = prereqs.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&' ') => dep.push(chars.next().unwrap()),
+                c if c.is_whitespace() => {
+                    if !dep.is_empty() {
+                        deps.push(std::mem::take(&mut dep));
+                    }
+                }
+                c => dep.push(c),
+            }
+        }
+        if !dep.is_empty() {
+            deps.push(dep);
+        }
+        deps
+    }
+
+    fn compile_obj(&self, source: &str, obj: &Path, cflags: &[String]) -> Result<()> {
+        self.keep_src_intermediates(source, obj, cflags);
+
+        let dep_file = Self::dep_file(obj);
+        let cached = match &self.cache_dir {
+            Some(dir) => {
+                let name = obj.file_name().unwrap().to_string_lossy();
+                let key = self.obj_cache_key(source, cflags)?;
+                Some(PathBuf::from(dir).join(format!("{:016x}-{}", key, name)))
+            }
+            None => None,
+        };
+
+        // The cached dependency files have the per-crate paths replaced with
+        // placeholders, see cache_path_maps().
+        if let Some(cached) = &cached {
+            if std::fs::read_to_string(Self::dep_file(cached))
+                .and_then(|deps| std::fs::write(&dep_file, self.restore_crate_paths(&deps)))
+                .is_ok()
+                && std::fs::copy(cached, obj).is_ok()
+            {
+                println!("scx_utils:cache_hit={:?} {:?}", source, cached);
+                return Ok(());
+            }
+        }
+
+        let dep_args = [
+            "-MD".to_string(),
+            "-MF".to_string(),
+            dep_file.to_string_lossy().to_string(),
+        ];
+        let stderr = match &self.gcc {
+            Some(_) => {
+                let (cmd, args) = self.compiler_cmd(cflags);
+                let output = Command::new(&cmd)
+                    .args(args)
+                    .args(dep_args)
+                    .arg("-c")
+                    .arg(source)
+                    .arg("-o")
+                    .arg(obj)
+                    .output()
+                    .with_context(|| format!("Failed to run {:?}", &cmd))?;
+                if !output.status.success() {
+                    bail!(
+                        "Failed to compile {:?} with {:?} ({}):\n{}",
+                        source,
+                        &cmd,
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr)
+                    );
+                }
+                output.stderr
+            }
+            None => SkeletonBuilder::new()
+                .debug(true)
+                .source(source)
+                .obj(obj)
+                .clang(&self.clang.clang)
+                .clang_args(cflags.iter().cloned().chain(dep_args))
+                .build()?
+                .stderr()
+                .to_vec(),
+        };
+
+        for line in String::from_utf8_lossy(&stderr).lines() {
+            println!("cargo:warning={}", line);
+        }
+
+        // Populate the cache through a temp file and rename so that
+        // concurrent builds never see a partially written object. Failing
+        // to populate the cache isn't fatal.
+        if let Some(cached) = &cached {
+            for (src, dst, strip) in [
+                (dep_file.as_path(), Self::dep_file(cached), true),
+                (obj, cached.clone(), false),
+            ] {
+                let tmp = dst.with_extension(format!("tmp.{}", std::process::id()));
+                let res = std::fs::create_dir_all(dst.parent().unwrap())
+                    .and_then(|_| match strip {
+                        true => std::fs::read_to_string(src)
+                            .and_then(|deps| std::fs::write(&tmp, self.strip_crate_paths(&deps))),
+                        false => std::fs::copy(src, &tmp).map(|_| ()),
+                    })
+                    .and_then(|_| std::fs::rename(&tmp, &dst));
+                if let Err(e) = res {
+                    let _ = std::fs::remove_file(&tmp);
+                    println!("cargo:warning=Failed to cache {:?} ({})", &dst, &e);
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keep the intermediate build artifacts in `OUT_DIR` for debugging,
+    /// e.g. verifier rejections. For each source, the preprocessed source
+    /// and LLVM IR are kept as `.i` and `.ll` next to the object. For each
+    /// final object, the disassembly and the layout printed by
+    /// `print_obj_layout()` are kept as `.objdump` and `.layout`.
+    pub fn keep_intermediates(&mut self, enable: bool) -> &mut Self {
+        self.keep_intermediates = enable;
+        self
+    }
+
+    /// Run `@cmd` writing its stdout into `@output`. Failures aren't fatal
+    /// as the artifacts are only for debugging.
+    fn write_cmd_output(mut cmd: Command, output: &Path) {
+        let res = cmd.output();
+        match res {
+            Ok(out) if out.status.success() => {
+                if let Err(e) = std::fs::write(output, &out.stdout) {
+                    println!("cargo:warning=Failed to write {:?} ({})", output, &e);
+                }
+            }
+            Ok(out) => println!(
+                "cargo:warning=Failed to generate {:?} ({}): {}",
+                output,
+                out.status,
+                String::from_utf8_lossy(&out.stderr).trim()
+            ),
+            Err(e) => println!("cargo:warning=Failed to generate {:?} ({})", output, &e),
+        }
+    }
+
+    fn keep_src_intermediates(&self, source: &str, obj: &Path, cflags: &[String]) {
+        if !self.keep_intermediates {
+            return;
+        }
+
+        // gcc can't emit LLVM IR, keep the assembly instead.
+        let ir = match self.gcc {
+            Some(_) => ("s", vec!["-S"]),
+            None => ("ll", vec!["-S", "-emit-llvm"]),
+        };
+
+        let (compiler, compiler_args) = self.compiler_cmd(cflags);
+        for (ext, args) in [("i", vec!["-E"]), ir] {
+            let mut cmd = Command::new(&compiler);
+            cmd.args(&compiler_args)
+                .args(args)
+                .args([source, "-o", "-"]);
+            Self::write_cmd_output(cmd, &obj.with_extension(ext));
+        }
+    }
+
+    fn keep_obj_intermediates(&self, obj: &Path) {
+        if !self.keep_intermediates {
+            return;
+        }
+
+        let objdump = env::var("LLVM_OBJDUMP").unwrap_or("llvm-objdump".into());
+        let mut cmd = Command::new(&objdump);
+        cmd.args(["-d", "-r", "-S", "--no-show-raw-insn"]).arg(obj);
+        Self::write_cmd_output(cmd, &obj.with_extension("objdump"));
+
+        let layout = obj.with_extension("layout");
+        let res = Self::obj_layout(obj).and_then(|text| Ok(std::fs::write(&layout, text)?));
+        if let Err(e) = res {
+            println!("cargo:warning=Failed to generate {:?} ({:#})", &layout, &e);
+        }
+    }
+
+    /// The (section, name, size) of the symbols of `@kinds` in `@elf`.
+    fn obj_symbols(elf: &object::File, kinds: &[object::SymbolKind]) -> Vec<(String, String, u64)> {
+        use object::{Object, ObjectSection, ObjectSymbol};
+
+        let mut syms = vec![];
+        for sym in elf.symbols().filter(|sym| kinds.contains(&sym.kind())) {
+            let section = match sym.section_index().map(|idx| elf.section_by_index(idx)) {
+                Some(Ok(section)) => section.name().unwrap_or("").to_string(),
+                _ => continue,
+            };
+            match sym.name() {
+                Ok(name) if !name.is_empty() => syms.push((section, name.to_string(), sym.size())),
+                _ => {}
+            }
+        }
+        syms.sort();
+        syms
+    }
+
+    fn obj_layout(obj: &Path) -> Result<String> {
+        use object::{Object, ObjectSection, SectionKind, SymbolKind};
+
+        let data = std::fs::read(obj).with_context(|| format!("Failed to read {:?}", obj))?;
+        let elf =
+            object::File::parse(&*data).with_context(|| format!("Failed to parse {:?}", obj))?;
+
+        let progs = Self::obj_symbols(&elf, &[SymbolKind::Text]);
+        let (maps, vars): (Vec<_>, Vec<_>) = Self::obj_symbols(&elf, &[SymbolKind::Data])
+            .into_iter()
+            .partition(|(section, _, _)| section == ".maps");
+
+        let mut out = format!("{:?}\n", obj);
+        out += &format!("\nPrograms ({}):\n", progs.len());
+        for (section, name, size) in progs.iter() {
+            let subprog = if section.starts_with(".text") {
+                " (subprog)"
+            } else {
+                ""
+            };
+            out += &format!(
+                "  {:<40} {:<32} {:>6} insns{}\n",
+                section,
+                name,
+                size / 8,
+                subprog
+            );
+        }
+
+        out += &format!("\nMaps ({}):\n", maps.len());
+        for (_, name, size) in maps.iter() {
+            out += &format!("  {:<40} {:>6} bytes\n", name, size);
+        }
+
+        out += "\nData sections:\n";
+        for section in elf.sections() {
+            let name = section.name().unwrap_or("");
+            if !matches!(
+                section.kind(),
+                SectionKind::Data | SectionKind::ReadOnlyData | SectionKind::UninitializedData
+            ) || name == ".maps"
+                || name.starts_with(".BTF")
+            {
+                continue;
+            }
+            out += &format!("  {:<40} {:>6} bytes\n", name, section.size());
+            for (_, var, size) in vars.iter().filter(|(s, _, _)| s == name) {
+                out += &format!("    {:<38} {:>6} bytes\n", var, size);
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Warn about the BPF programs which are larger than `@insns`
+    /// instructions. The verifier has to walk through all instructions of
+    /// a program and its subprograms at least once and large programs are
+    /// more likely to hit the complexity limit. Note that the subprograms
+    /// are counted separately. Can also be set with `BPF_INSN_BUDGET`.
+    pub fn set_insn_budget(&mut self, insns: u64) -> &mut Self {
+        self.insn_budget = Some(insns);
+        self
+    }
+
+    fn check_insn_budget(&self, obj: &Path) -> Result<()> {
+        let budget = match self.insn_budget {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let data = std::fs::read(obj).with_context(|| format!("Failed to read {:?}", obj))?;
+        let elf =
+            object::File::parse(&*data).with_context(|| format!("Failed to parse {:?}", obj))?;
+
+        for (section, name, size) in Self::obj_symbols(&elf, &[object::SymbolKind::Text]) {
+            let insns = size / 8;
+            println!("scx_utils:insns={} {} {}", &section, &name, insns);
+            if insns > budget {
+                println!(
+                    "cargo:warning={:?}: {} ({}) has {} insns exceeding the budget of {}",
+                    obj.file_name().unwrap_or_default(),
+                    &name,
+                    &section,
+                    insns,
+                    budget
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Print the verifier-relevant layout of the BPF object `@obj` - the
+    /// programs with their instruction counts, the maps and the global
+    /// data sections with their variables.
+    pub fn print_obj_layout(obj: &Path) -> Result<()> {
+        print!("{}", Self::obj_layout(obj)?);
+        Ok(())
+    }
+
+    /// Run `@func` on each of `@items` on up to `NUM_JOBS` threads, which
+    /// is set by cargo for build scripts. The first error is returned.
+    fn run_jobs<T, F>(&self, items: &[T], func: F) -> Result<()>
+    where
+        T: Sync,
+        F: Fn(&T) -> Result<()> + Sync,
+    {
+        let nr_jobs = env::var("NUM_JOBS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .or_else(|| std::thread::available_parallelism().ok().map(|v| v.get()))
+            .unwrap_or(1)
+            .clamp(1, items.len().max(1));
+        let next = AtomicUsize::new(0);
+
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..nr_jobs)
+                .map(|_| {
+                    scope.spawn(|| -> Result<()> {
+                        loop {
+                            let idx = next.fetch_add(1, Ordering::Relaxed);
+                            match items.get(idx) {
+                                Some(item) => func(item)?,
+                                None => return Ok(()),
+                            }
+                        }
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .map(|w| {
+                    w.join()
+                        .unwrap_or_else(|_| Err(anyhow!("BPF build job panicked")))
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        Ok(())
+    }
+
+    /// Compile multiple independent BPF skeletons concurrently. Unlike
+    /// `.enable_skel()`, each `SkelSpec` is compiled into its own object
+    /// and skeleton named `{name}_skel.rs`. Header bindings are generated
+    /// if enabled with `.enable_intf()`.
+    pub fn gen_bpf_skels(&self, specs: &[SkelSpec]) -> Result<()> {
+        self.check_compiler()?;
+        self.check_vmlinux_h()?;
+        let cflags = self.cflags();
+
+        self.gen_features()?;
+        self.bindgen_bpf_intf()?;
+
+        self.run_jobs(specs, |spec| {
+            let obj = self.out_dir.join(format!("{}.bpf.o", &spec.name));
+            let skel_path = self.out_dir.join(format!("{}_skel.rs", &spec.name));
+
+            self.compile_obj(&spec.input, &obj, &cflags)?;
+            self.keep_obj_intermediates(&obj);
+            self.check_insn_budget(&obj)?;
+            self.write_min_core_btfs(&spec.name, &obj)?;
+            self.check_core_relos(&obj)?;
+
+            SkeletonBuilder::new()
+                .obj(&obj)
+                .clang(&self.clang.clang)
+                .clang_args(&cflags)
+                .generate(&skel_path)?;
+            self.compress_skel_data(&obj, &skel_path)?;
+            self.post_process_skel(&spec.name, &skel_path)?;
+            Ok(())
+        })?;
+
+        let objs: Vec<_> = specs
+            .iter()
+            .map(|spec| {
+                let obj = self.out_dir.join(format!("{}.bpf.o", &spec.name));
+                (spec.input.as_str(), obj)
+            })
+            .collect();
+        self.write_compile_commands(
+            &objs
+                .iter()
+                .map(|(f, o)| (*f, o.as_path()))
+                .collect::<Vec<_>>(),
+            &cflags,
+        )?;
+
+        let mut deps = BTreeSet::new();
+        self.input_insert_deps(&mut deps);
+        for (input, obj) in objs.iter() {
+            deps.insert(input.to_string());
+            self.add_obj_deps(&mut deps, obj)?;
+        }
+
+        self.gen_cargo_reruns(Some(&deps))
+    }
+
+    /// Write `compile_commands.json` for the BPF sources into `@dir` so
+    /// that clangd and other tools can find the right compiler flags. The
+    /// entries for other source files already in the file are preserved.
+    pub fn set_compile_commands_dir(&mut self, dir: &str) -> &mut Self {
+        self.compile_commands_dir = Some(dir.into());
+        self
+    }
+
+    fn write_compile_commands(&self, objs: &[(&str, &Path)], cflags: &[String]) -> Result<()> {
+        let dir = match &self.compile_commands_dir {
+            Some(v) => PathBuf::from(v),
+            None => return Ok(()),
+        };
+        let path = dir.join("compile_commands.json");
+        let cwd = env::current_dir()?;
+
+        let mut entries: Vec<serde_json::Value> = match std::fs::read(&path) {
+            Ok(v) => serde_json::from_slice(&v)
+                .with_context(|| format!("Failed to parse {:?}", &path))?,
+            Err(_) => vec![],
+        };
+
+        for (source, obj) in objs.iter() {
+            let file = cwd.join(source).to_string_lossy().to_string();
+            let (cmd, args) = self.compiler_cmd(cflags);
+            let mut arguments = vec![cmd];
+            arguments.extend(args);
+            arguments.extend(["-c".into(), file.clone()]);
+            arguments.extend(["-o".into(), obj.to_string_lossy().to_string()]);
+
+            entries.retain(|e| e["file"].as_str() != Some(&file));
+            entries.push(serde_json::json!({
+                "directory": cwd.to_string_lossy(),
+                "file": file,
+                "arguments": arguments,
+            }));
+        }
+
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(&path, serde_json::to_string_pretty(&entries)?)
+            .with_context(|| format!("Failed to write {:?}", &path))
+    }
+
+    /// Generate the minimal BTF required by the CO-RE relocations of the
+    /// BPF object for each kernel BTF file `@btf`, which can be either a
+    /// file or a directory containing the BTF files. The resulting BTFs are
+    /// embedded in `{name}_min_core_btf.rs` as `MIN_CORE_BTFS`, a list of
+    /// (kernel release, blob) pairs, which can be used with
+    /// `compat::min_core_btf_path()` to load on kernels without
+    /// `/sys/kernel/btf/vmlinux`. The file names, without the `.btf`
+    /// extension if present, are expected to be the kernel release as
+    /// reported by `uname -r`.
+    pub fn gen_min_core_btf(&mut self, btf: &str) -> &mut Self {
+        self.min_core_btf_inputs.push(btf.into());
+        self
+    }
+
+    fn min_core_btf_input_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for input in self.min_core_btf_inputs.iter() {
+            let path = PathBuf::from(input);
+            if path.is_dir() {
+                for ent in std::fs::read_dir(&path)? {
+                    let ent = ent?;
+                    if ent.file_type()?.is_file() {
+                        files.push(ent.path());
+                    }
+                }
+            } else {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// The kernel release a BTF file is for. Only the `.btf` extension is
+    /// stripped as releases like "6.6.0-foo" contain dots.
+    fn min_core_btf_release(input: &Path) -> Result<String> {
+        let name = input
+            .file_name()
+            .ok_or(anyhow!("Invalid BTF file name {:?}", input))?
+            .to_string_lossy();
+        Ok(name.strip_suffix(".btf").unwrap_or(&name).to_string())
+    }
+
+    fn write_min_core_btfs(&self, name: &str, obj: &Path) -> Result<()> {
+        if self.min_core_btf_inputs.is_empty() {
+            return Ok(());
+        }
+
+        let bpftool = env::var("BPFTOOL").unwrap_or("bpftool".into());
+        let dir = self.out_dir.join(format!("{}_min_core_btf", name));
+        std::fs::create_dir_all(&dir)?;
+
+        let inputs = self.min_core_btf_input_files()?;
+        let mut code = String::from("pub const MIN_CORE_BTFS: &[(&str, &[u8])] = &[\n");
+
+        for input in inputs.iter() {
+            let release = Self::min_core_btf_release(input)?;
+            let output = dir.join(format!("{}.btf", &release));
+
+            let res = Command::new(&bpftool)
+                .args(["gen", "min_core_btf"])
+                .arg(input)
+                .arg(&output)
+                .arg(obj)
+                .output()
+                .with_context(|| format!("Failed to run \"{} gen min_core_btf\"", &bpftool))?;
+            if !res.status.success() {
+                bail!(
+                    "\"{} gen min_core_btf {:?}\" failed ({}): {}",
+                    &bpftool,
+                    input,
+                    res.status,
+                    String::from_utf8_lossy(&res.stderr)
+                );
+            }
+
+            code.push_str(&format!(
+                "    ({:?}, include_bytes!({:?})),\n",
+                &release,
+                output.to_string_lossy()
+            ));
+            println!("cargo:rerun-if-changed={}", input.to_string_lossy());
+        }
+
+        code.push_str("];\n");
+        std::fs::write(self.out_dir.join(format!("{}_min_core_btf.rs", name)), code)?;
+        Ok(())
+    }
+
+    /// Verify that all CO-RE relocations of the built BPF object resolve
+    /// on each kernel BTF file in the directory `@dir` and fail the build
+    /// with a per-kernel report otherwise. Relocations guarded by
+    /// `bpf_core_*_exists()` probes are not considered failures.
+    pub fn verify_core_relos(&mut self, dir: &str) -> &mut Self {
+        self.core_btf_dirs.push(dir.into());
+        self
+    }
+
+    fn check_core_relos(&self, obj: &Path) -> Result<()> {
+        let mut report = String::new();
+
+        for dir in self.core_btf_dirs.iter() {
+            let mut btfs: Vec<PathBuf> = std::fs::read_dir(dir)
+                .with_context(|| format!("Failed to read BTF directory {:?}", dir))?
+                .filter_map(|ent| ent.ok().map(|ent| ent.path()))
+                .filter(|path| path.is_file())
+                .collect();
+            btfs.sort();
+
+            for btf in btfs.iter() {
+                let failures = crate::core_relo::verify_core_relos(obj, btf)
+                    .with_context(|| format!("Failed to verify {:?} against {:?}", obj, btf))?;
+                if failures.is_empty() {
+                    continue;
+                }
+                report += &format!("\n{}:", btf.display());
+                for failure in failures.iter() {
+                    report += &format!("\n  {}", failure);
+                }
+            }
+
+            println!("cargo:rerun-if-changed={}", dir);
+        }
+
+        if !report.is_empty() {
+            bail!(
+                "Unresolved CO-RE relocations in {:?}:{}",
+                obj.file_name().unwrap(),
+                report
+            );
+        }
+        Ok(())
+    }
+
     fn gen_bpf_skel(&self, deps: &mut BTreeSet<String>) -> Result<()> {
         let (input, name) = match &self.skel_input_name {
             Some(pair) => pair,
@@ -393,36 +2239,41 @@ impl BpfBuilder {
         let obj = self.out_dir.join(format!("{}.bpf.o", name));
         let skel_path = self.out_dir.join(format!("{}_skel.rs", name));
 
-        let output = SkeletonBuilder::new()
-            .source(input)
+        let cflags = self.cflags();
+
+        self.compile_obj(input, &obj, &cflags)?;
+        self.write_compile_commands(&[(input, &obj)], &cflags)?;
+        self.keep_obj_intermediates(&obj);
+        self.check_insn_budget(&obj)?;
+        self.write_min_core_btfs(name, &obj)?;
+        self.check_core_relos(&obj)?;
+
+        SkeletonBuilder::new()
             .obj(&obj)
             .clang(&self.clang.clang)
-            .clang_args(&self.cflags)
-            .build_and_generate(&skel_path)?;
-
-        for line in String::from_utf8_lossy(output.stderr()).lines() {
-            println!("cargo:warning={}", line);
-        }
+            .clang_args(&cflags)
+            .generate(&skel_path)?;
+        self.compress_skel_data(&obj, &skel_path)?;
+        self.post_process_skel(name, &skel_path)?;
 
-        self.add_src_deps(deps, input)?;
+        self.add_obj_deps(deps, &obj)?;
 
         Ok(())
     }
 
-    fn add_src_deps(&self, deps: &mut BTreeSet<String>, input: &str) -> Result<()> {
-        let c_path = PathBuf::from(input);
-        let dir = c_path
-            .parent()
-            .ok_or(anyhow!("Source {:?} doesn't have parent dir", c_path))?
-            .to_str()
-            .ok_or(anyhow!("Parent dir of {:?} isn't a UTF-8 string", c_path))?;
-
-        for path in glob(&format!("{}/*.[hc]", dir))?.filter_map(Result::ok) {
-            deps.insert(
-                path.to_str()
-                    .ok_or(anyhow!("Path {:?} is not a valid string", path))?
-                    .to_string(),
-            );
+    /// Add the files `@obj` was built from, as recorded in the dependency
+    /// file by clang, to `@deps`. The headers under `OUT_DIR` are skipped
+    /// as they're either installed by `BpfBuilder` on each run or tracked
+    /// through their own inputs.
+    fn add_obj_deps(&self, deps: &mut BTreeSet<String>, obj: &Path) -> Result<()> {
+        let dep_file = Self::dep_file(obj);
+        let text = std::fs::read_to_string(&dep_file)
+            .with_context(|| format!("Failed to read {:?}", &dep_file))?;
+
+        for dep in Self::parse_dep_file(&text) {
+            if !Path::new(&dep).starts_with(&self.out_dir) {
+                deps.insert(dep);
+            }
         }
 
         Ok(())
@@ -430,10 +2281,30 @@ impl BpfBuilder {
 
     fn gen_cargo_reruns(&self, dependencies: Option<&BTreeSet<String>>) -> Result<()> {
         println!("cargo:rerun-if-env-changed=BPF_CLANG");
+        println!("cargo:rerun-if-env-changed=BPF_GCC");
         println!("cargo:rerun-if-env-changed=BPF_CFLAGS");
         println!("cargo:rerun-if-env-changed=BPF_BASE_CFLAGS");
         println!("cargo:rerun-if-env-changed=BPF_EXTRA_CFLAGS_PRE_INCL");
         println!("cargo:rerun-if-env-changed=BPF_EXTRA_CFLAGS_POST_INCL");
+        println!("cargo:rerun-if-env-changed=BPF_VMLINUX_BTF");
+        println!("cargo:rerun-if-env-changed=BPF_VMLINUX_H");
+        println!("cargo:rerun-if-env-changed=BPFTOOL");
+        println!("cargo:rerun-if-env-changed=BPF_CACHE_DIR");
+        println!("cargo:rerun-if-env-changed=BPF_COMPILE_COMMANDS_DIR");
+        println!("cargo:rerun-if-env-changed=BPF_KEEP_INTERMEDIATES");
+        println!("cargo:rerun-if-env-changed=BPF_PROFILE");
+        println!("cargo:rerun-if-env-changed=BPF_FEATURES_BTF");
+        println!("cargo:rerun-if-env-changed=BPF_INSN_BUDGET");
+        println!("cargo:rerun-if-env-changed=LLVM_OBJDUMP");
+        if let Some(btf) = &self.vmlinux_btf {
+            println!("cargo:rerun-if-changed={}", btf);
+        }
+        if let Some(vmlinux_h) = &self.vmlinux_h {
+            println!("cargo:rerun-if-changed={}", vmlinux_h);
+        }
+        if self.features_output.is_some() {
+            println!("cargo:rerun-if-changed={}", self.features_btf());
+        }
         if let Some(deps) = dependencies {
             for dep in deps.iter() {
                 println!("cargo:rerun-if-changed={}", dep);
@@ -447,12 +2318,21 @@ impl BpfBuilder {
         Ok(())
     }
 
-    /// Build and generate the enabled bindings.
+    /// Build and generate the enabled bindings. If there are sources
+    /// added with `.add_source()`, this is equivalent to
+    /// `.compile_link_gen()`.
     pub fn build(&self) -> Result<()> {
+        if self.sources.len() > 1 {
+            return self.compile_link_gen();
+        }
+        self.check_compiler()?;
+        self.check_vmlinux_h()?;
+
         let mut deps = BTreeSet::new();
 
         self.input_insert_deps(&mut deps);
 
+        self.gen_features()?;
         self.bindgen_bpf_intf()?;
         self.gen_bpf_skel(&mut deps)?;
         self.gen_cargo_reruns(Some(&deps))?;
@@ -476,11 +2356,200 @@ mod tests {
         assert!(res.is_ok(), "Failed to create BpfBuilder ({:?})", res);
     }
 
+    #[test]
+    fn test_add_cflags_set_target() {
+        let td = tempfile::tempdir().unwrap();
+        unsafe { std::env::set_var("OUT_DIR", td.path()) };
+
+        let mut builder = super::BpfBuilder::new().unwrap();
+        builder.add_cflags(&["-DADDED_FLAG"]);
+        builder.set_target("aarch64").unwrap();
+
+        // The automatic flags are re-determined for the new target while
+        // the added ones are kept at the end.
+        let cflags = builder.cflags();
+        assert!(cflags.iter().any(|x| x.ends_with("/arch/arm64")));
+        assert!(!cflags.iter().any(|x| x.ends_with("/arch/x86")));
+        assert_eq!(cflags.iter().filter(|x| *x == "-DADDED_FLAG").count(), 1);
+
+        builder.set_sysroot("/").unwrap();
+        assert!(builder.cflags().iter().any(|x| x == "-DADDED_FLAG"));
+
+        builder.set_cflags(&["-O2"]);
+        assert!(!builder.cflags().iter().any(|x| x == "-DADDED_FLAG"));
+    }
+
+    #[test]
+    fn test_install_bpf_h() {
+        let td = tempfile::tempdir().unwrap();
+        let policy = super::OverwritePolicy::Always;
+
+        let installed = super::BpfBuilder::install_bpf_h(td.path(), &["scx/"], policy).unwrap();
+        assert!(installed.contains(&td.path().join("scx/common.bpf.h")));
+        assert!(installed
+            .iter()
+            .all(|x| x.starts_with(td.path().join("scx"))));
+        assert!(!td.path().join("arch").exists());
+
+        // Identical files shouldn't be rewritten.
+        let installed = super::BpfBuilder::install_bpf_h(td.path(), &["scx/"], policy).unwrap();
+        assert!(installed.is_empty());
+    }
+
+    #[test]
+    fn test_install_bpf_h_skip_if_newer() {
+        let td = tempfile::tempdir().unwrap();
+        let install = |policy| super::BpfBuilder::install_bpf_h(td.path(), &["scx/"], policy);
+
+        install(super::OverwritePolicy::Always).unwrap();
+
+        // Locally modified files are kept.
+        let modified = td.path().join("scx/common.bpf.h");
+        std::fs::write(&modified, "modified").unwrap();
+        let installed = install(super::OverwritePolicy::SkipIfNewer).unwrap();
+        assert!(installed.is_empty());
+        assert_eq!(std::fs::read_to_string(&modified).unwrap(), "modified");
+
+        // Files which are still as installed by an older bundle are updated.
+        let manifest_path = td.path().join(super::BpfBuilder::BPF_H_MANIFEST);
+        let manifest: String = std::fs::read_to_string(&manifest_path)
+            .unwrap()
+            .lines()
+            .map(|line| match line.ends_with(" scx/common.bpf.h") {
+                true => format!(
+                    "{:016x} scx/common.bpf.h\n",
+                    super::BpfBuilder::content_hash(b"modified")
+                ),
+                false => format!("{}\n", line),
+            })
+            .collect();
+        std::fs::write(&manifest_path, manifest).unwrap();
+        let installed = install(super::OverwritePolicy::SkipIfNewer).unwrap();
+        assert_eq!(installed, vec![modified.clone()]);
+        assert_ne!(std::fs::read_to_string(&modified).unwrap(), "modified");
+    }
+
+    #[test]
+    fn test_gcc_cflags() {
+        let cflags: Vec<String> = ["-g", "-O2", "-mcpu=v3", "-target", "bpfel", "--target=bpf"]
+            .into_iter()
+            .map(|x| x.into())
+            .collect();
+        assert_eq!(
+            super::BpfBuilder::gcc_cflags(&cflags),
+            vec!["-gbtf", "-mco-re", "-O2", "-mcpu=v3"]
+        );
+    }
+
+    #[test]
+    fn test_intf_layouts() {
+        let bindings = r#"
+const _: () = {
+    ["Size of task_ctx"][::std::mem::size_of::<task_ctx>() - 16usize];
+    ["Alignment of task_ctx"][::std::mem::align_of::<task_ctx>() - 8usize];
+    ["Offset of field: task_ctx::pid"][::std::mem::offset_of!(task_ctx, pid) - 0usize];
+    ["Offset of field: task_ctx::vtime"]
+        [::std::mem::offset_of!(task_ctx, vtime) - 8usize];
+};
+const _: () = {
+    ["Size of task_ctx__bindgen_ty_1"][::std::mem::size_of::<task_ctx__bindgen_ty_1>() - 4usize];
+    ["Offset of field: task_ctx__bindgen_ty_1::a"]
+        [::std::mem::offset_of!(task_ctx__bindgen_ty_1, a) - 0usize];
+};
+const _: () = {
+    ["Size of cpu_ctx"][::std::mem::size_of::<cpu_ctx>() - 64usize];
+};
+"#;
+        assert_eq!(
+            super::BpfBuilder::intf_layouts(bindings),
+            vec![
+                (
+                    "task_ctx".to_string(),
+                    16,
+                    vec![("pid".to_string(), 0), ("vtime".to_string(), 8)]
+                ),
+                ("cpu_ctx".to_string(), 64, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_dep_file() {
+        let deps = super::BpfBuilder::parse_dep_file(
+            "/out/main.bpf.o: src/bpf/main.bpf.c src/bpf/intf.h \\\n  ../../include/scx/common.bpf.h \\\n  src/bpf/with\\ space.h\n",
+        );
+        assert_eq!(
+            deps,
+            vec![
+                "src/bpf/main.bpf.c",
+                "src/bpf/intf.h",
+                "../../include/scx/common.bpf.h",
+                "src/bpf/with space.h",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compress_skel_text() {
+        // Trimmed down skeleton as generated by libbpf-cargo and rustfmt.
+        let skel = r#"// SPDX-License-Identifier: (LGPL-2.1 OR BSD-2-Clause)
+//
+// THIS FILE IS AUTOGENERATED BY CARGO-LIBBPF-GEN!
+
+pub use self::imp::*;
+
+#[allow(dead_code)]
+mod imp {
+    #[allow(unused_imports)]
+    use super::*;
+    fn build_skel_config(
+    ) -> libbpf_rs::Result<libbpf_rs::__internal_skel::ObjectSkeletonConfig<'static>> {
+        let mut builder = libbpf_rs::__internal_skel::ObjectSkeletonConfigBuilder::new(DATA);
+        builder.name("main_bpf").map("main_bpf.bss", true);
+        builder.build()
+    }
+    impl MainSkel<'_> {}
+    const DATA: &[u8] = &[
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
+        25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47,
+    ];
+}
+"#;
+        let out = super::BpfBuilder::compress_skel_text(
+            skel,
+            std::path::Path::new("/out/main.bpf.o.zst"),
+        )
+        .unwrap();
+
+        assert!(out.contains("ObjectSkeletonConfigBuilder::new(*DATA);"));
+        assert!(out.contains(
+            "\n    static DATA_INFLATED: std::sync::OnceLock<Vec<u8>> = std::sync::OnceLock::new();\n    static DATA: "
+        ));
+        assert!(out.contains("include_bytes!(\"/out/main.bpf.o.zst\")));\n}\n"));
+        assert!(!out.contains("const DATA"));
+        assert!(!out.contains("25, 26"));
+        assert!(out.starts_with(&skel[..skel.find("    fn build_skel_config").unwrap()]));
+
+        assert!(
+            super::BpfBuilder::compress_skel_text("mod imp {}", std::path::Path::new("x")).is_err()
+        );
+    }
+
+    #[test]
+    fn test_min_core_btf_release() {
+        let release = |path: &str| {
+            super::BpfBuilder::min_core_btf_release(std::path::Path::new(path)).unwrap()
+        };
+        assert_eq!(release("/btfs/6.6.0-foo.btf"), "6.6.0-foo");
+        assert_eq!(release("/btfs/6.6.0-foo"), "6.6.0-foo");
+        assert_eq!(release("6.9.0-rc1.x86_64.btf"), "6.9.0-rc1.x86_64");
+    }
+
     #[test]
     fn test_vmlinux_h_ver_sha1() {
         let clang_info = ClangInfo::new().unwrap();
 
-        let mut ar = tar::Archive::new(super::BpfBuilder::BPF_H_TAR);
+        let mut ar = super::BpfBuilder::bpf_h_tar().unwrap();
         let mut found = false;
 
         let pattern = Regex::new(r"arch\/.*\/vmlinux-.*.h").unwrap();
@@ -515,4 +2584,23 @@ mod tests {
 
         assert!(found);
     }
+
+    #[test]
+    fn test_vmlinux_h_version() {
+        let ver = super::BpfBuilder::vmlinux_h_version("x86").unwrap();
+        assert_eq!(ver.arch, "x86");
+        assert!(ver.version >= crate::KernelVersion::new(6, 0, 0));
+        assert!(regex::Regex::new(r"^[0-9a-z]{12}$")
+            .unwrap()
+            .is_match(&ver.sha));
+        assert!(super::BpfBuilder::vmlinux_h_version("no-such-arch").is_err());
+    }
+
+    #[test]
+    fn test_vmlinux_h_archs() {
+        let archs = super::BpfBuilder::vmlinux_h_archs().unwrap();
+        for arch in ["x86", "arm64", "riscv", "s390"] {
+            assert!(archs.contains(arch), "vmlinux.h for {} missing", arch);
+        }
+    }
 }
@@ -21,12 +21,15 @@ impl Builder {
 
     fn gen_bpf_h(&self) {
         let out_dir = env::var("OUT_DIR").unwrap();
-        let file = File::create(PathBuf::from(&out_dir).join(format!("{}.tar", BPF_H))).unwrap();
-        let mut ar = tar::Builder::new(file);
+        let file =
+            File::create(PathBuf::from(&out_dir).join(format!("{}.tar.zst", BPF_H))).unwrap();
+        let enc = zstd::stream::Encoder::new(file, 19).unwrap();
+        let mut ar = tar::Builder::new(enc);
 
         ar.follow_symlinks(false);
+        ar.mode(tar::HeaderMode::Deterministic);
         ar.append_dir_all(".", BPF_H).unwrap();
-        ar.finish().unwrap();
+        ar.into_inner().unwrap().finish().unwrap();
 
         for ent in walkdir::WalkDir::new(BPF_H) {
             let ent = ent.unwrap();
@@ -34,17 +34,34 @@ pub struct ClangInfo {
     pub clang: String,
     pub ver: String,
     pub arch: String,
+    pub target: Option<String>,
+    pub sysroot: Option<String>,
 }
 
 impl ClangInfo {
     pub fn new() -> Result<ClangInfo> {
+        Self::with_clang(&env::var("BPF_CLANG").unwrap_or("clang".into()))
+    }
+
+    pub fn with_clang(clang: &str) -> Result<ClangInfo> {
+        Self::with_target(clang, env::var("TARGET").ok(), None)
+    }
+
+    /// Probe `clang` for the userspace `target`, which can be a full
+    /// triple or just the architecture, with the optional `sysroot`. If
+    /// `target` is `None`, clang's default target is used.
+    pub fn with_target(
+        clang: &str,
+        target: Option<String>,
+        sysroot: Option<String>,
+    ) -> Result<ClangInfo> {
         let mut clang_args = vec!["--version".to_string()];
 
-        if let Ok(target) = env::var("TARGET") {
+        if let Some(target) = &target {
             clang_args.push(format!("--target={}", target));
         }
 
-        let clang = env::var("BPF_CLANG").unwrap_or("clang".into());
+        let clang = clang.to_string();
         let output = Command::new(&clang)
             .args(clang_args)
             .output()
@@ -73,21 +90,47 @@ impl ClangInfo {
             arch.ok_or(anyhow!("Failed to read clang target arch"))?,
         );
 
-        if version_compare::compare(&ver, "16") == Ok(version_compare::Cmp::Lt) {
+        Ok(ClangInfo {
+            clang,
+            ver,
+            arch,
+            target,
+            sysroot,
+        })
+    }
+
+    /// Check whether the clang version is recent enough to compile BPF.
+    /// This doesn't matter if clang is only used for probing, e.g. when
+    /// compiling with gcc.
+    #[allow(dead_code)] // for it is not used during build script execution
+    pub fn check_bpf_ver(&self) -> Result<()> {
+        if version_compare::compare(&self.ver, "16") == Ok(version_compare::Cmp::Lt) {
             bail!(
                 "clang < 16 loses high 32 bits of 64 bit enums when compiling BPF ({:?} ver={:?})",
-                &clang,
-                &ver
+                &self.clang,
+                &self.ver
             );
         }
-        if version_compare::compare(&ver, "17") == Ok(version_compare::Cmp::Lt) {
+        if version_compare::compare(&self.ver, "17") == Ok(version_compare::Cmp::Lt) {
             println!(
                 "cargo:warning=clang >= 17 recommended ({:?} ver={:?})",
-                &clang, &ver
+                &self.clang, &self.ver
             );
         }
+        Ok(())
+    }
 
-        Ok(ClangInfo { clang, ver, arch })
+    /// The arguments to pass when probing clang for the userspace target.
+    #[allow(dead_code)] // for it is not used during build script execution
+    fn target_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(target) = &self.target {
+            args.push(format!("--target={}", target));
+        }
+        if let Some(sysroot) = &self.sysroot {
+            args.push(format!("--sysroot={}", sysroot));
+        }
+        args
     }
 
     fn skip_clang_version_prefix(line: &str) -> &str {
@@ -112,6 +155,7 @@ impl ClangInfo {
 
         // Determine system includes.
         let output = Command::new(&self.clang)
+            .args(self.target_args())
             .args(["-v", "-E", "-"])
             .output()
             .with_context(|| format!("Failed to run \"{} -v -E - < /dev/null", self.clang))?;
@@ -139,6 +183,7 @@ impl ClangInfo {
 
         // Determine endian.
         let output = Command::new(&self.clang)
+            .args(self.target_args())
             .args(["-dM", "-E", "-"])
             .output()
             .with_context(|| format!("Failed to run \"{} -dM E - < /dev/null", self.clang))?;
@@ -168,6 +213,9 @@ impl ClangInfo {
         cflags.push(format!("-D__TARGET_ARCH_{}", &kernel_target));
         cflags.push("-mcpu=v3".into());
         cflags.push(format!("-m{}-endian", endian));
+        if let Some(sysroot) = &self.sysroot {
+            cflags.push(format!("--sysroot={}", sysroot));
+        }
         cflags.append(
             &mut sys_incls
                 .into_iter()
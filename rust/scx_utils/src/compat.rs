@@ -7,6 +7,7 @@ use anyhow::{anyhow, bail, Context, Result};
 use libbpf_rs::libbpf_sys::*;
 use libbpf_rs::{AsRawLibbpf, OpenProgramImpl};
 use log::warn;
+use std::collections::HashMap;
 use std::ffi::c_void;
 use std::ffi::CStr;
 use std::ffi::CString;
@@ -35,6 +36,8 @@ lazy_static::lazy_static! {
         read_enum("scx_ops_flags", "SCX_OPS_ALLOW_QUEUED_WAKEUP").unwrap_or(0);
     pub static ref SCX_OPS_BUILTIN_IDLE_PER_NODE: u64 =
         read_enum("scx_ops_flags", "SCX_OPS_BUILTIN_IDLE_PER_NODE").unwrap_or(0);
+    pub static ref SCX_OPS_HAS_CGROUP_WEIGHT: u64 =
+        read_enum("scx_ops_flags", "SCX_OPS_HAS_CGROUP_WEIGHT").unwrap_or(0);
 
     pub static ref SCX_PICK_IDLE_CORE: u64 =
         read_enum("scx_pick_idle_cpu_flags", "SCX_PICK_IDLE_CORE").unwrap_or(0);
@@ -54,35 +57,35 @@ lazy_static::lazy_static! {
     static ref VMLINUX_BTF: &'static mut btf = load_vmlinux_btf();
 }
 
-fn btf_kind(t: &btf_type) -> u32 {
+pub(crate) fn btf_kind(t: &btf_type) -> u32 {
     (t.info >> 24) & 0x1f
 }
 
-fn btf_vlen(t: &btf_type) -> u32 {
+pub(crate) fn btf_vlen(t: &btf_type) -> u32 {
     t.info & 0xffff
 }
 
-fn btf_type_plus_1(t: &btf_type) -> *const c_void {
+pub(crate) fn btf_type_plus_1(t: &btf_type) -> *const c_void {
     let ptr_val = t as *const btf_type as usize;
     (ptr_val + size_of::<btf_type>()) as *const c_void
 }
 
-fn btf_enum(t: &btf_type) -> &[btf_enum] {
+pub(crate) fn btf_enum(t: &btf_type) -> &[btf_enum] {
     let ptr = btf_type_plus_1(t);
     unsafe { from_raw_parts(ptr as *const btf_enum, btf_vlen(t) as usize) }
 }
 
-fn btf_enum64(t: &btf_type) -> &[btf_enum64] {
+pub(crate) fn btf_enum64(t: &btf_type) -> &[btf_enum64] {
     let ptr = btf_type_plus_1(t);
     unsafe { from_raw_parts(ptr as *const btf_enum64, btf_vlen(t) as usize) }
 }
 
-fn btf_members(t: &btf_type) -> &[btf_member] {
+pub(crate) fn btf_members(t: &btf_type) -> &[btf_member] {
     let ptr = btf_type_plus_1(t);
     unsafe { from_raw_parts(ptr as *const btf_member, btf_vlen(t) as usize) }
 }
 
-fn btf_name_str_by_offset(btf: &btf, name_off: u32) -> Result<&str> {
+pub(crate) fn btf_name_str_by_offset(btf: &btf, name_off: u32) -> Result<&str> {
     let n = unsafe { btf__name_by_offset(btf, name_off) };
     if n.is_null() {
         bail!("btf__name_by_offset() returned NULL");
@@ -93,8 +96,10 @@ fn btf_name_str_by_offset(btf: &btf, name_off: u32) -> Result<&str> {
 }
 
 pub fn read_enum(type_name: &str, name: &str) -> Result<u64> {
-    let btf: &btf = *VMLINUX_BTF;
+    read_enum_in(*VMLINUX_BTF, type_name, name)
+}
 
+pub(crate) fn read_enum_in(btf: &btf, type_name: &str, name: &str) -> Result<u64> {
     let type_name = CString::new(type_name).unwrap();
     let tid = unsafe { btf__find_by_name(btf, type_name.as_ptr()) };
     if tid < 0 {
@@ -129,8 +134,10 @@ pub fn read_enum(type_name: &str, name: &str) -> Result<u64> {
 }
 
 pub fn struct_has_field(type_name: &str, field: &str) -> Result<bool> {
-    let btf: &btf = *VMLINUX_BTF;
+    struct_has_field_in(*VMLINUX_BTF, type_name, field)
+}
 
+pub(crate) fn struct_has_field_in(btf: &btf, type_name: &str, field: &str) -> Result<bool> {
     let type_name = CString::new(type_name).unwrap();
     let tid = unsafe { btf__find_by_name_kind(btf, type_name.as_ptr(), BTF_KIND_STRUCT) };
     if tid < 0 {
@@ -280,6 +287,42 @@ macro_rules! unwrap_or_break {
     }};
 }
 
+/// Pick the min_core_btf blob generated by `BpfBuilder::gen_min_core_btf()`
+/// matching the running kernel release. If the kernel provides
+/// `/sys/kernel/btf/vmlinux`, `None` is returned as the kernel BTF should be
+/// used. Otherwise, the matching blob is written into a file under `@dir`
+/// and its path is returned, which can be set as `btf_custom_path` in
+/// `bpf_object_open_opts` when opening the skeleton.
+pub fn min_core_btf_path(
+    btfs: &[(&str, &[u8])],
+    dir: &std::path::Path,
+) -> Result<Option<std::path::PathBuf>> {
+    if std::path::Path::new("/sys/kernel/btf/vmlinux").exists() {
+        return Ok(None);
+    }
+
+    let release = kernel_release()?;
+    let (name, blob) = btfs
+        .iter()
+        .find(|(name, _)| *name == release)
+        .ok_or_else(|| anyhow!("No min_core_btf for kernel release {:?}", &release))?;
+
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.btf", name));
+    std::fs::write(&path, blob).with_context(|| format!("Failed to write {:?}", &path))?;
+    Ok(Some(path))
+}
+
+/// The running kernel release as reported by `uname -r`.
+pub fn kernel_release() -> Result<String> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } < 0 {
+        bail!("uname() failed ({})", io::Error::last_os_error());
+    }
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) };
+    Ok(release.to_string_lossy().to_string())
+}
+
 pub fn check_min_requirements() -> Result<()> {
     // ec7e3b0463e1 ("implement-ops") in https://github.com/sched-ext/sched_ext
     // is the current minimum required kernel version.
@@ -289,6 +332,96 @@ pub fn check_min_requirements() -> Result<()> {
     Ok(())
 }
 
+/// Struct layouts as emitted by BpfBuilder into the intf.h bindings: the
+/// name, size and named field offsets of each struct and union.
+pub type IntfLayouts<'a> = &'a [(&'a str, usize, &'a [(&'a str, usize)])];
+
+/// Compare the userspace struct layouts in `@layouts` against the BTF of
+/// the open BPF object `@obj`. Structs which the BPF object doesn't use
+/// aren't in its BTF and are skipped.
+pub fn check_intf_layouts(obj: &libbpf_rs::OpenObject, layouts: IntfLayouts) -> Result<()> {
+    let btf = unsafe { bpf_object__btf(obj.as_libbpf_object().as_ptr()) };
+    if btf.is_null() {
+        bail!("BPF object doesn't have BTF, can't verify the intf.h struct layouts");
+    }
+    check_intf_layouts_in(unsafe { &*btf }, layouts)
+}
+
+pub(crate) fn check_intf_layouts_in(btf: &btf, layouts: IntfLayouts) -> Result<()> {
+    // Index the named structs and unions, also through typedefs as bindgen
+    // names anonymous ones after the typedef.
+    let mut types: HashMap<&str, &btf_type> = HashMap::new();
+    for id in 1..unsafe { btf__type_cnt(btf) } {
+        let t = unsafe { &*btf__type_by_id(btf, id) };
+        let mut target = t;
+        while btf_kind(target) == BTF_KIND_TYPEDEF {
+            target = unsafe { &*btf__type_by_id(btf, target.__bindgen_anon_1.type_) };
+        }
+        if matches!(btf_kind(target), BTF_KIND_STRUCT | BTF_KIND_UNION) && t.name_off != 0 {
+            types
+                .entry(btf_name_str_by_offset(btf, t.name_off)?)
+                .or_insert(target);
+        }
+    }
+
+    let mut diffs = vec![];
+    for (name, size, fields) in layouts.iter() {
+        let Some(t) = types.get(name) else {
+            continue;
+        };
+
+        let bpf_size = unsafe { t.__bindgen_anon_1.size } as usize;
+        if bpf_size != *size {
+            diffs.push(format!("size of {} is {} != {}", name, bpf_size, size));
+        }
+
+        let kflag = t.info >> 31 != 0;
+        let mut members = HashMap::new();
+        for m in btf_members(t).iter() {
+            let bit_off = if kflag { m.offset & 0xffffff } else { m.offset };
+            members.insert(btf_name_str_by_offset(btf, m.name_off)?, bit_off / 8);
+        }
+
+        for (field, off) in fields.iter() {
+            // bindgen appends '_' to the field names which are Rust keywords.
+            let bpf_off = members
+                .get(field)
+                .or_else(|| field.strip_suffix('_').and_then(|f| members.get(f)));
+            match bpf_off {
+                Some(bpf_off) if *bpf_off as usize == *off => {}
+                Some(bpf_off) => diffs.push(format!(
+                    "offset of {}::{} is {} != {}",
+                    name, field, bpf_off, off
+                )),
+                None => diffs.push(format!("{}::{} is missing", name, field)),
+            }
+        }
+    }
+
+    if !diffs.is_empty() {
+        bail!(
+            "BPF and userspace intf.h struct layouts differ ({}), \
+             the BPF object and the binary must be built from the same source",
+            diffs.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Verify that the intf.h struct layouts in the BTF of the BPF object match
+/// the ones in the userspace bindings generated by BpfBuilder. `$intf` is
+/// the module the bindings are included into. Should be used on the open
+/// skeleton before loading, e.g. `intf_layout_check!(skel, bpf_intf)?`.
+#[macro_export]
+macro_rules! intf_layout_check {
+    ($skel: expr, $intf: ident) => {
+        $crate::compat::check_intf_layouts(
+            libbpf_rs::skel::OpenSkel::open_object(&$skel),
+            $intf::SCX_INTF_LAYOUTS,
+        )
+    };
+}
+
 /// struct sched_ext_ops can change over time. If compat.bpf.h::SCX_OPS_DEFINE()
 /// is used to define ops, and scx_ops_open!(), scx_ops_load!(), and
 /// scx_ops_attach!() are used to open, load and attach it, backward
@@ -386,6 +519,7 @@ macro_rules! scx_ops_attach {
                     .attach_struct_ops()
                     .context("Failed to attach struct_ops BPF programs")
             })
+            .inspect(|_| scx_utils::journal_attach(stringify!($ops)))
     }};
 }
 
@@ -403,6 +537,53 @@ mod tests {
         assert!(super::struct_has_field("NO_SUCH_STRUCT", "NO_SUCH_FIELD").is_err());
     }
 
+    #[test]
+    fn test_check_intf_layouts() {
+        use libbpf_rs::libbpf_sys::*;
+        use std::ffi::CString;
+
+        let name = |s: &str| CString::new(s).unwrap();
+        let btf = unsafe { btf__new_empty() };
+        assert!(!btf.is_null());
+        unsafe {
+            let u32_id = btf__add_int(btf, name("u32").as_ptr(), 4, 0);
+            let u64_id = btf__add_int(btf, name("u64").as_ptr(), 8, 0);
+            btf__add_struct(btf, name("task_ctx").as_ptr(), 16);
+            btf__add_field(btf, name("pid").as_ptr(), u32_id, 0, 0);
+            btf__add_field(btf, name("type").as_ptr(), u32_id, 32, 0);
+            btf__add_field(btf, name("vtime").as_ptr(), u64_id, 64, 0);
+            let anon_id = btf__add_struct(btf, std::ptr::null(), 8);
+            btf__add_field(btf, name("cnt").as_ptr(), u64_id, 0, 0);
+            btf__add_typedef(btf, name("cnt_t").as_ptr(), anon_id);
+        }
+        let btf_ref = unsafe { &*btf };
+
+        let good: super::IntfLayouts = &[
+            ("task_ctx", 16, &[("pid", 0), ("type_", 4), ("vtime", 8)]),
+            ("cnt_t", 8, &[("cnt", 0)]),
+            ("unused_by_bpf", 4, &[("x", 0)]),
+        ];
+        assert!(super::check_intf_layouts_in(btf_ref, good).is_ok());
+
+        let bad: super::IntfLayouts = &[
+            ("task_ctx", 24, &[("pid", 0), ("type_", 8), ("extra", 16)]),
+            ("cnt_t", 8, &[("cnt", 0)]),
+        ];
+        let err = super::check_intf_layouts_in(btf_ref, bad)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("size of task_ctx is 16 != 24"), "{}", err);
+        assert!(
+            err.contains("offset of task_ctx::type_ is 4 != 8"),
+            "{}",
+            err
+        );
+        assert!(err.contains("task_ctx::extra is missing"), "{}", err);
+        assert!(!err.contains("cnt_t"), "{}", err);
+
+        unsafe { btf__free(btf) };
+    }
+
     #[test]
     fn test_ksym_exists() {
         assert!(super::ksym_exists("bpf_task_acquire").unwrap());
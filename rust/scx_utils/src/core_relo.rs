@@ -0,0 +1,635 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2.
+
+//! Offline verification of CO-RE relocations against kernel BTF files.
+//!
+//! The CO-RE relocation records in `.BTF.ext` of a BPF object are matched
+//! against the target BTF by name, similar to but simpler than what libbpf
+//! does at load time. Field and type relocations are resolved by walking
+//! the access string of the local type and looking up the members by name
+//! in the same-named target types, descending into anonymous members.
+//! Relocations which are themselves existence probes are not checked and
+//! relocations guarded by a matching probe in the same object are skipped
+//! as they are expected to fail on some kernels.
+
+use crate::compat::{btf_enum, btf_enum64};
+use crate::compat::{btf_kind, btf_members, btf_name_str_by_offset, btf_type_plus_1};
+use anyhow::{anyhow, bail, Context, Result};
+use libbpf_rs::libbpf_sys::*;
+use object::{Object, ObjectSection};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::CString;
+use std::path::Path;
+
+const BTF_EXT_MAGIC: u16 = 0xeb9f;
+
+const FIELD_BYTE_OFFSET: u32 = 0;
+const FIELD_EXISTS: u32 = 2;
+const FIELD_RSHIFT_U64: u32 = 5;
+const TYPE_ID_TARGET: u32 = 7;
+const TYPE_EXISTS: u32 = 8;
+const TYPE_SIZE: u32 = 9;
+const ENUMVAL_EXISTS: u32 = 10;
+const ENUMVAL_VALUE: u32 = 11;
+const TYPE_MATCHES: u32 = 12;
+
+fn relo_kind_str(kind: u32) -> &'static str {
+    match kind {
+        0 => "byte_off",
+        1 => "byte_sz",
+        2 => "field_exists",
+        3 => "signed",
+        4 => "lshift_u64",
+        5 => "rshift_u64",
+        6 => "local_type_id",
+        7 => "target_type_id",
+        8 => "type_exists",
+        9 => "type_size",
+        10 => "enumval_exists",
+        11 => "enumval_value",
+        12 => "type_matches",
+        _ => "unknown",
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct CoreRelo {
+    sec: String,
+    insn_off: u32,
+    type_id: u32,
+    access: String,
+    kind: u32,
+}
+
+/// Owned BTF handle which is freed on drop.
+pub(crate) struct OwnedBtf(*mut btf);
+
+impl OwnedBtf {
+    pub(crate) fn parse(path: &Path, elf: bool) -> Result<Self> {
+        let cpath = CString::new(path.to_string_lossy().as_bytes())?;
+        let ptr = unsafe {
+            if elf {
+                btf__parse_elf(cpath.as_ptr(), std::ptr::null_mut())
+            } else {
+                btf__parse(cpath.as_ptr(), std::ptr::null_mut())
+            }
+        };
+        if ptr.is_null() {
+            bail!("Failed to parse BTF from {:?}", path);
+        }
+        Ok(Self(ptr))
+    }
+
+    pub(crate) fn btf(&self) -> &btf {
+        unsafe { &*self.0 }
+    }
+}
+
+impl Drop for OwnedBtf {
+    fn drop(&mut self) {
+        unsafe { btf__free(self.0) };
+    }
+}
+
+fn type_by_id(btf: &btf, id: u32) -> Result<&btf_type> {
+    let t = unsafe { btf__type_by_id(btf, id) };
+    if t.is_null() {
+        bail!("btf__type_by_id({}) returned NULL", id);
+    }
+    Ok(unsafe { &*t })
+}
+
+fn skip_mods_and_typedefs(btf: &btf, mut id: u32) -> Result<(u32, &btf_type)> {
+    loop {
+        let t = type_by_id(btf, id)?;
+        match btf_kind(t) {
+            BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE | BTF_KIND_CONST | BTF_KIND_RESTRICT
+            | BTF_KIND_TYPE_TAG => id = unsafe { t.__bindgen_anon_1.type_ },
+            _ => return Ok((id, t)),
+        }
+    }
+}
+
+fn btf_array(t: &btf_type) -> &btf_array {
+    unsafe { &*(btf_type_plus_1(t) as *const btf_array) }
+}
+
+/// Strip the `___flavor` suffix which libbpf ignores when matching names.
+fn essential_name(name: &str) -> &str {
+    match name.find("___") {
+        Some(pos) => &name[..pos],
+        None => name,
+    }
+}
+
+/// Kinds which are considered the same when matching local and target.
+fn kind_class(kind: u32) -> u32 {
+    match kind {
+        BTF_KIND_ENUM64 => BTF_KIND_ENUM,
+        v => v,
+    }
+}
+
+fn read_u32(data: &[u8], off: usize, big: bool) -> Result<u32> {
+    let bytes: [u8; 4] = data
+        .get(off..off + 4)
+        .ok_or(anyhow!(".BTF.ext truncated at {}", off))?
+        .try_into()
+        .unwrap();
+    Ok(match big {
+        true => u32::from_be_bytes(bytes),
+        false => u32::from_le_bytes(bytes),
+    })
+}
+
+fn read_core_relos(data: &[u8], local: &btf) -> Result<Vec<CoreRelo>> {
+    if data.len() < 8 {
+        bail!(".BTF.ext too short");
+    }
+    let big = match (
+        u16::from_le_bytes([data[0], data[1]]),
+        data[0..2] == [0xeb, 0x9f],
+    ) {
+        (BTF_EXT_MAGIC, _) => false,
+        (_, true) => true,
+        _ => bail!("Invalid .BTF.ext magic"),
+    };
+
+    let hdr_len = read_u32(data, 4, big)? as usize;
+    // btf_ext_header: magic, version, flags, hdr_len, func_info_off/len,
+    // line_info_off/len, core_relo_off/len.
+    if hdr_len < 32 {
+        return Ok(vec![]);
+    }
+    let core_relo_off = hdr_len + read_u32(data, 24, big)? as usize;
+    let core_relo_len = read_u32(data, 28, big)? as usize;
+    if core_relo_len == 0 {
+        return Ok(vec![]);
+    }
+
+    let end = core_relo_off + core_relo_len;
+    let rec_size = read_u32(data, core_relo_off, big)? as usize;
+    if rec_size < 16 {
+        bail!("Invalid CO-RE relocation record size {}", rec_size);
+    }
+
+    let mut relos = vec![];
+    let mut off = core_relo_off + 4;
+    while off < end {
+        let sec = btf_name_str_by_offset(local, read_u32(data, off, big)?)?.to_string();
+        let num_info = read_u32(data, off + 4, big)? as usize;
+        off += 8;
+
+        for _ in 0..num_info {
+            relos.push(CoreRelo {
+                sec: sec.clone(),
+                insn_off: read_u32(data, off, big)?,
+                type_id: read_u32(data, off + 4, big)?,
+                access: btf_name_str_by_offset(local, read_u32(data, off + 8, big)?)?.to_string(),
+                kind: read_u32(data, off + 12, big)?,
+            });
+            off += rec_size;
+        }
+    }
+
+    Ok(relos)
+}
+
+/// A step of the local access spec resolved into names.
+#[derive(Debug)]
+enum Step {
+    Member(String),
+    Index,
+}
+
+struct LocalSpec {
+    root_name: String,
+    root_kind: u32,
+    steps: Vec<Step>,
+    enumval: Option<String>,
+}
+
+fn parse_access(access: &str) -> Result<Vec<u32>> {
+    access
+        .split(':')
+        .map(|x| {
+            x.parse::<u32>()
+                .with_context(|| format!("Invalid access string {:?}", access))
+        })
+        .collect()
+}
+
+fn local_spec(local: &btf, relo: &CoreRelo) -> Result<LocalSpec> {
+    let (_, root) = skip_mods_and_typedefs(local, relo.type_id)?;
+    let root_kind = btf_kind(root);
+    let root_name = essential_name(btf_name_str_by_offset(local, root.name_off)?).to_string();
+    let access = parse_access(&relo.access)?;
+
+    let mut spec = LocalSpec {
+        root_name,
+        root_kind,
+        steps: vec![],
+        enumval: None,
+    };
+
+    match relo.kind {
+        ENUMVAL_VALUE => {
+            let idx = access[0] as usize;
+            let name_off = match root_kind {
+                BTF_KIND_ENUM => btf_enum(root).get(idx).map(|e| e.name_off),
+                BTF_KIND_ENUM64 => btf_enum64(root).get(idx).map(|e| e.name_off),
+                _ => None,
+            }
+            .ok_or(anyhow!("Invalid enum access {:?}", &relo.access))?;
+            spec.enumval = Some(btf_name_str_by_offset(local, name_off)?.to_string());
+        }
+        FIELD_BYTE_OFFSET..=FIELD_RSHIFT_U64 => {
+            let mut t = root;
+            for &idx in access.iter().skip(1) {
+                let next = match btf_kind(t) {
+                    BTF_KIND_STRUCT | BTF_KIND_UNION => {
+                        let m = btf_members(t)
+                            .get(idx as usize)
+                            .ok_or(anyhow!("Invalid member access {:?}", &relo.access))?;
+                        let name = btf_name_str_by_offset(local, m.name_off)?;
+                        // Anonymous members are looked up through in target.
+                        if !name.is_empty() {
+                            spec.steps.push(Step::Member(name.to_string()));
+                        }
+                        m.type_
+                    }
+                    BTF_KIND_ARRAY => {
+                        spec.steps.push(Step::Index);
+                        btf_array(t).type_
+                    }
+                    _ => bail!("Invalid access {:?} into non-composite", &relo.access),
+                };
+                t = skip_mods_and_typedefs(local, next)?.1;
+            }
+        }
+        _ => (),
+    }
+
+    Ok(spec)
+}
+
+/// Kernel BTF indexed by essential type names.
+struct TargetBtf {
+    btf: OwnedBtf,
+    by_name: BTreeMap<(String, u32), Vec<u32>>,
+}
+
+impl TargetBtf {
+    fn new(path: &Path) -> Result<Self> {
+        Self::from_btf(OwnedBtf::parse(path, false)?)
+    }
+
+    fn from_btf(btf: OwnedBtf) -> Result<Self> {
+        let mut by_name = BTreeMap::<(String, u32), Vec<u32>>::new();
+
+        let cnt = unsafe { btf__type_cnt(btf.btf()) };
+        for id in 1..cnt {
+            let t = type_by_id(btf.btf(), id)?;
+            let name = btf_name_str_by_offset(btf.btf(), t.name_off)?;
+            if name.is_empty() {
+                continue;
+            }
+            by_name
+                .entry((essential_name(name).to_string(), kind_class(btf_kind(t))))
+                .or_default()
+                .push(id);
+        }
+
+        Ok(Self { btf, by_name })
+    }
+
+    fn candidates(&self, name: &str, kind: u32) -> &[u32] {
+        self.by_name
+            .get(&(name.to_string(), kind_class(kind)))
+            .map(|v| v.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Find member `name` in composite `t`, descending into anonymous members.
+    fn find_member(&self, t: &btf_type, name: &str) -> Result<Option<u32>> {
+        let btf = self.btf.btf();
+        for m in btf_members(t).iter() {
+            let mname = btf_name_str_by_offset(btf, m.name_off)?;
+            if mname == name {
+                return Ok(Some(m.type_));
+            }
+            if mname.is_empty() {
+                let (_, mt) = skip_mods_and_typedefs(btf, m.type_)?;
+                if matches!(btf_kind(mt), BTF_KIND_STRUCT | BTF_KIND_UNION) {
+                    if let Some(v) = self.find_member(mt, name)? {
+                        return Ok(Some(v));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn resolves_steps(&self, id: u32, steps: &[Step]) -> Result<bool> {
+        let btf = self.btf.btf();
+        let mut t = skip_mods_and_typedefs(btf, id)?.1;
+
+        for step in steps.iter() {
+            let next = match (step, btf_kind(t)) {
+                (Step::Member(name), BTF_KIND_STRUCT | BTF_KIND_UNION) => {
+                    match self.find_member(t, name)? {
+                        Some(v) => v,
+                        None => return Ok(false),
+                    }
+                }
+                (Step::Index, BTF_KIND_ARRAY) => btf_array(t).type_,
+                _ => return Ok(false),
+            };
+            t = skip_mods_and_typedefs(btf, next)?.1;
+        }
+        Ok(true)
+    }
+
+    fn has_enumval(&self, id: u32, enumval: &str) -> Result<bool> {
+        let btf = self.btf.btf();
+        let t = type_by_id(btf, id)?;
+        let name_offs: Vec<u32> = match btf_kind(t) {
+            BTF_KIND_ENUM => btf_enum(t).iter().map(|e| e.name_off).collect(),
+            BTF_KIND_ENUM64 => btf_enum64(t).iter().map(|e| e.name_off).collect(),
+            _ => vec![],
+        };
+        for off in name_offs {
+            if essential_name(btf_name_str_by_offset(btf, off)?) == essential_name(enumval) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn resolves(&self, spec: &LocalSpec, kind: u32) -> Result<bool> {
+        for &id in self.candidates(&spec.root_name, spec.root_kind) {
+            let ok = match kind {
+                ENUMVAL_VALUE => self.has_enumval(id, spec.enumval.as_ref().unwrap())?,
+                TYPE_ID_TARGET | TYPE_SIZE => true,
+                _ => self.resolves_steps(id, &spec.steps)?,
+            };
+            if ok {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Verify that the CO-RE relocations of the BPF object `@obj` resolve on
+/// the kernel BTF `@target`. Returns the descriptions of the relocations
+/// which failed to resolve.
+pub(crate) fn verify_core_relos(obj: &Path, target: &Path) -> Result<Vec<String>> {
+    let data = std::fs::read(obj).with_context(|| format!("Failed to read {:?}", obj))?;
+    let elf = object::File::parse(&*data).with_context(|| format!("Failed to parse {:?}", obj))?;
+    let ext = match elf.section_by_name(".BTF.ext") {
+        Some(v) => v.data()?,
+        None => return Ok(vec![]),
+    };
+
+    let local = OwnedBtf::parse(obj, true)?;
+    check_core_relos(local.btf(), ext, &TargetBtf::new(target)?)
+}
+
+fn check_core_relos(local: &btf, ext: &[u8], target: &TargetBtf) -> Result<Vec<String>> {
+    let relos = read_core_relos(ext, local)?;
+
+    // Relocations guarded by existence probes are expected to fail.
+    let probes: BTreeSet<(u32, &str, bool)> = relos
+        .iter()
+        .filter_map(|r| match r.kind {
+            FIELD_EXISTS => Some((r.type_id, r.access.as_str(), false)),
+            TYPE_EXISTS | TYPE_MATCHES => Some((r.type_id, "0", true)),
+            ENUMVAL_EXISTS => Some((r.type_id, r.access.as_str(), false)),
+            _ => None,
+        })
+        .collect();
+
+    let mut failures = BTreeSet::new();
+    for relo in relos.iter() {
+        let guarded = match relo.kind {
+            FIELD_BYTE_OFFSET..=FIELD_RSHIFT_U64 | ENUMVAL_VALUE => {
+                probes.contains(&(relo.type_id, relo.access.as_str(), false))
+            }
+            TYPE_ID_TARGET | TYPE_SIZE => probes.contains(&(relo.type_id, "0", true)),
+            _ => true,
+        };
+        if guarded {
+            continue;
+        }
+
+        let spec = local_spec(local, relo)?;
+        if spec.root_name.is_empty() || target.resolves(&spec, relo.kind)? {
+            continue;
+        }
+
+        let mut desc = spec.root_name.clone();
+        for step in spec.steps.iter() {
+            match step {
+                Step::Member(name) => desc += &format!(".{}", name),
+                Step::Index => desc += "[]",
+            }
+        }
+        if let Some(v) = &spec.enumval {
+            desc += &format!("::{}", v);
+        }
+        failures.insert(format!(
+            "{}: {} {} ({})",
+            &relo.sec,
+            relo_kind_str(relo.kind),
+            desc,
+            &relo.access
+        ));
+    }
+
+    Ok(failures.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// In-memory BTF built with the libbpf btf__add_*() API.
+    struct BtfFixture(OwnedBtf);
+
+    impl BtfFixture {
+        fn new() -> Self {
+            let ptr = unsafe { btf__new_empty() };
+            assert!(!ptr.is_null());
+            Self(OwnedBtf(ptr))
+        }
+
+        fn ptr(&self) -> *mut btf {
+            self.0 .0
+        }
+
+        fn str(&self, s: &str) -> u32 {
+            let s = CString::new(s).unwrap();
+            let off = unsafe { btf__add_str(self.ptr(), s.as_ptr()) };
+            assert!(off >= 0);
+            off as u32
+        }
+
+        fn int(&self, name: &str) -> u32 {
+            let name = CString::new(name).unwrap();
+            let id = unsafe { btf__add_int(self.ptr(), name.as_ptr(), 4, 0) };
+            assert!(id > 0);
+            id as u32
+        }
+
+        /// Add struct `name` with u32 fields `fields` of type `int_id`.
+        fn add_struct(&self, name: &str, int_id: u32, fields: &[&str]) -> u32 {
+            let name = CString::new(name).unwrap();
+            let size = 4 * fields.len() as u32;
+            let id = unsafe { btf__add_struct(self.ptr(), name.as_ptr(), size) };
+            assert!(id > 0);
+            for (idx, field) in fields.iter().enumerate() {
+                let field = CString::new(*field).unwrap();
+                let ret = unsafe {
+                    btf__add_field(self.ptr(), field.as_ptr(), int_id as _, idx as u32 * 32, 0)
+                };
+                assert_eq!(ret, 0);
+            }
+            id as u32
+        }
+
+        fn add_enum(&self, name: &str, vals: &[&str]) -> u32 {
+            let name = CString::new(name).unwrap();
+            let id = unsafe { btf__add_enum(self.ptr(), name.as_ptr(), 4) };
+            assert!(id > 0);
+            for (idx, val) in vals.iter().enumerate() {
+                let val = CString::new(*val).unwrap();
+                let ret = unsafe { btf__add_enum_value(self.ptr(), val.as_ptr(), idx as i64) };
+                assert_eq!(ret, 0);
+            }
+            id as u32
+        }
+    }
+
+    /// Build .BTF.ext with only CO-RE relocations, all in section `sec`.
+    /// Each relocation is (type_id, access, kind).
+    fn btf_ext(local: &BtfFixture, sec: &str, relos: &[(u32, &str, u32)]) -> Vec<u8> {
+        let mut relo_data: Vec<u32> = vec![16, local.str(sec), relos.len() as u32];
+        for (idx, (type_id, access, kind)) in relos.iter().enumerate() {
+            relo_data.extend([idx as u32 * 8, *type_id, local.str(access), *kind]);
+        }
+
+        let mut data = vec![];
+        data.extend(BTF_EXT_MAGIC.to_le_bytes());
+        data.extend([1u8, 0u8]);
+        // hdr_len, func_info_off/len, line_info_off/len, core_relo_off/len
+        for v in [32, 0, 0, 0, 0, 0, relo_data.len() as u32 * 4] {
+            data.extend(u32::to_le_bytes(v));
+        }
+        for v in relo_data {
+            data.extend(v.to_le_bytes());
+        }
+        data
+    }
+
+    fn target() -> TargetBtf {
+        let target = BtfFixture::new();
+        let u32_id = target.int("unsigned int");
+        // Same fields as local but at different offsets, without "nr_new".
+        target.add_struct("task_struct", u32_id, &["flags", "pid"]);
+        target.add_enum("pid_type", &["PIDTYPE_PID", "PIDTYPE_TGID"]);
+        TargetBtf::from_btf(target.0).unwrap()
+    }
+
+    fn check(relos: &[(&str, u32)]) -> Vec<String> {
+        let local = BtfFixture::new();
+        let u32_id = local.int("unsigned int");
+        let task_id = local.add_struct("task_struct", u32_id, &["pid", "flags", "nr_new"]);
+        let flavor_id = local.add_struct("task_struct___new", u32_id, &["pid", "nr_new"]);
+        let enum_id = local.add_enum("pid_type", &["PIDTYPE_PID", "PIDTYPE_NEW"]);
+
+        let relos: Vec<(u32, &str, u32)> = relos
+            .iter()
+            .map(|(access, kind)| {
+                let (ty, access) = access.split_once(' ').unwrap();
+                let type_id = match ty {
+                    "task" => task_id,
+                    "flavor" => flavor_id,
+                    _ => enum_id,
+                };
+                (type_id, access, *kind)
+            })
+            .collect();
+
+        let ext = btf_ext(&local, "tp_btf/sched_switch", &relos);
+        check_core_relos(local.0.btf(), &ext, &target()).unwrap()
+    }
+
+    #[test]
+    fn test_read_core_relos() {
+        let local = BtfFixture::new();
+        let u32_id = local.int("unsigned int");
+        let task_id = local.add_struct("task_struct", u32_id, &["pid"]);
+        let ext = btf_ext(&local, "sec", &[(task_id, "0:0", FIELD_BYTE_OFFSET)]);
+        assert_eq!(
+            read_core_relos(&ext, local.0.btf()).unwrap(),
+            vec![CoreRelo {
+                sec: "sec".into(),
+                insn_off: 0,
+                type_id: task_id,
+                access: "0:0".into(),
+                kind: FIELD_BYTE_OFFSET,
+            }]
+        );
+        assert!(read_core_relos(&ext[..4], local.0.btf()).is_err());
+        assert!(read_core_relos(&[0u8; 32], local.0.btf()).is_err());
+    }
+
+    #[test]
+    fn test_offset_mismatch() {
+        // Matched by name, the offsets differing is what CO-RE is for.
+        assert!(check(&[
+            ("task 0:0", FIELD_BYTE_OFFSET),
+            ("task 0:1", FIELD_BYTE_OFFSET)
+        ])
+        .is_empty());
+        assert!(check(&[("flavor 0:0", FIELD_BYTE_OFFSET)]).is_empty());
+
+        assert_eq!(
+            check(&[("task 0:2", FIELD_BYTE_OFFSET)]),
+            vec!["tp_btf/sched_switch: byte_off task_struct.nr_new (0:2)"]
+        );
+        assert_eq!(
+            check(&[("flavor 0:1", FIELD_BYTE_OFFSET)]),
+            vec!["tp_btf/sched_switch: byte_off task_struct.nr_new (0:1)"]
+        );
+    }
+
+    #[test]
+    fn test_field_exists() {
+        // The probe itself is expected to fail on some kernels.
+        assert!(check(&[("task 0:2", FIELD_EXISTS)]).is_empty());
+        // And guards the accesses to the same field.
+        assert!(check(&[("task 0:2", FIELD_EXISTS), ("task 0:2", FIELD_BYTE_OFFSET)]).is_empty());
+        // But not to other fields.
+        assert_eq!(
+            check(&[
+                ("flavor 0:1", FIELD_EXISTS),
+                ("task 0:2", FIELD_BYTE_OFFSET)
+            ]),
+            vec!["tp_btf/sched_switch: byte_off task_struct.nr_new (0:2)"]
+        );
+    }
+
+    #[test]
+    fn test_enumval() {
+        assert!(check(&[("enum 0", ENUMVAL_VALUE)]).is_empty());
+        assert_eq!(
+            check(&[("enum 1", ENUMVAL_VALUE)]),
+            vec!["tp_btf/sched_switch: enumval_value pid_type::PIDTYPE_NEW (1)"]
+        );
+        assert!(check(&[("enum 1", ENUMVAL_EXISTS), ("enum 1", ENUMVAL_VALUE)]).is_empty());
+    }
+}
@@ -34,6 +34,18 @@
 //!     let all_ones = Cpumask::from_str(&str);
 //!```
 //!
+//! Cpumasks can also be built from the kernel's list format as used by
+//! sysfs and the `isolcpus=` style boot parameters, and formatted back into
+//! it with `to_cpulist()`. `Cpumask::parse()` accepts either format which
+//! makes it suitable for command line arguments such as `--cpus 0-15`:
+//!
+//!```
+//!     use scx_utils::Cpumask;
+//!     let mask = Cpumask::parse("0").unwrap();
+//!     assert_eq!(mask.to_cpulist(), "0");
+//!     assert_eq!(Cpumask::parse("0x1").unwrap(), mask);
+//!```
+//!
 //! A Cpumask can be queried and updated using its helper functions:
 //!
 //!```rust
@@ -55,16 +67,29 @@
 //!     assert!(mask.test_cpu(0));
 //!```
 
+use crate::CoreType;
+use crate::Topology;
 use crate::NR_CPU_IDS;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use bitvec::prelude::*;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
 use sscanf::sscanf;
+use std::cell::Cell;
 use std::fmt;
+use std::ops::BitAnd;
 use std::ops::BitAndAssign;
+use std::ops::BitOr;
 use std::ops::BitOrAssign;
+use std::ops::BitXor;
 use std::ops::BitXorAssign;
+use std::ops::Not;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 
 #[derive(Debug, Eq, Clone, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Cpumask {
@@ -73,8 +98,8 @@ pub struct Cpumask {
 
 impl Cpumask {
     fn check_cpu(&self, cpu: usize) -> Result<()> {
-        if cpu >= *NR_CPU_IDS {
-            bail!("Invalid CPU {} passed, max {}", cpu, *NR_CPU_IDS);
+        if cpu >= self.mask.len() {
+            bail!("Invalid CPU {} passed, max {}", cpu, self.mask.len());
         }
 
         Ok(())
@@ -87,6 +112,16 @@ impl Cpumask {
         }
     }
 
+    /// Build a new empty Cpumask object which can hold at least `@len` CPUs.
+    /// This is used for synthetic topologies which may have more CPUs than
+    /// the host. The mask is never smaller than NR_CPU_IDS so that it can
+    /// be combined with masks created by `Cpumask::new()`.
+    pub fn with_len(len: usize) -> Cpumask {
+        Cpumask {
+            mask: bitvec![u64, Lsb0; 0; len.max(*NR_CPU_IDS)],
+        }
+    }
+
     /// Build a Cpumask object from a hexadecimal string.
     pub fn from_str(cpumask: &str) -> Result<Cpumask> {
         match cpumask {
@@ -120,7 +155,7 @@ impl Cpumask {
                 let lsb = v.trailing_zeros() as usize;
                 v &= !(1 << lsb);
                 let cpu = index * 8 + lsb;
-                if cpu > *NR_CPU_IDS {
+                if cpu >= *NR_CPU_IDS {
                     bail!(
                         concat!(
                             "Found cpu ({}) in cpumask ({}) which is larger",
@@ -138,6 +173,8 @@ impl Cpumask {
         Ok(Self { mask })
     }
 
+    /// Build a Cpumask object from a kernel list format string, e.g.
+    /// "0-3,8-11,14". CPUs beyond NR_CPU_IDS are ignored.
     pub fn from_cpulist(cpulist: &str) -> Result<Cpumask> {
         let mut mask = Cpumask::new();
         for cpu_id in read_cpulist(cpulist)? {
@@ -147,16 +184,108 @@ impl Cpumask {
         Ok(mask)
     }
 
-    pub fn from_vec(vec: Vec<u64>) -> Self {
-        Self {
-            mask: BitVec::from_vec(vec),
+    /// Build a Cpumask object from either a hexadecimal string prefixed
+    /// with "0x", the special values "none" and "all", or a kernel list
+    /// format string. Unlike `from_cpulist()`, CPUs beyond NR_CPU_IDS are
+    /// rejected. This is meant for parsing user-supplied arguments and can
+    /// be used directly as a clap `value_parser`.
+    pub fn parse(cpumask: &str) -> Result<Cpumask> {
+        let cpumask = cpumask.trim();
+        if cpumask == "none"
+            || cpumask == "all"
+            || cpumask.starts_with("0x")
+            || cpumask.starts_with("0X")
+        {
+            return Cpumask::from_str(&cpumask.to_lowercase());
         }
+
+        let mut mask = Cpumask::new();
+        for cpu in read_cpulist(cpumask)? {
+            mask.set_cpu(cpu).with_context(|| {
+                format!(
+                    "Found cpu ({}) in cpulist ({}) which is larger than the number of cpus on the machine ({})",
+                    cpu, cpumask, *NR_CPU_IDS
+                )
+            })?;
+        }
+
+        Ok(mask)
+    }
+
+    /// Format the Cpumask in the kernel list format, e.g. "0-3,8-11,14".
+    /// An empty Cpumask is formatted as an empty string.
+    pub fn to_cpulist(&self) -> String {
+        let mut groups = vec![];
+        let mut cpus = self.iter().peekable();
+        while let Some(first) = cpus.next() {
+            let mut last = first;
+            while cpus.next_if_eq(&(last + 1)).is_some() {
+                last += 1;
+            }
+            if first == last {
+                groups.push(format!("{}", first));
+            } else {
+                groups.push(format!("{}-{}", first, last));
+            }
+        }
+        groups.join(",")
+    }
+
+    /// Build a Cpumask from raw u64 words, e.g. read from a BPF map. Bits
+    /// beyond NR_CPU_IDS are dropped.
+    pub fn from_vec(vec: Vec<u64>) -> Self {
+        let mut mask = BitVec::from_vec(vec);
+        mask.resize(*NR_CPU_IDS, false);
+        Self { mask }
     }
 
     pub fn from_bitvec(bitvec: BitVec<u64, Lsb0>) -> Self {
         Self { mask: bitvec }
     }
 
+    /// Build a Cpumask of the CPUs in the NUMA node `@node_id` of `@topo`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use scx_utils::Cpumask;
+    /// use scx_utils::TopologyBuilder;
+    /// let topo = TopologyBuilder::new().nodes(2).llcs_per_node(2).build().unwrap();
+    /// let node = Cpumask::from_node(&topo, 1).unwrap();
+    /// let llc = Cpumask::from_llc(&topo, 2).unwrap();
+    /// assert!(llc.is_subset(&node));
+    /// assert!(Cpumask::from_llc(&topo, 4).is_err());
+    /// ```
+    pub fn from_node(topo: &Topology, node_id: usize) -> Result<Cpumask> {
+        match topo.nodes.get(&node_id) {
+            Some(node) => Ok(node.span.clone()),
+            None => bail!("Node {} doesn't exist", node_id),
+        }
+    }
+
+    /// Build a Cpumask of the CPUs in the LLC `@llc_id` of `@topo`.
+    pub fn from_llc(topo: &Topology, llc_id: usize) -> Result<Cpumask> {
+        match topo.all_llcs.get(&llc_id) {
+            Some(llc) => Ok(llc.span.clone()),
+            None => bail!("LLC {} doesn't exist", llc_id),
+        }
+    }
+
+    /// Build a Cpumask of the CPUs in the core `@core_id` of `@topo`.
+    pub fn from_core(topo: &Topology, core_id: usize) -> Result<Cpumask> {
+        match topo.all_cores.get(&core_id) {
+            Some(core) => Ok(core.span.clone()),
+            None => bail!("Core {} doesn't exist", core_id),
+        }
+    }
+
+    /// Build a Cpumask of the CPUs of `@topo` whose core type is
+    /// `@core_type`. Use `Topology::performance_cpus()` to match big cores
+    /// regardless of turbo.
+    pub fn from_core_type(topo: &Topology, core_type: &CoreType) -> Cpumask {
+        topo.core_type_cpus(|t| t == core_type)
+    }
+
     /// Return a slice of u64's whose bits reflect the Cpumask.
     pub fn as_raw_slice(&self) -> &[u64] {
         self.mask.as_raw_slice()
@@ -219,12 +348,12 @@ impl Cpumask {
 
     /// Return true if the Cpumask has all bits set, false otherwise.
     pub fn is_full(&self) -> bool {
-        self.mask.count_ones() == *NR_CPU_IDS
+        self.mask.count_ones() == self.mask.len()
     }
 
     /// The total size of the cpumask.
     pub fn len(&self) -> usize {
-        *NR_CPU_IDS
+        self.mask.len()
     }
 
     /// Create a Cpumask that is the negation of the current Cpumask.
@@ -237,24 +366,89 @@ impl Cpumask {
     /// Create a Cpumask that is the AND of the current Cpumask and another.
     pub fn and(&self, other: &Cpumask) -> Cpumask {
         let mut new = self.clone();
-        new.mask &= other.mask.clone();
+        new.and_assign(other);
         new
     }
 
     /// Create a Cpumask that is the OR of the current Cpumask and another.
     pub fn or(&self, other: &Cpumask) -> Cpumask {
         let mut new = self.clone();
-        new.mask |= other.mask.clone();
+        new.or_assign(other);
         new
     }
 
     /// Create a Cpumask that is the XOR of the current Cpumask and another.
     pub fn xor(&self, other: &Cpumask) -> Cpumask {
         let mut new = self.clone();
-        new.mask ^= other.mask.clone();
+        new.xor_assign(other);
+        new
+    }
+
+    /// Create a Cpumask with the CPUs of the current Cpumask which are not
+    /// in another.
+    pub fn andnot(&self, other: &Cpumask) -> Cpumask {
+        let mut new = self.clone();
+        new.andnot_assign(other);
         new
     }
 
+    /// Grow the Cpumask to the length of `@other` if it's shorter. Masks
+    /// built for the host all have the same length and are never resized.
+    fn grow_to(&mut self, other: &Cpumask) {
+        if self.mask.len() < other.mask.len() {
+            self.mask.resize(other.mask.len(), false);
+        }
+    }
+
+    /// AND another Cpumask into the current one without allocating.
+    pub fn and_assign(&mut self, other: &Cpumask) {
+        self.grow_to(other);
+        let len = other.mask.len();
+        self.mask[len..].fill(false);
+        self.mask[..len] &= &other.mask;
+    }
+
+    /// OR another Cpumask into the current one without allocating.
+    pub fn or_assign(&mut self, other: &Cpumask) {
+        self.grow_to(other);
+        self.mask[..other.mask.len()] |= &other.mask;
+    }
+
+    /// XOR another Cpumask into the current one without allocating.
+    pub fn xor_assign(&mut self, other: &Cpumask) {
+        self.grow_to(other);
+        self.mask[..other.mask.len()] ^= &other.mask;
+    }
+
+    /// Clear the CPUs of another Cpumask from the current one without
+    /// allocating.
+    pub fn andnot_assign(&mut self, other: &Cpumask) {
+        for cpu in other.mask.iter_ones() {
+            if cpu >= self.mask.len() {
+                break;
+            }
+            self.mask.set(cpu, false);
+        }
+    }
+
+    /// Negate the current Cpumask in place.
+    pub fn not_assign(&mut self) {
+        let mask = std::mem::take(&mut self.mask);
+        self.mask = !mask;
+    }
+
+    /// Return true if all CPUs set in the current Cpumask are also set in
+    /// `@other`.
+    pub fn is_subset(&self, other: &Cpumask) -> bool {
+        self.mask.iter_ones().all(|cpu| other.test_cpu(cpu))
+    }
+
+    /// Return true if the current Cpumask and `@other` have any CPU in
+    /// common.
+    pub fn intersects(&self, other: &Cpumask) -> bool {
+        self.mask.iter_ones().any(|cpu| other.test_cpu(cpu))
+    }
+
     /// Iterate over each element of a Cpumask, and return the indices with bits
     /// set.
     ///
@@ -276,6 +470,144 @@ impl Cpumask {
         }
     }
 
+    /// Iterate over the set CPUs in descending order.
+    pub fn iter_rev(&self) -> impl Iterator<Item = usize> + '_ {
+        self.mask.iter_ones().rev()
+    }
+
+    /// Iterate over the set CPUs in ascending order starting from `@cpu`
+    /// and wrapping around to CPU 0 after the last one, so that each set
+    /// CPU is visited exactly once. This is useful for spreading searches
+    /// across the mask instead of always favoring the lowest CPUs.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use scx_utils::Cpumask;
+    /// let mask = Cpumask::from_str("all").unwrap();
+    /// let cpus: Vec<usize> = mask.iter_from(mask.len() / 2).collect();
+    /// assert_eq!(cpus.len(), mask.weight());
+    /// assert_eq!(cpus[0], mask.len() / 2);
+    /// ```
+    pub fn iter_from(&self, cpu: usize) -> impl Iterator<Item = usize> + '_ {
+        let start = cpu.min(self.mask.len());
+        let (head, tail) = self.mask.split_at(start);
+        tail.iter_ones()
+            .map(move |cpu| cpu + start)
+            .chain(head.iter_ones())
+    }
+
+    /// Iterate over the Cpumask split into per-LLC chunks of `@topo`.
+    /// Yields the LLC ID and the CPUs of the Cpumask in that LLC, skipping
+    /// LLCs which don't contain any of them.
+    pub fn iter_llcs<'a>(
+        &'a self,
+        topo: &'a Topology,
+    ) -> impl Iterator<Item = (usize, Cpumask)> + 'a {
+        topo.all_llcs.iter().filter_map(|(id, llc)| {
+            let chunk = self.and(&llc.span);
+            (!chunk.is_empty()).then_some((*id, chunk))
+        })
+    }
+
+    /// Pick a set CPU uniformly at random. Returns None if the Cpumask is
+    /// empty.
+    pub fn pick_random(&self) -> Option<usize> {
+        match self.weight() {
+            0 => None,
+            weight => self.mask.iter_ones().nth(fastrand::usize(..weight)),
+        }
+    }
+
+    /// Pick a set CPU at random with the probability of each CPU being
+    /// proportional to its weight in `@weights` which is indexed by CPU
+    /// ID. CPUs with zero weight or beyond the end of `@weights` are never
+    /// picked. Returns None if no CPU has a positive weight.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use scx_utils::Cpumask;
+    /// let mask = Cpumask::from_str("all").unwrap();
+    /// let mut weights = vec![0; mask.len()];
+    /// weights[0] = 100;
+    /// assert_eq!(mask.pick_weighted(&weights), Some(0));
+    /// assert_eq!(mask.pick_weighted(&[]), None);
+    /// ```
+    pub fn pick_weighted(&self, weights: &[u64]) -> Option<usize> {
+        let weight_of = |cpu: usize| weights.get(cpu).copied().unwrap_or(0) as u128;
+        let total: u128 = self.mask.iter_ones().map(weight_of).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut pick = fastrand::u128(..total);
+        for cpu in self.mask.iter_ones() {
+            let weight = weight_of(cpu);
+            if pick < weight {
+                return Some(cpu);
+            }
+            pick -= weight;
+        }
+        unreachable!()
+    }
+
+    /// Write the Cpumask into `@words` laid out as the `u64
+    /// mask[MAX_CPUS / 64]` arrays used for cpumasks in BPF maps and global
+    /// variables, where `MAX_CPUS` comes from the scheduler's intf.h. The
+    /// array size is checked against `MAX_CPUS` at compile time. Fails if a
+    /// CPU at or above `MAX_CPUS` is set. Words beyond the Cpumask are
+    /// cleared.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use scx_utils::Cpumask;
+    /// const MAX_CPUS: usize = 512;
+    /// let mut bpf_mask = [u64::MAX; MAX_CPUS / 64];
+    /// let mask = Cpumask::from_str("0x1").unwrap();
+    /// mask.write_to_bpf::<MAX_CPUS, _>(&mut bpf_mask).unwrap();
+    /// assert_eq!(bpf_mask, [1, 0, 0, 0, 0, 0, 0, 0]);
+    /// assert_eq!(Cpumask::from_bpf::<MAX_CPUS, _>(&bpf_mask), mask);
+    /// ```
+    pub fn write_to_bpf<const MAX_CPUS: usize, const N: usize>(
+        &self,
+        words: &mut [u64; N],
+    ) -> Result<()> {
+        const {
+            assert!(
+                N == MAX_CPUS.div_ceil(64),
+                "BPF cpumask array size doesn't match MAX_CPUS"
+            )
+        };
+        if let Some(cpu) = self.mask.iter_ones().find(|cpu| *cpu >= MAX_CPUS) {
+            bail!(
+                "Cpumask has CPU {} set which is beyond MAX_CPUS {}",
+                cpu,
+                MAX_CPUS
+            );
+        }
+
+        let raw = self.as_raw_slice();
+        for (idx, word) in words.iter_mut().enumerate() {
+            *word = raw.get(idx).copied().unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    /// Build a Cpumask from a BPF cpumask array written by
+    /// `write_to_bpf()`. Like `from_vec()`, CPUs beyond NR_CPU_IDS are
+    /// dropped.
+    pub fn from_bpf<const MAX_CPUS: usize, const N: usize>(words: &[u64; N]) -> Cpumask {
+        const {
+            assert!(
+                N == MAX_CPUS.div_ceil(64),
+                "BPF cpumask array size doesn't match MAX_CPUS"
+            )
+        };
+        Cpumask::from_vec(words.to_vec())
+    }
+
     /// Write out a CPU mask to a raw memory pointer. We normally use this as part of updating
     /// the CPU masks on the BPF side.
     ///
@@ -307,10 +639,10 @@ impl Cpumask {
             .collect();
 
         // Throw out possible stray from u64 -> u32.
-        masks.truncate((*NR_CPU_IDS).div_ceil(32));
+        masks.truncate(self.mask.len().div_ceil(32));
 
-        // Print the highest 32bit. Trim digits beyond *NR_CPU_IDS.
-        let width = match (*NR_CPU_IDS).div_ceil(4) % 8 {
+        // Print the highest 32bit. Trim digits beyond the mask length.
+        let width = match self.mask.len().div_ceil(4) % 8 {
             0 => 8,
             v => v,
         };
@@ -332,28 +664,88 @@ impl Cpumask {
     }
 }
 
+/// Parse a kernel list format string, e.g. "0-3,8-11,14", into the list of
+/// CPU IDs. Ranges may carry a stride suffix as accepted by the kernel,
+/// e.g. "0-15:2/4" selects the first two CPUs of every group of four. An
+/// empty string yields an empty list.
 pub fn read_cpulist(cpulist: &str) -> Result<Vec<usize>> {
-    let cpulist = cpulist.trim_end_matches('\0');
+    let cpulist = cpulist.trim_end_matches('\0').trim();
+    if cpulist.is_empty() {
+        return Ok(vec![]);
+    }
+
     let cpu_groups: Vec<&str> = cpulist.split(',').collect();
     let mut cpu_ids = vec![];
     for group in cpu_groups.iter() {
-        let (min, max) = match sscanf!(group.trim(), "{usize}-{usize}") {
+        let group = group.trim();
+        let (range, used, size) = match sscanf!(group, "{str}:{usize}/{usize}") {
+            Ok((range, used, size)) => (range, used, size),
+            Err(_) => (group, 1, 1),
+        };
+        let (min, max) = match sscanf!(range, "{usize}-{usize}") {
             Ok((x, y)) => (x, y),
-            Err(_) => match sscanf!(group.trim(), "{usize}") {
+            Err(_) => match sscanf!(range, "{usize}") {
                 Ok(x) => (x, x),
                 Err(_) => {
-                    bail!("Failed to parse cpulist {}", group.trim());
+                    bail!("Failed to parse cpulist {}", group);
                 }
             },
         };
+        if min > max || used == 0 || used > size {
+            bail!("Invalid cpulist range {}", group);
+        }
         for i in min..(max + 1) {
-            cpu_ids.push(i);
+            if (i - min) % size < used {
+                cpu_ids.push(i);
+            }
         }
     }
 
     Ok(cpu_ids)
 }
 
+/// A persistent round-robin cursor over the CPUs of a Cpumask. Each call to
+/// `next()` returns the first set CPU after the previously returned one,
+/// wrapping around at the end, so all CPUs are picked equally often even if
+/// the Cpumask changes between calls. The cursor can be shared between
+/// threads.
+///
+/// # Examples
+///
+/// ```rust
+/// use scx_utils::Cpumask;
+/// use scx_utils::CpumaskCursor;
+/// let mask = Cpumask::from_str("0x1").unwrap();
+/// let cursor = CpumaskCursor::new();
+/// assert_eq!(cursor.next(&mask), Some(0));
+/// assert_eq!(cursor.next(&mask), Some(0));
+/// assert_eq!(cursor.next(&Cpumask::new()), None);
+/// ```
+#[derive(Debug, Default)]
+pub struct CpumaskCursor {
+    pos: AtomicUsize,
+}
+
+impl CpumaskCursor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the next set CPU of `@mask` after the last returned one or
+    /// None if `@mask` is empty.
+    pub fn next(&self, mask: &Cpumask) -> Option<usize> {
+        let pos = self.pos.load(Ordering::Relaxed);
+        let cpu = mask.iter_from(pos).next()?;
+        self.pos.store(cpu + 1, Ordering::Relaxed);
+        Some(cpu)
+    }
+
+    /// Restart the iteration from CPU 0.
+    pub fn reset(&self) {
+        self.pos.store(0, Ordering::Relaxed);
+    }
+}
+
 pub struct CpumaskIterator<'a> {
     mask: &'a Cpumask,
     index: usize,
@@ -363,7 +755,7 @@ impl Iterator for CpumaskIterator<'_> {
     type Item = usize;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while self.index < *NR_CPU_IDS {
+        while self.index < self.mask.len() {
             let index = self.index;
             self.index += 1;
             let bit_val = self.mask.test_cpu(index);
@@ -394,20 +786,413 @@ impl fmt::UpperHex for Cpumask {
     }
 }
 
+/// Cpumasks are serialized as hexadecimal strings by default. Use
+/// `#[serde(with = "scx_utils::serde_cpulist")]` on a field to serialize it
+/// in the kernel list format instead. Either format is accepted when
+/// deserializing, see `Cpumask::parse()`.
+impl Serialize for Cpumask {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serde_hex::serialize(self, serializer)
+    }
+}
+
+thread_local! {
+    static DESERIALIZE_UNBOUNDED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `@f` with Cpumask deserialization sized by the serialized masks
+/// instead of being limited to the host's NR_CPU_IDS. This is used to load
+/// recorded topologies of machines which may have more CPUs than the host.
+pub(crate) fn with_unbounded_deserialize<T>(f: impl FnOnce() -> T) -> T {
+    let prev = DESERIALIZE_UNBOUNDED.with(|flag| flag.replace(true));
+    let ret = f();
+    DESERIALIZE_UNBOUNDED.with(|flag| flag.set(prev));
+    ret
+}
+
+impl Cpumask {
+    /// Like `parse()` but the mask is sized to hold all CPUs in `@cpumask`
+    /// even if they are beyond NR_CPU_IDS. A hexadecimal mask is sized by
+    /// its number of digits so that masks which were serialized with the
+    /// same length are deserialized with the same length.
+    fn parse_unbounded(cpumask: &str) -> Result<Cpumask> {
+        let cpumask = cpumask.trim().to_lowercase();
+        if cpumask == "none" || cpumask == "all" {
+            return Cpumask::parse(&cpumask);
+        }
+
+        let (cpus, len) = match cpumask.strip_prefix("0x") {
+            Some(hex_str) => {
+                let mut hex_str = hex_str.replace('_', "");
+                let len = hex_str.len() * 4;
+                if hex_str.len() % 2 != 0 {
+                    hex_str = "0".to_string() + &hex_str;
+                }
+                let byte_vec = hex::decode(&hex_str)
+                    .with_context(|| format!("Failed to parse cpumask: {}", cpumask))?;
+                let bits = BitVec::<u8, Lsb0>::from_iter(
+                    byte_vec
+                        .iter()
+                        .rev()
+                        .flat_map(|&v| (0..8).map(move |i| v & (1 << i) != 0)),
+                );
+                (bits.iter_ones().collect::<Vec<_>>(), len)
+            }
+            None => (read_cpulist(&cpumask)?, 0),
+        };
+
+        let len = cpus.iter().map(|cpu| cpu + 1).fold(len, usize::max);
+        let mut mask = Cpumask::with_len(len);
+        for cpu in cpus {
+            mask.set_cpu(cpu)?;
+        }
+        Ok(mask)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cpumask {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mask = String::deserialize(deserializer)?;
+        if DESERIALIZE_UNBOUNDED.with(|flag| flag.get()) {
+            Cpumask::parse_unbounded(&mask).map_err(serde::de::Error::custom)
+        } else {
+            Cpumask::parse(&mask).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+/// Serialize a Cpumask as a hexadecimal string, e.g. "0xf0f". For use with
+/// `#[serde(with = "scx_utils::serde_hex")]`.
+pub mod serde_hex {
+    use super::Cpumask;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub fn serialize<S>(mask: &Cpumask, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("0x{}", mask).replace(',', ""))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Cpumask, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        serde::Deserialize::deserialize(deserializer)
+    }
+}
+
+/// Serialize a Cpumask in the kernel list format, e.g. "0-3,8-11". For use
+/// with `#[serde(with = "scx_utils::serde_cpulist")]`.
+///
+/// # Examples
+///
+/// ```rust
+/// use scx_utils::Cpumask;
+/// use serde::Deserialize;
+/// use serde::Serialize;
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Config {
+///     #[serde(with = "scx_utils::serde_cpulist")]
+///     cpus: Cpumask,
+/// }
+///
+/// let config: Config = serde_json::from_str(r#"{"cpus": "0"}"#).unwrap();
+/// assert!(config.cpus.test_cpu(0));
+/// assert_eq!(serde_json::to_string(&config).unwrap(), r#"{"cpus":"0"}"#);
+/// ```
+pub mod serde_cpulist {
+    use super::Cpumask;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub fn serialize<S>(mask: &Cpumask, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&mask.to_cpulist())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Cpumask, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        serde::Deserialize::deserialize(deserializer)
+    }
+}
+
 impl BitAndAssign<&Self> for Cpumask {
     fn bitand_assign(&mut self, rhs: &Self) {
-        self.mask &= &rhs.mask;
+        self.and_assign(rhs);
     }
 }
 
 impl BitOrAssign<&Self> for Cpumask {
     fn bitor_assign(&mut self, rhs: &Self) {
-        self.mask |= &rhs.mask;
+        self.or_assign(rhs);
     }
 }
 
 impl BitXorAssign<&Self> for Cpumask {
     fn bitxor_assign(&mut self, rhs: &Self) {
-        self.mask ^= &rhs.mask;
+        self.xor_assign(rhs);
+    }
+}
+
+impl BitAnd for &Cpumask {
+    type Output = Cpumask;
+
+    fn bitand(self, rhs: Self) -> Cpumask {
+        self.and(rhs)
+    }
+}
+
+impl BitAnd<&Cpumask> for Cpumask {
+    type Output = Cpumask;
+
+    fn bitand(mut self, rhs: &Cpumask) -> Cpumask {
+        self.and_assign(rhs);
+        self
+    }
+}
+
+impl BitOr for &Cpumask {
+    type Output = Cpumask;
+
+    fn bitor(self, rhs: Self) -> Cpumask {
+        self.or(rhs)
+    }
+}
+
+impl BitOr<&Cpumask> for Cpumask {
+    type Output = Cpumask;
+
+    fn bitor(mut self, rhs: &Cpumask) -> Cpumask {
+        self.or_assign(rhs);
+        self
+    }
+}
+
+impl BitXor for &Cpumask {
+    type Output = Cpumask;
+
+    fn bitxor(self, rhs: Self) -> Cpumask {
+        self.xor(rhs)
+    }
+}
+
+impl BitXor<&Cpumask> for Cpumask {
+    type Output = Cpumask;
+
+    fn bitxor(mut self, rhs: &Cpumask) -> Cpumask {
+        self.xor_assign(rhs);
+        self
+    }
+}
+
+impl Not for &Cpumask {
+    type Output = Cpumask;
+
+    fn not(self) -> Cpumask {
+        Cpumask::not(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask_of(cpus: &[usize]) -> Cpumask {
+        let mut mask = Cpumask::with_len(cpus.iter().max().map_or(0, |x| x + 1));
+        for &cpu in cpus {
+            mask.set_cpu(cpu).unwrap();
+        }
+        mask
+    }
+
+    #[test]
+    fn test_read_cpulist() {
+        assert_eq!(read_cpulist("").unwrap(), Vec::<usize>::new());
+        assert_eq!(read_cpulist("\n").unwrap(), Vec::<usize>::new());
+        assert_eq!(read_cpulist("5").unwrap(), vec![5]);
+        assert_eq!(
+            read_cpulist("0-3,8-11,14\n").unwrap(),
+            vec![0, 1, 2, 3, 8, 9, 10, 11, 14]
+        );
+        assert_eq!(
+            read_cpulist("0-15:2/4").unwrap(),
+            vec![0, 1, 4, 5, 8, 9, 12, 13]
+        );
+        assert!(read_cpulist("3-1").is_err());
+        assert!(read_cpulist("0-3:5/4").is_err());
+        assert!(read_cpulist("0-3,,4").is_err());
+        assert!(read_cpulist("0x3").is_err());
+    }
+
+    #[test]
+    fn test_to_cpulist() {
+        assert_eq!(Cpumask::new().to_cpulist(), "");
+        assert_eq!(mask_of(&[7]).to_cpulist(), "7");
+        assert_eq!(mask_of(&[0, 2, 4]).to_cpulist(), "0,2,4");
+        assert_eq!(
+            mask_of(&[0, 1, 2, 3, 8, 9, 10, 11, 14]).to_cpulist(),
+            "0-3,8-11,14"
+        );
+        assert_eq!(mask_of(&(60..70).collect::<Vec<_>>()).to_cpulist(), "60-69");
+    }
+
+    #[test]
+    fn test_cpulist_round_trip() {
+        for cpulist in ["", "0", "0-3,8-11,14", "1,3,5-6,63-64,127", "0-255"] {
+            let cpus = read_cpulist(cpulist).unwrap();
+            assert_eq!(mask_of(&cpus).to_cpulist(), cpulist);
+        }
+
+        let nr_cpus = *NR_CPU_IDS;
+        let all = Cpumask::from_str("all").unwrap();
+        match nr_cpus {
+            1 => assert_eq!(all.to_cpulist(), "0"),
+            _ => assert_eq!(all.to_cpulist(), format!("0-{}", nr_cpus - 1)),
+        }
+        assert_eq!(Cpumask::from_cpulist(&all.to_cpulist()).unwrap(), all);
+        assert_eq!(Cpumask::parse(&all.to_cpulist()).unwrap(), all);
+
+        let mut odd = Cpumask::new();
+        for cpu in (1..nr_cpus).step_by(2) {
+            odd.set_cpu(cpu).unwrap();
+        }
+        assert_eq!(Cpumask::parse(&odd.to_cpulist()).unwrap(), odd);
+    }
+
+    #[test]
+    fn test_iter_order() {
+        let mask = mask_of(&[1, 4, 5, 9]);
+        assert_eq!(mask.iter_rev().collect::<Vec<_>>(), vec![9, 5, 4, 1]);
+        assert_eq!(mask.iter_from(0).collect::<Vec<_>>(), vec![1, 4, 5, 9]);
+        assert_eq!(mask.iter_from(5).collect::<Vec<_>>(), vec![5, 9, 1, 4]);
+        assert_eq!(mask.iter_from(6).collect::<Vec<_>>(), vec![9, 1, 4, 5]);
+        assert_eq!(mask.iter_from(1000).collect::<Vec<_>>(), vec![1, 4, 5, 9]);
+        assert_eq!(Cpumask::new().iter_from(3).count(), 0);
+    }
+
+    #[test]
+    fn test_serde() {
+        #[derive(Serialize, Deserialize)]
+        struct Masks {
+            hex: Cpumask,
+            #[serde(with = "serde_cpulist")]
+            list: Cpumask,
+        }
+
+        let mut mask = Cpumask::new();
+        mask.set_cpu(*NR_CPU_IDS - 1).unwrap();
+        let masks = Masks {
+            hex: mask.clone(),
+            list: mask.clone(),
+        };
+        let json = serde_json::to_string(&masks).unwrap();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"hex":"0x{}","list":"{}"}}"#,
+                format!("{}", mask).replace(',', ""),
+                *NR_CPU_IDS - 1
+            )
+        );
+
+        let masks: Masks = serde_json::from_str(&json).unwrap();
+        assert_eq!(masks.hex, mask);
+        assert_eq!(masks.list, mask);
+
+        let swapped = format!(r#"{{"hex":"{}","list":"0x0"}}"#, *NR_CPU_IDS - 1);
+        let masks: Masks = serde_json::from_str(&swapped).unwrap();
+        assert_eq!(masks.hex, mask);
+        assert!(masks.list.is_empty());
+
+        let bad = format!(r#"{{"hex":"{}","list":""}}"#, *NR_CPU_IDS);
+        assert!(serde_json::from_str::<Masks>(&bad).is_err());
+    }
+
+    #[test]
+    fn test_set_ops() {
+        let a = mask_of(&[0, 1, 2, 8]);
+        let b = mask_of(&[2, 3, 8, 9]);
+
+        assert_eq!((&a & &b).to_cpulist(), "2,8");
+        assert_eq!((&a | &b).to_cpulist(), "0-3,8-9");
+        assert_eq!((&a ^ &b).to_cpulist(), "0-1,3,9");
+        assert_eq!(a.andnot(&b).to_cpulist(), "0-1");
+        assert_eq!(a.clone() & &b, a.and(&b));
+        assert_eq!(!&a, a.not());
+
+        let mut c = a.clone();
+        c.or_assign(&b);
+        c.andnot_assign(&a);
+        assert_eq!(c.to_cpulist(), "3,9");
+        c.not_assign();
+        assert!(!c.test_cpu(3) && c.test_cpu(0));
+
+        assert!(a.intersects(&b));
+        assert!(!a.intersects(&mask_of(&[5])));
+        assert!(!a.is_subset(&b));
+        assert!((&a & &b).is_subset(&a));
+        assert!(Cpumask::new().is_subset(&b));
+
+        let long = mask_of(&[1, 300]);
+        assert_eq!((&a | &long).to_cpulist(), "0-2,8,300");
+        assert_eq!((&long & &a).to_cpulist(), "1");
+        assert_eq!((&a ^ &long).len(), long.len());
+    }
+
+    #[test]
+    fn test_pickers() {
+        let mask = mask_of(&[2, 5, 7]);
+        for _ in 0..100 {
+            assert!(mask.test_cpu(mask.pick_random().unwrap()));
+        }
+        assert_eq!(Cpumask::new().pick_random(), None);
+
+        let weights = [100, 100, 0, 0, 0, 1, 0, 3];
+        let mut counts = [0; 8];
+        for _ in 0..4000 {
+            counts[mask.pick_weighted(&weights).unwrap()] += 1;
+        }
+        assert_eq!(counts[0] + counts[1] + counts[2], 0);
+        assert!(counts[7] > counts[5]);
+        assert_eq!(mask.pick_weighted(&[1, 1]), None);
+
+        let cursor = CpumaskCursor::new();
+        let picks: Vec<_> = (0..4).map(|_| cursor.next(&mask).unwrap()).collect();
+        assert_eq!(picks, vec![2, 5, 7, 2]);
+        assert_eq!(cursor.next(&mask_of(&[1, 4])), Some(4));
+        cursor.reset();
+        assert_eq!(cursor.next(&mask), Some(2));
+    }
+
+    #[test]
+    fn test_parse() {
+        let nr_cpus = *NR_CPU_IDS;
+        assert_eq!(Cpumask::parse("none").unwrap(), Cpumask::new());
+        assert_eq!(Cpumask::parse("").unwrap(), Cpumask::new());
+        assert!(Cpumask::parse("all").unwrap().is_full());
+        assert_eq!(
+            Cpumask::parse("0x1").unwrap(),
+            Cpumask::parse(" 0 ").unwrap()
+        );
+        assert_eq!(
+            Cpumask::parse("0X1").unwrap(),
+            Cpumask::parse("0x1").unwrap()
+        );
+        assert!(Cpumask::parse(&format!("{}", nr_cpus)).is_err());
+        assert!(Cpumask::parse(&format!("0-{}", nr_cpus)).is_err());
+        assert!(Cpumask::parse("foo").is_err());
     }
 }
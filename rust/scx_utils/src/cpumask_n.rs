@@ -0,0 +1,269 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2.
+
+//! # Fixed-size Cpumask
+//!
+//! `CpumaskN<N>` is a cpumask backed by an inline array of `N` u64 words
+//! which can hold up to `N * 64` CPUs. Unlike `Cpumask`, it is `Copy` and
+//! never allocates, which makes it suitable for per-task hot paths and for
+//! mirroring fixed-size cpumasks embedded in BPF map values, e.g.
+//! `CpumaskN<8>` for a `u64 cpumask[MAX_CPUS / 64]` with `MAX_CPUS = 512`.
+//!
+//!```
+//!     use scx_utils::Cpumask;
+//!     use scx_utils::CpumaskN;
+//!     let mut mask = CpumaskN::<8>::new();
+//!     mask.set_cpu(0).unwrap();
+//!     assert!(mask.set_cpu(512).is_err());
+//!
+//!     let heap = Cpumask::from(&mask);
+//!     assert!(heap.test_cpu(0));
+//!     assert_eq!(CpumaskN::<8>::try_from(&heap).unwrap(), mask);
+//!```
+
+use crate::Cpumask;
+use anyhow::bail;
+use anyhow::Result;
+use std::ops::BitAnd;
+use std::ops::BitAndAssign;
+use std::ops::BitOr;
+use std::ops::BitOrAssign;
+use std::ops::BitXor;
+use std::ops::BitXorAssign;
+use std::ops::Not;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CpumaskN<const N: usize> {
+    words: [u64; N],
+}
+
+impl<const N: usize> CpumaskN<N> {
+    /// The number of CPUs which fit in the mask.
+    pub const NR_CPUS: usize = N * 64;
+
+    /// Build an empty CpumaskN.
+    pub const fn new() -> Self {
+        Self { words: [0; N] }
+    }
+
+    /// Build a CpumaskN from raw u64 words, e.g. a BPF map value.
+    pub const fn from_raw(words: [u64; N]) -> Self {
+        Self { words }
+    }
+
+    /// Return the raw u64 words backing the CpumaskN.
+    pub fn as_raw(&self) -> &[u64; N] {
+        &self.words
+    }
+
+    fn check_cpu(&self, cpu: usize) -> Result<()> {
+        if cpu >= Self::NR_CPUS {
+            bail!("Invalid CPU {} passed, max {}", cpu, Self::NR_CPUS);
+        }
+        Ok(())
+    }
+
+    /// Set all bits in the CpumaskN to 1.
+    pub fn set_all(&mut self) {
+        self.words = [u64::MAX; N];
+    }
+
+    /// Set all bits in the CpumaskN to 0.
+    pub fn clear_all(&mut self) {
+        self.words = [0; N];
+    }
+
+    /// Set a bit in the CpumaskN. Returns an error if the specified CPU
+    /// doesn't fit in the mask.
+    pub fn set_cpu(&mut self, cpu: usize) -> Result<()> {
+        self.check_cpu(cpu)?;
+        self.words[cpu / 64] |= 1 << (cpu % 64);
+        Ok(())
+    }
+
+    /// Clear a bit from the CpumaskN. Returns an error if the specified CPU
+    /// doesn't fit in the mask.
+    pub fn clear_cpu(&mut self, cpu: usize) -> Result<()> {
+        self.check_cpu(cpu)?;
+        self.words[cpu / 64] &= !(1 << (cpu % 64));
+        Ok(())
+    }
+
+    /// Test whether the specified CPU bit is set in the CpumaskN. CPUs
+    /// which don't fit in the mask are never set.
+    pub fn test_cpu(&self, cpu: usize) -> bool {
+        match self.words.get(cpu / 64) {
+            Some(word) => word & (1 << (cpu % 64)) != 0,
+            None => false,
+        }
+    }
+
+    /// Count the number of bits set in the CpumaskN.
+    pub fn weight(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Return true if the CpumaskN has no bit set, false otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|w| *w == 0)
+    }
+
+    /// Return true if all CPUs set in the current CpumaskN are also set in
+    /// `@other`.
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.words
+            .iter()
+            .zip(other.words.iter())
+            .all(|(a, b)| a & !b == 0)
+    }
+
+    /// Return true if the current CpumaskN and `@other` have any CPU in
+    /// common.
+    pub fn intersects(&self, other: &Self) -> bool {
+        self.words
+            .iter()
+            .zip(other.words.iter())
+            .any(|(a, b)| a & b != 0)
+    }
+
+    /// Iterate over the set CPUs in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(idx, &word)| {
+            let mut word = word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(idx * 64 + bit)
+            })
+        })
+    }
+
+    fn zip_with(mut self, rhs: &Self, op: impl Fn(u64, u64) -> u64) -> Self {
+        for (a, b) in self.words.iter_mut().zip(rhs.words.iter()) {
+            *a = op(*a, *b);
+        }
+        self
+    }
+}
+
+impl<const N: usize> Default for CpumaskN<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Convert into a heap-backed Cpumask. Like `Cpumask::from_vec()`, CPUs
+/// beyond NR_CPU_IDS are dropped.
+impl<const N: usize> From<&CpumaskN<N>> for Cpumask {
+    fn from(mask: &CpumaskN<N>) -> Self {
+        Cpumask::from_vec(mask.words.to_vec())
+    }
+}
+
+/// Convert from a heap-backed Cpumask. Fails if the Cpumask has a CPU set
+/// which doesn't fit in the CpumaskN.
+impl<const N: usize> TryFrom<&Cpumask> for CpumaskN<N> {
+    type Error = anyhow::Error;
+
+    fn try_from(mask: &Cpumask) -> Result<Self> {
+        let mut new = Self::new();
+        for cpu in mask.iter() {
+            new.set_cpu(cpu)?;
+        }
+        Ok(new)
+    }
+}
+
+impl<const N: usize> BitAnd for CpumaskN<N> {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        self.zip_with(&rhs, |a, b| a & b)
+    }
+}
+
+impl<const N: usize> BitOr for CpumaskN<N> {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.zip_with(&rhs, |a, b| a | b)
+    }
+}
+
+impl<const N: usize> BitXor for CpumaskN<N> {
+    type Output = Self;
+
+    fn bitxor(self, rhs: Self) -> Self {
+        self.zip_with(&rhs, |a, b| a ^ b)
+    }
+}
+
+impl<const N: usize> Not for CpumaskN<N> {
+    type Output = Self;
+
+    fn not(mut self) -> Self {
+        for word in self.words.iter_mut() {
+            *word = !*word;
+        }
+        self
+    }
+}
+
+impl<const N: usize> BitAndAssign for CpumaskN<N> {
+    fn bitand_assign(&mut self, rhs: Self) {
+        *self = *self & rhs;
+    }
+}
+
+impl<const N: usize> BitOrAssign for CpumaskN<N> {
+    fn bitor_assign(&mut self, rhs: Self) {
+        *self = *self | rhs;
+    }
+}
+
+impl<const N: usize> BitXorAssign for CpumaskN<N> {
+    fn bitxor_assign(&mut self, rhs: Self) {
+        *self = *self ^ rhs;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpumask_n() {
+        let mut a = CpumaskN::<2>::new();
+        for cpu in [0, 63, 64, 127] {
+            a.set_cpu(cpu).unwrap();
+        }
+        assert!(a.set_cpu(128).is_err());
+        assert!(!a.test_cpu(128));
+        assert_eq!(a.weight(), 4);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![0, 63, 64, 127]);
+
+        let b = CpumaskN::from_raw([1, 1 << 63]);
+        assert!(b.is_subset(&a));
+        assert_eq!((a & b).iter().collect::<Vec<_>>(), vec![0, 127]);
+        assert_eq!((a ^ b).iter().collect::<Vec<_>>(), vec![63, 64]);
+        assert_eq!((!a).weight(), 124);
+        assert!(!(!a).intersects(&b));
+
+        a.clear_cpu(0).unwrap();
+        assert!(!a.test_cpu(0));
+    }
+
+    #[test]
+    fn test_cpumask_n_interop() {
+        let mut mask = Cpumask::new();
+        mask.set_cpu(0).unwrap();
+        let fixed = CpumaskN::<8>::try_from(&mask).unwrap();
+        assert_eq!(fixed.iter().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(Cpumask::from(&fixed), mask);
+    }
+}
@@ -59,7 +59,7 @@ impl EnergyModel {
         };
 
         for (pd_id, pd_path) in pd_paths {
-            let pd = PerfDomain::new(pd_id, pd_path).unwrap();
+            let pd = PerfDomain::new(pd_id, pd_path)?;
             perf_doms.insert(pd.id, pd.into());
         }
 
@@ -74,6 +74,25 @@ impl EnergyModel {
         }
         None
     }
+
+    /// Returns the performance state `@cpu` runs at when requesting
+    /// `@freq` in kHz, i.e. the lowest state whose frequency is at least
+    /// `@freq` or the highest state if `@freq` is beyond all of them.
+    pub fn perf_state(&self, cpu_id: usize, freq: usize) -> Option<&PerfState> {
+        let pd = self.get_pd(cpu_id)?;
+        pd.perf_table
+            .values()
+            .find(|ps| ps.frequency >= freq)
+            .or(pd.perf_table.values().next_back())
+            .map(|ps| ps.as_ref())
+    }
+
+    /// Returns the energy cost of running `@cpu` at `@freq` in kHz as
+    /// reported by the energy model. See `perf_state()` for how `@freq`
+    /// is mapped to a performance state.
+    pub fn energy_cost(&self, cpu_id: usize, freq: usize) -> Option<usize> {
+        Some(self.perf_state(cpu_id, freq)?.cost)
+    }
 }
 
 impl PerfDomain {
@@ -83,8 +102,8 @@ impl PerfDomain {
         let cpulist = std::fs::read_to_string(root.clone() + "/cpus")?;
         let span = Cpumask::from_cpulist(&cpulist)?;
 
-        for ps_path in get_ps_paths(root).unwrap() {
-            let ps = PerfState::new(ps_path).unwrap();
+        for ps_path in get_ps_paths(root)? {
+            let ps = PerfState::new(ps_path)?;
             perf_table.insert(ps.performance, ps.into());
         }
 
@@ -161,7 +180,7 @@ fn get_ps_paths(root: String) -> Result<Vec<String>> {
 }
 
 fn get_pd_paths() -> Result<Vec<(usize, String)>> {
-    let prefix = get_em_root().unwrap() + "/cpu";
+    let prefix = get_em_root()? + "/cpu";
     let pd_paths = glob(&(prefix.clone() + "[0-9]*"))?;
 
     let mut pd_vec = vec![];
@@ -184,6 +203,6 @@ fn get_pd_paths() -> Result<Vec<(usize, String)>> {
 }
 
 fn get_em_root() -> Result<String> {
-    let root = compat::debugfs_mount().unwrap().join("energy_model");
+    let root = compat::debugfs_mount()?.join("energy_model");
     Ok(root.display().to_string())
 }
@@ -0,0 +1,159 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2.
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Result;
+use log::warn;
+use std::fmt;
+use std::str::FromStr;
+
+/// Kernel version in the `MAJOR.MINOR[.PATCH][-EXTRA]` format used by
+/// kernel releases and the bundled `vmlinux.h` file names, e.g. `6.16`,
+/// `6.13-rc2` or `6.9.0-0_fbk1`. Versions are ordered by the numeric
+/// components and `extra` only breaks ties.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KernelVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    pub extra: String,
+}
+
+impl KernelVersion {
+    pub fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            extra: String::new(),
+        }
+    }
+
+    /// The version of the running kernel as reported by `uname -r`.
+    pub fn running() -> Result<Self> {
+        crate::compat::kernel_release()?.parse()
+    }
+
+    /// The number of minor releases between `self` and `@other` ignoring
+    /// the patch levels. Each major release is counted as 100 minor ones,
+    /// which is always a large skew.
+    pub fn minor_skew(&self, other: &KernelVersion) -> u32 {
+        let flat = |v: &KernelVersion| v.major as i64 * 100 + v.minor as i64;
+        (flat(self) - flat(other)).unsigned_abs() as u32
+    }
+}
+
+impl FromStr for KernelVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (nums, extra) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+            Some(idx) => (&s[..idx], s[idx..].trim_start_matches(['-', '+', '_'])),
+            None => (s, ""),
+        };
+
+        let nums = nums
+            .trim_end_matches('.')
+            .split('.')
+            .map(|x| x.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("Invalid kernel version {:?}", s))?;
+        if nums.len() < 2 || nums.len() > 3 {
+            bail!("Invalid kernel version {:?}", s);
+        }
+
+        Ok(Self {
+            major: nums[0],
+            minor: nums[1],
+            patch: nums.get(2).copied().unwrap_or(0),
+            extra: extra.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.patch != 0 {
+            write!(f, ".{}", self.patch)?;
+        }
+        if !self.extra.is_empty() {
+            write!(f, "-{}", self.extra)?;
+        }
+        Ok(())
+    }
+}
+
+/// The version of a bundled `vmlinux.h` which is named
+/// `vmlinux-v{VERSION}-g{SHA}.h`. See `BpfBuilder::vmlinux_h_version()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VmlinuxHVersion {
+    pub arch: String,
+    pub version: KernelVersion,
+    pub sha: String,
+}
+
+impl VmlinuxHVersion {
+    /// Parse the file name of a bundled `vmlinux.h` for `@arch`.
+    pub fn from_file_name(arch: &str, name: &str) -> Result<Self> {
+        let (version, sha) = name
+            .strip_prefix("vmlinux-v")
+            .and_then(|x| x.strip_suffix(".h"))
+            .and_then(|x| x.rsplit_once("-g"))
+            .ok_or(anyhow!("Invalid vmlinux.h file name {:?}", name))?;
+
+        Ok(Self {
+            arch: arch.to_string(),
+            version: version.parse()?,
+            sha: sha.to_string(),
+        })
+    }
+
+    /// Compare against the running kernel and warn if it is more than
+    /// `@max_minor_skew` minor releases apart. BPF programs built against
+    /// a `vmlinux.h` far from the running kernel are more likely to hit
+    /// CO-RE relocation failures. Returns the running kernel version.
+    pub fn check_running_kernel(&self, max_minor_skew: u32) -> Result<KernelVersion> {
+        let running = KernelVersion::running()?;
+        let skew = self.version.minor_skew(&running);
+        if skew > max_minor_skew {
+            warn!(
+                "vmlinux.h for {} is from {} which is {} releases apart from the running kernel {}",
+                &self.arch, &self.version, skew, &running
+            );
+        }
+        Ok(running)
+    }
+}
+
+impl fmt::Display for VmlinuxHVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} v{} (g{})", &self.arch, &self.version, &self.sha)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KernelVersion;
+    use super::VmlinuxHVersion;
+
+    #[test]
+    fn test_kernel_version() {
+        let ver: KernelVersion = "6.9.0-0_fbk1_1234".parse().unwrap();
+        assert_eq!((ver.major, ver.minor, ver.patch), (6, 9, 0));
+        assert_eq!(ver.extra, "0_fbk1_1234");
+        assert_eq!(ver.minor_skew(&"6.13-rc2".parse().unwrap()), 4);
+        assert!("6".parse::<KernelVersion>().is_err());
+    }
+
+    #[test]
+    fn test_vmlinux_h_version_from_file_name() {
+        let ver = VmlinuxHVersion::from_file_name("x86", "vmlinux-v6.12-g0123456789ab.h").unwrap();
+        assert_eq!(ver.version, KernelVersion::new(6, 12, 0));
+        assert_eq!(ver.sha, "0123456789ab");
+        assert!(VmlinuxHVersion::from_file_name("x86", "vmlinux.h").is_err());
+    }
+}
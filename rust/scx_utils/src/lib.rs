@@ -39,12 +39,22 @@ mod clang_info;
 mod bindings;
 
 mod bpf_builder;
+mod core_relo;
 pub use bpf_builder::BpfBuilder;
+pub use bpf_builder::BpfEndian;
+pub use bpf_builder::BpfProfile;
+pub use bpf_builder::OverwritePolicy;
+pub use bpf_builder::SkelSpec;
 
 mod builder;
 pub use builder::Builder;
 
+mod kernel_version;
+pub use kernel_version::KernelVersion;
+pub use kernel_version::VmlinuxHVersion;
+
 mod user_exit_info;
+pub use user_exit_info::journal_attach;
 pub use user_exit_info::ScxConsts;
 pub use user_exit_info::ScxExitKind;
 pub use user_exit_info::UeiDumpPtr;
@@ -62,15 +72,30 @@ pub use libbpf_logger::init_libbpf_logging;
 pub mod ravg;
 
 mod topology;
+pub use topology::CacheGroup;
+pub use topology::CacheInfo;
+pub use topology::CacheType;
+pub use topology::Cluster;
 pub use topology::Core;
 pub use topology::CoreType;
 pub use topology::Cpu;
+pub use topology::Die;
+pub use topology::HugePages;
 pub use topology::Llc;
+pub use topology::MemoryKind;
 pub use topology::Node;
+pub use topology::NodeMemory;
+pub use topology::SmtControl;
 pub use topology::Topology;
+pub use topology::TopologyBuilder;
+pub use topology::TopologyDiff;
+pub use topology::ValueChange;
 pub use topology::NR_CPUS_POSSIBLE;
 pub use topology::NR_CPU_IDS;
 
+mod topology_watcher;
+pub use topology_watcher::TopologyWatcher;
+
 mod energy_model;
 pub use energy_model::EnergyModel;
 pub use energy_model::PerfDomain;
@@ -78,7 +103,16 @@ pub use energy_model::PerfState;
 
 mod cpumask;
 pub use cpumask::read_cpulist;
+pub use cpumask::serde_cpulist;
+pub use cpumask::serde_hex;
 pub use cpumask::Cpumask;
+pub use cpumask::CpumaskCursor;
+
+mod cpumask_n;
+pub use cpumask_n::CpumaskN;
+
+mod atomic_cpumask;
+pub use atomic_cpumask::AtomicCpumask;
 
 mod gpu;
 
@@ -97,8 +131,19 @@ mod netdev;
 pub use netdev::read_netdevs;
 pub use netdev::NetDev;
 
+mod pci;
+pub use pci::read_pci_devices;
+pub use pci::PciDevice;
+pub use pci::PciDeviceKind;
+
 pub mod pm;
 
+mod thermal;
+pub use thermal::CpuThermal;
+pub use thermal::ThermalMonitor;
+pub use thermal::ThermalSnapshot;
+pub use thermal::ThermalZone;
+
 pub mod enums;
 pub use enums::scx_enums;
 
@@ -163,3 +163,13 @@ pub fn read_file_byte(path: &Path) -> Result<usize> {
 pub fn normalize_load_metric(metric: f64) -> f64 {
     metric / 100.0
 }
+
+/// Inflate the zstd compressed BPF object embedded in a skeleton generated
+/// with `BpfBuilder::compress_skel()`. The result is cached in `@cell` so
+/// that the object is inflated only once.
+pub fn inflate_skel_data(
+    cell: &'static std::sync::OnceLock<Vec<u8>>,
+    data: &[u8],
+) -> &'static [u8] {
+    cell.get_or_init(|| zstd::decode_all(data).expect("Failed to inflate BPF object"))
+}
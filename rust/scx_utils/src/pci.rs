@@ -0,0 +1,146 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2.
+
+//! # SCX PCI Device Locality
+//!
+//! Discover PCI devices such as GPUs, NICs and NVMe drives along with the
+//! NUMA node and CPUs they're local to, so that schedulers can co-locate
+//! tasks with the devices they use.
+//!
+//!```no_run
+//!     use scx_utils::PciDeviceKind;
+//!     use scx_utils::Topology;
+//!     let topo = Topology::new().unwrap();
+//!     let gpus = topo.pci_devices(Some(PciDeviceKind::Gpu)).unwrap();
+//!     if let Some(gpu) = gpus.get(3) {
+//!         println!("GPU 3 at {} is close to CPUs {}", gpu.address, gpu.local_cpus);
+//!     }
+//!```
+
+use crate::misc::read_from_file;
+use crate::Cpumask;
+use anyhow::Result;
+use glob::glob;
+use log::warn;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PciDeviceKind {
+    /// VGA, 3D and display controllers.
+    Gpu,
+    /// Ethernet, Infiniband and other network controllers.
+    Network,
+    /// NVM Express controllers.
+    Nvme,
+    /// Processing accelerators such as NPUs.
+    Accelerator,
+    Other,
+}
+
+impl PciDeviceKind {
+    /// Classify a device by its 24bit PCI class code.
+    pub fn from_class(class: u32) -> Self {
+        match (class >> 16, (class >> 8) & 0xff) {
+            (0x03, _) => PciDeviceKind::Gpu,
+            (0x02, _) => PciDeviceKind::Network,
+            (0x01, 0x08) => PciDeviceKind::Nvme,
+            (0x12, _) => PciDeviceKind::Accelerator,
+            _ => PciDeviceKind::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PciDevice {
+    /// The PCI address, e.g. "0000:3b:00.0".
+    pub address: String,
+    pub kind: PciDeviceKind,
+    pub class: u32,
+    pub vendor: u16,
+    pub device: u16,
+    /// The NUMA node the device is attached to, None if the platform
+    /// doesn't report it.
+    pub numa_node: Option<usize>,
+    /// CPUs local to the device. All CPUs if the platform doesn't report
+    /// locality.
+    pub local_cpus: Cpumask,
+    /// Kernel names of the device, e.g. "eth0", "nvme0" or "card1".
+    pub names: Vec<String>,
+}
+
+impl PciDevice {
+    fn read(path: &Path) -> Result<PciDevice> {
+        let address = path.file_name().unwrap().to_string_lossy().to_string();
+        let class = read_hex(&path.join("class"))?;
+        let vendor = read_hex(&path.join("vendor")).unwrap_or(0) as u16;
+        let device = read_hex(&path.join("device")).unwrap_or(0) as u16;
+
+        // numa_node is -1 if unknown.
+        let numa_node = read_from_file::<isize>(&path.join("numa_node"))
+            .ok()
+            .and_then(|node| usize::try_from(node).ok());
+
+        let local_cpus = match fs::read_to_string(path.join("local_cpulist")) {
+            Ok(cpulist) if !cpulist.trim().is_empty() => Cpumask::from_cpulist(cpulist.trim())?,
+            _ => Cpumask::from_str("all")?,
+        };
+
+        let mut names = vec![];
+        for pattern in ["net/*", "*/net/*", "nvme/*", "drm/card*"] {
+            let pattern = format!("{}/{}", path.display(), pattern);
+            if let Ok(paths) = glob(&pattern) {
+                for name in paths.filter_map(Result::ok) {
+                    names.push(name.file_name().unwrap().to_string_lossy().to_string());
+                }
+            }
+        }
+        names.sort();
+
+        Ok(PciDevice {
+            address,
+            kind: PciDeviceKind::from_class(class),
+            class,
+            vendor,
+            device,
+            numa_node,
+            local_cpus,
+            names,
+        })
+    }
+}
+
+fn read_hex(path: &Path) -> Result<u32> {
+    let val: String = read_from_file(path)?;
+    Ok(u32::from_str_radix(val.trim_start_matches("0x"), 16)?)
+}
+
+/// Read all PCI devices, optionally only of `@kind`, ordered by their PCI
+/// address. The order matches e.g. CUDA_DEVICE_ORDER=PCI_BUS_ID, so the
+/// n-th GPU is the n-th device of kind Gpu. Devices which can't be read,
+/// e.g. because they were removed while being read, are skipped.
+pub fn read_pci_devices(kind: Option<PciDeviceKind>) -> Result<Vec<PciDevice>> {
+    let mut devices = vec![];
+    let dir = Path::new("/sys/bus/pci/devices");
+    if !dir.exists() {
+        return Ok(devices);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let dev = match PciDevice::read(&path) {
+            Ok(dev) => dev,
+            Err(e) => {
+                warn!("Skipping PCI device {:?} ({:#})", &path, &e);
+                continue;
+            }
+        };
+        if kind.is_none_or(|kind| kind == dev.kind) {
+            devices.push(dev);
+        }
+    }
+    devices.sort_by(|a, b| a.address.cmp(&b.address));
+    Ok(devices)
+}
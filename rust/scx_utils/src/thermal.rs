@@ -0,0 +1,287 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2.
+
+//! # SCX Thermal
+//!
+//! Read thermal state from sysfs so that schedulers can steer latency
+//! critical work away from hot or throttled CPUs. A ThermalSnapshot is a
+//! single read of:
+//!
+//! - Temperatures of the thermal zones in /sys/class/thermal and of the
+//!   packages as reported by the coretemp hwmon driver.
+//! - Per-CPU thermal throttle event counters on Intel.
+//! - Per-CPU state of the cpufreq cooling devices which the thermal
+//!   framework uses to cap the frequency of hot CPUs, e.g. on arm64.
+//! - Per-CPU frequency cap, i.e. how much the cpufreq scaling limit has
+//!   been lowered below the hardware maximum. The cap may come from user
+//!   policy as well and is not treated as a thermal signal.
+//!
+//! A ThermalMonitor samples the snapshot periodically from a background
+//! thread:
+//!
+//!```no_run
+//!     use scx_utils::ThermalMonitor;
+//!     use std::time::Duration;
+//!     let monitor = ThermalMonitor::start(Duration::from_secs(1)).unwrap();
+//!     let snapshot = monitor.snapshot();
+//!     println!("throttled CPUs: {}", snapshot.throttled);
+//!```
+
+use crate::misc::read_from_file;
+use crate::read_cpulist;
+use crate::Cpumask;
+use anyhow::Result;
+use glob::glob;
+use log::warn;
+use sscanf::sscanf;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct ThermalZone {
+    /// The zone type, e.g. "x86_pkg_temp" or "cpu-thermal".
+    pub kind: String,
+    /// Temperature in millidegrees Celsius.
+    pub temp: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CpuThermal {
+    /// Cumulative number of core and package throttle events.
+    pub core_throttle_count: u64,
+    pub package_throttle_count: u64,
+    /// Current state of the cpufreq cooling device covering the CPU, 0 if
+    /// not cooled or if there is no such device.
+    pub cooling_state: u64,
+    /// Reduction of the scaling limit below the hardware maximum frequency
+    /// scaled to 1024, 0 if not capped.
+    pub freq_cap: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ThermalSnapshot {
+    pub at: Instant,
+    /// Thermal zones indexed by zone ID.
+    pub zones: BTreeMap<usize, ThermalZone>,
+    /// Package temperatures in millidegrees Celsius indexed by package ID.
+    pub package_temps: BTreeMap<usize, i64>,
+    pub cpus: BTreeMap<usize, CpuThermal>,
+    /// CPUs which are being cooled or had throttle events since the
+    /// previous snapshot.
+    pub throttled: Cpumask,
+}
+
+impl ThermalSnapshot {
+    /// Read the current thermal state. If `@prev` is given, CPUs whose
+    /// throttle counters increased since then are included in `throttled`.
+    pub fn read(prev: Option<&ThermalSnapshot>) -> Result<ThermalSnapshot> {
+        let cpus = read_cpu_thermal()?;
+        let mut throttled = Cpumask::new();
+        for (&cpu, thermal) in cpus.iter() {
+            let events = match prev.and_then(|prev| prev.cpus.get(&cpu)) {
+                Some(prev) => {
+                    thermal.core_throttle_count > prev.core_throttle_count
+                        || thermal.package_throttle_count > prev.package_throttle_count
+                }
+                None => false,
+            };
+            if events || thermal.cooling_state > 0 {
+                throttled.set_cpu(cpu)?;
+            }
+        }
+
+        Ok(ThermalSnapshot {
+            at: Instant::now(),
+            zones: read_thermal_zones(),
+            package_temps: read_package_temps(),
+            cpus,
+            throttled,
+        })
+    }
+
+    /// Returns the hottest package temperature, or the hottest thermal zone
+    /// if package temperatures aren't available.
+    pub fn max_temp(&self) -> Option<i64> {
+        self.package_temps
+            .values()
+            .max()
+            .or(self.zones.values().map(|z| &z.temp).max())
+            .copied()
+    }
+}
+
+fn read_thermal_zones() -> BTreeMap<usize, ThermalZone> {
+    let mut zones = BTreeMap::new();
+    let Ok(paths) = glob("/sys/class/thermal/thermal_zone[0-9]*") else {
+        return zones;
+    };
+    for path in paths.filter_map(Result::ok) {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let Ok(id) = sscanf!(name, "thermal_zone{usize}") else {
+            continue;
+        };
+        let (Ok(kind), Ok(temp)) = (
+            read_from_file(&path.join("type")),
+            read_from_file(&path.join("temp")),
+        ) else {
+            continue;
+        };
+        zones.insert(id, ThermalZone { kind, temp });
+    }
+    zones
+}
+
+/// Read the package temperatures from the coretemp hwmon driver, which
+/// labels them "Package id N".
+fn read_package_temps() -> BTreeMap<usize, i64> {
+    let mut temps = BTreeMap::new();
+    let Ok(paths) = glob("/sys/class/hwmon/hwmon*/temp*_label") else {
+        return temps;
+    };
+    for label_path in paths.filter_map(Result::ok) {
+        let Ok(label) = read_from_file::<String>(&label_path) else {
+            continue;
+        };
+        let Ok(package) = sscanf!(label, "Package id {usize}") else {
+            continue;
+        };
+        let input = label_path.to_string_lossy().replace("_label", "_input");
+        if let Ok(temp) = read_from_file(Path::new(&input)) {
+            temps.insert(package, temp);
+        }
+    }
+    temps
+}
+
+/// Read the current states of the cpufreq cooling devices, which are named
+/// after the first CPU of their cpufreq policy, indexed by all CPUs of the
+/// policy.
+fn read_cpufreq_cooling_states() -> BTreeMap<usize, u64> {
+    let mut states = BTreeMap::new();
+    let Ok(paths) = glob("/sys/class/thermal/cooling_device[0-9]*") else {
+        return states;
+    };
+    for path in paths.filter_map(Result::ok) {
+        let Ok(kind) = read_from_file::<String>(&path.join("type")) else {
+            continue;
+        };
+        let Ok(policy_cpu) = sscanf!(kind, "cpufreq-cpu{usize}") else {
+            continue;
+        };
+        let Ok(state) = read_from_file::<u64>(&path.join("cur_state")) else {
+            continue;
+        };
+        let related = format!(
+            "/sys/devices/system/cpu/cpu{}/cpufreq/related_cpus",
+            policy_cpu
+        );
+        let cpus = read_from_file::<String>(Path::new(&related))
+            .ok()
+            .and_then(|cpus| read_cpulist(&cpus).ok())
+            .unwrap_or_else(|| vec![policy_cpu]);
+        for cpu in cpus {
+            states.insert(cpu, state);
+        }
+    }
+    states
+}
+
+fn read_cpu_thermal() -> Result<BTreeMap<usize, CpuThermal>> {
+    let online: String = read_from_file(Path::new("/sys/devices/system/cpu/online"))?;
+    let cooling_states = read_cpufreq_cooling_states();
+    let mut cpus = BTreeMap::new();
+    for cpu in read_cpulist(&online)? {
+        let cpu_path = format!("/sys/devices/system/cpu/cpu{}", cpu);
+        let cpu_path = Path::new(&cpu_path);
+        let throttle_path = cpu_path.join("thermal_throttle");
+        let freq_path = cpu_path.join("cpufreq");
+
+        let hw_max: usize = read_from_file(&freq_path.join("cpuinfo_max_freq")).unwrap_or(0);
+        let limit: usize = read_from_file(&freq_path.join("scaling_max_freq")).unwrap_or(hw_max);
+        let freq_cap = match hw_max {
+            0 => 0,
+            _ => hw_max.saturating_sub(limit) * 1024 / hw_max,
+        };
+
+        cpus.insert(
+            cpu,
+            CpuThermal {
+                core_throttle_count: read_from_file(&throttle_path.join("core_throttle_count"))
+                    .unwrap_or(0),
+                package_throttle_count: read_from_file(
+                    &throttle_path.join("package_throttle_count"),
+                )
+                .unwrap_or(0),
+                cooling_state: cooling_states.get(&cpu).copied().unwrap_or(0),
+                freq_cap,
+            },
+        );
+    }
+    Ok(cpus)
+}
+
+/// Periodically samples ThermalSnapshot from a background thread. The
+/// thread is stopped when the monitor is dropped.
+#[derive(Debug)]
+pub struct ThermalMonitor {
+    snapshot: Arc<Mutex<Arc<ThermalSnapshot>>>,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ThermalMonitor {
+    pub fn start(interval: Duration) -> Result<ThermalMonitor> {
+        let snapshot = Arc::new(Mutex::new(Arc::new(ThermalSnapshot::read(None)?)));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let snapshot = snapshot.clone();
+            let shutdown = shutdown.clone();
+            std::thread::Builder::new()
+                .name("scx_thermal".into())
+                .spawn(move || {
+                    while !shutdown.load(Ordering::Relaxed) {
+                        std::thread::park_timeout(interval);
+                        if shutdown.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        let prev = snapshot.lock().unwrap().clone();
+                        match ThermalSnapshot::read(Some(&prev)) {
+                            Ok(next) => *snapshot.lock().unwrap() = Arc::new(next),
+                            Err(e) => warn!("Failed to read thermal state ({:?})", &e),
+                        }
+                    }
+                })?
+        };
+
+        Ok(ThermalMonitor {
+            snapshot,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    /// Returns the latest snapshot.
+    pub fn snapshot(&self) -> Arc<ThermalSnapshot> {
+        self.snapshot.lock().unwrap().clone()
+    }
+}
+
+impl Drop for ThermalMonitor {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
@@ -51,6 +51,10 @@
 //! Every object contains a Cpumask that spans all CPUs in that point in the
 //! topological hierarchy.
 //!
+//! Dies and clusters, which don't nest cleanly into the hierarchy on all
+//! architectures, are available through the `all_dies` and `all_clusters`
+//! skip indices.
+//!
 //! Creating Topology
 //! -----------------
 //!
@@ -67,25 +71,37 @@
 //! With a created Topology, you can query the topological hierarchy using the
 //! set of accessor functions defined below. All objects in the topological
 //! hierarchy are entirely read-only. If the host topology were to change (due
-//! to e.g. hotplug), a new Topology object should be created.
+//! to e.g. hotplug), a new Topology object should be created. TopologyWatcher
+//! can be used to detect such changes and refresh the Topology.
 
 use crate::cpumask::read_cpulist;
 use crate::misc::read_file_byte;
 use crate::misc::read_file_usize_vec;
 use crate::misc::read_from_file;
+use crate::pci::read_pci_devices;
 use crate::Cpumask;
+use crate::EnergyModel;
+use crate::PciDevice;
+use crate::PciDeviceKind;
 use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
 use glob::glob;
+use serde::Deserialize;
+use serde::Serialize;
 use sscanf::sscanf;
 use std::collections::BTreeMap;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::Mutex;
 
 #[cfg(feature = "gpu-topology")]
 use crate::gpu::{create_gpus, Gpu, GpuIndex};
 
+/// NUMA distance from a node to itself.
+const LOCAL_DISTANCE: usize = 10;
+
 lazy_static::lazy_static! {
     /// The maximum possible number of CPU IDs in the system. As mentioned
     /// above, this is different than the number of possible CPUs on the
@@ -102,15 +118,45 @@ lazy_static::lazy_static! {
     /// disabled CPUs that may not be onlined, whose IDs are lower than the
     /// IDs of other CPUs that may be onlined.
     pub static ref NR_CPUS_POSSIBLE: usize = libbpf_rs::num_possible_cpus().unwrap();
+
+    /// The process-wide Topology returned by `Topology::cached()`.
+    static ref CACHED_TOPOLOGY: Mutex<Option<Arc<Topology>>> = Mutex::new(None);
 }
 
-#[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
+/// Performance class of a core. On Intel hybrid CPUs, P-cores and E-cores
+/// are identified through the `cpu_core` and `cpu_atom` PMU devices. On
+/// other hybrid architectures (AMD compact cores, ARM big.LITTLE), cores are
+/// classified by comparing their capacity against the average and maximum
+/// capacity of the system.
+#[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum CoreType {
     Big { turbo: bool },
     Little,
 }
 
-#[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum CacheType {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// Geometry of a cache as reported by
+/// /sys/devices/system/cpu/cpuX/cache/indexI. Fields which aren't reported,
+/// which is common on ARM, are 0.
+#[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct CacheInfo {
+    pub level: usize,
+    pub cache_type: CacheType,
+    /// Size in bytes.
+    pub size: usize,
+    pub ways: usize,
+    pub sets: usize,
+    /// Coherency line size in bytes.
+    pub line_size: usize,
+}
+
+#[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct Cpu {
     pub id: usize,
     pub min_freq: usize,
@@ -120,6 +166,8 @@ pub struct Cpu {
     pub base_freq: usize,
     /// The best-effort guessing of cpu_capacity scaled to 1024.
     pub cpu_capacity: usize,
+    /// The current cpufreq scaling governor, empty if not available.
+    pub governor: String,
     pub smt_level: usize,
     /// CPU idle resume latency
     pub pm_qos_resume_latency_us: usize,
@@ -128,6 +176,9 @@ pub struct Cpu {
     pub l3_id: usize,
     /// Per-CPU cache size of all levels.
     pub cache_size: usize,
+    /// Geometry of all caches of this CPU ordered by level.
+    #[serde(default)]
+    pub caches: Vec<CacheInfo>,
     pub core_type: CoreType,
 
     /// Ancestor IDs.
@@ -136,9 +187,28 @@ pub struct Cpu {
     pub node_id: usize,
     pub package_id: usize,
     pub cluster_id: isize,
+    #[serde(default)]
+    pub die_id: usize,
 }
 
-#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+impl Cpu {
+    /// Returns the data or unified cache of `@level`, e.g. 1 for L1d.
+    pub fn cache(&self, level: usize) -> Option<&CacheInfo> {
+        self.caches
+            .iter()
+            .find(|c| c.level == level && c.cache_type != CacheType::Instruction)
+    }
+
+    /// Returns the last level cache of this CPU.
+    pub fn llc(&self) -> Option<&CacheInfo> {
+        self.caches
+            .iter()
+            .filter(|c| c.cache_type != CacheType::Instruction)
+            .max_by_key(|c| c.level)
+    }
+}
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct Core {
     /// Monotonically increasing unique id
     pub id: usize,
@@ -155,7 +225,7 @@ pub struct Core {
     pub node_id: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Llc {
     /// Monotonically increasing unique id
     pub id: usize,
@@ -169,26 +239,230 @@ pub struct Llc {
     pub node_id: usize,
 
     /// Skip indices to access lower level members easily.
+    #[serde(skip)]
     pub all_cpus: BTreeMap<usize, Arc<Cpu>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Node {
     pub id: usize,
+    /// Distances to all nodes as reported by sysfs, in the order of the
+    /// node IDs. Use `Topology::distance()` to look up a specific node.
     pub distance: Vec<usize>,
     pub llcs: BTreeMap<usize, Arc<Llc>>,
     /// Cpumask of all CPUs in this node.
     pub span: Cpumask,
 
     /// Skip indices to access lower level members easily.
+    #[serde(skip)]
     pub all_cores: BTreeMap<usize, Arc<Core>>,
+    #[serde(skip)]
     pub all_cpus: BTreeMap<usize, Arc<Cpu>>,
 
+    /// Memory of this node at the time it was last read. See
+    /// `Topology::refresh_memory()`.
+    #[serde(default)]
+    pub memory: NodeMemory,
+    /// The kind of memory attached to this node.
+    #[serde(default)]
+    pub memory_kind: MemoryKind,
+    /// The memory tier of this node as reported by
+    /// /sys/devices/virtual/memory_tiering. Lower tiers are faster.
+    #[serde(default)]
+    pub memory_tier: Option<usize>,
+
+    /// GPUs are discovered through NVML and not serialized.
     #[cfg(feature = "gpu-topology")]
+    #[serde(skip)]
     pub gpus: BTreeMap<GpuIndex, Gpu>,
 }
 
-#[derive(Debug)]
+/// Classification of the memory attached to a NUMA node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemoryKind {
+    /// Memory of a node with CPUs.
+    #[default]
+    Dram,
+    /// A CPU-less node in a slower memory tier than DRAM, e.g. a CXL
+    /// memory expander.
+    Cxl,
+    /// A CPU-less node in the same or a faster memory tier than DRAM, e.g.
+    /// HBM in flat mode.
+    Hbm,
+    /// A CPU-less node whose memory tier is not known.
+    CpuLess,
+}
+
+/// Huge pages of a given size.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HugePages {
+    pub total: usize,
+    pub free: usize,
+}
+
+/// Memory statistics of a node in bytes as reported by
+/// /sys/devices/system/node/nodeN/meminfo.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeMemory {
+    pub total: usize,
+    pub free: usize,
+    /// Huge pages indexed by the page size in bytes.
+    pub hugepages: BTreeMap<usize, HugePages>,
+    /// True if the node doesn't come from the NUMA hierarchy and the
+    /// statistics are for the whole system, read from /proc/meminfo.
+    pub system_wide: bool,
+}
+
+impl NodeMemory {
+    /// Read the memory statistics of NUMA node `@node_id`, or of the whole
+    /// system if None.
+    pub fn read(node_id: Option<usize>) -> Result<NodeMemory> {
+        let (meminfo, hugepages_dir) = match node_id {
+            Some(id) => (
+                format!("/sys/devices/system/node/node{}/meminfo", id),
+                format!("/sys/devices/system/node/node{}/hugepages", id),
+            ),
+            None => (
+                "/proc/meminfo".to_string(),
+                "/sys/kernel/mm/hugepages".to_string(),
+            ),
+        };
+
+        let mut mem = NodeMemory {
+            system_wide: node_id.is_none(),
+            ..Default::default()
+        };
+
+        // Node lines are prefixed with "Node N", e.g.
+        // "Node 0 MemTotal:        6147400 kB".
+        let meminfo = std::fs::read_to_string(&meminfo)
+            .with_context(|| format!("Failed to read {:?}", &meminfo))?;
+        for line in meminfo.lines() {
+            let mut fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.first() == Some(&"Node") {
+                fields.drain(..fields.len().min(2));
+            }
+            let (Some(key), Some(val)) = (fields.first(), fields.get(1)) else {
+                continue;
+            };
+            let Ok(val) = val.parse::<usize>() else {
+                continue;
+            };
+            let val = if fields.get(2) == Some(&"kB") {
+                val * 1024
+            } else {
+                val
+            };
+            match *key {
+                "MemTotal:" => mem.total = val,
+                "MemFree:" => mem.free = val,
+                _ => {}
+            }
+        }
+
+        if let Ok(paths) = glob(&format!("{}/hugepages-*kB", hugepages_dir)) {
+            for path in paths.filter_map(Result::ok) {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                let Ok(size_kb) = sscanf!(name, "hugepages-{usize}kB") else {
+                    continue;
+                };
+                mem.hugepages.insert(
+                    size_kb * 1024,
+                    HugePages {
+                        total: read_from_file(&path.join("nr_hugepages")).unwrap_or(0),
+                        free: read_from_file(&path.join("free_hugepages")).unwrap_or(0),
+                    },
+                );
+            }
+        }
+
+        Ok(mem)
+    }
+}
+
+/// A die within a package. Multi-die packages such as AMD EPYC with
+/// multiple CCDs or Intel parts with multiple compute tiles report one die
+/// per die_id. Dies are a skip index over the Node hierarchy and an LLC is
+/// included in every die that contains one of its CPUs.
+#[derive(Debug, Clone)]
+pub struct Die {
+    /// Monotonically increasing unique id
+    pub id: usize,
+    pub package_id: usize,
+    /// Cpumask of all CPUs in this die.
+    pub span: Cpumask,
+
+    pub llcs: BTreeMap<usize, Arc<Llc>>,
+    pub all_cores: BTreeMap<usize, Arc<Core>>,
+    pub all_cpus: BTreeMap<usize, Arc<Cpu>>,
+}
+
+/// A group of cores as reported by the ACPI PPTT or the device tree
+/// cluster_id, which usually share an L2 cache or a DynamIQ cluster. Cores
+/// without a cluster_id are each in their own cluster.
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    /// Monotonically increasing unique id
+    pub id: usize,
+    /// The sysfs value of cluster_id, -1 if not available
+    pub kernel_id: isize,
+    /// Cpumask of all CPUs in this cluster.
+    pub span: Cpumask,
+
+    /// Ancestor IDs.
+    pub llc_id: usize,
+    pub die_id: usize,
+    pub node_id: usize,
+
+    pub cores: BTreeMap<usize, Arc<Core>>,
+    pub all_cpus: BTreeMap<usize, Arc<Cpu>>,
+}
+
+/// The SMT control state as reported by /sys/devices/system/cpu/smt/control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtControl {
+    On,
+    Off,
+    ForceOff,
+    NotSupported,
+    NotImplemented,
+}
+
+impl std::str::FromStr for SmtControl {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "on" => SmtControl::On,
+            "off" => SmtControl::Off,
+            "forceoff" => SmtControl::ForceOff,
+            "notsupported" => SmtControl::NotSupported,
+            "notimplemented" => SmtControl::NotImplemented,
+            _ => bail!("Unknown SMT control state {:?}", s),
+        })
+    }
+}
+
+/// A group of CPUs sharing a data or unified cache, e.g. an L3 cache / CCX.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheGroup {
+    /// Index of the group within its cache level. Groups are ordered by
+    /// their first CPU, so the IDs are stable across boots of the same
+    /// machine.
+    pub id: usize,
+    /// The sysfs value of the cache id, if available.
+    pub kernel_id: Option<usize>,
+    pub level: usize,
+    /// Size of the cache in bytes.
+    pub size: usize,
+    /// Cpumask of all CPUs sharing this cache.
+    pub span: Cpumask,
+}
+
+/// Topology can be serialized, e.g. to record the topology of a production
+/// machine, and loaded back with `Topology::from_json()`. Skip indices are
+/// not serialized and are rebuilt on deserialization.
+#[derive(Debug, Serialize)]
 pub struct Topology {
     pub nodes: BTreeMap<usize, Node>,
     /// Cpumask all CPUs in the system.
@@ -197,13 +471,87 @@ pub struct Topology {
     pub smt_enabled: bool,
 
     /// Skip indices to access lower level members easily.
+    #[serde(skip)]
     pub all_llcs: BTreeMap<usize, Arc<Llc>>,
+    #[serde(skip)]
     pub all_cores: BTreeMap<usize, Arc<Core>>,
+    #[serde(skip)]
     pub all_cpus: BTreeMap<usize, Arc<Cpu>>,
+    #[serde(skip)]
+    pub all_dies: BTreeMap<usize, Die>,
+    #[serde(skip)]
+    pub all_clusters: BTreeMap<usize, Cluster>,
+
+    /// Cache sharing groups indexed by cache level.
+    pub cache_groups: BTreeMap<usize, Vec<CacheGroup>>,
+
+    /// The energy model from debugfs, if the kernel has one, which is
+    /// usually only the case on hybrid and mobile systems.
+    #[serde(skip)]
+    pub energy_model: Option<Arc<EnergyModel>>,
+
+    /// True if built by TopologyBuilder or loaded from JSON, i.e. the
+    /// Topology doesn't describe the host.
+    #[serde(skip)]
+    synthetic: bool,
+}
+
+impl<'de> Deserialize<'de> for Topology {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct RawTopology {
+            nodes: BTreeMap<usize, Node>,
+            span: Cpumask,
+            smt_enabled: bool,
+            #[serde(default)]
+            cache_groups: BTreeMap<usize, Vec<CacheGroup>>,
+        }
+
+        // The recorded machine may have more CPUs than the host.
+        let raw =
+            crate::cpumask::with_unbounded_deserialize(|| RawTopology::deserialize(deserializer))?;
+        let mut topo = Topology {
+            nodes: raw.nodes,
+            span: raw.span,
+            smt_enabled: raw.smt_enabled,
+            all_llcs: BTreeMap::new(),
+            all_cores: BTreeMap::new(),
+            all_cpus: BTreeMap::new(),
+            all_dies: BTreeMap::new(),
+            all_clusters: BTreeMap::new(),
+            cache_groups: raw.cache_groups,
+            energy_model: None,
+            synthetic: true,
+        };
+        topo.build_skip_indices()
+            .map_err(serde::de::Error::custom)?;
+        Ok(topo)
+    }
 }
 
 impl Topology {
-    fn instantiate(span: Cpumask, mut nodes: BTreeMap<usize, Node>) -> Result<Self> {
+    fn instantiate(span: Cpumask, nodes: BTreeMap<usize, Node>) -> Result<Self> {
+        let mut topo = Topology {
+            nodes,
+            smt_enabled: is_smt_active().unwrap_or(false),
+            all_llcs: BTreeMap::new(),
+            all_cores: BTreeMap::new(),
+            all_cpus: BTreeMap::new(),
+            all_dies: BTreeMap::new(),
+            all_clusters: BTreeMap::new(),
+            cache_groups: read_cache_groups(&span),
+            energy_model: EnergyModel::new().ok().map(Arc::new),
+            synthetic: false,
+            span,
+        };
+        topo.build_skip_indices()?;
+        Ok(topo)
+    }
+
+    fn build_skip_indices(&mut self) -> Result<()> {
         // Build skip indices prefixed with all_ for easy lookups. As Arc
         // objects can only be modified while there's only one reference,
         // skip indices must be built from bottom to top.
@@ -211,20 +559,20 @@ impl Topology {
         let mut topo_cores = BTreeMap::new();
         let mut topo_cpus = BTreeMap::new();
 
-        for (_node_id, node) in nodes.iter_mut() {
+        for (_node_id, node) in self.nodes.iter_mut() {
             let mut node_cores = BTreeMap::new();
             let mut node_cpus = BTreeMap::new();
 
             for (&llc_id, llc) in node.llcs.iter_mut() {
-                let llc_mut = Arc::get_mut(llc).unwrap();
+                let llc_mut = Arc::make_mut(llc);
                 let mut llc_cpus = BTreeMap::new();
 
                 for (&core_id, core) in llc_mut.cores.iter_mut() {
-                    let core_mut = Arc::get_mut(core).unwrap();
+                    let core_mut = Arc::make_mut(core);
                     let smt_level = core_mut.cpus.len();
 
                     for (&cpu_id, cpu) in core_mut.cpus.iter_mut() {
-                        let cpu_mut = Arc::get_mut(cpu).unwrap();
+                        let cpu_mut = Arc::make_mut(cpu);
                         cpu_mut.smt_level = smt_level;
 
                         if topo_cpus
@@ -255,18 +603,127 @@ impl Topology {
             node.all_cpus = node_cpus;
         }
 
-        Ok(Topology {
-            nodes,
-            span,
-            smt_enabled: is_smt_active().unwrap_or(false),
-            all_llcs: topo_llcs,
-            all_cores: topo_cores,
-            all_cpus: topo_cpus,
-        })
+        self.all_llcs = topo_llcs;
+        self.all_cores = topo_cores;
+        self.all_cpus = topo_cpus;
+        self.build_die_cluster_indices();
+        Ok(())
+    }
+
+    fn build_die_cluster_indices(&mut self) {
+        let mask_len = self.span.len();
+        let mut dies = BTreeMap::<usize, Die>::new();
+        let mut clusters = BTreeMap::<usize, Cluster>::new();
+        let mut cluster_ids = BTreeMap::<(usize, usize, isize, usize), usize>::new();
+
+        // Walk the CPUs in order so that the cluster IDs follow the first
+        // CPU of each cluster.
+        for (&cpu_id, cpu) in self.all_cpus.iter() {
+            let llc = &self.all_llcs[&cpu.llc_id];
+            let core = &llc.cores[&cpu.core_id];
+
+            let die = dies.entry(cpu.die_id).or_insert_with(|| Die {
+                id: cpu.die_id,
+                package_id: cpu.package_id,
+                span: Cpumask::with_len(mask_len),
+                llcs: BTreeMap::new(),
+                all_cores: BTreeMap::new(),
+                all_cpus: BTreeMap::new(),
+            });
+            die.span.set_cpu(cpu_id).unwrap();
+            die.llcs.insert(llc.id, llc.clone());
+            die.all_cores.insert(core.id, core.clone());
+            die.all_cpus.insert(cpu_id, cpu.clone());
+
+            let key = match cpu.cluster_id {
+                id if id >= 0 => (cpu.package_id, cpu.die_id, id, usize::MAX),
+                id => (cpu.package_id, cpu.die_id, id, core.id),
+            };
+            let nr_clusters = cluster_ids.len();
+            let cluster_id = *cluster_ids.entry(key).or_insert(nr_clusters);
+            let cluster = clusters.entry(cluster_id).or_insert_with(|| Cluster {
+                id: cluster_id,
+                kernel_id: cpu.cluster_id,
+                span: Cpumask::with_len(mask_len),
+                llc_id: cpu.llc_id,
+                die_id: cpu.die_id,
+                node_id: cpu.node_id,
+                cores: BTreeMap::new(),
+                all_cpus: BTreeMap::new(),
+            });
+            cluster.span.set_cpu(cpu_id).unwrap();
+            cluster.cores.insert(core.id, core.clone());
+            cluster.all_cpus.insert(cpu_id, cpu.clone());
+        }
+
+        self.all_dies = dies;
+        self.all_clusters = clusters;
+    }
+
+    /// Re-read the memory statistics of all nodes. This only reads one
+    /// meminfo file and the hugepages counters per node and is cheap enough
+    /// to call on every stats interval. This is a no-op for synthetic
+    /// Topologies built by TopologyBuilder or loaded from JSON as their
+    /// nodes don't exist on the host.
+    pub fn refresh_memory(&mut self) -> Result<()> {
+        if self.synthetic {
+            return Ok(());
+        }
+        for node in self.nodes.values_mut() {
+            let node_id = match node.memory.system_wide {
+                true => None,
+                false => Some(node.id),
+            };
+            node.memory = NodeMemory::read(node_id)?;
+        }
+        Ok(())
     }
 
-    /// Build a complete host Topology
+    /// Re-read the frequencies, governor and capacity of all CPUs from
+    /// sysfs, e.g. after cpufreq limits or the governor were changed. The
+    /// hierarchy itself is not changed. Use TopologyWatcher to track CPU
+    /// hotplug.
+    ///
+    /// Cpu objects are copied on write, so Arcs held from before the
+    /// refresh keep the old values.
+    pub fn refresh_cpus(&mut self) -> Result<()> {
+        let capacity_src = cpu_capacity_source();
+
+        // Drop all the skip indices, including the die and cluster ones
+        // which hold references to the LLCs, cores and CPUs too, so that
+        // Arc::make_mut() only copies objects which are still referenced
+        // from outside, e.g. by clones of the Topology. The indices are
+        // rebuilt from the refreshed hierarchy below.
+        self.all_llcs.clear();
+        self.all_cores.clear();
+        self.all_cpus.clear();
+        self.all_dies.clear();
+        self.all_clusters.clear();
+        for node in self.nodes.values_mut() {
+            node.all_cores.clear();
+            node.all_cpus.clear();
+            for llc in node.llcs.values_mut() {
+                let llc_mut = Arc::make_mut(llc);
+                llc_mut.all_cpus.clear();
+                for core in llc_mut.cores.values_mut() {
+                    for cpu in Arc::make_mut(core).cpus.values_mut() {
+                        Arc::make_mut(cpu).read_freqs(&capacity_src);
+                    }
+                }
+            }
+        }
+
+        self.build_skip_indices()
+    }
+
+    /// Build a complete host Topology. If the `SCX_TOPOLOGY_JSON`
+    /// environment variable is set, the Topology is loaded from the JSON
+    /// file it points to instead of sysfs. See `Topology::from_json()`.
     pub fn new() -> Result<Topology> {
+        if let Ok(path) = std::env::var("SCX_TOPOLOGY_JSON") {
+            return Self::from_json(Path::new(&path));
+        }
+
         let span = cpus_online()?;
         let mut topo_ctx = TopoCtx::new();
         // If the kernel is compiled with CONFIG_NUMA, then build a topology
@@ -281,6 +738,43 @@ impl Topology {
         Self::instantiate(span, nodes)
     }
 
+    /// Returns the process-wide cached Topology, building it with
+    /// `Topology::new()` on first use or after `invalidate_cached()`.
+    /// Building a Topology reads hundreds of sysfs files on large machines,
+    /// so code which needs the topology repeatedly should use this instead
+    /// of `new()`. A TopologyWatcher can invalidate the cache on hotplug.
+    pub fn cached() -> Result<Arc<Topology>> {
+        let mut cached = CACHED_TOPOLOGY.lock().unwrap();
+        if let Some(topo) = cached.as_ref() {
+            return Ok(topo.clone());
+        }
+        let topo = Arc::new(Topology::new()?);
+        *cached = Some(topo.clone());
+        Ok(topo)
+    }
+
+    /// Drop the cached Topology so that the next `cached()` call rebuilds
+    /// it. Arcs returned earlier keep the old Topology.
+    pub fn invalidate_cached() {
+        *CACHED_TOPOLOGY.lock().unwrap() = None;
+    }
+
+    /// Load a Topology from a JSON file created with `Topology::to_json()`.
+    /// The loaded Topology reflects the recorded machine, not the host.
+    /// Cpumasks are sized by the recorded masks, so the recorded machine
+    /// may have more CPU IDs than the host.
+    pub fn from_json(path: &Path) -> Result<Topology> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read topology from {:?}", path))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse topology from {:?}", path))
+    }
+
+    /// Serialize the Topology to pretty printed JSON.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
     pub fn with_flattened_llc_node() -> Result<Topology> {
         let span = cpus_online()?;
         let mut topo_ctx = TopoCtx::new();
@@ -307,14 +801,357 @@ impl Topology {
             .any(|c| c.core_type == CoreType::Little)
     }
 
+    /// Compare two Topology snapshots, e.g. from before and after a hotplug
+    /// event or `refresh_cpus()`, so that schedulers can update only what
+    /// changed instead of rebuilding all domains.
+    pub fn diff(old: &Topology, new: &Topology) -> TopologyDiff {
+        let mut capacity = BTreeMap::new();
+        let mut freq_limits = BTreeMap::new();
+
+        for (cpu_id, new_cpu) in new.all_cpus.iter() {
+            let Some(old_cpu) = old.all_cpus.get(cpu_id) else {
+                continue;
+            };
+            if old_cpu.cpu_capacity != new_cpu.cpu_capacity {
+                capacity.insert(
+                    *cpu_id,
+                    ValueChange {
+                        old: old_cpu.cpu_capacity,
+                        new: new_cpu.cpu_capacity,
+                    },
+                );
+            }
+            let old_limits = (old_cpu.min_freq, old_cpu.max_freq);
+            let new_limits = (new_cpu.min_freq, new_cpu.max_freq);
+            if old_limits != new_limits {
+                freq_limits.insert(
+                    *cpu_id,
+                    ValueChange {
+                        old: old_limits,
+                        new: new_limits,
+                    },
+                );
+            }
+        }
+
+        TopologyDiff {
+            added: new.span.and(&old.span.not()),
+            removed: old.span.and(&new.span.not()),
+            capacity,
+            freq_limits,
+        }
+    }
+
+    /// Returns the nodes which have CPUs. Memory-only nodes such as CXL
+    /// expanders should never be used as scheduling domains.
+    pub fn cpu_nodes(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.values().filter(|node| !node.span.is_empty())
+    }
+
+    /// Returns the NUMA distance between nodes `@from` and `@to` as
+    /// reported by the SLIT, or None if either node doesn't exist. The
+    /// distance from a node to itself is 10 and larger values mean further
+    /// apart.
+    pub fn distance(&self, from: usize, to: usize) -> Option<usize> {
+        let from_node = self.nodes.get(&from)?;
+        let idx = self.nodes.keys().position(|&id| id == to)?;
+        match from_node.distance.get(idx) {
+            Some(&dist) => Some(dist),
+            None if from == to => Some(LOCAL_DISTANCE),
+            None => None,
+        }
+    }
+
+    /// Returns the NUMA distance matrix indexed by the position of each
+    /// node in `nodes`.
+    pub fn distance_matrix(&self) -> Vec<Vec<usize>> {
+        self.nodes
+            .keys()
+            .map(|&from| {
+                self.nodes
+                    .keys()
+                    .map(|&to| self.distance(from, to).unwrap_or(usize::MAX))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Returns the IDs of all other nodes ordered from the nearest to the
+    /// furthest from `@node`. Nodes at the same distance are ordered by ID.
+    pub fn nearest_nodes(&self, node: usize) -> Vec<usize> {
+        let mut nodes: Vec<(usize, usize)> = self
+            .nodes
+            .keys()
+            .filter(|&&id| id != node)
+            .map(|&id| (self.distance(node, id).unwrap_or(usize::MAX), id))
+            .collect();
+        nodes.sort();
+        nodes.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// Returns the groups of CPUs sharing each cache of `@level`, ordered by
+    /// their first CPU. Empty if the cache level doesn't exist.
+    pub fn cache_groups(&self, level: usize) -> &[CacheGroup] {
+        self.cache_groups
+            .get(&level)
+            .map(|groups| groups.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Returns the groups of CPUs sharing each last level cache, i.e. the
+    /// highest cache level reported by sysfs.
+    pub fn llc_groups(&self) -> &[CacheGroup] {
+        self.cache_groups
+            .values()
+            .next_back()
+            .map(|groups| groups.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Returns a Cpumask of the CPUs isolated from the scheduler domains,
+    /// either with the `isolcpus=` boot parameter or by an isolated cpuset
+    /// partition.
+    pub fn isolated_cpus(&self) -> Cpumask {
+        let mut isolated = read_cpulist_file("/sys/devices/system/cpu/isolated")
+            .or_else(|| kernel_cmdline_cpus("isolcpus"))
+            .unwrap_or_else(|| self.new_mask());
+        if let Some(mask) = read_cpulist_file("/sys/fs/cgroup/cpuset.cpus.isolated") {
+            isolated |= &mask;
+        }
+        isolated.and(&self.span)
+    }
+
+    /// Returns a Cpumask of the adaptive-tick CPUs set up with the
+    /// `nohz_full=` boot parameter.
+    pub fn nohz_full_cpus(&self) -> Cpumask {
+        read_cpulist_file("/sys/devices/system/cpu/nohz_full")
+            .or_else(|| kernel_cmdline_cpus("nohz_full"))
+            .unwrap_or_else(|| self.new_mask())
+            .and(&self.span)
+    }
+
+    /// Returns a Cpumask of the housekeeping CPUs, i.e. the CPUs which are
+    /// neither isolated nor nohz_full and are expected to run unbound
+    /// kernel work and daemons.
+    pub fn housekeeping_cpus(&self) -> Cpumask {
+        let excluded = self.isolated_cpus().or(&self.nohz_full_cpus());
+        self.span.and(&excluded.not())
+    }
+
+    /// Returns a Cpumask of the CPUs a scheduler should place work on, i.e.
+    /// all CPUs which are not isolated. nohz_full CPUs are included as
+    /// they're still meant to run tasks.
+    pub fn schedulable_cpus(&self) -> Cpumask {
+        self.span.and(&self.isolated_cpus().not())
+    }
+
+    /// Returns the CPUs of the Topology which are allowed by the cpuset of
+    /// `@cgroup`, a cgroup directory such as `/sys/fs/cgroup/workload`, or
+    /// of the calling process's cgroup if None. Both cgroup v1 and v2 are
+    /// supported. If no cpuset constraint is found, the span is returned.
+    pub fn effective_cpus(&self, cgroup: Option<&Path>) -> Result<Cpumask> {
+        let cpus = match cgroup {
+            Some(cgroup) => cgroup_cpuset_cpus(cgroup)?,
+            None => process_cpuset_cpus()?,
+        };
+        Ok(match cpus {
+            Some(cpus) => cpus.and(&self.span),
+            None => self.span.clone(),
+        })
+    }
+
+    /// Returns a copy of the Topology which only contains the CPUs in
+    /// `@mask`, e.g. from `Topology::effective_cpus()`, so that a scheduler
+    /// confined to a container doesn't assume it owns every CPU. Cores,
+    /// LLCs and nodes without any CPU left are dropped and IDs are kept.
+    pub fn restrict(&self, mask: &Cpumask) -> Result<Topology> {
+        let span = self.span.and(mask);
+        if span.is_empty() {
+            bail!("No CPU left after restricting topology to {}", mask);
+        }
+
+        let kept: Vec<usize> = self
+            .nodes
+            .values()
+            .enumerate()
+            .filter(|(_, node)| !node.span.and(mask).is_empty())
+            .map(|(pos, _)| pos)
+            .collect();
+
+        let mut nodes = BTreeMap::new();
+        for (pos, node) in self.nodes.values().enumerate() {
+            if !kept.contains(&pos) {
+                continue;
+            }
+            let mut node = node.clone();
+            node.span = node.span.and(mask);
+            node.all_cores.clear();
+            node.all_cpus.clear();
+            if node.distance.len() == self.nodes.len() {
+                node.distance = kept.iter().map(|&pos| node.distance[pos]).collect();
+            }
+
+            node.llcs.retain(|_, llc| !llc.span.and(mask).is_empty());
+            for llc in node.llcs.values_mut() {
+                let llc_mut = Arc::make_mut(llc);
+                llc_mut.span = llc_mut.span.and(mask);
+                llc_mut.all_cpus.clear();
+
+                llc_mut
+                    .cores
+                    .retain(|_, core| !core.span.and(mask).is_empty());
+                for core in llc_mut.cores.values_mut() {
+                    let core_mut = Arc::make_mut(core);
+                    core_mut.span = core_mut.span.and(mask);
+                    core_mut.cpus.retain(|&cpu, _| mask.test_cpu(cpu));
+                }
+            }
+            nodes.insert(node.id, node);
+        }
+
+        let cache_groups = self
+            .cache_groups
+            .iter()
+            .map(|(&level, groups)| {
+                let groups = groups
+                    .iter()
+                    .filter(|g| !g.span.and(mask).is_empty())
+                    .map(|g| CacheGroup {
+                        span: g.span.and(mask),
+                        ..g.clone()
+                    })
+                    .collect();
+                (level, groups)
+            })
+            .collect();
+
+        let mut topo = Topology {
+            nodes,
+            span,
+            smt_enabled: self.smt_enabled,
+            all_llcs: BTreeMap::new(),
+            all_cores: BTreeMap::new(),
+            all_cpus: BTreeMap::new(),
+            all_dies: BTreeMap::new(),
+            all_clusters: BTreeMap::new(),
+            cache_groups,
+            energy_model: self.energy_model.clone(),
+            synthetic: self.synthetic,
+        };
+        topo.build_skip_indices()?;
+        Ok(topo)
+    }
+
+    /// Returns the PCI devices, optionally only of `@kind`, ordered by
+    /// their PCI address. Local CPUs are restricted to the Topology. See
+    /// `read_pci_devices()`.
+    pub fn pci_devices(&self, kind: Option<PciDeviceKind>) -> Result<Vec<PciDevice>> {
+        let mut devices = read_pci_devices(kind)?;
+        for dev in devices.iter_mut() {
+            dev.local_cpus = dev.local_cpus.and(&self.span);
+            if dev.local_cpus.is_empty() {
+                dev.local_cpus = match dev.numa_node.and_then(|id| self.nodes.get(&id)) {
+                    Some(node) => node.span.clone(),
+                    None => self.span.clone(),
+                };
+            }
+        }
+        Ok(devices)
+    }
+
+    /// Returns the energy cost of running `@cpu` at `@freq` in kHz, or
+    /// None if there's no energy model. See `EnergyModel::perf_state()`.
+    pub fn energy_cost(&self, cpu: usize, freq: usize) -> Option<usize> {
+        self.energy_model.as_ref()?.energy_cost(cpu, freq)
+    }
+
+    fn new_mask(&self) -> Cpumask {
+        Cpumask::with_len(self.span.len())
+    }
+
+    /// Returns a Cpumask of all performance (big) CPUs. On systems without
+    /// a hybrid architecture, this is the same as the Topology span.
+    pub fn performance_cpus(&self) -> Cpumask {
+        self.core_type_cpus(|t| matches!(t, CoreType::Big { .. }))
+    }
+
+    /// Returns a Cpumask of all efficiency (little) CPUs. On systems without
+    /// a hybrid architecture, this is empty.
+    pub fn efficiency_cpus(&self) -> Cpumask {
+        self.core_type_cpus(|t| *t == CoreType::Little)
+    }
+
+    pub(crate) fn core_type_cpus(&self, pred: impl Fn(&CoreType) -> bool) -> Cpumask {
+        let mut mask = self.new_mask();
+        for cpu in self.all_cpus.values().filter(|c| pred(&c.core_type)) {
+            mask.set_cpu(cpu.id).unwrap();
+        }
+        mask
+    }
+
+    /// Returns whether SMT is currently active. Unlike the `smt_enabled`
+    /// field, which is a snapshot taken when the Topology was built, this
+    /// reads the current state from sysfs and falls back to the snapshot
+    /// if it's not available.
+    pub fn smt_enabled(&self) -> bool {
+        is_smt_active().unwrap_or(self.smt_enabled)
+    }
+
+    /// Returns the current SMT control state, or None if the kernel doesn't
+    /// report it.
+    pub fn smt_control(&self) -> Option<SmtControl> {
+        let control: String =
+            read_from_file(Path::new("/sys/devices/system/cpu/smt/control")).ok()?;
+        control.parse().ok()
+    }
+
+    /// Returns a Cpumask of the other hardware threads in the core of
+    /// `@cpu`. Empty if `@cpu` doesn't exist or has no online siblings.
+    pub fn smt_siblings(&self, cpu: usize) -> Cpumask {
+        let mut siblings = self.new_mask();
+        if let Some(cpu) = self.all_cpus.get(&cpu) {
+            if let Some(core) = self
+                .all_llcs
+                .get(&cpu.llc_id)
+                .and_then(|llc| llc.cores.get(&cpu.core_id))
+            {
+                siblings = core.span.clone();
+                siblings.clear_cpu(cpu.id).unwrap();
+            }
+        }
+        siblings
+    }
+
+    /// Returns a Cpumask of the primary hardware thread, i.e. the lowest
+    /// numbered CPU, of each core. Without SMT, this is the Topology span.
+    pub fn primary_smt_cpus(&self) -> Cpumask {
+        let mut mask = self.new_mask();
+        for llc in self.all_llcs.values() {
+            for core in llc.cores.values() {
+                if let Some(&cpu) = core.cpus.keys().next() {
+                    mask.set_cpu(cpu).unwrap();
+                }
+            }
+        }
+        mask
+    }
+
+    /// Returns a Cpumask of all the non-primary hardware threads. Without
+    /// SMT, this is empty.
+    pub fn secondary_smt_cpus(&self) -> Cpumask {
+        self.span.and(&self.primary_smt_cpus().not())
+    }
+
     /// Returns a vector that maps the index of each logical CPU to the
     /// sibling CPU. This represents the "next sibling" CPU within a package
     /// in systems that support SMT. The sibling CPU is the other logical
     /// CPU that shares the physical resources of the same physical core.
     ///
-    /// Assuming each core holds exactly at most two cpus.
+    /// Assuming each core holds exactly at most two cpus. The vector covers
+    /// at least all possible CPUs and all CPUs of the Topology, which may be
+    /// more for synthetic Topologies.
     pub fn sibling_cpus(&self) -> Vec<i32> {
-        let mut sibling_cpu = vec![-1i32; *NR_CPUS_POSSIBLE];
+        let mut sibling_cpu = vec![-1i32; self.span.len().max(*NR_CPUS_POSSIBLE)];
         for core in self.all_cores.values() {
             let mut first = -1i32;
             for &cpu in core.cpus.keys() {
@@ -331,6 +1168,288 @@ impl Topology {
     }
 }
 
+/// The old and new values of a changed attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueChange<T> {
+    pub old: T,
+    pub new: T,
+}
+
+/// Changes between two Topology snapshots, see `Topology::diff()`.
+#[derive(Debug, Clone)]
+pub struct TopologyDiff {
+    /// CPUs which came online.
+    pub added: Cpumask,
+    /// CPUs which went offline.
+    pub removed: Cpumask,
+    /// CPUs present in both snapshots whose cpu_capacity changed.
+    pub capacity: BTreeMap<usize, ValueChange<usize>>,
+    /// CPUs present in both snapshots whose (min_freq, max_freq) scaling
+    /// limits changed.
+    pub freq_limits: BTreeMap<usize, ValueChange<(usize, usize)>>,
+}
+
+impl TopologyDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.capacity.is_empty()
+            && self.freq_limits.is_empty()
+    }
+
+    /// CPUs which were added or changed in any way. Removed CPUs are not
+    /// included.
+    pub fn changed_cpus(&self) -> Cpumask {
+        let mut mask = self.added.clone();
+        for &cpu in self.capacity.keys().chain(self.freq_limits.keys()) {
+            mask.set_cpu(cpu).unwrap();
+        }
+        mask
+    }
+}
+
+/// Builds synthetic Topology objects without touching sysfs, e.g. to test
+/// scheduling logic against machines other than the host. Nodes, LLCs and
+/// cores are numbered in order. CPUs are numbered like on x86, i.e. the
+/// first hardware threads of all cores come first, followed by the second
+/// ones and so on.
+///
+///```
+///     use scx_utils::TopologyBuilder;
+///     let topo = TopologyBuilder::new()
+///         .nodes(2)
+///         .llcs_per_node(2)
+///         .cores_per_llc(4)
+///         .smt(2)
+///         .build()
+///         .unwrap();
+///     assert_eq!(topo.all_cpus.len(), 32);
+///     assert_eq!(topo.smt_siblings(0).iter().collect::<Vec<_>>(), vec![16]);
+///```
+#[derive(Debug, Clone)]
+pub struct TopologyBuilder {
+    nr_nodes: usize,
+    llcs_per_node: usize,
+    cores_per_llc: usize,
+    smt: usize,
+    little_cores_per_llc: usize,
+    min_freq: usize,
+    max_freq: usize,
+    llc_size: usize,
+    remote_distance: usize,
+}
+
+impl TopologyBuilder {
+    /// A single node with one LLC containing one core without SMT.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            nr_nodes: 1,
+            llcs_per_node: 1,
+            cores_per_llc: 1,
+            smt: 1,
+            little_cores_per_llc: 0,
+            min_freq: 0,
+            max_freq: 0,
+            llc_size: 32 << 20,
+            remote_distance: 20,
+        }
+    }
+
+    pub fn nodes(&mut self, nr_nodes: usize) -> &mut Self {
+        self.nr_nodes = nr_nodes;
+        self
+    }
+
+    pub fn llcs_per_node(&mut self, nr_llcs: usize) -> &mut Self {
+        self.llcs_per_node = nr_llcs;
+        self
+    }
+
+    pub fn cores_per_llc(&mut self, nr_cores: usize) -> &mut Self {
+        self.cores_per_llc = nr_cores;
+        self
+    }
+
+    /// Number of hardware threads per core.
+    pub fn smt(&mut self, nr_threads: usize) -> &mut Self {
+        self.smt = nr_threads;
+        self
+    }
+
+    /// Make the last `@nr_cores` cores of each LLC little cores with half
+    /// the capacity of the big ones.
+    pub fn little_cores_per_llc(&mut self, nr_cores: usize) -> &mut Self {
+        self.little_cores_per_llc = nr_cores;
+        self
+    }
+
+    /// Scaling frequency limits of all CPUs in kHz.
+    pub fn freq(&mut self, min_freq: usize, max_freq: usize) -> &mut Self {
+        self.min_freq = min_freq;
+        self.max_freq = max_freq;
+        self
+    }
+
+    /// Size of each LLC in bytes.
+    pub fn llc_size(&mut self, size: usize) -> &mut Self {
+        self.llc_size = size;
+        self
+    }
+
+    /// NUMA distance between different nodes.
+    pub fn remote_distance(&mut self, distance: usize) -> &mut Self {
+        self.remote_distance = distance;
+        self
+    }
+
+    pub fn build(&self) -> Result<Topology> {
+        let nr_llcs = self.nr_nodes * self.llcs_per_node;
+        let nr_cores = nr_llcs * self.cores_per_llc;
+        let nr_cpus = nr_cores * self.smt;
+        if nr_cpus == 0 {
+            bail!("Synthetic topology must have at least one CPU");
+        }
+        if self.little_cores_per_llc > self.cores_per_llc {
+            bail!(
+                "{} little cores exceed {} cores per LLC",
+                self.little_cores_per_llc,
+                self.cores_per_llc
+            );
+        }
+
+        let mut span = Cpumask::with_len(nr_cpus);
+        let mut nodes = BTreeMap::new();
+        let mut llc_groups = vec![];
+
+        for node_id in 0..self.nr_nodes {
+            let distance = (0..self.nr_nodes)
+                .map(|id| {
+                    if id == node_id {
+                        LOCAL_DISTANCE
+                    } else {
+                        self.remote_distance
+                    }
+                })
+                .collect();
+            let mut node = Node {
+                id: node_id,
+                distance,
+                llcs: BTreeMap::new(),
+                span: Cpumask::with_len(nr_cpus),
+                all_cores: BTreeMap::new(),
+                all_cpus: BTreeMap::new(),
+                memory: NodeMemory::default(),
+                memory_kind: MemoryKind::Dram,
+                memory_tier: None,
+                #[cfg(feature = "gpu-topology")]
+                gpus: BTreeMap::new(),
+            };
+
+            for llc_idx in 0..self.llcs_per_node {
+                let llc_id = node_id * self.llcs_per_node + llc_idx;
+                let mut llc = Llc {
+                    id: llc_id,
+                    kernel_id: llc_id,
+                    cores: BTreeMap::new(),
+                    span: Cpumask::with_len(nr_cpus),
+                    node_id,
+                    all_cpus: BTreeMap::new(),
+                };
+
+                for core_idx in 0..self.cores_per_llc {
+                    let core_id = llc_id * self.cores_per_llc + core_idx;
+                    let (core_type, cpu_capacity) =
+                        if core_idx >= self.cores_per_llc - self.little_cores_per_llc {
+                            (CoreType::Little, 512)
+                        } else {
+                            (CoreType::Big { turbo: true }, 1024)
+                        };
+                    let mut core = Core {
+                        id: core_id,
+                        kernel_id: core_id,
+                        cluster_id: -1,
+                        cpus: BTreeMap::new(),
+                        span: Cpumask::with_len(nr_cpus),
+                        core_type: core_type.clone(),
+                        llc_id,
+                        node_id,
+                    };
+
+                    for thread in 0..self.smt {
+                        let id = thread * nr_cores + core_id;
+                        core.cpus.insert(
+                            id,
+                            Arc::new(Cpu {
+                                id,
+                                min_freq: self.min_freq,
+                                max_freq: self.max_freq,
+                                base_freq: self.max_freq,
+                                cpu_capacity,
+                                governor: String::new(),
+                                smt_level: 0, // Will be initialized at build_skip_indices().
+                                pm_qos_resume_latency_us: 0,
+                                trans_lat_ns: 0,
+                                l2_id: core_id,
+                                l3_id: llc_id,
+                                cache_size: self.llc_size / (self.cores_per_llc * self.smt),
+                                caches: vec![CacheInfo {
+                                    level: 3,
+                                    cache_type: CacheType::Unified,
+                                    size: self.llc_size,
+                                    ways: 16,
+                                    sets: self.llc_size / (16 * 64),
+                                    line_size: 64,
+                                }],
+                                core_type: core_type.clone(),
+                                core_id,
+                                llc_id,
+                                node_id,
+                                package_id: node_id,
+                                cluster_id: -1,
+                                die_id: node_id,
+                            }),
+                        );
+                        core.span.set_cpu(id)?;
+                        llc.span.set_cpu(id)?;
+                        node.span.set_cpu(id)?;
+                        span.set_cpu(id)?;
+                    }
+
+                    llc.cores.insert(core_id, Arc::new(core));
+                }
+
+                llc_groups.push(CacheGroup {
+                    id: llc_id,
+                    kernel_id: Some(llc_id),
+                    level: 3,
+                    size: self.llc_size,
+                    span: llc.span.clone(),
+                });
+                node.llcs.insert(llc_id, Arc::new(llc));
+            }
+
+            nodes.insert(node_id, node);
+        }
+
+        let mut topo = Topology {
+            nodes,
+            span,
+            smt_enabled: self.smt > 1,
+            all_llcs: BTreeMap::new(),
+            all_cores: BTreeMap::new(),
+            all_cpus: BTreeMap::new(),
+            all_dies: BTreeMap::new(),
+            all_clusters: BTreeMap::new(),
+            cache_groups: BTreeMap::from([(3, llc_groups)]),
+            energy_model: None,
+            synthetic: true,
+        };
+        topo.build_skip_indices()?;
+        Ok(topo)
+    }
+}
+
 /******************************************************
  * Helper structs/functions for creating the Topology *
  ******************************************************/
@@ -340,10 +1459,16 @@ struct TopoCtx {
     node_core_kernel_ids: BTreeMap<(usize, usize, usize), usize>,
     /// Mapping of NUMA node LLC ids
     node_llc_kernel_ids: BTreeMap<(usize, usize, usize), usize>,
+    /// Mapping of package die ids
+    die_kernel_ids: BTreeMap<(usize, usize), usize>,
     /// Mapping of L2 ids
     l2_ids: BTreeMap<String, usize>,
     /// Mapping of L3 ids
     l3_ids: BTreeMap<String, usize>,
+    /// P-core and E-core CPUs on Intel hybrid systems
+    intel_hybrid_cpus: Option<(Cpumask, Cpumask)>,
+    /// Mapping of CPUs to device tree clusters on arm64
+    dt_cluster_ids: BTreeMap<usize, isize>,
 }
 
 impl TopoCtx {
@@ -355,13 +1480,16 @@ impl TopoCtx {
         TopoCtx {
             node_core_kernel_ids: core_kernel_ids,
             node_llc_kernel_ids: llc_kernel_ids,
+            die_kernel_ids: BTreeMap::new(),
             l2_ids,
             l3_ids,
+            intel_hybrid_cpus: intel_hybrid_cpus(),
+            dt_cluster_ids: dt_cluster_ids(),
         }
     }
 }
 
-fn cpus_online() -> Result<Cpumask> {
+pub(crate) fn cpus_online() -> Result<Cpumask> {
     let path = "/sys/devices/system/cpu/online";
     let online = std::fs::read_to_string(path)?;
     Cpumask::from_cpulist(&online)
@@ -420,6 +1548,89 @@ fn get_per_cpu_cache_size(cache_path: &PathBuf) -> Result<usize> {
     Ok(tot_size)
 }
 
+/// Group the online CPUs by the data and unified caches they share as
+/// reported by /sys/devices/system/cpu/cpuX/cache/indexI.
+fn read_cache_groups(span: &Cpumask) -> BTreeMap<usize, Vec<CacheGroup>> {
+    let mut caches = BTreeMap::<(usize, String), CacheGroup>::new();
+
+    for cpu in span.iter() {
+        let pattern = format!("/sys/devices/system/cpu/cpu{}/cache/index[0-9]*", cpu);
+        let Ok(paths) = glob(&pattern) else {
+            continue;
+        };
+        for index in paths.filter_map(Result::ok) {
+            let cache_type: String = read_from_file(&index.join("type")).unwrap_or_default();
+            if cache_type == "Instruction" {
+                continue;
+            }
+            let Ok(level) = read_from_file::<usize>(&index.join("level")) else {
+                continue;
+            };
+            let Ok(cpulist) = read_from_file::<String>(&index.join("shared_cpu_list")) else {
+                continue;
+            };
+            if caches.contains_key(&(level, cpulist.clone())) {
+                continue;
+            }
+            let Ok(mask) = Cpumask::from_cpulist(&cpulist) else {
+                continue;
+            };
+
+            caches.insert(
+                (level, cpulist),
+                CacheGroup {
+                    id: 0,
+                    kernel_id: read_from_file(&index.join("id")).ok(),
+                    level,
+                    size: read_file_byte(&index.join("size")).unwrap_or(0),
+                    span: mask.and(span),
+                },
+            );
+        }
+    }
+
+    let mut groups = BTreeMap::<usize, Vec<CacheGroup>>::new();
+    for ((level, _), group) in caches.into_iter() {
+        groups.entry(level).or_default().push(group);
+    }
+    for level_groups in groups.values_mut() {
+        level_groups.sort_by_key(|g| g.span.iter().next());
+        for (id, group) in level_groups.iter_mut().enumerate() {
+            group.id = id;
+        }
+    }
+    groups
+}
+
+fn read_cpu_caches(cache_path: &Path) -> Vec<CacheInfo> {
+    let Ok(paths) = glob(&format!("{}/index[0-9]*", cache_path.display())) else {
+        return vec![];
+    };
+
+    let mut caches = vec![];
+    for index in paths.filter_map(Result::ok) {
+        let cache_type = match read_from_file::<String>(&index.join("type")).as_deref() {
+            Ok("Data") => CacheType::Data,
+            Ok("Instruction") => CacheType::Instruction,
+            Ok("Unified") => CacheType::Unified,
+            _ => continue,
+        };
+        let Ok(level) = read_from_file(&index.join("level")) else {
+            continue;
+        };
+        caches.push(CacheInfo {
+            level,
+            cache_type,
+            size: read_file_byte(&index.join("size")).unwrap_or(0),
+            ways: read_from_file(&index.join("ways_of_associativity")).unwrap_or(0),
+            sets: read_from_file(&index.join("number_of_sets")).unwrap_or(0),
+            line_size: read_from_file(&index.join("coherency_line_size")).unwrap_or(0),
+        });
+    }
+    caches.sort();
+    caches
+}
+
 #[allow(clippy::too_many_arguments)]
 fn create_insert_cpu(
     id: usize,
@@ -443,7 +1654,20 @@ fn create_insert_cpu(
     let top_path = cpu_path.join("topology");
     let core_kernel_id = read_from_file(&top_path.join("core_id"))?;
     let package_id = read_from_file(&top_path.join("physical_package_id"))?;
-    let cluster_id = read_from_file(&top_path.join("cluster_id"))?;
+    let cluster_id = match read_from_file::<isize>(&top_path.join("cluster_id")) {
+        Ok(id) if id >= 0 => id,
+        res => match topo_ctx.dt_cluster_ids.get(&id) {
+            Some(&id) => id,
+            None => res?,
+        },
+    };
+    // die_id is only reported on x86.
+    let die_kernel_id = read_from_file(&top_path.join("die_id")).unwrap_or(0_usize);
+    let num_dies = topo_ctx.die_kernel_ids.len();
+    let die_id = *topo_ctx
+        .die_kernel_ids
+        .entry((package_id, die_kernel_id))
+        .or_insert(num_dies);
 
     // Evaluate L2, L3 and LLC cache IDs.
     //
@@ -463,21 +1687,13 @@ fn create_insert_cpu(
 
     // Per-CPU cache size
     let cache_size = get_per_cpu_cache_size(&cache_path).unwrap_or(0_usize);
+    let caches = read_cpu_caches(&cache_path);
 
-    // Min and max frequencies. If the kernel is not compiled with
-    // CONFIG_CPU_FREQ, just assume 0 for both frequencies.
-    let freq_path = cpu_path.join("cpufreq");
-    let min_freq = read_from_file(&freq_path.join("scaling_min_freq")).unwrap_or(0_usize);
-    let max_freq = read_from_file(&freq_path.join("scaling_max_freq")).unwrap_or(0_usize);
-    let base_freq = read_from_file(&freq_path.join("base_frequency")).unwrap_or(max_freq);
-    let trans_lat_ns =
-        read_from_file(&freq_path.join("cpuinfo_transition_latency")).unwrap_or(0_usize);
+    let freqs = read_cpu_freqs(cpu_path);
 
     // Cpu capacity
-    let (cap_suffix, avg_rcap, max_rcap) = capacity_src.unwrap_or(("".to_string(), 1024, 1024));
-    let cap_path = cpu_path.join(cap_suffix);
-    let rcap = read_from_file(&cap_path).unwrap_or(max_rcap);
-    let cpu_capacity = (rcap * 1024) / max_rcap;
+    let (rcap, cpu_capacity) = read_cpu_capacity(cpu_path, &capacity_src);
+    let (_, avg_rcap, max_rcap) = capacity_src.unwrap_or(("".to_string(), 1024, 1024));
 
     // Power management
     let power_path = cpu_path.join("power");
@@ -501,7 +1717,15 @@ fn create_insert_cpu(
     }));
     let llc_mut = Arc::get_mut(llc).unwrap();
 
-    let core_type = if rcap == max_rcap {
+    let core_type = if let Some((pcpus, ecpus)) = &topo_ctx.intel_hybrid_cpus {
+        if ecpus.test_cpu(id) {
+            CoreType::Little
+        } else {
+            CoreType::Big {
+                turbo: pcpus.test_cpu(id) && rcap == max_rcap,
+            }
+        }
+    } else if rcap == max_rcap {
         CoreType::Big { turbo: true }
     } else if rcap >= avg_rcap {
         CoreType::Big { turbo: false }
@@ -532,16 +1756,18 @@ fn create_insert_cpu(
         id,
         Arc::new(Cpu {
             id,
-            min_freq,
-            max_freq,
-            base_freq,
+            min_freq: freqs.min_freq,
+            max_freq: freqs.max_freq,
+            base_freq: freqs.base_freq,
             cpu_capacity,
+            governor: freqs.governor,
             smt_level: 0, // Will be initialized at instantiate().
             pm_qos_resume_latency_us,
-            trans_lat_ns,
+            trans_lat_ns: freqs.trans_lat_ns,
             l2_id,
             l3_id,
             cache_size,
+            caches,
             core_type: core_type.clone(),
 
             core_id: *core_id,
@@ -549,6 +1775,7 @@ fn create_insert_cpu(
             node_id: node.id,
             package_id,
             cluster_id,
+            die_id,
         }),
     );
 
@@ -564,6 +1791,142 @@ fn create_insert_cpu(
     Ok(())
 }
 
+struct CpuFreqs {
+    min_freq: usize,
+    max_freq: usize,
+    base_freq: usize,
+    trans_lat_ns: usize,
+    governor: String,
+}
+
+fn read_cpu_freqs(cpu_path: &Path) -> CpuFreqs {
+    // Min and max frequencies. If the kernel is not compiled with
+    // CONFIG_CPU_FREQ, just assume 0 for both frequencies.
+    let freq_path = cpu_path.join("cpufreq");
+    let min_freq = read_from_file(&freq_path.join("scaling_min_freq")).unwrap_or(0_usize);
+    let max_freq = read_from_file(&freq_path.join("scaling_max_freq")).unwrap_or(0_usize);
+    let base_freq = read_from_file(&freq_path.join("base_frequency")).unwrap_or(max_freq);
+    let trans_lat_ns =
+        read_from_file(&freq_path.join("cpuinfo_transition_latency")).unwrap_or(0_usize);
+    let governor = read_from_file(&freq_path.join("scaling_governor")).unwrap_or_default();
+
+    CpuFreqs {
+        min_freq,
+        max_freq,
+        base_freq,
+        trans_lat_ns,
+        governor,
+    }
+}
+
+/// Returns the raw capacity and the capacity scaled to 1024.
+fn read_cpu_capacity(
+    cpu_path: &Path,
+    capacity_src: &Option<(String, usize, usize)>,
+) -> (usize, usize) {
+    let (cap_suffix, max_rcap) = match capacity_src {
+        Some((suffix, _, max)) => (suffix.as_str(), *max),
+        None => ("", 1024),
+    };
+    let rcap = read_raw_capacity(cpu_path, cap_suffix).unwrap_or(max_rcap);
+    (rcap, (rcap * 1024) / max_rcap)
+}
+
+/// The device tree capacity-dmips-mhz property of arm64 CPUs. The kernel
+/// derives cpu_capacity from it, but only once cpufreq is up and only if
+/// all CPUs have the property, which leaves cpu_capacity flat on many
+/// big.LITTLE boards.
+const DT_CAPACITY_SRC: &str = "of_node/capacity-dmips-mhz";
+
+/// Read a device tree cell, which is a big-endian u32.
+fn read_dt_u32(path: &Path) -> Option<u32> {
+    let bytes = std::fs::read(path).ok()?;
+    Some(u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+fn read_raw_capacity(cpu_path: &Path, src: &str) -> Option<usize> {
+    if src == DT_CAPACITY_SRC {
+        // Like the kernel, scale DMIPS/MHz by the maximum frequency.
+        let dmips = read_dt_u32(&cpu_path.join(src))? as usize;
+        let max_freq =
+            read_from_file(&cpu_path.join("cpufreq/cpuinfo_max_freq")).unwrap_or(1_usize);
+        return Some(dmips * max_freq);
+    }
+    read_from_file(&cpu_path.join(src)).ok()
+}
+
+/// Sort key for device tree paths which orders nodes such as cluster2 and
+/// cluster10 by their numeric IDs rather than lexicographically.
+fn dt_path_key(path: &Path) -> Vec<(String, u64)> {
+    path.components()
+        .map(|c| {
+            let name = c.as_os_str().to_string_lossy();
+            let prefix = name.trim_end_matches(|c: char| c.is_ascii_digit());
+            let id = name[prefix.len()..].parse().unwrap_or(0);
+            (prefix.to_string(), id)
+        })
+        .collect()
+}
+
+/// Map CPUs to clusters from the device tree cpu-map, for arm64 kernels which
+/// don't report cluster_id. Each clusterN node in the cpu-map, which may be
+/// nested, gets a distinct ID in the order of the cpu-map.
+fn dt_cluster_ids() -> BTreeMap<usize, isize> {
+    let mut cluster_ids = BTreeMap::new();
+    let cpu_map = Path::new("/proc/device-tree/cpus/cpu-map");
+    if !cpu_map.exists() {
+        return cluster_ids;
+    }
+
+    // Map device tree phandles to logical CPU IDs.
+    let mut phandles = BTreeMap::new();
+    for cpu_id in read_cpu_ids().unwrap_or_default() {
+        let path = format!("/sys/devices/system/cpu/cpu{}/of_node/phandle", cpu_id);
+        if let Some(phandle) = read_dt_u32(Path::new(&path)) {
+            phandles.insert(phandle, cpu_id);
+        }
+    }
+
+    let mut clusters = BTreeMap::new();
+    let mut entries: Vec<_> = walkdir::WalkDir::new(cpu_map)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name() == "cpu")
+        .map(|e| e.into_path())
+        .collect();
+    entries.sort_by_cached_key(|path| dt_path_key(path));
+    for entry in entries {
+        let Some(cluster) = entry.ancestors().find(|p| {
+            p.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("cluster"))
+        }) else {
+            continue;
+        };
+        let Some(phandle) = read_dt_u32(&entry) else {
+            continue;
+        };
+        let nr_clusters = clusters.len() as isize;
+        let cluster_id = *clusters.entry(cluster.to_path_buf()).or_insert(nr_clusters);
+        if let Some(&cpu_id) = phandles.get(&phandle) {
+            cluster_ids.insert(cpu_id, cluster_id);
+        }
+    }
+    cluster_ids
+}
+
+impl Cpu {
+    fn read_freqs(&mut self, capacity_src: &Option<(String, usize, usize)>) {
+        let cpu_path = PathBuf::from(format!("/sys/devices/system/cpu/cpu{}", self.id));
+        let freqs = read_cpu_freqs(&cpu_path);
+        self.min_freq = freqs.min_freq;
+        self.max_freq = freqs.max_freq;
+        self.base_freq = freqs.base_freq;
+        self.trans_lat_ns = freqs.trans_lat_ns;
+        self.governor = freqs.governor;
+        self.cpu_capacity = read_cpu_capacity(&cpu_path, capacity_src).1;
+    }
+}
+
 fn read_cpu_ids() -> Result<Vec<usize>> {
     let mut cpu_ids = vec![];
     let cpu_paths = glob("/sys/devices/system/cpu/cpu[0-9]*")?;
@@ -587,6 +1950,7 @@ fn cpu_capacity_source() -> Option<(String, usize, usize)> {
         "cpufreq/amd_pstate_highest_perf",
         "acpi_cppc/highest_perf",
         "cpu_capacity",
+        DT_CAPACITY_SRC,
         "cpufreq/cpuinfo_max_freq",
     ];
 
@@ -595,16 +1959,14 @@ fn cpu_capacity_source() -> Option<(String, usize, usize)> {
     let mut raw_capacity;
     let mut suffix = sources[sources.len() - 1];
     'outer: for src in sources {
-        let path_str = [prefix, src].join("/");
-        let path = Path::new(&path_str);
-        raw_capacity = read_from_file(&path).unwrap_or(0_usize);
+        raw_capacity = read_raw_capacity(Path::new(prefix), src).unwrap_or(0_usize);
         if raw_capacity > 0 {
             // It would be an okay source...
             suffix = src;
             // But double-check if the source has meaningful information.
             let cpu_paths = glob("/sys/devices/system/cpu/cpu[0-9]*").ok()?;
             for cpu_path in cpu_paths.filter_map(Result::ok) {
-                let raw_capacity2 = read_from_file(&cpu_path.join(suffix)).unwrap_or(0_usize);
+                let raw_capacity2 = read_raw_capacity(&cpu_path, suffix).unwrap_or(0_usize);
                 if raw_capacity != raw_capacity2 {
                     break 'outer;
                 }
@@ -622,7 +1984,7 @@ fn cpu_capacity_source() -> Option<(String, usize, usize)> {
     let mut nr_cpus = 0;
     let cpu_paths = glob("/sys/devices/system/cpu/cpu[0-9]*").ok()?;
     for cpu_path in cpu_paths.filter_map(Result::ok) {
-        let raw_capacity = read_from_file(&cpu_path.join(suffix)).unwrap_or(0_usize);
+        let raw_capacity = read_raw_capacity(&cpu_path, suffix).unwrap_or(0_usize);
         if max_raw_capacity < raw_capacity {
             max_raw_capacity = raw_capacity;
         }
@@ -640,6 +2002,124 @@ fn cpu_capacity_source() -> Option<(String, usize, usize)> {
     ))
 }
 
+/// Intel hybrid CPUs expose a separate PMU for each core type. The PMU
+/// devices list the CPUs of each type, which is more reliable than guessing
+/// from the capacity, as ITMT may report similar priorities for P-cores and
+/// E-cores.
+fn intel_hybrid_cpus() -> Option<(Cpumask, Cpumask)> {
+    let read_mask = |path: &str| -> Option<Cpumask> {
+        let cpulist = std::fs::read_to_string(path).ok()?;
+        Cpumask::from_cpulist(cpulist.trim()).ok()
+    };
+
+    let pcpus = read_mask("/sys/devices/cpu_core/cpus")?;
+    let ecpus = read_mask("/sys/devices/cpu_atom/cpus")?;
+    Some((pcpus, ecpus))
+}
+
+/// Read the effective cpuset of the cgroup directory `@cgroup`. If the
+/// cpuset controller isn't enabled for the cgroup, the closest ancestor
+/// which has it is used. Returns None if no cpuset is found.
+fn cgroup_cpuset_cpus(cgroup: &Path) -> Result<Option<Cpumask>> {
+    if !cgroup.is_dir() {
+        bail!("cgroup {:?} not found", cgroup);
+    }
+
+    for dir in cgroup.ancestors() {
+        // cgroup v2 and v1 respectively
+        for file in ["cpuset.cpus.effective", "cpuset.effective_cpus"] {
+            let path = dir.join(file);
+            if path.exists() {
+                let cpulist = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {:?}", &path))?;
+                let cpulist = cpulist.trim();
+                if cpulist.is_empty() {
+                    return Ok(Some(Cpumask::new()));
+                }
+                return Ok(Some(Cpumask::from_cpulist(cpulist)?));
+            }
+        }
+        if dir == Path::new("/sys/fs/cgroup") {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// Read the effective cpuset of the calling process's cgroup.
+fn process_cpuset_cpus() -> Result<Option<Cpumask>> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup")?;
+
+    // Look for a cgroup v1 cpuset hierarchy first, as it takes precedence
+    // over the unified hierarchy, whose entry starts with "0::", if mounted.
+    let mut lines: Vec<&str> = cgroups.lines().collect();
+    lines.sort_by_key(|line| line.starts_with("0::"));
+    for line in lines {
+        let mut fields = line.splitn(3, ':');
+        let (Some(_), Some(controllers), Some(path)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let path = path.trim_start_matches('/');
+
+        let dir = if controllers.split(',').any(|c| c == "cpuset") {
+            Path::new("/sys/fs/cgroup/cpuset").join(path)
+        } else if controllers.is_empty() {
+            let unified = Path::new("/sys/fs/cgroup/unified");
+            if unified.is_dir() {
+                unified.join(path)
+            } else {
+                Path::new("/sys/fs/cgroup").join(path)
+            }
+        } else {
+            continue;
+        };
+
+        if dir.is_dir() {
+            if let Some(cpus) = cgroup_cpuset_cpus(&dir)? {
+                return Ok(Some(cpus));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Read a cpulist from sysfs. Missing files and "(null)" which nohz_full
+/// reports when not configured are None. An empty list is an empty mask.
+/// The mask is sized by the host's NR_CPU_IDS, callers AND it with the
+/// Topology span to size it for the Topology.
+fn read_cpulist_file(path: &str) -> Option<Cpumask> {
+    let cpulist = std::fs::read_to_string(path).ok()?;
+    let cpulist = cpulist.trim();
+    match cpulist {
+        "(null)" => None,
+        "" => Some(Cpumask::new()),
+        _ => Cpumask::from_cpulist(cpulist).ok(),
+    }
+}
+
+/// Parse the cpulist of the kernel boot parameter `@param` from
+/// /proc/cmdline. Flags such as the ones in `isolcpus=nohz,domain,1-3` are
+/// skipped. For isolcpus, only domain isolation, which is the default
+/// without flags, is considered.
+fn kernel_cmdline_cpus(param: &str) -> Option<Cpumask> {
+    let cmdline = std::fs::read_to_string("/proc/cmdline").ok()?;
+    let value = cmdline
+        .split_whitespace()
+        .take_while(|arg| *arg != "--")
+        .filter_map(|arg| arg.strip_prefix(param)?.strip_prefix('='))
+        .last()?;
+
+    let (flags, cpus): (Vec<&str>, Vec<&str>) = value
+        .split(',')
+        .partition(|x| x.starts_with(|c: char| c.is_ascii_alphabetic()));
+    if param == "isolcpus" && !flags.is_empty() && !flags.contains(&"domain") {
+        return None;
+    }
+    Cpumask::from_cpulist(&cpus.join(",")).ok()
+}
+
 fn is_smt_active() -> Option<bool> {
     let smt_on: u8 = read_from_file(Path::new("/sys/devices/system/cpu/smt/active")).ok()?;
     Some(smt_on == 1)
@@ -661,6 +2141,9 @@ fn create_default_node(
         gpus: BTreeMap::new(),
         all_cores: BTreeMap::new(),
         all_cpus: BTreeMap::new(),
+        memory: NodeMemory::read(None).unwrap_or_default(),
+        memory_kind: MemoryKind::Dram,
+        memory_tier: None,
     };
 
     #[cfg(feature = "gpu-topology")]
@@ -728,6 +2211,9 @@ fn create_numa_nodes(
 
             all_cores: BTreeMap::new(),
             all_cpus: BTreeMap::new(),
+            memory: NodeMemory::read(Some(node_id)).unwrap_or_default(),
+            memory_kind: MemoryKind::Dram,
+            memory_tier: None,
 
             #[cfg(feature = "gpu-topology")]
             gpus: BTreeMap::new(),
@@ -757,6 +2243,9 @@ fn create_numa_nodes(
             cpu_ids.push(cpu_id);
         }
         cpu_ids.sort();
+        if cpu_ids.is_empty() {
+            node.memory_kind = MemoryKind::CpuLess;
+        }
 
         for cpu_id in cpu_ids {
             create_insert_cpu(
@@ -771,5 +2260,151 @@ fn create_numa_nodes(
 
         nodes.insert(node.id, node);
     }
+
+    classify_memory_tiers(&mut nodes);
     Ok(nodes)
 }
+
+/// Assign memory tiers to the nodes and classify CPU-less nodes by
+/// comparing their tier against the fastest tier of nodes with CPUs.
+fn classify_memory_tiers(nodes: &mut BTreeMap<usize, Node>) {
+    let mut node_tiers = BTreeMap::new();
+    if let Ok(paths) = glob("/sys/devices/virtual/memory_tiering/memory_tier[0-9]*") {
+        for path in paths.filter_map(Result::ok) {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let Ok(tier) = sscanf!(name, "memory_tier{usize}") else {
+                continue;
+            };
+            let Ok(nodelist) = read_from_file::<String>(&path.join("nodelist")) else {
+                continue;
+            };
+            for node in read_cpulist(&nodelist).unwrap_or_default() {
+                node_tiers.insert(node, tier);
+            }
+        }
+    }
+
+    let dram_tier = nodes
+        .values()
+        .filter(|node| node.memory_kind == MemoryKind::Dram)
+        .filter_map(|node| node_tiers.get(&node.id))
+        .min()
+        .copied();
+
+    for node in nodes.values_mut() {
+        node.memory_tier = node_tiers.get(&node.id).copied();
+        if node.memory_kind == MemoryKind::Dram {
+            continue;
+        }
+        node.memory_kind = match (node.memory_tier, dram_tier) {
+            (Some(tier), Some(dram)) if tier > dram => MemoryKind::Cxl,
+            (Some(_), Some(_)) => MemoryKind::Hbm,
+            _ => MemoryKind::CpuLess,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trip_larger_than_host() {
+        let topo = TopologyBuilder::new()
+            .nodes(2)
+            .llcs_per_node(2)
+            .cores_per_llc(*NR_CPU_IDS)
+            .smt(2)
+            .build()
+            .unwrap();
+        assert!(topo.span.weight() > *NR_CPU_IDS);
+
+        let loaded: Topology = serde_json::from_str(&topo.to_json().unwrap()).unwrap();
+        assert_eq!(loaded.span, topo.span);
+        assert_eq!(
+            loaded.all_cpus.keys().collect::<Vec<_>>(),
+            topo.all_cpus.keys().collect::<Vec<_>>()
+        );
+        for (id, llc) in topo.all_llcs.iter() {
+            assert_eq!(loaded.all_llcs[id].span, llc.span);
+        }
+        assert_eq!(loaded.smt_siblings(0), topo.smt_siblings(0));
+    }
+
+    #[test]
+    fn test_dt_path_key() {
+        let mut paths = vec![
+            "cpu-map/cluster10/core0/cpu",
+            "cpu-map/cluster2/core1/cpu",
+            "cpu-map/cluster2/core0/cpu",
+            "cpu-map/cluster0/cluster11/core0/cpu",
+            "cpu-map/cluster0/cluster9/core0/cpu",
+        ];
+        paths.sort_by_cached_key(|path| dt_path_key(Path::new(path)));
+        assert_eq!(
+            paths,
+            vec![
+                "cpu-map/cluster0/cluster9/core0/cpu",
+                "cpu-map/cluster0/cluster11/core0/cpu",
+                "cpu-map/cluster2/core0/cpu",
+                "cpu-map/cluster2/core1/cpu",
+                "cpu-map/cluster10/core0/cpu",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_refresh_memory_synthetic() {
+        let mut topo = TopologyBuilder::new().nodes(64).build().unwrap();
+        topo.refresh_memory().unwrap();
+        assert_eq!(topo.nodes[&63].memory.total, 0);
+
+        let mut loaded: Topology = serde_json::from_str(&topo.to_json().unwrap()).unwrap();
+        loaded.refresh_memory().unwrap();
+        assert_eq!(loaded.nodes[&63].memory.total, 0);
+    }
+
+    #[test]
+    fn test_sibling_cpus_larger_than_host() {
+        let topo = TopologyBuilder::new()
+            .cores_per_llc(*NR_CPU_IDS)
+            .smt(2)
+            .build()
+            .unwrap();
+        let siblings = topo.sibling_cpus();
+        let nr_cores = topo.all_cores.len();
+        assert!(siblings.len() >= nr_cores * 2);
+        assert_eq!(siblings[0], nr_cores as i32);
+        assert_eq!(siblings[nr_cores * 2 - 1], nr_cores as i32 - 1);
+    }
+
+    #[test]
+    fn test_smt_cpus_larger_than_host() {
+        let topo = TopologyBuilder::new()
+            .nodes(2)
+            .cores_per_llc(*NR_CPU_IDS)
+            .smt(2)
+            .build()
+            .unwrap();
+        let nr_cores = topo.all_cores.len();
+        assert_eq!(topo.span.weight(), nr_cores * 2);
+        assert!(topo.span.weight() > *NR_CPU_IDS);
+
+        let primary = topo.primary_smt_cpus();
+        let secondary = topo.secondary_smt_cpus();
+        assert_eq!(primary.weight(), nr_cores);
+        assert_eq!(secondary.weight(), nr_cores);
+        assert_eq!(primary.and(&secondary).weight(), 0);
+        assert_eq!(primary.or(&secondary), topo.span);
+        assert!(primary.test_cpu(nr_cores - 1));
+        assert!(secondary.test_cpu(nr_cores * 2 - 1));
+
+        let last = nr_cores * 2 - 1;
+        assert_eq!(
+            topo.smt_siblings(last).iter().collect::<Vec<_>>(),
+            vec![nr_cores - 1]
+        );
+        assert_eq!(topo.smt_siblings(last + 1).weight(), 0);
+        assert_eq!(topo.smt_siblings(last + 1).len(), topo.span.len());
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2.
+
+//! # SCX Topology Watcher
+//!
+//! Topology objects are read-only snapshots of the host and go stale when
+//! CPUs are hotplugged, e.g. when cores are offlined for power management.
+//! A TopologyWatcher polls the online CPUs in sysfs and, if they changed,
+//! builds a fresh Topology and invokes the registered callbacks with the
+//! CPUs that came online and went offline.
+//!
+//!```no_run
+//!     use scx_utils::TopologyWatcher;
+//!     let mut watcher = TopologyWatcher::new().unwrap();
+//!     watcher.add_callback(|diff, topo| {
+//!         println!("+{} -{} => {}", diff.added, diff.removed, topo.span);
+//!         Ok(())
+//!     });
+//!     loop {
+//!         watcher.poll().unwrap();
+//!         std::thread::sleep(std::time::Duration::from_secs(1));
+//!     }
+//!```
+
+use crate::topology::cpus_online;
+use crate::Cpumask;
+use crate::Topology;
+use crate::TopologyDiff;
+use anyhow::Result;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+type TopologyCallback = dyn FnMut(&TopologyDiff, &Arc<Topology>) -> Result<()> + Send;
+
+pub struct TopologyWatcher {
+    topo: Arc<Topology>,
+    /// The online CPUs as of the last poll. The Topology's span can't be
+    /// compared against as it may not match the host, e.g. if restricted.
+    online: Cpumask,
+    build_fn: fn() -> Result<Topology>,
+    callbacks: Vec<Box<TopologyCallback>>,
+    invalidate_cached: bool,
+}
+
+impl TopologyWatcher {
+    /// Create a watcher which builds Topology objects with
+    /// `Topology::new()`.
+    pub fn new() -> Result<Self> {
+        Self::with_build_fn(Topology::new)
+    }
+
+    /// Create a watcher which builds Topology objects with `@build_fn`,
+    /// e.g. `Topology::with_flattened_llc_node`.
+    pub fn with_build_fn(build_fn: fn() -> Result<Topology>) -> Result<Self> {
+        Ok(Self {
+            topo: Arc::new(build_fn()?),
+            online: cpus_online()?,
+            build_fn,
+            callbacks: vec![],
+            invalidate_cached: false,
+        })
+    }
+
+    /// The most recent Topology.
+    pub fn topology(&self) -> Arc<Topology> {
+        self.topo.clone()
+    }
+
+    /// Register `@cb` to be invoked with the diff and the refreshed
+    /// Topology whenever the online CPUs change. Callbacks are invoked in
+    /// the order they were registered and an error from any of them is
+    /// returned from `poll()`.
+    pub fn add_callback<F>(&mut self, cb: F) -> &mut Self
+    where
+        F: FnMut(&TopologyDiff, &Arc<Topology>) -> Result<()> + Send + 'static,
+    {
+        self.callbacks.push(Box::new(cb));
+        self
+    }
+
+    /// Invalidate the process-wide `Topology::cached()` instance whenever
+    /// the online CPUs change.
+    pub fn invalidate_cached(&mut self, enable: bool) -> &mut Self {
+        self.invalidate_cached = enable;
+        self
+    }
+
+    /// Check the online CPUs once. If they changed since the last poll,
+    /// refresh the Topology, invoke the callbacks and return the diff.
+    pub fn poll(&mut self) -> Result<Option<TopologyDiff>> {
+        let online = cpus_online()?;
+        if online == self.online {
+            return Ok(None);
+        }
+        self.online = online;
+
+        if self.invalidate_cached {
+            Topology::invalidate_cached();
+        }
+
+        let topo = Arc::new((self.build_fn)()?);
+        let diff = Topology::diff(&self.topo, &topo);
+        self.topo = topo;
+        if diff.is_empty() {
+            return Ok(None);
+        }
+
+        for cb in self.callbacks.iter_mut() {
+            cb(&diff, &self.topo)?;
+        }
+        Ok(Some(diff))
+    }
+
+    /// Poll every `@interval` until `@shutdown` is set.
+    pub fn watch(&mut self, interval: Duration, shutdown: &AtomicBool) -> Result<()> {
+        while !shutdown.load(Ordering::Relaxed) {
+            self.poll()?;
+            std::thread::sleep(interval);
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for TopologyWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TopologyWatcher")
+            .field("span", &self.topo.span)
+            .field("callbacks", &self.callbacks.len())
+            .finish()
+    }
+}
@@ -6,6 +6,8 @@ use crate::bindings;
 use crate::compat;
 use anyhow::bail;
 use anyhow::Result;
+use scx_stats::{journal_event, JOURNAL_MSGID_ATTACH, JOURNAL_MSGID_EXIT};
+use scx_stats::{JOURNAL_PRIO_ERR, JOURNAL_PRIO_INFO};
 use std::ffi::CStr;
 use std::os::raw::c_char;
 use std::sync::Mutex;
@@ -211,6 +213,8 @@ impl UserExitInfo {
             _ => "<UNKNOWN>".into(),
         };
 
+        self.journal(&why);
+
         if self.kind <= ScxExitKind::UnregKern as i32 {
             eprintln!("{}", why);
             Ok(())
@@ -219,6 +223,30 @@ impl UserExitInfo {
         }
     }
 
+    /// Log the exit info to the journal so that it can be correlated with
+    /// the other system logs, e.g. with `journalctl
+    /// MESSAGE_ID=f25c471b1b994d43af31e13a51be8e01`.
+    fn journal(&self, why: &str) {
+        let prio = match self.kind <= ScxExitKind::UnregKern as i32 {
+            true => JOURNAL_PRIO_INFO,
+            false => JOURNAL_PRIO_ERR,
+        };
+        let mut fields = vec![
+            ("SCX_EXIT_KIND".to_string(), self.kind.to_string()),
+            ("SCX_EXIT_CODE".to_string(), self.exit_code.to_string()),
+        ];
+        for (key, val) in [
+            ("SCX_EXIT_REASON", &self.reason),
+            ("SCX_EXIT_MSG", &self.msg),
+            ("SCX_EXIT_DUMP", &self.dump),
+        ] {
+            if let Some(val) = val {
+                fields.push((key.to_string(), val.clone()));
+            }
+        }
+        journal_event(JOURNAL_MSGID_EXIT, prio, why, &fields);
+    }
+
     /// Return the exit code that the scheduler gracefully exited with. This
     /// only applies when the BPF scheduler exits with scx_bpf_exit(), i.e. kind
     /// ScxExitKind::UnregBPF.
@@ -238,3 +266,15 @@ impl UserExitInfo {
         }
     }
 }
+
+/// Log the attachment of the struct_ops `ops` to the journal. Called by
+/// scx_ops_attach!().
+pub fn journal_attach(ops: &str) {
+    let fields = vec![("SCX_OPS".to_string(), ops.to_string())];
+    journal_event(
+        JOURNAL_MSGID_ATTACH,
+        JOURNAL_PRIO_INFO,
+        &format!("sched_ext scheduler {} attached", ops),
+        &fields,
+    );
+}
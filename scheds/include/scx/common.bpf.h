@@ -39,6 +39,21 @@
 #define NR_CPUS 1024
 #endif
 
+/*
+ * SCX_BPF_DEBUG is defined by BpfBuilder when building with the debug
+ * profile. scx_dbg_printk() is compiled out otherwise while still having
+ * its arguments type-checked.
+ */
+#ifndef SCX_BPF_DEBUG
+#define SCX_BPF_DEBUG 0
+#endif
+
+#define scx_dbg_printk(fmt, args...)						\
+	do {									\
+		if (SCX_BPF_DEBUG)						\
+			bpf_printk(fmt, ##args);				\
+	} while (0)
+
 #ifndef NUMA_NO_NODE
 #define	NUMA_NO_NODE	(-1)
 #endif
@@ -33,9 +33,17 @@ enum consts {
 	MAX_LLCS		= 64,
 	MAX_COMM		= 16,
 	MAX_LAYER_MATCH_ORS	= 32,
+	MAX_MATCH_TGIDS		= 16,
+	MAX_ANCESTORS		= 16,
+	MAX_CGROUP_REGEXES	= 64,
+	MAX_CGROUP_REGEX_CGRPS	= 16384,
 	/* 64 chars for user-provided name, 64 for possible template suffix. */
 	MAX_LAYER_NAME		= 128,
 	MAX_LAYERS		= 16,
+	/* layer->qos_tier must be lower, preempting per-cpu kthreads run at it */
+	MAX_QOS_TIERS		= 16,
+	/* lat_hist bucket N counts latencies below 2^N usecs, the last one the rest */
+	NR_LAT_BUCKETS		= 24,
 	MAX_LAYER_WEIGHT	= 10000,
 	MIN_LAYER_WEIGHT	= 1,
 	DEFAULT_LAYER_WEIGHT	= 100,
@@ -105,6 +113,7 @@ enum global_stat_id {
 	GSTAT_ANTISTALL,
 	GSTAT_SKIP_PREEMPT,
 	GSTAT_FIXUP_VTIME,
+	GSTAT_ASSIGN_DROPPED,
 	NR_GSTATS,
 };
 
@@ -127,6 +136,8 @@ enum layer_stat_id {
 	LSTAT_PREEMPT_XNUMA,
 	LSTAT_PREEMPT_IDLE,
 	LSTAT_PREEMPT_FAIL,
+	LSTAT_PREEMPT_RECV,
+	LSTAT_PREEMPT_BUDGET,
 	LSTAT_EXCL_COLLISION,
 	LSTAT_EXCL_PREEMPT,
 	LSTAT_YIELD,
@@ -140,9 +151,24 @@ enum layer_stat_id {
 	LSTAT_LLC_DRAIN_TRY,
 	LSTAT_LLC_DRAIN,
 	LSTAT_SKIP_REMOTE_NODE,
+	LSTAT_ANTI_AFFN_KICK,
+	LSTAT_SLO_MET,
+	LSTAT_SLO_MISS,
+	/* must be in the same order as layer_perf_cnt_id */
+	LSTAT_PERF_CYCLES,
+	LSTAT_PERF_INSNS,
+	LSTAT_PERF_LLC_MISSES,
 	NR_LSTATS,
 };
 
+/* Hardware counters collected with --enable-perf-counters */
+enum layer_perf_cnt_id {
+	LPERF_CYCLES,
+	LPERF_INSNS,
+	LPERF_LLC_MISSES,
+	NR_LPERFS,
+};
+
 enum llc_layer_stat_id {
 	LLC_LSTAT_LAT,
 	LLC_LSTAT_CNT,
@@ -161,6 +187,7 @@ struct cpu_prox_map {
 struct cpu_ctx {
 	s32			cpu;
 	bool			current_preempt;
+	u32			current_qos_tier;
 	bool			current_excl;
 	bool			prev_excl;
 	bool			next_excl;
@@ -175,10 +202,16 @@ struct cpu_ctx {
 	bool			running_fallback;
 	u64			used_at;
 	bool			is_protected;
+	/* owned by a confidential layer, see confidential_cpumask */
+	bool			is_confidential;
+	/* since when the tasks of the layer have been kept off by anti-affinity */
+	u64			anti_affn_wait_at;
+	u32			anti_affn_wait_layer_id;
 
 	u64			layer_usages[MAX_LAYERS][NR_LAYER_USAGES];
 	u64			gstats[NR_GSTATS];
 	u64			lstats[MAX_LAYERS][NR_LSTATS];
+	u64			lat_hist[MAX_LAYERS][NR_LAT_BUCKETS];
 	u64			ran_current_for;
 
 	u64			usage;
@@ -189,14 +222,34 @@ struct cpu_ctx {
 	bool			in_open_layers;
 	u32			layer_id;
 	u32			task_layer_id;
+	/* when the current task started running, for anti-affinity conflicts */
+	u64			task_running_at;
 	u32			llc_id;
 	u32			node_id;
 	u32			perf;
+	/* cpuperf target to go back to after a perf_min/max clamped task */
+	u32			perf_restore;
+	bool			perf_clamped;
+	/* last read values of the layer_perf_events counters */
+	u64			perf_cnts[NR_LPERFS];
 
 	u64			lo_fb_seq;
 	u64			lo_fb_seq_at;
 	u64			lo_fb_usage_base;
 
+	struct cpu_prox_map	prox_map;
+};
+
+/*
+ * The order in which a CPU consumes the layers. Owned by userspace, which
+ * rewrites it whenever the layer specs are reloaded.
+ */
+struct cpu_layer_order {
+	u32			nr_op_layers;	/* open && preempt */
+	u32			nr_on_layers;	/* open && !preempt */
+	u32			nr_gp_layers;	/* grouped && preempt */
+	u32			nr_gn_layers;	/* grouped && !preempt */
+
 	u32			ogp_layer_order[MAX_LAYERS];	/* open/grouped preempt */
 	u32			ogn_layer_order[MAX_LAYERS];	/* open/grouped non-preempt */
 
@@ -204,8 +257,6 @@ struct cpu_ctx {
 	u32			on_layer_order[MAX_LAYERS];	/* open non-preempt */
 	u32			gp_layer_order[MAX_LAYERS];	/* grouped preempt */
 	u32			gn_layer_order[MAX_LAYERS];	/* grouped non-preempt */
-
-	struct cpu_prox_map	prox_map;
 };
 
 struct llc_prox_map {
@@ -255,6 +306,11 @@ enum layer_match_kind {
 	MATCH_AVG_RUNTIME,
 	MATCH_CGROUP_SUFFIX,
 	MATCH_CGROUP_CONTAINS,
+	MATCH_COMM_GLOB,
+	MATCH_PCOMM_GLOB,
+	MATCH_CGROUP_REGEX,
+	MATCH_TGID_IN,
+	MATCH_ANCESTOR_COMM_PREFIX,
 
 	NR_LAYER_MATCH_KINDS,
 };
@@ -272,6 +328,9 @@ struct layer_match {
 	u32		pid;
 	u32		ppid;
 	u32		tgid;
+	u32		tgids[MAX_MATCH_TGIDS];
+	u32		nr_tgids;
+	u32		cgroup_regex_id;
 	u64		nsid;
 	bool		is_group_leader;
 	bool		is_kthread;
@@ -304,6 +363,7 @@ enum layer_growth_algo {
 	GROWTH_ALGO_CPUSET_SPREAD_RANDOM,
 	GROWTH_ALGO_RANDOM_TOPO,
 	GROWTH_ALGO_STICKY_DYNAMIC,
+	GROWTH_ALGO_COMPACT,
 };
 
 enum layer_task_place {
@@ -329,11 +389,16 @@ struct layer {
 	int			kind;
 	bool			preempt;
 	bool			preempt_first;
+	u32			qos_tier;
+	/* preemptions of busy CPUs left, refilled by userspace */
+	bool			preempt_budgeted;
+	s64			preempt_budget_left;
 	bool			excl;
 	bool			allow_node_aligned;
 	bool			skip_remote_node;
 	bool			prev_over_idle_core;
 	int			growth_algo;
+	u64			anti_affine_layers;	/* bitmap of layer IDs */
 
 	u64			nr_tasks;
 
@@ -342,6 +407,8 @@ struct layer {
 	u64			llc_mask;
 	bool			check_no_idle;
 	u32			perf;
+	u32			perf_min;
+	u32			perf_max;
 	u64			refresh_cpus;
 	u8			cpus[MAX_CPUS_U8];
 
@@ -354,8 +421,27 @@ struct layer {
 
 	char			name[MAX_LAYER_NAME];
 	bool			is_protected;
+	bool			confidential;
 	bool			periodically_refresh;
 	u8			cpuset[MAX_CPUS_U8];
+
+	/* latency SLO, 0 if none */
+	u64			slo_lat_ns;
+	bool			slo_wakeup_only;
+};
+
+/* task_assign_event->match_idx if the layer wasn't picked by its matches */
+enum task_assign_match {
+	ASSIGN_MATCH_OVERRIDE	= -1,	/* put there through the control API */
+};
+
+struct task_assign_event {
+	u32			pid;
+	u32			tgid;
+	u32			layer_id;
+	u32			prev_layer_id;	/* MAX_LAYERS if none */
+	s32			match_idx;	/* index into the layer's matches */
+	char			comm[MAX_COMM];
 };
 
 struct scx_cmd {
@@ -33,7 +33,6 @@ const volatile u32 nr_possible_cpus = 1;
 const volatile u64 numa_cpumasks[MAX_NUMA_NODES][MAX_CPUS / 64];
 const volatile u32 llc_numa_id_map[MAX_LLCS];
 const volatile u32 cpu_llc_id_map[MAX_CPUS];
-const volatile u32 nr_layers = 1;
 const volatile u32 nr_nodes = 32;	/* !0 for veristat, set during init */
 const volatile u32 nr_llcs = 32;	/* !0 for veristat, set during init */
 const volatile bool smt_enabled = true;
@@ -42,24 +41,35 @@ const volatile bool xnuma_preemption = false;
 const volatile s32 __sibling_cpu[MAX_CPUS];
 const volatile bool monitor_disable = false;
 const volatile unsigned char all_cpus[MAX_CPUS_U8];
-const volatile u32 layer_iteration_order[MAX_LAYERS];
-const volatile u32 nr_op_layers;	/* open && preempt */
-const volatile u32 nr_on_layers;	/* open && !preempt */
-const volatile u32 nr_gp_layers;	/* grouped && preempt */
-const volatile u32 nr_gn_layers;	/* grouped && !preempt */
-const volatile u32 nr_excl_layers;
-const volatile u64 min_open_layer_disallow_open_after_ns;
-const volatile u64 min_open_layer_disallow_preempt_after_ns;
 const volatile u64 lo_fb_wait_ns = 5000000;	/* !0 for veristat */
 const volatile u32 lo_fb_share_ppk = 128;	/* !0 for veristat */
 const volatile bool percpu_kthread_preempt = true;
 const volatile bool percpu_kthread_preempt_all = false;
+/*
+ * The layers can be added and removed on spec reloads. All MAX_LAYERS slots are
+ * initialized upfront and userspace updates the following along with the
+ * layers and the per-CPU cpu_layer_orders.
+ */
+volatile u32 nr_layers = 1;
+volatile u32 layer_iteration_order[MAX_LAYERS];
+volatile u32 nr_excl_layers;
+volatile u64 min_open_layer_disallow_open_after_ns;
+volatile u64 min_open_layer_disallow_preempt_after_ns;
+
 volatile u64 layer_refresh_seq_avgruntime;
+volatile u64 layer_spec_seq;
+/* bumped when cgroups are created, removed or renamed, see tp_cgroup_mkdir() */
+volatile u64 cgroup_gen;
+/* set while there are subscribers to the task_assign_events or mempolicy layers */
+volatile bool emit_assign_events;
+/* bumped on spec reloads and event losses to re-emit all task_assign_events */
+u64 assign_events_seq;
 
 /* Flag to enable or disable antistall feature */
 const volatile bool enable_antistall = true;
 const volatile bool enable_match_debug = false;
 const volatile bool enable_gpu_support = false;
+const volatile bool enable_perf_counters = false;
 /* Delay permitted, in seconds, before antistall activates */
 const volatile u64 antistall_sec = 3;
 const u32 zero_u32 = 0;
@@ -74,6 +84,12 @@ const volatile u64 idle_sched_class_addr = 0;
 private(unprotected_cpumask) struct bpf_cpumask __kptr *unprotected_cpumask;
 u64 unprotected_seq = 0;
 
+/*
+ * CPUs owned by confidential layers. Tasks of other layers run on them only if
+ * they can run nowhere else, e.g. per-CPU kthreads.
+ */
+private(confidential_cpumask) struct bpf_cpumask __kptr *confidential_cpumask;
+
 private(all_cpumask) struct bpf_cpumask __kptr *all_cpumask;
 private(big_cpumask) struct bpf_cpumask __kptr *big_cpumask;
 struct layer layers[MAX_LAYERS];
@@ -128,9 +144,14 @@ static inline s32 sibling_cpu(s32 cpu)
 		return -1;
 }
 
+/*
+ * Tasks may still be in a layer slot at or above nr_layers right after a spec
+ * reload removed layers, until they re-match. Such slots keep their last
+ * configuration and are valid to look up.
+ */
 static __always_inline struct layer *lookup_layer(u32 id)
 {
-	if (id >= nr_layers) {
+	if (id >= MAX_LAYERS) {
 		scx_bpf_error("invalid layer %d", id);
 		return NULL;
 	}
@@ -191,6 +212,13 @@ struct {
 	__uint(max_entries, 1);
 } cpu_ctxs SEC(".maps");
 
+struct {
+	__uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+	__type(key, u32);
+	__type(value, struct cpu_layer_order);
+	__uint(max_entries, 1);
+} cpu_layer_orders SEC(".maps");
+
 static struct cpu_ctx *lookup_cpu_ctx(int cpu)
 {
 	struct cpu_ctx *cpuc;
@@ -208,6 +236,23 @@ static struct cpu_ctx *lookup_cpu_ctx(int cpu)
 	return cpuc;
 }
 
+static struct cpu_layer_order *lookup_cpu_layer_order(int cpu)
+{
+	struct cpu_layer_order *order;
+
+	if (cpu < 0)
+		order = bpf_map_lookup_elem(&cpu_layer_orders, &zero_u32);
+	else
+		order = bpf_map_lookup_percpu_elem(&cpu_layer_orders, &zero_u32, cpu);
+
+	if (!order) {
+		scx_bpf_error("no cpu_layer_order for cpu %d", cpu);
+		return NULL;
+	}
+
+	return order;
+}
+
 static bool cpuc_in_layer(struct cpu_ctx *cpuc, struct layer *layer)
 {
 	if (layer->kind == LAYER_KIND_OPEN)
@@ -224,6 +269,23 @@ struct {
 	__uint(map_flags, BPF_F_NO_PREALLOC);
 } layer_match_dbg SEC(".maps");
 
+/*
+ * Layer assignments made through the control API, keyed by tid or tgid. These
+ * take precedence over the layer matches.
+ */
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__type(key, u32);
+	__type(value, u32);
+	__uint(max_entries, MAX_TASKS);
+	__uint(map_flags, BPF_F_NO_PREALLOC);
+} layer_pid_overrides SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_RINGBUF);
+	__uint(max_entries, 1024 * 1024 /* 1 MB, for the initial assignments */);
+} task_assign_events SEC(".maps");
+
 struct {
 	__uint(type, BPF_MAP_TYPE_HASH);
 	__type(key, u32);
@@ -232,6 +294,19 @@ struct {
 	__uint(map_flags, BPF_F_NO_PREALLOC);
 } gpu_tgid SEC(".maps");
 
+/*
+ * Regexes can't be evaluated in BPF. Userspace matches the cgroup paths
+ * against the CgroupRegex rules and maps the matching cgroup IDs to the
+ * bitmap of the cgroup_regex_id's that they match.
+ */
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__type(key, u64);
+	__type(value, u64);
+	__uint(max_entries, MAX_CGROUP_REGEX_CGRPS);
+	__uint(map_flags, BPF_F_NO_PREALLOC);
+} cgroup_regex_matches SEC(".maps");
+
 struct {
 	__uint(type, BPF_MAP_TYPE_HASH);
 	__type(key, u32);
@@ -340,6 +415,38 @@ static void lstat_inc(u32 id, struct layer *layer, struct cpu_ctx *cpuc)
 	lstat_add(id, layer, cpuc, 1);
 }
 
+/*
+ * Record how long a task waited between becoming runnable and running in
+ * the layer's latency histogram and account it against the layer's SLO.
+ */
+static void account_lat(struct layer *layer, struct cpu_ctx *cpuc, u64 lat_ns,
+			bool wakeup)
+{
+	u64 lat_us = lat_ns / NSEC_PER_USEC;
+	u32 b, bucket = NR_LAT_BUCKETS - 1;
+	u64 *vptr;
+
+	if (layer->slo_wakeup_only && !wakeup)
+		return;
+
+	bpf_for(b, 0, NR_LAT_BUCKETS - 1) {
+		if (lat_us < (1LLU << b)) {
+			bucket = b;
+			break;
+		}
+	}
+
+	if ((vptr = MEMBER_VPTR(*cpuc, .lat_hist[layer->id][bucket])))
+		(*vptr)++;
+
+	if (layer->slo_lat_ns) {
+		if (lat_ns <= layer->slo_lat_ns)
+			lstat_inc(LSTAT_SLO_MET, layer, cpuc);
+		else
+			lstat_inc(LSTAT_SLO_MISS, layer, cpuc);
+	}
+}
+
 struct layer_cpumask_wrapper {
 	struct bpf_cpumask __kptr *cpumask;
 	struct bpf_cpumask __kptr *cpuset;
@@ -387,11 +494,29 @@ static void layer_llc_drain_disable(struct layer *layer, u32 llc_id)
 	__sync_and_and_fetch(&layer->llcs_to_drain, ~(1LLU << llc_id));
 }
 
+static void cpuc_set_confidential(struct cpu_ctx *cpuc, bool confidential)
+{
+	if (cpuc->is_confidential == confidential)
+		return;
+
+	if (unlikely(!confidential_cpumask)) {
+		scx_bpf_error("confidential_cpumask not initialized");
+		return;
+	}
+
+	cpuc->is_confidential = confidential;
+	if (confidential)
+		bpf_cpumask_set_cpu(cpuc->cpu, confidential_cpumask);
+	else
+		bpf_cpumask_clear_cpu(cpuc->cpu, confidential_cpumask);
+}
+
 static inline bool refresh_layer_cpuc(struct cpu_ctx *cpuc, struct layer *layer)
 {
 	/* a CPU can be shared by multiple open layers */
 	cpuc->in_open_layers = (layer->kind == LAYER_KIND_OPEN);
 	cpuc->layer_id = (layer->kind == LAYER_KIND_OPEN) ? MAX_LAYERS : layer->id;
+	cpuc_set_confidential(cpuc, layer->confidential);
 
 	if (cpuc->is_protected == layer->is_protected)
 		return false;
@@ -491,8 +616,11 @@ int refresh_cpumasks(u32 layer_id)
 			} else {
 				if (layer->kind == LAYER_KIND_OPEN)
 					cpuc->in_open_layers = false;
-				else if (cpuc->layer_id == layer_id)
+				/* the layer may have just turned open */
+				if (cpuc->layer_id == layer_id) {
 					cpuc->layer_id = MAX_LAYERS;
+					cpuc_set_confidential(cpuc, false);
+				}
 				bpf_cpumask_clear_cpu(cpu, layer_cpumask);
 			}
 		} else {
@@ -531,6 +659,67 @@ int refresh_cpumasks(u32 layer_id)
 	return 0;
 }
 
+/*
+ * Retire the layer slots at and above nr_layers after a spec reload removed
+ * layers. The CPUs they owned are released and their queued tasks are moved to
+ * the hi fallback DSQs where they run once and re-match.
+ */
+static void retire_layers(void)
+{
+	struct cpu_layer_order *order;
+	struct task_struct *p;
+	struct cpu_ctx *cpuc;
+	u64 dsq_id;
+	u32 id, llc_id;
+	s32 cpu;
+
+	bpf_for(cpu, 0, nr_possible_cpus) {
+		if (!(cpuc = lookup_cpu_ctx(cpu)) ||
+		    !(order = lookup_cpu_layer_order(cpu)))
+			return;
+
+		if (cpuc->in_open_layers && !order->nr_op_layers && !order->nr_on_layers)
+			cpuc->in_open_layers = false;
+		if (cpuc->layer_id < MAX_LAYERS && cpuc->layer_id >= nr_layers) {
+			cpuc->layer_id = MAX_LAYERS;
+			cpuc_set_confidential(cpuc, false);
+		}
+	}
+
+	bpf_for(id, nr_layers, MAX_LAYERS) {
+		bpf_for(llc_id, 0, nr_llcs) {
+			dsq_id = layer_dsq_id(id, llc_id);
+			if (!scx_bpf_dsq_nr_queued(dsq_id))
+				continue;
+
+			bpf_for_each(scx_dsq, p, dsq_id, 0)
+				__COMPAT_scx_bpf_dsq_move(BPF_FOR_EACH_ITER, p,
+							  hi_fb_dsq_id(llc_id), 0);
+		}
+	}
+}
+
+/*
+ * Applies layer configurations rewritten by userspace on spec reload, this is
+ * called via BPF_PROG_RUN from userspace. Tasks re-match on their next wakeup
+ * or enqueue.
+ */
+SEC("syscall")
+int BPF_PROG(reload_layers)
+{
+	u32 id;
+
+	bpf_for(id, 0, nr_layers)
+		layer_cpuset_bpfmask(id);
+
+	__sync_fetch_and_add(&layer_spec_seq, 1);
+	__sync_fetch_and_add(&assign_events_seq, 1);
+
+	/* after the seq bump so that the tasks aren't queued back */
+	retire_layers();
+	return 0;
+}
+
 /*
  * Refreshes all layer cpumasks, this is called via BPF_PROG_RUN from userspace.
  */
@@ -571,6 +760,9 @@ struct task_ctx {
 	bool			cpus_node_aligned;
 	u64			runnable_at;
 	u64			running_at;
+	/* start of the current wait for a CPU, for lat_hist */
+	u64			lat_start_at;
+	bool			lat_wakeup;
 	u64			runtime_avg;
 	u64			dsq_id;
 	u32			llc_id;
@@ -579,8 +771,13 @@ struct task_ctx {
 	u32			qrt_layer_id;
 	u32			qrt_llc_id;
 
+	/* index of the layer match which picked layer_id */
+	s32			match_idx;
+	u64			assign_events_seq;
+
 	char 			join_layer[SCXCMD_COMLEN];
 	u64			layer_refresh_seq;
+	u64			layer_spec_seq;
 };
 
 struct {
@@ -593,6 +790,8 @@ struct {
 
 static void refresh_cpus_flags(struct task_ctx *taskc,
 			       const struct cpumask *cpumask);
+static void maybe_refresh_layer(struct task_struct *p __arg_trusted,
+				struct task_ctx *taskc);
 
 static struct task_ctx *lookup_task_ctx_may_fail(struct task_struct *p)
 {
@@ -697,6 +896,88 @@ static int handle_cmd(struct task_ctx *taskc, struct scx_cmd *cmd)
 }
 
 
+/*
+ * Per-CPU hardware counters opened by userspace, indexed by
+ * cpu * NR_LPERFS + layer_perf_cnt_id.
+ */
+struct {
+	__uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
+	__uint(key_size, sizeof(u32));
+	__uint(value_size, sizeof(u32));
+	__uint(max_entries, MAX_CPUS * NR_LPERFS);
+} layer_perf_events SEC(".maps");
+
+/*
+ * Attribute the hardware counter deltas since the last context switch on this
+ * CPU to the layer of the task which is switching out. This would belong in
+ * ops.running() and ops.stopping() but struct_ops programs can't read perf
+ * events, so hook sched_switch which brackets the same execution periods.
+ * Tasks outside of sched_ext, including the idle task, only advance the
+ * snapshots.
+ */
+SEC("tp_btf/sched_switch")
+int BPF_PROG(tp_sched_switch, bool preempt, struct task_struct *prev,
+	     struct task_struct *next)
+{
+	struct bpf_perf_event_value val;
+	struct layer *layer = NULL;
+	struct task_ctx *taskc;
+	struct cpu_ctx *cpuc;
+	u32 cpu, i;
+
+	if (!enable_perf_counters || !(cpuc = lookup_cpu_ctx(-1)))
+		return 0;
+
+	if ((taskc = lookup_task_ctx_may_fail(prev)) && taskc->layer_id < MAX_LAYERS)
+		layer = &layers[taskc->layer_id];
+
+	cpu = bpf_get_smp_processor_id();
+
+	bpf_for(i, 0, NR_LPERFS) {
+		u64 *cntp;
+
+		if (bpf_perf_event_read_value(&layer_perf_events,
+					      cpu * NR_LPERFS + i,
+					      &val, sizeof(val)))
+			continue;
+		if (!(cntp = MEMBER_VPTR(*cpuc, .perf_cnts[i])))
+			break;
+		/* the first read on the CPU only establishes the baseline */
+		if (layer && *cntp && val.counter > *cntp)
+			lstat_add(LSTAT_PERF_CYCLES + i, layer, cpuc,
+				  val.counter - *cntp);
+		*cntp = val.counter;
+	}
+
+	return 0;
+}
+
+/*
+ * Userspace matches the cgroup paths against the CgroupRegex rules. Let it
+ * know when the hierarchy changed so that it doesn't have to walk it on
+ * every interval.
+ */
+SEC("tp_btf/cgroup_mkdir")
+int BPF_PROG(tp_cgroup_mkdir, struct cgroup *cgrp, const char *cgrp_path)
+{
+	__sync_fetch_and_add(&cgroup_gen, 1);
+	return 0;
+}
+
+SEC("tp_btf/cgroup_rmdir")
+int BPF_PROG(tp_cgroup_rmdir, struct cgroup *cgrp, const char *cgrp_path)
+{
+	__sync_fetch_and_add(&cgroup_gen, 1);
+	return 0;
+}
+
+SEC("tp_btf/cgroup_rename")
+int BPF_PROG(tp_cgroup_rename, struct cgroup *cgrp, const char *cgrp_path)
+{
+	__sync_fetch_and_add(&cgroup_gen, 1);
+	return 0;
+}
+
 SEC("tp_btf/task_rename")
 int BPF_PROG(tp_task_rename, struct task_struct *p, const char *buf)
 {
@@ -836,6 +1117,62 @@ static void maybe_refresh_layered_cpus_unprotected(struct task_struct *p, struct
 	}
 }
 
+/*
+ * Whether @cpu is owned by a confidential layer other than @layer, in which
+ * case @layer's tasks must stay off it.
+ */
+static __always_inline bool cpu_foreign_confidential(s32 cpu, const struct layer *layer)
+{
+	struct cpu_ctx *cpuc;
+
+	return (cpuc = lookup_cpu_ctx(cpu)) && cpuc->is_confidential &&
+		cpuc->layer_id != layer->id;
+}
+
+/*
+ * Whether @layer's tasks must stay off @cpu because its SMT sibling is running
+ * a task of an anti-affine layer.
+ */
+static __always_inline bool sib_anti_affine(s32 cpu, const struct layer *layer)
+{
+	struct cpu_ctx *sib_cpuc;
+	u32 sib_layer_id;
+	s32 sib;
+
+	if (!layer->anti_affine_layers || (sib = sibling_cpu(cpu)) < 0 ||
+	    !(sib_cpuc = lookup_cpu_ctx(sib)))
+		return false;
+
+	sib_layer_id = READ_ONCE(sib_cpuc->task_layer_id);
+	return sib_layer_id < MAX_LAYERS &&
+		(layer->anti_affine_layers & (1LLU << sib_layer_id));
+}
+
+/*
+ * Whether @layer's tasks should yield @cpu as the SMT sibling has been kept
+ * from running the tasks of an anti-affine layer for longer than @layer's
+ * slice. This bounds how long e.g. tasks pinned to the sibling wait next to
+ * an anti-affine CPU hog.
+ */
+static __always_inline bool sib_anti_affn_starved(s32 cpu, const struct layer *layer,
+						  u64 now)
+{
+	struct cpu_ctx *sib_cpuc;
+	u32 wait_layer_id;
+	u64 wait_at;
+	s32 sib;
+
+	if (!layer->anti_affine_layers || (sib = sibling_cpu(cpu)) < 0 ||
+	    !(sib_cpuc = lookup_cpu_ctx(sib)))
+		return false;
+
+	wait_at = READ_ONCE(sib_cpuc->anti_affn_wait_at);
+	wait_layer_id = READ_ONCE(sib_cpuc->anti_affn_wait_layer_id);
+	return wait_at && time_after(now, wait_at + layer->slice_ns) &&
+		wait_layer_id < MAX_LAYERS &&
+		(layer->anti_affine_layers & (1LLU << wait_layer_id));
+}
+
 static s32 pick_idle_cpu_from(const struct cpumask *cand_cpumask, s32 prev_cpu,
 			      const struct cpumask *idle_smtmask, const struct layer *layer)
 {
@@ -845,7 +1182,8 @@ static s32 pick_idle_cpu_from(const struct cpumask *cand_cpumask, s32 prev_cpu,
 	if (unlikely(!cand_cpumask || !idle_smtmask))
 		return -1;
 
-	prev_in_cand = bpf_cpumask_test_cpu(prev_cpu, cand_cpumask);
+	prev_in_cand = bpf_cpumask_test_cpu(prev_cpu, cand_cpumask) &&
+		!sib_anti_affine(prev_cpu, layer);
 
 	/*
 	 * If CPU has SMT, any wholly idle CPU is likely a better pick than
@@ -890,7 +1228,11 @@ static s32 pick_idle_cpu_from(const struct cpumask *cand_cpumask, s32 prev_cpu,
 				break;
 		}
 
-		// continue the search if the sibling is exclusive
+		// continue the search if the sibling is exclusive or anti-affine
+		if (sib_anti_affine(cpu, layer)) {
+			cpu = -1;
+			continue;
+		}
 		if (!nr_excl_layers ||
 		    (sib = sibling_cpu(cpu)) < 0 || !(sib_cpuc = lookup_cpu_ctx(sib)) ||
 		    (!sib_cpuc->current_excl && !sib_cpuc->next_excl))
@@ -900,6 +1242,19 @@ static s32 pick_idle_cpu_from(const struct cpumask *cand_cpumask, s32 prev_cpu,
 	return cpu;
 }
 
+/*
+ * Tasks of preempting layers and preempting per-cpu kthreads can only be
+ * preempted by layers in higher QoS tiers. Other tasks can also be preempted
+ * by layers in the same tier.
+ */
+static __always_inline
+bool can_preempt_current(struct layer *layer, struct cpu_ctx *cand_cpuc)
+{
+	if (cand_cpuc->current_preempt)
+		return layer->qos_tier > cand_cpuc->current_qos_tier;
+	return layer->qos_tier >= cand_cpuc->current_qos_tier;
+}
+
 static __always_inline
 bool should_try_preempt_first(s32 cand, struct layer *layer,
 			      const struct cpumask *layered_cpumask)
@@ -914,7 +1269,7 @@ bool should_try_preempt_first(s32 cand, struct layer *layer,
 	    !bpf_cpumask_test_cpu(cand, layered_cpumask))
 		return false;
 
-	if (!(cand_cpuc = lookup_cpu_ctx(cand)) || cand_cpuc->current_preempt)
+	if (!(cand_cpuc = lookup_cpu_ctx(cand)) || !can_preempt_current(layer, cand_cpuc))
 		return false;
 
 	if (nr_excl_layers && layer->excl && (sib = sibling_cpu(cand)) >= 0 &&
@@ -1196,7 +1551,8 @@ s32 BPF_STRUCT_OPS(layered_select_cpu, struct task_struct *p, s32 prev_cpu, u64
 	if (taskc->layer_id == MAX_LAYERS || !(layer = lookup_layer(taskc->layer_id)))
 		return prev_cpu;
 
-	if (layer->task_place == PLACEMENT_STICK)
+	if (layer->task_place == PLACEMENT_STICK &&
+	    !cpu_foreign_confidential(prev_cpu, layer))
 		cpu = prev_cpu;
 	else
 		cpu = pick_idle_cpu(p, prev_cpu, cpuc, taskc, layer, true);
@@ -1229,6 +1585,8 @@ static bool try_preempt_cpu(s32 cand, struct task_struct *p, struct task_ctx *ta
 	struct rq *rq;
 	struct task_struct *curr;
 	const struct cpumask *idle_cpumask;
+	u32 victim_layer_id;
+	bool cand_idle;
 	s32 sib;
 
 	if (cand >= nr_possible_cpus || !bpf_cpumask_test_cpu(cand, p->cpus_ptr))
@@ -1237,7 +1595,14 @@ static bool try_preempt_cpu(s32 cand, struct task_struct *p, struct task_ctx *ta
 	if (!(cand_cpuc = lookup_cpu_ctx(cand)))
 		return false;
 
-	if (cand_cpuc->current_preempt)
+	if (!can_preempt_current(layer, cand_cpuc))
+		return false;
+
+	/* CPUs of confidential layers can't be taken over by other layers */
+	if (cand_cpuc->is_confidential && cand_cpuc->layer_id != layer->id)
+		return false;
+
+	if (sib_anti_affine(cand, layer))
 		return false;
 
 	rq = scx_bpf_cpu_rq(cand);
@@ -1277,6 +1642,26 @@ static bool try_preempt_cpu(s32 cand, struct task_struct *p, struct task_ctx *ta
 		return false;
 	}
 
+	/*
+	 * Only preemptions of busy CPUs are charged against the budget. The
+	 * budget can go negative due to racing preemptions, which is fine as
+	 * userspace refills it every interval.
+	 */
+	if (layer->preempt_budgeted) {
+		idle_cpumask = scx_bpf_get_idle_cpumask();
+		cand_idle = bpf_cpumask_test_cpu(cand, idle_cpumask);
+		scx_bpf_put_idle_cpumask(idle_cpumask);
+
+		if (!cand_idle &&
+		    __sync_fetch_and_sub(&layer->preempt_budget_left, 1) <= 0) {
+			if ((cpuc = lookup_cpu_ctx(-1)))
+				lstat_inc(LSTAT_PREEMPT_BUDGET, layer, cpuc);
+			return false;
+		}
+	}
+
+	victim_layer_id = cand_cpuc->task_layer_id;
+
 	/* preempt */
 	taskc->dsq_id = SCX_DSQ_LOCAL_ON | cand;
 	scx_bpf_dsq_insert(p, taskc->dsq_id, layer->slice_ns, SCX_ENQ_PREEMPT);
@@ -1309,6 +1694,8 @@ static bool try_preempt_cpu(s32 cand, struct task_struct *p, struct task_ctx *ta
 		lstat_inc(LSTAT_PREEMPT, layer, cpuc);
 		if (flags & PREEMPT_FIRST)
 			lstat_inc(LSTAT_PREEMPT_FIRST, layer, cpuc);
+		if (victim_layer_id < MAX_LAYERS)
+			lstat_inc(LSTAT_PREEMPT_RECV, &layers[victim_layer_id], cpuc);
 	} else {
 		lstat_inc(LSTAT_PREEMPT_IDLE, layer, cpuc);
 	}
@@ -1345,6 +1732,28 @@ static void layer_kick_idle_cpu(struct layer *layer)
 	scx_bpf_put_idle_cpumask(idle_smtmask);
 }
 
+/*
+ * Confidential CPUs don't run the fallback DSQs, see layered_dispatch(). If @p
+ * can only run on confidential CPUs, e.g. a kthread bound to them or a task
+ * affined to a confidential layer's CPUs, return the local DSQ of one of them
+ * instead of @fb_dsq_id so that @p doesn't stall.
+ */
+static u64 fb_dsq_id_for(struct task_struct *p, s32 task_cpu, u64 fb_dsq_id)
+{
+	s32 cpu;
+
+	if (unlikely(!confidential_cpumask) ||
+	    !bpf_cpumask_subset(p->cpus_ptr, cast_mask(confidential_cpumask)))
+		return fb_dsq_id;
+
+	if (bpf_cpumask_test_cpu(task_cpu, p->cpus_ptr))
+		cpu = task_cpu;
+	else if ((cpu = bpf_cpumask_any_distribute(p->cpus_ptr)) >= nr_cpu_ids)
+		return fb_dsq_id;
+
+	return SCX_DSQ_LOCAL_ON | cpu;
+}
+
 void BPF_STRUCT_OPS(layered_enqueue, struct task_struct *p, u64 enq_flags)
 {
 	struct cpu_ctx *cpuc, *task_cpuc;
@@ -1361,6 +1770,15 @@ void BPF_STRUCT_OPS(layered_enqueue, struct task_struct *p, u64 enq_flags)
 	if (!(cpuc = lookup_cpu_ctx(-1)) || !(taskc = lookup_task_ctx(p)))
 		return;
 
+	/*
+	 * A spec reload may have removed or moved @p's layer while @p was
+	 * running. Re-match so that it isn't queued on a retired layer.
+	 */
+	if (taskc->layer_spec_seq != layer_spec_seq) {
+		taskc->refresh_layer = true;
+		maybe_refresh_layer(p, taskc);
+	}
+
 	layer_id = taskc->layer_id;
 	if (!(layer = lookup_layer(layer_id)))
 		return;
@@ -1476,7 +1894,7 @@ void BPF_STRUCT_OPS(layered_enqueue, struct task_struct *p, u64 enq_flags)
 			if (is_percpu_kthread_preempting(p))
 				enq_flags |= SCX_ENQ_PREEMPT;
 		} else {
-			taskc->dsq_id = task_cpuc->hi_fb_dsq_id;
+			taskc->dsq_id = fb_dsq_id_for(p, task_cpu, task_cpuc->hi_fb_dsq_id);
 		}
 
 		scx_bpf_dsq_insert(p, taskc->dsq_id, layer->slice_ns, enq_flags);
@@ -1536,13 +1954,14 @@ void BPF_STRUCT_OPS(layered_enqueue, struct task_struct *p, u64 enq_flags)
 	if ((!taskc->all_cpuset_allowed &&
 	     !(layer->allow_node_aligned && taskc->cpus_node_aligned)) ||
 	    !layer->nr_cpus) {
-		taskc->dsq_id = task_cpuc->lo_fb_dsq_id;
+		taskc->dsq_id = fb_dsq_id_for(p, task_cpu, task_cpuc->lo_fb_dsq_id);
 		/*
 		 * Start a new lo fallback queued region if the DSQ is empty.
 		 * While the following is racy, all that's needed is at least
 		 * one of the racing updates to succeed, which is guaranteed.
 		 */
-		if (!scx_bpf_dsq_nr_queued(taskc->dsq_id))
+		if (taskc->dsq_id == task_cpuc->lo_fb_dsq_id &&
+		    !scx_bpf_dsq_nr_queued(taskc->dsq_id))
 			llcc->lo_fb_seq++;
 		scx_bpf_dsq_insert(p, taskc->dsq_id, layer->slice_ns, enq_flags);
 		return;
@@ -1606,7 +2025,7 @@ static void account_used(struct cpu_ctx *cpuc, struct task_ctx *taskc, u64 now)
 		return;
 
 	task_lid = taskc->layer_id;
-	if (unlikely(task_lid >= nr_layers)) {
+	if (unlikely(task_lid >= MAX_LAYERS)) {
 		scx_bpf_error("invalid layer %d", task_lid);
 		return;
 	}
@@ -1892,6 +2311,19 @@ static __always_inline bool try_consume_layer(u32 layer_id, struct cpu_ctx *cpuc
 	if (!(layer = lookup_layer(layer_id)))
 		return false;
 
+	if (sib_anti_affine(cpuc->cpu, layer)) {
+		/* let the sibling know, see sib_anti_affn_starved() */
+		if (!cpuc->anti_affn_wait_at &&
+		    scx_bpf_dsq_nr_queued(layer_dsq_id(layer_id, llcc->id))) {
+			cpuc->anti_affn_wait_layer_id = layer_id;
+			cpuc->anti_affn_wait_at = scx_bpf_now();
+		}
+		return false;
+	}
+
+	if (sib_anti_affn_starved(cpuc->cpu, layer, scx_bpf_now()))
+		return false;
+
 	skip_remote_node = layer->skip_remote_node;
 
 	bpf_for(u, 0, llc_pmap->sys_end) {
@@ -1941,9 +2373,17 @@ bool try_consume_layers(u32 *layer_order, u32 nr, u32 exclude_layer_id,
 	}
 
 	bpf_for(u, 0, nr) {
-		u32 layer_id = layer_order[u];
+		u32 layer_id;
 
-		if (layer_id == exclude_layer_id)
+		if (u >= MAX_LAYERS)
+			break;
+
+		/*
+		 * Userspace may be rewriting @layer_order, unused entries are
+		 * MAX_LAYERS.
+		 */
+		layer_id = layer_order[u];
+		if (layer_id == exclude_layer_id || layer_id >= MAX_LAYERS)
 			continue;
 
 		if (try_consume_layer(layer_id, cpuc, llcc))
@@ -1979,15 +2419,18 @@ void BPF_STRUCT_OPS(layered_dispatch, s32 cpu, struct task_struct *prev)
 {
 	struct task_ctx *prev_taskc = NULL;
 	struct layer *prev_layer = NULL;
+	struct cpu_layer_order *order;
 	struct cpu_ctx *cpuc;
 	struct llc_ctx *llcc;
 	bool tried_preempting = false, tried_lo_fb = false;
-	u32 nr_ogp_layers = nr_op_layers + nr_gp_layers;
-	u32 nr_ogn_layers = nr_on_layers + nr_gn_layers;
+	u32 nr_ogp_layers, nr_ogn_layers;
 
-	if (!(cpuc = lookup_cpu_ctx(-1)))
+	if (!(cpuc = lookup_cpu_ctx(-1)) || !(order = lookup_cpu_layer_order(-1)))
 		return;
 
+	nr_ogp_layers = order->nr_op_layers + order->nr_gp_layers;
+	nr_ogn_layers = order->nr_on_layers + order->nr_gn_layers;
+
 	if (antistall_consume(cpuc))
 		return;
 
@@ -2001,6 +2444,16 @@ void BPF_STRUCT_OPS(layered_dispatch, s32 cpu, struct task_struct *prev)
 	if (prev && sib_keep_idle(cpu, prev, prev_layer, prev_taskc, cpuc))
 		return;
 
+	/*
+	 * The sibling started running an anti-affine layer or has been waiting
+	 * to for too long, @prev must go.
+	 */
+	if (prev_taskc && (sib_anti_affine(cpu, prev_layer) ||
+			   sib_anti_affn_starved(cpu, prev_layer, scx_bpf_now()))) {
+		prev_taskc = NULL;
+		prev_layer = NULL;
+	}
+
 	/*
 	 * if @prev was on SCX and is still runnable, we are here because @prev
 	 * has exhausted its slice. We may want to keep running it on this CPU
@@ -2021,8 +2474,13 @@ void BPF_STRUCT_OPS(layered_dispatch, s32 cpu, struct task_struct *prev)
 	if (!(llcc = lookup_llc_ctx(cpuc->llc_id)))
 		return;
 
-	/* always consume hi_fb_dsq_id first for kthreads */
-	if (scx_bpf_dsq_move_to_local(cpuc->hi_fb_dsq_id))
+	/*
+	 * Always consume hi_fb_dsq_id first for kthreads. Confidential CPUs
+	 * don't run the fallback DSQs, which hold the tasks of other layers.
+	 * The ones which can only run here are dispatched directly, see
+	 * fb_dsq_id_for().
+	 */
+	if (!cpuc->is_confidential && scx_bpf_dsq_move_to_local(cpuc->hi_fb_dsq_id))
 		return;
 
 	/*
@@ -2031,7 +2489,7 @@ void BPF_STRUCT_OPS(layered_dispatch, s32 cpu, struct task_struct *prev)
 	 * filled with MAX_LAYERS, excluding IDs matching MAX_LAYERS makes it
 	 * safe.
 	 */
-	if (cpuc->cpu == fallback_cpu &&
+	if (cpuc->cpu == fallback_cpu && !cpuc->is_confidential &&
 	    try_consume_layers(empty_layer_ids, nr_empty_layer_ids,
 			       MAX_LAYERS, cpuc, llcc)) {
 		cpuc->running_fallback = true;
@@ -2042,7 +2500,7 @@ void BPF_STRUCT_OPS(layered_dispatch, s32 cpu, struct task_struct *prev)
 	 * Low fallback DSQ execution is forced upto lo_fb_share_ppk fraction
 	 * after the DSQ had tasks queued for longer than lo_fb_wait_ns.
 	 */
-	if (scx_bpf_dsq_nr_queued(cpuc->lo_fb_dsq_id)) {
+	if (!cpuc->is_confidential && scx_bpf_dsq_nr_queued(cpuc->lo_fb_dsq_id)) {
 		u64 now = scx_bpf_now();
 		u64 dur, usage;
 
@@ -2079,26 +2537,26 @@ void BPF_STRUCT_OPS(layered_dispatch, s32 cpu, struct task_struct *prev)
 		 * CPU is in an open layer.
 		 */
 		if (cpuc->protect_owned) {
-			if (try_consume_layers(cpuc->op_layer_order, nr_op_layers,
+			if (try_consume_layers(order->op_layer_order, order->nr_op_layers,
 					       MAX_LAYERS, cpuc, llcc))
 				return;
-			if (try_consume_layers(cpuc->on_layer_order, nr_on_layers,
+			if (try_consume_layers(order->on_layer_order, order->nr_on_layers,
 					       MAX_LAYERS, cpuc, llcc))
 				return;
-			if (try_consume_layers(cpuc->gp_layer_order, nr_gp_layers,
+			if (try_consume_layers(order->gp_layer_order, order->nr_gp_layers,
 					       MAX_LAYERS, cpuc, llcc))
 				return;
-			if (try_consume_layers(cpuc->gn_layer_order, nr_gn_layers,
+			if (try_consume_layers(order->gn_layer_order, order->nr_gn_layers,
 					       MAX_LAYERS, cpuc, llcc))
 				return;
 		} else {
-			if (try_consume_layers(cpuc->op_layer_order, nr_op_layers,
+			if (try_consume_layers(order->op_layer_order, order->nr_op_layers,
 					       MAX_LAYERS, cpuc, llcc))
 				return;
-			if (try_consume_layers(cpuc->gp_layer_order, nr_gp_layers,
+			if (try_consume_layers(order->gp_layer_order, order->nr_gp_layers,
 					       MAX_LAYERS, cpuc, llcc))
 				return;
-			if (try_consume_layers(cpuc->ogn_layer_order, nr_ogn_layers,
+			if (try_consume_layers(order->ogn_layer_order, nr_ogn_layers,
 					       MAX_LAYERS, cpuc, llcc))
 				return;
 		}
@@ -2116,7 +2574,7 @@ void BPF_STRUCT_OPS(layered_dispatch, s32 cpu, struct task_struct *prev)
 		 * or the owner layer is not protected or preempting.
 		 */
 		if (!owner_layer || (!owner_layer->is_protected && !cpuc->protect_owned && !owner_layer->preempt)) {
-			if (try_consume_layers(cpuc->ogp_layer_order, nr_ogp_layers,
+			if (try_consume_layers(order->ogp_layer_order, nr_ogp_layers,
 					       cpuc->layer_id, cpuc, llcc))
 				return;
 
@@ -2138,17 +2596,18 @@ void BPF_STRUCT_OPS(layered_dispatch, s32 cpu, struct task_struct *prev)
 
 		/* try grouped/open preempting if not tried yet */
 		if (!tried_preempting &&
-		    try_consume_layers(cpuc->ogp_layer_order, nr_ogp_layers,
+		    try_consume_layers(order->ogp_layer_order, nr_ogp_layers,
 				       cpuc->layer_id, cpuc, llcc))
 			return;
 
 		/* grouped/open non-preempt layers */
-		if (try_consume_layers(cpuc->ogn_layer_order, nr_ogn_layers,
+		if (try_consume_layers(order->ogn_layer_order, nr_ogn_layers,
 				       cpuc->layer_id, cpuc, llcc))
 			return;
 	}
 
-	if (!tried_lo_fb && scx_bpf_dsq_move_to_local(cpuc->lo_fb_dsq_id))
+	if (!tried_lo_fb && !cpuc->is_confidential &&
+	    scx_bpf_dsq_move_to_local(cpuc->lo_fb_dsq_id))
 		return;
 
 	/* !NULL prev_taskc indicates runnable prev */
@@ -2194,6 +2653,57 @@ static __noinline bool match_one(struct layer_match *match,
 		__builtin_memcpy(pcomm, p->group_leader->comm, MAX_COMM);
 		return match_str(match->pcomm_prefix, pcomm, STR_PREFIX);
 	}
+	case MATCH_COMM_GLOB: {
+		char comm[MAX_COMM];
+		__builtin_memcpy(comm, p->comm, MAX_COMM);
+		return match_glob(match->comm_prefix, comm);
+	}
+	case MATCH_PCOMM_GLOB: {
+		char pcomm[MAX_COMM];
+
+		__builtin_memcpy(pcomm, p->group_leader->comm, MAX_COMM);
+		return match_glob(match->pcomm_prefix, pcomm);
+	}
+	case MATCH_CGROUP_REGEX: {
+		u64 cgid = BPF_CORE_READ(p, cgroups, dfl_cgrp, kn, id);
+		u64 *mask;
+
+		if (match->cgroup_regex_id >= MAX_CGROUP_REGEXES)
+			return false;
+
+		mask = bpf_map_lookup_elem(&cgroup_regex_matches, &cgid);
+		return mask && (*mask & (1LLU << match->cgroup_regex_id));
+	}
+	case MATCH_TGID_IN: {
+		u32 i;
+
+		bpf_for(i, 0, match->nr_tgids) {
+			if (i >= MAX_MATCH_TGIDS)
+				break;
+			if (match->tgids[i] == p->tgid)
+				return true;
+		}
+		return false;
+	}
+	case MATCH_ANCESTOR_COMM_PREFIX: {
+		struct task_struct *cur = p, *parent;
+		char pcomm[MAX_COMM];
+		u32 depth;
+
+		bpf_for(depth, 0, MAX_ANCESTORS) {
+			parent = BPF_CORE_READ(cur, real_parent);
+			/* init_task is its own parent */
+			if (!parent || parent == cur)
+				break;
+			cur = parent;
+
+			if (BPF_CORE_READ_STR_INTO(&pcomm, cur, group_leader, comm) < 0)
+				break;
+			if (match_str(match->comm_prefix, pcomm, STR_PREFIX))
+				return true;
+		}
+		return false;
+	}
 	case MATCH_NICE_ABOVE:
 		return prio_to_nice((s32)p->static_prio) > match->nice;
 	case MATCH_NICE_BELOW:
@@ -2327,10 +2837,9 @@ int match_layer(u32 layer_id, struct task_struct *p __arg_trusted, const char *c
 	u32 nr_match_ors, pid;
 	u64 or_id, and_id;
 
-	if (layer_id >= nr_layers)
+	if (layer_id >= nr_layers || !(layer = MEMBER_VPTR(layers, [layer_id])))
 		return -EINVAL;
 
-	layer = &layers[layer_id];
 	nr_match_ors = layer->nr_match_ors;
 
 	if (nr_match_ors > MAX_LAYER_MATCH_ORS)
@@ -2367,38 +2876,77 @@ int match_layer(u32 layer_id, struct task_struct *p __arg_trusted, const char *c
 			if (enable_match_debug && (pid = p->pid))
 				bpf_map_update_elem(&layer_match_dbg, &pid, &layer_id, BPF_ANY);
 
-			return 0;
+			return or_id;
 		}
 	}
 
 	return -ENOENT;
 }
 
+static void emit_assign_event(struct task_struct *p, struct cpu_ctx *cpuc,
+			      u32 prev_layer_id, u32 layer_id, s32 match_idx)
+{
+	struct task_assign_event *ev;
+
+	if (!(ev = bpf_ringbuf_reserve(&task_assign_events, sizeof(*ev), 0))) {
+		gstat_inc(GSTAT_ASSIGN_DROPPED, cpuc);
+		return;
+	}
+
+	ev->pid = p->pid;
+	ev->tgid = p->tgid;
+	ev->layer_id = layer_id;
+	ev->prev_layer_id = prev_layer_id;
+	ev->match_idx = match_idx;
+	__builtin_memcpy(ev->comm, p->comm, MAX_COMM);
+
+	bpf_ringbuf_submit(ev, 0);
+}
+
 static void maybe_refresh_layer(struct task_struct *p __arg_trusted, struct task_ctx *taskc)
 {
 	const char *cgrp_path;
 	bool matched = false;
 	u64 layer_id;	// XXX - int makes verifier unhappy
+	u32 pid, tgid, *ovr;
+	s32 match_idx = ASSIGN_MATCH_OVERRIDE;
 
 	if (!taskc->refresh_layer)
 		return;
 	taskc->refresh_layer = false;
 	taskc->layer_refresh_seq = layer_refresh_seq_avgruntime;
+	taskc->layer_spec_seq = layer_spec_seq;
 
 	if (!(cgrp_path = format_cgrp_path(p->cgroups->dfl_cgrp)))
 		return;
 
-	if (taskc->layer_id >= 0 && taskc->layer_id < nr_layers)
+	if (taskc->layer_id >= 0 && taskc->layer_id < MAX_LAYERS)
 		__sync_fetch_and_add(&layers[taskc->layer_id].nr_tasks, -1);
 
-	bpf_for(layer_id, 0, nr_layers) {
-		if (match_layer(layer_id, p, cgrp_path) == 0) {
+	pid = p->pid;
+	tgid = p->tgid;
+	if ((ovr = bpf_map_lookup_elem(&layer_pid_overrides, &pid)) ||
+	    (ovr = bpf_map_lookup_elem(&layer_pid_overrides, &tgid))) {
+		u32 ovr_id = *ovr;
+
+		if (ovr_id < nr_layers) {
+			layer_id = ovr_id;
 			matched = true;
-			break;
+			if (enable_match_debug && pid)
+				bpf_map_update_elem(&layer_match_dbg, &pid, &ovr_id, BPF_ANY);
 		}
 	}
 
-	if (matched) {
+	if (!matched) {
+		bpf_for(layer_id, 0, nr_layers) {
+			if ((match_idx = match_layer(layer_id, p, cgrp_path)) >= 0) {
+				matched = true;
+				break;
+			}
+		}
+	}
+
+	if (matched && layer_id < MAX_LAYERS) {
 		struct layer *layer = &layers[layer_id];
 		struct cpu_ctx *cpuc;
 		struct llc_ctx *llcc;
@@ -2407,7 +2955,14 @@ static void maybe_refresh_layer(struct task_struct *p __arg_trusted, struct task
 		    !(llcc = lookup_llc_ctx(cpuc->llc_id)))
 			return;
 
+		if (emit_assign_events &&
+		    (taskc->layer_id != layer_id || taskc->match_idx != match_idx ||
+		     taskc->assign_events_seq != assign_events_seq))
+			emit_assign_event(p, cpuc, taskc->layer_id, layer_id, match_idx);
+
 		taskc->layer_id = layer_id;
+		taskc->match_idx = match_idx;
+		taskc->assign_events_seq = assign_events_seq;
 		taskc->llc_id = cpuc->llc_id;
 		taskc->layered_cpus.seq = -1;
 		taskc->layered_cpus_llc.seq = -1;
@@ -2606,6 +3161,10 @@ void BPF_STRUCT_OPS(layered_runnable, struct task_struct *p, u64 enq_flags)
 		return;
 
 	taskc->runnable_at = now;
+	taskc->lat_start_at = now;
+	taskc->lat_wakeup = enq_flags & SCX_ENQ_WAKEUP;
+	if (taskc->layer_spec_seq != layer_spec_seq)
+		taskc->refresh_layer = true;
 	maybe_refresh_layer(p, taskc);
 
 	if (enq_flags & SCX_ENQ_WAKEUP)
@@ -2633,6 +3192,11 @@ void BPF_STRUCT_OPS(layered_running, struct task_struct *p)
 
 	task_uncharge_qrt(taskc);
 
+	if (taskc->lat_start_at) {
+		account_lat(layer, cpuc, now - taskc->lat_start_at, taskc->lat_wakeup);
+		taskc->lat_start_at = 0;
+	}
+
 	if (taskc->last_cpu >= 0 && taskc->last_cpu != task_cpu) {
 		lstat_inc(LSTAT_MIGRATION, layer, cpuc);
 		if (!(nodec = lookup_node_ctx(cpuc->node_id)))
@@ -2650,12 +3214,46 @@ void BPF_STRUCT_OPS(layered_running, struct task_struct *p)
 	if (time_before(llcc->vtime_now[layer_id], p->scx.dsq_vtime))
 		llcc->vtime_now[layer_id] = p->scx.dsq_vtime;
 
-	cpuc->current_preempt = layer->preempt ||
-		(is_percpu_kthread(p) && is_percpu_kthread_preempting(p));
+	if (is_percpu_kthread(p) && is_percpu_kthread_preempting(p)) {
+		cpuc->current_preempt = true;
+		cpuc->current_qos_tier = MAX_QOS_TIERS;
+	} else {
+		cpuc->current_preempt = layer->preempt;
+		cpuc->current_qos_tier = layer->qos_tier;
+	}
 	cpuc->task_layer_id = taskc->layer_id;
 	cpuc->used_at = now;
 	taskc->running_at = now;
 	cpuc->is_protected = layer->is_protected;
+	cpuc->anti_affn_wait_at = 0;
+
+	/*
+	 * The sibling may have picked an anti-affine task at the same time. The
+	 * full barrier of the xchg, paired with the sibling's, guarantees that
+	 * at least one of the two CPUs sees the other's task. The task which
+	 * started running later, or the one on the higher CPU on ties, yields
+	 * the core by kicking its CPU into dispatch() which won't keep it
+	 * running. If the two disagree as the timestamps raced, both yield.
+	 */
+	if (layer->anti_affine_layers) {
+		struct cpu_ctx *sib_cpuc;
+		u64 sib_running_at;
+		s32 sib;
+
+		cpuc->task_running_at = now;
+		__sync_lock_test_and_set(&cpuc->task_layer_id, taskc->layer_id);
+
+		if (sib_anti_affine(task_cpu, layer) && (sib = sibling_cpu(task_cpu)) >= 0 &&
+		    (sib_cpuc = lookup_cpu_ctx(sib))) {
+			sib_running_at = READ_ONCE(sib_cpuc->task_running_at);
+			lstat_inc(LSTAT_ANTI_AFFN_KICK, layer, cpuc);
+			if (time_after(now, sib_running_at) ||
+			    (now == sib_running_at && task_cpu > sib))
+				scx_bpf_kick_cpu(task_cpu, SCX_KICK_PREEMPT);
+			else
+				scx_bpf_kick_cpu(sib, SCX_KICK_PREEMPT);
+		}
+	}
 
 	/* running an owned task if the task is on the layer owning the CPU */
 	if (layer->kind == LAYER_KIND_OPEN) {
@@ -2685,7 +3283,25 @@ void BPF_STRUCT_OPS(layered_running, struct task_struct *p)
 		}
 	}
 
-	if (layer->perf > 0 && cpuc->perf != layer->perf) {
+	if (layer->perf_min || layer->perf_max) {
+		u32 perf = cpuc->perf ?: SCX_CPUPERF_ONE;
+
+		if (layer->perf > 0)
+			perf = layer->perf;
+		if (layer->perf_min && perf < layer->perf_min)
+			perf = layer->perf_min;
+		if (layer->perf_max && perf > layer->perf_max)
+			perf = layer->perf_max;
+
+		if (!cpuc->perf_clamped) {
+			cpuc->perf_restore = cpuc->perf;
+			cpuc->perf_clamped = true;
+		}
+		if (cpuc->perf != perf) {
+			scx_bpf_cpuperf_set(task_cpu, perf);
+			cpuc->perf = perf;
+		}
+	} else if (layer->perf > 0 && cpuc->perf != layer->perf) {
 		scx_bpf_cpuperf_set(task_cpu, layer->perf);
 		cpuc->perf = layer->perf;
 	}
@@ -2716,6 +3332,12 @@ void BPF_STRUCT_OPS(layered_stopping, struct task_struct *p, bool runnable)
 
 	account_used(cpuc, taskc, now);
 
+	/* preempted or yielded, the task starts waiting for a CPU again */
+	if (runnable) {
+		taskc->lat_start_at = now;
+		taskc->lat_wakeup = false;
+	}
+
 	if (taskc->dsq_id & HI_FB_DSQ_BASE)
 		gstat_inc(GSTAT_HI_FB_EVENTS, cpuc);
 	else if (taskc->dsq_id & LO_FB_DSQ_BASE)
@@ -2751,8 +3373,27 @@ void BPF_STRUCT_OPS(layered_stopping, struct task_struct *p, bool runnable)
 
 	cpuc->running_fallback = false;
 	cpuc->current_preempt = false;
+	cpuc->current_qos_tier = 0;
 	cpuc->task_layer_id = MAX_LAYERS;
 
+	/* the sibling can now run the tasks it kept waiting for too long */
+	if (sib_anti_affn_starved(scx_bpf_task_cpu(p), task_layer, now))
+		scx_bpf_kick_cpu(sibling_cpu(scx_bpf_task_cpu(p)), SCX_KICK_IDLE);
+
+	/*
+	 * perf_min/max only apply while the layer's tasks are running. Go back
+	 * to the previous target, SCX_CPUPERF_ONE if there was none.
+	 */
+	if (cpuc->perf_clamped) {
+		u32 perf = cpuc->perf_restore ?: SCX_CPUPERF_ONE;
+
+		cpuc->perf_clamped = false;
+		if (cpuc->perf != perf) {
+			scx_bpf_cpuperf_set(scx_bpf_task_cpu(p), perf);
+			cpuc->perf = perf;
+		}
+	}
+
 	if (nr_excl_layers) {
 		cpuc->prev_excl = cpuc->current_excl;
 		cpuc->current_excl = false;
@@ -3033,10 +3674,13 @@ void BPF_STRUCT_OPS(layered_exit_task, struct task_struct *p,
 	if (enable_match_debug && (pid = p->pid))
 		bpf_map_delete_elem(&layer_match_dbg, &pid);
 
+	pid = p->pid;
+	bpf_map_delete_elem(&layer_pid_overrides, &pid);
+
 	if (!(cpuc = lookup_cpu_ctx(-1)) || !(taskc = lookup_task_ctx(p)))
 		return;
 
-	if (taskc->layer_id < nr_layers)
+	if (taskc->layer_id < MAX_LAYERS)
 		__sync_fetch_and_add(&layers[taskc->layer_id].nr_tasks, -1);
 }
 
@@ -3176,6 +3820,7 @@ u64 antistall_set(u64 dsq_id, u64 jiffies_now)
 {
 	struct task_struct *__p, *p = NULL;
 	struct task_ctx *taskc;
+	struct cpu_ctx *cpuc;
 	s32 cpu;
 	u64 *antistall_dsq, *delay, cur_delay;
 	int pass;
@@ -3214,6 +3859,11 @@ u64 antistall_set(u64 dsq_id, u64 jiffies_now)
 			if (!bpf_cpumask_test_cpu(cpu, cpumask))
 				continue;
 
+			/* keep the tasks of other layers off confidential CPUs */
+			if (!(cpuc = lookup_cpu_ctx(cpu)) ||
+			    (cpuc->is_confidential && cpuc->layer_id != taskc->layer_id))
+				continue;
+
 			antistall_dsq = bpf_map_lookup_percpu_elem(&antistall_cpu_dsq, &zero_u32, cpu);
 			delay = bpf_map_lookup_percpu_elem(&antistall_cpu_max_delay, &zero_u32, cpu);
 
@@ -3259,7 +3909,8 @@ static bool antistall_scan(void)
 
 	jiffies_now = bpf_jiffies64();
 
-	bpf_for(layer_id, 0, nr_layers)
+	/* retired layers too, see retire_layers() */
+	bpf_for(layer_id, 0, MAX_LAYERS)
 		bpf_for(llc, 0, nr_llcs)
 			antistall_set(layer_dsq_id(layer_id, llc), jiffies_now);
 
@@ -3338,13 +3989,48 @@ init_layer_cpumasks(int layer_id)
 	return 0;
 }
 
+/*
+ * Initializes the cpumasks and DSQs of a layer slot. All MAX_LAYERS slots are
+ * initialized so that spec reloads can add layers.
+ */
+static s32 init_layer_slot(int layer_id)
+{
+	struct layer *layer;
+	int i, ret;
+
+	if (!(layer = MEMBER_VPTR(layers, [layer_id]))) {
+		scx_bpf_error("invalid layer %d", layer_id);
+		return -EINVAL;
+	}
+	layer->id = layer_id;
+
+	if ((ret = init_layer_cpumasks(layer_id))) {
+		scx_bpf_error("could not initalize cpumasks");
+		return ret;
+	}
+
+	// create the dsqs for the layer
+	bpf_for(i, 0, nr_llcs) {
+		u64 dsq_id = layer_dsq_id(layer_id, i);
+		int node_id = llc_node_id(i);
+
+		dbg("CFG creating DSQ 0x%llx for layer %d on LLC %d (node %d)",
+		    dsq_id, layer_id, i, node_id);
+		ret = scx_bpf_create_dsq(dsq_id, node_id);
+		if (ret < 0)
+			return ret;
+	}
+
+	return 0;
+}
+
 /*
  * Initializes per-layer specific data structures.
  */
 static s32 init_layer(int layer_id)
 {
 	struct layer *layer = &layers[layer_id];
-	int i, j, ret;
+	int i, j;
 
 	dbg("CFG LAYER[%d][%s] min_exec_ns=%lu open=%d preempt=%d excl=%d",
 	    layer_id, layer->name, layer->min_exec_ns,
@@ -3354,8 +4040,6 @@ static s32 init_layer(int layer_id)
 	    layer->disallow_open_after_ns, layer->disallow_preempt_after_ns,
 	    layer->is_protected);
 
-	layer->id = layer_id;
-
 	if (layer->nr_match_ors > MAX_LAYER_MATCH_ORS) {
 		scx_bpf_error("too many ORs");
 		return -EINVAL;
@@ -3456,6 +4140,21 @@ static s32 init_layer(int layer_id)
 			case MATCH_CGROUP_CONTAINS:
 				dbg("%s CGROUP_CONTAINS \"%s\"", header, match->cgroup_substr);
 				break;
+			case MATCH_COMM_GLOB:
+				dbg("%s COMM_GLOB \"%s\"", header, match->comm_prefix);
+				break;
+			case MATCH_PCOMM_GLOB:
+				dbg("%s PCOMM_GLOB \"%s\"", header, match->pcomm_prefix);
+				break;
+			case MATCH_CGROUP_REGEX:
+				dbg("%s CGROUP_REGEX %u", header, match->cgroup_regex_id);
+				break;
+			case MATCH_TGID_IN:
+				dbg("%s TGID_IN nr=%u", header, match->nr_tgids);
+				break;
+			case MATCH_ANCESTOR_COMM_PREFIX:
+				dbg("%s ANCESTOR_COMM_PREFIX \"%s\"", header, match->comm_prefix);
+				break;
 			default:
 				scx_bpf_error("%s Invalid kind", header);
 				return -EINVAL;
@@ -3465,24 +4164,7 @@ static s32 init_layer(int layer_id)
 			dbg("CFG     DEFAULT");
 	}
 
-	if ((ret = init_layer_cpumasks(layer_id))) {
-		scx_bpf_error("could not initalize cpumasks");
-		return ret;
-	}
-
-	// create the dsqs for the layer
-	bpf_for(i, 0, nr_llcs) {
-		u64 dsq_id = layer_dsq_id(layer_id, i);
-		int node_id = llc_node_id(i);
-
-		dbg("CFG creating DSQ 0x%llx for layer %d %s on LLC %d (node %d)",
-		    dsq_id, layer_id, layer->name, i, node_id);
-		ret = scx_bpf_create_dsq(dsq_id, node_id);
-		if (ret < 0)
-			return ret;
-	}
-
-	return 0;
+	return init_layer_slot(layer_id);
 }
 
 /*
@@ -3494,6 +4176,7 @@ static s32 init_cpu(s32 cpu, int *nr_online_cpus,
 		    struct bpf_cpumask *tmp_unprotected_cpumask)
 {
 	const volatile u8 *u8_ptr;
+	struct cpu_layer_order *order;
 	struct cpu_ctx *cpuc;
 	struct cpu_prox_map *pmap;
 	u64 *init_antistall_dsq;
@@ -3545,25 +4228,32 @@ static s32 init_cpu(s32 cpu, int *nr_online_cpus,
 		}
 	}
 
-	bpf_for(i, 0, nr_op_layers + nr_gp_layers)
-		dbg("CFG: CPU[%d] ogp_layer_order[%d]=%d",
-		    cpu, i, cpuc->ogp_layer_order[i]);
-	bpf_for(i, 0, nr_on_layers + nr_gn_layers)
-		dbg("CFG: CPU[%d] ogn_layer_order[%d]=%d",
-		    cpu, i, cpuc->ogn_layer_order[i]);
+	if (!(order = lookup_cpu_layer_order(cpu)))
+		return -ENOMEM;
 
-	bpf_for(i, 0, nr_op_layers)
-		dbg("CFG: CPU[%d] op_layer_order[%d]=%d",
-		    cpu, i, cpuc->op_layer_order[i]);
-	bpf_for(i, 0, nr_on_layers)
-		dbg("CFG: CPU[%d] on_layer_order[%d]=%d",
-		    cpu, i, cpuc->on_layer_order[i]);
-	bpf_for(i, 0, nr_gp_layers)
-		dbg("CFG: CPU[%d] gp_layer_order[%d]=%d",
-		    cpu, i, cpuc->gp_layer_order[i]);
-	bpf_for(i, 0, nr_gn_layers)
-		dbg("CFG: CPU[%d] gn_layer_order[%d]=%d",
-		    cpu, i, cpuc->gn_layer_order[i]);
+	bpf_for(i, 0, MAX_LAYERS) {
+		if (i < order->nr_op_layers + order->nr_gp_layers)
+			dbg("CFG: CPU[%d] ogp_layer_order[%d]=%d",
+			    cpu, i, order->ogp_layer_order[i]);
+		if (i < order->nr_on_layers + order->nr_gn_layers)
+			dbg("CFG: CPU[%d] ogn_layer_order[%d]=%d",
+			    cpu, i, order->ogn_layer_order[i]);
+	}
+
+	bpf_for(i, 0, MAX_LAYERS) {
+		if (i < order->nr_op_layers)
+			dbg("CFG: CPU[%d] op_layer_order[%d]=%d",
+			    cpu, i, order->op_layer_order[i]);
+		if (i < order->nr_on_layers)
+			dbg("CFG: CPU[%d] on_layer_order[%d]=%d",
+			    cpu, i, order->on_layer_order[i]);
+		if (i < order->nr_gp_layers)
+			dbg("CFG: CPU[%d] gp_layer_order[%d]=%d",
+			    cpu, i, order->gp_layer_order[i]);
+		if (i < order->nr_gn_layers)
+			dbg("CFG: CPU[%d] gn_layer_order[%d]=%d",
+			    cpu, i, order->gn_layer_order[i]);
+	}
 
 	return 0;
 }
@@ -3613,6 +4303,12 @@ s32 BPF_STRUCT_OPS_SLEEPABLE(layered_init)
 	if (tmp_unprotected_cpumask)
 		bpf_cpumask_release(tmp_unprotected_cpumask);
 
+	if (!(cpumask = bpf_cpumask_create()))
+		return -ENOMEM;
+	cpumask = bpf_kptr_xchg(&confidential_cpumask, cpumask);
+	if (cpumask)
+		bpf_cpumask_release(cpumask);
+
 	bpf_for(i, 0, nr_nodes) {
 		ret = create_node(i);
 		if (ret)
@@ -3629,8 +4325,8 @@ s32 BPF_STRUCT_OPS_SLEEPABLE(layered_init)
 	dbg("CFG: min_open_layer_disallow_open/preempt_after=%lu/%lu",
 	    min_open_layer_disallow_open_after_ns, min_open_layer_disallow_preempt_after_ns);
 
-	bpf_for(i, 0, nr_layers) {
-		ret = init_layer(i);
+	bpf_for(i, 0, MAX_LAYERS) {
+		ret = i < nr_layers ? init_layer(i) : init_layer_slot(i);
 		if (ret != 0)
 			return ret;
 	}
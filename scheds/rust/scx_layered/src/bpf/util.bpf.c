@@ -211,3 +211,77 @@ bool __noinline match_str(const char *prefix, const char *str, enum MatchType ma
 
 	return false;
 }
+
+static __always_inline int clamp_commind(int i)
+{
+	return i & (MAX_COMM - 1);
+}
+
+/*
+ * Matches @str against @pattern where '*' matches any sequence of characters
+ * and '?' any single character. Both are read up to MAX_COMM, which is all
+ * that comm globs need.
+ */
+bool __noinline match_glob(const char *pattern, const char *str)
+{
+	char pat_buf[MAX_COMM], str_buf[MAX_COMM];
+	int pat_len, str_len, p = 0, s = 0, star = -1, mark = 0, i;
+
+	if (!pattern || !str) {
+		scx_bpf_error("invalid args: %s %s",
+			      pattern, str);
+		return false;
+	}
+
+	pat_len = bpf_probe_read_kernel_str(pat_buf, MAX_COMM, pattern);
+	if (pat_len < 0) {
+		scx_bpf_error("failed to read pattern");
+		return false;
+	}
+
+	str_len = bpf_probe_read_kernel_str(str_buf, MAX_COMM, str);
+	if (str_len < 0) {
+		scx_bpf_error("failed to read str");
+		return false;
+	}
+
+	/* exclude the terminating NULs */
+	pat_len--;
+	str_len--;
+
+	/*
+	 * Greedy matching which backtracks only to the last '*'. This needs at
+	 * most pat_len * str_len steps.
+	 */
+	bpf_for(i, 0, MAX_COMM * MAX_COMM) {
+		if (s >= str_len)
+			break;
+
+		if (p < pat_len && pat_buf[clamp_commind(p)] == '*') {
+			star = p++;
+			mark = s;
+		} else if (p < pat_len &&
+			   (pat_buf[clamp_commind(p)] == '?' ||
+			    pat_buf[clamp_commind(p)] == str_buf[clamp_commind(s)])) {
+			p++;
+			s++;
+		} else if (star >= 0) {
+			p = star + 1;
+			s = ++mark;
+		} else {
+			return false;
+		}
+	}
+
+	if (s < str_len)
+		return false;
+
+	/* trailing '*'s match the empty string */
+	bpf_for(i, 0, MAX_COMM) {
+		if (p >= pat_len || pat_buf[clamp_commind(p)] != '*')
+			break;
+		p++;
+	}
+
+	return p == pat_len;
+}
@@ -27,6 +27,7 @@ enum MatchType {
 };
 
 bool match_str(const char *prefix, const char *str, enum MatchType match_type);
+bool match_glob(const char *pattern, const char *str);
 char *format_cgrp_path(struct cgroup *cgrp);
 
 #endif /* __LAYERED_UTIL_H */
@@ -2,15 +2,28 @@
 
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2.
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::Read;
+use std::path::Path;
 
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::Local;
+use chrono::NaiveTime;
+use chrono::Weekday;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value;
 
 use crate::bpf_intf;
 use crate::LayerGrowthAlgo;
+use crate::LayerSizingPolicy;
 
 use scx_utils::Cpumask;
 
@@ -29,11 +42,15 @@ pub struct LayerSpec {
     pub template: Option<LayerMatch>,
     pub matches: Vec<Vec<LayerMatch>>,
     pub kind: LayerKind,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedules: Vec<LayerSchedule>,
 }
 
 impl LayerSpec {
-    pub fn parse(input: &str) -> Result<Vec<Self>> {
-        let config: LayerConfig = if input.starts_with("f:") || input.starts_with("file:") {
+    /// Read the layer entries from @input, either a JSON string or a file
+    /// path prefixed with "f:" or "file:", without interpreting them.
+    pub fn parse_raw(input: &str) -> Result<Vec<Value>> {
+        let entries = if input.starts_with("f:") || input.starts_with("file:") {
             let mut f = fs::OpenOptions::new()
                 .read(true)
                 .open(input.split_once(':').unwrap().1)?;
@@ -43,7 +60,94 @@ impl LayerSpec {
         } else {
             serde_json::from_str(input)?
         };
-        Ok(config.specs)
+        Ok(entries)
+    }
+
+    pub fn parse(input: &str) -> Result<Vec<Self>> {
+        Self::from_raw(Self::parse_raw(input)?)
+    }
+
+    /// Build the specs from the layer entries. An entry with "inherits" set
+    /// to the name of another entry starts from that entry's fields, and
+    /// entries with "abstract" set only serve as bases and don't become
+    /// layers. See --help.
+    pub fn from_raw(entries: Vec<Value>) -> Result<Vec<Self>> {
+        let names: Vec<Option<&str>> = entries
+            .iter()
+            .map(|entry| entry.get("name").and_then(|v| v.as_str()))
+            .collect();
+        let mut by_name: BTreeMap<&str, Option<usize>> = BTreeMap::new();
+        for (idx, name) in names.iter().enumerate() {
+            if let Some(name) = *name {
+                by_name
+                    .entry(name)
+                    .and_modify(|v| *v = None)
+                    .or_insert(Some(idx));
+            }
+        }
+
+        let mut resolved: Vec<Option<Map<String, Value>>> = vec![None; entries.len()];
+        let mut specs = vec![];
+        for idx in 0..entries.len() {
+            let entry = Self::resolve_entry(idx, &entries, &names, &by_name, &mut resolved, 0)?;
+            if entry.get("abstract") == Some(&Value::Bool(true)) {
+                continue;
+            }
+
+            let mut entry = entry.clone();
+            entry.remove("abstract");
+            let name = names[idx].unwrap_or("?");
+            specs.push(
+                serde_json::from_value(Value::Object(entry))
+                    .with_context(|| format!("Invalid layer spec {:?}", name))?,
+            );
+        }
+        Ok(specs)
+    }
+
+    fn resolve_entry<'a>(
+        idx: usize,
+        entries: &[Value],
+        names: &[Option<&str>],
+        by_name: &BTreeMap<&str, Option<usize>>,
+        resolved: &'a mut Vec<Option<Map<String, Value>>>,
+        depth: usize,
+    ) -> Result<&'a Map<String, Value>> {
+        if resolved[idx].is_none() {
+            let name = names[idx].unwrap_or("?");
+            let mut entry = match &entries[idx] {
+                Value::Object(v) => v.clone(),
+                _ => bail!("Layer spec {} is not an object", idx),
+            };
+
+            let merged = match entry.remove("inherits") {
+                None => entry,
+                Some(Value::String(base)) => {
+                    if depth >= entries.len() {
+                        bail!("Layer spec {:?} has circular inheritance", name);
+                    }
+                    let base_idx = match by_name.get(base.as_str()) {
+                        Some(Some(v)) => *v,
+                        Some(None) => bail!("Layer spec {:?} inherits ambiguous {:?}", name, base),
+                        None => bail!("Layer spec {:?} inherits unknown {:?}", name, base),
+                    };
+                    let mut base = Self::resolve_entry(
+                        base_idx,
+                        entries,
+                        names,
+                        by_name,
+                        resolved,
+                        depth + 1,
+                    )?
+                    .clone();
+                    base.remove("abstract");
+                    merge_layer_entry(base, entry)
+                }
+                Some(_) => bail!("Layer spec {:?} has non-string inherits", name),
+            };
+            resolved[idx] = Some(merged);
+        }
+        Ok(resolved[idx].as_ref().unwrap())
     }
 
     pub fn nodes(&self) -> &Vec<usize> {
@@ -63,6 +167,132 @@ impl LayerSpec {
     }
 }
 
+/// Fields of @over replace the ones of @base, except in "kind" where the
+/// fields of the kind are merged if both are of the same kind.
+fn merge_layer_entry(mut base: Map<String, Value>, over: Map<String, Value>) -> Map<String, Value> {
+    for (key, val) in over.into_iter() {
+        let val = match (key.as_str(), base.remove(&key), val) {
+            ("kind", Some(Value::Object(mut bkind)), Value::Object(okind))
+                if bkind.len() == 1 && okind.len() == 1 && bkind.keys().eq(okind.keys()) =>
+            {
+                let (kind, ofields) = okind.into_iter().next().unwrap();
+                let fields = match (bkind.remove(&kind), ofields) {
+                    (Some(Value::Object(mut fields)), Value::Object(ofields)) => {
+                        fields.extend(ofields);
+                        Value::Object(fields)
+                    }
+                    (_, ofields) => ofields,
+                };
+                Value::Object(Map::from_iter([(kind, fields)]))
+            }
+            (_, _, val) => val,
+        };
+        base.insert(key, val);
+    }
+    base
+}
+
+/// Target for the delay between a task becoming runnable and running.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayerSlo {
+    pub lat_us: u64,
+    /// Percentage of the samples which must be within lat_us.
+    #[serde(default = "default_slo_pct")]
+    pub pct: f64,
+    /// Only count wakeups, not tasks going back to wait after preemption.
+    #[serde(default)]
+    pub wakeup_only: bool,
+    /// Warn after this many consecutive intervals out of the SLO, 0 to
+    /// never warn.
+    #[serde(default)]
+    pub alert_after: u32,
+}
+
+fn default_slo_pct() -> f64 {
+    99.0
+}
+
+/// Overrides of a layer's sizing knobs which apply while all of the set
+/// conditions are met.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayerSchedule {
+    /// Days of the week, e.g. "Mon" or "Saturday". Every day if empty.
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// Local-time window as ("HH:MM", "HH:MM"). Wraps around midnight if
+    /// the end is before the start.
+    #[serde(default)]
+    pub time: Option<(String, String)>,
+    /// Active while the file exists.
+    #[serde(default)]
+    pub trigger_file: Option<String>,
+
+    #[serde(default)]
+    pub util_range: Option<(f64, f64)>,
+    #[serde(default)]
+    pub cpus_range: Option<(usize, usize)>,
+    #[serde(default)]
+    pub weight: Option<u32>,
+    /// Seconds to linearly move from the previous values to the new ones.
+    #[serde(default)]
+    pub ramp_secs: u64,
+}
+
+impl LayerSchedule {
+    pub fn weekdays(&self) -> Result<Vec<Weekday>> {
+        self.days
+            .iter()
+            .map(|day| {
+                day.parse::<Weekday>()
+                    .map_err(|_| anyhow!("invalid day {:?}", day))
+            })
+            .collect()
+    }
+
+    pub fn time_window(&self) -> Result<Option<(NaiveTime, NaiveTime)>> {
+        let parse = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .with_context(|| format!("invalid time {:?}, expected HH:MM", time))
+        };
+        match &self.time {
+            Some((start, end)) => Ok(Some((parse(start)?, parse(end)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the schedule applies at @now. Invalid days and times, which
+    /// are rejected when the specs are verified, never match.
+    pub fn is_active(&self, now: &DateTime<Local>) -> bool {
+        match self.weekdays() {
+            Ok(days) if days.is_empty() || days.contains(&now.weekday()) => {}
+            _ => return false,
+        }
+
+        match self.time_window() {
+            Ok(None) => {}
+            Ok(Some((start, end))) => {
+                let time = now.time();
+                let inside = if start <= end {
+                    start <= time && time < end
+                } else {
+                    start <= time || time < end
+                };
+                if !inside {
+                    return false;
+                }
+            }
+            Err(_) => return false,
+        }
+
+        match &self.trigger_file {
+            Some(path) => Path::new(path).exists(),
+            None => true,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub enum LayerPlacement {
     #[default]
@@ -71,15 +301,35 @@ pub enum LayerPlacement {
     Floating,
 }
 
+/// How the memory of the tasks of a layer restricted to some NUMA nodes is
+/// kept on those nodes. Applied per process when its tasks join the layer.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum LayerMemPolicy {
+    #[default]
+    None,
+    /// Migrate the process's memory to the layer's nodes with
+    /// migrate_pages(2). Only the memory allocated by then is moved, later
+    /// allocations follow the process's own policy.
+    MigrateOnce,
+    /// Set cpuset.mems of the process's cgroup to the layer's nodes which
+    /// also makes the kernel migrate the memory of the whole cgroup. Only
+    /// applied to the non-root cgroups matched by an OR block of the layer
+    /// which matches only on cgroups.
+    CgroupMems,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum LayerMatch {
     CgroupPrefix(String),
     CgroupSuffix(String),
     CgroupContains(String),
+    CgroupRegex(String),
     CommPrefix(String),
     CommPrefixExclude(String),
+    CommGlob(String),
     PcommPrefix(String),
     PcommPrefixExclude(String),
+    PcommGlob(String),
     NiceAbove(i32),
     NiceBelow(i32),
     NiceEquals(i32),
@@ -87,7 +337,9 @@ pub enum LayerMatch {
     GIDEquals(u32),
     PIDEquals(u32),
     PPIDEquals(u32),
+    AncestorCommPrefix(String),
     TGIDEquals(u32),
+    TGIDIn(Vec<u32>),
     NSPIDEquals(u64, u32),
     NSEquals(u32),
     CmdJoin(String),
@@ -113,6 +365,10 @@ pub struct LayerCommon {
     #[serde(default)]
     pub preempt_first: bool,
     #[serde(default)]
+    pub qos_tier: u32,
+    #[serde(default)]
+    pub preempt_budget: Option<u64>,
+    #[serde(default)]
     pub exclusive: bool,
     #[serde(default)]
     pub allow_node_aligned: bool,
@@ -135,6 +391,10 @@ pub struct LayerCommon {
     #[serde(default)]
     pub perf: u64,
     #[serde(default)]
+    pub perf_min: u64,
+    #[serde(default)]
+    pub perf_max: u64,
+    #[serde(default)]
     pub idle_resume_us: Option<u32>,
     #[serde(default)]
     pub nodes: Vec<usize>,
@@ -142,6 +402,14 @@ pub struct LayerCommon {
     pub llcs: Vec<usize>,
     #[serde(default)]
     pub placement: LayerPlacement,
+    #[serde(default)]
+    pub anti_affinity: Vec<String>,
+    #[serde(default)]
+    pub sizing: LayerSizingPolicy,
+    #[serde(default)]
+    pub slo: Option<LayerSlo>,
+    #[serde(default)]
+    pub mempolicy: LayerMemPolicy,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -157,6 +425,9 @@ pub enum LayerKind {
         #[serde(default)]
         protected: bool,
 
+        #[serde(default)]
+        confidential: bool,
+
         #[serde(flatten)]
         common: LayerCommon,
     },
@@ -173,6 +444,9 @@ pub enum LayerKind {
         #[serde(default)]
         protected: bool,
 
+        #[serde(default)]
+        confidential: bool,
+
         #[serde(flatten)]
         common: LayerCommon,
     },
@@ -226,3 +500,179 @@ impl LayerKind {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn from_json(entries: Value) -> Result<Vec<LayerSpec>> {
+        match entries {
+            Value::Array(entries) => LayerSpec::from_raw(entries),
+            _ => panic!("not an array"),
+        }
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let specs = from_json(json!([
+            {
+                "name": "base",
+                "abstract": true,
+                "comment": "base comment",
+                "matches": [[{"CommPrefix": "base"}]],
+                "kind": {"Confined": {"util_range": [0.5, 0.8], "preempt": true}}
+            },
+            {
+                "name": "mid",
+                "inherits": "base",
+                "abstract": true,
+                "kind": {"Confined": {"cpus_range": [1, 4]}}
+            },
+            {
+                "name": "games",
+                "inherits": "mid",
+                "matches": [[{"CommPrefix": "game"}]],
+                "kind": {"Confined": {"util_range": [0.2, 0.4]}}
+            },
+            {
+                "name": "open",
+                "inherits": "later",
+                "kind": {"Open": {}}
+            },
+            {
+                "name": "later",
+                "matches": [[]],
+                "kind": {"Grouped": {"util_range": [0.1, 0.9], "preempt": true}}
+            }
+        ]))
+        .unwrap();
+
+        // Abstract entries don't become layers.
+        let names: Vec<&str> = specs.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["games", "open", "later"]);
+
+        // Fields other than kind are replaced as a whole.
+        let games = &specs[0];
+        assert_eq!(games.comment.as_deref(), Some("base comment"));
+        assert_eq!(games.matches.len(), 1);
+        assert!(matches!(
+            games.matches[0][..],
+            [LayerMatch::CommPrefix(ref v)] if v == "game"
+        ));
+
+        // The fields of the same kind are merged through the chain.
+        match &games.kind {
+            LayerKind::Confined {
+                util_range,
+                cpus_range,
+                common,
+                ..
+            } => {
+                assert_eq!(*util_range, (0.2, 0.4));
+                assert_eq!(*cpus_range, Some((1, 4)));
+                assert!(common.preempt);
+            }
+            kind => panic!("unexpected kind {:?}", kind),
+        }
+
+        // A different kind replaces the inherited one and bases can be
+        // defined after their users.
+        let open = &specs[1];
+        assert_eq!(open.matches.len(), 1);
+        match &open.kind {
+            LayerKind::Open { common } => assert!(!common.preempt),
+            kind => panic!("unexpected kind {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_merge_layer_entry() {
+        let as_map = |v: Value| match v {
+            Value::Object(v) => v,
+            _ => panic!("not an object"),
+        };
+        let base = as_map(json!({
+            "name": "base",
+            "matches": [[{"CommPrefix": "a"}], [{"CommPrefix": "b"}]],
+            "kind": {"Grouped": {"util_range": [0.5, 0.8], "nodes": [0, 1]}}
+        }));
+
+        // Kinds are merged field by field, with only the top-level fields
+        // being replaced, and fields of other kinds replace the whole kind.
+        let merged = merge_layer_entry(
+            base.clone(),
+            as_map(json!({
+                "name": "over",
+                "kind": {"Grouped": {"nodes": [2]}}
+            })),
+        );
+        assert_eq!(
+            Value::Object(merged),
+            json!({
+                "name": "over",
+                "matches": [[{"CommPrefix": "a"}], [{"CommPrefix": "b"}]],
+                "kind": {"Grouped": {"util_range": [0.5, 0.8], "nodes": [2]}}
+            })
+        );
+
+        let merged = merge_layer_entry(
+            base,
+            as_map(json!({
+                "matches": [],
+                "kind": {"Open": {"preempt": true}}
+            })),
+        );
+        assert_eq!(
+            Value::Object(merged),
+            json!({
+                "name": "base",
+                "matches": [],
+                "kind": {"Open": {"preempt": true}}
+            })
+        );
+    }
+
+    #[test]
+    fn test_inheritance_errors() {
+        let kind = json!({"Open": {}});
+        let err_of = |entries: Value| from_json(entries).unwrap_err().to_string();
+
+        let err = err_of(json!([
+            {"name": "a", "inherits": "b", "matches": [], "kind": kind},
+            {"name": "b", "inherits": "c", "matches": [], "kind": kind},
+            {"name": "c", "inherits": "a", "matches": [], "kind": kind}
+        ]));
+        assert!(err.contains("circular"), "{}", err);
+
+        let err = err_of(json!([
+            {"name": "a", "inherits": "a", "matches": [], "kind": kind}
+        ]));
+        assert!(err.contains("circular"), "{}", err);
+
+        let err = err_of(json!([
+            {"name": "a", "matches": [], "kind": kind},
+            {"name": "a", "matches": [], "kind": kind},
+            {"name": "b", "inherits": "a", "matches": [], "kind": kind}
+        ]));
+        assert!(err.contains("ambiguous"), "{}", err);
+
+        let err = err_of(json!([
+            {"name": "b", "inherits": "a", "matches": [], "kind": kind}
+        ]));
+        assert!(err.contains("unknown"), "{}", err);
+
+        let err = err_of(json!([
+            {"name": "b", "inherits": 1, "matches": [], "kind": kind}
+        ]));
+        assert!(err.contains("non-string"), "{}", err);
+
+        // Duplicate names are fine as long as nothing inherits from them.
+        let specs = from_json(json!([
+            {"name": "a", "matches": [], "kind": kind},
+            {"name": "a", "matches": [], "kind": kind}
+        ]))
+        .unwrap();
+        assert_eq!(specs.len(), 2);
+    }
+}
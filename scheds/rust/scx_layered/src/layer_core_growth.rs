@@ -62,6 +62,12 @@ pub enum LayerGrowthAlgo {
     /// size, while remaining sticky to LLCs, and tries to place layers across
     /// LLC boundary minimizing overlap.
     StickyDynamic,
+    /// Compact fills up an LLC before growing into the next LLC of the same
+    /// NUMA node and spills into other nodes, nearest first, only after the
+    /// node is full. It starts from the llcs and then the nodes in the layer
+    /// config or, if neither is set, from an LLC picked by the layer index
+    /// to spread the layers.
+    Compact,
 }
 
 const GROWTH_ALGO_STICKY: i32 = bpf_intf::layer_growth_algo_GROWTH_ALGO_STICKY as i32;
@@ -85,6 +91,7 @@ const GROWTH_ALGO_CPUSET_SPREAD_RANDOM: i32 =
 const GROWTH_ALGO_RANDOM_TOPO: i32 = bpf_intf::layer_growth_algo_GROWTH_ALGO_RANDOM_TOPO as i32;
 const GROWTH_ALGO_STICKY_DYNAMIC: i32 =
     bpf_intf::layer_growth_algo_GROWTH_ALGO_STICKY_DYNAMIC as i32;
+const GROWTH_ALGO_COMPACT: i32 = bpf_intf::layer_growth_algo_GROWTH_ALGO_COMPACT as i32;
 use std::collections::BTreeSet;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -183,6 +190,7 @@ impl LayerGrowthAlgo {
             LayerGrowthAlgo::CpuSetSpreadRandom => GROWTH_ALGO_CPUSET_SPREAD_RANDOM,
             LayerGrowthAlgo::RandomTopo => GROWTH_ALGO_RANDOM_TOPO,
             LayerGrowthAlgo::StickyDynamic => GROWTH_ALGO_STICKY_DYNAMIC,
+            LayerGrowthAlgo::Compact => GROWTH_ALGO_COMPACT,
         }
     }
 
@@ -245,6 +253,7 @@ impl LayerGrowthAlgo {
             LayerGrowthAlgo::CpuSetSpreadRandom => generator.grow_cpuset_spread_random(),
             LayerGrowthAlgo::RandomTopo => generator.grow_random_topo(),
             LayerGrowthAlgo::StickyDynamic => generator.grow_sticky_dynamic(),
+            LayerGrowthAlgo::Compact => generator.grow_compact(),
         })
     }
 }
@@ -544,6 +553,68 @@ impl<'a> LayerCoreOrderGenerator<'a> {
     fn grow_sticky_dynamic(&self) -> Vec<usize> {
         self.grow_sticky()
     }
+
+    fn grow_compact(&self) -> Vec<usize> {
+        let topo = self.topo;
+        let mut llcs: Vec<usize> = vec![];
+
+        for llc_id in self.spec.llcs().iter() {
+            if topo.all_llcs.contains_key(llc_id) && !llcs.contains(llc_id) {
+                llcs.push(*llc_id);
+            }
+        }
+        for node_id in self.spec.nodes().iter() {
+            if let Some(node) = topo.nodes.get(node_id) {
+                for llc_id in node.llcs.keys() {
+                    if !llcs.contains(llc_id) {
+                        llcs.push(*llc_id);
+                    }
+                }
+            }
+        }
+        if llcs.is_empty() {
+            let nr_llcs = topo.all_llcs.len();
+            let start = self.layer_idx * nr_llcs / self.layer_specs.len().max(1);
+            if let Some(llc_id) = topo.all_llcs.keys().nth(start % nr_llcs.max(1)) {
+                llcs.push(*llc_id);
+            }
+        }
+
+        // Grow into the rest of the node of each LLC picked so far, then
+        // into the other nodes from the nearest one.
+        let mut nodes: Vec<usize> = vec![];
+        for llc_id in llcs.iter() {
+            let node_id = topo.all_llcs[llc_id].node_id;
+            if !nodes.contains(&node_id) {
+                nodes.push(node_id);
+            }
+        }
+        if let Some(&first) = nodes.first() {
+            for node_id in topo.nearest_nodes(first) {
+                if !nodes.contains(&node_id) {
+                    nodes.push(node_id);
+                }
+            }
+        }
+        for node_id in nodes.iter() {
+            // Continue from the LLC after the first one picked in the node.
+            let node_llcs: Vec<usize> = topo.nodes[node_id].llcs.keys().copied().collect();
+            let rot = node_llcs
+                .iter()
+                .position(|llc_id| llcs.contains(llc_id))
+                .unwrap_or(0);
+            for llc_id in node_llcs.iter().cycle().skip(rot).take(node_llcs.len()) {
+                if !llcs.contains(llc_id) {
+                    llcs.push(*llc_id);
+                }
+            }
+        }
+
+        llcs.iter()
+            .flat_map(|llc_id| topo.all_llcs[llc_id].cores.values())
+            .map(|core| self.cpu_pool.get_core_topological_id(core))
+            .collect()
+    }
 }
 
 struct IteratorInterleaver<T>
@@ -0,0 +1,257 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2.
+use serde::Deserialize;
+use serde::Serialize;
+
+/// How the number of CPUs of a Confined or Grouped layer is determined on
+/// each scheduling interval. The result is always clamped to the layer's
+/// cpus_range, or cpus_range_frac, and the CPUs are then distributed by the
+/// layer weights if there aren't enough of them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum LayerSizingPolicy {
+    /// Keep the per-CPU utilization within util_range.
+    #[default]
+    UtilRange,
+    /// PID controller which keeps the queueing delay at target_lat_us by
+    /// adding CPUs to, or removing them from, what keeping the per-CPU
+    /// utilization at util_range.1 requires. The error fed to the
+    /// controller is relative to the target, so the gains are in CPUs per
+    /// 100% deviation from it.
+    Pid {
+        target_lat_us: u64,
+        #[serde(default = "default_pid_kp")]
+        kp: f64,
+        #[serde(default = "default_pid_ki")]
+        ki: f64,
+        #[serde(default)]
+        kd: f64,
+    },
+    /// Size the layer proportionally to its demand, which is the
+    /// utilization plus headroom, scaled up by the ratio of the queueing
+    /// delay to target_lat_us when the target is exceeded.
+    PropShare {
+        #[serde(default)]
+        target_lat_us: u64,
+        #[serde(default = "default_prop_share_headroom")]
+        headroom: f64,
+    },
+}
+
+fn default_pid_kp() -> f64 {
+    1.0
+}
+
+fn default_pid_ki() -> f64 {
+    0.5
+}
+
+fn default_prop_share_headroom() -> f64 {
+    0.2
+}
+
+impl LayerSizingPolicy {
+    pub fn controller(&self) -> Box<dyn LayerSizingController> {
+        match self {
+            LayerSizingPolicy::UtilRange => Box::new(UtilRangeController),
+            LayerSizingPolicy::Pid {
+                target_lat_us,
+                kp,
+                ki,
+                kd,
+            } => Box::new(PidController {
+                target_lat_us: *target_lat_us as f64,
+                gains: (*kp, *ki, *kd),
+                integral: 0.0,
+                prev_err: None,
+            }),
+            LayerSizingPolicy::PropShare {
+                target_lat_us,
+                headroom,
+            } => Box::new(PropShareController {
+                target_lat_us: *target_lat_us as f64,
+                headroom: *headroom,
+            }),
+        }
+    }
+}
+
+/// What a controller gets to see of a layer on each scheduling interval.
+#[derive(Clone, Copy, Debug)]
+pub struct LayerSizingInput {
+    /// The number of CPUs currently allocated to the layer.
+    pub nr_cpus: usize,
+    /// CPU utilization, 1.0 being one fully busy CPU.
+    pub util: f64,
+    pub util_range: (f64, f64),
+    /// Average queueing delay of the layer's tasks.
+    pub lat_us: f64,
+    /// The layer's min and max number of CPUs.
+    pub cpus_range: (usize, usize),
+    /// Seconds since the previous invocation, 0 on the first one.
+    pub dt: f64,
+}
+
+pub trait LayerSizingController: std::fmt::Debug + Send {
+    /// The number of CPUs the layer should have. The caller clamps the
+    /// result to @input.cpus_range.
+    fn target_nr_cpus(&mut self, input: &LayerSizingInput) -> usize;
+}
+
+#[derive(Debug)]
+struct UtilRangeController;
+
+impl LayerSizingController for UtilRangeController {
+    fn target_nr_cpus(&mut self, input: &LayerSizingInput) -> usize {
+        let low = (input.util / input.util_range.1).ceil() as usize;
+        let high = ((input.util / input.util_range.0).floor() as usize).max(low);
+        input.nr_cpus.clamp(low, high)
+    }
+}
+
+#[derive(Debug)]
+struct PidController {
+    target_lat_us: f64,
+    gains: (f64, f64, f64),
+    integral: f64,
+    prev_err: Option<f64>,
+}
+
+impl LayerSizingController for PidController {
+    fn target_nr_cpus(&mut self, input: &LayerSizingInput) -> usize {
+        let (kp, ki, kd) = self.gains;
+        let err = (input.lat_us - self.target_lat_us) / self.target_lat_us;
+
+        let mut deriv = 0.0;
+        if input.dt > 0.0 {
+            self.integral += err * input.dt;
+            if let Some(prev_err) = self.prev_err {
+                deriv = (err - prev_err) / input.dt;
+            }
+        }
+        self.prev_err = Some(err);
+
+        // Don't let the integral term wind up beyond what can be applied.
+        if ki > 0.0 {
+            let limit = input.cpus_range.1 as f64 / ki;
+            self.integral = self.integral.clamp(-limit, limit);
+        }
+
+        let base = input.util / input.util_range.1;
+        let target = base + kp * err + ki * self.integral + kd * deriv;
+
+        // Never go below what the layer is actually using.
+        target.max(input.util).ceil().max(0.0) as usize
+    }
+}
+
+#[derive(Debug)]
+struct PropShareController {
+    target_lat_us: f64,
+    headroom: f64,
+}
+
+impl LayerSizingController for PropShareController {
+    fn target_nr_cpus(&mut self, input: &LayerSizingInput) -> usize {
+        let pressure = if self.target_lat_us > 0.0 {
+            (input.lat_us / self.target_lat_us).max(1.0)
+        } else {
+            1.0
+        };
+        (input.util * (1.0 + self.headroom) * pressure).ceil() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(util: f64, lat_us: f64, dt: f64) -> LayerSizingInput {
+        LayerSizingInput {
+            nr_cpus: 4,
+            util,
+            util_range: (0.5, 1.0),
+            lat_us,
+            cpus_range: (1, 8),
+            dt,
+        }
+    }
+
+    fn pid(kp: f64, ki: f64, kd: f64) -> PidController {
+        PidController {
+            target_lat_us: 100.0,
+            gains: (kp, ki, kd),
+            integral: 0.0,
+            prev_err: None,
+        }
+    }
+
+    #[test]
+    fn test_pid_zero_dt() {
+        // Neither the integral nor the derivative terms move without time
+        // passing, only the proportional one applies.
+        let mut ctrl = pid(1.0, 1.0, 1.0);
+        assert_eq!(ctrl.target_nr_cpus(&input(2.0, 300.0, 0.0)), 4);
+        assert_eq!(ctrl.integral, 0.0);
+        assert_eq!(ctrl.prev_err, Some(2.0));
+
+        assert_eq!(ctrl.target_nr_cpus(&input(2.0, 300.0, 0.0)), 4);
+        assert_eq!(ctrl.integral, 0.0);
+
+        // Once time passes, the derivative is taken against the last error.
+        assert_eq!(ctrl.target_nr_cpus(&input(2.0, 400.0, 1.0)), 9);
+        assert_eq!(ctrl.integral, 3.0);
+    }
+
+    #[test]
+    fn test_pid_anti_windup() {
+        let mut ctrl = pid(0.0, 2.0, 0.0);
+        for _ in 0..100 {
+            ctrl.target_nr_cpus(&input(2.0, 1000.0, 1.0));
+        }
+        // The integral is limited to what can take the layer to its max.
+        assert_eq!(ctrl.integral, 4.0);
+        assert_eq!(ctrl.target_nr_cpus(&input(2.0, 1000.0, 1.0)), 10);
+
+        // And thus unwinds as soon as the latency drops below the target.
+        assert_eq!(ctrl.target_nr_cpus(&input(2.0, 50.0, 1.0)), 9);
+        assert_eq!(ctrl.integral, 3.5);
+        for _ in 0..100 {
+            ctrl.target_nr_cpus(&input(2.0, 0.0, 1.0));
+        }
+        assert_eq!(ctrl.integral, -4.0);
+    }
+
+    #[test]
+    fn test_pid_clamp_to_util() {
+        // However low the latency, the target doesn't drop below the
+        // current utilization.
+        let mut ctrl = pid(10.0, 1.0, 0.0);
+        for _ in 0..10 {
+            assert_eq!(ctrl.target_nr_cpus(&input(2.5, 0.0, 1.0)), 3);
+        }
+        assert_eq!(ctrl.target_nr_cpus(&input(0.0, 0.0, 1.0)), 0);
+    }
+
+    #[test]
+    fn test_prop_share() {
+        let mut ctrl = PropShareController {
+            target_lat_us: 100.0,
+            headroom: 0.5,
+        };
+        // Below the target, only the headroom is added.
+        assert_eq!(ctrl.target_nr_cpus(&input(2.0, 50.0, 1.0)), 3);
+        assert_eq!(ctrl.target_nr_cpus(&input(2.0, 100.0, 1.0)), 3);
+        // Above, the demand is scaled by the latency ratio.
+        assert_eq!(ctrl.target_nr_cpus(&input(2.0, 200.0, 1.0)), 6);
+        assert_eq!(ctrl.target_nr_cpus(&input(0.0, 200.0, 1.0)), 0);
+
+        // Without a latency target, the utilization is all that matters.
+        let mut ctrl = PropShareController {
+            target_lat_us: 0.0,
+            headroom: 0.0,
+        };
+        assert_eq!(ctrl.target_nr_cpus(&input(2.5, 1000.0, 0.0)), 3);
+    }
+}
@@ -4,6 +4,7 @@
 // GNU General Public License version 2.
 mod config;
 mod layer_core_growth;
+mod layer_sizing;
 
 pub mod bpf_intf;
 
@@ -16,9 +17,15 @@ pub use config::LayerCommon;
 pub use config::LayerConfig;
 pub use config::LayerKind;
 pub use config::LayerMatch;
+pub use config::LayerMemPolicy;
 pub use config::LayerPlacement;
+pub use config::LayerSchedule;
+pub use config::LayerSlo;
 pub use config::LayerSpec;
 pub use layer_core_growth::LayerGrowthAlgo;
+pub use layer_sizing::LayerSizingController;
+pub use layer_sizing::LayerSizingInput;
+pub use layer_sizing::LayerSizingPolicy;
 use log::info;
 use scx_utils::Core;
 use scx_utils::Cpumask;
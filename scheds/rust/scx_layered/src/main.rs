@@ -4,7 +4,9 @@
 // GNU General Public License version 2.
 mod bpf_skel;
 mod stats;
+mod varlink;
 
+use std::cmp::Reverse;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::ffi::CString;
@@ -12,6 +14,10 @@ use std::fs;
 use std::io::Write;
 use std::mem::MaybeUninit;
 use std::ops::Sub;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
@@ -28,7 +34,9 @@ use anyhow::Context;
 use anyhow::Result;
 pub use bpf_skel::*;
 use clap::Parser;
+use crossbeam::channel::Receiver;
 use crossbeam::channel::RecvTimeoutError;
+use crossbeam::channel::Sender;
 use lazy_static::lazy_static;
 use libbpf_rs::MapCore as _;
 use libbpf_rs::OpenObject;
@@ -37,10 +45,13 @@ use log::debug;
 use log::info;
 use log::trace;
 use log::warn;
+use regex::Regex;
 use scx_layered::*;
 use scx_stats::prelude::*;
 use scx_utils::compat;
 use scx_utils::init_libbpf_logging;
+use scx_utils::intf_layout_check;
+use scx_utils::perf;
 use scx_utils::pm::{cpu_idle_resume_latency_supported, update_cpu_idle_resume_latency};
 use scx_utils::read_netdevs;
 use scx_utils::scx_enums;
@@ -61,14 +72,25 @@ use stats::LayerStats;
 use stats::StatsReq;
 use stats::StatsRes;
 use stats::SysStats;
+use stats::TaskAssignment;
+use stats::TaskAssignments;
+use varlink::CtlError;
+use varlink::CtlMsg;
+use varlink::CtlReq;
+use varlink::CtlRes;
+use varlink::VarlinkServer;
+use walkdir::WalkDir;
 
 const MAX_PATH: usize = bpf_intf::consts_MAX_PATH as usize;
 const MAX_COMM: usize = bpf_intf::consts_MAX_COMM as usize;
+const MAX_MATCH_TGIDS: usize = bpf_intf::consts_MAX_MATCH_TGIDS as usize;
+const MAX_CGROUP_REGEXES: usize = bpf_intf::consts_MAX_CGROUP_REGEXES as usize;
 const MAX_LAYER_WEIGHT: u32 = bpf_intf::consts_MAX_LAYER_WEIGHT;
 const MIN_LAYER_WEIGHT: u32 = bpf_intf::consts_MIN_LAYER_WEIGHT;
 const MAX_LAYER_MATCH_ORS: usize = bpf_intf::consts_MAX_LAYER_MATCH_ORS as usize;
 const MAX_LAYER_NAME: usize = bpf_intf::consts_MAX_LAYER_NAME as usize;
 const MAX_LAYERS: usize = bpf_intf::consts_MAX_LAYERS as usize;
+const MAX_QOS_TIERS: u32 = bpf_intf::consts_MAX_QOS_TIERS;
 const DEFAULT_LAYER_WEIGHT: u32 = bpf_intf::consts_DEFAULT_LAYER_WEIGHT;
 const USAGE_HALF_LIFE: u32 = bpf_intf::consts_USAGE_HALF_LIFE;
 const USAGE_HALF_LIFE_F64: f64 = USAGE_HALF_LIFE as f64 / 1_000_000_000.0;
@@ -82,8 +104,20 @@ const LAYER_USAGE_PROTECTED_PREEMPT: usize =
 const NR_LAYER_USAGES: usize = bpf_intf::layer_usage_NR_LAYER_USAGES as usize;
 
 const NR_GSTATS: usize = bpf_intf::global_stat_id_NR_GSTATS as usize;
+const GSTAT_ASSIGN_DROPPED: usize = bpf_intf::global_stat_id_GSTAT_ASSIGN_DROPPED as usize;
 const NR_LSTATS: usize = bpf_intf::layer_stat_id_NR_LSTATS as usize;
 const NR_LLC_LSTATS: usize = bpf_intf::llc_layer_stat_id_NR_LLC_LSTATS as usize;
+const LLC_LSTAT_LAT: usize = bpf_intf::llc_layer_stat_id_LLC_LSTAT_LAT as usize;
+const LLC_LSTAT_CNT: usize = bpf_intf::llc_layer_stat_id_LLC_LSTAT_CNT as usize;
+const LSTAT_SLO_MET: usize = bpf_intf::layer_stat_id_LSTAT_SLO_MET as usize;
+const LSTAT_SLO_MISS: usize = bpf_intf::layer_stat_id_LSTAT_SLO_MISS as usize;
+const NR_LAT_BUCKETS: usize = bpf_intf::consts_NR_LAT_BUCKETS as usize;
+/// Assignments kept for each reader of the "assignments" stats target.
+const MAX_ASSIGN_BACKLOG: usize = 65536;
+const NR_LPERFS: usize = bpf_intf::layer_perf_cnt_id_NR_LPERFS as usize;
+const LPERF_CYCLES: usize = bpf_intf::layer_perf_cnt_id_LPERF_CYCLES as usize;
+const LPERF_INSNS: usize = bpf_intf::layer_perf_cnt_id_LPERF_INSNS as usize;
+const LPERF_LLC_MISSES: usize = bpf_intf::layer_perf_cnt_id_LPERF_LLC_MISSES as usize;
 
 const NR_LAYER_MATCH_KINDS: usize = bpf_intf::layer_match_kind_NR_LAYER_MATCH_KINDS as usize;
 
@@ -98,6 +132,7 @@ lazy_static! {
                 comment: Some("tasks under system.slice or tasks with nice value > 0".into()),
                 cpuset: None,
                 template: None,
+                schedules: vec![],
                 matches: vec![
                     vec![LayerMatch::CgroupPrefix("system.slice/".into())],
                     vec![LayerMatch::NiceAbove(0)],
@@ -107,11 +142,14 @@ lazy_static! {
                     cpus_range: Some((0, 16)),
                     cpus_range_frac: None,
                     protected: false,
+                    confidential: false,
                     common: LayerCommon {
                         min_exec_us: 1000,
                         yield_ignore: 0.0,
                         preempt: false,
                         preempt_first: false,
+                        qos_tier: 0,
+                        preempt_budget: None,
                         exclusive: false,
                         allow_node_aligned: false,
                         skip_remote_node: false,
@@ -126,9 +164,15 @@ lazy_static! {
                         growth_algo: LayerGrowthAlgo::Sticky,
                         idle_resume_us: None,
                         perf: 1024,
+                        perf_min: 0,
+                        perf_max: 0,
                         nodes: vec![],
                         llcs: vec![],
                         placement: LayerPlacement::Standard,
+                        anti_affinity: vec![],
+                        sizing: LayerSizingPolicy::UtilRange,
+                        slo: None,
+                        mempolicy: LayerMemPolicy::None,
                     },
                 },
             },
@@ -137,6 +181,7 @@ lazy_static! {
                 comment: Some("tasks under workload.slice with nice value < 0".into()),
                 cpuset: None,
                 template: None,
+                schedules: vec![],
                 matches: vec![vec![
                     LayerMatch::CgroupPrefix("workload.slice/".into()),
                     LayerMatch::NiceBelow(0),
@@ -147,6 +192,8 @@ lazy_static! {
                         yield_ignore: 0.25,
                         preempt: true,
                         preempt_first: false,
+                        qos_tier: 0,
+                        preempt_budget: None,
                         exclusive: true,
                         allow_node_aligned: true,
                         skip_remote_node: false,
@@ -160,10 +207,16 @@ lazy_static! {
                         xllc_mig_min_us: 0.0,
                         growth_algo: LayerGrowthAlgo::Sticky,
                         perf: 1024,
+                        perf_min: 0,
+                        perf_max: 0,
                         idle_resume_us: None,
                         nodes: vec![],
                         llcs: vec![],
                         placement: LayerPlacement::Standard,
+                        anti_affinity: vec![],
+                        sizing: LayerSizingPolicy::UtilRange,
+                        slo: None,
+                        mempolicy: LayerMemPolicy::None,
                     },
                 },
             },
@@ -172,6 +225,7 @@ lazy_static! {
                 comment: Some("stress-ng test layer".into()),
                 cpuset: None,
                 template: None,
+                schedules: vec![],
                 matches: vec![
                     vec![LayerMatch::CommPrefix("stress-ng".into()),],
                     vec![LayerMatch::PcommPrefix("stress-ng".into()),]
@@ -180,12 +234,15 @@ lazy_static! {
                     cpus_range: None,
                     util_range: (0.2, 0.8),
                     protected: false,
+                    confidential: false,
                     cpus_range_frac: None,
                     common: LayerCommon {
                         min_exec_us: 800,
                         yield_ignore: 0.0,
                         preempt: true,
                         preempt_first: false,
+                        qos_tier: 0,
+                        preempt_budget: None,
                         exclusive: false,
                         allow_node_aligned: false,
                         skip_remote_node: false,
@@ -199,10 +256,16 @@ lazy_static! {
                         xllc_mig_min_us: 0.0,
                         growth_algo: LayerGrowthAlgo::Topo,
                         perf: 1024,
+                        perf_min: 0,
+                        perf_max: 0,
                         idle_resume_us: None,
                         nodes: vec![],
                         llcs: vec![],
                         placement: LayerPlacement::Standard,
+                        anti_affinity: vec![],
+                        sizing: LayerSizingPolicy::UtilRange,
+                        slo: None,
+                        mempolicy: LayerMemPolicy::None,
                     },
                 },
             },
@@ -211,18 +274,22 @@ lazy_static! {
                 comment: Some("the rest".into()),
                 cpuset: None,
                 template: None,
+                schedules: vec![],
                 matches: vec![vec![]],
                 kind: LayerKind::Grouped {
                     cpus_range: None,
                     util_range: (0.5, 0.6),
                     util_includes_open_cputime: true,
                     protected: false,
+                    confidential: false,
                     cpus_range_frac: None,
                     common: LayerCommon {
                         min_exec_us: 200,
                         yield_ignore: 0.0,
                         preempt: false,
                         preempt_first: false,
+                        qos_tier: 0,
+                        preempt_budget: None,
                         exclusive: false,
                         allow_node_aligned: false,
                         skip_remote_node: false,
@@ -236,10 +303,16 @@ lazy_static! {
                         xllc_mig_min_us: 100.0,
                         growth_algo: LayerGrowthAlgo::Linear,
                         perf: 1024,
+                        perf_min: 0,
+                        perf_max: 0,
                         idle_resume_us: None,
                         nodes: vec![],
                         llcs: vec![],
                         placement: LayerPlacement::Standard,
+                        anti_affinity: vec![],
+                        sizing: LayerSizingPolicy::UtilRange,
+                        slo: None,
+                        mempolicy: LayerMemPolicy::None,
                     },
                 },
             },
@@ -291,10 +364,23 @@ lazy_static! {
 ///   which are under that particular cgroup while "TOP/CHILD" also matches
 ///   tasks under "TOP/CHILD0/" or "TOP/CHILD1/".
 ///
+/// - CgroupRegex: Matches the cgroup path against a regular expression.
+///   The path is in the same form as for CgroupPrefix, e.g.
+///   "system.slice/foo.service/". As regexes can't be evaluated in BPF, the
+///   cgroup hierarchy is scanned in userspace after cgroups are created,
+///   removed or renamed, and tasks in newly matching cgroups may stay in
+///   their current layers until the next --interval.
+///
 /// - CommPrefix: Matches the task's comm prefix.
 ///
+/// - CommGlob: Matches the task's comm against a glob pattern where '*'
+///   matches any string and '?' any single character, e.g. "kworker/*".
+///
 /// - PcommPrefix: Matches the task's thread group leader's comm prefix.
 ///
+/// - PcommGlob: Matches the task's thread group leader's comm against a
+///   glob pattern.
+///
 /// - NiceAbove: Matches if the task's nice value is greater than the
 ///   pattern.
 ///
@@ -312,8 +398,15 @@ lazy_static! {
 ///
 /// - PPIDEquals: Matches if the task's ppid matches the value.
 ///
+/// - AncestorCommPrefix: Matches if the thread group leader comm of any of
+///   the task's ancestors, up to 16 levels above, starts with the value.
+///   Useful to catch everything started by e.g. a game launcher.
+///
 /// - TGIDEquals: Matches if the task's tgid matches the value.
 ///
+/// - TGIDIn: Matches if the task's tgid is one of the values. Up to 16
+///   values are supported.
+///
 /// - NSPIDEquals: Matches if the task's namespace id and pid matches the values.
 ///
 /// - NSEquals: Matches if the task's namespace id matches the values.
@@ -355,6 +448,35 @@ lazy_static! {
 /// have a cgroup suffix rule that we use to find the relevant cgroups in the system. For each
 /// such cgroup, we copy the layer config and add a matching rule that matches just this cgroup.
 ///
+/// Inheritance
+/// -----------
+///
+/// A layer can inherit the configuration of another one by naming it in
+/// "inherits" and then only needs to specify what differs. Layers marked
+/// "abstract" are only used as bases and aren't instantiated, so the
+/// common configuration can be kept in one place:
+///
+///   [
+///     {
+///       "name": "interactive",
+///       "abstract": true,
+///       "kind": { "Confined": { "util_range": [0.5, 0.7], "preempt": true } }
+///     },
+///     {
+///       "name": "games",
+///       "inherits": "interactive",
+///       "matches": [[{ "CgroupPrefix": "games.slice/" }]],
+///       "kind": { "Confined": { "cpus_range": [4, 16] } }
+///     },
+///     ...
+///   ]
+///
+/// The fields of the layer replace the inherited ones as a whole, except for
+/// "kind", whose fields are merged if both are of the same kind. E.g. above,
+/// "games" is a Confined layer with util_range, preempt and cpus_range set.
+/// A base can inherit from another base and can be defined anywhere in the
+/// configuration, including a different file, as long as its name is
+/// unique.
 ///
 /// Policies
 /// ========
@@ -390,6 +512,18 @@ lazy_static! {
 ///   layers. Tasks in this group will spill into occupied CPUs if there are
 ///   no unoccupied idle CPUs.
 ///
+/// Confined and Grouped layers also take the following option:
+///
+/// - confidential: If true, the layer's cores are exclusive to it. On top
+///   of the layer being "protected", i.e. its CPUs not pulling the tasks
+///   of other layers from their queues even when idle, the tasks of other
+///   layers are never placed on the layer's CPUs, can't preempt the
+///   layer's tasks and aren't run from the fallback and antistall queues
+///   by the layer's CPUs. As CPUs are allocated in whole cores, this also
+///   covers the SMT siblings. The only tasks of other layers which still
+///   run on the layer's CPUs are the ones which can't run anywhere else,
+///   i.e. per-CPU kthreads and tasks affined to the layer's CPUs only.
+///
 /// All layers take the following options:
 ///
 /// - min_exec_us: Minimum execution time in microseconds. Whenever a task
@@ -410,6 +544,18 @@ lazy_static! {
 /// - preempt_first: If true, tasks in the layer will try to preempt tasks
 ///   in their previous CPUs before trying to find idle CPUs.
 ///
+/// - qos_tier: QoS tier of the layer, 0 by default and up to 15. Preempting
+///   layers can only preempt tasks of layers in the same or lower tiers and,
+///   if those are preempting layers too, only in lower tiers. This allows
+///   setting up more than two levels of preemption, e.g. a latency critical
+///   layer in tier 2 preempting an interactive layer in tier 1 which in turn
+///   preempts batch layers in tier 0. The DSQs of open and grouped layers
+///   are also consumed from the highest tier down.
+///
+/// - preempt_budget: Maximum number of busy CPUs the layer's tasks can
+///   preempt per second. Up to a second's worth of unused budget is carried
+///   over. Preemptions of idle CPUs aren't charged. Unlimited if not set.
+///
 /// - exclusive: If true, tasks in the layer will occupy the whole core. The
 ///   other logical CPUs sharing the same core will be kept idle. This isn't
 ///   a hard guarantee, so don't depend on it for security purposes.
@@ -447,32 +593,124 @@ lazy_static! {
 /// - growth_algo: When a layer is allocated new CPUs different algorithms can
 ///   be used to determine which CPU should be allocated next. The default
 ///   algorithm is a "sticky" algorithm that attempts to spread layers evenly
-///   across cores.
+///   across cores. "Compact" keeps a layer within as few LLCs and NUMA nodes
+///   as possible, which suits memory-bandwidth-sensitive layers.
 ///
 /// - perf: CPU performance target. 0 means no configuration. A value
 ///   between 1 and 1024 indicates the performance level CPUs running tasks
 ///   in this layer are configured to using scx_bpf_cpuperf_set().
 ///
+/// - perf_min, perf_max: Floor and cap, between 1 and 1024, for the CPU
+///   performance target while a task in this layer is running. 0 means no
+///   limit. The target is "perf" if set or the CPU's current target
+///   otherwise, and the previous target is restored when the task stops
+///   running. Only the scx_bpf_cpuperf_set() target is clamped, uclamp
+///   isn't implemented. E.g. gaming layers can use a perf_min of 1024 and
+///   background layers a low perf_max.
+///
 /// - idle_resume_us: Sets the idle resume QoS value. CPU idle time governors are expected to
 ///   regard the minimum of the global (effective) CPU latency limit and the effective resume
 ///   latency constraint for the given CPU as the upper limit for the exit latency of the idle
 ///   states. See the latest kernel docs for more details:
 ///   https://www.kernel.org/doc/html/latest/admin-guide/pm/cpuidle.html
 ///
+/// - anti_affinity: Names of the layers which must not share a physical
+///   core with this layer through SMT. The relation is symmetric. CPUs
+///   don't pick the tasks of a layer while the SMT sibling is running an
+///   anti-affine one. If both siblings pick anti-affine tasks at the same
+///   time, the task which started later is kicked off its CPU as soon as
+///   it starts running, so the two only overlap for that long. The core
+///   is handed over rather than shared: so that e.g. tasks pinned to a
+///   CPU aren't starved by an anti-affine layer hogging its sibling, a CPU
+///   which has been kept from running a layer for longer than the other
+///   layer's slice makes the sibling yield the core at the end of its
+///   slice.
+///
+/// - sizing: How the number of CPUs of a Confined or Grouped layer is
+///   determined, always bounded by cpus_range or cpus_range_frac.
+///   "UtilRange", the default, keeps the per-CPU utilization within
+///   util_range. {"Pid": {"target_lat_us": 500}} runs a PID controller
+///   on the layer's queueing delay on top of the CPUs needed to stay
+///   below util_range's upper bound. "kp", "ki" and "kd" override the
+///   default gains of 1.0, 0.5 and 0. {"PropShare": {"headroom": 0.2,
+///   "target_lat_us": 500}} allocates the utilization plus headroom,
+///   scaled up by how much the queueing delay exceeds the target if set.
+///
+/// - slo: Latency SLO of the layer, e.g. {"lat_us": 1000, "pct": 99.0,
+///   "alert_after": 10}. The delays between the layer's tasks becoming
+///   runnable and running are collected into a histogram and the
+///   percentage of them within lat_us, which should be at least pct
+///   (default 99.0), is reported along with the percentiles in the
+///   stats. If "wakeup_only" is set, only wakeups are counted instead of
+///   also the waits after preemption. If "alert_after" is set, a warning
+///   is logged when the layer has been out of the SLO for that many
+///   consecutive --interval's.
+///
 /// - nodes: If set the layer will use the set of NUMA nodes for scheduling
 ///   decisions. If unset then all available NUMA nodes will be used. If the
 ///   llcs value is set the cpuset of NUMA nodes will be or'ed with the LLC
-///   config.
+///   config. The CPUs allocated to Confined and Grouped layers are
+///   restricted to the resulting cpuset.
 ///
 /// - llcs: If set the layer will use the set of LLCs (last level caches)
 ///   for scheduling decisions. If unset then all LLCs will be used. If
 ///   the nodes value is set the cpuset of LLCs will be or'ed with the nodes
 ///   config.
 ///
+/// - mempolicy: Keep the memory of the layer's tasks on the NUMA nodes
+///   selected by nodes and llcs. "MigrateOnce" moves the memory of each
+///   process joining the layer to the nodes with migrate_pages(2) but
+///   doesn't bind its future allocations, which may still land on other
+///   nodes. "CgroupMems" instead sets cpuset.mems of the process's cgroup, which
+///   also binds future allocations but applies to the whole cgroup. It is
+///   only applied to the cgroups matched by an OR block of the layer which
+///   matches only on cgroups, which the layer must have, and never to the
+///   root cgroup. The policy is
+///   applied once per process when its threads join the layer, from a
+///   separate thread, and isn't reverted when they leave. "None", the
+///   default, leaves memory placement alone.
+///
 ///
 /// Similar to matches, adding new policies and extending existing ones
 /// should be relatively straightforward.
 ///
+/// Schedules
+/// ---------
+///
+/// A layer spec can have a list of "schedules" next to "kind" which
+/// override the layer's util_range, cpus_range and weight while they're
+/// active. The following gives a batch layer more CPUs at night and while
+/// /run/batch-boost exists.
+///
+///   "schedules": [
+///     {
+///       "time": ["22:00", "06:00"],
+///       "cpus_range": [8, 32],
+///       "weight": 500,
+///       "ramp_secs": 600
+///     },
+///     {
+///       "trigger_file": "/run/batch-boost",
+///       "cpus_range": [16, 32]
+///     }
+///   ]
+///
+/// A schedule is active while all of its conditions are met:
+///
+/// - days: Days of the week, e.g. ["Sat", "Sun"], in local time.
+///
+/// - time: Local-time window as ["HH:MM", "HH:MM"]. Wraps around midnight
+///   if the end is before the start.
+///
+/// - trigger_file: Path which must exist, which lets external events flip
+///   the schedule without rewriting the config.
+///
+/// The first active schedule is used. Overrides which aren't set keep the
+/// values from "kind". On transitions, the values are moved linearly over
+/// the "ramp_secs" of the schedule being entered, or left when going back
+/// to the spec's values. util_range and cpus_range can't be set for Open
+/// layers.
+///
 /// Configuration example and running scx_layered
 /// =============================================
 ///
@@ -497,6 +735,36 @@ lazy_static! {
 ///   ...
 ///   $ scx_layered f:example.json
 ///
+/// Reloading the Configuration
+/// ===========================
+///
+/// Sending SIGHUP makes scx_layered re-read the layer specs, including the
+/// files and re-expanding templates. The layers are matched by name. New
+/// layers are added, the ones which are gone are removed and the rest are
+/// updated in place without reloading the scheduler. Tasks are re-matched on
+/// their next wakeup or enqueue. If the new specs fail to parse or verify,
+/// the current ones are kept.
+///
+/// Control API
+/// ===========
+///
+/// With `--varlink PATH`, scx_layered serves the com.sched-ext.layered
+/// varlink interface on the unix socket at PATH. It can list the layers with
+/// their utilization and CPUs, list the threads in a layer, move a thread or
+/// process into a layer regardless of the matches, and create and delete
+/// layers. The full interface can be retrieved with
+/// org.varlink.service.GetInterfaceDescription.
+///
+///   ```bash
+///   $ varlinkctl call /run/scx/layered.varlink com.sched-ext.layered.MovePid \
+///       '{"pid": 1234, "layer": "immediate"}'
+///   ```
+///
+/// Layers are created and deleted in place like on a SIGHUP reload. Those
+/// layer changes live only in memory and are lost on the next SIGHUP, which
+/// re-reads the specs from the command line. The thread and process
+/// assignments are kept across reloads as long as the layer still exists.
+///
 /// Monitoring Statistics
 /// =====================
 ///
@@ -523,6 +791,20 @@ lazy_static! {
 ///
 /// Per-layer statistics: see [`LayerStats`]
 ///
+/// Task to layer assignments: see [`TaskAssignments`]
+///
+/// Whenever a task is assigned to a layer, or to the same layer through a
+/// different match, and for all tasks after the layer specs are reloaded,
+/// the task, the layer and the match which fired are reported through the
+/// "assignments" stats target, e.g. for profilers to label samples by
+/// layer. Each reader gets the assignments since its previous read,
+/// stats_subscribe streams them. `--monitor-assignments` prints them:
+///
+///   ```bash
+///   $ scx_layered --monitor-assignments
+///      4121    4121 stress           normal -> batch [0] [{"CgroupPrefix":"system.slice/"}]
+///   ```
+///
 #[derive(Debug, Parser)]
 #[command(verbatim_doc_comment)]
 struct Opts {
@@ -594,6 +876,11 @@ struct Opts {
     #[clap(long)]
     monitor: Option<f64>,
 
+    /// Print the task to layer assignments as they happen. Scheduler is not
+    /// launched.
+    #[clap(long)]
+    monitor_assignments: bool,
+
     /// Run with example layer specifications (useful for e.g. CI pipelines)
     #[clap(long)]
     run_example: bool,
@@ -634,6 +921,13 @@ struct Opts {
     #[clap(long, default_value = "false")]
     enable_gpu_support: bool,
 
+    /// Collect per-layer CPU cycles, instructions and LLC misses using
+    /// per-CPU hardware perf events and report IPC and LLC misses per kilo
+    /// instructions in the layer stats. Counters which can't be opened, e.g.
+    /// on VMs without PMU access, are skipped with a warning.
+    #[clap(long, default_value = "false")]
+    enable_perf_counters: bool,
+
     /// Gpu Kprobe Level
     /// The value set here determines how agressive
     /// the kprobes enabled on gpu driver functions are.
@@ -666,6 +960,11 @@ struct Opts {
     #[clap(long)]
     help_stats: bool,
 
+    /// Serve the layer control API over varlink on the unix socket at this
+    /// path, e.g. /run/scx/layered.varlink. See --help.
+    #[clap(long)]
+    varlink: Option<String>,
+
     /// Layer specification. See --help.
     specs: Vec<String>,
 
@@ -761,6 +1060,102 @@ fn llcmask_from_llcs(llcs: &BTreeMap<usize, Arc<Llc>>) -> usize {
     mask
 }
 
+/// The NUMA nodes a layer is restricted to by its nodes and llcs, 0 if
+/// it isn't.
+fn layer_mem_nodes(common: &LayerCommon, topo: &Topology) -> u64 {
+    let mut mask = nodemask_from_nodes(&common.nodes) as u64;
+    for (node_id, node) in &topo.nodes {
+        if node.llcs.keys().any(|llc_id| common.llcs.contains(llc_id)) {
+            mask |= 1 << node_id;
+        }
+    }
+    mask
+}
+
+#[derive(Debug)]
+struct MemPolicyReq {
+    tgid: u32,
+    layer: String,
+    matches: Vec<Vec<LayerMatch>>,
+    policy: LayerMemPolicy,
+    nodes: u64,
+    all_nodes: u64,
+}
+
+impl MemPolicyReq {
+    fn apply(&self) -> Result<()> {
+        match self.policy {
+            LayerMemPolicy::None => Ok(()),
+            LayerMemPolicy::MigrateOnce => {
+                let from = [(self.all_nodes & !self.nodes) as libc::c_ulong];
+                let to = [self.nodes as libc::c_ulong];
+                if from[0] == 0 {
+                    return Ok(());
+                }
+                let ret = unsafe {
+                    libc::syscall(
+                        libc::SYS_migrate_pages,
+                        self.tgid as libc::c_long,
+                        (libc::c_ulong::BITS + 1) as libc::c_ulong,
+                        from.as_ptr(),
+                        to.as_ptr(),
+                    )
+                };
+                if ret < 0 {
+                    bail!("migrate_pages failed ({})", std::io::Error::last_os_error());
+                }
+                Ok(())
+            }
+            LayerMemPolicy::CgroupMems => {
+                let cgrps = fs::read_to_string(format!("/proc/{}/cgroup", self.tgid))?;
+                let cgrp = cgrps
+                    .lines()
+                    .find_map(|line| line.strip_prefix("0::"))
+                    .ok_or_else(|| anyhow!("not on cgroup2"))?;
+                let cgrp = cgrp.trim_start_matches('/');
+                if cgrp.is_empty() {
+                    bail!("in the root cgroup which doesn't have cpuset.mems");
+                }
+                // Other tasks in the cgroup would be bound too.
+                if !cgroup_matched_whole(&self.matches, &format!("{}/", cgrp))? {
+                    bail!(
+                        "cgroup {:?} isn't matched by the layer's cgroup matches alone",
+                        cgrp
+                    );
+                }
+                let mems: Vec<String> = (0..u64::BITS)
+                    .filter(|node| self.nodes & (1 << node) != 0)
+                    .map(|node| node.to_string())
+                    .collect();
+                let path = Path::new("/sys/fs/cgroup").join(cgrp).join("cpuset.mems");
+                fs::write(&path, mems.join(","))
+                    .with_context(|| format!("Failed to write {:?}", &path))
+            }
+        }
+    }
+}
+
+/// Apply the MemPolicyReq's off the scheduling loop as migrating the memory
+/// of large processes can take a while.
+fn launch_mempolicy_worker() -> Sender<MemPolicyReq> {
+    let (tx, rx) = crossbeam::channel::unbounded::<MemPolicyReq>();
+    std::thread::spawn(move || {
+        for req in rx.iter() {
+            match req.apply() {
+                Ok(()) => debug!(
+                    "Applied {:?} mempolicy of layer {:?} to {}",
+                    req.policy, req.layer, req.tgid
+                ),
+                Err(e) => debug!(
+                    "Failed to apply {:?} mempolicy of layer {:?} to {} ({:#})",
+                    req.policy, req.layer, req.tgid, e
+                ),
+            }
+        }
+    });
+    tx
+}
+
 fn read_cpu_ctxs(skel: &BpfSkel) -> Result<Vec<bpf_intf::cpu_ctx>> {
     let mut cpu_ctxs = vec![];
     let cpu_ctxs_vec = skel
@@ -783,15 +1178,17 @@ struct BpfStats {
     lstats: Vec<Vec<u64>>,
     lstats_sums: Vec<u64>,
     llc_lstats: Vec<Vec<Vec<u64>>>, // [layer][llc][stat]
+    lat_hists: Vec<Vec<u64>>,       // [layer][bucket]
 }
 
 impl BpfStats {
     fn read(skel: &BpfSkel, cpu_ctxs: &[bpf_intf::cpu_ctx]) -> Self {
-        let nr_layers = skel.maps.rodata_data.nr_layers as usize;
+        let nr_layers = skel.maps.bss_data.nr_layers as usize;
         let nr_llcs = skel.maps.rodata_data.nr_llcs as usize;
         let mut gstats = vec![0u64; NR_GSTATS];
         let mut lstats = vec![vec![0u64; NR_LSTATS]; nr_layers];
         let mut llc_lstats = vec![vec![vec![0u64; NR_LLC_LSTATS]; nr_llcs]; nr_layers];
+        let mut lat_hists = vec![vec![0u64; NR_LAT_BUCKETS]; nr_layers];
 
         for cpu in 0..*NR_CPUS_POSSIBLE {
             for stat in 0..NR_GSTATS {
@@ -801,6 +1198,9 @@ impl BpfStats {
                 for stat in 0..NR_LSTATS {
                     lstats[layer][stat] += cpu_ctxs[cpu].lstats[layer][stat];
                 }
+                for bucket in 0..NR_LAT_BUCKETS {
+                    lat_hists[layer][bucket] += cpu_ctxs[cpu].lat_hist[layer][bucket];
+                }
             }
         }
 
@@ -840,8 +1240,28 @@ impl BpfStats {
             lstats,
             lstats_sums,
             llc_lstats,
+            lat_hists,
+        }
+    }
+}
+
+/// Upper bound in usecs of the lat_hist bucket which the @pct percentile
+/// falls into. The last bucket has no upper bound and reports its lower
+/// bound instead.
+fn lat_hist_pct_us(hist: &[u64], pct: f64) -> u64 {
+    let total: u64 = hist.iter().sum();
+    if total == 0 {
+        return 0;
+    }
+    let target = (total as f64 * pct / 100.0).ceil() as u64;
+    let mut seen = 0;
+    for (bucket, cnt) in hist.iter().enumerate() {
+        seen += cnt;
+        if seen >= target.max(1) {
+            return 1 << bucket.min(NR_LAT_BUCKETS - 2);
         }
     }
+    1 << (NR_LAT_BUCKETS - 2)
 }
 
 impl<'a, 'b> Sub<&'b BpfStats> for &'a BpfStats {
@@ -875,6 +1295,12 @@ impl<'a, 'b> Sub<&'b BpfStats> for &'a BpfStats {
                         .collect()
                 })
                 .collect(),
+            lat_hists: self
+                .lat_hists
+                .iter()
+                .zip(rhs.lat_hists.iter())
+                .map(|(l, r)| vec_sub(l, r))
+                .collect(),
         }
     }
 }
@@ -884,6 +1310,8 @@ struct Stats {
     at: Instant,
     elapsed: Duration,
     nr_layers: usize,
+    /// The per-layer counters are indexed by layer, see read_layer_names().
+    layer_names: Vec<String>,
     nr_layer_tasks: Vec<usize>,
     nr_nodes: usize,
 
@@ -904,6 +1332,27 @@ struct Stats {
 }
 
 impl Stats {
+    /// The BPF layer names by index. The per-layer counters can only be
+    /// compared while these stay the same, i.e. until a spec reload adds,
+    /// removes or moves layers.
+    fn read_layer_names(skel: &BpfSkel) -> Vec<String> {
+        let nr_layers = skel.maps.bss_data.nr_layers as usize;
+        skel.maps
+            .bss_data
+            .layers
+            .iter()
+            .take(nr_layers)
+            .map(|layer| {
+                layer
+                    .name
+                    .iter()
+                    .take_while(|&&c| c != 0)
+                    .map(|&c| c as u8 as char)
+                    .collect()
+            })
+            .collect()
+    }
+
     fn read_layer_usages(cpu_ctxs: &[bpf_intf::cpu_ctx], nr_layers: usize) -> Vec<Vec<u64>> {
         let mut layer_usages = vec![vec![0u64; NR_LAYER_USAGES]; nr_layers];
 
@@ -919,7 +1368,7 @@ impl Stats {
     }
 
     fn new(skel: &mut BpfSkel, proc_reader: &procfs::ProcReader) -> Result<Self> {
-        let nr_layers = skel.maps.rodata_data.nr_layers as usize;
+        let nr_layers = skel.maps.bss_data.nr_layers as usize;
         let cpu_ctxs = read_cpu_ctxs(skel)?;
         let bpf_stats = BpfStats::read(skel, &cpu_ctxs);
         let nr_nodes = skel.maps.rodata_data.nr_nodes as usize;
@@ -928,6 +1377,7 @@ impl Stats {
             at: Instant::now(),
             elapsed: Default::default(),
             nr_layers,
+            layer_names: Self::read_layer_names(skel),
             nr_layer_tasks: vec![0; nr_layers],
             nr_nodes,
 
@@ -955,6 +1405,12 @@ impl Stats {
         now: Instant,
         cur_processing_dur: Duration,
     ) -> Result<()> {
+        // The layers changed, start over.
+        if Self::read_layer_names(skel) != self.layer_names {
+            *self = Self::new(skel, proc_reader)?;
+            return Ok(());
+        }
+
         let elapsed = now.duration_since(self.at);
         let elapsed_f64 = elapsed.as_secs_f64();
         let cpu_ctxs = read_cpu_ctxs(skel)?;
@@ -1015,6 +1471,7 @@ impl Stats {
             at: now,
             elapsed,
             nr_layers: self.nr_layers,
+            layer_names: std::mem::take(&mut self.layer_names),
             nr_layer_tasks,
             nr_nodes: self.nr_nodes,
 
@@ -1054,6 +1511,107 @@ struct Layer {
     nr_llc_cpus: Vec<usize>,
     cpus: Cpumask,
     allowed_cpus: Cpumask,
+
+    /// Index of the active LayerSpec::schedules entry.
+    schedule: Option<usize>,
+    sizing_ramp: Option<SizingRamp>,
+    sizing_ctl: Box<dyn LayerSizingController>,
+    /// Number of consecutive intervals the layer has been out of its SLO.
+    slo_out_intvs: u32,
+    /// NUMA nodes the layer's mempolicy keeps memory on.
+    mem_nodes: u64,
+    /// Fractional preemption left over from the last budget refill.
+    preempt_budget_frac: f64,
+}
+
+/// The layer knobs which can be overridden by LayerSpec::schedules.
+#[derive(Clone, Copy, Debug)]
+struct LayerSizing {
+    util_range: (f64, f64),
+    cpus_range: (usize, usize),
+    weight: u32,
+}
+
+impl LayerSizing {
+    fn new(kind: &LayerKind, nr_cpus: usize) -> Result<Self> {
+        let (util_range, cpus_range) = match kind {
+            LayerKind::Confined {
+                util_range,
+                cpus_range,
+                cpus_range_frac,
+                ..
+            }
+            | LayerKind::Grouped {
+                util_range,
+                cpus_range,
+                cpus_range_frac,
+                ..
+            } => (
+                *util_range,
+                resolve_cpus_pct_range(cpus_range, cpus_range_frac, nr_cpus)?,
+            ),
+            LayerKind::Open { .. } => ((0.0, 0.0), (0, 0)),
+        };
+        Ok(Self {
+            util_range,
+            cpus_range,
+            weight: kind.common().weight,
+        })
+    }
+
+    fn with_schedule(mut self, sched: &LayerSchedule) -> Self {
+        self.util_range = sched.util_range.unwrap_or(self.util_range);
+        self.cpus_range = sched.cpus_range.unwrap_or(self.cpus_range);
+        self.weight = sched.weight.unwrap_or(self.weight);
+        self
+    }
+
+    fn lerp(&self, to: &Self, frac: f64) -> Self {
+        let f = |from: f64, to: f64| from + (to - from) * frac;
+        let u = |from: usize, to: usize| f(from as f64, to as f64).round() as usize;
+        Self {
+            util_range: (
+                f(self.util_range.0, to.util_range.0),
+                f(self.util_range.1, to.util_range.1),
+            ),
+            cpus_range: (
+                u(self.cpus_range.0, to.cpus_range.0),
+                u(self.cpus_range.1, to.cpus_range.1),
+            ),
+            weight: f(self.weight as f64, to.weight as f64).round() as u32,
+        }
+    }
+
+    fn apply(&self, kind: &mut LayerKind) {
+        match kind {
+            LayerKind::Confined {
+                util_range,
+                cpus_range,
+                cpus_range_frac,
+                ..
+            }
+            | LayerKind::Grouped {
+                util_range,
+                cpus_range,
+                cpus_range_frac,
+                ..
+            } => {
+                *util_range = self.util_range;
+                *cpus_range = Some(self.cpus_range);
+                *cpus_range_frac = None;
+            }
+            LayerKind::Open { .. } => {}
+        }
+        kind.common_mut().weight = self.weight;
+    }
+}
+
+#[derive(Debug)]
+struct SizingRamp {
+    from: LayerSizing,
+    to: LayerSizing,
+    started_at: Instant,
+    dur: Duration,
 }
 
 fn get_kallsyms_addr(sym_name: &str) -> Result<u64> {
@@ -1174,6 +1732,7 @@ impl Layer {
         }
 
         let layer_growth_algo = kind.common().growth_algo.clone();
+        let sizing_ctl = kind.common().sizing.controller();
 
         debug!(
             "layer: {} algo: {:?} core order: {:?}",
@@ -1193,6 +1752,13 @@ impl Layer {
             nr_llc_cpus: vec![0; topo.all_llcs.len()],
             cpus: Cpumask::new(),
             allowed_cpus,
+
+            schedule: None,
+            sizing_ramp: None,
+            sizing_ctl,
+            slo_out_intvs: 0,
+            mem_nodes: layer_mem_nodes(spec.kind.common(), topo),
+            preempt_budget_frac: 0.0,
         })
     }
 
@@ -1245,7 +1811,17 @@ impl Layer {
 struct Scheduler<'a> {
     skel: BpfSkel<'a>,
     struct_ops: Option<libbpf_rs::Link>,
+    opts: &'a Opts,
     layer_specs: Vec<LayerSpec>,
+    disable_topology: bool,
+    /// Layer assignments made through the control API, by tid or tgid.
+    pid_layers: BTreeMap<u32, String>,
+    cpumasks_stale: bool,
+    cgroup_regexes: Vec<Regex>,
+    cgroup_regex_cgrps: BTreeMap<u64, u64>,
+    /// The BPF cgroup_gen when cgroup_regex_cgrps was last refreshed.
+    cgroup_regex_gen: Option<u64>,
+    _perf_event_fds: Vec<OwnedFd>,
 
     sched_intv: Duration,
     layer_refresh_intv: Duration,
@@ -1263,250 +1839,361 @@ struct Scheduler<'a> {
     topo: Arc<Topology>,
     netdevs: BTreeMap<String, NetDev>,
     stats_server: StatsServer<StatsReq, StatsRes>,
+    assign_rb: libbpf_rs::RingBuffer<'static>,
+    assign_rx: Receiver<bpf_intf::task_assign_event>,
+    /// GSTAT_ASSIGN_DROPPED as of the last relay_task_assignments().
+    assign_rb_dropped: u64,
+    mempolicy_tx: Option<Sender<MemPolicyReq>>,
+    /// The layer whose mempolicy was last applied to each tgid.
+    mempolicy_tgids: HashMap<u32, usize>,
 }
 
 impl<'a> Scheduler<'a> {
-    fn init_layers(skel: &mut OpenBpfSkel, specs: &[LayerSpec], topo: &Topology) -> Result<()> {
-        skel.maps.rodata_data.nr_layers = specs.len() as u32;
-        let mut perf_set = false;
-
-        let mut layer_iteration_order = (0..specs.len()).collect::<Vec<_>>();
-        let mut layer_weights: Vec<usize> = vec![];
-
-        for (spec_i, spec) in specs.iter().enumerate() {
-            let layer = &mut skel.maps.bss_data.layers[spec_i];
-
-            for (or_i, or) in spec.matches.iter().enumerate() {
-                for (and_i, and) in or.iter().enumerate() {
-                    let mt = &mut layer.matches[or_i].matches[and_i];
+    /// Fill @layer according to @spec. Only touches the configuration fields
+    /// so that it can also be used to update a live layer on spec reload.
+    fn init_layer(
+        layer: &mut types::layer,
+        spec: &LayerSpec,
+        topo: &Topology,
+        cgroup_regexes: &[String],
+    ) -> Result<()> {
+        for (or_i, or) in spec.matches.iter().enumerate() {
+            for (and_i, and) in or.iter().enumerate() {
+                let mt = &mut layer.matches[or_i].matches[and_i];
 
-                    // Rules are allowlist-based by default
-                    mt.exclude.write(false);
+                // Rules are allowlist-based by default
+                mt.exclude.write(false);
 
-                    match and {
-                        LayerMatch::CgroupPrefix(prefix) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_CGROUP_PREFIX as i32;
-                            copy_into_cstr(&mut mt.cgroup_prefix, prefix.as_str());
-                        }
-                        LayerMatch::CgroupSuffix(suffix) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_CGROUP_SUFFIX as i32;
-                            copy_into_cstr(&mut mt.cgroup_suffix, suffix.as_str());
-                        }
-                        LayerMatch::CgroupContains(substr) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_CGROUP_CONTAINS as i32;
-                            copy_into_cstr(&mut mt.cgroup_substr, substr.as_str());
-                        }
-                        LayerMatch::CommPrefix(prefix) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_COMM_PREFIX as i32;
-                            copy_into_cstr(&mut mt.comm_prefix, prefix.as_str());
-                        }
-                        LayerMatch::CommPrefixExclude(prefix) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_COMM_PREFIX as i32;
-                            mt.exclude.write(true);
-                            copy_into_cstr(&mut mt.comm_prefix, prefix.as_str());
-                        }
-                        LayerMatch::PcommPrefix(prefix) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_PCOMM_PREFIX as i32;
-                            copy_into_cstr(&mut mt.pcomm_prefix, prefix.as_str());
-                        }
-                        LayerMatch::PcommPrefixExclude(prefix) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_PCOMM_PREFIX as i32;
-                            mt.exclude.write(true);
-                            copy_into_cstr(&mut mt.pcomm_prefix, prefix.as_str());
-                        }
-                        LayerMatch::NiceAbove(nice) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_NICE_ABOVE as i32;
-                            mt.nice = *nice;
-                        }
-                        LayerMatch::NiceBelow(nice) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_NICE_BELOW as i32;
-                            mt.nice = *nice;
-                        }
-                        LayerMatch::NiceEquals(nice) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_NICE_EQUALS as i32;
-                            mt.nice = *nice;
-                        }
-                        LayerMatch::UIDEquals(user_id) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_USER_ID_EQUALS as i32;
-                            mt.user_id = *user_id;
-                        }
-                        LayerMatch::GIDEquals(group_id) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_GROUP_ID_EQUALS as i32;
-                            mt.group_id = *group_id;
-                        }
-                        LayerMatch::PIDEquals(pid) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_PID_EQUALS as i32;
-                            mt.pid = *pid;
-                        }
-                        LayerMatch::PPIDEquals(ppid) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_PPID_EQUALS as i32;
-                            mt.ppid = *ppid;
-                        }
-                        LayerMatch::TGIDEquals(tgid) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_TGID_EQUALS as i32;
-                            mt.tgid = *tgid;
-                        }
-                        LayerMatch::NSPIDEquals(nsid, pid) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_NSPID_EQUALS as i32;
-                            mt.nsid = *nsid;
-                            mt.pid = *pid;
-                        }
-                        LayerMatch::NSEquals(nsid) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_NS_EQUALS as i32;
-                            mt.nsid = *nsid as u64;
-                        }
-                        LayerMatch::CmdJoin(joincmd) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_SCXCMD_JOIN as i32;
-                            copy_into_cstr(&mut mt.comm_prefix, joincmd);
-                        }
-                        LayerMatch::IsGroupLeader(polarity) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_IS_GROUP_LEADER as i32;
-                            mt.is_group_leader.write(*polarity);
-                        }
-                        LayerMatch::IsKthread(polarity) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_IS_KTHREAD as i32;
-                            mt.is_kthread.write(*polarity);
-                        }
-                        LayerMatch::UsedGpuTid(polarity) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_USED_GPU_TID as i32;
-                            mt.used_gpu_tid.write(*polarity);
-                        }
-                        LayerMatch::UsedGpuPid(polarity) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_USED_GPU_PID as i32;
-                            mt.used_gpu_pid.write(*polarity);
-                        }
-                        LayerMatch::AvgRuntime(min, max) => {
-                            mt.kind = bpf_intf::layer_match_kind_MATCH_AVG_RUNTIME as i32;
-                            mt.min_avg_runtime_us = *min;
-                            mt.max_avg_runtime_us = *max;
+                match and {
+                    LayerMatch::CgroupPrefix(prefix) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_CGROUP_PREFIX as i32;
+                        copy_into_cstr(&mut mt.cgroup_prefix, prefix.as_str());
+                    }
+                    LayerMatch::CgroupSuffix(suffix) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_CGROUP_SUFFIX as i32;
+                        copy_into_cstr(&mut mt.cgroup_suffix, suffix.as_str());
+                    }
+                    LayerMatch::CgroupContains(substr) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_CGROUP_CONTAINS as i32;
+                        copy_into_cstr(&mut mt.cgroup_substr, substr.as_str());
+                    }
+                    LayerMatch::CgroupRegex(regex) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_CGROUP_REGEX as i32;
+                        mt.cgroup_regex_id = cgroup_regexes
+                            .iter()
+                            .position(|re| re == regex)
+                            .with_context(|| format!("unknown cgroup regex {:?}", regex))?
+                            as u32;
+                    }
+                    LayerMatch::CommPrefix(prefix) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_COMM_PREFIX as i32;
+                        copy_into_cstr(&mut mt.comm_prefix, prefix.as_str());
+                    }
+                    LayerMatch::CommPrefixExclude(prefix) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_COMM_PREFIX as i32;
+                        mt.exclude.write(true);
+                        copy_into_cstr(&mut mt.comm_prefix, prefix.as_str());
+                    }
+                    LayerMatch::CommGlob(pattern) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_COMM_GLOB as i32;
+                        copy_into_cstr(&mut mt.comm_prefix, pattern.as_str());
+                    }
+                    LayerMatch::PcommPrefix(prefix) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_PCOMM_PREFIX as i32;
+                        copy_into_cstr(&mut mt.pcomm_prefix, prefix.as_str());
+                    }
+                    LayerMatch::PcommPrefixExclude(prefix) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_PCOMM_PREFIX as i32;
+                        mt.exclude.write(true);
+                        copy_into_cstr(&mut mt.pcomm_prefix, prefix.as_str());
+                    }
+                    LayerMatch::PcommGlob(pattern) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_PCOMM_GLOB as i32;
+                        copy_into_cstr(&mut mt.pcomm_prefix, pattern.as_str());
+                    }
+                    LayerMatch::NiceAbove(nice) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_NICE_ABOVE as i32;
+                        mt.nice = *nice;
+                    }
+                    LayerMatch::NiceBelow(nice) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_NICE_BELOW as i32;
+                        mt.nice = *nice;
+                    }
+                    LayerMatch::NiceEquals(nice) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_NICE_EQUALS as i32;
+                        mt.nice = *nice;
+                    }
+                    LayerMatch::UIDEquals(user_id) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_USER_ID_EQUALS as i32;
+                        mt.user_id = *user_id;
+                    }
+                    LayerMatch::GIDEquals(group_id) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_GROUP_ID_EQUALS as i32;
+                        mt.group_id = *group_id;
+                    }
+                    LayerMatch::PIDEquals(pid) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_PID_EQUALS as i32;
+                        mt.pid = *pid;
+                    }
+                    LayerMatch::PPIDEquals(ppid) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_PPID_EQUALS as i32;
+                        mt.ppid = *ppid;
+                    }
+                    LayerMatch::AncestorCommPrefix(prefix) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_ANCESTOR_COMM_PREFIX as i32;
+                        copy_into_cstr(&mut mt.comm_prefix, prefix.as_str());
+                    }
+                    LayerMatch::TGIDEquals(tgid) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_TGID_EQUALS as i32;
+                        mt.tgid = *tgid;
+                    }
+                    LayerMatch::TGIDIn(tgids) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_TGID_IN as i32;
+                        for (i, tgid) in tgids.iter().enumerate() {
+                            mt.tgids[i] = *tgid;
                         }
+                        mt.nr_tgids = tgids.len() as u32;
                     }
-                }
-                layer.matches[or_i].nr_match_ands = or.len() as i32;
-            }
-
-            layer.nr_match_ors = spec.matches.len() as u32;
-            layer.kind = spec.kind.as_bpf_enum();
-
-            {
-                let LayerCommon {
-                    min_exec_us,
-                    yield_ignore,
-                    perf,
-                    preempt,
-                    preempt_first,
-                    exclusive,
-                    allow_node_aligned,
-                    skip_remote_node,
-                    prev_over_idle_core,
-                    growth_algo,
-                    nodes,
-                    slice_us,
-                    fifo,
-                    weight,
-                    disallow_open_after_us,
-                    disallow_preempt_after_us,
-                    xllc_mig_min_us,
-                    placement,
-                    ..
-                } = spec.kind.common();
-
-                layer.slice_ns = *slice_us * 1000;
-                layer.fifo.write(*fifo);
-                layer.min_exec_ns = min_exec_us * 1000;
-                layer.yield_step_ns = if *yield_ignore > 0.999 {
-                    0
-                } else if *yield_ignore < 0.001 {
-                    layer.slice_ns
-                } else {
-                    (layer.slice_ns as f64 * (1.0 - *yield_ignore)) as u64
-                };
-                let mut layer_name: String = spec.name.clone();
-                layer_name.truncate(MAX_LAYER_NAME);
-                copy_into_cstr(&mut layer.name, layer_name.as_str());
-                layer.preempt.write(*preempt);
-                layer.preempt_first.write(*preempt_first);
-                layer.excl.write(*exclusive);
-                layer.allow_node_aligned.write(*allow_node_aligned);
-                layer.skip_remote_node.write(*skip_remote_node);
-                layer.prev_over_idle_core.write(*prev_over_idle_core);
-                layer.growth_algo = growth_algo.as_bpf_enum();
-                layer.weight = *weight;
-                layer.disallow_open_after_ns = match disallow_open_after_us.unwrap() {
-                    v if v == u64::MAX => v,
-                    v => v * 1000,
-                };
-                layer.disallow_preempt_after_ns = match disallow_preempt_after_us.unwrap() {
-                    v if v == u64::MAX => v,
-                    v => v * 1000,
-                };
-                layer.xllc_mig_min_ns = (xllc_mig_min_us * 1000.0) as u64;
-                layer_weights.push(layer.weight.try_into().unwrap());
-                layer.perf = u32::try_from(*perf)?;
-                layer.node_mask = nodemask_from_nodes(nodes) as u64;
-                for (topo_node_id, topo_node) in &topo.nodes {
-                    if !nodes.is_empty() && !nodes.contains(topo_node_id) {
-                        continue;
+                    LayerMatch::NSPIDEquals(nsid, pid) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_NSPID_EQUALS as i32;
+                        mt.nsid = *nsid;
+                        mt.pid = *pid;
                     }
-                    layer.llc_mask |= llcmask_from_llcs(&topo_node.llcs) as u64;
-                }
-
-                let task_place = |place: u32| crate::types::layer_task_place(place);
-                layer.task_place = match placement {
-                    LayerPlacement::Standard => {
-                        task_place(bpf_intf::layer_task_place_PLACEMENT_STD as u32)
+                    LayerMatch::NSEquals(nsid) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_NS_EQUALS as i32;
+                        mt.nsid = *nsid as u64;
                     }
-                    LayerPlacement::Sticky => {
-                        task_place(bpf_intf::layer_task_place_PLACEMENT_STICK as u32)
+                    LayerMatch::CmdJoin(joincmd) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_SCXCMD_JOIN as i32;
+                        copy_into_cstr(&mut mt.comm_prefix, joincmd);
                     }
-                    LayerPlacement::Floating => {
-                        task_place(bpf_intf::layer_task_place_PLACEMENT_FLOAT as u32)
+                    LayerMatch::IsGroupLeader(polarity) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_IS_GROUP_LEADER as i32;
+                        mt.is_group_leader.write(*polarity);
                     }
-                };
+                    LayerMatch::IsKthread(polarity) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_IS_KTHREAD as i32;
+                        mt.is_kthread.write(*polarity);
+                    }
+                    LayerMatch::UsedGpuTid(polarity) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_USED_GPU_TID as i32;
+                        mt.used_gpu_tid.write(*polarity);
+                    }
+                    LayerMatch::UsedGpuPid(polarity) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_USED_GPU_PID as i32;
+                        mt.used_gpu_pid.write(*polarity);
+                    }
+                    LayerMatch::AvgRuntime(min, max) => {
+                        mt.kind = bpf_intf::layer_match_kind_MATCH_AVG_RUNTIME as i32;
+                        mt.min_avg_runtime_us = *min;
+                        mt.max_avg_runtime_us = *max;
+                    }
+                }
             }
+            layer.matches[or_i].nr_match_ands = or.len() as i32;
+        }
+
+        layer.nr_match_ors = spec.matches.len() as u32;
+        layer.kind = spec.kind.as_bpf_enum();
+        layer.periodically_refresh.write(
+            spec.matches
+                .iter()
+                .flatten()
+                .any(|mt| matches!(mt, LayerMatch::AvgRuntime(..))),
+        );
 
-            layer.is_protected.write(match spec.kind {
-                LayerKind::Open { .. } => false,
-                LayerKind::Confined { protected, .. } | LayerKind::Grouped { protected, .. } => {
-                    protected
+        {
+            let LayerCommon {
+                min_exec_us,
+                yield_ignore,
+                perf,
+                perf_min,
+                perf_max,
+                preempt,
+                preempt_first,
+                qos_tier,
+                preempt_budget,
+                exclusive,
+                allow_node_aligned,
+                skip_remote_node,
+                prev_over_idle_core,
+                growth_algo,
+                nodes,
+                slice_us,
+                fifo,
+                weight,
+                disallow_open_after_us,
+                disallow_preempt_after_us,
+                xllc_mig_min_us,
+                placement,
+                slo,
+                ..
+            } = spec.kind.common();
+
+            layer.slice_ns = *slice_us * 1000;
+            layer.fifo.write(*fifo);
+            layer.min_exec_ns = min_exec_us * 1000;
+            layer.yield_step_ns = if *yield_ignore > 0.999 {
+                0
+            } else if *yield_ignore < 0.001 {
+                layer.slice_ns
+            } else {
+                (layer.slice_ns as f64 * (1.0 - *yield_ignore)) as u64
+            };
+            let mut layer_name: String = spec.name.clone();
+            layer_name.truncate(MAX_LAYER_NAME);
+            copy_into_cstr(&mut layer.name, layer_name.as_str());
+            layer.preempt.write(*preempt);
+            layer.preempt_first.write(*preempt_first);
+            layer.qos_tier = *qos_tier;
+            layer.preempt_budgeted.write(preempt_budget.is_some());
+            layer.preempt_budget_left = preempt_budget.unwrap_or(0) as i64;
+            layer.excl.write(*exclusive);
+            layer.allow_node_aligned.write(*allow_node_aligned);
+            layer.skip_remote_node.write(*skip_remote_node);
+            layer.prev_over_idle_core.write(*prev_over_idle_core);
+            layer.growth_algo = growth_algo.as_bpf_enum();
+            layer.weight = *weight;
+            layer.disallow_open_after_ns = match disallow_open_after_us.unwrap() {
+                v if v == u64::MAX => v,
+                v => v * 1000,
+            };
+            layer.disallow_preempt_after_ns = match disallow_preempt_after_us.unwrap() {
+                v if v == u64::MAX => v,
+                v => v * 1000,
+            };
+            layer.xllc_mig_min_ns = (xllc_mig_min_us * 1000.0) as u64;
+            layer.perf = u32::try_from(*perf)?;
+            layer.perf_min = u32::try_from(*perf_min)?;
+            layer.perf_max = u32::try_from(*perf_max)?;
+            layer.slo_lat_ns = slo.as_ref().map_or(0, |slo| slo.lat_us * 1000);
+            layer
+                .slo_wakeup_only
+                .write(slo.as_ref().is_some_and(|slo| slo.wakeup_only));
+            layer.node_mask = nodemask_from_nodes(nodes) as u64;
+            layer.llc_mask = 0;
+            for (topo_node_id, topo_node) in &topo.nodes {
+                if !nodes.is_empty() && !nodes.contains(topo_node_id) {
+                    continue;
                 }
-            });
+                layer.llc_mask |= llcmask_from_llcs(&topo_node.llcs) as u64;
+            }
 
-            match &spec.cpuset {
-                Some(mask) => {
-                    Self::update_cpumask(&mask, &mut layer.cpuset);
+            let task_place = |place: u32| crate::types::layer_task_place(place);
+            layer.task_place = match placement {
+                LayerPlacement::Standard => {
+                    task_place(bpf_intf::layer_task_place_PLACEMENT_STD as u32)
                 }
-                None => {
-                    for i in 0..layer.cpuset.len() {
-                        layer.cpuset[i] = u8::MAX;
-                    }
+                LayerPlacement::Sticky => {
+                    task_place(bpf_intf::layer_task_place_PLACEMENT_STICK as u32)
+                }
+                LayerPlacement::Floating => {
+                    task_place(bpf_intf::layer_task_place_PLACEMENT_FLOAT as u32)
                 }
             };
-
-            perf_set |= layer.perf > 0;
         }
 
-        layer_iteration_order.sort_by(|i, j| layer_weights[*i].cmp(&layer_weights[*j]));
-        for (idx, layer_idx) in layer_iteration_order.iter().enumerate() {
-            skel.maps.rodata_data.layer_iteration_order[idx] = *layer_idx as u32;
-        }
+        let (protected, confidential) = match spec.kind {
+            LayerKind::Open { .. } => (false, false),
+            LayerKind::Confined {
+                protected,
+                confidential,
+                ..
+            }
+            | LayerKind::Grouped {
+                protected,
+                confidential,
+                ..
+            } => (protected, confidential),
+        };
+        layer.is_protected.write(protected || confidential);
+        layer.confidential.write(confidential);
 
-        if perf_set && !compat::ksym_exists("scx_bpf_cpuperf_set")? {
-            warn!("cpufreq support not available, ignoring perf configurations");
-        }
+        match &spec.cpuset {
+            Some(mask) => {
+                Self::update_cpumask(&mask, &mut layer.cpuset);
+            }
+            None => {
+                for i in 0..layer.cpuset.len() {
+                    layer.cpuset[i] = u8::MAX;
+                }
+            }
+        };
 
         Ok(())
     }
 
-    fn init_nodes(skel: &mut OpenBpfSkel, _opts: &Opts, topo: &Topology) {
-        skel.maps.rodata_data.nr_nodes = topo.nodes.len() as u32;
-        skel.maps.rodata_data.nr_llcs = 0;
+    fn init_anti_affinity(layers: &mut [types::layer], specs: &[LayerSpec]) {
+        for (idx, layer) in layers.iter_mut().enumerate().take(specs.len()) {
+            layer.anti_affine_layers = anti_affine_layers(specs, idx);
+        }
+    }
 
-        for (&node_id, node) in &topo.nodes {
-            debug!("configuring node {}, LLCs {:?}", node_id, node.llcs.len());
-            skel.maps.rodata_data.nr_llcs += node.llcs.len() as u32;
-            let raw_numa_slice = node.span.as_raw_slice();
-            let node_cpumask_slice = &mut skel.maps.rodata_data.numa_cpumasks[node_id];
+    /// Set the BPF globals which are derived from the whole set of layers.
+    /// These live in the bss so that spec reloads can add and remove layers
+    /// or change their kinds.
+    fn init_layer_globals(bss: &mut types::bss, specs: &[LayerSpec]) {
+        bss.nr_layers = specs.len() as u32;
+
+        let mut layer_iteration_order = (0..specs.len()).collect::<Vec<_>>();
+        layer_iteration_order.sort_by_key(|&idx| specs[idx].kind.common().weight);
+        for (idx, layer_idx) in layer_iteration_order.iter().enumerate() {
+            bss.layer_iteration_order[idx] = *layer_idx as u32;
+        }
+
+        bss.nr_excl_layers = specs
+            .iter()
+            .filter(|spec| spec.kind.common().exclusive)
+            .count() as u32;
+
+        let mut min_open = u64::MAX;
+        let mut min_preempt = u64::MAX;
+
+        for spec in specs.iter() {
+            if let LayerKind::Open { common, .. } = &spec.kind {
+                min_open = min_open.min(common.disallow_open_after_us.unwrap());
+                min_preempt = min_preempt.min(common.disallow_preempt_after_us.unwrap());
+            }
+        }
+
+        bss.min_open_layer_disallow_open_after_ns = match min_open {
+            u64::MAX => *DFL_DISALLOW_OPEN_AFTER_US,
+            v => v,
+        };
+        bss.min_open_layer_disallow_preempt_after_ns = match min_preempt {
+            u64::MAX => *DFL_DISALLOW_PREEMPT_AFTER_US,
+            v => v,
+        };
+    }
+
+    fn init_layers(skel: &mut OpenBpfSkel, specs: &[LayerSpec], topo: &Topology) -> Result<()> {
+        Self::init_layer_globals(&mut skel.maps.bss_data, specs);
+        let mut perf_set = false;
+
+        let cgroup_regexes = cgroup_regexes(specs);
+        for (spec_i, spec) in specs.iter().enumerate() {
+            let layer = &mut skel.maps.bss_data.layers[spec_i];
+            Self::init_layer(layer, spec, topo, &cgroup_regexes)?;
+            perf_set |= layer.perf > 0 || layer.perf_min > 0 || layer.perf_max > 0;
+        }
+        Self::init_anti_affinity(&mut skel.maps.bss_data.layers, specs);
+
+        if perf_set && !compat::ksym_exists("scx_bpf_cpuperf_set")? {
+            warn!("cpufreq support not available, ignoring perf configurations");
+        }
+
+        Ok(())
+    }
+
+    fn init_nodes(skel: &mut OpenBpfSkel, _opts: &Opts, topo: &Topology) {
+        skel.maps.rodata_data.nr_nodes = topo.nodes.len() as u32;
+        skel.maps.rodata_data.nr_llcs = 0;
+
+        for (&node_id, node) in &topo.nodes {
+            debug!("configuring node {}, LLCs {:?}", node_id, node.llcs.len());
+            skel.maps.rodata_data.nr_llcs += node.llcs.len() as u32;
+            let raw_numa_slice = node.span.as_raw_slice();
+            let node_cpumask_slice = &mut skel.maps.rodata_data.numa_cpumasks[node_id];
             let (left, _) = node_cpumask_slice.split_at_mut(raw_numa_slice.len());
             left.clone_from_slice(raw_numa_slice);
             debug!(
@@ -1624,14 +2311,17 @@ impl<'a> Scheduler<'a> {
             .collect()
     }
 
-    fn init_cpus(skel: &BpfSkel, layer_specs: &[LayerSpec], topo: &Topology) -> Result<()> {
+    /// Write the order in which each CPU consumes the layers of
+    /// @layer_specs. This is also how spec reloads change the number and the
+    /// kinds of the layers in BPF.
+    fn init_layer_orders(skel: &BpfSkel, layer_specs: &[LayerSpec]) -> Result<()> {
         let key = (0_u32).to_ne_bytes();
-        let mut cpu_ctxs: Vec<bpf_intf::cpu_ctx> = vec![];
-        let cpu_ctxs_vec = skel
+        let mut orders: Vec<bpf_intf::cpu_layer_order> = vec![];
+        let orders_vec = skel
             .maps
-            .cpu_ctxs
+            .cpu_layer_orders
             .lookup_percpu(&key, libbpf_rs::MapFlags::ANY)
-            .context("Failed to lookup cpu_ctx")?
+            .context("Failed to lookup cpu_layer_order")?
             .unwrap();
 
         let op_layers: Vec<u32> = layer_specs
@@ -1671,56 +2361,102 @@ impl<'a> Scheduler<'a> {
             .map(|(idx, _)| idx as u32)
             .collect();
 
+        // Higher QoS tiers are consumed first. The sort is stable and keeps
+        // the shuffled order within each tier.
+        let tier_order = |idx: &u32| Reverse(layer_specs[*idx as usize].kind.common().qos_tier);
+
         // FIXME - this incorrectly assumes all possible CPUs are consecutive.
         for cpu in 0..*NR_CPUS_POSSIBLE {
-            cpu_ctxs.push(*unsafe {
-                &*(cpu_ctxs_vec[cpu].as_slice().as_ptr() as *const bpf_intf::cpu_ctx)
-            });
-
-            let topo_cpu = topo.all_cpus.get(&cpu).unwrap();
-            let is_big = topo_cpu.core_type == CoreType::Big { turbo: true };
-            cpu_ctxs[cpu].cpu = cpu as i32;
-            cpu_ctxs[cpu].layer_id = MAX_LAYERS as u32;
-            cpu_ctxs[cpu].task_layer_id = MAX_LAYERS as u32;
-            cpu_ctxs[cpu].is_big = is_big;
+            let mut order = *unsafe {
+                &*(orders_vec[cpu].as_slice().as_ptr() as *const bpf_intf::cpu_layer_order)
+            };
+            order.nr_op_layers = op_layers.len() as u32;
+            order.nr_on_layers = on_layers.len() as u32;
+            order.nr_gp_layers = gp_layers.len() as u32;
+            order.nr_gn_layers = gn_layers.len() as u32;
 
             fastrand::seed(cpu as u64);
 
             let mut ogp_order = op_layers.clone();
             ogp_order.append(&mut gp_layers.clone());
             fastrand::shuffle(&mut ogp_order);
+            ogp_order.sort_by_key(tier_order);
 
             let mut ogn_order = on_layers.clone();
             ogn_order.append(&mut gn_layers.clone());
             fastrand::shuffle(&mut ogn_order);
+            ogn_order.sort_by_key(tier_order);
 
             let mut op_order = op_layers.clone();
             fastrand::shuffle(&mut op_order);
+            op_order.sort_by_key(tier_order);
 
             let mut on_order = on_layers.clone();
             fastrand::shuffle(&mut on_order);
+            on_order.sort_by_key(tier_order);
 
             let mut gp_order = gp_layers.clone();
             fastrand::shuffle(&mut gp_order);
+            gp_order.sort_by_key(tier_order);
 
             let mut gn_order = gn_layers.clone();
             fastrand::shuffle(&mut gn_order);
+            gn_order.sort_by_key(tier_order);
 
             for i in 0..MAX_LAYERS {
-                cpu_ctxs[cpu].ogp_layer_order[i] =
-                    ogp_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
-                cpu_ctxs[cpu].ogn_layer_order[i] =
-                    ogn_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
+                order.ogp_layer_order[i] = ogp_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
+                order.ogn_layer_order[i] = ogn_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
 
-                cpu_ctxs[cpu].op_layer_order[i] =
-                    op_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
-                cpu_ctxs[cpu].on_layer_order[i] =
-                    on_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
-                cpu_ctxs[cpu].gp_layer_order[i] =
-                    gp_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
-                cpu_ctxs[cpu].gn_layer_order[i] =
-                    gn_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
+                order.op_layer_order[i] = op_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
+                order.on_layer_order[i] = on_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
+                order.gp_layer_order[i] = gp_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
+                order.gn_layer_order[i] = gn_order.get(i).cloned().unwrap_or(MAX_LAYERS as u32);
             }
+            orders.push(order);
+        }
+
+        let orders: Vec<Vec<u8>> = orders
+            .iter()
+            .map(|order| {
+                let bytes = unsafe {
+                    std::slice::from_raw_parts(
+                        (order as *const bpf_intf::cpu_layer_order) as *const u8,
+                        std::mem::size_of::<bpf_intf::cpu_layer_order>(),
+                    )
+                };
+                bytes.to_vec()
+            })
+            .collect();
+        skel.maps
+            .cpu_layer_orders
+            .update_percpu(&key, &orders, libbpf_rs::MapFlags::ANY)
+            .context("Failed to update cpu_layer_order")?;
+
+        Ok(())
+    }
+
+    fn init_cpus(skel: &BpfSkel, topo: &Topology) -> Result<()> {
+        let key = (0_u32).to_ne_bytes();
+        let mut cpu_ctxs: Vec<bpf_intf::cpu_ctx> = vec![];
+        let cpu_ctxs_vec = skel
+            .maps
+            .cpu_ctxs
+            .lookup_percpu(&key, libbpf_rs::MapFlags::ANY)
+            .context("Failed to lookup cpu_ctx")?
+            .unwrap();
+
+        // FIXME - this incorrectly assumes all possible CPUs are consecutive.
+        for cpu in 0..*NR_CPUS_POSSIBLE {
+            cpu_ctxs.push(*unsafe {
+                &*(cpu_ctxs_vec[cpu].as_slice().as_ptr() as *const bpf_intf::cpu_ctx)
+            });
+
+            let topo_cpu = topo.all_cpus.get(&cpu).unwrap();
+            let is_big = topo_cpu.core_type == CoreType::Big { turbo: true };
+            cpu_ctxs[cpu].cpu = cpu as i32;
+            cpu_ctxs[cpu].layer_id = MAX_LAYERS as u32;
+            cpu_ctxs[cpu].task_layer_id = MAX_LAYERS as u32;
+            cpu_ctxs[cpu].is_big = is_big;
         }
 
         Self::init_cpu_prox_map(topo, &mut cpu_ctxs);
@@ -1866,28 +2602,17 @@ impl<'a> Scheduler<'a> {
 
         let cpu_pool = CpuPool::new(topo.clone())?;
 
-        // If disabling topology awareness clear out any set NUMA/LLC configs and
-        // it will fallback to using all cores.
-        let layer_specs: Vec<_> = if disable_topology {
+        if disable_topology {
             info!("Disabling topology awareness");
-            layer_specs
-                .iter()
-                .cloned()
-                .map(|mut s| {
-                    s.kind.common_mut().nodes.clear();
-                    s.kind.common_mut().llcs.clear();
-                    s
-                })
-                .collect()
-        } else {
-            layer_specs.to_vec()
-        };
+        }
+        let layer_specs = Self::prep_layer_specs(layer_specs, disable_topology);
 
         // Open the BPF prog first for verification.
         let mut skel_builder = BpfSkelBuilder::default();
         skel_builder.obj_builder.debug(opts.verbose > 1);
         init_libbpf_logging(None);
         let mut skel = scx_ops_open!(skel_builder, open_object, layered)?;
+        intf_layout_check!(skel, bpf_intf)?;
 
         // enable autoloads for conditionally loaded things
         // immediately after creating skel (because this is always before loading)
@@ -1952,8 +2677,11 @@ impl<'a> Scheduler<'a> {
         skel.maps.rodata_data.lo_fb_wait_ns = opts.lo_fb_wait_us * 1000;
         skel.maps.rodata_data.lo_fb_share_ppk = ((opts.lo_fb_share * 1024.0) as u32).clamp(1, 1024);
         skel.maps.rodata_data.enable_antistall = !opts.disable_antistall;
-        skel.maps.rodata_data.enable_match_debug = opts.enable_match_debug;
+        // The control API reports the task layers from layer_match_dbg.
+        skel.maps.rodata_data.enable_match_debug =
+            opts.enable_match_debug || opts.varlink.is_some();
         skel.maps.rodata_data.enable_gpu_support = opts.enable_gpu_support;
+        skel.maps.rodata_data.enable_perf_counters = opts.enable_perf_counters;
 
         for (cpu, sib) in topo.sibling_cpus().iter().enumerate() {
             skel.maps.rodata_data.__sibling_cpu[cpu] = *sib;
@@ -1962,60 +2690,6 @@ impl<'a> Scheduler<'a> {
             skel.maps.rodata_data.all_cpus[cpu / 8] |= 1 << (cpu % 8);
         }
 
-        skel.maps.rodata_data.nr_op_layers = layer_specs
-            .iter()
-            .filter(|spec| match &spec.kind {
-                LayerKind::Open { .. } => spec.kind.common().preempt,
-                _ => false,
-            })
-            .count() as u32;
-        skel.maps.rodata_data.nr_on_layers = layer_specs
-            .iter()
-            .filter(|spec| match &spec.kind {
-                LayerKind::Open { .. } => !spec.kind.common().preempt,
-                _ => false,
-            })
-            .count() as u32;
-        skel.maps.rodata_data.nr_gp_layers = layer_specs
-            .iter()
-            .filter(|spec| match &spec.kind {
-                LayerKind::Grouped { .. } => spec.kind.common().preempt,
-                _ => false,
-            })
-            .count() as u32;
-        skel.maps.rodata_data.nr_gn_layers = layer_specs
-            .iter()
-            .filter(|spec| match &spec.kind {
-                LayerKind::Grouped { .. } => !spec.kind.common().preempt,
-                _ => false,
-            })
-            .count() as u32;
-        skel.maps.rodata_data.nr_excl_layers = layer_specs
-            .iter()
-            .filter(|spec| spec.kind.common().exclusive)
-            .count() as u32;
-
-        let mut min_open = u64::MAX;
-        let mut min_preempt = u64::MAX;
-
-        for spec in layer_specs.iter() {
-            if let LayerKind::Open { common, .. } = &spec.kind {
-                min_open = min_open.min(common.disallow_open_after_us.unwrap());
-                min_preempt = min_preempt.min(common.disallow_preempt_after_us.unwrap());
-            }
-        }
-
-        skel.maps.rodata_data.min_open_layer_disallow_open_after_ns = match min_open {
-            u64::MAX => *DFL_DISALLOW_OPEN_AFTER_US,
-            v => v,
-        };
-        skel.maps
-            .rodata_data
-            .min_open_layer_disallow_preempt_after_ns = match min_preempt {
-            u64::MAX => *DFL_DISALLOW_PREEMPT_AFTER_US,
-            v => v,
-        };
-
         // Consider all layers empty at the beginning.
         for i in 0..layer_specs.len() {
             skel.maps.bss_data.empty_layer_ids[i] = i as u32;
@@ -2048,6 +2722,18 @@ impl<'a> Scheduler<'a> {
             layers.push(Layer::new(spec, &topo, growth_order)?);
         }
 
+        // Layer mempolicies are applied as the task_assign_events come in.
+        let mempolicy_tx = match layers
+            .iter()
+            .any(|layer| layer.kind.common().mempolicy != LayerMemPolicy::None)
+        {
+            true => {
+                skel.maps.bss_data.emit_assign_events = true;
+                Some(launch_mempolicy_worker())
+            }
+            false => None,
+        };
+
         let mut idle_qos_enabled = layers
             .iter()
             .any(|layer| layer.kind.common().idle_resume_us.unwrap_or(0) > 0);
@@ -2056,8 +2742,13 @@ impl<'a> Scheduler<'a> {
             idle_qos_enabled = false;
         }
 
-        Self::init_cpus(&skel, &layer_specs, &topo)?;
+        Self::init_cpus(&skel, &topo)?;
+        Self::init_layer_orders(&skel, &layer_specs)?;
         Self::init_llc_prox_map(&mut skel, &topo)?;
+        let perf_event_fds = match opts.enable_perf_counters {
+            true => Self::init_perf_counters(&mut skel, &topo)?,
+            false => vec![],
+        };
 
         // Other stuff.
         let proc_reader = procfs::ProcReader::new();
@@ -2092,9 +2783,35 @@ impl<'a> Scheduler<'a> {
         let struct_ops = scx_ops_attach!(skel, layered)?;
         let stats_server = StatsServer::new(stats::server_data()).launch()?;
 
-        let sched = Self {
+        let (assign_tx, assign_rx) = crossbeam::channel::unbounded();
+        let mut builder = libbpf_rs::RingBufferBuilder::new();
+        builder.add(&skel.maps.task_assign_events, move |data| {
+            if data.len() >= std::mem::size_of::<bpf_intf::task_assign_event>() {
+                let ev = unsafe {
+                    std::ptr::read_unaligned(data.as_ptr() as *const bpf_intf::task_assign_event)
+                };
+                let _ = assign_tx.send(ev);
+            }
+            0
+        })?;
+        let assign_rb = builder.build()?;
+
+        let cgroup_regexes = cgroup_regexes(&layer_specs)
+            .iter()
+            .map(|re| Regex::new(re))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut sched = Self {
             struct_ops: Some(struct_ops),
+            opts,
             layer_specs,
+            disable_topology,
+            pid_layers: BTreeMap::new(),
+            cpumasks_stale: false,
+            cgroup_regexes,
+            cgroup_regex_cgrps: BTreeMap::new(),
+            cgroup_regex_gen: None,
+            _perf_event_fds: perf_event_fds,
 
             sched_intv: Duration::from_secs_f64(opts.interval),
             layer_refresh_intv: Duration::from_millis(opts.layer_refresh_ms_avgruntime),
@@ -2114,13 +2831,103 @@ impl<'a> Scheduler<'a> {
             topo,
             netdevs,
             stats_server,
+            assign_rb,
+            assign_rx,
+            assign_rb_dropped: 0,
+            mempolicy_tx,
+            mempolicy_tgids: HashMap::new(),
         };
 
+        sched.refresh_cgroup_regex_matches()?;
+
         info!("Layered Scheduler Attached. Run `scx_layered --monitor` for metrics.");
 
         Ok(sched)
     }
 
+    fn init_perf_counters(skel: &mut BpfSkel, topo: &Topology) -> Result<Vec<OwnedFd>> {
+        let events = [
+            (
+                LPERF_CYCLES,
+                perf::bindings::PERF_COUNT_HW_CPU_CYCLES,
+                "cycles",
+            ),
+            (
+                LPERF_INSNS,
+                perf::bindings::PERF_COUNT_HW_INSTRUCTIONS,
+                "instructions",
+            ),
+            (
+                LPERF_LLC_MISSES,
+                perf::bindings::PERF_COUNT_HW_CACHE_MISSES,
+                "LLC misses",
+            ),
+        ];
+        let mut fds = vec![];
+
+        for (idx, config, name) in events.iter() {
+            let mut failed = vec![];
+            for &cpu in topo.all_cpus.keys() {
+                let mut attrs = perf::bindings::perf_event_attr {
+                    size: std::mem::size_of::<perf::bindings::perf_event_attr>() as u32,
+                    type_: perf::bindings::PERF_TYPE_HARDWARE,
+                    config: *config as u64,
+                    ..Default::default()
+                };
+                attrs.set_disabled(0);
+
+                let fd = unsafe { perf::perf_event_open(&mut attrs, -1, cpu as i32, -1, 0) };
+                if fd < 0 {
+                    failed.push((cpu, std::io::Error::last_os_error()));
+                    continue;
+                }
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+                let key = (cpu * NR_LPERFS + idx) as u32;
+                skel.maps
+                    .layer_perf_events
+                    .update(
+                        &key.to_ne_bytes(),
+                        &(fd.as_raw_fd() as u32).to_ne_bytes(),
+                        libbpf_rs::MapFlags::ANY,
+                    )
+                    .with_context(|| {
+                        format!("Failed to install {} counter for CPU {}", name, cpu)
+                    })?;
+                fds.push(fd);
+            }
+            if let Some((cpu, err)) = failed.first() {
+                warn!(
+                    "Failed to open {} perf counter on {} CPUs, first CPU {} ({})",
+                    name,
+                    failed.len(),
+                    cpu,
+                    err
+                );
+            }
+        }
+
+        Ok(fds)
+    }
+
+    fn prep_layer_specs(specs: &[LayerSpec], disable_topology: bool) -> Vec<LayerSpec> {
+        // If disabling topology awareness clear out any set NUMA/LLC configs and
+        // it will fallback to using all cores.
+        if disable_topology {
+            specs
+                .iter()
+                .cloned()
+                .map(|mut s| {
+                    s.kind.common_mut().nodes.clear();
+                    s.kind.common_mut().llcs.clear();
+                    s
+                })
+                .collect()
+        } else {
+            specs.to_vec()
+        }
+    }
+
     fn update_cpumask(mask: &Cpumask, bpfmask: &mut [u8]) {
         for cpu in 0..mask.len() {
             if mask.test_cpu(cpu) {
@@ -2180,19 +2987,45 @@ impl<'a> Scheduler<'a> {
         Ok(())
     }
 
+    /// The average queueing delay of each layer in microseconds, weighted
+    /// by the number of enqueues in each LLC.
+    fn layer_lats_us(&self) -> Vec<f64> {
+        self.sched_stats
+            .bpf_stats
+            .llc_lstats
+            .iter()
+            .map(|llcs| {
+                let (sum, cnt) = llcs.iter().fold((0.0, 0), |(sum, cnt), lstats| {
+                    let llc_cnt = lstats[LLC_LSTAT_CNT];
+                    (
+                        sum + lstats[LLC_LSTAT_LAT] as f64 * llc_cnt as f64,
+                        cnt + llc_cnt,
+                    )
+                });
+                if cnt > 0 {
+                    sum / cnt as f64 / 1000.0
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
     /// Calculate how many CPUs each layer would like to have if there were
     /// no competition. The CPU range is determined by applying the inverse
     /// of util_range and then capping by cpus_range. If the current
     /// allocation is within the acceptable range, no change is made.
     /// Returns (target, min) pair for each layer.
-    fn calc_target_nr_cpus(&self) -> Vec<(usize, usize)> {
+    fn calc_target_nr_cpus(&mut self) -> Vec<(usize, usize)> {
         let nr_cpus = self.cpu_pool.topo.all_cpus.len();
         let utils = &self.sched_stats.layer_utils;
+        let lats_us = self.layer_lats_us();
+        let dt = self.sched_stats.elapsed.as_secs_f64();
 
-        let mut records: Vec<(u64, u64, u64, usize, usize, usize)> = vec![];
+        let mut records: Vec<(u64, u64, u64, u64, usize)> = vec![];
         let mut targets: Vec<(usize, usize)> = vec![];
 
-        for (idx, layer) in self.layers.iter().enumerate() {
+        for (idx, layer) in self.layers.iter_mut().enumerate() {
             targets.push(match &layer.kind {
                 LayerKind::Confined {
                     util_range,
@@ -2219,18 +3052,22 @@ impl<'a> Scheduler<'a> {
                     }
 
                     let util = if util < 0.01 { 0.0 } else { util };
-                    let low = (util / util_range.1).ceil() as usize;
-                    let high = ((util / util_range.0).floor() as usize).max(low);
-                    let target = layer.cpus.weight().clamp(low, high);
                     let cpus_range =
                         resolve_cpus_pct_range(cpus_range, cpus_range_frac, nr_cpus).unwrap();
+                    let target = layer.sizing_ctl.target_nr_cpus(&LayerSizingInput {
+                        nr_cpus: layer.cpus.weight(),
+                        util,
+                        util_range: *util_range,
+                        lat_us: lats_us[idx],
+                        cpus_range,
+                        dt,
+                    });
 
                     records.push((
                         (owned * 100.0) as u64,
                         (open * 100.0) as u64,
                         (util * 100.0) as u64,
-                        low,
-                        high,
+                        lats_us[idx] as u64,
                         target,
                     ));
 
@@ -2241,7 +3078,7 @@ impl<'a> Scheduler<'a> {
         }
 
         trace!("initial targets: {:?}", &targets);
-        trace!("(owned, open, util, low, high, target): {:?}", &records);
+        trace!("(owned, open, util, lat_us, target): {:?}", &records);
         targets
     }
 
@@ -2505,7 +3342,7 @@ impl<'a> Scheduler<'a> {
     fn refresh_cpumasks(&mut self) -> Result<()> {
         let layer_is_open = |layer: &Layer| matches!(layer.kind, LayerKind::Open { .. });
 
-        let mut updated = false;
+        let mut updated = std::mem::take(&mut self.cpumasks_stale);
         let targets = self.calc_target_nr_cpus();
         let targets = self.weighted_target_nr_cpus(&targets);
 
@@ -2680,116 +3517,1059 @@ impl<'a> Scheduler<'a> {
         Ok(())
     }
 
-    fn step(&mut self) -> Result<()> {
-        let started_at = Instant::now();
-        self.sched_stats.refresh(
-            &mut self.skel,
-            &self.proc_reader,
-            started_at,
-            self.processing_dur,
-        )?;
-        self.refresh_cpumasks()?;
-        self.refresh_idle_qos()?;
-        self.processing_dur += Instant::now().duration_since(started_at);
-        Ok(())
+    /// Re-read the layer specs and apply them, see apply_layer_specs().
+    fn reload_layer_specs(&mut self) -> Result<()> {
+        let config = load_layer_config(self.opts)?;
+        verify_layer_specs(&config.specs)?;
+        let specs = Self::prep_layer_specs(&config.specs, self.disable_topology);
+        self.apply_layer_specs(specs)
     }
 
-    fn generate_sys_stats(
-        &mut self,
-        stats: &Stats,
-        cpus_ranges: &mut [(usize, usize)],
-    ) -> Result<SysStats> {
-        let bstats = &stats.bpf_stats;
-        let mut sys_stats = SysStats::new(stats, bstats, self.cpu_pool.fallback_cpu)?;
+    /// Switch to the verified and prepped @specs without reloading the BPF
+    /// scheduler. Layers are matched by name. The added, moved and modified
+    /// ones are rebuilt, the removed ones give up their CPUs and all tasks
+    /// re-match on their next wakeup or enqueue.
+    fn apply_layer_specs(&mut self, specs: Vec<LayerSpec>) -> Result<()> {
+        let cgroup_regex_strs = cgroup_regexes(&specs);
+        let new_cgroup_regexes = cgroup_regex_strs
+            .iter()
+            .map(|re| Regex::new(re))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        for (lidx, (spec, layer)) in self.layer_specs.iter().zip(self.layers.iter()).enumerate() {
-            let layer_stats = LayerStats::new(lidx, layer, stats, bstats, cpus_ranges[lidx]);
-            sys_stats.layers.insert(spec.name.to_string(), layer_stats);
-            cpus_ranges[lidx] = (layer.nr_cpus, layer.nr_cpus);
+        let diff = layer_specs_diff(&self.layer_specs, &specs);
+        if diff.changed.is_empty() && diff.removed.is_empty() {
+            info!("Layer specs unchanged");
+            return Ok(());
         }
 
-        Ok(sys_stats)
-    }
+        // Build all the new layers before touching anything so that an
+        // invalid spec doesn't leave the layers partially updated.
+        let growth_orders = LayerGrowthAlgo::layer_core_orders(&self.cpu_pool, &specs, &self.topo)?;
+        let mut new_layers = vec![];
+        for &idx in diff.changed.iter() {
+            let growth_order = growth_orders
+                .get(&idx)
+                .with_context(|| "layer has no growth order".to_string())?;
+            new_layers.push(Layer::new(&specs[idx], &self.topo, growth_order)?);
+        }
+        for &idx in diff.changed.iter() {
+            let bpf_layer = &mut self.skel.maps.bss_data.layers[idx];
+            Self::init_layer(bpf_layer, &specs[idx], &self.topo, &cgroup_regex_strs)?;
+        }
 
-    fn run(&mut self, shutdown: Arc<AtomicBool>) -> Result<UserExitInfo> {
-        let (res_ch, req_ch) = self.stats_server.channels();
-        let mut next_sched_at = Instant::now() + self.sched_intv;
-        let enable_layer_refresh = !self.layer_refresh_intv.is_zero();
-        let mut next_layer_refresh_at = Instant::now() + self.layer_refresh_intv;
-        let mut cpus_ranges = HashMap::<ThreadId, Vec<(usize, usize)>>::new();
+        let mut old_layers: Vec<Option<Layer>> = std::mem::take(&mut self.layers)
+            .into_iter()
+            .map(Some)
+            .collect();
+        let mut new_layers = new_layers.into_iter();
+        for (idx, old_idx) in diff.old_idx.iter().enumerate() {
+            let old = old_idx.and_then(|old_idx| old_layers[old_idx].take());
+            if !diff.changed.contains(&idx) {
+                self.layers.push(old.unwrap());
+                continue;
+            }
 
-        while !shutdown.load(Ordering::Relaxed) && !uei_exited!(&self.skel, uei) {
-            let now = Instant::now();
+            let mut layer = new_layers.next().unwrap();
+            match old {
+                Some(mut old) => {
+                    info!(
+                        "Updating layer {}{}",
+                        &layer.name,
+                        match old_idx {
+                            Some(old_idx) if *old_idx != idx =>
+                                format!(" (moved from {} to {})", old_idx, idx),
+                            _ => String::new(),
+                        }
+                    );
 
-            if now >= next_sched_at {
-                self.step()?;
-                while next_sched_at < now {
-                    next_sched_at += self.sched_intv;
+                    // Keep the current CPUs unless the layer changed kind
+                    // or is no longer allowed on all of them. In that case,
+                    // start over and let refresh_cpumasks() allocate again.
+                    let old_open = matches!(old.kind, LayerKind::Open { .. });
+                    if std::mem::discriminant(&old.kind) == std::mem::discriminant(&layer.kind)
+                        && (old_open || old.cpus.is_subset(&layer.allowed_cpus))
+                    {
+                        layer.target_llc_cpus = old.target_llc_cpus;
+                        layer.assigned_llcs = std::mem::take(&mut old.assigned_llcs);
+                        layer.nr_cpus = old.nr_cpus;
+                        layer.nr_llc_cpus = std::mem::take(&mut old.nr_llc_cpus);
+                        layer.cpus = old.cpus.clone();
+                    } else if !old_open && !old.cpus.is_empty() {
+                        self.cpu_pool.free(&old.cpus)?;
+                    }
                 }
+                None => info!("Adding layer {}", &layer.name),
             }
 
-            if enable_layer_refresh && now >= next_layer_refresh_at {
+            Self::update_bpf_layer_cpumask(&layer, &mut self.skel.maps.bss_data.layers[idx]);
+            self.layers.push(layer);
+        }
+
+        // Open layers don't own their CPUs.
+        for old in old_layers.into_iter().flatten() {
+            info!("Removing layer {}", &old.name);
+            if !matches!(old.kind, LayerKind::Open { .. }) && !old.cpus.is_empty() {
+                self.cpu_pool.free(&old.cpus)?;
+            }
+        }
+
+        for (idx, layer) in self.layers.iter_mut().enumerate() {
+            if let Some(growth_order) = growth_orders.get(&idx) {
+                layer.core_order = growth_order.clone();
+            }
+        }
+        self.nr_layer_cpus_ranges = self
+            .layers
+            .iter()
+            .map(|layer| (layer.nr_cpus, layer.nr_cpus))
+            .collect();
+
+        // The cgroup_regex_id's may have shifted for the unchanged layers too.
+        if self
+            .cgroup_regexes
+            .iter()
+            .map(|re| re.as_str())
+            .ne(cgroup_regex_strs.iter().map(|re| re.as_str()))
+        {
+            for (idx, spec) in specs.iter().enumerate() {
+                if !diff.changed.contains(&idx) {
+                    let bpf_layer = &mut self.skel.maps.bss_data.layers[idx];
+                    Self::init_layer(bpf_layer, spec, &self.topo, &cgroup_regex_strs)?;
+                }
+            }
+        }
+        Self::init_layer_globals(&mut self.skel.maps.bss_data, &specs);
+        Self::init_layer_orders(&self.skel, &specs)?;
+        Self::init_anti_affinity(&mut self.skel.maps.bss_data.layers, &specs);
+        self.cgroup_regexes = new_cgroup_regexes;
+        self.cgroup_regex_gen = None;
+        self.refresh_cgroup_regex_matches()?;
+        self.layer_specs = specs;
+
+        // The control API assignments are by layer index.
+        if diff.remapped() {
+            let pid_layers = std::mem::take(&mut self.pid_layers);
+            for pid in pid_layers.keys() {
+                let _ = self
+                    .skel
+                    .maps
+                    .layer_pid_overrides
+                    .delete(&pid.to_ne_bytes());
+            }
+            self.restore_pid_layers(pid_layers)?;
+        }
+
+        if !self.idle_qos_enabled
+            && self
+                .layers
+                .iter()
+                .any(|layer| layer.kind.common().idle_resume_us.unwrap_or(0) > 0)
+        {
+            if cpu_idle_resume_latency_supported() {
+                self.idle_qos_enabled = true;
+            } else {
+                warn!("idle_resume_us not supported, ignoring");
+            }
+        }
+
+        // The tasks are re-assigned below, apply the mempolicies again.
+        self.mempolicy_tgids.clear();
+        if self.mempolicy_tx.is_none()
+            && self
+                .layers
+                .iter()
+                .any(|layer| layer.kind.common().mempolicy != LayerMemPolicy::None)
+        {
+            self.skel.maps.bss_data.emit_assign_events = true;
+            self.mempolicy_tx = Some(launch_mempolicy_worker());
+        }
+
+        // Refresh the cpusets and bump the spec seq so that tasks re-match.
+        let input = ProgramInput {
+            ..Default::default()
+        };
+        let prog = &mut self.skel.progs.reload_layers;
+        let _ = prog.test_run(input);
+
+        self.cpumasks_stale = true;
+        self.refresh_cpumasks()?;
+
+        info!(
+            "Reloaded layer specs, {} layer(s) updated, {} removed",
+            diff.changed.len(),
+            diff.removed.len()
+        );
+        Ok(())
+    }
+
+    /// Match the cgroup hierarchy against the CgroupRegex rules and update
+    /// the cgroup_regex_matches BPF map accordingly. The hierarchy is only
+    /// walked again after BPF saw cgroups being created, removed or renamed.
+    fn refresh_cgroup_regex_matches(&mut self) -> Result<()> {
+        if self.cgroup_regexes.is_empty() && self.cgroup_regex_cgrps.is_empty() {
+            return Ok(());
+        }
+
+        // Read before walking so that changes during the walk are caught
+        // on the next refresh.
+        let gen = self.skel.maps.bss_data.cgroup_gen;
+        if self.cgroup_regex_gen == Some(gen) {
+            return Ok(());
+        }
+
+        let root = Path::new("/sys/fs/cgroup");
+        let mut cgrps = BTreeMap::new();
+        for entry in WalkDir::new(root).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_dir() {
+                continue;
+            }
+            // Cgroups may go away while walking, skip them.
+            let (Ok(rel), Ok(meta)) = (entry.path().strip_prefix(root), entry.metadata()) else {
+                continue;
+            };
+
+            // Same format as format_cgrp_path() in BPF.
+            let path = format!("{}/", rel.display());
+            let mask = cgroup_regex_mask(&self.cgroup_regexes, &path);
+            if mask != 0 {
+                cgrps.insert(meta.ino(), mask);
+            }
+        }
+
+        if cgrps == self.cgroup_regex_cgrps {
+            self.cgroup_regex_gen = Some(gen);
+            return Ok(());
+        }
+
+        let map = &self.skel.maps.cgroup_regex_matches;
+        for cgid in self.cgroup_regex_cgrps.keys() {
+            if !cgrps.contains_key(cgid) {
+                let _ = map.delete(&cgid.to_ne_bytes());
+            }
+        }
+        for (cgid, mask) in cgrps.iter() {
+            if self.cgroup_regex_cgrps.get(cgid) != Some(mask) {
+                map.update(
+                    &cgid.to_ne_bytes(),
+                    &mask.to_ne_bytes(),
+                    libbpf_rs::MapFlags::ANY,
+                )
+                .with_context(|| format!("Failed to update cgroup regex match {}", cgid))?;
+            }
+        }
+        self.cgroup_regex_cgrps = cgrps;
+        self.cgroup_regex_gen = Some(gen);
+
+        // Tasks may have joined the cgroups before they showed up here.
+        self.skel.maps.bss_data.layer_spec_seq += 1;
+        Ok(())
+    }
+
+    /// Move the sizing of the layers with schedules towards the active
+    /// schedule's, or the spec's if none is active.
+    fn refresh_layer_schedules(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let local_now = chrono::Local::now();
+        let nr_cpus = self.topo.all_cpus.len();
+
+        for (spec, layer) in self.layer_specs.iter().zip(self.layers.iter_mut()) {
+            if spec.schedules.is_empty() {
+                continue;
+            }
+
+            let active = spec
+                .schedules
+                .iter()
+                .position(|sched| sched.is_active(&local_now));
+            if active != layer.schedule {
+                let base = LayerSizing::new(&spec.kind, nr_cpus)?;
+                let to = match active {
+                    Some(idx) => base.with_schedule(&spec.schedules[idx]),
+                    None => base,
+                };
+                // Ramp at the pace of the schedule being entered or left.
+                let ramp_secs = active
+                    .or(layer.schedule)
+                    .map(|idx| spec.schedules[idx].ramp_secs)
+                    .unwrap_or(0);
+                info!(
+                    "Layer {} switching to {} over {}s: {:?}",
+                    &spec.name,
+                    match active {
+                        Some(idx) => format!("schedule {}", idx),
+                        None => "base sizing".to_string(),
+                    },
+                    ramp_secs,
+                    &to
+                );
+
+                layer.sizing_ramp = Some(SizingRamp {
+                    from: LayerSizing::new(&layer.kind, nr_cpus)?,
+                    to,
+                    started_at: now,
+                    dur: Duration::from_secs(ramp_secs),
+                });
+                layer.schedule = active;
+            }
+
+            if let Some(ramp) = layer.sizing_ramp.take() {
+                let elapsed = now.duration_since(ramp.started_at);
+                if elapsed < ramp.dur {
+                    let frac = elapsed.as_secs_f64() / ramp.dur.as_secs_f64();
+                    ramp.from.lerp(&ramp.to, frac).apply(&mut layer.kind);
+                    layer.sizing_ramp = Some(ramp);
+                } else {
+                    // The base sizing if no schedule is active.
+                    ramp.to.apply(&mut layer.kind);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<()> {
+        let started_at = Instant::now();
+        self.sched_stats.refresh(
+            &mut self.skel,
+            &self.proc_reader,
+            started_at,
+            self.processing_dur,
+        )?;
+        self.refresh_layer_schedules()?;
+        self.refresh_cpumasks()?;
+        self.refresh_idle_qos()?;
+        self.refresh_cgroup_regex_matches()?;
+        self.check_layer_slos();
+        self.refresh_preempt_budgets();
+        self.processing_dur += Instant::now().duration_since(started_at);
+        Ok(())
+    }
+
+    /// Track the consecutive intervals each layer has been out of its
+    /// latency SLO and warn once there have been alert_after of them.
+    fn check_layer_slos(&mut self) {
+        let bstats = &self.sched_stats.bpf_stats;
+        for (idx, layer) in self.layers.iter_mut().enumerate() {
+            let slo = match &layer.kind.common().slo {
+                Some(v) => v.clone(),
+                None => continue,
+            };
+
+            // No samples, e.g. the layer is idle, keeps the current state.
+            let met = bstats.lstats[idx][LSTAT_SLO_MET];
+            let total = met + bstats.lstats[idx][LSTAT_SLO_MISS];
+            if total == 0 {
+                continue;
+            }
+
+            let met_pct = met as f64 / total as f64 * 100.0;
+            if met_pct >= slo.pct {
+                if slo.alert_after > 0 && layer.slo_out_intvs >= slo.alert_after {
+                    info!("Layer {:?} is back within its latency SLO", layer.name);
+                }
+                layer.slo_out_intvs = 0;
+                continue;
+            }
+
+            layer.slo_out_intvs += 1;
+            if slo.alert_after > 0 && layer.slo_out_intvs == slo.alert_after {
+                warn!(
+                    "Layer {:?} has been out of its latency SLO for {} intervals, {:.2}% within {}us (target {:.2}%), p99 {}us",
+                    layer.name,
+                    layer.slo_out_intvs,
+                    met_pct,
+                    slo.lat_us,
+                    slo.pct,
+                    lat_hist_pct_us(&bstats.lat_hists[idx], 99.0),
+                );
+            }
+        }
+    }
+
+    /// Refill the preemption budgets for the next interval. Racing
+    /// preemptions between the read and write may go uncharged, which is
+    /// fine for a rate limit.
+    fn refresh_preempt_budgets(&mut self) {
+        let intv = self.sched_intv.as_secs_f64();
+        for (idx, layer) in self.layers.iter_mut().enumerate() {
+            let Some(budget) = layer.kind.common().preempt_budget else {
+                continue;
+            };
+            let bpf_layer = &mut self.skel.maps.bss_data.layers[idx];
+            let left = bpf_layer.preempt_budget_left.max(0) as f64 + layer.preempt_budget_frac;
+            let left = (left + budget as f64 * intv).min(budget as f64);
+            bpf_layer.preempt_budget_left = left as i64;
+            layer.preempt_budget_frac = left.fract();
+        }
+    }
+
+    /// Apply the mempolicy of layer @idx to @tgid whose task just joined it
+    /// unless it has already been.
+    fn apply_layer_mempolicy(&mut self, tgid: u32, idx: usize) {
+        let Some(tx) = &self.mempolicy_tx else {
+            return;
+        };
+        let layer = &self.layers[idx];
+        let policy = &layer.kind.common().mempolicy;
+        if *policy == LayerMemPolicy::None || self.mempolicy_tgids.get(&tgid) == Some(&idx) {
+            return;
+        }
+
+        let req = MemPolicyReq {
+            tgid,
+            layer: layer.name.clone(),
+            matches: self.layer_specs[idx].matches.clone(),
+            policy: policy.clone(),
+            nodes: layer.mem_nodes,
+            all_nodes: self.topo.nodes.keys().fold(0, |mask, id| mask | 1 << id),
+        };
+        if tx.send(req).is_err() {
+            warn!("mempolicy worker is gone");
+            return;
+        }
+
+        // Forget the exited processes once in a while.
+        if self.mempolicy_tgids.len() >= bpf_intf::consts_MAX_TASKS as usize {
+            self.mempolicy_tgids
+                .retain(|tgid, _| Path::new(&format!("/proc/{}", tgid)).exists());
+        }
+        self.mempolicy_tgids.insert(tgid, idx);
+    }
+
+    /// Hand the task_assign_events from BPF to each reader of the
+    /// "assignments" stats target and apply the layer mempolicies.
+    fn relay_task_assignments(
+        &mut self,
+        subs: &mut HashMap<ThreadId, TaskAssignments>,
+    ) -> Result<()> {
+        self.assign_rb.consume()?;
+
+        // The events which didn't fit in the ring buffer are lost for all.
+        let rb_dropped = read_cpu_ctxs(&self.skel)?
+            .iter()
+            .map(|cpuc| cpuc.gstats[GSTAT_ASSIGN_DROPPED])
+            .sum::<u64>();
+        let nr_lost = rb_dropped - self.assign_rb_dropped;
+        self.assign_rb_dropped = rb_dropped;
+        for tas in subs.values_mut() {
+            tas.dropped += nr_lost;
+        }
+        if nr_lost > 0 && self.mempolicy_tx.is_some() {
+            // Some processes may have missed their mempolicies, make all
+            // tasks re-match and re-emit. The already applied ones are
+            // skipped through mempolicy_tgids.
+            warn!(
+                "{} task assignment events lost, re-emitting for mempolicies",
+                nr_lost
+            );
+            self.skel.maps.bss_data.assign_events_seq += 1;
+            self.skel.maps.bss_data.layer_spec_seq += 1;
+        }
+
+        while let Ok(ev) = self.assign_rx.try_recv() {
+            let layer_name = |id: u32| match self.layers.get(id as usize) {
+                Some(layer) => layer.name.clone(),
+                None => String::new(),
+            };
+            let comm: Vec<u8> = ev
+                .comm
+                .iter()
+                .take_while(|c| **c != 0)
+                .map(|c| *c as u8)
+                .collect();
+            let rule = match self
+                .layer_specs
+                .get(ev.layer_id as usize)
+                .and_then(|spec| spec.matches.get(ev.match_idx as usize))
+            {
+                Some(ands) if ev.match_idx >= 0 => serde_json::to_string(ands)?,
+                _ => String::new(),
+            };
+
+            let ta = TaskAssignment {
+                pid: ev.pid,
+                tgid: ev.tgid,
+                comm: String::from_utf8_lossy(&comm).into(),
+                layer: layer_name(ev.layer_id),
+                prev_layer: layer_name(ev.prev_layer_id),
+                match_idx: ev.match_idx,
+                rule,
+            };
+            if (ev.layer_id as usize) < self.layers.len() {
+                self.apply_layer_mempolicy(ev.tgid, ev.layer_id as usize);
+            }
+            for tas in subs.values_mut() {
+                if tas.assignments.len() < MAX_ASSIGN_BACKLOG {
+                    tas.assignments.push(ta.clone());
+                } else {
+                    tas.dropped += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn layer_idx(&self, name: &str) -> std::result::Result<usize, CtlError> {
+        self.layer_specs
+            .iter()
+            .position(|spec| spec.name == name)
+            .ok_or_else(|| CtlError::no_such_layer(name))
+    }
+
+    fn layer_info(&self, idx: usize) -> serde_json::Value {
+        let layer = &self.layers[idx];
+        let kind = match layer.kind {
+            LayerKind::Confined { .. } => "Confined",
+            LayerKind::Grouped { .. } => "Grouped",
+            LayerKind::Open { .. } => "Open",
+        };
+        let util = self.sched_stats.layer_utils[idx]
+            .iter()
+            .take(LAYER_USAGE_SUM_UPTO + 1)
+            .sum::<f64>();
+
+        serde_json::json!({
+            "name": layer.name,
+            "kind": kind,
+            "nr_tasks": self.sched_stats.nr_layer_tasks[idx],
+            "util": util * 100.0,
+            "nr_cpus": layer.nr_cpus,
+            "cpus": layer.cpus.to_cpulist(),
+        })
+    }
+
+    /// The tids in layer_match_dbg and the layers they're in.
+    fn task_layers(&self) -> Vec<(u32, usize)> {
+        let map = &self.skel.maps.layer_match_dbg;
+        map.keys()
+            .filter_map(|key| {
+                let val = map.lookup(&key, libbpf_rs::MapFlags::ANY).ok()??;
+                let tid = u32::from_ne_bytes(key.as_slice().try_into().ok()?);
+                let idx = u32::from_ne_bytes(val.as_slice().try_into().ok()?);
+                Some((tid, idx as usize))
+            })
+            .collect()
+    }
+
+    fn set_pid_layer(&mut self, pid: u32, layer: Option<usize>) -> Result<()> {
+        let map = &self.skel.maps.layer_pid_overrides;
+        match layer {
+            Some(idx) => {
+                map.update(
+                    &pid.to_ne_bytes(),
+                    &(idx as u32).to_ne_bytes(),
+                    libbpf_rs::MapFlags::ANY,
+                )?;
+                self.pid_layers
+                    .insert(pid, self.layer_specs[idx].name.clone());
+            }
+            None => {
+                let _ = map.delete(&pid.to_ne_bytes());
+                self.pid_layers.remove(&pid);
+            }
+        }
+
+        // Make the task re-match, its threads too if @pid is a tgid.
+        self.skel.maps.bss_data.layer_spec_seq += 1;
+        Ok(())
+    }
+
+    /// Re-apply the control API layer assignments after a restart or a spec
+    /// reload which moved layers. The ones for deleted layers and exited
+    /// tasks are dropped.
+    fn restore_pid_layers(&mut self, pid_layers: BTreeMap<u32, String>) -> Result<()> {
+        for (pid, name) in pid_layers.into_iter() {
+            if !Path::new(&format!("/proc/{}", pid)).exists() {
+                continue;
+            }
+            match self.layer_idx(&name) {
+                Ok(idx) => self.set_pid_layer(pid, Some(idx))?,
+                Err(_) => info!(
+                    "Layer {:?} is gone, dropping the assignment of {}",
+                    name, pid
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_ctl_req(&mut self, req: CtlReq) -> CtlRes {
+        match req {
+            CtlReq::ListLayers {} => {
+                let layers: Vec<_> = (0..self.layers.len())
+                    .map(|idx| self.layer_info(idx))
+                    .collect();
+                Ok(serde_json::json!({ "layers": layers }))
+            }
+            CtlReq::GetLayer { name } => {
+                let idx = self.layer_idx(&name)?;
+                let tids: Vec<u32> = self
+                    .task_layers()
+                    .into_iter()
+                    .filter(|(_, lidx)| *lidx == idx)
+                    .map(|(tid, _)| tid)
+                    .collect();
+                Ok(serde_json::json!({ "layer": self.layer_info(idx), "tids": tids }))
+            }
+            CtlReq::GetPidLayer { pid } => {
+                let map = &self.skel.maps.layer_match_dbg;
+                let idx = match map.lookup(&pid.to_ne_bytes(), libbpf_rs::MapFlags::ANY) {
+                    Ok(Some(val)) if val.len() == 4 => {
+                        u32::from_ne_bytes(val.as_slice().try_into().unwrap()) as usize
+                    }
+                    _ => return Err(CtlError::no_such_pid(pid)),
+                };
+                if idx >= self.layers.len() {
+                    return Err(CtlError::no_such_pid(pid));
+                }
+                Ok(serde_json::json!({
+                    "layer": self.layers[idx].name,
+                    "overridden": self.pid_layers.contains_key(&pid),
+                }))
+            }
+            CtlReq::MovePid { pid, layer } => {
+                let idx = self.layer_idx(&layer)?;
+                if pid == 0 || !Path::new(&format!("/proc/{}", pid)).exists() {
+                    return Err(CtlError::no_such_pid(pid));
+                }
+                self.set_pid_layer(pid, Some(idx))
+                    .map_err(|_| CtlError::no_such_pid(pid))?;
+                info!("Moved {} to layer {:?}", pid, &layer);
+                Ok(serde_json::json!({}))
+            }
+            CtlReq::ResetPid { pid } => {
+                if !self.pid_layers.contains_key(&pid) {
+                    return Err(CtlError::no_such_pid(pid));
+                }
+                self.set_pid_layer(pid, None)
+                    .map_err(|_| CtlError::no_such_pid(pid))?;
+                Ok(serde_json::json!({}))
+            }
+            CtlReq::CreateLayer { spec, before } => {
+                if spec.template.is_some() {
+                    return Err(CtlError::invalid_spec("templates aren't supported"));
+                }
+                if self.layer_idx(&spec.name).is_ok() {
+                    return Err(CtlError::invalid_spec(format!(
+                        "layer {:?} already exists",
+                        &spec.name
+                    )));
+                }
+                let idx = match before {
+                    Some(name) => self.layer_idx(&name)?,
+                    None => self.layer_specs.len() - 1,
+                };
+
+                let mut specs = self.layer_specs.clone();
+                specs.insert(idx, *spec);
+                verify_layer_specs(&specs).map_err(CtlError::invalid_spec)?;
+
+                info!("Creating layer {:?}", &specs[idx].name);
+                let specs = Self::prep_layer_specs(&specs, self.disable_topology);
+                self.apply_layer_specs(specs)
+                    .map_err(CtlError::invalid_spec)?;
+                Ok(serde_json::json!({}))
+            }
+            CtlReq::DeleteLayer { name } => {
+                let idx = self.layer_idx(&name)?;
+                if idx == self.layer_specs.len() - 1 {
+                    return Err(CtlError::invalid_spec("the last layer can't be deleted"));
+                }
+
+                let mut specs = self.layer_specs.clone();
+                specs.remove(idx);
+                verify_layer_specs(&specs).map_err(CtlError::invalid_spec)?;
+
+                info!("Deleting layer {:?}", &name);
+                self.apply_layer_specs(specs)
+                    .map_err(CtlError::invalid_spec)?;
+                Ok(serde_json::json!({}))
+            }
+        }
+    }
+
+    fn generate_sys_stats(
+        &mut self,
+        stats: &Stats,
+        cpus_ranges: &mut [(usize, usize)],
+    ) -> Result<SysStats> {
+        let bstats = &stats.bpf_stats;
+        let mut sys_stats = SysStats::new(stats, bstats, self.cpu_pool.fallback_cpu)?;
+
+        for (lidx, (spec, layer)) in self.layer_specs.iter().zip(self.layers.iter()).enumerate() {
+            let layer_stats = LayerStats::new(lidx, layer, stats, bstats, cpus_ranges[lidx]);
+            sys_stats.layers.insert(spec.name.to_string(), layer_stats);
+            cpus_ranges[lidx] = (layer.nr_cpus, layer.nr_cpus);
+        }
+
+        Ok(sys_stats)
+    }
+
+    fn run(
+        &mut self,
+        shutdown: Arc<AtomicBool>,
+        ctl_ch: &Receiver<CtlMsg>,
+    ) -> Result<UserExitInfo> {
+        let (res_ch, req_ch) = self.stats_server.channels();
+        let mut next_sched_at = Instant::now() + self.sched_intv;
+        let enable_layer_refresh = !self.layer_refresh_intv.is_zero();
+        let mut next_layer_refresh_at = Instant::now() + self.layer_refresh_intv;
+        let mut cpus_ranges = HashMap::<ThreadId, Vec<(usize, usize)>>::new();
+        let mut assign_subs = HashMap::<ThreadId, TaskAssignments>::new();
+
+        while !shutdown.load(Ordering::Relaxed) && !uei_exited!(&self.skel, uei) {
+            if RELOAD_SPECS.swap(false, Ordering::Relaxed) {
+                if let Err(e) = self.reload_layer_specs() {
+                    warn!(
+                        "Failed to reload layer specs, keeping the current ones ({:#})",
+                        e
+                    );
+                }
+            }
+
+            let now = Instant::now();
+
+            if now >= next_sched_at {
+                self.step()?;
+                while next_sched_at < now {
+                    next_sched_at += self.sched_intv;
+                }
+            }
+
+            if !assign_subs.is_empty() || self.mempolicy_tx.is_some() {
+                self.relay_task_assignments(&mut assign_subs)?;
+            }
+
+            if enable_layer_refresh && now >= next_layer_refresh_at {
                 self.skel.maps.bss_data.layer_refresh_seq_avgruntime += 1;
                 while next_layer_refresh_at < now {
                     next_layer_refresh_at += self.layer_refresh_intv;
                 }
             }
 
-            match req_ch.recv_deadline(next_sched_at) {
-                Ok(StatsReq::Hello(tid)) => {
-                    cpus_ranges.insert(
-                        tid,
-                        self.layers.iter().map(|l| (l.nr_cpus, l.nr_cpus)).collect(),
-                    );
-                    let stats = Stats::new(&mut self.skel, &self.proc_reader)?;
-                    res_ch.send(StatsRes::Hello(stats))?;
-                }
-                Ok(StatsReq::Refresh(tid, mut stats)) => {
-                    // Propagate self's layer cpu ranges into each stat's.
-                    for i in 0..self.nr_layer_cpus_ranges.len() {
-                        for (_, ranges) in cpus_ranges.iter_mut() {
-                            ranges[i] = (
-                                ranges[i].0.min(self.nr_layer_cpus_ranges[i].0),
-                                ranges[i].1.max(self.nr_layer_cpus_ranges[i].1),
-                            );
-                        }
-                        self.nr_layer_cpus_ranges[i] =
-                            (self.layers[i].nr_cpus, self.layers[i].nr_cpus);
-                    }
-
-                    stats.refresh(&mut self.skel, &self.proc_reader, now, self.processing_dur)?;
-                    let sys_stats =
-                        self.generate_sys_stats(&stats, cpus_ranges.get_mut(&tid).unwrap())?;
-                    res_ch.send(StatsRes::Refreshed((stats, sys_stats)))?;
-                }
-                Ok(StatsReq::Bye(tid)) => {
-                    cpus_ranges.remove(&tid);
-                    res_ch.send(StatsRes::Bye)?;
-                }
-                Err(RecvTimeoutError::Timeout) => {}
-                Err(e) => Err(e)?,
+            let req = crossbeam::select! {
+                recv(req_ch) -> req => req.map_err(|_| RecvTimeoutError::Disconnected),
+                recv(ctl_ch) -> msg => {
+                    if let Ok((req, ctl_res_ch)) = msg {
+                        let _ = ctl_res_ch.send(self.handle_ctl_req(req));
+                    }
+                    continue;
+                }
+                default(next_sched_at.saturating_duration_since(Instant::now())) => {
+                    Err(RecvTimeoutError::Timeout)
+                }
+            };
+
+            match req {
+                Ok(StatsReq::Hello(tid)) => {
+                    cpus_ranges.insert(
+                        tid,
+                        self.layers.iter().map(|l| (l.nr_cpus, l.nr_cpus)).collect(),
+                    );
+                    let stats = Stats::new(&mut self.skel, &self.proc_reader)?;
+                    res_ch.send(StatsRes::Hello(stats))?;
+                }
+                Ok(StatsReq::Refresh(tid, mut stats)) => {
+                    // Propagate self's layer cpu ranges into each stat's. A
+                    // spec reload may have changed the number of layers.
+                    for (_, ranges) in cpus_ranges.iter_mut() {
+                        ranges.resize(self.nr_layer_cpus_ranges.len(), (usize::MAX, 0));
+                    }
+                    for i in 0..self.nr_layer_cpus_ranges.len() {
+                        for (_, ranges) in cpus_ranges.iter_mut() {
+                            ranges[i] = (
+                                ranges[i].0.min(self.nr_layer_cpus_ranges[i].0),
+                                ranges[i].1.max(self.nr_layer_cpus_ranges[i].1),
+                            );
+                        }
+                        self.nr_layer_cpus_ranges[i] =
+                            (self.layers[i].nr_cpus, self.layers[i].nr_cpus);
+                    }
+
+                    stats.refresh(&mut self.skel, &self.proc_reader, now, self.processing_dur)?;
+                    let sys_stats =
+                        self.generate_sys_stats(&stats, cpus_ranges.get_mut(&tid).unwrap())?;
+                    res_ch.send(StatsRes::Refreshed((stats, sys_stats)))?;
+                }
+                Ok(StatsReq::Bye(tid)) => {
+                    cpus_ranges.remove(&tid);
+                    res_ch.send(StatsRes::Bye)?;
+                }
+                Ok(StatsReq::AssignOpen(tid)) => {
+                    // Don't hand out what was queued before the reader.
+                    self.relay_task_assignments(&mut assign_subs)?;
+                    assign_subs.insert(tid, TaskAssignments::default());
+                    self.skel.maps.bss_data.emit_assign_events = true;
+                    res_ch.send(StatsRes::Ack)?;
+                }
+                Ok(StatsReq::AssignRead(tid)) => {
+                    self.relay_task_assignments(&mut assign_subs)?;
+                    let tas = assign_subs.get_mut(&tid).map(std::mem::take);
+                    res_ch.send(StatsRes::Assignments(tas.unwrap_or_default()))?;
+                }
+                Ok(StatsReq::AssignClose(tid)) => {
+                    assign_subs.remove(&tid);
+                    self.skel.maps.bss_data.emit_assign_events =
+                        !assign_subs.is_empty() || self.mempolicy_tx.is_some();
+                    res_ch.send(StatsRes::Ack)?;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(e) => Err(e)?,
+            }
+        }
+
+        let _ = self.struct_ops.take();
+        uei_report!(&self.skel, uei)
+    }
+}
+
+impl Drop for Scheduler<'_> {
+    fn drop(&mut self) {
+        if let Some(struct_ops) = self.struct_ops.take() {
+            drop(struct_ops);
+        }
+    }
+}
+
+fn write_example_file(path: &str) -> Result<()> {
+    let mut f = fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(path)?;
+    Ok(f.write_all(serde_json::to_string_pretty(&*EXAMPLE_CONFIG)?.as_bytes())?)
+}
+
+/// Set by SIGHUP, consumed by Scheduler::run().
+static RELOAD_SPECS: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sighup(_: libc::c_int) {
+    RELOAD_SPECS.store(true, Ordering::Relaxed);
+}
+
+/// Parse the layer specs from @opts, expand templates and fill in the
+/// defaults. Used both on startup and on reload.
+fn load_layer_config(opts: &Opts) -> Result<LayerConfig> {
+    let mut layer_config = match opts.run_example {
+        true => EXAMPLE_CONFIG.clone(),
+        false => LayerConfig { specs: vec![] },
+    };
+
+    // Inheritance may cross inputs, collect all the entries first.
+    let mut entries = vec![];
+    for (idx, input) in opts.specs.iter().enumerate() {
+        entries.extend(
+            LayerSpec::parse_raw(input)
+                .context(format!("Failed to parse specs[{}] ({:?})", idx, input))?,
+        );
+    }
+
+    let specs = LayerSpec::from_raw(entries)?;
+
+    for spec in specs {
+        match spec.template {
+            Some(ref rule) => {
+                let matches = expand_template(&rule)?;
+                for (mt, mask) in matches {
+                    let mut genspec = spec.clone();
+
+                    genspec.cpuset = Some(mask);
+
+                    // Push the new "and" rule into each "or" term.
+                    for orterm in &mut genspec.matches {
+                        orterm.push(mt.clone());
+                    }
+
+                    match &mt {
+                        LayerMatch::CgroupSuffix(cgroup) => genspec.name.push_str(cgroup),
+                        _ => bail!("Template match has unexpected type"),
+                    }
+
+                    // Push the generated layer into the config
+                    layer_config.specs.push(genspec);
+                }
+            }
+
+            None => {
+                layer_config.specs.push(spec);
+            }
+        }
+    }
+
+    for spec in layer_config.specs.iter_mut() {
+        let common = spec.kind.common_mut();
+
+        if common.slice_us == 0 {
+            common.slice_us = opts.slice_us;
+        }
+
+        if common.weight == 0 {
+            common.weight = DEFAULT_LAYER_WEIGHT;
+        }
+        common.weight = common.weight.clamp(MIN_LAYER_WEIGHT, MAX_LAYER_WEIGHT);
+
+        if common.preempt {
+            if common.disallow_open_after_us.is_some() {
+                warn!(
+                    "Preempt layer {} has non-null disallow_open_after_us, ignored",
+                    &spec.name
+                );
+            }
+            if common.disallow_preempt_after_us.is_some() {
+                warn!(
+                    "Preempt layer {} has non-null disallow_preempt_after_us, ignored",
+                    &spec.name
+                );
+            }
+            common.disallow_open_after_us = Some(u64::MAX);
+            common.disallow_preempt_after_us = Some(u64::MAX);
+        } else {
+            if common.disallow_open_after_us.is_none() {
+                common.disallow_open_after_us = Some(*DFL_DISALLOW_OPEN_AFTER_US);
+            }
+
+            if common.disallow_preempt_after_us.is_none() {
+                common.disallow_preempt_after_us = Some(*DFL_DISALLOW_PREEMPT_AFTER_US);
             }
         }
 
-        let _ = self.struct_ops.take();
-        uei_report!(&self.skel, uei)
+        if common.idle_smt.is_some() {
+            warn!("Layer {} has deprecated flag \"idle_smt\"", &spec.name);
+        }
     }
+
+    Ok(layer_config)
 }
 
-impl Drop for Scheduler<'_> {
-    fn drop(&mut self) {
-        if let Some(struct_ops) = self.struct_ops.take() {
-            drop(struct_ops);
+/// How the layers change when switching from @old to @new. Layers are
+/// matched by name.
+#[derive(Debug, PartialEq)]
+struct LayerSpecsDiff {
+    /// The index in @old of each layer of @new, None for the added ones.
+    old_idx: Vec<Option<usize>>,
+    /// The indices in @new of the added, moved and modified layers.
+    changed: Vec<usize>,
+    /// The indices in @old of the removed layers.
+    removed: Vec<usize>,
+}
+
+impl LayerSpecsDiff {
+    /// Whether some of the existing layer indices now mean other layers.
+    fn remapped(&self) -> bool {
+        !self.removed.is_empty()
+            || self
+                .old_idx
+                .iter()
+                .enumerate()
+                .any(|(idx, old_idx)| old_idx.is_some_and(|old_idx| old_idx != idx))
+    }
+}
+
+fn layer_specs_diff(old: &[LayerSpec], new: &[LayerSpec]) -> LayerSpecsDiff {
+    let spec_eq = |a: &LayerSpec, b: &LayerSpec| {
+        a.cpuset == b.cpuset && serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+    };
+
+    let old_idx: Vec<Option<usize>> = new
+        .iter()
+        .map(|n| old.iter().position(|o| o.name == n.name))
+        .collect();
+    let changed = old_idx
+        .iter()
+        .enumerate()
+        .filter(|(idx, old_idx)| match old_idx {
+            Some(old_idx) => old_idx != idx || !spec_eq(&old[*old_idx], &new[*idx]),
+            None => true,
+        })
+        .map(|(idx, _)| idx)
+        .collect();
+    let removed = (0..old.len())
+        .filter(|idx| !old_idx.contains(&Some(*idx)))
+        .collect();
+
+    LayerSpecsDiff {
+        old_idx,
+        changed,
+        removed,
+    }
+}
+
+/// The bitmap of the layers which are anti-affine to @specs[@idx], in either
+/// direction.
+fn anti_affine_layers(specs: &[LayerSpec], idx: usize) -> u64 {
+    let name = &specs[idx].name;
+    let mut mask = 0;
+    for (other_idx, other) in specs.iter().enumerate() {
+        if other_idx != idx
+            && (specs[idx].kind.common().anti_affinity.contains(&other.name)
+                || other.kind.common().anti_affinity.contains(name))
+        {
+            mask |= 1 << other_idx;
         }
     }
+    mask
 }
 
-fn write_example_file(path: &str) -> Result<()> {
-    let mut f = fs::OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(path)?;
-    Ok(f.write_all(serde_json::to_string_pretty(&*EXAMPLE_CONFIG)?.as_bytes())?)
+/// The unique CgroupRegex patterns in @specs. The index of each pattern is
+/// its cgroup_regex_id in BPF.
+fn cgroup_regexes(specs: &[LayerSpec]) -> Vec<String> {
+    let mut regexes: Vec<String> = vec![];
+    for mt in specs.iter().flat_map(|spec| spec.matches.iter().flatten()) {
+        if let LayerMatch::CgroupRegex(regex) = mt {
+            if !regexes.contains(regex) {
+                regexes.push(regex.clone());
+            }
+        }
+    }
+    regexes
+}
+
+/// The bitmap of the cgroup_regex_id's of @regexes which match the cgroup
+/// @path, formatted as in format_cgrp_path() in BPF.
+fn cgroup_regex_mask(regexes: &[Regex], path: &str) -> u64 {
+    let mut mask = 0u64;
+    for (id, re) in regexes.iter().enumerate() {
+        if re.is_match(path) {
+            mask |= 1 << id;
+        }
+    }
+    mask
+}
+
+fn is_cgroup_match(mt: &LayerMatch) -> bool {
+    matches!(
+        mt,
+        LayerMatch::CgroupPrefix(_)
+            | LayerMatch::CgroupSuffix(_)
+            | LayerMatch::CgroupContains(_)
+            | LayerMatch::CgroupRegex(_)
+    )
+}
+
+/// Whether the cgroup @path, formatted as in format_cgrp_path() in BPF, is
+/// matched by one of the OR blocks of @matches which only test the cgroup
+/// path. All the tasks in such a cgroup belong to the layer unless claimed
+/// by an earlier one.
+fn cgroup_matched_whole(matches: &[Vec<LayerMatch>], path: &str) -> Result<bool> {
+    for ands in matches.iter() {
+        if ands.is_empty() || !ands.iter().all(is_cgroup_match) {
+            continue;
+        }
+        let mut matched = true;
+        for mt in ands.iter() {
+            matched &= match mt {
+                LayerMatch::CgroupPrefix(prefix) => path.starts_with(prefix.as_str()),
+                LayerMatch::CgroupSuffix(suffix) => path.ends_with(suffix.as_str()),
+                LayerMatch::CgroupContains(substr) => path.contains(substr.as_str()),
+                LayerMatch::CgroupRegex(regex) => Regex::new(regex)?.is_match(path),
+                _ => unreachable!(),
+            };
+        }
+        if matched {
+            return Ok(true);
+        }
+    }
+    Ok(false)
 }
 
 fn verify_layer_specs(specs: &[LayerSpec]) -> Result<()> {
@@ -2800,8 +4580,16 @@ fn verify_layer_specs(specs: &[LayerSpec]) -> Result<()> {
     if nr_specs > MAX_LAYERS {
         bail!("Too many layer specs");
     }
+    if cgroup_regexes(specs).len() > MAX_CGROUP_REGEXES {
+        bail!("Too many unique cgroup regexes, max {}", MAX_CGROUP_REGEXES);
+    }
 
     for (idx, spec) in specs.iter().enumerate() {
+        // Spec reloads and the control API find the layers by name.
+        if specs[..idx].iter().any(|other| other.name == spec.name) {
+            bail!("Duplicate layer name {:?}", spec.name);
+        }
+
         if idx < nr_specs - 1 {
             if spec.matches.is_empty() {
                 bail!("Non-terminal spec {:?} has NULL matches", spec.name);
@@ -2856,6 +4644,30 @@ fn verify_layer_specs(specs: &[LayerSpec]) -> Result<()> {
                             bail!("Spec {:?} has too long a process name prefix", spec.name);
                         }
                     }
+                    LayerMatch::CommGlob(pattern) | LayerMatch::PcommGlob(pattern) => {
+                        if pattern.len() >= MAX_COMM {
+                            bail!("Spec {:?} has too long a comm glob", spec.name);
+                        }
+                    }
+                    LayerMatch::AncestorCommPrefix(prefix) => {
+                        if prefix.len() >= MAX_COMM {
+                            bail!("Spec {:?} has too long an ancestor comm prefix", spec.name);
+                        }
+                    }
+                    LayerMatch::CgroupRegex(regex) => {
+                        if let Err(e) = Regex::new(regex) {
+                            bail!("Spec {:?} has invalid cgroup regex ({})", spec.name, e);
+                        }
+                    }
+                    LayerMatch::TGIDIn(tgids) => {
+                        if tgids.is_empty() || tgids.len() > MAX_MATCH_TGIDS {
+                            bail!(
+                                "Spec {:?} must have between 1 and {} TGIDIn values",
+                                spec.name,
+                                MAX_MATCH_TGIDS
+                            );
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -2893,6 +4705,131 @@ fn verify_layer_specs(specs: &[LayerSpec]) -> Result<()> {
             }
             _ => {}
         }
+
+        let common = spec.kind.common();
+        if common.perf_min > 1024 || common.perf_max > 1024 {
+            bail!("Spec {:?} has perf_min or perf_max above 1024", spec.name);
+        }
+        if common.perf_min > 0 && common.perf_max > 0 && common.perf_min > common.perf_max {
+            bail!(
+                "Spec {:?} has perf_min {} above perf_max {}",
+                spec.name,
+                common.perf_min,
+                common.perf_max
+            );
+        }
+        for (sched_idx, sched) in spec.schedules.iter().enumerate() {
+            let what = format!("Spec {:?} schedule {}", spec.name, sched_idx);
+            sched.weekdays().with_context(|| what.clone())?;
+            sched.time_window().with_context(|| what.clone())?;
+            if sched.days.is_empty() && sched.time.is_none() && sched.trigger_file.is_none() {
+                bail!("{} has no days, time or trigger_file", what);
+            }
+            if matches!(spec.kind, LayerKind::Open { .. })
+                && (sched.util_range.is_some() || sched.cpus_range.is_some())
+            {
+                bail!(
+                    "{} can't set util_range or cpus_range of an open layer",
+                    what
+                );
+            }
+            if let Some(util_range) = sched.util_range {
+                if util_range.0 < 0.0 || util_range.0 >= util_range.1 {
+                    bail!("{} has invalid util_range {:?}", what, util_range);
+                }
+            }
+            if let Some(cpus_range) = sched.cpus_range {
+                if cpus_range.0 > cpus_range.1 || cpus_range.1 == 0 {
+                    bail!("{} has invalid cpus_range {:?}", what, cpus_range);
+                }
+            }
+            if let Some(weight) = sched.weight {
+                if !(MIN_LAYER_WEIGHT..=MAX_LAYER_WEIGHT).contains(&weight) {
+                    bail!(
+                        "{} has weight {} outside [{}, {}]",
+                        what,
+                        weight,
+                        MIN_LAYER_WEIGHT,
+                        MAX_LAYER_WEIGHT
+                    );
+                }
+            }
+        }
+        match &common.sizing {
+            LayerSizingPolicy::UtilRange => {}
+            _ if matches!(spec.kind, LayerKind::Open { .. }) => {
+                bail!("Spec {:?} can't set sizing of an open layer", spec.name);
+            }
+            LayerSizingPolicy::Pid {
+                target_lat_us,
+                kp,
+                ki,
+                kd,
+            } => {
+                if *target_lat_us == 0 {
+                    bail!("Spec {:?} has Pid sizing without target_lat_us", spec.name);
+                }
+                if *kp < 0.0 || *ki < 0.0 || *kd < 0.0 {
+                    bail!("Spec {:?} has negative Pid sizing gains", spec.name);
+                }
+            }
+            LayerSizingPolicy::PropShare { headroom, .. } => {
+                if *headroom < 0.0 {
+                    bail!("Spec {:?} has negative PropShare headroom", spec.name);
+                }
+            }
+        }
+        if common.mempolicy != LayerMemPolicy::None
+            && common.nodes.is_empty()
+            && common.llcs.is_empty()
+        {
+            bail!(
+                "Spec {:?} has mempolicy without nodes or llcs to apply it for",
+                spec.name
+            );
+        }
+        if common.mempolicy == LayerMemPolicy::CgroupMems
+            && !spec
+                .matches
+                .iter()
+                .any(|ands| !ands.is_empty() && ands.iter().all(is_cgroup_match))
+        {
+            bail!(
+                "Spec {:?} has CgroupMems mempolicy without an OR block matching only on cgroups",
+                spec.name
+            );
+        }
+        if common.qos_tier >= MAX_QOS_TIERS {
+            bail!(
+                "Spec {:?} has qos_tier {} which should be below {}",
+                spec.name,
+                common.qos_tier,
+                MAX_QOS_TIERS
+            );
+        }
+        if common.preempt_budget.is_some() && !common.preempt {
+            bail!("Spec {:?} has preempt_budget without preempt", spec.name);
+        }
+        if let Some(slo) = &common.slo {
+            if slo.lat_us == 0 {
+                bail!("Spec {:?} has SLO without lat_us", spec.name);
+            }
+            if slo.pct <= 0.0 || slo.pct > 100.0 {
+                bail!("Spec {:?} has invalid SLO pct {}", spec.name, slo.pct);
+            }
+        }
+        for name in common.anti_affinity.iter() {
+            if *name == spec.name {
+                bail!("Spec {:?} can't be anti-affine to itself", spec.name);
+            }
+            if !specs.iter().any(|other| other.name == *name) {
+                bail!(
+                    "Spec {:?} is anti-affine to unknown layer {:?}",
+                    spec.name,
+                    name
+                );
+            }
+        }
     }
 
     Ok(())
@@ -3025,108 +4962,303 @@ fn main() -> Result<()> {
         }
     }
 
+    if opts.monitor_assignments {
+        return stats::monitor_assignments(shutdown.clone());
+    }
+
     if let Some(path) = &opts.example {
         write_example_file(path)?;
         return Ok(());
     }
 
-    let mut layer_config = match opts.run_example {
-        true => EXAMPLE_CONFIG.clone(),
-        false => LayerConfig { specs: vec![] },
+    let layer_config = load_layer_config(&opts)?;
+
+    if opts.print_and_exit {
+        println!("specs={}", serde_json::to_string_pretty(&layer_config)?);
+        return Ok(());
+    }
+
+    debug!("specs={}", serde_json::to_string_pretty(&layer_config)?);
+    verify_layer_specs(&layer_config.specs)?;
+
+    // SIGHUP reloads the layer specs instead of terminating, see --help.
+    unsafe {
+        libc::signal(libc::SIGHUP, handle_sighup as libc::sighandler_t);
+    }
+
+    let varlink = match &opts.varlink {
+        Some(path) => Some(VarlinkServer::launch(Path::new(path))?),
+        None => None,
+    };
+    let ctl_ch = match &varlink {
+        Some(server) => server.channel(),
+        None => crossbeam::channel::never(),
     };
 
-    for (idx, input) in opts.specs.iter().enumerate() {
-        let specs = LayerSpec::parse(input)
-            .context(format!("Failed to parse specs[{}] ({:?})", idx, input))?;
+    let mut layer_specs = layer_config.specs;
+    let mut pid_layers = BTreeMap::new();
+    let mut open_object = MaybeUninit::uninit();
+    loop {
+        let mut sched = Scheduler::init(&opts, &layer_specs, &mut open_object)?;
+        sched.restore_pid_layers(std::mem::take(&mut pid_layers))?;
+        let uei = sched.run(shutdown.clone(), &ctl_ch)?;
+        // Keep the layer changes made through SIGHUP and the control API.
+        pid_layers = std::mem::take(&mut sched.pid_layers);
+        layer_specs = std::mem::take(&mut sched.layer_specs);
+        if !uei.should_restart() {
+            break;
+        }
+    }
 
-        for spec in specs {
-            match spec.template {
-                Some(ref rule) => {
-                    let matches = expand_template(&rule)?;
-                    for (mt, mask) in matches {
-                        let mut genspec = spec.clone();
+    Ok(())
+}
 
-                        genspec.cpuset = Some(mask);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A layer with @matches followed by the terminal layer.
+    fn specs_with_matches(matches: &str) -> Vec<LayerSpec> {
+        LayerSpec::parse(&format!(
+            r#"[
+                {{"name": "a", "matches": {}, "kind": {{"Open": {{}}}}}},
+                {{"name": "rest", "matches": [[]], "kind": {{"Open": {{}}}}}}
+            ]"#,
+            matches
+        ))
+        .unwrap()
+    }
 
-                        // Push the new "and" rule into each "or" term.
-                        for orterm in &mut genspec.matches {
-                            orterm.push(mt.clone());
-                        }
+    #[test]
+    fn test_example_matches() {
+        let specs = LayerSpec::parse(include_str!("../examples/desktop_matches.json")).unwrap();
+        verify_layer_specs(&specs).unwrap();
+        assert_eq!(
+            cgroup_regexes(&specs),
+            vec![r"^user\.slice/.*/app-(firefox|chromium)[^/]*\.scope/"]
+        );
+    }
 
-                        match &mt {
-                            LayerMatch::CgroupSuffix(cgroup) => genspec.name.push_str(cgroup),
-                            _ => bail!("Template match has unexpected type"),
-                        }
+    #[test]
+    fn test_cgroup_regexes() {
+        let specs = specs_with_matches(
+            r#"[
+                [{"CgroupRegex": "^system\\.slice/"}],
+                [{"CgroupRegex": "\\.scope/$"}, {"CommGlob": "foo*"}],
+                [{"CgroupRegex": "^system\\.slice/"}, {"NiceAbove": 0}]
+            ]"#,
+        );
+        verify_layer_specs(&specs).unwrap();
 
-                        // Push the generated layer into the config
-                        layer_config.specs.push(genspec);
-                    }
-                }
+        // Unique and in the order of appearance, which is the
+        // cgroup_regex_id in BPF.
+        let regexes = cgroup_regexes(&specs);
+        assert_eq!(regexes, vec![r"^system\.slice/", r"\.scope/$"]);
 
-                None => {
-                    layer_config.specs.push(spec);
-                }
-            }
-        }
+        let regexes: Vec<Regex> = regexes.iter().map(|re| Regex::new(re).unwrap()).collect();
+        assert_eq!(
+            cgroup_regex_mask(&regexes, "system.slice/foo.service/"),
+            0b01
+        );
+        assert_eq!(cgroup_regex_mask(&regexes, "system.slice/foo.scope/"), 0b11);
+        assert_eq!(cgroup_regex_mask(&regexes, "user.slice/foo.scope/"), 0b10);
+        assert_eq!(cgroup_regex_mask(&regexes, "/"), 0);
+        assert_eq!(cgroup_regex_mask(&[], "system.slice/"), 0);
     }
 
-    for spec in layer_config.specs.iter_mut() {
-        let common = spec.kind.common_mut();
+    #[test]
+    fn test_verify_matches() {
+        let ok = |matches: &str| verify_layer_specs(&specs_with_matches(matches)).is_ok();
 
-        if common.slice_us == 0 {
-            common.slice_us = opts.slice_us;
-        }
+        assert!(ok(r#"[[{"CommGlob": "kworker/*:?"}]]"#));
+        assert!(ok(r#"[[{"PcommGlob": "123456789012345"}]]"#));
+        assert!(!ok(r#"[[{"PcommGlob": "1234567890123456"}]]"#));
+        assert!(!ok(r#"[[{"CommGlob": "1234567890123456"}]]"#));
 
-        if common.weight == 0 {
-            common.weight = DEFAULT_LAYER_WEIGHT;
-        }
-        common.weight = common.weight.clamp(MIN_LAYER_WEIGHT, MAX_LAYER_WEIGHT);
+        assert!(ok(r#"[[{"AncestorCommPrefix": "steam"}]]"#));
+        assert!(!ok(r#"[[{"AncestorCommPrefix": "1234567890123456"}]]"#));
 
-        if common.preempt {
-            if common.disallow_open_after_us.is_some() {
-                warn!(
-                    "Preempt layer {} has non-null disallow_open_after_us, ignored",
-                    &spec.name
-                );
-            }
-            if common.disallow_preempt_after_us.is_some() {
-                warn!(
-                    "Preempt layer {} has non-null disallow_preempt_after_us, ignored",
-                    &spec.name
-                );
-            }
-            common.disallow_open_after_us = Some(u64::MAX);
-            common.disallow_preempt_after_us = Some(u64::MAX);
-        } else {
-            if common.disallow_open_after_us.is_none() {
-                common.disallow_open_after_us = Some(*DFL_DISALLOW_OPEN_AFTER_US);
-            }
+        assert!(ok(r#"[[{"CgroupRegex": "^a/(b|c)/"}]]"#));
+        assert!(!ok(r#"[[{"CgroupRegex": "^a/(b"}]]"#));
 
-            if common.disallow_preempt_after_us.is_none() {
-                common.disallow_preempt_after_us = Some(*DFL_DISALLOW_PREEMPT_AFTER_US);
-            }
-        }
+        let tgids = |nr: usize| {
+            (1..=nr)
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        assert!(ok(&format!(r#"[[{{"TGIDIn": [{}]}}]]"#, tgids(1))));
+        assert!(ok(&format!(
+            r#"[[{{"TGIDIn": [{}]}}]]"#,
+            tgids(MAX_MATCH_TGIDS)
+        )));
+        assert!(!ok(&format!(
+            r#"[[{{"TGIDIn": [{}]}}]]"#,
+            tgids(MAX_MATCH_TGIDS + 1)
+        )));
+        assert!(!ok(r#"[[{"TGIDIn": []}]]"#));
+    }
 
-        if common.idle_smt.is_some() {
-            warn!("Layer {} has deprecated flag \"idle_smt\"", &spec.name);
-        }
+    #[test]
+    fn test_cgroup_matched_whole() {
+        let matches: Vec<Vec<LayerMatch>> = serde_json::from_str(
+            r#"[
+                [{"CgroupPrefix": "system.slice/"}, {"CgroupSuffix": ".service/"}],
+                [{"CgroupRegex": "^workload/[0-9]+/"}],
+                [{"CgroupPrefix": "user.slice/"}, {"CommPrefix": "steam"}]
+            ]"#,
+        )
+        .unwrap();
+        let whole = |path: &str| cgroup_matched_whole(&matches, path).unwrap();
+
+        assert!(whole("system.slice/foo.service/"));
+        assert!(!whole("system.slice/foo.scope/"));
+        assert!(whole("workload/42/"));
+        assert!(!whole("workload/x/"));
+        // Only matched together with the comm, other tasks may be in it.
+        assert!(!whole("user.slice/"));
+        assert!(!whole("/"));
+
+        let mut specs = specs_with_matches(r#"[[{"CommPrefix": "steam"}]]"#);
+        let common = specs[0].kind.common_mut();
+        common.mempolicy = LayerMemPolicy::CgroupMems;
+        common.nodes = vec![0];
+        assert!(verify_layer_specs(&specs).is_err());
+        specs[0].matches = matches;
+        assert!(verify_layer_specs(&specs).is_ok());
     }
 
-    if opts.print_and_exit {
-        println!("specs={}", serde_json::to_string_pretty(&layer_config)?);
-        return Ok(());
+    #[test]
+    fn test_too_many_cgroup_regexes() {
+        // Spread over layers as each can only have MAX_LAYER_MATCH_ORS.
+        let specs_with_regexes = |nr: usize| {
+            let mut layers: Vec<String> = (0..nr.div_ceil(MAX_LAYER_MATCH_ORS))
+                .map(|i| {
+                    let ors: Vec<String> = (i * MAX_LAYER_MATCH_ORS
+                        ..nr.min((i + 1) * MAX_LAYER_MATCH_ORS))
+                        .map(|j| format!(r#"[{{"CgroupRegex": "^{}/"}}]"#, j))
+                        .collect();
+                    format!(
+                        r#"{{"name": "l{}", "matches": [{}], "kind": {{"Open": {{}}}}}}"#,
+                        i,
+                        ors.join(", ")
+                    )
+                })
+                .collect();
+            layers.push(r#"{"name": "rest", "matches": [[]], "kind": {"Open": {}}}"#.into());
+            LayerSpec::parse(&format!("[{}]", layers.join(", "))).unwrap()
+        };
+
+        let specs = specs_with_regexes(MAX_CGROUP_REGEXES);
+        assert_eq!(cgroup_regexes(&specs).len(), MAX_CGROUP_REGEXES);
+        verify_layer_specs(&specs).unwrap();
+        assert!(verify_layer_specs(&specs_with_regexes(MAX_CGROUP_REGEXES + 1)).is_err());
     }
 
-    debug!("specs={}", serde_json::to_string_pretty(&layer_config)?);
-    verify_layer_specs(&layer_config.specs)?;
+    #[test]
+    fn test_anti_affine_layers() {
+        let specs = LayerSpec::parse(
+            r#"[
+                {"name": "a", "matches": [[]], "kind": {"Confined": {"util_range": [0.5, 0.8], "anti_affinity": ["b", "c"]}}},
+                {"name": "b", "matches": [[]], "kind": {"Confined": {"util_range": [0.5, 0.8]}}},
+                {"name": "c", "matches": [[]], "kind": {"Grouped": {"util_range": [0.5, 0.8], "anti_affinity": ["a"]}}},
+                {"name": "d", "matches": [[]], "kind": {"Open": {"anti_affinity": ["b"]}}}
+            ]"#,
+        )
+        .unwrap();
 
-    let mut open_object = MaybeUninit::uninit();
-    loop {
-        let mut sched = Scheduler::init(&opts, &layer_config.specs, &mut open_object)?;
-        if !sched.run(shutdown.clone())?.should_restart() {
-            break;
-        }
+        // The relation is symmetric and duplicates don't matter.
+        let masks: Vec<u64> = (0..specs.len())
+            .map(|idx| anti_affine_layers(&specs, idx))
+            .collect();
+        assert_eq!(masks, vec![0b0110, 0b1001, 0b0001, 0b0010]);
+
+        let verify = |anti_affinity: &str| {
+            verify_layer_specs(
+                &LayerSpec::parse(&format!(
+                    r#"[
+                        {{"name": "a", "matches": [[]], "kind": {{"Open": {{"anti_affinity": {}}}}}}},
+                        {{"name": "b", "matches": [[]], "kind": {{"Open": {{}}}}}}
+                    ]"#,
+                    anti_affinity
+                ))
+                .unwrap(),
+            )
+        };
+        assert!(verify(r#"["b"]"#).is_ok());
+        assert!(verify(r#"["a"]"#).is_err());
+        assert!(verify(r#"["nope"]"#).is_err());
     }
 
-    Ok(())
+    #[test]
+    fn test_layer_specs_diff() {
+        let specs = |layers: &[(&str, &str)]| {
+            let layers: Vec<String> = layers
+                .iter()
+                .map(|(name, kind)| {
+                    format!(
+                        r#"{{"name": "{}", "matches": [[]], "kind": {{{}}}}}"#,
+                        name, kind
+                    )
+                })
+                .collect();
+            LayerSpec::parse(&format!("[{}]", layers.join(", "))).unwrap()
+        };
+        let diff = |old: &[(&str, &str)], new: &[(&str, &str)]| {
+            let d = layer_specs_diff(&specs(old), &specs(new));
+            let remapped = d.remapped();
+            (d, remapped)
+        };
+        let open = r#""Open": {}"#;
+        let confined = r#""Confined": {"util_range": [0.5, 1.0]}"#;
+
+        let (d, remapped) = diff(&[("a", open), ("b", open)], &[("a", open), ("b", open)]);
+        assert_eq!(
+            d,
+            LayerSpecsDiff {
+                old_idx: vec![Some(0), Some(1)],
+                changed: vec![],
+                removed: vec![],
+            }
+        );
+        assert!(!remapped);
+
+        // Kind changes are applied in place.
+        let (d, remapped) = diff(&[("a", open), ("b", open)], &[("a", confined), ("b", open)]);
+        assert_eq!(d.changed, vec![0]);
+        assert!(!remapped);
+
+        // Appending doesn't move the existing layers.
+        let (d, remapped) = diff(&[("a", open)], &[("a", open), ("b", open)]);
+        assert_eq!(d.old_idx, vec![Some(0), None]);
+        assert_eq!(d.changed, vec![1]);
+        assert!(!remapped);
+
+        // Inserting and removing in the middle move the following layers.
+        let (d, remapped) = diff(
+            &[("a", open), ("c", open)],
+            &[("a", open), ("b", open), ("c", open)],
+        );
+        assert_eq!(d.old_idx, vec![Some(0), None, Some(1)]);
+        assert_eq!(d.changed, vec![1, 2]);
+        assert!(remapped);
+
+        let (d, remapped) = diff(
+            &[("a", open), ("b", open), ("c", open)],
+            &[("a", open), ("c", open)],
+        );
+        assert_eq!(d.old_idx, vec![Some(0), Some(2)]);
+        assert_eq!(d.changed, vec![1]);
+        assert_eq!(d.removed, vec![1]);
+        assert!(remapped);
+
+        // Renaming is removing and adding at the same index.
+        let (d, remapped) = diff(&[("a", open), ("b", open)], &[("x", open), ("b", open)]);
+        assert_eq!(d.old_idx, vec![None, Some(1)]);
+        assert_eq!(d.changed, vec![0]);
+        assert_eq!(d.removed, vec![0]);
+        assert!(remapped);
+
+        assert!(verify_layer_specs(&specs(&[("a", open), ("a", open)])).is_err());
+    }
 }
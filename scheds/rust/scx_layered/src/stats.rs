@@ -22,6 +22,7 @@ use serde::Deserialize;
 use serde::Serialize;
 
 use crate::bpf_intf;
+use crate::lat_hist_pct_us;
 use crate::BpfStats;
 use crate::Layer;
 use crate::Stats;
@@ -59,6 +60,8 @@ const LSTAT_PREEMPT_XLLC: usize = bpf_intf::layer_stat_id_LSTAT_PREEMPT_XLLC as
 const LSTAT_PREEMPT_XNUMA: usize = bpf_intf::layer_stat_id_LSTAT_PREEMPT_XNUMA as usize;
 const LSTAT_PREEMPT_IDLE: usize = bpf_intf::layer_stat_id_LSTAT_PREEMPT_IDLE as usize;
 const LSTAT_PREEMPT_FAIL: usize = bpf_intf::layer_stat_id_LSTAT_PREEMPT_FAIL as usize;
+const LSTAT_PREEMPT_RECV: usize = bpf_intf::layer_stat_id_LSTAT_PREEMPT_RECV as usize;
+const LSTAT_PREEMPT_BUDGET: usize = bpf_intf::layer_stat_id_LSTAT_PREEMPT_BUDGET as usize;
 const LSTAT_EXCL_COLLISION: usize = bpf_intf::layer_stat_id_LSTAT_EXCL_COLLISION as usize;
 const LSTAT_EXCL_PREEMPT: usize = bpf_intf::layer_stat_id_LSTAT_EXCL_PREEMPT as usize;
 const LSTAT_YIELD: usize = bpf_intf::layer_stat_id_LSTAT_YIELD as usize;
@@ -72,6 +75,12 @@ const LSTAT_XLAYER_REWAKE: usize = bpf_intf::layer_stat_id_LSTAT_XLAYER_REWAKE a
 const LSTAT_LLC_DRAIN_TRY: usize = bpf_intf::layer_stat_id_LSTAT_LLC_DRAIN_TRY as usize;
 const LSTAT_LLC_DRAIN: usize = bpf_intf::layer_stat_id_LSTAT_LLC_DRAIN as usize;
 const LSTAT_SKIP_REMOTE_NODE: usize = bpf_intf::layer_stat_id_LSTAT_SKIP_REMOTE_NODE as usize;
+const LSTAT_ANTI_AFFN_KICK: usize = bpf_intf::layer_stat_id_LSTAT_ANTI_AFFN_KICK as usize;
+const LSTAT_SLO_MET: usize = bpf_intf::layer_stat_id_LSTAT_SLO_MET as usize;
+const LSTAT_SLO_MISS: usize = bpf_intf::layer_stat_id_LSTAT_SLO_MISS as usize;
+const LSTAT_PERF_CYCLES: usize = bpf_intf::layer_stat_id_LSTAT_PERF_CYCLES as usize;
+const LSTAT_PERF_INSNS: usize = bpf_intf::layer_stat_id_LSTAT_PERF_INSNS as usize;
+const LSTAT_PERF_LLC_MISSES: usize = bpf_intf::layer_stat_id_LSTAT_PERF_LLC_MISSES as usize;
 
 const LLC_LSTAT_LAT: usize = bpf_intf::llc_layer_stat_id_LLC_LSTAT_LAT as usize;
 const LLC_LSTAT_CNT: usize = bpf_intf::llc_layer_stat_id_LLC_LSTAT_CNT as usize;
@@ -152,6 +161,12 @@ pub struct LayerStats {
     pub preempt_idle: f64,
     #[stat(desc = "% attempted to preempt other tasks but failed")]
     pub preempt_fail: f64,
+    #[stat(desc = "% preempted by other layers")]
+    pub preempt_recv: f64,
+    #[stat(desc = "% skipped preempting due to preempt_budget")]
+    pub preempt_budget_fail: f64,
+    #[stat(desc = "QoS tier", _om_skip)]
+    pub qos_tier: u32,
     #[stat(desc = "% violated config due to CPU affinity")]
     pub affn_viol: f64,
     #[stat(desc = "% continued executing after slice expiration")]
@@ -166,6 +181,8 @@ pub struct LayerStats {
     pub excl_collision: f64,
     #[stat(desc = "% a sibling CPU was preempted for an exclusive task")]
     pub excl_preempt: f64,
+    #[stat(desc = "% a sibling CPU was kicked as it was running an anti-affine layer")]
+    pub anti_affn_kick: f64,
     #[stat(desc = "% yielded")]
     pub yielded: f64,
     #[stat(desc = "count of times yield was ignored")]
@@ -188,6 +205,22 @@ pub struct LayerStats {
     pub llc_drain: f64,
     #[stat(desc = "% skip LLC dispatch on remote node")]
     pub skip_remote_node: f64,
+    #[stat(desc = "instructions per cycle, requires --enable-perf-counters")]
+    pub ipc: f64,
+    #[stat(desc = "LLC misses per 1000 instructions, requires --enable-perf-counters")]
+    pub llc_mpki: f64,
+    #[stat(desc = "50th percentile of the delay from runnable to running in usecs")]
+    pub lat_p50_us: u64,
+    #[stat(desc = "99th percentile of the delay from runnable to running in usecs")]
+    pub lat_p99_us: u64,
+    #[stat(desc = "latency SLO target in usecs, 0 if not set")]
+    pub slo_lat_us: u64,
+    #[stat(desc = "% of the samples which must be within the latency SLO")]
+    pub slo_pct: f64,
+    #[stat(desc = "% of the samples within the latency SLO")]
+    pub slo_met: f64,
+    #[stat(desc = "count of consecutive scheduling intervals out of the latency SLO")]
+    pub slo_out_intvs: u32,
     #[stat(desc = "mask of allocated CPUs", _om_skip)]
     pub cpus: Vec<u64>,
     #[stat(desc = "count of CPUs assigned")]
@@ -234,6 +267,8 @@ impl LayerStats {
             .take(LAYER_USAGE_SUM_UPTO + 1)
             .sum::<f64>();
 
+        let slo = layer.kind.common().slo.as_ref();
+
         Self {
             index: lidx,
             util: util_sum * 100.0,
@@ -263,6 +298,9 @@ impl LayerStats {
             preempt_first: lstat_pct(LSTAT_PREEMPT_FIRST),
             preempt_idle: lstat_pct(LSTAT_PREEMPT_IDLE),
             preempt_fail: lstat_pct(LSTAT_PREEMPT_FAIL),
+            preempt_recv: lstat_pct(LSTAT_PREEMPT_RECV),
+            preempt_budget_fail: lstat_pct(LSTAT_PREEMPT_BUDGET),
+            qos_tier: layer.kind.common().qos_tier,
             affn_viol: lstat_pct(LSTAT_AFFN_VIOL),
             keep: lstat_pct(LSTAT_KEEP),
             keep_fail_max_exec: lstat_pct(LSTAT_KEEP_FAIL_MAX_EXEC),
@@ -270,6 +308,7 @@ impl LayerStats {
             is_excl: layer.kind.common().exclusive as u32,
             excl_collision: lstat_pct(LSTAT_EXCL_COLLISION),
             excl_preempt: lstat_pct(LSTAT_EXCL_PREEMPT),
+            anti_affn_kick: lstat_pct(LSTAT_ANTI_AFFN_KICK),
             yielded: lstat_pct(LSTAT_YIELD),
             yield_ignore: lstat(LSTAT_YIELD_IGNORE) as u64,
             migration: lstat_pct(LSTAT_MIGRATION),
@@ -281,6 +320,23 @@ impl LayerStats {
             llc_drain_try: lstat_pct(LSTAT_LLC_DRAIN_TRY),
             llc_drain: lstat_pct(LSTAT_LLC_DRAIN),
             skip_remote_node: lstat_pct(LSTAT_SKIP_REMOTE_NODE),
+            ipc: calc_frac(
+                lstat(LSTAT_PERF_INSNS) as f64,
+                lstat(LSTAT_PERF_CYCLES) as f64,
+            ) / 100.0,
+            llc_mpki: calc_frac(
+                lstat(LSTAT_PERF_LLC_MISSES) as f64,
+                lstat(LSTAT_PERF_INSNS) as f64,
+            ) * 10.0,
+            lat_p50_us: lat_hist_pct_us(&bstats.lat_hists[lidx], 50.0),
+            lat_p99_us: lat_hist_pct_us(&bstats.lat_hists[lidx], 99.0),
+            slo_lat_us: slo.map(|slo| slo.lat_us).unwrap_or(0),
+            slo_pct: slo.map(|slo| slo.pct).unwrap_or(0.0),
+            slo_met: calc_frac(
+                lstat(LSTAT_SLO_MET) as f64,
+                (lstat(LSTAT_SLO_MET) + lstat(LSTAT_SLO_MISS)) as f64,
+            ),
+            slo_out_intvs: layer.slo_out_intvs,
             cpus: layer.cpus.as_raw_slice().to_vec(),
             cur_nr_cpus: layer.cpus.weight() as u32,
             min_nr_cpus: nr_cpus_range.0 as u32,
@@ -359,7 +415,7 @@ impl LayerStats {
 
         writeln!(
             w,
-            "  {:<width$}  preempt/first/xllc/xnuma/idle/fail={}/{}/{}/{}/{}/{}",
+            "  {:<width$}  preempt/first/xllc/xnuma/idle/fail={}/{}/{}/{}/{}/{} recv={} budget_fail={} tier={}",
             "",
             fmt_pct(self.preempt),
             fmt_pct(self.preempt_first),
@@ -367,6 +423,9 @@ impl LayerStats {
             fmt_pct(self.preempt_xnuma),
             fmt_pct(self.preempt_idle),
             fmt_pct(self.preempt_fail),
+            fmt_pct(self.preempt_recv),
+            fmt_pct(self.preempt_budget_fail),
+            self.qos_tier,
             width = header_width,
         )?;
 
@@ -392,6 +451,37 @@ impl LayerStats {
             width = header_width
         )?;
 
+        if self.ipc > 0.0 {
+            writeln!(
+                w,
+                "  {:<width$}  ipc={:5.2} llc_mpki={:6.2}",
+                "",
+                self.ipc,
+                self.llc_mpki,
+                width = header_width
+            )?;
+        }
+
+        write!(
+            w,
+            "  {:<width$}  lat_p50/p99={}/{}us",
+            "",
+            self.lat_p50_us,
+            self.lat_p99_us,
+            width = header_width
+        )?;
+        if self.slo_lat_us > 0 {
+            write!(
+                w,
+                " slo={}us@{}% met={} out_intvs={}",
+                self.slo_lat_us,
+                self.slo_pct,
+                fmt_pct(self.slo_met),
+                self.slo_out_intvs,
+            )?;
+        }
+        writeln!(w, "")?;
+
         let cpumask = Cpumask::from_vec(self.cpus.clone());
 
         writeln!(
@@ -447,6 +537,16 @@ impl LayerStats {
             );
         }
 
+        if self.anti_affn_kick != 0.0 {
+            writeln!(
+                w,
+                "  {:<width$}  anti_affn_kick={}",
+                "",
+                fmt_pct(self.anti_affn_kick),
+                width = header_width,
+            )?;
+        }
+
         Ok(())
     }
 }
@@ -628,11 +728,61 @@ impl SysStats {
     }
 }
 
+#[stat_doc]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Stats)]
+pub struct TaskAssignment {
+    #[stat(desc = "thread ID")]
+    pub pid: u32,
+    #[stat(desc = "thread group ID")]
+    pub tgid: u32,
+    #[stat(desc = "thread name")]
+    pub comm: String,
+    #[stat(desc = "layer the task was assigned to")]
+    pub layer: String,
+    #[stat(desc = "layer the task was in before, empty for new tasks")]
+    pub prev_layer: String,
+    #[stat(desc = "index of the layer match which fired, -1 if moved through the control API")]
+    pub match_idx: i32,
+    #[stat(desc = "the layer match which fired as in the layer spec, empty if moved")]
+    pub rule: String,
+}
+
+impl TaskAssignment {
+    pub fn format<W: Write>(&self, w: &mut W) -> Result<()> {
+        let prev = match self.prev_layer.as_str() {
+            "" => "-",
+            v => v,
+        };
+        let rule = match self.match_idx {
+            -1 => "[control API]".to_string(),
+            idx => format!("[{}] {}", idx, self.rule),
+        };
+        writeln!(
+            w,
+            "{:>7} {:>7} {:<16} {} -> {} {}",
+            self.pid, self.tgid, self.comm, prev, self.layer, rule,
+        )?;
+        Ok(())
+    }
+}
+
+#[stat_doc]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Stats)]
+pub struct TaskAssignments {
+    #[stat(desc = "task to layer assignments since the previous read")]
+    pub assignments: Vec<TaskAssignment>,
+    #[stat(desc = "count of assignments dropped for lack of buffer space")]
+    pub dropped: u64,
+}
+
 #[derive(Debug)]
 pub enum StatsReq {
     Hello(ThreadId),
     Refresh(ThreadId, Stats),
     Bye(ThreadId),
+    AssignOpen(ThreadId),
+    AssignRead(ThreadId),
+    AssignClose(ThreadId),
 }
 
 #[derive(Debug)]
@@ -640,6 +790,8 @@ pub enum StatsRes {
     Hello(Stats),
     Refreshed((Stats, SysStats)),
     Bye,
+    Ack,
+    Assignments(TaskAssignments),
 }
 
 pub fn server_data() -> StatsServerData<StatsReq, StatsRes> {
@@ -673,6 +825,35 @@ pub fn server_data() -> StatsServerData<StatsReq, StatsRes> {
         }
     });
 
+    let assign_open: Box<dyn StatsOpener<StatsReq, StatsRes>> =
+        Box::new(move |(req_ch, res_ch)| {
+            let tid = current().id();
+            req_ch.send(StatsReq::AssignOpen(tid))?;
+            match res_ch.recv()? {
+                StatsRes::Ack => {}
+                res => bail!("invalid response to AssignOpen: {:?}", res),
+            }
+
+            let read: Box<dyn StatsReader<StatsReq, StatsRes>> =
+                Box::new(move |_args, (req_ch, res_ch)| {
+                    req_ch.send(StatsReq::AssignRead(tid))?;
+                    match res_ch.recv()? {
+                        StatsRes::Assignments(v) => v.to_json(),
+                        res => bail!("invalid response to AssignRead: {:?}", res),
+                    }
+                });
+            Ok(read)
+        });
+
+    let assign_close: Box<dyn StatsCloser<StatsReq, StatsRes>> =
+        Box::new(move |(req_ch, res_ch)| {
+            req_ch.send(StatsReq::AssignClose(current().id())).unwrap();
+            match res_ch.recv().unwrap() {
+                StatsRes::Ack => {}
+                res => panic!("invalid response to AssignClose: {:?}", res),
+            }
+        });
+
     StatsServerData::new()
         .add_meta(LayerStats::meta())
         .add_meta(SysStats::meta())
@@ -683,6 +864,15 @@ pub fn server_data() -> StatsServerData<StatsReq, StatsRes> {
                 close: Some(close),
             },
         )
+        .add_meta(TaskAssignment::meta())
+        .add_meta(TaskAssignments::meta())
+        .add_ops(
+            "assignments",
+            StatsOps {
+                open: assign_open,
+                close: Some(assign_close),
+            },
+        )
 }
 
 pub fn monitor(intv: Duration, shutdown: Arc<AtomicBool>) -> Result<()> {
@@ -697,3 +887,21 @@ pub fn monitor(intv: Duration, shutdown: Arc<AtomicBool>) -> Result<()> {
         },
     )
 }
+
+pub fn monitor_assignments(shutdown: Arc<AtomicBool>) -> Result<()> {
+    scx_utils::monitor_stats::<TaskAssignments>(
+        &vec![("target".into(), "assignments".into())],
+        Duration::from_millis(100),
+        || shutdown.load(Ordering::Relaxed),
+        |tas| {
+            let mut stdout = std::io::stdout();
+            for ta in tas.assignments.iter() {
+                ta.format(&mut stdout)?;
+            }
+            if tas.dropped > 0 {
+                warn!("{} task assignments dropped", tas.dropped);
+            }
+            Ok(())
+        },
+    )
+}
@@ -0,0 +1,316 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2.
+
+//! Layer control API served over varlink, see --varlink.
+//!
+//! Varlink messages are JSON objects terminated by a NUL byte on a unix
+//! socket. Each connection is served by its own thread which forwards the
+//! calls to Scheduler::run() as CtlReq's and waits for the CtlRes.
+use std::fmt::Display;
+use std::fs;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use crossbeam::channel::Receiver;
+use crossbeam::channel::Sender;
+use log::debug;
+use log::warn;
+use scx_layered::LayerSpec;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Value;
+
+pub const INTERFACE: &str = "com.sched-ext.layered";
+
+const INTERFACE_DESC: &str = r#"# Runtime control of the scx_layered layers.
+interface com.sched-ext.layered
+
+type Layer (
+  name: string,
+  kind: string,
+  nr_tasks: int,
+  util: float,
+  nr_cpus: int,
+  cpus: string
+)
+
+# List the layers in matching order.
+method ListLayers() -> (layers: []Layer)
+
+# Get a layer and the IDs of the threads which have been matched into it.
+method GetLayer(name: string) -> (layer: Layer, tids: []int)
+
+# Get the layer a thread is in and whether it was put there by MovePid().
+method GetPidLayer(pid: int) -> (layer: string, overridden: bool)
+
+# Move a thread, or all threads of a process if @pid is a tgid, into a
+# layer regardless of the layer matches.
+method MovePid(pid: int, layer: string) -> ()
+
+# Drop the MovePid() assignment so that the layer matches apply again.
+method ResetPid(pid: int) -> ()
+
+# Add a layer before @before, or before the last layer if not specified.
+method CreateLayer(spec: object, before: ?string) -> ()
+
+# Delete a layer. The last layer can't be deleted.
+method DeleteLayer(name: string) -> ()
+
+error NoSuchLayer (name: string)
+error NoSuchPid (pid: int)
+error InvalidSpec (reason: string)
+error Unavailable ()
+"#;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", content = "parameters", deny_unknown_fields)]
+pub enum CtlReq {
+    ListLayers {},
+    GetLayer {
+        name: String,
+    },
+    GetPidLayer {
+        pid: u32,
+    },
+    MovePid {
+        pid: u32,
+        layer: String,
+    },
+    ResetPid {
+        pid: u32,
+    },
+    CreateLayer {
+        spec: Box<LayerSpec>,
+        before: Option<String>,
+    },
+    DeleteLayer {
+        name: String,
+    },
+}
+
+const METHODS: &[&str] = &[
+    "ListLayers",
+    "GetLayer",
+    "GetPidLayer",
+    "MovePid",
+    "ResetPid",
+    "CreateLayer",
+    "DeleteLayer",
+];
+
+#[derive(Debug)]
+pub struct CtlError {
+    pub error: String,
+    pub parameters: Value,
+}
+
+impl CtlError {
+    fn new(error: &str, parameters: Value) -> Self {
+        Self {
+            error: error.into(),
+            parameters,
+        }
+    }
+
+    pub fn no_such_layer(name: &str) -> Self {
+        Self::new(
+            &format!("{}.NoSuchLayer", INTERFACE),
+            json!({ "name": name }),
+        )
+    }
+
+    pub fn no_such_pid(pid: u32) -> Self {
+        Self::new(&format!("{}.NoSuchPid", INTERFACE), json!({ "pid": pid }))
+    }
+
+    pub fn invalid_spec(reason: impl Display) -> Self {
+        Self::new(
+            &format!("{}.InvalidSpec", INTERFACE),
+            json!({ "reason": format!("{:#}", reason) }),
+        )
+    }
+
+    fn unavailable() -> Self {
+        Self::new(&format!("{}.Unavailable", INTERFACE), json!({}))
+    }
+
+    fn method_not_found(method: &str) -> Self {
+        Self::new(
+            "org.varlink.service.MethodNotFound",
+            json!({ "method": method }),
+        )
+    }
+
+    fn interface_not_found(interface: &str) -> Self {
+        Self::new(
+            "org.varlink.service.InterfaceNotFound",
+            json!({ "interface": interface }),
+        )
+    }
+
+    fn invalid_parameter(parameter: impl Display) -> Self {
+        Self::new(
+            "org.varlink.service.InvalidParameter",
+            json!({ "parameter": parameter.to_string() }),
+        )
+    }
+}
+
+pub type CtlRes = std::result::Result<Value, CtlError>;
+pub type CtlMsg = (CtlReq, Sender<CtlRes>);
+
+#[derive(Debug, Deserialize)]
+struct Call {
+    method: String,
+    #[serde(default)]
+    parameters: Value,
+    #[serde(default)]
+    oneway: bool,
+}
+
+pub struct VarlinkServer {
+    path: PathBuf,
+    rx: Receiver<CtlMsg>,
+    // Keeps @rx connected while there are no connections.
+    _tx: Sender<CtlMsg>,
+}
+
+impl VarlinkServer {
+    pub fn launch(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        }
+
+        let res = fs::remove_file(path);
+        if let Err(e) = &res {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                res.with_context(|| format!("Failed to delete {:?}", path))?;
+            }
+        }
+
+        let listener =
+            UnixListener::bind(path).with_context(|| format!("Failed to bind {:?}", path))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+        let (tx, rx) = crossbeam::channel::unbounded::<CtlMsg>();
+        let conn_tx = tx.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let tx = conn_tx.clone();
+                        std::thread::spawn(move || {
+                            if let Err(e) = serve(stream, &tx) {
+                                debug!("varlink connection failed ({:#})", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept varlink connection ({})", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            path: path.to_owned(),
+            rx,
+            _tx: tx,
+        })
+    }
+
+    pub fn channel(&self) -> Receiver<CtlMsg> {
+        self.rx.clone()
+    }
+}
+
+impl Drop for VarlinkServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve(stream: UnixStream, tx: &Sender<CtlMsg>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut buf = vec![];
+
+    loop {
+        buf.clear();
+        reader.read_until(0, &mut buf)?;
+        if buf.pop() != Some(0) {
+            // EOF, possibly in the middle of a message.
+            return Ok(());
+        }
+
+        let (oneway, res) = match serde_json::from_slice::<Call>(&buf) {
+            Ok(call) => (call.oneway, handle_call(call, tx)),
+            Err(e) => (false, Err(CtlError::invalid_parameter(e))),
+        };
+        if oneway {
+            continue;
+        }
+
+        let reply = match res {
+            Ok(parameters) => json!({ "parameters": parameters }),
+            Err(e) => json!({ "error": e.error, "parameters": e.parameters }),
+        };
+        let mut out = serde_json::to_vec(&reply)?;
+        out.push(0);
+        writer.write_all(&out)?;
+    }
+}
+
+fn handle_call(call: Call, tx: &Sender<CtlMsg>) -> CtlRes {
+    let parameters = match call.parameters {
+        Value::Null => json!({}),
+        v => v,
+    };
+
+    match call.method.as_str() {
+        "org.varlink.service.GetInfo" => {
+            return Ok(json!({
+                "vendor": "sched_ext",
+                "product": "scx_layered",
+                "version": env!("CARGO_PKG_VERSION"),
+                "url": "https://github.com/sched-ext/scx",
+                "interfaces": ["org.varlink.service", INTERFACE],
+            }))
+        }
+        "org.varlink.service.GetInterfaceDescription" => {
+            return match parameters["interface"].as_str() {
+                Some(INTERFACE) => Ok(json!({ "description": INTERFACE_DESC })),
+                Some(v) => Err(CtlError::interface_not_found(v)),
+                None => Err(CtlError::invalid_parameter("interface")),
+            };
+        }
+        _ => {}
+    }
+
+    let method = match call.method.rsplit_once('.') {
+        Some((iface, method)) if iface == INTERFACE && METHODS.contains(&method) => method,
+        Some((iface, _)) if iface != INTERFACE && iface != "org.varlink.service" => {
+            return Err(CtlError::interface_not_found(iface))
+        }
+        _ => return Err(CtlError::method_not_found(&call.method)),
+    };
+
+    let req: CtlReq = serde_json::from_value(json!({
+        "method": method,
+        "parameters": parameters,
+    }))
+    .map_err(CtlError::invalid_parameter)?;
+
+    let (res_tx, res_rx) = crossbeam::channel::bounded(1);
+    tx.send((req, res_tx))
+        .map_err(|_| CtlError::unavailable())?;
+    res_rx.recv().map_err(|_| CtlError::unavailable())?
+}
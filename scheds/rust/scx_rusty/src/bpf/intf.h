@@ -33,11 +33,17 @@ enum consts {
 	CACHELINE_SIZE		= 64,
 	NO_DOM_FOUND		= MAX_DOMS + 1,
 
+	/* Per-CPU DSQs of the tasks pinned to a single CPU are at base + cpu */
+	PINNED_DSQ_BASE		= 1 << 20,
+	/* With --fifo-sched, pinned tasks in a row before the domain's turn */
+	MAX_PINNED_STREAK	= 4,
+
 	LB_DEFAULT_WEIGHT	= 100,
 	LB_MIN_WEIGHT		= 1,
 	LB_MAX_WEIGHT		= 10000,
 	LB_LOAD_BUCKETS		= 100,	/* Must be a factor of LB_MAX_WEIGHT */
 	LB_WEIGHT_PER_BUCKET	= LB_MAX_WEIGHT / LB_LOAD_BUCKETS,
+	MAX_LB_CGRPS		= 4096,	/* cgroups tracked with --cgroup-lb */
 
 	/* Time constants */
 	MSEC_PER_SEC		= 1000LLU,
@@ -65,6 +71,21 @@ enum consts {
 	 */
 	MAX_DOM_ACTIVE_TPTRS	= 1024,
 
+	/*
+	 * A task's home NUMA node moves to the node it's running on after
+	 * running there for this long without going back to the old one.
+	 */
+	NUMA_HOME_RUNTIME_NS	= (100 * NSEC_PER_MSEC),
+
+	/*
+	 * Bounds on the idle CPU picks tried when looking for a CPU compatible
+	 * with the sibling's core scheduling cookie, and on the DSQ tasks
+	 * scanned when looking for a task compatible with the sibling's cookie
+	 * or the CPU's reservation for latency critical tasks.
+	 */
+	CORE_SCHED_MAX_PICKS	= 4,
+	DSQ_MAX_SCAN		= 16,
+
 	STATIC_ALLOC_PAGES_GRANULARITY = 1,
 };
 
@@ -82,11 +103,16 @@ enum stat_idx {
 	RUSTY_STAT_DSQ_DISPATCH,
 	RUSTY_STAT_GREEDY_LOCAL,
 	RUSTY_STAT_GREEDY_XNUMA,
+	RUSTY_STAT_INTERACTIVE_IDLE,
+	RUSTY_STAT_IDLE_CORE,
+	RUSTY_STAT_PINNED_DISPATCH,
 
 	/* Extra stats that don't contribute to total */
 	RUSTY_STAT_REPATRIATE,
 	RUSTY_STAT_KICK_GREEDY,
 	RUSTY_STAT_LOAD_BALANCE,
+	RUSTY_STAT_PARK_MIGRATE,
+	RUSTY_STAT_COOKIE_IDLE,
 
 	/* Errors */
 	RUSTY_STAT_TASK_GET_ERR,
@@ -98,4 +124,31 @@ enum stat_idx {
 	RUSTY_NR_STATS,
 };
 
+/*
+ * Load balancing state of a cgroup with cgroup_lb, keyed by cgroup ID. The
+ * BPF side wraps these in map values which start with a spin lock, so
+ * userspace reads them from the end of the values.
+ */
+struct cgrp_lb_ctx {
+	/* cpu.weight of the cgroup */
+	u32 weight;
+	/* Number of runnable tasks in the cgroup */
+	u32 nr_runnable;
+	/* Whether any task in the cgroup is runnable */
+	struct ravg_data active_rd;
+};
+
+/* Load of a cgroup's tasks in a domain, keyed by cgrp_dom_key */
+struct cgrp_dom_lb_ctx {
+	/* Sum of the weights of the cgroup's runnable tasks in the domain */
+	u64 runnable_weight;
+	struct ravg_data runnable_weight_rd;
+};
+
+struct cgrp_dom_key {
+	u64 cgid;
+	u32 dom_id;
+	u32 pad;
+};
+
 #endif /* __INTF_H */
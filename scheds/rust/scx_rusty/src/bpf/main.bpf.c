@@ -80,22 +80,40 @@ const volatile u32 load_half_life = 1000000000	/* 1s */;
 
 const volatile bool kthreads_local;
 const volatile bool fifo_sched = false;
+const volatile bool eevdf_dl = false;
+const volatile bool interactive = false;
+const volatile u64 interactive_slice_ns = 1000000;	/* 1ms */
+const volatile u64 interactive_runtime_ns = 1000000;	/* 1ms */
+const volatile u64 interactive_min_freq = 10;		/* per 100ms */
 const volatile bool direct_greedy_numa;
+const volatile u64 steal_window_ns = 10000000;	/* 10ms */
+const volatile bool smt_enabled;
+const volatile s32 __sibling_cpu[MAX_CPUS];
+const volatile bool prefer_idle_cores;
+const volatile bool reserve_sibling;
 const volatile bool mempolicy_affinity;
-const volatile u32 greedy_threshold;
-const volatile u32 greedy_threshold_x_numa;
+const volatile bool cgroup_lb;
 const volatile u32 rusty_perf_mode;
+const volatile u32 perf_boost = SCX_CPUPERF_ONE;
 const volatile u32 debug;
 
 /* base slice duration */
 volatile u64 slice_ns;
 
+/* number of tasks currently classified as interactive */
+u64 nr_interactive;
+
+/* greedy stealing thresholds, can be changed by userspace at runtime */
+volatile u32 greedy_threshold;
+volatile u32 greedy_threshold_x_numa;
+
 struct bpfmask_wrapper {
 	struct bpf_cpumask __kptr *instance;
 };
 
 struct rusty_percpu_storage {
 	struct bpf_cpumask __kptr *bpfmask;
+	struct bpf_cpumask __kptr *pickmask;
 };
 
 struct {
@@ -142,6 +160,10 @@ static int scx_rusty_percpu_storage_init(void)
 		ret = create_save_cpumask(&storage->bpfmask);
 		if (ret)
 			return ret;
+
+		ret = create_save_cpumask(&storage->pickmask);
+		if (ret)
+			return ret;
 	}
 
 	return 0;
@@ -166,12 +188,38 @@ static struct bpf_cpumask *scx_percpu_bpfmask(void)
 	return storage->bpfmask;
 }
 
+/*
+ * Separate from scx_percpu_bpfmask() as the cpumask passed to pick_idle_cpu()
+ * is often the former.
+ */
+static struct bpf_cpumask *scx_percpu_pickmask(void)
+{
+	struct rusty_percpu_storage *storage;
+	void *map = &scx_percpu_bpfmask_map;
+	const u32 zero = 0;
+
+	storage = bpf_map_lookup_elem(map, &zero);
+	if (!storage || !storage->pickmask) {
+		scx_bpf_error("Did not properly initialize pickmask");
+		return NULL;
+	}
+
+	return storage->pickmask;
+}
+
 /*
  * Per-CPU context
  */
 struct pcpu_ctx {
 	u32 dom_rr_cur; /* used when scanning other doms */
 	u32 dom_id;
+	/* # of times tasks and latency critical tasks started running, cumulative */
+	u64 nr_runs;
+	u64 nr_lat_crit_runs;
+	/* the last value passed to scx_bpf_cpuperf_set() */
+	u32 cpuperf;
+	/* pinned tasks dispatched since the last task from the domain */
+	u32 nr_pinned_streak;
 	/*
 	 * Add some padding so that libbpf-rs can generate the rest of the
 	 * padding to CACHELINE_SIZE. This is necessary for now because most
@@ -210,6 +258,14 @@ struct {
 	__uint(map_flags, 0);
 } dom_dcycle_locks SEC(".maps");
 
+struct {
+	__uint(type, BPF_MAP_TYPE_ARRAY);
+	__type(key, u32);
+	__type(value, struct lock_wrapper);
+	__uint(max_entries, MAX_DOMS);
+	__uint(map_flags, 0);
+} dom_pinned_locks SEC(".maps");
+
 const u64 ravg_1 = 1 << RAVG_FRAC_BITS;
 
 struct {
@@ -304,6 +360,153 @@ static void task_load_adj(struct task_ctx *taskc,
 	ravg_accumulate(&taskc->dcyc_rd, taskc->runnable, now, load_half_life);
 }
 
+/*
+ * Cgroup load balancing state, only maintained if cgroup_lb is set. With
+ * cgroup_lb, the weight of a cgroup is split amongst its runnable tasks. This
+ * way, a batch cgroup with many runnable threads doesn't carry more load than
+ * a cgroup of the same weight with a few latency sensitive threads, and the
+ * load balancer doesn't end up crowding the latter out of their domains.
+ *
+ * As the share of each task changes whenever any task in the cgroup becomes
+ * runnable or quiescent, the shares aren't tracked here. Instead, the runnable
+ * weight of the cgroup's tasks in each domain and whether the cgroup has any
+ * runnable task are tracked, and userspace splits the cgroup's weight
+ * accordingly when load balancing. Only the task's own cgroup is considered
+ * and tasks in the root cgroup are accounted in the domain load buckets.
+ */
+struct cgrp_lb_val {
+	struct bpf_spin_lock lock;
+	struct cgrp_lb_ctx lb;
+};
+
+struct cgrp_dom_lb_val {
+	struct bpf_spin_lock lock;
+	struct cgrp_dom_lb_ctx lb;
+};
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__type(key, u64);
+	__type(value, struct cgrp_lb_val);
+	__uint(max_entries, MAX_LB_CGRPS);
+	__uint(map_flags, BPF_F_NO_PREALLOC);
+} cgrp_lb_data SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__type(key, struct cgrp_dom_key);
+	__type(value, struct cgrp_dom_lb_val);
+	__uint(max_entries, MAX_LB_CGRPS * MAX_DOMS);
+	__uint(map_flags, BPF_F_NO_PREALLOC);
+} cgrp_dom_lb_data SEC(".maps");
+
+static struct cgrp_dom_lb_val *lookup_cgrp_dom_lb(u64 cgid, u32 dom_id)
+{
+	struct cgrp_dom_key key = { .cgid = cgid, .dom_id = dom_id };
+	struct cgrp_dom_lb_val *cdv, zero = {};
+
+	if ((cdv = bpf_map_lookup_elem(&cgrp_dom_lb_data, &key)))
+		return cdv;
+
+	bpf_map_update_elem(&cgrp_dom_lb_data, &key, &zero, BPF_NOEXIST);
+	cdv = bpf_map_lookup_elem(&cgrp_dom_lb_data, &key);
+	if (!cdv)
+		scx_bpf_error("cgrp_dom_lb lookup failed for cgid %llu dom %u",
+			      cgid, dom_id);
+	return cdv;
+}
+
+/*
+ * @p is becoming runnable. Latch the weight its duty cycle is accounted under
+ * and the cgroup it's accounted in until it becomes quiescent.
+ */
+static void task_lb_latch(struct task_struct *p, struct task_ctx *taskc)
+{
+	struct cgroup *cgrp;
+	u64 cgid;
+
+	taskc->lb_weight = taskc->weight;
+	taskc->lb_cgid = 0;
+
+	if (!cgroup_lb || !(cgrp = __COMPAT_scx_bpf_task_cgroup(p)))
+		return;
+
+	cgid = cgrp->kn->id;
+	if (cgrp->level && bpf_map_lookup_elem(&cgrp_lb_data, &cgid))
+		taskc->lb_cgid = cgid;
+
+	bpf_cgroup_release(cgrp);
+}
+
+static void cgrp_dcycle_adj(u64 cgid, u32 dom_id, u32 weight, u64 now,
+			    bool runnable)
+{
+	struct cgrp_dom_lb_val *cdv;
+	struct cgrp_lb_val *cgv;
+
+	if (!(cgv = bpf_map_lookup_elem(&cgrp_lb_data, &cgid)) ||
+	    !(cdv = lookup_cgrp_dom_lb(cgid, dom_id)))
+		return;
+
+	bpf_spin_lock(&cgv->lock);
+	if (runnable)
+		cgv->lb.nr_runnable++;
+	else if (cgv->lb.nr_runnable)
+		cgv->lb.nr_runnable--;
+	ravg_accumulate(&cgv->lb.active_rd, cgv->lb.nr_runnable > 0, now,
+			load_half_life);
+	bpf_spin_unlock(&cgv->lock);
+
+	bpf_spin_lock(&cdv->lock);
+	if (runnable)
+		cdv->lb.runnable_weight += weight;
+	else if (cdv->lb.runnable_weight > weight)
+		cdv->lb.runnable_weight -= weight;
+	else
+		cdv->lb.runnable_weight = 0;
+	ravg_accumulate(&cdv->lb.runnable_weight_rd, cdv->lb.runnable_weight,
+			now, load_half_life);
+	bpf_spin_unlock(&cdv->lock);
+}
+
+/*
+ * Move @taskc's weighted duty cycle in its cgroup from @from_dom_id to
+ * @to_dom_id.
+ */
+static void cgrp_dcycle_xfer_task(struct task_ctx *taskc, u32 from_dom_id,
+				  u32 to_dom_id, u64 now)
+{
+	struct cgrp_dom_lb_val *from_cdv, *to_cdv;
+	u32 weight = taskc->runnable ? taskc->lb_weight : 0;
+	struct ravg_data task_rd;
+
+	if (!bpf_map_lookup_elem(&cgrp_lb_data, &taskc->lb_cgid) ||
+	    !(from_cdv = lookup_cgrp_dom_lb(taskc->lb_cgid, from_dom_id)) ||
+	    !(to_cdv = lookup_cgrp_dom_lb(taskc->lb_cgid, to_dom_id)))
+		return;
+
+	ravg_accumulate(&taskc->dcyc_rd, taskc->runnable, now, load_half_life);
+	task_rd = taskc->dcyc_rd;
+	ravg_scale(&task_rd, taskc->lb_weight, 0);
+
+	bpf_spin_lock(&from_cdv->lock);
+	if (from_cdv->lb.runnable_weight > weight)
+		from_cdv->lb.runnable_weight -= weight;
+	else
+		from_cdv->lb.runnable_weight = 0;
+	ravg_transfer(&from_cdv->lb.runnable_weight_rd,
+		      from_cdv->lb.runnable_weight, &task_rd, weight,
+		      load_half_life, false);
+	bpf_spin_unlock(&from_cdv->lock);
+
+	bpf_spin_lock(&to_cdv->lock);
+	to_cdv->lb.runnable_weight += weight;
+	ravg_transfer(&to_cdv->lb.runnable_weight_rd,
+		      to_cdv->lb.runnable_weight, &task_rd, weight,
+		      load_half_life, true);
+	bpf_spin_unlock(&to_cdv->lock);
+}
+
 static struct bucket_ctx *lookup_dom_bucket(dom_ptr dom_ctx,
 					    u32 weight, u32 *bucket_id)
 {
@@ -380,16 +583,82 @@ static void dom_dcycle_adj(dom_ptr domc, u32 weight, u64 now, bool runnable)
 	}
 }
 
+/*
+ * Account @taskc's latched weight as runnable or not in the load of @domc,
+ * either in its cgroup's or in the domain load buckets.
+ */
+static void task_dcycle_adj(struct task_ctx *taskc, dom_ptr domc, u64 now,
+			    bool runnable)
+{
+	if (taskc->lb_cgid) {
+		cast_kern(domc);
+		cgrp_dcycle_adj(taskc->lb_cgid, domc->id, taskc->lb_weight,
+				now, runnable);
+	} else {
+		dom_dcycle_adj(domc, taskc->lb_weight, now, runnable);
+	}
+}
+
+/*
+ * Account @taskc's load as pinned to its domain if it can't run in any other
+ * domain. The domain and the weight are latched when the task becomes
+ * runnable so that the same load is taken out when it goes quiescent.
+ */
+static void task_pinned_adj(struct task_ctx *taskc, u64 now, bool runnable)
+{
+	struct lock_wrapper *lockw;
+	dom_ptr domc;
+	u32 dom_id;
+
+	if (runnable) {
+		dom_id = taskc->target_dom;
+		if (taskc->pinned_counted || dom_id >= MAX_DOMS ||
+		    taskc->dom_mask != (1LLU << dom_id))
+			return;
+		taskc->pinned_dom = dom_id;
+		taskc->pinned_weight = taskc->lb_weight;
+	} else {
+		if (!taskc->pinned_counted)
+			return;
+		dom_id = taskc->pinned_dom;
+	}
+
+	domc = lookup_dom_ctx(dom_id);
+	lockw = bpf_map_lookup_elem(&dom_pinned_locks, &dom_id);
+	if (!domc || !lockw)
+		return;
+
+	bpf_spin_lock(&lockw->lock);
+	if (runnable) {
+		domc->pinned_nr++;
+		domc->pinned_weight += taskc->pinned_weight;
+	} else {
+		domc->pinned_nr--;
+		domc->pinned_weight -= taskc->pinned_weight;
+	}
+	ravg_accumulate(&domc->pinned_nr_rd, domc->pinned_nr, now, load_half_life);
+	ravg_accumulate(&domc->pinned_weight_rd, domc->pinned_weight, now,
+			load_half_life);
+	bpf_spin_unlock(&lockw->lock);
+
+	taskc->pinned_counted = runnable;
+}
+
 static void dom_dcycle_xfer_task(struct task_struct *p, struct task_ctx *taskc,
 			         dom_ptr from_domc,
 				 dom_ptr to_domc, u64 now)
 {
 	struct bucket_ctx *from_bucket, *to_bucket;
-	u32 idx = 0, weight = taskc->weight;
+	u32 idx = 0, weight = taskc->lb_weight;
 	struct lock_wrapper *from_lockw, *to_lockw;
 	struct ravg_data task_dcyc_rd;
 	u64 from_dcycle[2], to_dcycle[2], task_dcycle;
 
+	if (taskc->lb_cgid) {
+		cgrp_dcycle_xfer_task(taskc, from_domc->id, to_domc->id, now);
+		return;
+	}
+
 	from_lockw = lookup_dom_bkt_lock(from_domc->id, weight);
 	to_lockw = lookup_dom_bkt_lock(to_domc->id, weight);
 	if (!from_lockw || !to_lockw)
@@ -481,6 +750,51 @@ int dom_xfer_task(struct task_struct *p __arg_trusted, u32 new_dom_id, u64 now)
 /*
  * Statistics
  */
+
+/* A task of domain @from got executed greedily by a CPU of domain @to */
+static void dom_stat_greedy(u32 to, u32 from)
+{
+	dom_ptr domc;
+
+	if ((domc = try_lookup_dom_ctx(to)))
+		__sync_fetch_and_add(&domc->nr_greedy_in, 1);
+	if ((domc = try_lookup_dom_ctx(from)))
+		__sync_fetch_and_add(&domc->nr_greedy_out, 1);
+}
+
+/*
+ * Whether the CPUs of @domc may steal a task from a foreign domain, i.e.
+ * the domain hasn't used up its steal budget in the current window. The
+ * window accounting is racy but only needs to be right most of the time.
+ */
+static bool dom_steal_allowed(dom_ptr domc)
+{
+	u64 now;
+
+	if (!domc->steal_budget)
+		return true;
+
+	now = scx_bpf_now();
+	if (now - domc->steal_window_at >= steal_window_ns) {
+		WRITE_ONCE(domc->steal_window_at, now);
+		WRITE_ONCE(domc->steal_window_cnt, 0);
+	}
+
+	if (READ_ONCE(domc->steal_window_cnt) < domc->steal_budget)
+		return true;
+
+	__sync_fetch_and_add(&domc->nr_steal_throttled, 1);
+	return false;
+}
+
+/* A CPU of @domc stole a task from a foreign domain on dispatch */
+static void dom_steal_charge(dom_ptr domc)
+{
+	__sync_fetch_and_add(&domc->nr_steals, 1);
+	if (domc->steal_budget)
+		__sync_fetch_and_add(&domc->steal_window_cnt, 1);
+}
+
 struct {
 	__uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
 	__uint(key_size, sizeof(u32));
@@ -507,12 +821,19 @@ struct tune_input{
 	u64 slice_ns;
 	u64 direct_greedy_cpumask[MAX_CPUS / 64];
 	u64 kick_greedy_cpumask[MAX_CPUS / 64];
+	u64 parked_dom_mask;
+	u64 perf_boost_cpumask[MAX_CPUS / 64];
 } tune_input;
 
+/* domains parked by userspace, see dom_parked() */
+u64 parked_dom_mask;
+
 u64 tune_params_gen;
 private(A) struct bpf_cpumask __kptr *all_cpumask;
 private(A) struct bpf_cpumask __kptr *direct_greedy_cpumask;
 private(A) struct bpf_cpumask __kptr *kick_greedy_cpumask;
+private(A) struct bpf_cpumask __kptr *parked_cpumask;
+private(A) struct bpf_cpumask __kptr *reserved_cpumask;
 
 static u32 cpu_to_dom_id(s32 cpu)
 {
@@ -530,6 +851,204 @@ static inline bool is_offline_cpu(s32 cpu)
 	return cpu_to_dom_id(cpu) > MAX_DOMS;
 }
 
+/*
+ * When utilization is low, userspace consolidates the load onto fewer domains
+ * and parks the rest. No new tasks are placed on the CPUs of parked domains,
+ * the tasks already there are moved out as they wake up or get re-enqueued
+ * and the CPUs don't steal work, letting them stay idle.
+ */
+static inline bool dom_parked(u32 dom_id)
+{
+	return dom_id < MAX_DOMS && (parked_dom_mask & (1LLU << dom_id));
+}
+
+/*
+ * Core scheduling
+ *
+ * With core scheduling, the kernel never runs tasks with different cookies on
+ * the SMT siblings of a core at the same time and forces one of the siblings
+ * idle instead. Avoid placing tasks next to tasks with different cookies so
+ * that the isolation doesn't cost CPUs which look idle to us but can't run
+ * the task. Tasks without a cookie have cookie 0 and are only compatible with
+ * each other.
+ */
+static inline s32 sibling_cpu(s32 cpu)
+{
+	const volatile s32 *sib;
+
+	if (!smt_enabled)
+		return -1;
+
+	sib = MEMBER_VPTR(__sibling_cpu, [cpu]);
+	return sib ? *sib : -1;
+}
+
+static u64 task_core_cookie(struct task_struct *p)
+{
+	if (bpf_core_field_exists(p->core_cookie))
+		return BPF_CORE_READ(p, core_cookie);
+	return 0;
+}
+
+/*
+ * Returns whether the SMT sibling of @cpu is running a task and if so, stores
+ * the task's cookie in @cookiep.
+ */
+static bool sibling_core_cookie(s32 cpu, u64 *cookiep)
+{
+	struct task_struct *curr;
+	struct rq *rq;
+	s32 sib;
+
+	if ((sib = sibling_cpu(cpu)) < 0 || !(rq = scx_bpf_cpu_rq(sib)))
+		return false;
+
+	curr = BPF_CORE_READ(rq, curr);
+	if (!curr || BPF_CORE_READ(curr, pid) == 0)
+		return false;
+
+	*cookiep = task_core_cookie(curr);
+	return true;
+}
+
+static bool cookie_conflict(struct task_struct *p, s32 cpu)
+{
+	u64 cookie;
+
+	if (!bpf_core_field_exists(p->core_cookie) ||
+	    !sibling_core_cookie(cpu, &cookie))
+		return false;
+
+	return cookie != task_core_cookie(p);
+}
+
+/*
+ * @cpu has been claimed for @p. If @p can't run there, release @cpu by kicking
+ * it so that it goes through idle again and return false.
+ */
+static bool claim_idle_cpu(struct task_struct *p, s32 cpu)
+{
+	if (!cookie_conflict(p, cpu))
+		return true;
+
+	scx_bpf_kick_cpu(cpu, SCX_KICK_IDLE);
+	stat_add(RUSTY_STAT_COOKIE_IDLE, 1);
+	return false;
+}
+
+/*
+ * With reserve_sibling, one SMT sibling of each core is kept for latency
+ * critical tasks so that they don't have to wait for a CPU or share the core
+ * with a busy sibling. These are tasks classified as interactive and tasks
+ * with negative nice values.
+ */
+static bool task_latency_critical(struct task_struct *p, struct task_ctx *taskc)
+{
+	return taskc->is_interactive || p->scx.weight > 100;
+}
+
+static bool cpu_reserved(s32 cpu)
+{
+	return reserve_sibling && reserved_cpumask &&
+		bpf_cpumask_test_cpu(cpu, cast_mask(reserved_cpumask));
+}
+
+static bool test_and_clear_cpu_idle(struct task_struct *p,
+				    struct task_ctx *taskc, s32 cpu)
+{
+	if (cpu_reserved(cpu) && !task_latency_critical(p, taskc))
+		return false;
+
+	return scx_bpf_test_and_clear_cpu_idle(cpu) && claim_idle_cpu(p, cpu);
+}
+
+static s32 pick_idle_cpu(struct task_struct *p, struct task_ctx *taskc,
+			 const struct cpumask *cpumask, u64 flags)
+{
+	struct bpf_cpumask *pickmask;
+	s32 cpu;
+	u32 i;
+
+	if (reserve_sibling && reserved_cpumask &&
+	    !task_latency_critical(p, taskc)) {
+		if (!(pickmask = scx_percpu_pickmask()))
+			return -ENOENT;
+
+		bpf_cpumask_andnot(pickmask, cpumask, cast_mask(reserved_cpumask));
+		cpumask = cast_mask(pickmask);
+	}
+
+	bpf_for(i, 0, CORE_SCHED_MAX_PICKS) {
+		cpu = scx_bpf_pick_idle_cpu(cpumask, flags);
+		if (cpu < 0 || claim_idle_cpu(p, cpu))
+			return cpu;
+	}
+
+	return -EBUSY;
+}
+
+/*
+ * Move the first task in @dsq_id which can run next to the task on @cpu's
+ * sibling to @cpu's local DSQ. Sets @blockedp if there were tasks which
+ * couldn't. Reserved siblings only take latency critical tasks.
+ */
+static bool dsq_move_to_local(u64 dsq_id, s32 cpu, bool *blockedp)
+{
+	struct task_struct *p;
+	struct task_ctx *taskc;
+	bool has_cookie, reserved = cpu_reserved(cpu);
+	u64 cookie;
+	u32 nr_scanned = 0;
+
+	has_cookie = bpf_core_field_exists(p->core_cookie) &&
+		sibling_core_cookie(cpu, &cookie);
+	if (!has_cookie && !reserved)
+		return scx_bpf_dsq_move_to_local(dsq_id);
+
+	bpf_for_each(scx_dsq, p, dsq_id, 0) {
+		if (nr_scanned++ >= DSQ_MAX_SCAN)
+			break;
+
+		if (has_cookie && task_core_cookie(p) != cookie) {
+			*blockedp = true;
+			continue;
+		}
+
+		if (reserved && (!(taskc = try_lookup_task_ctx(p)) ||
+				 !task_latency_critical(p, taskc)))
+			continue;
+
+		if (__COMPAT_scx_bpf_dsq_move(BPF_FOR_EACH_ITER, p,
+					      SCX_DSQ_LOCAL, 0))
+			return true;
+	}
+
+	return false;
+}
+
+/*
+ * The cpuperf target of @cpu: perf_boost while userspace asks for it as the
+ * domain is saturated by latency critical tasks, see --perf-boost-over, the
+ * lowest while the domain is parked and --perf otherwise.
+ */
+static void refresh_cpuperf(s32 cpu, u32 dom_id)
+{
+	struct pcpu_ctx *pcpuc;
+	u32 perf = rusty_perf_mode;
+
+	if (dom_parked(dom_id))
+		perf = 0;
+	else if (tune_input.perf_boost_cpumask[cpu / 64] & (1LLU << (cpu % 64)))
+		perf = max(perf, perf_boost);
+	perf = min(SCX_CPUPERF_ONE, perf);
+
+	if (!(pcpuc = lookup_pcpu_ctx(cpu)) || pcpuc->cpuperf == perf)
+		return;
+
+	scx_bpf_cpuperf_set(cpu, perf);
+	pcpuc->cpuperf = perf;
+}
+
 static void refresh_tune_params(void)
 {
 	s32 cpu;
@@ -539,6 +1058,7 @@ static void refresh_tune_params(void)
 
 	tune_params_gen = tune_input.genn;
 	slice_ns = tune_input.slice_ns;
+	parked_dom_mask = tune_input.parked_dom_mask;
 
 	bpf_for(cpu, 0, nr_cpu_ids) {
 		u32 dom_id = cpu_to_dom_id(cpu);
@@ -569,6 +1089,15 @@ static void refresh_tune_params(void)
 			if (kick_greedy_cpumask)
 				bpf_cpumask_clear_cpu(cpu, kick_greedy_cpumask);
 		}
+
+		if (parked_cpumask) {
+			if (dom_parked(dom_id))
+				bpf_cpumask_set_cpu(cpu, parked_cpumask);
+			else
+				bpf_cpumask_clear_cpu(cpu, parked_cpumask);
+		}
+
+		refresh_cpuperf(cpu, dom_id);
 	}
 }
 
@@ -616,6 +1145,37 @@ static __noinline u64 sched_prio_to_latency_weight(u64 prio)
 	return sched_prio_to_weight[DL_MAX_LAT_PRIO - prio - 1];
 }
 
+/*
+ * The slice @p requested with sched_setattr(2) through sched_attr.sched_runtime,
+ * 0 if none. This is what EEVDF uses in place of latency nice. The kernel only
+ * records it for SCHED_NORMAL and SCHED_BATCH tasks.
+ */
+static u64 task_custom_slice(struct task_struct *p)
+{
+	if (!bpf_core_field_exists(p->se.custom_slice) || !p->se.custom_slice)
+		return 0;
+
+	return p->se.slice;
+}
+
+/*
+ * The slice to dispatch @p with. With eevdf_dl, tasks which requested a
+ * shorter slice than the base slice get what they asked for. Interactive
+ * tasks are capped at interactive_slice_ns.
+ */
+static u64 task_slice_ns(struct task_struct *p, struct task_ctx *taskc)
+{
+	u64 slice = slice_ns, custom_slice;
+
+	if (eevdf_dl && (custom_slice = task_custom_slice(p)))
+		slice = min(custom_slice, slice);
+
+	if (taskc->is_interactive)
+		slice = min(interactive_slice_ns, slice);
+
+	return slice;
+}
+
 static u64 task_compute_dl(struct task_struct *p, struct task_ctx *taskc,
 			   u64 enq_flags)
 {
@@ -623,6 +1183,19 @@ static u64 task_compute_dl(struct task_struct *p, struct task_ctx *taskc,
 	u64 lat_prio, lat_scale, avg_run_raw, avg_run;
 	u64 freq_factor;
 
+	/*
+	 * With eevdf_dl, the request length is the slice the task asked for,
+	 * or the base slice if it didn't, scaled inversely by its weight as in
+	 * EEVDF. Tasks which ask for short slices get early deadlines and are
+	 * picked first from the domain DSQ, but can only run for as long as
+	 * they asked for before their deadline is pushed out again. This makes
+	 * the latency requirements explicit instead of relying on the
+	 * interactivity heuristics below.
+	 */
+	if (eevdf_dl)
+		return scale_inverse_fair(task_custom_slice(p) ?: slice_ns,
+					  p->scx.weight);
+
 	/*
 	 * Determine the latency criticality of a task, and scale a task's
 	 * deadline accordingly. Much of this is inspired by the logic in
@@ -841,6 +1414,12 @@ static bool task_set_domain(struct task_struct *p __arg_trusted,
 		if (!init_dsq_vtime)
 			dom_xfer_task(p, new_dom_id, now);
 
+		if (!init_dsq_vtime && old_dom_id != new_dom_id) {
+			taskc->prev_dom = old_dom_id;
+			taskc->nr_dom_migrations++;
+			taskc->last_dom_migration_at = now;
+		}
+
 		taskc->target_dom = new_dom_id;
 		taskc->domc = new_domc;
 
@@ -868,7 +1447,7 @@ static s32 try_sync_wakeup(struct task_struct *p, struct task_ctx *taskc,
 
 	cpu = bpf_get_smp_processor_id();
 	pcpuc = lookup_pcpu_ctx(cpu);
-	if (!pcpuc)
+	if (!pcpuc || dom_parked(pcpuc->dom_id))
 		return -ENOENT;
 
 	lb_domain = lb_domain_get(pcpuc->dom_id);
@@ -885,7 +1464,7 @@ static s32 try_sync_wakeup(struct task_struct *p, struct task_ctx *taskc,
 	idle_cpumask = scx_bpf_get_idle_cpumask();
 
 	share_llc = bpf_cpumask_test_cpu(prev_cpu, cast_mask(d_cpumask));
-	if (share_llc && scx_bpf_test_and_clear_cpu_idle(prev_cpu)) {
+	if (share_llc && test_and_clear_cpu_idle(p, taskc, prev_cpu)) {
 		stat_add(RUSTY_STAT_SYNC_PREV_IDLE, 1);
 
 		cpu = prev_cpu;
@@ -895,6 +1474,7 @@ static s32 try_sync_wakeup(struct task_struct *p, struct task_ctx *taskc,
 	has_idle = bpf_cpumask_intersects(cast_mask(d_cpumask), idle_cpumask);
 
 	if (has_idle && bpf_cpumask_test_cpu(cpu, p->cpus_ptr) &&
+	    !cookie_conflict(p, cpu) &&
 	    !(current->flags & PF_EXITING) && taskc->target_dom < MAX_DOMS &&
 	    scx_bpf_dsq_nr_queued(SCX_DSQ_LOCAL_ON | cpu) == 0) {
 		stat_add(RUSTY_STAT_WAKE_SYNC, 1);
@@ -908,6 +1488,75 @@ out:
 	return cpu;
 }
 
+/*
+ * Find an idle CPU for @p outside its domain, within the NUMA node of its
+ * domain unless direct_greedy_numa. Unlike DIRECT_GREEDY, this doesn't care
+ * whether the CPU is in the direct_greedy_cpumask. Used for interactive tasks
+ * and to find wholly idle cores with prefer_idle_cores, in which case only
+ * idle cores are considered if @core_only.
+ */
+static s32 try_pick_idle_node(struct task_struct *p, struct task_ctx *taskc,
+			      bool has_idle_cores, bool core_only)
+{
+	struct bpf_cpumask *tmp_cpumask;
+	struct lb_domain *lb_domain;
+	s32 cpu;
+
+	tmp_cpumask = scx_percpu_bpfmask();
+	if (!tmp_cpumask)
+		return -ENOENT;
+
+	bpf_cpumask_copy(tmp_cpumask, p->cpus_ptr);
+
+	if (!direct_greedy_numa) {
+		lb_domain = lb_domain_get(taskc->target_dom);
+		if (!lb_domain || !lb_domain->node_cpumask)
+			return -ENOENT;
+
+		bpf_cpumask_and(tmp_cpumask, cast_mask(tmp_cpumask),
+				cast_mask(lb_domain->node_cpumask));
+	}
+
+	if (parked_dom_mask && parked_cpumask)
+		bpf_cpumask_andnot(tmp_cpumask, cast_mask(tmp_cpumask),
+				   cast_mask(parked_cpumask));
+
+	if (has_idle_cores) {
+		cpu = pick_idle_cpu(p, taskc, cast_mask(tmp_cpumask), SCX_PICK_IDLE_CORE);
+		if (cpu >= 0)
+			return cpu;
+	}
+
+	if (core_only)
+		return -EBUSY;
+
+	return pick_idle_cpu(p, taskc, cast_mask(tmp_cpumask), 0);
+}
+
+static u32 task_pick_domain(struct task_ctx *taskc, struct task_struct *p,
+			    const struct cpumask *cpumask);
+
+/*
+ * If @p's domain is parked, move @p to an active domain it can run in. Returns
+ * whether @p was moved.
+ */
+static bool task_unpark(struct task_struct *p __arg_trusted,
+			struct task_ctx *taskc)
+{
+	u32 dom_id;
+
+	if (!dom_parked(taskc->target_dom) || p->nr_cpus_allowed == 1)
+		return false;
+
+	dom_id = task_pick_domain(taskc, p, p->cpus_ptr);
+	if (dom_id == NO_DOM_FOUND || dom_parked(dom_id) ||
+	    !task_set_domain(p, dom_id, false))
+		return false;
+
+	stat_add(RUSTY_STAT_PARK_MIGRATE, 1);
+	return true;
+}
+
 s32 BPF_STRUCT_OPS(rusty_select_cpu, struct task_struct *p, s32 prev_cpu,
 		   u64 wake_flags)
 {
@@ -922,6 +1571,8 @@ s32 BPF_STRUCT_OPS(rusty_select_cpu, struct task_struct *p, s32 prev_cpu,
 	if (!(taskc = lookup_task_ctx_mask(p, &p_cpumask)) || !p_cpumask)
 		goto enoent;
 
+	task_unpark(p, taskc);
+
 	if (p->nr_cpus_allowed == 1) {
 		cpu = prev_cpu;
 		if (kthreads_local && (p->flags & PF_KTHREAD)) {
@@ -954,7 +1605,7 @@ s32 BPF_STRUCT_OPS(rusty_select_cpu, struct task_struct *p, s32 prev_cpu,
 	 */
 	if (prev_domestic) {
 		if (bpf_cpumask_test_cpu(prev_cpu, idle_smtmask) &&
-		    scx_bpf_test_and_clear_cpu_idle(prev_cpu)) {
+		    test_and_clear_cpu_idle(p, taskc, prev_cpu)) {
 			stat_add(RUSTY_STAT_PREV_IDLE, 1);
 			cpu = prev_cpu;
 			goto direct;
@@ -968,7 +1619,7 @@ s32 BPF_STRUCT_OPS(rusty_select_cpu, struct task_struct *p, s32 prev_cpu,
 		if (direct_greedy_cpumask &&
 		    bpf_cpumask_test_cpu(prev_cpu, cast_mask(direct_greedy_cpumask)) &&
 		    bpf_cpumask_test_cpu(prev_cpu, idle_smtmask) &&
-		    scx_bpf_test_and_clear_cpu_idle(prev_cpu)) {
+		    test_and_clear_cpu_idle(p, taskc, prev_cpu)) {
 			stat_add(RUSTY_STAT_GREEDY_IDLE, 1);
 			cpu = prev_cpu;
 			goto direct;
@@ -983,30 +1634,54 @@ s32 BPF_STRUCT_OPS(rusty_select_cpu, struct task_struct *p, s32 prev_cpu,
 
 	/* If there is a domestic idle core, dispatch directly */
 	if (has_idle_cores) {
-		cpu = scx_bpf_pick_idle_cpu(cast_mask(p_cpumask), SCX_PICK_IDLE_CORE);
+		cpu = pick_idle_cpu(p, taskc, cast_mask(p_cpumask), SCX_PICK_IDLE_CORE);
 		if (cpu >= 0) {
 			stat_add(RUSTY_STAT_DIRECT_DISPATCH, 1);
 			goto direct;
 		}
 	}
 
+	/*
+	 * With prefer_idle_cores, a wholly idle core elsewhere in the node
+	 * beats sharing a core with a busy sibling in the domestic domain.
+	 */
+	if (prefer_idle_cores && has_idle_cores) {
+		cpu = try_pick_idle_node(p, taskc, true, true);
+		if (cpu >= 0) {
+			stat_add(RUSTY_STAT_IDLE_CORE, 1);
+			goto direct;
+		}
+	}
+
 	/*
 	 * If @prev_cpu was domestic and is idle itself even though the core
 	 * isn't, picking @prev_cpu may improve L1/2 locality.
 	 */
-	if (prev_domestic && scx_bpf_test_and_clear_cpu_idle(prev_cpu)) {
+	if (prev_domestic && test_and_clear_cpu_idle(p, taskc, prev_cpu)) {
 		stat_add(RUSTY_STAT_DIRECT_DISPATCH, 1);
 		cpu = prev_cpu;
 		goto direct;
 	}
 
 	/* If there is any domestic idle CPU, dispatch directly */
-	cpu = scx_bpf_pick_idle_cpu(cast_mask(p_cpumask), 0);
+	cpu = pick_idle_cpu(p, taskc, cast_mask(p_cpumask), 0);
 	if (cpu >= 0) {
 		stat_add(RUSTY_STAT_DIRECT_DISPATCH, 1);
 		goto direct;
 	}
 
+	/*
+	 * Interactive tasks shouldn't wait behind the domestic queue while
+	 * there are idle CPUs elsewhere.
+	 */
+	if (taskc->is_interactive) {
+		cpu = try_pick_idle_node(p, taskc, has_idle_cores, false);
+		if (cpu >= 0) {
+			stat_add(RUSTY_STAT_INTERACTIVE_IDLE, 1);
+			goto direct;
+		}
+	}
+
 	/*
 	 * Domestic domain is fully booked. If there are CPUs which are idle and
 	 * under-utilized, ignore domain boundaries (while still respecting NUMA
@@ -1073,8 +1748,8 @@ s32 BPF_STRUCT_OPS(rusty_select_cpu, struct task_struct *p, s32 prev_cpu,
 		/* Try to find an idle core in the previous and then any domain */
 		if (has_idle_cores) {
 			if (domc && lb_domain->direct_greedy_cpumask) {
-				cpu = scx_bpf_pick_idle_cpu(cast_mask(lb_domain->direct_greedy_cpumask),
-							    SCX_PICK_IDLE_CORE);
+				cpu = pick_idle_cpu(p, taskc, cast_mask(lb_domain->direct_greedy_cpumask),
+						    SCX_PICK_IDLE_CORE);
 				if (cpu >= 0) {
 					stat_add(RUSTY_STAT_DIRECT_GREEDY, 1);
 					goto direct;
@@ -1082,8 +1757,8 @@ s32 BPF_STRUCT_OPS(rusty_select_cpu, struct task_struct *p, s32 prev_cpu,
 			}
 
 			if (direct_greedy_cpumask) {
-				cpu = scx_bpf_pick_idle_cpu(cast_mask(tmp_direct_greedy),
-							    SCX_PICK_IDLE_CORE);
+				cpu = pick_idle_cpu(p, taskc, cast_mask(tmp_direct_greedy),
+						    SCX_PICK_IDLE_CORE);
 				if (cpu >= 0) {
 					stat_add(RUSTY_STAT_DIRECT_GREEDY_FAR, 1);
 					goto direct;
@@ -1095,7 +1770,7 @@ s32 BPF_STRUCT_OPS(rusty_select_cpu, struct task_struct *p, s32 prev_cpu,
 		 * No idle core. Is there any idle CPU?
 		 */
 		if (domc && lb_domain->direct_greedy_cpumask) {
-			cpu = scx_bpf_pick_idle_cpu(cast_mask(lb_domain->direct_greedy_cpumask), 0);
+			cpu = pick_idle_cpu(p, taskc, cast_mask(lb_domain->direct_greedy_cpumask), 0);
 			if (cpu >= 0) {
 				stat_add(RUSTY_STAT_DIRECT_GREEDY, 1);
 				goto direct;
@@ -1103,7 +1778,7 @@ s32 BPF_STRUCT_OPS(rusty_select_cpu, struct task_struct *p, s32 prev_cpu,
 		}
 
 		if (direct_greedy_cpumask) {
-			cpu = scx_bpf_pick_idle_cpu(cast_mask(tmp_direct_greedy), 0);
+			cpu = pick_idle_cpu(p, taskc, cast_mask(tmp_direct_greedy), 0);
 			if (cpu >= 0) {
 				stat_add(RUSTY_STAT_DIRECT_GREEDY_FAR, 1);
 				goto direct;
@@ -1129,6 +1804,9 @@ s32 BPF_STRUCT_OPS(rusty_select_cpu, struct task_struct *p, s32 prev_cpu,
 	return cpu;
 
 direct:
+	if (!bpf_cpumask_test_cpu(cpu, cast_mask(p_cpumask)) &&
+	    p->nr_cpus_allowed > 1)
+		dom_stat_greedy(cpu_to_dom_id(cpu), taskc->target_dom);
 	taskc->dispatch_local = true;
 	scx_bpf_put_idle_cpumask(idle_smtmask);
 	return cpu;
@@ -1139,11 +1817,11 @@ enoent:
 }
 
 static void place_task_dl(struct task_struct *p, struct task_ctx *taskc,
-			  u64 enq_flags)
+			  u64 dsq_id, u64 enq_flags)
 {
 	clamp_task_vtime(p, taskc, enq_flags);
-	scx_bpf_dsq_insert_vtime(p, taskc->target_dom, slice_ns, taskc->deadline,
-				 enq_flags);
+	scx_bpf_dsq_insert_vtime(p, dsq_id, task_slice_ns(p, taskc),
+				 taskc->deadline, enq_flags);
 }
 
 void BPF_STRUCT_OPS(rusty_enqueue, struct task_struct *p __arg_trusted, u64 enq_flags)
@@ -1173,12 +1851,42 @@ void BPF_STRUCT_OPS(rusty_enqueue, struct task_struct *p __arg_trusted, u64 enq_
 		goto dom_queue;
 	}
 
+	/*
+	 * @p may have been re-enqueued without going through ->select_cpu()
+	 * after its domain got parked.
+	 */
+	if (!taskc->dispatch_local && task_unpark(p, taskc)) {
+		cpu = bpf_cpumask_any_distribute(cast_mask(p_cpumask));
+		if (cpu < nr_cpu_ids)
+			scx_bpf_kick_cpu(cpu, 0);
+		goto dom_queue;
+	}
+
 	if (taskc->dispatch_local) {
 		taskc->dispatch_local = false;
-		scx_bpf_dsq_insert(p, SCX_DSQ_LOCAL, slice_ns, enq_flags);
+		scx_bpf_dsq_insert(p, SCX_DSQ_LOCAL, task_slice_ns(p, taskc), enq_flags);
 		return;
 	}
 
+	/*
+	 * Tasks restricted to a single CPU are queued on the CPU's pinned DSQ
+	 * instead of their domain's, where they would be in the way of the
+	 * other CPUs of the domain.
+	 */
+	if (p->nr_cpus_allowed == 1) {
+		cpu = bpf_cpumask_first(p->cpus_ptr);
+		if (cpu < nr_cpu_ids) {
+			if (fifo_sched)
+				scx_bpf_dsq_insert(p, PINNED_DSQ_BASE + cpu,
+						   slice_ns, enq_flags);
+			else
+				place_task_dl(p, taskc, PINNED_DSQ_BASE + cpu,
+					      enq_flags);
+			scx_bpf_kick_cpu(cpu, SCX_KICK_IDLE);
+			return;
+		}
+	}
+
 	/*
 	 * @p is about to be queued on its domain's dsq. However, @p may be on a
 	 * foreign CPU due to a greedy execution and not have gone through
@@ -1198,7 +1906,7 @@ dom_queue:
 	if (fifo_sched)
 		scx_bpf_dsq_insert(p, taskc->target_dom, slice_ns, enq_flags);
 	else
-		place_task_dl(p, taskc, enq_flags);
+		place_task_dl(p, taskc, taskc->target_dom, enq_flags);
 
 	/*
 	 * If there are CPUs which are idle and not saturated, wake them up to
@@ -1339,10 +2047,51 @@ static void task_set_preferred_mempolicy_dom_mask(struct task_struct *p,
 #endif
 
 
+/*
+ * The deadline of the first task queued on @dsq_id. The DSQ must not be
+ * empty.
+ */
+static u64 dsq_first_deadline(u64 dsq_id)
+{
+	struct task_struct *p;
+	struct task_ctx *taskc;
+
+	bpf_for_each(scx_dsq, p, dsq_id, 0) {
+		if ((taskc = try_lookup_task_ctx(p)))
+			return taskc->deadline;
+		break;
+	}
+
+	return 0;
+}
+
+/*
+ * Whether @cpu should run the tasks pinned to it before its domain's. The
+ * pinned tasks can't go anywhere else but mustn't starve the domain either.
+ * The earlier deadline goes first or, with --fifo-sched where the DSQs
+ * aren't ordered by deadline, the pinned tasks go first at most
+ * MAX_PINNED_STREAK times in a row.
+ */
+static bool pinned_first(s32 cpu, u32 dom_id, struct pcpu_ctx *pcpuc)
+{
+	if (!scx_bpf_dsq_nr_queued(PINNED_DSQ_BASE + cpu))
+		return false;
+	if (!scx_bpf_dsq_nr_queued(dom_id))
+		return true;
+
+	if (fifo_sched)
+		return pcpuc->nr_pinned_streak < MAX_PINNED_STREAK;
+
+	return !time_before(dsq_first_deadline(dom_id),
+			    dsq_first_deadline(PINNED_DSQ_BASE + cpu));
+}
+
 void BPF_STRUCT_OPS(rusty_dispatch, s32 cpu, struct task_struct *prev)
 {
 	u32 curr_dom = cpu_to_dom_id(cpu), dom;
+	bool cookie_blocked = false;
 	struct pcpu_ctx *pcpuc;
+	dom_ptr domc;
 	u32 my_node;
 
 	/*
@@ -1355,17 +2104,39 @@ void BPF_STRUCT_OPS(rusty_dispatch, s32 cpu, struct task_struct *prev)
 	if (unlikely(is_offline_cpu(cpu)))
 		return;
 
-	if (scx_bpf_dsq_move_to_local(curr_dom)) {
+	domc = try_lookup_dom_ctx(curr_dom);
+	pcpuc = lookup_pcpu_ctx(cpu);
+
+	if (pcpuc && pinned_first(cpu, curr_dom, pcpuc) &&
+	    scx_bpf_dsq_move_to_local(PINNED_DSQ_BASE + cpu)) {
+		pcpuc->nr_pinned_streak++;
+		stat_add(RUSTY_STAT_PINNED_DISPATCH, 1);
+		return;
+	}
+
+	if (dsq_move_to_local(curr_dom, cpu, &cookie_blocked)) {
+		if (pcpuc)
+			pcpuc->nr_pinned_streak = 0;
 		stat_add(RUSTY_STAT_DSQ_DISPATCH, 1);
 		return;
 	}
 
-	if (!greedy_threshold)
+	/* none of the domain's tasks can run on @cpu right now */
+	if (scx_bpf_dsq_move_to_local(PINNED_DSQ_BASE + cpu)) {
+		stat_add(RUSTY_STAT_PINNED_DISPATCH, 1);
 		return;
+	}
+
+	/* parked CPUs only drain their own domain */
+	if (!greedy_threshold || dom_parked(curr_dom))
+		goto out_idle;
+
+	/* don't let a hot domain keep stealing from its neighbors */
+	if (!domc || !dom_steal_allowed(domc))
+		goto out_idle;
 
-	pcpuc = lookup_pcpu_ctx(cpu);
 	if (!pcpuc)
-		return;
+		goto out_idle;
 
 	my_node = dom_node_id(curr_dom);
 
@@ -1375,14 +2146,16 @@ void BPF_STRUCT_OPS(rusty_dispatch, s32 cpu, struct task_struct *prev)
 		if (dom == curr_dom || dom_node_id(dom) != my_node)
 			continue;
 
-		if (scx_bpf_dsq_move_to_local(dom)) {
+		if (dsq_move_to_local(dom, cpu, &cookie_blocked)) {
 			stat_add(RUSTY_STAT_GREEDY_LOCAL, 1);
+			dom_stat_greedy(curr_dom, dom);
+			dom_steal_charge(domc);
 			return;
 		}
 	}
 
 	if (!greedy_threshold_x_numa || nr_nodes == 1)
-		return;
+		goto out_idle;
 
 	/* try to steal a task from domains on other NUMA nodes */
 	bpf_repeat(nr_doms - 1) {
@@ -1392,11 +2165,17 @@ void BPF_STRUCT_OPS(rusty_dispatch, s32 cpu, struct task_struct *prev)
 		    scx_bpf_dsq_nr_queued(dom) >= greedy_threshold_x_numa)
 			continue;
 
-		if (scx_bpf_dsq_move_to_local(dom)) {
+		if (dsq_move_to_local(dom, cpu, &cookie_blocked)) {
 			stat_add(RUSTY_STAT_GREEDY_XNUMA, 1);
+			dom_stat_greedy(curr_dom, dom);
+			dom_steal_charge(domc);
 			return;
 		}
 	}
+
+out_idle:
+	if (cookie_blocked)
+		stat_add(RUSTY_STAT_COOKIE_IDLE, 1);
 }
 
 /*
@@ -1431,7 +2210,9 @@ void BPF_STRUCT_OPS(rusty_runnable, struct task_struct *p, u64 enq_flags)
 	wakee_ctx->is_kworker = p->flags & PF_WQ_WORKER;
 
 	task_load_adj(wakee_ctx, now, true);
-	dom_dcycle_adj(wakee_ctx->domc, wakee_ctx->weight, now, true);
+	task_lb_latch(p, wakee_ctx);
+	task_dcycle_adj(wakee_ctx, wakee_ctx->domc, now, true);
+	task_pinned_adj(wakee_ctx, now, true);
 
 	if (fifo_sched)
 		return;
@@ -1466,6 +2247,7 @@ void BPF_STRUCT_OPS(rusty_running, struct task_struct *p)
 {
 	task_ptr usrptr = (task_ptr)sdt_task_data(p);
 	struct task_ctx *taskc;
+	struct pcpu_ctx *pcpuc;
 	dom_ptr domc;
 	u32 dap_gen;
 
@@ -1501,8 +2283,19 @@ void BPF_STRUCT_OPS(rusty_running, struct task_struct *p)
 		taskc->dom_active_tasks_gen = dap_gen;
 	}
 
-	if (fifo_sched)
+	if (cpu_to_dom_id(scx_bpf_task_cpu(p)) != taskc->target_dom)
+		taskc->nr_foreign_runs++;
+
+	if ((pcpuc = lookup_pcpu_ctx(scx_bpf_task_cpu(p)))) {
+		pcpuc->nr_runs++;
+		if (task_latency_critical(p, taskc))
+			pcpuc->nr_lat_crit_runs++;
+	}
+
+	if (fifo_sched) {
+		taskc->last_run_at = scx_bpf_now();
 		return;
+	}
 
 	running_update_vtime(p, taskc, domc);
 	taskc->last_run_at = scx_bpf_now();
@@ -1524,21 +2317,84 @@ static void stopping_update_vtime(struct task_struct *p,
 	taskc->deadline = p->scx.dsq_vtime + task_compute_dl(p, taskc, 0);
 }
 
+/*
+ * Classify @p as interactive if it runs for short periods at a time and
+ * frequently blocks or wakes up other tasks, i.e. is a consumer or producer
+ * in a wakeup chain, as UI and audio threads usually are. CPU hogs such as
+ * compilers run until they exhaust their slices and rarely block. The exit
+ * thresholds are twice as loose as the entry ones so that tasks near the
+ * thresholds don't flap.
+ */
+static void task_update_interactive(struct task_ctx *taskc)
+{
+	u64 freq = taskc->blocked_freq > taskc->waker_freq ?
+		   taskc->blocked_freq : taskc->waker_freq;
+	bool is_interactive;
+
+	if (taskc->is_interactive)
+		is_interactive = taskc->avg_runtime <= 2 * interactive_runtime_ns &&
+				 2 * freq >= interactive_min_freq;
+	else
+		is_interactive = taskc->avg_runtime <= interactive_runtime_ns &&
+				 freq >= interactive_min_freq;
+
+	if (is_interactive == taskc->is_interactive)
+		return;
+
+	taskc->is_interactive = is_interactive;
+	if (is_interactive)
+		__sync_fetch_and_add(&nr_interactive, 1);
+	else
+		__sync_fetch_and_sub(&nr_interactive, 1);
+}
+
+/*
+ * Track the NUMA node @p has mostly been running on, which is likely where
+ * most of its memory is. The load balancer prefers moving tasks back to their
+ * home nodes. Short stays on other nodes, e.g. through greedy execution, don't
+ * change the home node.
+ */
+static void task_update_home_node(struct task_struct *p, struct task_ctx *taskc)
+{
+	u32 dom_id = cpu_to_dom_id(scx_bpf_task_cpu(p)), node;
+
+	if (dom_id >= MAX_DOMS)
+		return;
+
+	node = dom_node_id(dom_id);
+	if (node == taskc->home_node) {
+		taskc->away_runtime = 0;
+		return;
+	}
+
+	taskc->away_runtime += scx_bpf_now() - taskc->last_run_at;
+	if (taskc->away_runtime >= NUMA_HOME_RUNTIME_NS) {
+		taskc->home_node = node;
+		taskc->away_runtime = 0;
+	}
+}
+
 void BPF_STRUCT_OPS(rusty_stopping, struct task_struct *p, bool runnable)
 {
 	struct task_ctx *taskc;
 	dom_ptr domc;
 
-	if (fifo_sched)
+	if (!(taskc = lookup_task_ctx(p)))
 		return;
 
-	if (!(taskc = lookup_task_ctx(p)))
+	if (nr_nodes > 1)
+		task_update_home_node(p, taskc);
+
+	if (fifo_sched)
 		return;
 
 	if (!(domc = task_domain(taskc)))
 		return;
 
 	stopping_update_vtime(p, taskc, domc);
+
+	if (interactive)
+		task_update_interactive(taskc);
 }
 
 void BPF_STRUCT_OPS(rusty_quiescent, struct task_struct *p, u64 deq_flags)
@@ -1554,7 +2410,8 @@ void BPF_STRUCT_OPS(rusty_quiescent, struct task_struct *p, u64 deq_flags)
 		return;
 
 	task_load_adj(taskc, now, false);
-	dom_dcycle_adj(domc, taskc->weight, now, false);
+	task_dcycle_adj(taskc, domc, now, false);
+	task_pinned_adj(taskc, now, false);
 
 	if (fifo_sched)
 		return;
@@ -1578,11 +2435,81 @@ void BPF_STRUCT_OPS(rusty_set_weight, struct task_struct *p, u32 weight)
 	taskc->weight = weight;
 }
 
+s32 BPF_STRUCT_OPS_SLEEPABLE(rusty_cgroup_init, struct cgroup *cgrp,
+			     struct scx_cgroup_init_args *args)
+{
+	struct cgrp_lb_val cgv = { .lb.weight = args->weight };
+	u64 cgid = cgrp->kn->id;
+	s32 ret;
+
+	if (!cgroup_lb || !cgrp->level)
+		return 0;
+
+	/*
+	 * If there are more than MAX_LB_CGRPS cgroups, the tasks of the ones
+	 * which don't fit are accounted in the domain load buckets.
+	 */
+	ret = bpf_map_update_elem(&cgrp_lb_data, &cgid, &cgv, BPF_NOEXIST);
+	if (ret && ret != -E2BIG && ret != -EEXIST)
+		return ret;
+	return 0;
+}
+
+void BPF_STRUCT_OPS(rusty_cgroup_exit, struct cgroup *cgrp)
+{
+	struct cgrp_dom_key key = { .cgid = cgrp->kn->id };
+	u64 cgid = cgrp->kn->id;
+	u32 dom_id;
+
+	if (!cgroup_lb)
+		return;
+
+	bpf_map_delete_elem(&cgrp_lb_data, &cgid);
+	bpf_for(dom_id, 0, nr_doms) {
+		key.dom_id = dom_id;
+		bpf_map_delete_elem(&cgrp_dom_lb_data, &key);
+	}
+}
+
+void BPF_STRUCT_OPS(rusty_cgroup_set_weight, struct cgroup *cgrp, u32 weight)
+{
+	struct cgrp_lb_val *cgv;
+	u64 cgid = cgrp->kn->id;
+
+	if (!cgroup_lb || !(cgv = bpf_map_lookup_elem(&cgrp_lb_data, &cgid)))
+		return;
+
+	cgv->lb.weight = weight;
+}
+
+void BPF_STRUCT_OPS(rusty_cgroup_move, struct task_struct *p,
+		    struct cgroup *from, struct cgroup *to)
+{
+	u64 now = scx_bpf_now(), cgid = to->kn->id;
+	struct task_ctx *taskc;
+	dom_ptr domc;
+
+	if (!cgroup_lb || !(taskc = try_lookup_task_ctx(p)) ||
+	    !taskc->runnable || !(domc = task_domain(taskc)))
+		return;
+
+	/*
+	 * Move @p's runnable weight along. Its duty cycle history stays where
+	 * it was accounted.
+	 */
+	task_dcycle_adj(taskc, domc, now, false);
+	taskc->lb_cgid = 0;
+	if (to->level && bpf_map_lookup_elem(&cgrp_lb_data, &cgid))
+		taskc->lb_cgid = cgid;
+	task_dcycle_adj(taskc, domc, now, true);
+}
+
 static u32 task_pick_domain(struct task_ctx *taskc, struct task_struct *p,
 			    const struct cpumask *cpumask)
 {
 	s32 cpu = bpf_get_smp_processor_id();
 	u32 first_dom = NO_DOM_FOUND, dom, preferred_dom = NO_DOM_FOUND;
+	u32 parked_dom = NO_DOM_FOUND;
 
 	if (cpu < 0 || cpu >= MAX_CPUS)
 		return NO_DOM_FOUND;
@@ -1596,6 +2523,14 @@ static u32 task_pick_domain(struct task_ctx *taskc, struct task_struct *p,
 
 		if (cpumask_intersects_domain(cpumask, dom)) {
 			taskc->dom_mask |= 1LLU << dom;
+
+			/* parked domains are used only if there's no other */
+			if (dom_parked(dom)) {
+				if (parked_dom == NO_DOM_FOUND)
+					parked_dom = dom;
+				continue;
+			}
+
 			/*
 			 * The starting point is round-robin'd and the first
 			 * match should be spread across all the domains.
@@ -1612,7 +2547,9 @@ static u32 task_pick_domain(struct task_ctx *taskc, struct task_struct *p,
 		}
 	}
 
-	return preferred_dom != NO_DOM_FOUND ? preferred_dom: first_dom;
+	if (preferred_dom != NO_DOM_FOUND)
+		return preferred_dom;
+	return first_dom != NO_DOM_FOUND ? first_dom : parked_dom;
 }
 
 static void task_pick_and_set_domain(struct task_ctx *taskc,
@@ -1700,14 +2637,21 @@ s32 BPF_STRUCT_OPS_SLEEPABLE(rusty_init_task, struct task_struct *p,
 	task_pick_and_set_domain(taskc, p, p->cpus_ptr, true);
 	bpf_rcu_read_unlock();
 
+	if (taskc->target_dom < MAX_DOMS)
+		taskc->home_node = dom_node_id(taskc->target_dom);
+
 	return 0;
 }
 
 void BPF_STRUCT_OPS(rusty_exit_task, struct task_struct *p,
 		    struct scx_exit_task_args *args)
 {
+	struct task_ctx *taskc;
 	long ret;
 
+	if ((taskc = try_lookup_task_ctx(p)) && taskc->is_interactive)
+		__sync_fetch_and_sub(&nr_interactive, 1);
+
 	sdt_task_free(p);
 
 	/*
@@ -1774,6 +2718,7 @@ __weak s32 create_dom(u32 dom_id)
 	struct node_ctx *nodec;
 	struct bpf_cpumask *dom_mask, *node_mask, *all_mask;
 	struct lb_domain *lb_domain;
+	struct pcpu_ctx *pcpuc;
 	u32 cpu, node_id;
 	int perf;
 	s32 ret;
@@ -1849,6 +2794,8 @@ __weak s32 create_dom(u32 dom_id)
 		 */
 		perf = min(SCX_CPUPERF_ONE, rusty_perf_mode);
 		scx_bpf_cpuperf_set(cpu, perf);
+		if ((pcpuc = lookup_pcpu_ctx(cpu)))
+			pcpuc->cpuperf = perf;
 	}
 	bpf_rcu_read_unlock();
 	if (ret)
@@ -1946,6 +2893,24 @@ s32 BPF_STRUCT_OPS_SLEEPABLE(rusty_init)
 	if (ret)
 		return ret;
 
+	ret = create_save_cpumask(&parked_cpumask);
+	if (ret)
+		return ret;
+
+	ret = create_save_cpumask(&reserved_cpumask);
+	if (ret)
+		return ret;
+
+	/* reserve the second sibling of each core */
+	if (reserve_sibling && reserved_cpumask) {
+		bpf_for(i, 0, nr_cpu_ids) {
+			s32 sib = sibling_cpu(i);
+
+			if (sib >= 0 && sib < i)
+				bpf_cpumask_set_cpu(i, reserved_cpumask);
+		}
+	}
+
 	ret = scx_rusty_percpu_storage_init();
 	if (ret)
 		return ret;
@@ -1962,6 +2927,13 @@ s32 BPF_STRUCT_OPS_SLEEPABLE(rusty_init)
 	}
 
 	bpf_for(i, 0, nr_cpu_ids) {
+		ret = scx_bpf_create_dsq(PINNED_DSQ_BASE + i, -1);
+		if (ret < 0) {
+			scx_bpf_error("Failed to create pinned dsq for cpu%d (%d)",
+				      i, ret);
+			return ret;
+		}
+
 		if (is_offline_cpu(i))
 			continue;
 
@@ -1973,6 +2945,61 @@ s32 BPF_STRUCT_OPS_SLEEPABLE(rusty_init)
 	return 0;
 }
 
+/*
+ * Sample the DSQ depth of each domain into dom_ctx->nr_queued. Run by
+ * userspace when reading the stats so that idle domains don't report stale
+ * values.
+ */
+SEC("syscall")
+int sample_nr_queued(void *ctx)
+{
+	dom_ptr domc;
+	u32 dom_id;
+
+	bpf_for(dom_id, 0, nr_doms) {
+		if ((domc = try_lookup_dom_ctx(dom_id)))
+			WRITE_ONCE(domc->nr_queued, scx_bpf_dsq_nr_queued(dom_id));
+	}
+
+	return 0;
+}
+
+/* Written by inspect_task(), the pid is set by userspace */
+struct task_inspect task_inspect;
+
+SEC("syscall")
+int inspect_task(void *ctx)
+{
+	struct task_struct *p;
+	struct task_ctx *taskc;
+	s32 cpu;
+
+	p = bpf_task_from_pid(task_inspect.pid);
+	if (!p)
+		return -ESRCH;
+
+	if (!(taskc = try_lookup_task_ctx(p))) {
+		bpf_task_release(p);
+		return -ENOENT;
+	}
+
+	task_inspect.taskc = *taskc;
+	task_inspect.nr_cpus_allowed = p->nr_cpus_allowed;
+	task_inspect.dsq_vtime = p->scx.dsq_vtime;
+	task_inspect.slice = p->scx.slice;
+
+	__builtin_memset(task_inspect.cpumask, 0, sizeof(task_inspect.cpumask));
+	bpf_for(cpu, 0, nr_cpu_ids) {
+		u32 idx = cpu / 64;
+
+		if (idx < MAX_CPUS / 64 && bpf_cpumask_test_cpu(cpu, p->cpus_ptr))
+			task_inspect.cpumask[idx] |= 1LLU << (cpu % 64);
+	}
+
+	bpf_task_release(p);
+	return 0;
+}
+
 void BPF_STRUCT_OPS(rusty_exit, struct scx_exit_info *ei)
 {
 	UEI_RECORD(uei, ei);
@@ -1990,6 +3017,10 @@ SCX_OPS_DEFINE(rusty,
 	       .set_cpumask		= (void *)rusty_set_cpumask,
 	       .init_task		= (void *)rusty_init_task,
 	       .exit_task		= (void *)rusty_exit_task,
+	       .cgroup_init		= (void *)rusty_cgroup_init,
+	       .cgroup_exit		= (void *)rusty_cgroup_exit,
+	       .cgroup_set_weight	= (void *)rusty_cgroup_set_weight,
+	       .cgroup_move		= (void *)rusty_cgroup_move,
 	       .init			= (void *)rusty_init,
 	       .exit			= (void *)rusty_exit,
 	       .timeout_ms		= 10000,
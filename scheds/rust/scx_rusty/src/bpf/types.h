@@ -34,6 +34,17 @@ struct task_ctx {
 
 	u32 target_dom;
 	u32 weight;
+
+	/*
+	 * Weight the duty cycle is accounted under, latched when the task
+	 * becomes runnable. With cgroup_lb, tasks in non-root cgroups are
+	 * accounted in their cgroup's load in the domain rather than in the
+	 * domain load buckets, and lb_cgid is the ID of the cgroup. The
+	 * cgroup's weight is split amongst its tasks when load balancing.
+	 */
+	u32 lb_weight;
+	u64 lb_cgid;
+
 	bool runnable;
 	u64 dom_active_tasks_gen;
 	u64 deadline;
@@ -50,9 +61,34 @@ struct task_ctx {
 	u64 waker_freq;
 	u64 last_woke_at;
 
+	/*
+	 * NUMA node the task has mostly been running on and how long it has
+	 * run elsewhere since, see task_update_home_node().
+	 */
+	u32 home_node;
+	u64 away_runtime;
+
+	/* Domain migration history, for --inspect-pid */
+	u32 prev_dom;
+	u64 nr_dom_migrations;
+	u64 last_dom_migration_at;
+	/* Number of times the task ran on a CPU outside its domain */
+	u64 nr_foreign_runs;
+
+	/*
+	 * Whether the task's runnable load is accounted as pinned, and to
+	 * which domain under which weight, see task_pinned_adj().
+	 */
+	bool pinned_counted;
+	u32 pinned_dom;
+	u32 pinned_weight;
+
 	/* The task is a workqueue worker thread */
 	bool is_kworker;
 
+	/* The task is classified as interactive, see task_update_interactive() */
+	bool is_interactive;
+
 	/* Allowed on all CPUs and eligible for DIRECT_GREEDY optimization */
 	bool all_cpus;
 
@@ -73,6 +109,16 @@ typedef struct task_ctx __attribute__((address_space(1))) *task_ptr;
 typedef struct task_ctx *task_ptr;
 #endif
 
+/* Snapshot of a task filled by the inspect_task() syscall program */
+struct task_inspect {
+	s32 pid;
+	u32 nr_cpus_allowed;
+	u64 dsq_vtime;
+	u64 slice;
+	u64 cpumask[MAX_CPUS / 64];
+	struct task_ctx taskc;
+};
+
 struct bucket_ctx {
 	u64 dcycle;
 	struct ravg_data rd;
@@ -92,6 +138,31 @@ struct dom_ctx {
 	u64 dbg_dcycle_printed_at;
 	struct bucket_ctx buckets[LB_LOAD_BUCKETS];
 	struct dom_active_tasks active_tasks;
+
+	/* Statistics read by userspace, the greedy counts are cumulative */
+	u64 nr_queued;		/* DSQ depth, see sample_nr_queued() */
+	u64 nr_greedy_in;	/* foreign tasks executed on the domain's CPUs */
+	u64 nr_greedy_out;	/* domestic tasks executed on foreign CPUs */
+	u64 nr_steals;		/* foreign tasks stolen on dispatch */
+	u64 nr_steal_throttled;	/* steals skipped as the budget ran out */
+
+	/*
+	 * Max # of tasks the domain's CPUs may steal on dispatch per
+	 * steal_window_ns, 0 for unlimited. Set by userspace, see
+	 * --greedy-steal-budget.
+	 */
+	u64 steal_budget;
+	u64 steal_window_at;
+	u64 steal_window_cnt;
+
+	/*
+	 * # and summed LB weights of the runnable tasks which can't run in any
+	 * other domain. The LB can't move their load and leaves it out.
+	 */
+	u64 pinned_nr;
+	u64 pinned_weight;
+	struct ravg_data pinned_nr_rd;
+	struct ravg_data pinned_weight_rd;
 };
 
 struct node_ctx {
@@ -0,0 +1,350 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2.
+
+//! TOML configuration file, see --config.
+//!
+//! The top-level keys are the long names of the command line options with
+//! either dashes or underscores, e.g.:
+//!
+//! ```toml
+//! slice_us_underutil = 10000
+//! greedy_threshold = 2
+//! cpumasks = ["0xff", "0xff00"]
+//! cgroup_lb = true
+//!
+//! # Per-domain overrides, keyed by the domain ID.
+//! [domains.1]
+//! direct_greedy_under = 50.0
+//! park = false
+//! ```
+//!
+//! The file is watched with inotify and re-applied on changes. Only the
+//! options which can be changed at runtime through --tunables, and the
+//! per-domain overrides, take effect without restarting the scheduler.
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::ffi::OsString;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+
+/// Settings which apply to a single domain instead of the global option.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DomainOverride {
+    /// --direct-greedy-under for the domain's CPUs.
+    pub direct_greedy_under: Option<f64>,
+    /// --kick-greedy-under for the domain's CPUs.
+    pub kick_greedy_under: Option<f64>,
+    /// --greedy-steal-budget for the domain.
+    pub greedy_steal_budget: Option<u64>,
+    /// Whether the domain can be parked, see --park-under. Defaults to true.
+    pub park: Option<bool>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub path: PathBuf,
+    /// Top-level options keyed by their names with underscores.
+    opts: BTreeMap<String, toml::Value>,
+    pub domains: BTreeMap<usize, DomainOverride>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {:?}", path))?;
+        let mut table: toml::Table =
+            toml::from_str(&text).with_context(|| format!("Failed to parse config {:?}", path))?;
+
+        let mut domains = BTreeMap::new();
+        if let Some(val) = table.remove("domains") {
+            let doms = match val {
+                toml::Value::Table(v) => v,
+                _ => bail!("\"domains\" must be a table"),
+            };
+            for (key, val) in doms.into_iter() {
+                let dom_id = key
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("invalid domain ID {:?}", key))?;
+                let ovr: DomainOverride = val
+                    .try_into()
+                    .with_context(|| format!("Invalid overrides for domain {}", dom_id))?;
+                domains.insert(dom_id, ovr);
+            }
+        }
+
+        let mut opts = BTreeMap::new();
+        for (key, val) in table.into_iter() {
+            let key = key.replace('-', "_");
+            if key == "config" {
+                bail!("config can't be set from the config file");
+            }
+            opts.insert(key, val);
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            opts,
+            domains,
+        })
+    }
+
+    /// The options as command line arguments.
+    pub fn args(&self) -> Result<Vec<String>> {
+        fn scalar(key: &str, val: &toml::Value) -> Result<String> {
+            match val {
+                toml::Value::Integer(v) => Ok(format!("--{}={}", key, v)),
+                toml::Value::Float(v) => Ok(format!("--{}={}", key, v)),
+                toml::Value::String(v) => Ok(format!("--{}={}", key, v)),
+                _ => bail!("invalid value {} for {}", val, key),
+            }
+        }
+
+        let mut args = vec![];
+        for (key, val) in self.opts.iter() {
+            let key = key.replace('_', "-");
+            match val {
+                toml::Value::Boolean(true) => args.push(format!("--{}", key)),
+                toml::Value::Boolean(false) => {}
+                toml::Value::Array(vals) => {
+                    for v in vals.iter() {
+                        args.push(scalar(&key, v)?);
+                    }
+                }
+                v => args.push(scalar(&key, v)?),
+            }
+        }
+        Ok(args)
+    }
+
+    /// The names of the options, with underscores, which differ in `other`.
+    pub fn changed_opts(&self, other: &Config) -> Vec<String> {
+        let mut keys: Vec<String> = self.opts.keys().chain(other.opts.keys()).cloned().collect();
+        keys.sort();
+        keys.dedup();
+        keys.retain(|key| self.opts.get(key) != other.opts.get(key));
+        keys
+    }
+}
+
+/// Watches a file for updates, including replacement by rename as done by
+/// most editors, by watching its parent directory.
+pub struct ConfigWatcher {
+    fd: OwnedFd,
+    name: OsString,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid config path {:?}", path))?
+            .to_owned();
+        let dir = match path.parent() {
+            Some(v) if !v.as_os_str().is_empty() => v,
+            _ => Path::new("."),
+        };
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            bail!("inotify_init1 failed ({})", std::io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let cdir = CString::new(dir.as_os_str().as_bytes())?;
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+        if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), cdir.as_ptr(), mask) } < 0 {
+            bail!(
+                "Failed to watch {:?} ({})",
+                dir,
+                std::io::Error::last_os_error()
+            );
+        }
+
+        Ok(Self { fd, name })
+    }
+
+    /// Whether the file has been updated since the last call. Doesn't block.
+    pub fn changed(&self) -> Result<bool> {
+        const HDR_LEN: usize = std::mem::size_of::<libc::inotify_event>();
+        let mut buf = [0u8; 4096];
+        let mut changed = false;
+
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if len < 0 {
+                let err = std::io::Error::last_os_error();
+                match err.kind() {
+                    std::io::ErrorKind::WouldBlock => break,
+                    std::io::ErrorKind::Interrupted => continue,
+                    _ => bail!("Failed to read inotify events ({})", err),
+                }
+            }
+
+            let len = len as usize;
+            let mut off = 0;
+            while off + HDR_LEN <= len {
+                let ev: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buf[off..].as_ptr() as *const _) };
+                let name_end = (off + HDR_LEN + ev.len as usize).min(len);
+                let name = &buf[off + HDR_LEN..name_end];
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                if name == self.name.as_bytes() {
+                    changed = true;
+                }
+                off = name_end;
+            }
+        }
+
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_str(name: &str, text: &str) -> Result<Config> {
+        let path = std::env::temp_dir().join(format!(
+            "scx_rusty_config_{}_{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, text).unwrap();
+        let res = Config::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        res
+    }
+
+    #[test]
+    fn test_args() {
+        let cfg = load_str(
+            "args",
+            r#"
+slice_us_underutil = 10000
+greedy-threshold = 2
+direct_greedy_under = 90.5
+cpumasks = ["0xff", "0xff00"]
+cgroup_lb = true
+no_load_balance = false
+exit_dump_len = 0
+"#,
+        )
+        .unwrap();
+
+        // Keys are sorted and converted to dashes.
+        assert_eq!(
+            cfg.args().unwrap(),
+            vec![
+                "--cgroup-lb",
+                "--cpumasks=0xff",
+                "--cpumasks=0xff00",
+                "--direct-greedy-under=90.5",
+                "--exit-dump-len=0",
+                "--greedy-threshold=2",
+                "--slice-us-underutil=10000",
+            ]
+        );
+        assert!(cfg.domains.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        let cfg = load_str("table", "[greedy_threshold]\nfoo = 1\n").unwrap();
+        assert!(cfg.args().is_err());
+        let cfg = load_str("nested", "cpumasks = [[\"0xff\"]]\n").unwrap();
+        assert!(cfg.args().is_err());
+
+        assert!(load_str("config", "config = \"foo.toml\"\n").is_err());
+        assert!(load_str("parse", "greedy_threshold = \n").is_err());
+        assert!(Config::load(Path::new("/nonexistent/scx_rusty.toml")).is_err());
+    }
+
+    #[test]
+    fn test_domains() {
+        let cfg = load_str(
+            "domains",
+            r#"
+greedy_threshold = 2
+
+[domains.1]
+direct_greedy_under = 50.0
+park = false
+
+[domains.3]
+greedy_steal_budget = 4
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(cfg.args().unwrap(), vec!["--greedy-threshold=2"]);
+        assert_eq!(cfg.domains.len(), 2);
+        assert_eq!(
+            cfg.domains[&1],
+            DomainOverride {
+                direct_greedy_under: Some(50.0),
+                park: Some(false),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            cfg.domains[&3],
+            DomainOverride {
+                greedy_steal_budget: Some(4),
+                ..Default::default()
+            }
+        );
+
+        assert!(load_str("dom_id", "[domains.foo]\npark = true\n").is_err());
+        assert!(load_str("dom_field", "[domains.0]\nfoo = true\n").is_err());
+        assert!(load_str("dom_type", "[domains.0]\npark = 1\n").is_err());
+        assert!(load_str("dom_table", "domains = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_changed_opts() {
+        let a = load_str(
+            "changed_a",
+            "greedy_threshold = 2\ncgroup_lb = true\ncpumasks = [\"0xff\"]\n",
+        )
+        .unwrap();
+        let b = load_str(
+            "changed_b",
+            "greedy-threshold = 2\ncpumasks = [\"0xff\", \"0xff00\"]\nslice_us_underutil = 100\n",
+        )
+        .unwrap();
+
+        assert!(a.changed_opts(&a).is_empty());
+        assert_eq!(
+            a.changed_opts(&b),
+            vec!["cgroup_lb", "cpumasks", "slice_us_underutil"]
+        );
+        assert_eq!(a.changed_opts(&b), b.changed_opts(&a));
+
+        // Domain overrides aren't options.
+        let c = load_str(
+            "changed_c",
+            "greedy_threshold = 2\ncgroup_lb = true\ncpumasks = [\"0xff\"]\n[domains.0]\npark = false\n",
+        )
+        .unwrap();
+        assert!(a.changed_opts(&c).is_empty());
+    }
+}
@@ -5,6 +5,7 @@
 use std::collections::BTreeMap;
 
 use crate::bpf_skel::*;
+use anyhow::bail;
 use anyhow::Result;
 use scx_utils::Cpumask;
 use scx_utils::Topology;
@@ -29,12 +30,6 @@ impl Domain {
         self.mask.clone()
     }
 
-    /// Get a raw slice of the domain's cpumask as a set of one or more u64
-    /// variables whose bits represent CPUs in the mask.
-    pub fn mask_slice(&self) -> &[u64] {
-        self.mask.as_raw_slice()
-    }
-
     /// The number of CPUs in the domain.
     pub fn weight(&self) -> usize {
         self.mask.weight()
@@ -53,6 +48,31 @@ impl Domain {
     }
 }
 
+/// The topology level domains are built at, see --dom-level.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum DomLevel {
+    /// CPUs sharing a last level cache. On AMD, this is a CCX.
+    #[value(alias = "ccx")]
+    Llc,
+    /// CPUs sharing the cache at --cache-level.
+    Cache,
+    /// CPUs in a die, e.g. a CCD on AMD.
+    Die,
+    /// CPUs in a NUMA node.
+    Node,
+}
+
+/// How the CPUs are split into domains.
+#[derive(Clone, Debug)]
+pub enum DomSpec {
+    /// A domain for each group of CPUs at the level. The second field is
+    /// the cache level for DomLevel::Cache.
+    Level(DomLevel, usize),
+    /// A domain for each cpumask. The whole machine is treated as a single
+    /// NUMA node.
+    Masks(Vec<Cpumask>),
+}
+
 #[derive(Debug)]
 pub struct DomainGroup {
     doms: BTreeMap<usize, Domain>,
@@ -62,51 +82,91 @@ pub struct DomainGroup {
 }
 
 impl DomainGroup {
-    pub fn new(top: &Topology, cpumasks: &[String]) -> Result<Self> {
-        let mut span = Cpumask::new();
+    pub fn new(top: &Topology, spec: &DomSpec) -> Result<Self> {
+        // The NUMA node of the first CPU of @mask.
+        let cpu_node = |mask: &Cpumask| {
+            mask.iter()
+                .next()
+                .and_then(|cpu| top.all_cpus.get(&cpu))
+                .map(|cpu| cpu.node_id)
+                .unwrap_or(0)
+        };
+
+        let (groups, num_numa_nodes): (Vec<(Cpumask, usize)>, usize) = match spec {
+            DomSpec::Masks(masks) => {
+                let mut seen = Cpumask::new();
+                for mask in masks.iter() {
+                    if mask.intersects(&seen) {
+                        bail!("Domain cpumask {:x} overlaps with another domain", mask);
+                    }
+                    seen |= mask;
+                }
+                (masks.iter().map(|mask| (mask.clone(), 0)).collect(), 1)
+            }
+            DomSpec::Level(level, cache_level) => {
+                let groups = match level {
+                    DomLevel::Llc => top
+                        .nodes
+                        .iter()
+                        .flat_map(|(node_id, node)| {
+                            node.llcs
+                                .values()
+                                .map(move |llc| (llc.span.clone(), *node_id))
+                        })
+                        .collect(),
+                    DomLevel::Cache => {
+                        let groups = top.cache_groups(*cache_level);
+                        if groups.is_empty() {
+                            bail!("No L{} caches found, see --cache-level", cache_level);
+                        }
+                        groups
+                            .iter()
+                            .map(|group| group.span.and(&top.span))
+                            .map(|mask| {
+                                let node_id = cpu_node(&mask);
+                                (mask, node_id)
+                            })
+                            .collect()
+                    }
+                    DomLevel::Die => top
+                        .all_dies
+                        .values()
+                        .map(|die| (die.span.clone(), cpu_node(&die.span)))
+                        .collect(),
+                    DomLevel::Node => top
+                        .nodes
+                        .iter()
+                        .map(|(node_id, node)| (node.span.clone(), *node_id))
+                        .collect(),
+                };
+                (groups, top.nodes.len())
+            }
+        };
+
+        let mut doms = BTreeMap::new();
         let mut dom_numa_map = BTreeMap::new();
+        let mut span = Cpumask::new();
         // Track the domain ID separate from the LLC ID, because LLC IDs can
         // have gaps if there are offlined CPUs, and domain IDs need to be
         // contiguous (at least for now, until we can update libraries to not
-        // return vectors of domain values).
-        let mut dom_id = 0;
-        let (doms, num_numa_nodes) = if !cpumasks.is_empty() {
-            let mut doms: BTreeMap<usize, Domain> = BTreeMap::new();
-            for mask_str in cpumasks.iter() {
-                let mask = Cpumask::from_str(mask_str)?;
-                span |= &mask;
-                doms.insert(
-                    dom_id,
-                    Domain {
-                        id: dom_id,
-                        mask,
-                        ctx: Arc::new(Mutex::new(None)),
-                    },
-                );
-                dom_numa_map.insert(dom_id, 0);
-                dom_id += 1;
-            }
-            (doms, 1)
-        } else {
-            let mut doms: BTreeMap<usize, Domain> = BTreeMap::new();
-            for (node_id, node) in &top.nodes {
-                for (_, llc) in node.llcs.iter() {
-                    let mask = llc.span.clone();
-                    span |= &mask;
-                    doms.insert(
-                        dom_id,
-                        Domain {
-                            id: dom_id,
-                            mask,
-                            ctx: Arc::new(Mutex::new(None)),
-                        },
-                    );
-                    dom_numa_map.insert(dom_id, *node_id);
-                    dom_id += 1;
-                }
+        // return vectors of domain values). Groups without CPUs, e.g.
+        // memory-only nodes, are skipped for the same reason.
+        for (mask, node_id) in groups.into_iter() {
+            if mask.is_empty() {
+                continue;
             }
-            (doms, top.nodes.len())
-        };
+            let dom_id = doms.len();
+            span |= &mask;
+            doms.insert(
+                dom_id,
+                Domain {
+                    id: dom_id,
+                    mask,
+                    ctx: Arc::new(Mutex::new(None)),
+                },
+            );
+            dom_numa_map.insert(dom_id, node_id);
+        }
 
         Ok(Self {
             doms,
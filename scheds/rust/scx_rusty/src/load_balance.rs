@@ -89,6 +89,30 @@
 //! LoadBalancer object, but actual load balancing is only performed if the
 //! balance_load option is specified.
 //!
+//! Steps 3 and 4 describe GreedyLoadBalancer, the default policy. Policies
+//! implement the LoadBalancer trait on top of an LbContext, which builds the
+//! hierarchy and moves tasks between domains, and are selected with
+//! --lb-policy. StealLoadBalancer turns the migrations around: under-loaded
+//! domains, least loaded first, steal from the most loaded domains, first
+//! within their node and then across nodes.
+//!
+//! The load of tasks which can't run in any other domain is pinned. It still
+//! counts towards the domain's load as the tasks occupy its CPUs but can't be
+//! pushed out, so a domain only pushes up to its unpinned load. Tasks
+//! restricted to a single CPU are queued on per-CPU pinned DSQs by BPF.
+//!
+//! Domains parked by the tuner are left out of the hierarchy so that no load
+//! is pushed onto them. Their remaining load still counts towards the total
+//! and is spread over the active domains.
+//!
+//! Moving a task to another NUMA node separates it from its memory, so
+//! migrations between nodes are damped in two ways. A node only pushes load
+//! after being over-loaded for --xnuma-hysteresis consecutive LB rounds, and
+//! a migration must reduce the imbalance by at least --xnuma-mig-cost times
+//! the task's load. Tasks moving back to their home node, the node on which
+//! BPF saw them run most recently for a while, are tried first and are exempt
+//! from the migration cost.
+//!
 //! Statistics
 //! ----------
 //!
@@ -96,7 +120,8 @@
 //! the get_stats() function on the LoadBalancer object:
 //!
 //! ```
-//! let lb = LoadBalancer::new(...)?;
+//! let ctx = LbContext::new(...);
+//! let mut lb = new_load_balancer(LbPolicy::Greedy, ctx);
 //! lb.load_balance()?;
 //!
 //! let stats = lb.get_stats();
@@ -112,10 +137,10 @@
 //!
 //! There are a few ways that we could further improve the implementation here:
 //!
-//! - The logic for load balancing between NUMA nodes, and load balancing within
-//!   a specific NUMA node (i.e. between domains in that NUMA node), could
-//!   probably be improved to avoid code duplication using traits and/or
-//!   generics.
+//! - The greedy logic for load balancing between NUMA nodes, and load
+//!   balancing within a specific NUMA node (i.e. between domains in that NUMA
+//!   node), could probably be improved to avoid code duplication using traits
+//!   and/or generics.
 //!
 //! - When deciding whether to migrate a task, we're only looking at its impact
 //!   on addressing load imbalances. In reality, this is a very complex,
@@ -128,7 +153,10 @@
 //!   Coming up with an extensible and clean way to model and implement this is
 //!   likely itself a large project.
 //!
-//! - We're not accounting for cgroups when performing load balancing.
+//! - Cgroups are only accounted for when --cgroup-lb is specified, in which
+//!   case the weight of each task's innermost cgroup is split amongst its
+//!   runnable tasks when load balancing. Nested cgroup weights aren't
+//!   considered.
 
 use core::cmp::Ordering;
 use std::cell::Cell;
@@ -139,6 +167,8 @@ use std::sync::Arc;
 
 use anyhow::bail;
 use anyhow::Result;
+use libbpf_rs::MapCore as _;
+use libbpf_rs::MapFlags;
 use log::debug;
 use log::trace;
 use ordered_float::OrderedFloat;
@@ -156,7 +186,16 @@ use crate::DomainGroup;
 const DEFAULT_WEIGHT: f64 = bpf_intf::consts_LB_DEFAULT_WEIGHT as f64;
 const RAVG_FRAC_BITS: u32 = bpf_intf::ravg_consts_RAVG_FRAC_BITS;
 
-fn now_monotonic() -> u64 {
+/// Copy a `T` out of the end of the BPF map key or value `bytes`. The cgroup
+/// load balancing map values start with a spin lock which isn't shared with
+/// userspace.
+fn read_map_tail<T: Copy>(bytes: &[u8]) -> T {
+    let size = std::mem::size_of::<T>();
+    assert!(bytes.len() >= size);
+    unsafe { std::ptr::read_unaligned(bytes[bytes.len() - size..].as_ptr() as *const T) }
+}
+
+pub fn now_monotonic() -> u64 {
     let mut time = libc::timespec {
         tv_sec: 0,
         tv_nsec: 0,
@@ -319,6 +358,7 @@ struct TaskInfo {
     load: OrderedFloat<f64>,
     dom_mask: u64,
     preferred_dom_mask: u64,
+    home_node: usize,
     migrated: Cell<bool>,
     is_kworker: bool,
 }
@@ -335,7 +375,17 @@ struct Domain {
     id: usize,
     queried_tasks: bool,
     load: LoadEntity,
+    // Load of the tasks which can't leave the domain.
+    pinned_load: f64,
     tasks: SortedVec<TaskInfo>,
+
+    // Balance state when the hierarchy was created, the number of tasks
+    // migrated out and in, and why no task could be pushed if that was the
+    // case. Reported in DomainStats::lb.
+    init_state: BalanceState,
+    nr_pushed: u64,
+    nr_pulled: u64,
+    push_failure: Option<&'static str>,
 }
 
 impl Domain {
@@ -343,18 +393,24 @@ impl Domain {
     const LOAD_IMBAL_XFER_TARGET_RATIO: f64 = 0.50;
     const LOAD_IMBAL_PUSH_MAX_RATIO: f64 = 0.50;
 
-    fn new(id: usize, load_sum: f64, load_avg: f64) -> Self {
+    fn new(id: usize, load_sum: f64, load_avg: f64, pinned_load: f64) -> Self {
+        let load = LoadEntity::new(
+            Domain::LOAD_IMBAL_HIGH_RATIO,
+            Domain::LOAD_IMBAL_PUSH_MAX_RATIO,
+            Domain::LOAD_IMBAL_XFER_TARGET_RATIO,
+            load_sum,
+            load_avg,
+        );
         Self {
             id,
             queried_tasks: false,
-            load: LoadEntity::new(
-                Domain::LOAD_IMBAL_HIGH_RATIO,
-                Domain::LOAD_IMBAL_PUSH_MAX_RATIO,
-                Domain::LOAD_IMBAL_XFER_TARGET_RATIO,
-                load_sum,
-                load_avg,
-            ),
+            init_state: load.state(),
+            load,
+            pinned_load,
             tasks: SortedVec::new(),
+            nr_pushed: 0,
+            nr_pulled: 0,
+            push_failure: None,
         }
     }
 
@@ -366,10 +422,42 @@ impl Domain {
 
         self.load.add_load(-load);
         other.load.add_load(load);
+        self.nr_pushed += 1;
+        other.nr_pulled += 1;
+    }
+
+    /// Describe what load balancing did with the domain and why.
+    fn lb_decision(&self, balanced: bool) -> String {
+        if !balanced {
+            return "not balancing".to_string();
+        }
+        match (self.init_state, self.nr_pushed, self.nr_pulled) {
+            (BalanceState::Balanced, 0, 0) => "balanced, imbalance under threshold".to_string(),
+            (BalanceState::NeedsPush, 0, _) => format!(
+                "over-loaded, {}",
+                self.push_failure.unwrap_or("no domain to push to")
+            ),
+            (BalanceState::NeedsPull, _, 0) => "under-loaded, nothing pulled".to_string(),
+            (state, pushed, pulled) => {
+                let mut desc = state.to_string().to_lowercase();
+                if pushed > 0 {
+                    desc += &format!(", pushed {} tasks", pushed);
+                }
+                if pulled > 0 {
+                    desc += &format!(", pulled {} tasks", pulled);
+                }
+                desc
+            }
+        }
+    }
+
+    /// The part of the load which can be pushed to other domains.
+    fn movable_load(&self) -> f64 {
+        (self.load.load_sum() - self.pinned_load).max(0.0)
     }
 
     fn xfer_between(&self, other: &Domain) -> f64 {
-        self.load.xfer_between(&other.load)
+        self.load.xfer_between(&other.load).min(self.movable_load())
     }
 }
 
@@ -384,7 +472,12 @@ impl_ord_for_type!(Domain);
 struct NumaNode {
     id: usize,
     load: LoadEntity,
+    pinned_load: f64,
     domains: SortedVec<Domain>,
+
+    // Loads and pinned loads of the parked domains which don't take part
+    // in load balancing but are still reported in the stats.
+    parked_doms: BTreeMap<usize, (f64, f64)>,
 }
 
 impl NumaNode {
@@ -402,19 +495,23 @@ impl NumaNode {
                 0.0f64,
                 numa_load_avg,
             ),
+            pinned_load: 0.0f64,
             domains: SortedVec::new(),
+            parked_doms: BTreeMap::new(),
         }
     }
 
-    fn allocate_domain(&mut self, id: usize, load: f64, dom_load_avg: f64) {
-        let domain = Domain::new(id, load, dom_load_avg);
+    fn allocate_domain(&mut self, id: usize, load: f64, dom_load_avg: f64, pinned_load: f64) {
+        let domain = Domain::new(id, load, dom_load_avg, pinned_load);
 
         self.insert_domain(domain);
         self.load.rebalance(self.load.load_sum() + load);
+        self.pinned_load += pinned_load;
     }
 
     fn xfer_between(&self, other: &NumaNode) -> f64 {
-        self.load.xfer_between(&other.load)
+        let movable = (self.load.load_sum() - self.pinned_load).max(0.0);
+        self.load.xfer_between(&other.load).min(movable)
     }
 
     fn insert_domain(&mut self, domain: Domain) {
@@ -425,18 +522,30 @@ impl NumaNode {
         self.load.add_load(delta);
     }
 
-    fn stats(&self) -> NodeStats {
+    fn stats(&self, balanced: bool) -> NodeStats {
         let mut stats = NodeStats::new(
             self.load.load_sum(),
+            self.pinned_load,
             self.load.imbal(),
             self.load.delta(),
             BTreeMap::new(),
         );
         for dom in self.domains.iter() {
-            stats.doms.insert(
-                dom.id,
-                DomainStats::new(dom.load.load_sum(), dom.load.imbal(), dom.load.delta()),
+            let mut dom_stats = DomainStats::new(
+                dom.load.load_sum(),
+                dom.pinned_load,
+                dom.load.imbal(),
+                dom.load.delta(),
             );
+            dom_stats.nr_pushed = dom.nr_pushed;
+            dom_stats.nr_pulled = dom.nr_pulled;
+            dom_stats.lb = dom.lb_decision(balanced);
+            stats.doms.insert(dom.id, dom_stats);
+        }
+        for (dom_id, (load, pinned_load)) in self.parked_doms.iter() {
+            let mut dom_stats = DomainStats::new(*load, *pinned_load, 0.0, 0.0);
+            dom_stats.lb = "parked".to_string();
+            stats.doms.insert(*dom_id, dom_stats);
         }
         stats
     }
@@ -449,7 +558,37 @@ impl LoadOrdered for NumaNode {
 }
 impl_ord_for_type!(NumaNode);
 
-pub struct LoadBalancer<'a, 'b> {
+/// Load balancing policy, see --lb-policy.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum LbPolicy {
+    /// Over-loaded nodes and domains push load to the least loaded ones.
+    Greedy,
+    /// Under-loaded domains steal load from the most loaded ones.
+    Steal,
+}
+
+/// A load balancing policy. A new one is created for each LB round with the
+/// load hierarchy in an LbContext.
+pub trait LoadBalancer {
+    /// Create the load hierarchy and, if load balancing is enabled, migrate
+    /// tasks between domains to balance it.
+    fn load_balance(&mut self) -> Result<()>;
+
+    fn get_stats(&self) -> BTreeMap<usize, NodeStats>;
+}
+
+pub fn new_load_balancer<'a, 'b: 'a>(
+    policy: LbPolicy,
+    ctx: LbContext<'a, 'b>,
+) -> Box<dyn LoadBalancer + 'a> {
+    match policy {
+        LbPolicy::Greedy => Box::new(GreedyLoadBalancer { ctx }),
+        LbPolicy::Steal => Box::new(StealLoadBalancer { ctx }),
+    }
+}
+
+/// The load hierarchy and the operations on it shared by the policies.
+pub struct LbContext<'a, 'b> {
     skel: &'a mut BpfSkel<'b>,
     dom_group: Arc<DomainGroup>,
     skip_kworkers: bool,
@@ -460,6 +599,24 @@ pub struct LoadBalancer<'a, 'b> {
 
     lb_apply_weight: bool,
     balance_load: bool,
+    parked_dom_mask: u64,
+
+    xnuma_mig_cost: f64,
+    xnuma_hysteresis: u32,
+    xnuma_push_rounds: &'a mut BTreeMap<usize, u32>,
+
+    cgrp_loads: BTreeMap<u64, CgrpLoad>,
+}
+
+/// Load of a cgroup with --cgroup-lb, see read_cgrp_loads().
+#[derive(Clone, Copy, Debug)]
+struct CgrpLoad {
+    /// cpu.weight of the cgroup.
+    weight: f64,
+    /// The cgroup's duty cycle per unit of the weighted duty cycle of its
+    /// tasks. A task's share of the cgroup's load is its weighted duty
+    /// cycle multiplied by this.
+    share: f64,
 }
 
 // Verify that the number of buckets is a factor of the maximum weight to
@@ -469,13 +626,18 @@ const_assert_eq!(
     0
 );
 
-impl<'a, 'b> LoadBalancer<'a, 'b> {
+impl<'a, 'b> LbContext<'a, 'b> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         skel: &'a mut BpfSkel<'b>,
         dom_group: Arc<DomainGroup>,
         skip_kworkers: bool,
         lb_apply_weight: bool,
         balance_load: bool,
+        parked_dom_mask: u64,
+        xnuma_mig_cost: f64,
+        xnuma_hysteresis: u32,
+        xnuma_push_rounds: &'a mut BTreeMap<usize, u32>,
     ) -> Self {
         Self {
             skel,
@@ -487,32 +649,42 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
 
             lb_apply_weight,
             balance_load,
+            parked_dom_mask,
 
-            dom_group,
-        }
-    }
+            xnuma_mig_cost,
+            xnuma_hysteresis,
+            xnuma_push_rounds,
 
-    /// Perform load balancing calculations. When load balancing is enabled,
-    /// also perform rebalances between NUMA nodes (when running on a
-    /// multi-socket host) and domains.
-    pub fn load_balance(&mut self) -> Result<()> {
-        self.create_domain_hierarchy()?;
+            dom_group,
 
-        if self.balance_load {
-            self.perform_balancing()?
+            cgrp_loads: BTreeMap::new(),
         }
-
-        Ok(())
     }
 
-    pub fn get_stats(&self) -> BTreeMap<usize, NodeStats> {
+    fn get_stats(&self) -> BTreeMap<usize, NodeStats> {
         let mut stats = BTreeMap::new();
         for node in self.nodes.iter() {
-            stats.insert(node.id, node.stats());
+            stats.insert(node.id, node.stats(self.balance_load));
         }
         stats
     }
 
+    /// Count the consecutive LB rounds each node has been over-loaded for,
+    /// see --xnuma-hysteresis.
+    fn update_xnuma_push_rounds(&mut self) {
+        for node in self.nodes.iter() {
+            let rounds = self.xnuma_push_rounds.entry(node.id).or_default();
+            *rounds = match node.load.state() {
+                BalanceState::NeedsPush => rounds.saturating_add(1),
+                _ => 0,
+            };
+        }
+    }
+
+    fn xnuma_push_ready(&self, node_id: usize) -> bool {
+        self.xnuma_push_rounds[&node_id] >= self.xnuma_hysteresis
+    }
+
     fn create_domain_hierarchy(&mut self) -> Result<()> {
         let ledger = self.calculate_load_avgs()?;
 
@@ -532,23 +704,48 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
         };
 
         let num_numa_nodes = self.dom_group.nr_nodes();
-        let numa_load_avg = total_load / num_numa_nodes as f64;
+        let active = |dom_id: usize| self.parked_dom_mask & (1 << dom_id) == 0;
 
-        let mut nodes: Vec<NumaNode> = Vec::with_capacity(num_numa_nodes);
-        for id in 0..num_numa_nodes {
-            nodes.push(NumaNode::new(id, numa_load_avg));
-        }
-
-        let dom_load_avg = total_load / dom_loads.len() as f64;
-        for (dom_id, load) in dom_loads.iter().enumerate() {
+        // Each node's share of the load is the fraction of its domains which
+        // are active, which is equal for all nodes if none is parked.
+        let mut node_doms = vec![(0usize, 0usize); num_numa_nodes];
+        for dom_id in 0..dom_loads.len() {
             let numa_id = self.dom_group.dom_numa_id(&dom_id).unwrap();
 
             if numa_id >= num_numa_nodes {
                 bail!("NUMA ID {} exceeds maximum {}", numa_id, num_numa_nodes);
             }
 
+            node_doms[numa_id].0 += active(dom_id) as usize;
+            node_doms[numa_id].1 += 1;
+        }
+        let node_share = |(nr_active, nr): (usize, usize)| match nr {
+            0 => 0.0,
+            nr => nr_active as f64 / nr as f64,
+        };
+        let share_sum: f64 = node_doms.iter().map(|v| node_share(*v)).sum();
+
+        let mut nodes: Vec<NumaNode> = Vec::with_capacity(num_numa_nodes);
+        for (id, doms) in node_doms.iter().enumerate() {
+            let numa_load_avg = if share_sum > 0.0 {
+                total_load * node_share(*doms) / share_sum
+            } else {
+                0.0
+            };
+            nodes.push(NumaNode::new(id, numa_load_avg));
+        }
+
+        let nr_active = node_doms.iter().map(|v| v.0).sum::<usize>().max(1);
+        let dom_load_avg = total_load / nr_active as f64;
+        for (dom_id, load) in dom_loads.iter().enumerate() {
+            let numa_id = self.dom_group.dom_numa_id(&dom_id).unwrap();
+            let pinned_load = self.dom_pinned_load(dom_id, *load);
             let node = &mut nodes[numa_id];
-            node.allocate_domain(dom_id, *load, dom_load_avg);
+            if active(dom_id) {
+                node.allocate_domain(dom_id, *load, dom_load_avg, pinned_load);
+            } else {
+                node.parked_doms.insert(dom_id, (*load, pinned_load));
+            }
         }
 
         for _ in 0..num_numa_nodes {
@@ -565,6 +762,7 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
 
         let mut aggregator =
             LoadAggregator::new(self.dom_group.weight(), !self.lb_apply_weight.clone());
+        let mut loads: BTreeMap<(usize, usize), f64> = BTreeMap::new();
 
         for (dom_id, dom) in self.dom_group.doms() {
             aggregator.init_domain(*dom_id);
@@ -589,13 +787,120 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
                 }
 
                 let weight = self.bucket_weight(bucket);
-                aggregator.record_dom_load(*dom_id, weight, duty_cycle)?;
+                *loads.entry((*dom_id, weight)).or_default() += duty_cycle;
             }
         }
 
+        self.read_cgrp_loads(&mut loads)?;
+        for ((dom_id, weight), duty_cycle) in loads {
+            aggregator.record_dom_load(dom_id, weight, duty_cycle)?;
+        }
+
         Ok(aggregator.calculate())
     }
 
+    /// With --cgroup-lb, the tasks in non-root cgroups are accounted per
+    /// cgroup rather than in the domain load buckets. Each such cgroup
+    /// carries its weight for the fraction of time any of its tasks is
+    /// runnable, split amongst the domains in proportion to the average
+    /// runnable weight of its tasks there. Add the cgroup loads as
+    /// (domain, weight) duty cycles to @loads and remember their shares
+    /// for populate_tasks_by_load().
+    fn read_cgrp_loads(&mut self, loads: &mut BTreeMap<(usize, usize), f64>) -> Result<()> {
+        self.cgrp_loads.clear();
+        if !self.skel.maps.rodata_data.cgroup_lb {
+            return Ok(());
+        }
+
+        let now_mono = now_monotonic();
+        let load_half_life = self.skel.maps.rodata_data.load_half_life;
+        let read = |rd: &bpf_intf::ravg_data| {
+            ravg_read(
+                rd.val,
+                rd.val_at,
+                rd.old,
+                rd.cur,
+                now_mono,
+                load_half_life,
+                RAVG_FRAC_BITS,
+            )
+        };
+
+        let mut dom_weights: BTreeMap<u64, Vec<(usize, f64)>> = BTreeMap::new();
+        let cgrp_dom_lb_data = &self.skel.maps.cgrp_dom_lb_data;
+        for key in cgrp_dom_lb_data.keys() {
+            let val = match cgrp_dom_lb_data.lookup(&key, MapFlags::ANY)? {
+                Some(v) => v,
+                None => continue,
+            };
+            let key: bpf_intf::cgrp_dom_key = read_map_tail(&key);
+            let ctx: bpf_intf::cgrp_dom_lb_ctx = read_map_tail(&val);
+            let dom_id = key.dom_id as usize;
+            let avg = read(&ctx.runnable_weight_rd);
+            if avg > 0.0 && self.dom_group.doms().contains_key(&dom_id) {
+                dom_weights.entry(key.cgid).or_default().push((dom_id, avg));
+            }
+        }
+
+        for (cgid, doms) in dom_weights {
+            let val = match self
+                .skel
+                .maps
+                .cgrp_lb_data
+                .lookup(&cgid.to_ne_bytes(), MapFlags::ANY)?
+            {
+                Some(v) => v,
+                None => continue,
+            };
+            let ctx: bpf_intf::cgrp_lb_ctx = read_map_tail(&val);
+            let weight = ctx.weight.clamp(
+                bpf_intf::consts_LB_MIN_WEIGHT,
+                bpf_intf::consts_LB_MAX_WEIGHT,
+            ) as usize;
+            let total: f64 = doms.iter().map(|(_, w)| w).sum();
+            let share = read(&ctx.active_rd).min(1.0) / total;
+
+            for (dom_id, dom_weight) in doms {
+                *loads.entry((dom_id, weight)).or_default() += dom_weight * share;
+            }
+            self.cgrp_loads.insert(
+                cgid,
+                CgrpLoad {
+                    weight: weight as f64,
+                    share,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The load of @dom_id's runnable tasks which can't run in any other
+    /// domain, capped at the domain's load.
+    fn dom_pinned_load(&self, dom_id: usize, dom_load: f64) -> f64 {
+        let now_mono = now_monotonic();
+        let load_half_life = self.skel.maps.rodata_data.load_half_life;
+        let dom_ctx = unsafe { &*self.skel.maps.bss_data.dom_ctxs[dom_id] };
+
+        let read = |rd: &types::ravg_data| {
+            ravg_read(
+                rd.val,
+                rd.val_at,
+                rd.old,
+                rd.cur,
+                now_mono,
+                load_half_life,
+                RAVG_FRAC_BITS,
+            )
+        };
+        let load = if self.lb_apply_weight {
+            read(&dom_ctx.pinned_weight_rd)
+        } else {
+            DEFAULT_WEIGHT * read(&dom_ctx.pinned_nr_rd)
+        };
+        load.min(dom_load)
+    }
+
     fn bucket_range(&self, bucket: u64) -> (f64, f64) {
         const MAX_WEIGHT: u64 = bpf_intf::consts_LB_MAX_WEIGHT as u64;
         const NUM_BUCKETS: u64 = bpf_intf::consts_LB_LOAD_BUCKETS as u64;
@@ -649,7 +954,8 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
             let taskc_p = active_tasks.tasks[(idx % MAX_TPTRS) as usize];
             let taskc = unsafe { &mut *taskc_p };
 
-            if taskc.target_dom as usize != dom.id {
+            // Tasks which can't leave the domain are accounted as pinned.
+            if taskc.target_dom as usize != dom.id || taskc.dom_mask == 1 << dom.id {
                 continue;
             }
 
@@ -664,10 +970,15 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
                 RAVG_FRAC_BITS,
             );
 
-            let weight = if self.lb_apply_weight {
-                (taskc.weight as f64).min(self.infeas_threshold)
-            } else {
-                DEFAULT_WEIGHT
+            // Tasks accounted in their cgroup carry their share of the
+            // cgroup's weight, see read_cgrp_loads().
+            let weight = match (self.cgrp_loads.get(&taskc.lb_cgid), self.lb_apply_weight) {
+                (Some(cgrp), true) => {
+                    taskc.lb_weight as f64 * cgrp.share * cgrp.weight.min(self.infeas_threshold)
+                }
+                (Some(cgrp), false) => taskc.lb_weight as f64 * cgrp.share * DEFAULT_WEIGHT,
+                (None, true) => (taskc.lb_weight as f64).min(self.infeas_threshold),
+                (None, false) => DEFAULT_WEIGHT,
             };
             load *= weight;
 
@@ -676,6 +987,7 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
                 load: OrderedFloat(load),
                 dom_mask: taskc.dom_mask,
                 preferred_dom_mask: taskc.preferred_dom_mask,
+                home_node: taskc.home_node as usize,
                 migrated: Cell::new(false),
                 is_kworker: unsafe { taskc.is_kworker.assume_init() },
             });
@@ -695,19 +1007,46 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
 
     /// Try to find a task in @push_dom to be moved into @pull_dom. If a task is
     /// found, move the task between the domains, and return the amount of load
-    /// transferred between the two.
+    /// transferred between the two. The move must reduce the imbalance by at
+    /// least @mig_cost times the task's load.
     fn try_find_move_task(
         &mut self,
         (push_dom, to_push): (&mut Domain, f64),
         (pull_dom, to_pull): (&mut Domain, f64),
         task_filter: impl Fn(&TaskInfo, u32) -> bool,
         to_xfer: f64,
+        mig_cost: f64,
     ) -> Result<Option<f64>> {
-        let to_pull = to_pull.abs();
-        let calc_new_imbal = |xfer: f64| (to_push - xfer).abs() + (to_pull - xfer).abs();
+        if push_dom.movable_load() <= 0.0f64 {
+            push_dom.push_failure = Some("load is pinned");
+            return Ok(None);
+        }
 
         self.populate_tasks_by_load(push_dom)?;
 
+        Ok(Self::move_task(
+            (push_dom, to_push),
+            (pull_dom, to_pull),
+            task_filter,
+            to_xfer,
+            mig_cost,
+            self.skip_kworkers,
+        ))
+    }
+
+    /// The part of try_find_move_task() after @push_dom's tasks have been
+    /// populated.
+    fn move_task(
+        (push_dom, to_push): (&mut Domain, f64),
+        (pull_dom, to_pull): (&mut Domain, f64),
+        task_filter: impl Fn(&TaskInfo, u32) -> bool,
+        to_xfer: f64,
+        mig_cost: f64,
+        skip_kworkers: bool,
+    ) -> Option<f64> {
+        let to_pull = to_pull.abs();
+        let calc_new_imbal = |xfer: f64| (to_push - xfer).abs() + (to_pull - xfer).abs();
+
         // We want to pick a task to transfer from push_dom to pull_dom to
         // reduce the load imbalance between the two closest to $to_xfer.
         // IOW, pick a task which has the closest load value to $to_xfer
@@ -721,7 +1060,7 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
             .into_iter()
             .filter(|task| {
                 task.dom_mask & (1 << pull_dom_id) != 0
-                    && !(self.skip_kworkers && task.is_kworker)
+                    && !(skip_kworkers && task.is_kworker)
                     && !task.migrated.get()
             })
             .collect();
@@ -743,7 +1082,8 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
         ) {
             (None, None) => {
                 std::mem::swap(&mut push_dom.tasks, &mut SortedVec::from_unsorted(tasks));
-                return Ok(None);
+                push_dom.push_failure = Some("no task can move");
+                return None;
             }
             (Some(task), None) | (None, Some(task)) => (task, calc_new_imbal(*task.load)),
             (Some(task0), Some(task1)) => {
@@ -762,18 +1102,49 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
         let old_imbal = to_push + to_pull;
         if old_imbal < new_imbal {
             std::mem::swap(&mut push_dom.tasks, &mut SortedVec::from_unsorted(tasks));
-            return Ok(None);
+            push_dom.push_failure = Some("no task reduces the imbalance");
+            return None;
         }
 
         let load = *(task.load);
+        if old_imbal < new_imbal + mig_cost * load {
+            std::mem::swap(&mut push_dom.tasks, &mut SortedVec::from_unsorted(tasks));
+            push_dom.push_failure = Some("gain below migration cost");
+            return None;
+        }
+
         let taskc_p = task.taskc_p;
         task.migrated.set(true);
         std::mem::swap(&mut push_dom.tasks, &mut SortedVec::from_unsorted(tasks));
 
         push_dom.transfer_load(load, unsafe { &mut *taskc_p }, pull_dom);
-        Ok(Some(load))
+        Some(load)
     }
+}
+
+/// Over-loaded NUMA nodes, and then domains, push tasks to the least loaded
+/// ones, favoring the largest imbalances.
+pub struct GreedyLoadBalancer<'a, 'b> {
+    ctx: LbContext<'a, 'b>,
+}
 
+impl LoadBalancer for GreedyLoadBalancer<'_, '_> {
+    fn load_balance(&mut self) -> Result<()> {
+        self.ctx.create_domain_hierarchy()?;
+
+        if self.ctx.balance_load {
+            self.perform_balancing()?
+        }
+
+        Ok(())
+    }
+
+    fn get_stats(&self) -> BTreeMap<usize, NodeStats> {
+        self.ctx.get_stats()
+    }
+}
+
+impl GreedyLoadBalancer<'_, '_> {
     fn transfer_between_nodes(
         &mut self,
         push_node: &mut NumaNode,
@@ -797,6 +1168,7 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
         let mut pushers = VecDeque::with_capacity(push_node.domains.len());
         let mut pullers = Vec::with_capacity(pull_node.domains.len());
         let mut pushed = 0f64;
+        let pull_node_id = pull_node.id;
 
         while push_node.domains.len() > 0 {
             // Push from the busiest node
@@ -812,20 +1184,33 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
                     pull_node.domains.insert(pull_dom);
                     break;
                 }
-                let mut transferred = self.try_find_move_task(
+                // Try sending tasks back home first, then tasks which prefer
+                // the pull domain, and finally any task.
+                let mut transferred = self.ctx.try_find_move_task(
                     (&mut push_dom, push_imbal),
                     (&mut pull_dom, pull_imbal),
-                    |task: &TaskInfo, pull_dom: u32| -> bool {
-                        (task.preferred_dom_mask & (1 << pull_dom)) > 0
-                    },
+                    |task: &TaskInfo, _pull_dom: u32| -> bool { task.home_node == pull_node_id },
                     xfer,
+                    0.0,
                 )?;
                 if transferred.is_none() {
-                    transferred = self.try_find_move_task(
+                    transferred = self.ctx.try_find_move_task(
+                        (&mut push_dom, push_imbal),
+                        (&mut pull_dom, pull_imbal),
+                        |task: &TaskInfo, pull_dom: u32| -> bool {
+                            (task.preferred_dom_mask & (1 << pull_dom)) > 0
+                        },
+                        xfer,
+                        self.ctx.xnuma_mig_cost,
+                    )?;
+                }
+                if transferred.is_none() {
+                    transferred = self.ctx.try_find_move_task(
                         (&mut push_dom, push_imbal),
                         (&mut pull_dom, pull_imbal),
                         |_task: &TaskInfo, _pull_dom: u32| -> bool { true },
                         xfer,
+                        self.ctx.xnuma_mig_cost,
                     )?;
                 }
 
@@ -853,7 +1238,7 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
     }
 
     fn balance_between_nodes(&mut self) -> Result<()> {
-        if self.nodes.len() < 2 {
+        if self.ctx.nodes.len() < 2 {
             return Ok(());
         }
 
@@ -892,33 +1277,43 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
         // always sending load to the least-loaded node.
         //
         // Note that we use a VecDeque for the pushers because we're iterating
-        // over self.nodes in descending-load order. Thus, when we're done
-        // iterating and we're adding the popped nodes back into self.nodes, we
-        // want to add them back in _ascending_ order so that we don't have to
-        // unnecessarily shift any already-re-added nodes to the right in the
-        // backing vector. In other words, this lets us do a true append in the
+        // over self.ctx.nodes in descending-load order. Thus, when we're
+        // done iterating and we're adding the popped nodes back into
+        // self.ctx.nodes, we want to add them back in _ascending_ order so
+        // that we don't have to unnecessarily shift any already-re-added
+        // nodes to the right in the backing vector. In other words, this lets us do a true append in the
         // SortedVec, rather than doing an insert(list.len() - 2, node). This
         // applies both to iterating over push-imbalanced nodes, and iterating
         // over push-imbalanced domains in the inner loops.
-        let mut pushers = VecDeque::with_capacity(self.nodes.len());
-        let mut pullers = Vec::with_capacity(self.nodes.len());
+        let mut pushers = VecDeque::with_capacity(self.ctx.nodes.len());
+        let mut pullers = Vec::with_capacity(self.ctx.nodes.len());
 
-        while self.nodes.len() >= 2 {
+        while self.ctx.nodes.len() >= 2 {
             // Push from the busiest node
-            let mut push_node = self.nodes.pop().unwrap();
+            let mut push_node = self.ctx.nodes.pop().unwrap();
             if push_node.load.state() != BalanceState::NeedsPush {
-                self.nodes.insert(push_node);
+                self.ctx.nodes.insert(push_node);
                 break;
             }
 
+            // Don't push across nodes on short-lived imbalances.
+            if !self.ctx.xnuma_push_ready(push_node.id) {
+                debug!(
+                    "NODE {} over-loaded for {} rounds, not pushing yet",
+                    push_node.id, self.ctx.xnuma_push_rounds[&push_node.id]
+                );
+                pushers.push_back(push_node);
+                continue;
+            }
+
             let push_cutoff = push_node.load.push_cutoff();
             let mut pushed = 0f64;
-            while self.nodes.len() > 0 && pushed < push_cutoff {
+            while self.ctx.nodes.len() > 0 && pushed < push_cutoff {
                 // To the least busy node
-                let mut pull_node = self.nodes.remove_index(0);
+                let mut pull_node = self.ctx.nodes.remove_index(0);
                 let pull_id = pull_node.id;
                 if pull_node.load.state() != BalanceState::NeedsPull {
-                    self.nodes.insert(pull_node);
+                    self.ctx.nodes.insert(pull_node);
                     break;
                 }
                 let migrated = self.transfer_between_nodes(&mut push_node, &mut pull_node)?;
@@ -936,7 +1331,7 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
                 }
             }
             while let Some(puller) = pullers.pop() {
-                self.nodes.insert(puller);
+                self.ctx.nodes.insert(puller);
             }
 
             if pushed > 0.0f64 {
@@ -946,7 +1341,7 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
         }
 
         while let Some(pusher) = pushers.pop_front() {
-            self.nodes.insert(pusher);
+            self.ctx.nodes.insert(pusher);
         }
 
         Ok(())
@@ -1001,20 +1396,22 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
                     );
                 }
                 let xfer = push_dom.xfer_between(&pull_dom);
-                let mut transferred = self.try_find_move_task(
+                let mut transferred = self.ctx.try_find_move_task(
                     (&mut push_dom, push_imbal),
                     (&mut pull_dom, pull_imbal),
                     |task: &TaskInfo, pull_dom: u32| -> bool {
                         (task.preferred_dom_mask & (1 << pull_dom)) > 0
                     },
                     xfer,
+                    0.0,
                 )?;
                 if transferred.is_none() {
-                    transferred = self.try_find_move_task(
+                    transferred = self.ctx.try_find_move_task(
                         (&mut push_dom, push_imbal),
                         (&mut pull_dom, pull_imbal),
                         |_task: &TaskInfo, _pull_dom: u32| -> bool { true },
                         xfer,
+                        0.0,
                     )?;
                 }
 
@@ -1056,7 +1453,8 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
         // higher cost function than balancing between domains inside of NUMA
         // nodes, but the mechanics are the same. Adjustments made here are
         // reflected in intra-node balancing decisions made next.
-        if self.dom_group.nr_nodes() > 1 {
+        if self.ctx.dom_group.nr_nodes() > 1 {
+            self.ctx.update_xnuma_push_rounds();
             self.balance_between_nodes()?;
         }
 
@@ -1067,12 +1465,395 @@ impl<'a, 'b> LoadBalancer<'a, 'b> {
 
         // Assume all nodes are now balanced.
 
-        let mut nodes = std::mem::take(&mut self.nodes).into_vec();
+        let mut nodes = std::mem::take(&mut self.ctx.nodes).into_vec();
         for node in nodes.iter_mut() {
             self.balance_within_node(node)?;
         }
-        std::mem::swap(&mut self.nodes, &mut SortedVec::from_unsorted(nodes));
+        std::mem::swap(&mut self.ctx.nodes, &mut SortedVec::from_unsorted(nodes));
+
+        Ok(())
+    }
+}
+
+/// Under-loaded domains, least loaded first, steal tasks from the most loaded
+/// domains which can spare them, first in their own NUMA node and then in
+/// other nodes which have been over-loaded for long enough. Compared to
+/// greedy, this spreads the load more evenly over the under-loaded domains
+/// but favors the larger imbalances less.
+pub struct StealLoadBalancer<'a, 'b> {
+    ctx: LbContext<'a, 'b>,
+}
+
+impl LoadBalancer for StealLoadBalancer<'_, '_> {
+    fn load_balance(&mut self) -> Result<()> {
+        self.ctx.create_domain_hierarchy()?;
+
+        if self.ctx.balance_load {
+            self.perform_balancing()?
+        }
 
         Ok(())
     }
+
+    fn get_stats(&self) -> BTreeMap<usize, NodeStats> {
+        self.ctx.get_stats()
+    }
+}
+
+impl StealLoadBalancer<'_, '_> {
+    fn steal(
+        &mut self,
+        thieves: &mut [Domain],
+        victims: &mut [Domain],
+        xnuma_node: Option<usize>,
+        max_load: f64,
+    ) -> Result<f64> {
+        let ctx = &mut self.ctx;
+        let xnuma_mig_cost = ctx.xnuma_mig_cost;
+        steal_tasks(
+            thieves,
+            victims,
+            xnuma_node,
+            max_load,
+            xnuma_mig_cost,
+            |victim, thief, task_filter, xfer, mig_cost| {
+                ctx.try_find_move_task(victim, thief, task_filter, xfer, mig_cost)
+            },
+        )
+    }
+
+    fn steal_within_node(&mut self, node: &mut NumaNode) -> Result<()> {
+        let (mut thieves, mut victims): (Vec<Domain>, Vec<Domain>) =
+            std::mem::take(&mut node.domains)
+                .into_vec()
+                .into_iter()
+                .partition(|dom| dom.load.state() == BalanceState::NeedsPull);
+
+        let stolen = self.steal(&mut thieves, &mut victims, None, f64::INFINITY)?;
+        if stolen > 0.0f64 {
+            debug!("NODE {} domains stole {:.06} total load", node.id, stolen);
+        }
+
+        thieves.append(&mut victims);
+        node.domains = SortedVec::from_unsorted(thieves);
+        Ok(())
+    }
+
+    fn steal_between_nodes(&mut self, nodes: &mut [NumaNode]) -> Result<()> {
+        nodes.sort();
+
+        for thief_idx in 0..nodes.len() {
+            // Steal from the busiest nodes first.
+            for victim_idx in (0..nodes.len()).rev() {
+                if victim_idx == thief_idx {
+                    continue;
+                }
+
+                let (thief_node, victim_node) = if thief_idx < victim_idx {
+                    let (left, right) = nodes.split_at_mut(victim_idx);
+                    (&mut left[thief_idx], &mut right[0])
+                } else {
+                    let (left, right) = nodes.split_at_mut(thief_idx);
+                    (&mut right[0], &mut left[victim_idx])
+                };
+
+                if thief_node.load.state() != BalanceState::NeedsPull {
+                    break;
+                }
+                if victim_node.load.state() != BalanceState::NeedsPush
+                    || !self.ctx.xnuma_push_ready(victim_node.id)
+                {
+                    continue;
+                }
+
+                let max_load = victim_node
+                    .load
+                    .push_cutoff()
+                    .min(thief_node.load.imbal().abs());
+                let mut thieves = std::mem::take(&mut thief_node.domains).into_vec();
+                let mut victims = std::mem::take(&mut victim_node.domains).into_vec();
+
+                let stolen =
+                    self.steal(&mut thieves, &mut victims, Some(thief_node.id), max_load)?;
+                if stolen > 0.0f64 {
+                    debug!(
+                        "NODE {} stole {:.06} from NODE {}",
+                        thief_node.id, stolen, victim_node.id
+                    );
+                    thief_node.update_load(stolen);
+                    victim_node.update_load(-stolen);
+                }
+
+                thief_node.domains = SortedVec::from_unsorted(thieves);
+                victim_node.domains = SortedVec::from_unsorted(victims);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn perform_balancing(&mut self) -> Result<()> {
+        let multi_node = self.ctx.dom_group.nr_nodes() > 1;
+        if multi_node {
+            self.ctx.update_xnuma_push_rounds();
+        }
+
+        let mut nodes = std::mem::take(&mut self.ctx.nodes).into_vec();
+
+        // Stealing within a node is cheaper, so do that first and only look
+        // at other nodes for what's left.
+        for node in nodes.iter_mut() {
+            if node.domains.len() >= 2 {
+                self.steal_within_node(node)?;
+            }
+        }
+
+        if multi_node {
+            self.steal_between_nodes(&mut nodes)?;
+        }
+
+        self.ctx.nodes = SortedVec::from_unsorted(nodes);
+        Ok(())
+    }
+}
+
+/// Let the under-loaded domains in @thieves steal tasks from the over-loaded
+/// domains in @victims until balanced, nothing more can be stolen or
+/// @max_load has been stolen. If @xnuma_node is set, the thieves are in that
+/// node and the victims in others, and moving a task which isn't going home
+/// costs @xnuma_mig_cost. A task is moved with @move_task, see
+/// LbContext::try_find_move_task(). Returns the load stolen.
+fn steal_tasks(
+    thieves: &mut [Domain],
+    victims: &mut [Domain],
+    xnuma_node: Option<usize>,
+    max_load: f64,
+    xnuma_mig_cost: f64,
+    mut move_task: impl FnMut(
+        (&mut Domain, f64),
+        (&mut Domain, f64),
+        &dyn Fn(&TaskInfo, u32) -> bool,
+        f64,
+        f64,
+    ) -> Result<Option<f64>>,
+) -> Result<f64> {
+    let mut stolen = 0.0f64;
+
+    thieves.sort();
+    for thief in thieves.iter_mut() {
+        let mut tried = vec![false; victims.len()];
+
+        while thief.load.state() == BalanceState::NeedsPull && stolen < max_load {
+            // From the busiest domain which it hasn't failed to steal from
+            let (idx, victim) = match victims
+                .iter_mut()
+                .enumerate()
+                .filter(|(idx, dom)| !tried[*idx] && dom.load.state() == BalanceState::NeedsPush)
+                .max_by(|(_, a), (_, b)| a.cmp(b))
+            {
+                Some(v) => v,
+                None => break,
+            };
+
+            let (push_imbal, pull_imbal) = (victim.load.imbal(), thief.load.imbal());
+            let xfer = victim.xfer_between(thief);
+            let mig_cost = match xnuma_node {
+                Some(_) => xnuma_mig_cost,
+                None => 0.0,
+            };
+
+            let mut stole = None;
+            if let Some(node_id) = xnuma_node {
+                stole = move_task(
+                    (&mut *victim, push_imbal),
+                    (&mut *thief, pull_imbal),
+                    &|task: &TaskInfo, _pull_dom: u32| -> bool { task.home_node == node_id },
+                    xfer,
+                    0.0,
+                )?;
+            }
+            if stole.is_none() {
+                stole = move_task(
+                    (&mut *victim, push_imbal),
+                    (&mut *thief, pull_imbal),
+                    &|task: &TaskInfo, pull_dom: u32| -> bool {
+                        (task.preferred_dom_mask & (1 << pull_dom)) > 0
+                    },
+                    xfer,
+                    mig_cost,
+                )?;
+            }
+            if stole.is_none() {
+                stole = move_task(
+                    (&mut *victim, push_imbal),
+                    (&mut *thief, pull_imbal),
+                    &|_task: &TaskInfo, _pull_dom: u32| -> bool { true },
+                    xfer,
+                    mig_cost,
+                )?;
+            }
+
+            match stole {
+                Some(load) => {
+                    debug!("DOM {} stole {:.06} from DOM {}", thief.id, load, victim.id);
+                    stolen += load;
+                }
+                None => tried[idx] = true,
+            }
+        }
+    }
+
+    Ok(stolen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A domain with tasks of @loads, (load, home node) pairs, which can run
+    /// anywhere. The tasks' task_ctx's are kept in @taskcs.
+    fn dom_with_tasks(
+        id: usize,
+        load_avg: f64,
+        loads: &[(f64, usize)],
+        taskcs: &mut Vec<Box<types::task_ctx>>,
+    ) -> Domain {
+        let mut dom = Domain::new(id, loads.iter().map(|(l, _)| l).sum(), load_avg, 0.0);
+        dom.queried_tasks = true;
+        for (load, home_node) in loads.iter() {
+            let mut taskc = Box::new(types::task_ctx::default());
+            taskc.pid = taskcs.len() as i32;
+            taskc.target_dom = id as u32;
+            dom.tasks.insert(TaskInfo {
+                taskc_p: &mut *taskc,
+                load: OrderedFloat(*load),
+                dom_mask: u64::MAX,
+                preferred_dom_mask: 0,
+                home_node: *home_node,
+                migrated: Cell::new(false),
+                is_kworker: false,
+            });
+            taskcs.push(taskc);
+        }
+        dom
+    }
+
+    fn steal(
+        thieves: &mut [Domain],
+        victims: &mut [Domain],
+        xnuma_node: Option<usize>,
+        max_load: f64,
+        xnuma_mig_cost: f64,
+    ) -> f64 {
+        steal_tasks(
+            thieves,
+            victims,
+            xnuma_node,
+            max_load,
+            xnuma_mig_cost,
+            |victim, thief, task_filter, xfer, mig_cost| {
+                Ok(LbContext::move_task(
+                    victim,
+                    thief,
+                    task_filter,
+                    xfer,
+                    mig_cost,
+                    false,
+                ))
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_steal_spreads_load() {
+        let mut taskcs = vec![];
+        let mut victims = vec![dom_with_tasks(0, 10.0, &[(5.0, 0); 6], &mut taskcs)];
+        let mut thieves = vec![
+            dom_with_tasks(1, 10.0, &[], &mut taskcs),
+            dom_with_tasks(2, 10.0, &[], &mut taskcs),
+        ];
+
+        let stolen = steal(&mut thieves, &mut victims, None, f64::INFINITY, 0.0);
+        assert_eq!(stolen, 20.0);
+
+        // Every thief stole two tasks and all are balanced now.
+        for dom in thieves.iter().chain(victims.iter()) {
+            assert_eq!(dom.load.load_sum(), 10.0);
+            assert_eq!(dom.load.state(), BalanceState::Balanced);
+        }
+        assert_eq!(victims[0].nr_pushed, 4);
+        for thief in thieves.iter() {
+            assert_eq!(thief.nr_pulled, 2);
+            let nr_moved = taskcs
+                .iter()
+                .filter(|taskc| taskc.target_dom as usize == thief.id)
+                .count();
+            assert_eq!(nr_moved, 2);
+        }
+    }
+
+    #[test]
+    fn test_steal_from_busiest() {
+        let mut taskcs = vec![];
+        let mut victims = vec![
+            dom_with_tasks(0, 12.0, &[(4.0, 0); 4], &mut taskcs),
+            dom_with_tasks(
+                1,
+                12.0,
+                &[(5.0, 0), (5.0, 0), (5.0, 0), (5.0, 0)],
+                &mut taskcs,
+            ),
+        ];
+        let mut thieves = vec![dom_with_tasks(2, 12.0, &[], &mut taskcs)];
+
+        // Only one task can be stolen with @max_load and it comes from the
+        // busiest victim.
+        let stolen = steal(&mut thieves, &mut victims, None, 5.0, 0.0);
+        assert_eq!(stolen, 5.0);
+        assert_eq!(victims[0].nr_pushed, 0);
+        assert_eq!(victims[1].nr_pushed, 1);
+        assert_eq!(thieves[0].load.load_sum(), 5.0);
+        assert_eq!(thieves[0].load.state(), BalanceState::NeedsPull);
+    }
+
+    #[test]
+    fn test_steal_xnuma() {
+        // The tasks whose home is the thief's node go first, even if
+        // another task would be a better fit.
+        let mut taskcs = vec![];
+        let mut victims = vec![dom_with_tasks(
+            0,
+            10.0,
+            &[(5.0, 0), (5.0, 0), (5.0, 0), (2.0, 1)],
+            &mut taskcs,
+        )];
+        let mut thieves = vec![dom_with_tasks(1, 10.0, &[(3.0, 1)], &mut taskcs)];
+
+        let stolen = steal(&mut thieves, &mut victims, Some(1), 2.0, f64::INFINITY);
+        assert_eq!(stolen, 2.0);
+        assert_eq!(taskcs[3].target_dom, 1);
+        assert!(taskcs[..3].iter().all(|taskc| taskc.target_dom == 0));
+
+        // Moving the other tasks away from their home is too expensive.
+        let stolen = steal(
+            &mut thieves,
+            &mut victims,
+            Some(1),
+            f64::INFINITY,
+            f64::INFINITY,
+        );
+        assert_eq!(stolen, 0.0);
+        assert_eq!(victims[0].push_failure, Some("gain below migration cost"));
+
+        // But not within the node.
+        let stolen = steal(
+            &mut thieves,
+            &mut victims,
+            None,
+            f64::INFINITY,
+            f64::INFINITY,
+        );
+        assert_eq!(stolen, 5.0);
+    }
 }
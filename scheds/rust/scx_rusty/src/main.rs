@@ -7,17 +7,28 @@ pub use bpf_skel::*;
 pub mod bpf_intf;
 
 mod domain;
+use domain::DomLevel;
+use domain::DomSpec;
 use domain::DomainGroup;
 
 pub mod tuner;
 use tuner::Tuner;
 
 pub mod load_balance;
-use load_balance::LoadBalancer;
+use load_balance::new_load_balancer;
+use load_balance::now_monotonic;
+use load_balance::LbContext;
+use load_balance::LbPolicy;
+
+mod config;
+use config::Config;
+use config::ConfigWatcher;
 
 mod stats;
 use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::mem::MaybeUninit;
+use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -28,6 +39,11 @@ use std::time::UNIX_EPOCH;
 
 use stats::ClusterStats;
 use stats::NodeStats;
+use stats::StatsReq;
+use stats::StatsRes;
+use stats::TaskStats;
+use stats::Tunables;
+use stats::TunablesUpdate;
 
 #[macro_use]
 extern crate static_assertions;
@@ -41,11 +57,15 @@ use clap::Parser;
 use crossbeam::channel::RecvTimeoutError;
 use libbpf_rs::MapCore as _;
 use libbpf_rs::OpenObject;
+use libbpf_rs::ProgramInput;
 use log::info;
+use log::warn;
 use scx_stats::prelude::*;
 use scx_utils::build_id;
 use scx_utils::compat;
 use scx_utils::init_libbpf_logging;
+use scx_utils::intf_layout_check;
+use scx_utils::ravg::ravg_read;
 use scx_utils::scx_enums;
 use scx_utils::scx_ops_attach;
 use scx_utils::scx_ops_load;
@@ -91,6 +111,7 @@ const MAX_CPUS: usize = bpf_intf::consts_MAX_CPUS as usize;
 /// processing power and at similar distances from each other. This
 /// limitation will be removed in the future.
 #[derive(Debug, Parser)]
+#[clap(args_override_self = true)]
 struct Opts {
     /// Scheduling slice duration for under-utilized hosts, in microseconds.
     #[clap(short = 'u', long, default_value = "20000")]
@@ -113,8 +134,16 @@ struct Opts {
     #[clap(short = 'l', long, default_value = "1.0")]
     load_half_life: f64,
 
+    /// Build a domain for each group of CPUs at this topology level: llc
+    /// (or ccx), cache for the CPUs sharing the cache at --cache-level, die
+    /// or node. Smaller domains keep tasks closer to their caches while
+    /// larger ones balance load with fewer migrations.
+    #[clap(long, value_enum, default_value = "llc")]
+    dom_level: DomLevel,
+
     /// Build domains according to how CPUs are grouped at this cache level
-    /// as determined by /sys/devices/system/cpu/cpuX/cache/indexI/id.
+    /// as determined by /sys/devices/system/cpu/cpuX/cache/indexI/id, e.g.
+    /// 2 to split a large L3 into L2 clusters. Used with --dom-level cache.
     #[clap(short = 'c', long, default_value = "3")]
     cache_level: u32,
 
@@ -123,9 +152,14 @@ struct Opts {
     /// --cpumasks 0xff_00ff --cpumasks 0xff00 will create two domains, with
     /// the corresponding CPUs belonging to each domain. Each CPU must
     /// belong to precisely one domain.
-    #[clap(short = 'C', long, num_args = 1.., conflicts_with = "cache_level")]
+    #[clap(short = 'C', long, num_args = 1.., conflicts_with_all = ["cache_level", "dom_level"])]
     cpumasks: Vec<String>,
 
+    /// Same as --cpumasks but each domain is specified as a CPU list, e.g.
+    /// --dom-cpus 0-7,16-23 --dom-cpus 8-15,24-31.
+    #[clap(long, num_args = 1.., conflicts_with_all = ["cache_level", "dom_level", "cpumasks"])]
+    dom_cpus: Vec<String>,
+
     /// When non-zero, enable greedy task stealing. When a domain is idle, a cpu
     /// will attempt to steal tasks from another domain as follows:
     ///
@@ -147,6 +181,18 @@ struct Opts {
     #[clap(long, default_value = "0")]
     greedy_threshold_x_numa: u32,
 
+    /// Limit the number of tasks the CPUs of a domain may steal from other
+    /// domains on dispatch to this many per --greedy-steal-window-us, so
+    /// that a single hot domain can't keep stealing from its neighbors. 0
+    /// for unlimited.
+    #[clap(long, default_value = "0")]
+    greedy_steal_budget: u64,
+
+    /// The window the steal budget of --greedy-steal-budget applies to, in
+    /// microseconds.
+    #[clap(long, default_value = "10000")]
+    greedy_steal_window_us: u64,
+
     /// Disable load balancing. Unless disabled, userspace will periodically calculate
     /// the load factor of each domain and instruct BPF which processes to move.
     #[clap(long, action = clap::ArgAction::SetTrue)]
@@ -166,6 +212,38 @@ struct Opts {
     #[clap(short = 'f', long, action = clap::ArgAction::SetTrue)]
     fifo_sched: bool,
 
+    /// Compute virtual deadlines EEVDF-style from the slices tasks request
+    /// with sched_setattr(2) (sched_attr.sched_runtime), which replaced
+    /// latency nice upstream, instead of the interactivity heuristics. Tasks
+    /// which request a shorter slice than the base slice are picked earlier
+    /// from their domain and run for the requested slice. Only tasks with
+    /// the SCHED_NORMAL or SCHED_BATCH policy can request slices.
+    #[clap(long, action = clap::ArgAction::SetTrue, conflicts_with = "fifo_sched")]
+    eevdf_dl: bool,
+
+    /// Detect interactive tasks, i.e. tasks which run for short periods at
+    /// a time and frequently block or wake up other tasks, and boost them.
+    /// Interactive tasks get shorter slices and are pushed to idle CPUs
+    /// outside their domain when their own domain is busy.
+    #[clap(long, action = clap::ArgAction::SetTrue, conflicts_with = "fifo_sched")]
+    interactive: bool,
+
+    /// Scheduling slice for interactive tasks in microseconds. Capped by the
+    /// current base slice.
+    #[clap(long, default_value = "1000")]
+    interactive_slice_us: u64,
+
+    /// Tasks which on average run longer than this many microseconds after
+    /// waking up aren't considered interactive.
+    #[clap(long, default_value = "1000")]
+    interactive_runtime_us: u64,
+
+    /// Tasks which block or wake up other tasks less often than this many
+    /// times per second aren't considered interactive. Rounded up to a
+    /// multiple of 10.
+    #[clap(long, default_value = "100")]
+    interactive_min_freq: u64,
+
     /// Idle CPUs with utilization lower than this will get remote tasks
     /// directly pushed onto them. 0 disables, 100 always enables.
     #[clap(short = 'D', long, default_value = "90.0")]
@@ -185,6 +263,56 @@ struct Opts {
     #[clap(short = 'r', long, action = clap::ArgAction::SetTrue)]
     direct_greedy_numa: bool,
 
+    /// When there's no wholly idle core in a task's domain, look for one in
+    /// the other domains of its NUMA node (or all nodes with
+    /// --direct-greedy-numa) before settling for a CPU whose SMT sibling is
+    /// busy.
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    prefer_idle_cores: bool,
+
+    /// Reserve one SMT sibling of each core for latency critical tasks,
+    /// i.e. tasks classified as interactive (see --interactive) and tasks
+    /// with negative nice values. Other tasks are never placed on or
+    /// dispatched from the reserved siblings. This trades throughput for
+    /// lower latencies and less sibling interference for the latency
+    /// critical tasks. No-op without SMT.
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    reserve_sibling: bool,
+
+    /// The cost of migrating a task to another NUMA node relative to its
+    /// load. The load balancer moves a task across nodes only if doing so
+    /// reduces the imbalance by at least this times the task's load. Tasks
+    /// moving back to the node they were running on recently are exempt.
+    /// 0 disables. Values above 2 block all cross-node migrations but those.
+    #[clap(long, default_value = "0.5")]
+    xnuma_mig_cost: f64,
+
+    /// The number of consecutive load balancing rounds a NUMA node must be
+    /// over-loaded for before load is pushed from it to other nodes.
+    #[clap(long, default_value = "2")]
+    xnuma_hysteresis: u32,
+
+    /// Load balancing policy. greedy: over-loaded NUMA nodes and domains push
+    /// load to the least loaded ones. steal: under-loaded domains steal load
+    /// from the most loaded ones, which spreads the load more evenly over the
+    /// under-loaded domains.
+    #[clap(long, value_enum, default_value = "greedy")]
+    lb_policy: LbPolicy,
+
+    /// Park domains to save power while the CPU utilization is lower than
+    /// this percentage. The load is consolidated onto the remaining active
+    /// domains and the CPUs of the parked domains are left idle. Domains are
+    /// parked and unparked one at a time. 0 disables.
+    #[clap(long, default_value = "0.0")]
+    park_under: f64,
+
+    /// Unpark a domain when the CPU utilization of the active domains goes
+    /// over this percentage. A domain is parked only if the utilization
+    /// would stay under this afterwards. Only used with --park-under, which
+    /// it must be above.
+    #[clap(long, default_value = "75.0")]
+    unpark_over: f64,
+
     /// If specified, only tasks which have their scheduling policy set to
     /// SCHED_EXT using sched_setscheduler(2) are switched. Otherwise, all
     /// tasks are switched.
@@ -196,6 +324,14 @@ struct Opts {
     #[clap(long, action = clap::ArgAction::SetTrue)]
     mempolicy_affinity: bool,
 
+    /// Account load per cgroup when load balancing. The weight of each
+    /// cgroup is split amongst its runnable tasks so that a cgroup with many
+    /// runnable threads doesn't carry more load than another cgroup of the
+    /// same weight. Only the innermost cgroup of each task is considered and
+    /// at most 4096 cgroups are tracked.
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    cgroup_lb: bool,
+
     /// Enable stats monitoring with the specified interval.
     #[clap(long)]
     stats: Option<f64>,
@@ -205,6 +341,36 @@ struct Opts {
     #[clap(long)]
     monitor: Option<f64>,
 
+    /// Change the tunables of the running scheduler and print the results.
+    /// The scheduler is not launched. Each argument is KEY=VALUE where KEY
+    /// is one of slice_us_underutil, slice_us_overutil, interval,
+    /// tune_interval, greedy_threshold, greedy_threshold_x_numa,
+    /// direct_greedy_under, kick_greedy_under, greedy_steal_budget,
+    /// park_under and unpark_over.
+    /// Without arguments, the current tunables are printed. Changing them
+    /// requires root or the scheduler's UID and the changes aren't
+    /// persistent.
+    #[clap(long, num_args = 0.., value_name = "KEY=VALUE")]
+    tunables: Option<Vec<String>>,
+
+    /// Print the running scheduler's view of the task with this PID: its
+    /// domain, deadline and vtime, runtime averages, load, domain migration
+    /// history and allowed CPUs. The scheduler is not launched.
+    #[clap(long, value_name = "PID")]
+    inspect_pid: Option<u32>,
+
+    /// Read options from this TOML file. The keys are the long option names,
+    /// e.g. slice_us_underutil = 10000, and a [domains.ID] table can
+    /// override direct_greedy_under, kick_greedy_under, greedy_steal_budget
+    /// and park (whether the domain can be parked) for a domain. Options on
+    /// the command line take precedence except for the ones which take
+    /// multiple values, which are combined. The file is watched and the
+    /// tunables, see --tunables, and the per-domain overrides are re-applied
+    /// when it changes. Changes to other options take effect when the
+    /// scheduler restarts.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
     /// Exit debug dump buffer length. 0 indicates default.
     #[clap(long, default_value = "0")]
     exit_dump_len: u32,
@@ -227,6 +393,67 @@ struct Opts {
     /// prioritize energy efficiency. When in doubt, use 0 or 1024.
     #[clap(long, default_value = "0")]
     perf: u32,
+
+    /// Raise the cpuperf target of a domain's CPUs to --perf-boost while
+    /// the domain's utilization is over this percentage and most of the
+    /// tasks running on it are latency critical, i.e. interactive (see
+    /// --interactive) or with negative nice values. This helps when the
+    /// cpufreq governor, e.g. schedutil, ramps up too slowly for bursty
+    /// workloads. The boost is dropped when the domain gets parked. 0
+    /// disables.
+    #[clap(long, default_value = "0")]
+    perf_boost_over: f64,
+
+    /// The cpuperf target of the domains boosted with --perf-boost-over, in
+    /// [0, 1024].
+    #[clap(long, default_value = "1024")]
+    perf_boost: u32,
+}
+
+impl Opts {
+    /// The options which can be changed at runtime, in the KEY=VALUE form
+    /// accepted by TunablesUpdate.
+    fn tunables(&self) -> BTreeMap<String, String> {
+        [
+            ("slice_us_underutil", self.slice_us_underutil.to_string()),
+            ("slice_us_overutil", self.slice_us_overutil.to_string()),
+            ("interval", self.interval.to_string()),
+            ("tune_interval", self.tune_interval.to_string()),
+            ("greedy_threshold", self.greedy_threshold.to_string()),
+            (
+                "greedy_threshold_x_numa",
+                self.greedy_threshold_x_numa.to_string(),
+            ),
+            ("direct_greedy_under", self.direct_greedy_under.to_string()),
+            ("kick_greedy_under", self.kick_greedy_under.to_string()),
+            ("greedy_steal_budget", self.greedy_steal_budget.to_string()),
+            ("park_under", self.park_under.to_string()),
+            ("unpark_over", self.unpark_over.to_string()),
+        ]
+        .into_iter()
+        .map(|(key, val)| (key.to_string(), val))
+        .collect()
+    }
+}
+
+/// Parse the command line with the options from `config` inserted in front
+/// so that the command line takes precedence.
+fn parse_opts_with(config: &Config) -> Result<Opts> {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let at = args.len().min(1);
+    args.splice(at..at, config.args()?.into_iter().map(OsString::from));
+    Ok(Opts::try_parse_from(args)?)
+}
+
+/// Load the config file if --config is specified.
+fn apply_config(opts: Opts) -> Result<(Opts, Option<Config>)> {
+    match opts.config.as_ref() {
+        Some(path) => {
+            let config = Config::load(path)?;
+            Ok((parse_opts_with(&config)?, Some(config)))
+        }
+        None => Ok((opts, None)),
+    }
 }
 
 fn read_cpu_busy_and_total(reader: &procfs::ProcReader) -> Result<(u64, u64)> {
@@ -337,6 +564,11 @@ struct Scheduler<'a> {
     tune_interval: Duration,
     balance_load: bool,
     balanced_kworkers: bool,
+    xnuma_mig_cost: f64,
+    xnuma_hysteresis: u32,
+    xnuma_push_rounds: BTreeMap<usize, u32>,
+    lb_policy: LbPolicy,
+    greedy_steal_budget: u64,
 
     dom_group: Arc<DomainGroup>,
 
@@ -344,14 +576,22 @@ struct Scheduler<'a> {
 
     lb_at: SystemTime,
     lb_stats: BTreeMap<usize, NodeStats>,
+    dom_greedy_prev: BTreeMap<usize, [u64; 4]>,
     time_used: Duration,
 
     tuner: Tuner,
-    stats_server: StatsServer<StatsCtx, (StatsCtx, ClusterStats)>,
+    stats_server: StatsServer<StatsReq, StatsRes>,
+
+    config: Option<Config>,
+    config_watcher: Option<ConfigWatcher>,
 }
 
 impl<'a> Scheduler<'a> {
-    fn init(opts: &Opts, open_object: &'a mut MaybeUninit<OpenObject>) -> Result<Self> {
+    fn init(
+        opts: &Opts,
+        config: Option<Config>,
+        open_object: &'a mut MaybeUninit<OpenObject>,
+    ) -> Result<Self> {
         // Open the BPF prog first for verification.
         let mut skel_builder = BpfSkelBuilder::default();
         skel_builder.obj_builder.debug(opts.verbose > 0);
@@ -361,9 +601,22 @@ impl<'a> Scheduler<'a> {
             build_id::full_version(env!("CARGO_PKG_VERSION"))
         );
         let mut skel = scx_ops_open!(skel_builder, open_object, rusty).unwrap();
+        intf_layout_check!(skel, bpf_intf)?;
 
         // Initialize skel according to @opts.
-        let domains = Arc::new(DomainGroup::new(&Topology::new()?, &opts.cpumasks)?);
+        let topo = Topology::new()?;
+        let mut dom_masks = vec![];
+        for mask in opts.cpumasks.iter() {
+            dom_masks.push(Cpumask::from_str(mask)?);
+        }
+        for cpus in opts.dom_cpus.iter() {
+            dom_masks.push(Cpumask::parse(cpus)?);
+        }
+        let dom_spec = match dom_masks.is_empty() {
+            true => DomSpec::Level(opts.dom_level, opts.cache_level as usize),
+            false => DomSpec::Masks(dom_masks),
+        };
+        let domains = Arc::new(DomainGroup::new(&topo, &dom_spec)?);
 
         if *NR_CPU_IDS > MAX_CPUS {
             bail!(
@@ -381,9 +634,19 @@ impl<'a> Scheduler<'a> {
             );
         }
 
+        TunablesUpdate::check_park(opts.park_under, opts.unpark_over)?;
+
         skel.maps.bss_data.slice_ns = scx_enums.SCX_SLICE_DFL;
 
         skel.maps.rodata_data.nr_nodes = domains.nr_nodes() as u32;
+        skel.maps.rodata_data.smt_enabled = topo.smt_enabled;
+        skel.maps.rodata_data.prefer_idle_cores = opts.prefer_idle_cores;
+        skel.maps.rodata_data.reserve_sibling = opts.reserve_sibling;
+        // NR_CPU_IDS is checked against MAX_CPUS above, so only absent CPUs
+        // are cut off.
+        for (cpu, sib) in topo.sibling_cpus().iter().enumerate().take(MAX_CPUS) {
+            skel.maps.rodata_data.__sibling_cpu[cpu] = *sib;
+        }
         skel.maps.rodata_data.nr_doms = domains.nr_doms() as u32;
         skel.maps.rodata_data.nr_cpu_ids = *NR_CPU_IDS as u32;
 
@@ -409,17 +672,14 @@ impl<'a> Scheduler<'a> {
                 numa_mask = numa_mask.or(&dom_mask);
             }
 
-            let raw_numa_slice = numa_mask.as_raw_slice();
-            let node_cpumask_slice = &mut skel.maps.rodata_data.numa_cpumasks[numa];
-            let (left, _) = node_cpumask_slice.split_at_mut(raw_numa_slice.len());
-            left.clone_from_slice(raw_numa_slice);
+            numa_mask
+                .write_to_bpf::<MAX_CPUS, _>(&mut skel.maps.rodata_data.numa_cpumasks[numa])?;
             info!("NODE[{:02}] mask= {}", numa, numa_mask);
 
             for dom in node_domains.iter() {
-                let raw_dom_slice = dom.mask_slice();
-                let dom_cpumask_slice = &mut skel.maps.rodata_data.dom_cpumasks[dom.id()];
-                let (left, _) = dom_cpumask_slice.split_at_mut(raw_dom_slice.len());
-                left.clone_from_slice(raw_dom_slice);
+                dom.mask().write_to_bpf::<MAX_CPUS, _>(
+                    &mut skel.maps.rodata_data.dom_cpumasks[dom.id()],
+                )?;
                 skel.maps.rodata_data.dom_numa_id_map[dom.id()] =
                     numa.try_into().expect("NUMA ID could not fit into 32 bits");
 
@@ -430,20 +690,33 @@ impl<'a> Scheduler<'a> {
         if opts.partial {
             skel.struct_ops.rusty_mut().flags |= *compat::SCX_OPS_SWITCH_PARTIAL;
         }
+        if opts.cgroup_lb {
+            skel.struct_ops.rusty_mut().flags |= *compat::SCX_OPS_HAS_CGROUP_WEIGHT;
+        }
         skel.struct_ops.rusty_mut().exit_dump_len = opts.exit_dump_len;
 
         skel.maps.rodata_data.load_half_life = (opts.load_half_life * 1000000000.0) as u32;
         skel.maps.rodata_data.kthreads_local = opts.kthreads_local;
         skel.maps.rodata_data.fifo_sched = opts.fifo_sched;
-        skel.maps.rodata_data.greedy_threshold = opts.greedy_threshold;
-        skel.maps.rodata_data.greedy_threshold_x_numa = opts.greedy_threshold_x_numa;
+        skel.maps.rodata_data.eevdf_dl = opts.eevdf_dl;
+        skel.maps.rodata_data.interactive = opts.interactive;
+        skel.maps.rodata_data.interactive_slice_ns = opts.interactive_slice_us * 1000;
+        skel.maps.rodata_data.interactive_runtime_ns = opts.interactive_runtime_us * 1000;
+        // BPF tracks the frequencies per 100ms. Round up so that the
+        // threshold isn't lowered.
+        skel.maps.rodata_data.interactive_min_freq = opts.interactive_min_freq.div_ceil(10).max(1);
         skel.maps.rodata_data.direct_greedy_numa = opts.direct_greedy_numa;
+        skel.maps.rodata_data.steal_window_ns = opts.greedy_steal_window_us * 1000;
         skel.maps.rodata_data.mempolicy_affinity = opts.mempolicy_affinity;
+        skel.maps.rodata_data.cgroup_lb = opts.cgroup_lb;
         skel.maps.rodata_data.debug = opts.verbose as u32;
         skel.maps.rodata_data.rusty_perf_mode = opts.perf;
+        skel.maps.rodata_data.perf_boost = opts.perf_boost;
 
         // Attach.
         let mut skel = scx_ops_load!(skel, rusty, uei)?;
+        skel.maps.bss_data.greedy_threshold = opts.greedy_threshold;
+        skel.maps.bss_data.greedy_threshold_x_numa = opts.greedy_threshold_x_numa;
         let struct_ops = Some(scx_ops_attach!(skel, rusty)?);
         let stats_server = StatsServer::new(stats::server_data()).launch()?;
 
@@ -458,7 +731,26 @@ impl<'a> Scheduler<'a> {
         // Other stuff.
         let proc_reader = procfs::ProcReader::new();
 
-        Ok(Self {
+        let mut tuner = Tuner::new(
+            domains.clone(),
+            opts.direct_greedy_under,
+            opts.kick_greedy_under,
+            opts.slice_us_underutil * 1000,
+            opts.slice_us_overutil * 1000,
+            opts.park_under,
+            opts.unpark_over,
+        )?;
+        tuner.perf_boost_over = opts.perf_boost_over / 100.0;
+
+        let config_watcher = match config.as_ref() {
+            Some(config) => {
+                tuner.dom_overrides = config.domains.clone();
+                Some(ConfigWatcher::new(&config.path)?)
+            }
+            None => None,
+        };
+
+        let mut sched = Self {
             skel,
             struct_ops, // should be held to keep it attached
 
@@ -466,23 +758,28 @@ impl<'a> Scheduler<'a> {
             tune_interval: Duration::from_secs_f64(opts.tune_interval),
             balance_load: !opts.no_load_balance,
             balanced_kworkers: opts.balanced_kworkers,
+            xnuma_mig_cost: opts.xnuma_mig_cost,
+            xnuma_hysteresis: opts.xnuma_hysteresis,
+            xnuma_push_rounds: BTreeMap::new(),
+            lb_policy: opts.lb_policy,
+            greedy_steal_budget: opts.greedy_steal_budget,
 
             dom_group: domains.clone(),
             proc_reader,
 
             lb_at: SystemTime::now(),
             lb_stats: BTreeMap::new(),
+            dom_greedy_prev: BTreeMap::new(),
             time_used: Duration::default(),
 
-            tuner: Tuner::new(
-                domains,
-                opts.direct_greedy_under,
-                opts.kick_greedy_under,
-                opts.slice_us_underutil * 1000,
-                opts.slice_us_overutil * 1000,
-            )?,
+            tuner,
             stats_server,
-        })
+
+            config,
+            config_watcher,
+        };
+        sched.update_steal_budgets();
+        Ok(sched)
     }
 
     fn cluster_stats(&self, sc: &StatsCtx, node_stats: BTreeMap<usize, NodeStats>) -> ClusterStats {
@@ -497,7 +794,10 @@ impl<'a> Scheduler<'a> {
             + stat(bpf_intf::stat_idx_RUSTY_STAT_DIRECT_GREEDY_FAR)
             + stat(bpf_intf::stat_idx_RUSTY_STAT_DSQ_DISPATCH)
             + stat(bpf_intf::stat_idx_RUSTY_STAT_GREEDY_LOCAL)
-            + stat(bpf_intf::stat_idx_RUSTY_STAT_GREEDY_XNUMA);
+            + stat(bpf_intf::stat_idx_RUSTY_STAT_GREEDY_XNUMA)
+            + stat(bpf_intf::stat_idx_RUSTY_STAT_INTERACTIVE_IDLE)
+            + stat(bpf_intf::stat_idx_RUSTY_STAT_IDLE_CORE)
+            + stat(bpf_intf::stat_idx_RUSTY_STAT_PINNED_DISPATCH);
         let stat_pct = |idx| stat(idx) as f64 / total as f64 * 100.0;
 
         let cpu_busy = if sc.cpu_total != 0 {
@@ -506,6 +806,35 @@ impl<'a> Scheduler<'a> {
             0.0
         };
 
+        // Steals and throttled steals of the active domains since the last
+        // load balancing.
+        let doms = || {
+            node_stats
+                .values()
+                .flat_map(|node| node.doms.iter())
+                .filter(|(dom_id, _)| self.tuner.parked_dom_mask & (1 << **dom_id) == 0)
+                .map(|(_, dom)| dom)
+        };
+        let steals: Vec<u64> = doms().map(|dom| dom.steals).collect();
+        let steal_imbal = match steals.iter().sum::<u64>() {
+            0 => 0.0,
+            sum => {
+                let avg = sum as f64 / steals.len() as f64;
+                *steals.iter().max().unwrap() as f64 / avg
+            }
+        };
+        let nr_steal_throttled = doms().map(|dom| dom.steal_throttled).sum();
+
+        let load = node_stats.values().map(|node| node.load).sum::<f64>();
+        let pinned_load = node_stats
+            .values()
+            .map(|node| node.pinned_load)
+            .sum::<f64>();
+        let unbalanceable = match load {
+            v if v > 0.0 => pinned_load / v * 100.0,
+            _ => 0.0,
+        };
+
         ClusterStats {
             at_us: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
@@ -524,8 +853,14 @@ impl<'a> Scheduler<'a> {
             slice_us: self.tuner.slice_ns / 1000,
 
             cpu_busy,
-            load: node_stats.iter().map(|(_k, v)| v.load).sum::<f64>(),
+            load,
+            unbalanceable,
             nr_migrations: sc.bpf_stats[bpf_intf::stat_idx_RUSTY_STAT_LOAD_BALANCE as usize],
+            nr_park_migrations: stat(bpf_intf::stat_idx_RUSTY_STAT_PARK_MIGRATE),
+            nr_cookie_idle: stat(bpf_intf::stat_idx_RUSTY_STAT_COOKIE_IDLE),
+            nr_parked: self.tuner.parked_dom_mask.count_ones() as u64,
+            nr_perf_boosted: self.tuner.perf_boost_mask.weight() as u64,
+            parked_dom_mask: self.tuner.parked_dom_mask,
 
             task_get_err: sc.bpf_stats[bpf_intf::stat_idx_RUSTY_STAT_TASK_GET_ERR as usize],
             time_used: sc.time_used.as_secs_f64(),
@@ -539,12 +874,18 @@ impl<'a> Scheduler<'a> {
             greedy: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_DIRECT_GREEDY),
             greedy_far: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_DIRECT_GREEDY_FAR),
             dsq_dispatch: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_DSQ_DISPATCH),
+            pinned_dispatch: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_PINNED_DISPATCH),
             greedy_local: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_GREEDY_LOCAL),
             greedy_xnuma: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_GREEDY_XNUMA),
+            interactive_idle: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_INTERACTIVE_IDLE),
+            idle_core: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_IDLE_CORE),
             kick_greedy: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_KICK_GREEDY),
             repatriate: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_REPATRIATE),
             dl_clamp: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_DL_CLAMP),
             dl_preset: stat_pct(bpf_intf::stat_idx_RUSTY_STAT_DL_PRESET),
+            nr_interactive: self.skel.maps.bss_data.nr_interactive,
+            steal_imbal,
+            nr_steal_throttled,
 
             direct_greedy_cpus: self.tuner.direct_greedy_mask.as_raw_slice().to_owned(),
             kick_greedy_cpus: self.tuner.kick_greedy_mask.as_raw_slice().to_owned(),
@@ -553,22 +894,277 @@ impl<'a> Scheduler<'a> {
         }
     }
 
+    fn tunables(&self) -> Tunables {
+        let bss_data = &self.skel.maps.bss_data;
+        Tunables {
+            slice_us_underutil: self.tuner.underutil_slice_ns / 1000,
+            slice_us_overutil: self.tuner.overutil_slice_ns / 1000,
+            interval: self.sched_interval.as_secs_f64(),
+            tune_interval: self.tune_interval.as_secs_f64(),
+            greedy_threshold: bss_data.greedy_threshold,
+            greedy_threshold_x_numa: bss_data.greedy_threshold_x_numa,
+            direct_greedy_under: self.tuner.direct_greedy_under * 100.0,
+            kick_greedy_under: self.tuner.kick_greedy_under * 100.0,
+            greedy_steal_budget: self.greedy_steal_budget,
+            park_under: self.tuner.park_under * 100.0,
+            unpark_over: self.tuner.unpark_over * 100.0,
+        }
+    }
+
+    fn inspect_task(&mut self, pid: u32) -> Result<TaskStats> {
+        self.skel.maps.bss_data.task_inspect.pid = pid as i32;
+        let out = self
+            .skel
+            .progs
+            .inspect_task
+            .test_run(ProgramInput::default())?;
+        match -(out.return_value as i32) {
+            0 => {}
+            libc::ESRCH => {
+                Err(anyhow!("no task with pid {}", pid).context(StatsErrno(libc::ESRCH)))?
+            }
+            libc::ENOENT => Err(anyhow!("pid {} isn't scheduled by scx_rusty", pid)
+                .context(StatsErrno(libc::ENOENT)))?,
+            errno => bail!("inspect_task failed for pid {} ({})", pid, errno),
+        }
+
+        let ti = &self.skel.maps.bss_data.task_inspect;
+        let taskc = &ti.taskc;
+        let dom = taskc.target_dom as usize;
+        let min_vruntime = self
+            .dom_group
+            .doms()
+            .get(&dom)
+            .and_then(|d| d.ctx())
+            .map(|domc| domc.min_vruntime)
+            .unwrap_or(0);
+        let rel_us = |vtime: u64| (vtime as i64 - min_vruntime as i64) as f64 / 1000.0;
+
+        let rd = &taskc.dcyc_rd;
+        let duty_cycle = ravg_read(
+            rd.val,
+            rd.val_at,
+            rd.old,
+            rd.cur,
+            now_monotonic(),
+            self.skel.maps.rodata_data.load_half_life,
+            bpf_intf::ravg_consts_RAVG_FRAC_BITS,
+        );
+
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid))
+            .map(|v| v.trim_end().to_string())
+            .unwrap_or_default();
+
+        Ok(TaskStats {
+            pid,
+            comm,
+            dom: taskc.target_dom,
+            node: self.dom_group.dom_numa_id(&dom).unwrap_or(0) as u32,
+            home_node: taskc.home_node,
+            dom_mask: taskc.dom_mask,
+            preferred_dom_mask: taskc.preferred_dom_mask,
+            nr_cpus_allowed: ti.nr_cpus_allowed,
+            all_cpus: unsafe { taskc.all_cpus.assume_init() } as u32,
+            interactive: unsafe { taskc.is_interactive.assume_init() } as u32,
+            kworker: unsafe { taskc.is_kworker.assume_init() } as u32,
+            runnable: unsafe { taskc.runnable.assume_init() } as u32,
+            weight: taskc.weight,
+            lb_weight: taskc.lb_weight,
+            lb_cgid: taskc.lb_cgid,
+            deadline_us: rel_us(taskc.deadline),
+            vtime_us: rel_us(ti.dsq_vtime),
+            slice_us: ti.slice as f64 / 1000.0,
+            avg_runtime_us: taskc.avg_runtime as f64 / 1000.0,
+            blocked_freq: taskc.blocked_freq,
+            waker_freq: taskc.waker_freq,
+            duty_cycle,
+            load: duty_cycle * taskc.lb_weight as f64,
+            nr_dom_migrations: taskc.nr_dom_migrations,
+            prev_dom: taskc.prev_dom,
+            last_dom_migration_ago: match taskc.last_dom_migration_at {
+                0 => 0.0,
+                at => now_monotonic().saturating_sub(at) as f64 / 1_000_000_000.0,
+            },
+            nr_foreign_runs: taskc.nr_foreign_runs,
+            cpus: ti.cpumask.to_vec(),
+        })
+    }
+
+    fn update_tunables(&mut self, update: &TunablesUpdate) -> Result<()> {
+        // Only one of the park thresholds may be changing.
+        TunablesUpdate::check_park(
+            update.park_under.unwrap_or(self.tuner.park_under * 100.0),
+            update.unpark_over.unwrap_or(self.tuner.unpark_over * 100.0),
+        )?;
+
+        if let Some(v) = update.slice_us_underutil {
+            self.tuner.underutil_slice_ns = v * 1000;
+        }
+        if let Some(v) = update.slice_us_overutil {
+            self.tuner.overutil_slice_ns = v * 1000;
+        }
+        if let Some(v) = update.interval {
+            self.sched_interval = Duration::from_secs_f64(v);
+        }
+        if let Some(v) = update.tune_interval {
+            self.tune_interval = Duration::from_secs_f64(v);
+        }
+        if let Some(v) = update.greedy_threshold {
+            self.skel.maps.bss_data.greedy_threshold = v;
+        }
+        if let Some(v) = update.greedy_threshold_x_numa {
+            self.skel.maps.bss_data.greedy_threshold_x_numa = v;
+        }
+        if let Some(v) = update.direct_greedy_under {
+            self.tuner.direct_greedy_under = v / 100.0;
+        }
+        if let Some(v) = update.kick_greedy_under {
+            self.tuner.kick_greedy_under = v / 100.0;
+        }
+        if let Some(v) = update.greedy_steal_budget {
+            self.greedy_steal_budget = v;
+            self.update_steal_budgets();
+        }
+        if let Some(v) = update.park_under {
+            self.tuner.park_under = v / 100.0;
+        }
+        if let Some(v) = update.unpark_over {
+            self.tuner.unpark_over = v / 100.0;
+        }
+        Ok(())
+    }
+
+    /// Tell BPF the steal budget of each domain, --greedy-steal-budget
+    /// unless overridden for the domain in the config file.
+    fn update_steal_budgets(&mut self) {
+        for (dom_id, dom) in self.dom_group.doms().iter() {
+            let budget = self
+                .tuner
+                .dom_overrides
+                .get(dom_id)
+                .and_then(|ovr| ovr.greedy_steal_budget)
+                .unwrap_or(self.greedy_steal_budget);
+            if let Some(domc) = dom.ctx() {
+                domc.steal_budget = budget;
+            }
+        }
+    }
+
+    fn config_changed(&self) -> bool {
+        match self.config_watcher.as_ref().map(|w| w.changed()) {
+            Some(Ok(changed)) => changed,
+            Some(Err(e)) => {
+                warn!("Failed to watch the config file: {:#}", e);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Re-read the config file and apply the changes which don't need a
+    /// restart.
+    fn reload_config(&mut self) -> Result<()> {
+        let old = match self.config.as_ref() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let new = Config::load(&old.path)?;
+        let tunables = parse_opts_with(&new)?.tunables();
+
+        let mut args = BTreeMap::new();
+        for key in old.changed_opts(&new).into_iter() {
+            match tunables.get(&key) {
+                Some(val) => {
+                    args.insert(key, val.clone());
+                }
+                None => warn!("Config: {} changed, takes effect on restart", key),
+            }
+        }
+        let update = TunablesUpdate::from_args(&args)?;
+        self.update_tunables(&update)?;
+
+        info!("Reloaded config {:?}", &new.path);
+        self.tuner.dom_overrides = new.domains.clone();
+        self.update_steal_budgets();
+        self.config = Some(new);
+        Ok(())
+    }
+
     fn lb_step(&mut self) -> Result<()> {
-        let mut lb = LoadBalancer::new(
+        let ctx = LbContext::new(
             &mut self.skel,
             self.dom_group.clone(),
             self.balanced_kworkers,
             self.tuner.fully_utilized,
             self.balance_load,
+            self.tuner.parked_dom_mask,
+            self.xnuma_mig_cost,
+            self.xnuma_hysteresis,
+            &mut self.xnuma_push_rounds,
         );
+        let mut lb = new_load_balancer(self.lb_policy, ctx);
 
         lb.load_balance()?;
 
         self.lb_at = SystemTime::now();
         self.lb_stats = lb.get_stats();
+        self.update_dom_stats();
         Ok(())
     }
 
+    /// Fill in the per-domain stats which come from BPF. The greedy counts
+    /// are since the previous load balancing.
+    fn update_dom_stats(&mut self) {
+        for node in self.lb_stats.values_mut() {
+            for (dom_id, stats) in node.doms.iter_mut() {
+                let domc = match self.dom_group.doms().get(dom_id).and_then(|d| d.ctx()) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let greedy = [
+                    domc.nr_greedy_in,
+                    domc.nr_greedy_out,
+                    domc.nr_steals,
+                    domc.nr_steal_throttled,
+                ];
+                let prev = self
+                    .dom_greedy_prev
+                    .insert(*dom_id, greedy)
+                    .unwrap_or(greedy);
+                let delta = |i: usize| sub_or_zero(&greedy[i], &prev[i]);
+
+                stats.greedy_in = delta(0);
+                stats.greedy_out = delta(1);
+                stats.steals = delta(2);
+                stats.steal_throttled = delta(3);
+            }
+        }
+    }
+
+    /// The per-node stats of the last load balancing with the current DSQ
+    /// depths of the domains.
+    fn node_stats(&mut self) -> BTreeMap<usize, NodeStats> {
+        let mut node_stats = self.lb_stats.clone();
+        if let Err(e) = self
+            .skel
+            .progs
+            .sample_nr_queued
+            .test_run(ProgramInput::default())
+        {
+            warn!("Failed to sample the domain queue depths ({:?})", e);
+            return node_stats;
+        }
+
+        for node in node_stats.values_mut() {
+            for (dom_id, stats) in node.doms.iter_mut() {
+                if let Some(domc) = self.dom_group.doms().get(dom_id).and_then(|d| d.ctx()) {
+                    stats.nr_queued = domc.nr_queued;
+                }
+            }
+        }
+        node_stats
+    }
+
     fn run(&mut self, shutdown: Arc<AtomicBool>) -> Result<UserExitInfo> {
         let (res_ch, req_ch) = self.stats_server.channels();
         let now = Instant::now();
@@ -599,15 +1195,36 @@ impl<'a> Scheduler<'a> {
             self.time_used += Instant::now().duration_since(now);
 
             match req_ch.recv_deadline(next_sched_at.min(next_tune_at)) {
-                Ok(prev_sc) => {
+                Ok(StatsReq::Cluster(prev_sc)) => {
                     let cur_sc = StatsCtx::new(&self.skel, &self.proc_reader, self.time_used)?;
                     let delta_sc = cur_sc.delta(&prev_sc);
-                    let cstats = self.cluster_stats(&delta_sc, self.lb_stats.clone());
-                    res_ch.send((cur_sc, cstats))?;
+                    let node_stats = self.node_stats();
+                    let cstats = self.cluster_stats(&delta_sc, node_stats);
+                    res_ch.send(StatsRes::Cluster(cur_sc, cstats))?;
+                }
+                Ok(StatsReq::Tunables(update)) => {
+                    let res = self.update_tunables(&update).map(|_| self.tunables());
+                    // Shortened intervals should take effect right away.
+                    let now = Instant::now();
+                    next_tune_at = next_tune_at.min(now + self.tune_interval);
+                    next_sched_at = next_sched_at.min(now + self.sched_interval);
+                    res_ch.send(StatsRes::Tunables(res))?;
+                }
+                Ok(StatsReq::Task(pid)) => {
+                    res_ch.send(StatsRes::Task(self.inspect_task(pid)))?;
                 }
                 Err(RecvTimeoutError::Timeout) => {}
                 Err(e) => Err(e)?,
             }
+
+            if self.config_changed() {
+                if let Err(e) = self.reload_config() {
+                    warn!("Failed to reload config: {:#}", e);
+                }
+                let now = Instant::now();
+                next_tune_at = next_tune_at.min(now + self.tune_interval);
+                next_sched_at = next_sched_at.min(now + self.sched_interval);
+            }
         }
 
         let _ = self.struct_ops.take();
@@ -624,7 +1241,7 @@ impl Drop for Scheduler<'_> {
 }
 
 fn main() -> Result<()> {
-    let opts = Opts::parse();
+    let (mut opts, mut config) = apply_config(Opts::parse())?;
 
     if opts.version {
         println!(
@@ -665,6 +1282,14 @@ fn main() -> Result<()> {
     })
     .context("Error setting Ctrl-C handler")?;
 
+    if let Some(settings) = opts.tunables.as_ref() {
+        return stats::set_tunables(settings);
+    }
+
+    if let Some(pid) = opts.inspect_pid {
+        return stats::inspect_task(pid);
+    }
+
     if let Some(intv) = opts.monitor.or(opts.stats) {
         let shutdown_copy = shutdown.clone();
         let jh = std::thread::spawn(move || {
@@ -678,10 +1303,12 @@ fn main() -> Result<()> {
 
     let mut open_object = MaybeUninit::uninit();
     loop {
-        let mut sched = Scheduler::init(&opts, &mut open_object)?;
+        let mut sched = Scheduler::init(&opts, config.take(), &mut open_object)?;
         if !sched.run(shutdown.clone())?.should_restart() {
             break;
         }
+        // Pick up the config changes which needed a restart.
+        (opts, config) = apply_config(opts)?;
     }
     Ok(())
 }
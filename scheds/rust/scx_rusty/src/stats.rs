@@ -6,6 +6,8 @@ use std::sync::Arc;
 use std::time::Duration;
 use std::time::UNIX_EPOCH;
 
+use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Result;
 use chrono::DateTime;
 use chrono::Local;
@@ -33,29 +35,61 @@ fn signed(x: f64) -> String {
 pub struct DomainStats {
     #[stat(desc = "sum of weight * duty_cycle for all tasks")]
     pub load: f64,
+    #[stat(desc = "load of the tasks which can't run in any other domain")]
+    pub pinned_load: f64,
     #[stat(desc = "load imbalance from average")]
     pub imbal: f64,
     #[stat(desc = "load migrated for load balancing")]
     pub delta: f64,
+    #[stat(desc = "# of tasks queued on the domain")]
+    pub nr_queued: u64,
+    #[stat(desc = "# of foreign tasks executed greedily on the domain's CPUs")]
+    pub greedy_in: u64,
+    #[stat(desc = "# of the domain's tasks executed greedily on foreign CPUs")]
+    pub greedy_out: u64,
+    #[stat(desc = "# of foreign tasks stolen by the domain's CPUs on dispatch")]
+    pub steals: u64,
+    #[stat(desc = "# of steals skipped as the domain used up its steal budget")]
+    pub steal_throttled: u64,
+    #[stat(desc = "# of tasks migrated out for load balancing")]
+    pub nr_pushed: u64,
+    #[stat(desc = "# of tasks migrated in for load balancing")]
+    pub nr_pulled: u64,
+    #[stat(desc = "what the last load balancing did with the domain and why")]
+    pub lb: String,
 }
 
 impl DomainStats {
-    pub fn new(load: f64, imbal: f64, delta: f64) -> Self {
+    pub fn new(load: f64, pinned_load: f64, imbal: f64, delta: f64) -> Self {
         Self {
             load: normalize_load_metric(load),
+            pinned_load: normalize_load_metric(pinned_load),
             imbal: normalize_load_metric(imbal),
             delta: normalize_load_metric(delta),
+            ..Default::default()
         }
     }
 
     pub fn format<W: Write>(&self, w: &mut W, id: usize) -> Result<()> {
         writeln!(
             w,
-            "   DOM[{:02}] load={:6.2} imbal={} delta={}",
+            "   DOM[{:02}] load={:6.2} pinned={:6.2} imbal={} delta={} queued={}",
             id,
             self.load,
+            self.pinned_load,
             signed(self.imbal),
-            signed(self.delta)
+            signed(self.delta),
+            self.nr_queued,
+        )?;
+        writeln!(
+            w,
+            "           greedy_in={} greedy_out={} steal={} throttled={}",
+            self.greedy_in, self.greedy_out, self.steals, self.steal_throttled,
+        )?;
+        writeln!(
+            w,
+            "           push={} pull={} lb=\"{}\"",
+            self.nr_pushed, self.nr_pulled, self.lb,
         )?;
         Ok(())
     }
@@ -67,6 +101,8 @@ impl DomainStats {
 pub struct NodeStats {
     #[stat(desc = "sum of weight * duty_cycle for all tasks")]
     pub load: f64,
+    #[stat(desc = "load of the tasks which can't leave their domains")]
+    pub pinned_load: f64,
     #[stat(desc = "load imbalance from average")]
     pub imbal: f64,
     #[stat(desc = "load migrated for load balancing")]
@@ -76,9 +112,16 @@ pub struct NodeStats {
 }
 
 impl NodeStats {
-    pub fn new(load: f64, imbal: f64, delta: f64, doms: BTreeMap<usize, DomainStats>) -> Self {
+    pub fn new(
+        load: f64,
+        pinned_load: f64,
+        imbal: f64,
+        delta: f64,
+        doms: BTreeMap<usize, DomainStats>,
+    ) -> Self {
         Self {
             load: normalize_load_metric(load),
+            pinned_load: normalize_load_metric(pinned_load),
             imbal: normalize_load_metric(imbal),
             delta: normalize_load_metric(delta),
             doms,
@@ -88,9 +131,10 @@ impl NodeStats {
     pub fn format<W: Write>(&self, w: &mut W, id: usize) -> Result<()> {
         writeln!(
             w,
-            "  NODE[{:02}] load={:6.2} imbal={} delta={}",
+            "  NODE[{:02}] load={:6.2} pinned={:6.2} imbal={} delta={}",
             id,
             self.load,
+            self.pinned_load,
             signed(self.imbal),
             signed(self.delta)
         )?;
@@ -115,8 +159,24 @@ pub struct ClusterStats {
     pub cpu_busy: f64,
     #[stat(desc = "sum of weight * duty_cycle for all tasks")]
     pub load: f64,
+    #[stat(desc = "% of the load which can't be balanced as the tasks can't leave their domains")]
+    pub unbalanceable: f64,
     #[stat(desc = "# of migrations from load balancing")]
     pub nr_migrations: u64,
+    #[stat(desc = "# of migrations out of parked domains")]
+    pub nr_park_migrations: u64,
+    #[stat(desc = "# of parked domains")]
+    pub nr_parked: u64,
+    #[stat(_om_skip)]
+    pub parked_dom_mask: u64,
+    #[stat(desc = "# of CPUs with boosted cpuperf target (--perf-boost-over)")]
+    pub nr_perf_boosted: u64,
+    #[stat(desc = "# of times a CPU was left idle because of a core-sched cookie mismatch")]
+    pub nr_cookie_idle: u64,
+    #[stat(desc = "steals by the domain which stole the most over the average, 1 is even")]
+    pub steal_imbal: f64,
+    #[stat(desc = "# of steals skipped as domains used up their steal budgets")]
+    pub nr_steal_throttled: u64,
 
     #[stat(desc = "# of BPF task get errors")]
     pub task_get_err: u64,
@@ -141,10 +201,16 @@ pub struct ClusterStats {
     pub greedy_far: f64,
     #[stat(desc = "% scheduled from local domain")]
     pub dsq_dispatch: f64,
+    #[stat(desc = "% scheduled from the CPU's DSQ of tasks restricted to it")]
+    pub pinned_dispatch: f64,
     #[stat(desc = "% scheduled from foreign domain")]
     pub greedy_local: f64,
     #[stat(desc = "% scheduled from foreign node")]
     pub greedy_xnuma: f64,
+    #[stat(desc = "% interactive directly dispatched to idle CPU in foreign domain")]
+    pub interactive_idle: f64,
+    #[stat(desc = "% directly dispatched to idle core in foreign domain (--prefer-idle-cores)")]
+    pub idle_core: f64,
     #[stat(desc = "% foreign domain CPU kicked on enqueue")]
     pub kick_greedy: f64,
     #[stat(desc = "% repatriated to local domain on enqueue")]
@@ -153,6 +219,8 @@ pub struct ClusterStats {
     pub dl_clamp: f64,
     #[stat(desc = "% accumulated vtime budget used as-is")]
     pub dl_preset: f64,
+    #[stat(desc = "# of tasks classified as interactive")]
+    pub nr_interactive: u64,
 
     #[stat(_om_skip)]
     pub direct_greedy_cpus: Vec<u64>,
@@ -167,9 +235,10 @@ impl ClusterStats {
     pub fn format<W: Write>(&self, w: &mut W) -> Result<()> {
         writeln!(
             w,
-            "cpu={:7.2} load={:8.2} mig={} task_err={} time_used={:4.1}ms",
+            "cpu={:7.2} load={:8.2} unbal={:5.2} mig={} task_err={} time_used={:4.1}ms",
             self.cpu_busy,
             self.load,
+            self.unbalanceable,
             self.nr_migrations,
             self.task_get_err,
             self.time_used * 1000.0,
@@ -187,14 +256,14 @@ impl ClusterStats {
 
         writeln!(
             w,
-            "dir={:5.2} dir_greedy={:5.2} dir_greedy_far={:5.2}",
-            self.direct, self.greedy, self.greedy_far,
+            "dir={:5.2} dir_greedy={:5.2} dir_greedy_far={:5.2} idle_core={:5.2}",
+            self.direct, self.greedy, self.greedy_far, self.idle_core,
         )?;
 
         writeln!(
             w,
-            "dsq={:5.2} greedy_local={:5.2} greedy_xnuma={:5.2}",
-            self.dsq_dispatch, self.greedy_local, self.greedy_xnuma,
+            "dsq={:5.2} pin_dsq={:5.2} greedy_local={:5.2} greedy_xnuma={:5.2}",
+            self.dsq_dispatch, self.pinned_dispatch, self.greedy_local, self.greedy_xnuma,
         )?;
 
         writeln!(
@@ -202,11 +271,34 @@ impl ClusterStats {
             "kick_greedy={:5.2} rep={:5.2}",
             self.kick_greedy, self.repatriate
         )?;
+        writeln!(
+            w,
+            "steal_imbal={:5.2} steal_throttled={}",
+            self.steal_imbal, self.nr_steal_throttled
+        )?;
+        if self.nr_perf_boosted > 0 {
+            writeln!(w, "perf_boosted={}", self.nr_perf_boosted)?;
+        }
+        if self.nr_cookie_idle > 0 {
+            writeln!(w, "cookie_idle={}", self.nr_cookie_idle)?;
+        }
+        if self.nr_parked > 0 || self.nr_park_migrations > 0 {
+            writeln!(
+                w,
+                "parked={} parked_doms={:x} park_mig={}",
+                self.nr_parked, self.parked_dom_mask, self.nr_park_migrations,
+            )?;
+        }
         writeln!(
             w,
             "dl_clamp={:5.2} dl_preset={:5.2}",
             self.dl_clamp, self.dl_preset,
         )?;
+        writeln!(
+            w,
+            "interactive={} interactive_idle={:5.2}",
+            self.nr_interactive, self.interactive_idle,
+        )?;
 
         writeln!(w, "slice={}us", self.slice_us)?;
         writeln!(
@@ -231,30 +323,407 @@ impl ClusterStats {
     }
 }
 
-pub fn server_data() -> StatsServerData<StatsCtx, (StatsCtx, ClusterStats)> {
-    let open: Box<dyn StatsOpener<StatsCtx, (StatsCtx, ClusterStats)>> =
-        Box::new(move |(req_ch, res_ch)| {
-            // Send one bogus request on open to establish prev_sc.
-            let mut prev_sc = StatsCtx::blank();
-            req_ch.send(prev_sc.clone())?;
-            let (cur_sc, _) = res_ch.recv()?;
-            prev_sc = cur_sc;
-
-            let read: Box<dyn StatsReader<StatsCtx, (StatsCtx, ClusterStats)>> =
-                Box::new(move |_args, (req_ch, res_ch)| {
-                    req_ch.send(prev_sc.clone())?;
-                    let (cur_sc, cluster_stats) = res_ch.recv()?;
-                    prev_sc = cur_sc;
-                    cluster_stats.to_json()
-                });
-            Ok(read)
+#[stat_doc]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Stats)]
+pub struct Tunables {
+    #[stat(desc = "scheduling slice for under-utilized hosts in usecs")]
+    pub slice_us_underutil: u64,
+    #[stat(desc = "scheduling slice for over-utilized hosts in usecs")]
+    pub slice_us_overutil: u64,
+    #[stat(desc = "load balance interval in secs")]
+    pub interval: f64,
+    #[stat(desc = "tuning interval in secs")]
+    pub tune_interval: f64,
+    #[stat(desc = "min # of queued tasks to steal from a domain on the same node, 0 disables")]
+    pub greedy_threshold: u32,
+    #[stat(desc = "min # of queued tasks to steal from a domain on another node, 0 disables")]
+    pub greedy_threshold_x_numa: u32,
+    #[stat(desc = "util % under which idle CPUs get remote tasks directly pushed")]
+    pub direct_greedy_under: f64,
+    #[stat(desc = "util % under which idle CPUs may get kicked to steal remote tasks")]
+    pub kick_greedy_under: f64,
+    #[stat(desc = "max # of tasks a domain may steal per steal window, 0 for unlimited")]
+    pub greedy_steal_budget: u64,
+    #[stat(desc = "util % under which domains get parked, 0 disables")]
+    pub park_under: f64,
+    #[stat(desc = "util % of the active domains over which a domain gets unparked")]
+    pub unpark_over: f64,
+}
+
+impl Tunables {
+    pub fn format<W: Write>(&self, w: &mut W) -> Result<()> {
+        writeln!(
+            w,
+            "slice_us_underutil={} slice_us_overutil={}",
+            self.slice_us_underutil, self.slice_us_overutil
+        )?;
+        writeln!(
+            w,
+            "interval={} tune_interval={}",
+            self.interval, self.tune_interval
+        )?;
+        writeln!(
+            w,
+            "greedy_threshold={} greedy_threshold_x_numa={}",
+            self.greedy_threshold, self.greedy_threshold_x_numa
+        )?;
+        writeln!(
+            w,
+            "direct_greedy_under={} kick_greedy_under={}",
+            self.direct_greedy_under, self.kick_greedy_under
+        )?;
+        writeln!(w, "greedy_steal_budget={}", self.greedy_steal_budget)?;
+        writeln!(
+            w,
+            "park_under={} unpark_over={}",
+            self.park_under, self.unpark_over
+        )?;
+        Ok(())
+    }
+}
+
+#[stat_doc]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Stats)]
+pub struct TaskStats {
+    #[stat(desc = "task PID")]
+    pub pid: u32,
+    #[stat(desc = "task name")]
+    pub comm: String,
+    #[stat(desc = "domain the task is assigned to")]
+    pub dom: u32,
+    #[stat(desc = "NUMA node of the domain")]
+    pub node: u32,
+    #[stat(desc = "NUMA node the task has mostly been running on")]
+    pub home_node: u32,
+    #[stat(desc = "domains the task can run on")]
+    pub dom_mask: u64,
+    #[stat(desc = "domains preferred by the task's memory policy")]
+    pub preferred_dom_mask: u64,
+    #[stat(desc = "# of CPUs the task is allowed on")]
+    pub nr_cpus_allowed: u32,
+    #[stat(desc = "allowed on all CPUs and eligible for direct greedy dispatch")]
+    pub all_cpus: u32,
+    #[stat(desc = "classified as interactive")]
+    pub interactive: u32,
+    #[stat(desc = "workqueue worker thread")]
+    pub kworker: u32,
+    #[stat(desc = "currently runnable")]
+    pub runnable: u32,
+    #[stat(desc = "scheduling weight")]
+    pub weight: u32,
+    #[stat(desc = "weight the task's load is accounted under")]
+    pub lb_weight: u32,
+    #[stat(desc = "ID of the cgroup the task's load is accounted in, 0 if none")]
+    pub lb_cgid: u64,
+    #[stat(desc = "deadline relative to the domain's min vruntime in usecs")]
+    pub deadline_us: f64,
+    #[stat(desc = "vtime relative to the domain's min vruntime in usecs")]
+    pub vtime_us: f64,
+    #[stat(desc = "remaining slice in usecs")]
+    pub slice_us: f64,
+    #[stat(desc = "average runtime per activation in usecs")]
+    pub avg_runtime_us: f64,
+    #[stat(desc = "frequency with which the task blocks (per 100ms)")]
+    pub blocked_freq: u64,
+    #[stat(desc = "frequency with which the task wakes other tasks (per 100ms)")]
+    pub waker_freq: u64,
+    #[stat(desc = "duty cycle, the fraction of time the task has been runnable")]
+    pub duty_cycle: f64,
+    #[stat(desc = "load, lb_weight * duty_cycle, without the share of lb_cgid applied")]
+    pub load: f64,
+    #[stat(desc = "# of domain migrations")]
+    pub nr_dom_migrations: u64,
+    #[stat(desc = "domain the task last migrated from")]
+    pub prev_dom: u32,
+    #[stat(desc = "secs since the last domain migration")]
+    pub last_dom_migration_ago: f64,
+    #[stat(desc = "# of times the task ran on a CPU outside its domain")]
+    pub nr_foreign_runs: u64,
+
+    #[stat(_om_skip)]
+    pub cpus: Vec<u64>,
+}
+
+impl TaskStats {
+    pub fn format<W: Write>(&self, w: &mut W) -> Result<()> {
+        writeln!(
+            w,
+            "pid={} comm={} dom={} node={} home_node={}",
+            self.pid, self.comm, self.dom, self.node, self.home_node
+        )?;
+        writeln!(
+            w,
+            "dom_mask={:x} preferred_dom_mask={:x} nr_cpus={} cpus={:x}",
+            self.dom_mask,
+            self.preferred_dom_mask,
+            self.nr_cpus_allowed,
+            Cpumask::from_vec(self.cpus.clone())
+        )?;
+        writeln!(
+            w,
+            "all_cpus={} interactive={} kworker={} runnable={}",
+            self.all_cpus, self.interactive, self.kworker, self.runnable
+        )?;
+        writeln!(
+            w,
+            "weight={} lb_weight={} lb_cgid={} dcycle={:5.2} load={:8.2}",
+            self.weight, self.lb_weight, self.lb_cgid, self.duty_cycle, self.load
+        )?;
+        writeln!(
+            w,
+            "deadline={}us vtime={}us slice={:.1}us",
+            signed(self.deadline_us),
+            signed(self.vtime_us),
+            self.slice_us
+        )?;
+        writeln!(
+            w,
+            "avg_runtime={:.1}us blocked_freq={} waker_freq={}",
+            self.avg_runtime_us, self.blocked_freq, self.waker_freq
+        )?;
+        write!(w, "dom_mig={}", self.nr_dom_migrations)?;
+        if self.nr_dom_migrations > 0 {
+            write!(
+                w,
+                " prev_dom={} last_mig={:.2}s ago",
+                self.prev_dom, self.last_dom_migration_ago
+            )?;
+        }
+        writeln!(w, " foreign_runs={}", self.nr_foreign_runs)?;
+        Ok(())
+    }
+}
+
+/// Tunables to change, parsed from the arguments of a "tunables_set" request
+/// or from the config file.
+#[derive(Clone, Debug, Default)]
+pub struct TunablesUpdate {
+    pub slice_us_underutil: Option<u64>,
+    pub slice_us_overutil: Option<u64>,
+    pub interval: Option<f64>,
+    pub tune_interval: Option<f64>,
+    pub greedy_threshold: Option<u32>,
+    pub greedy_threshold_x_numa: Option<u32>,
+    pub direct_greedy_under: Option<f64>,
+    pub kick_greedy_under: Option<f64>,
+    pub greedy_steal_budget: Option<u64>,
+    pub park_under: Option<f64>,
+    pub unpark_over: Option<f64>,
+}
+
+impl TunablesUpdate {
+    // Upper bound of the intervals in secs.
+    const MAX_INTERVAL: f64 = 3600.0;
+
+    pub fn from_args(args: &BTreeMap<String, String>) -> Result<Self> {
+        fn parse<T: std::str::FromStr>(key: &str, val: &str) -> Result<T> {
+            val.parse::<T>()
+                .map_err(|_| anyhow!("invalid value {:?} for {}", val, key))
+        }
+        fn interval(key: &str, val: &str) -> Result<f64> {
+            match parse::<f64>(key, val)? {
+                v if v > 0.0 && v <= TunablesUpdate::MAX_INTERVAL => Ok(v),
+                _ => bail!("{} must be in (0, {}]", key, TunablesUpdate::MAX_INTERVAL),
+            }
+        }
+        fn pct(key: &str, val: &str) -> Result<f64> {
+            match parse::<f64>(key, val)? {
+                v if (0.0..=100.0).contains(&v) => Ok(v),
+                _ => bail!("{} must be in [0, 100]", key),
+            }
+        }
+
+        let mut update = Self::default();
+        for (key, val) in args.iter() {
+            let key = key.as_str();
+            match key {
+                "slice_us_underutil" | "slice_us_overutil" => {
+                    let slice_us = match parse::<u64>(key, val)? {
+                        0 => bail!("{} must be positive", key),
+                        v if v.checked_mul(1000).is_none() => bail!("{} is too large", key),
+                        v => v,
+                    };
+                    match key {
+                        "slice_us_underutil" => update.slice_us_underutil = Some(slice_us),
+                        _ => update.slice_us_overutil = Some(slice_us),
+                    }
+                }
+                "interval" => update.interval = Some(interval(key, val)?),
+                "tune_interval" => update.tune_interval = Some(interval(key, val)?),
+                "greedy_threshold" => update.greedy_threshold = Some(parse(key, val)?),
+                "greedy_threshold_x_numa" => {
+                    update.greedy_threshold_x_numa = Some(parse(key, val)?)
+                }
+                "direct_greedy_under" => update.direct_greedy_under = Some(pct(key, val)?),
+                "kick_greedy_under" => update.kick_greedy_under = Some(pct(key, val)?),
+                "greedy_steal_budget" => update.greedy_steal_budget = Some(parse(key, val)?),
+                "park_under" => update.park_under = Some(pct(key, val)?),
+                "unpark_over" => update.unpark_over = Some(pct(key, val)?),
+                _ => bail!("unknown tunable {:?}", key),
+            }
+        }
+        if let (Some(park_under), Some(unpark_over)) = (update.park_under, update.unpark_over) {
+            Self::check_park(park_under, unpark_over)?;
+        }
+        Ok(update)
+    }
+
+    /// Domains would be parked and unparked back and forth if the
+    /// utilization could be both under park_under and over unpark_over.
+    pub fn check_park(park_under: f64, unpark_over: f64) -> Result<()> {
+        if park_under > 0.0 && park_under >= unpark_over {
+            bail!(
+                "park_under ({}) must be below unpark_over ({})",
+                park_under,
+                unpark_over
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum StatsReq {
+    Cluster(StatsCtx),
+    Tunables(TunablesUpdate),
+    Task(u32),
+}
+
+#[derive(Debug)]
+pub enum StatsRes {
+    Cluster(StatsCtx, ClusterStats),
+    Tunables(Result<Tunables>),
+    Task(Result<TaskStats>),
+}
+
+pub fn server_data() -> StatsServerData<StatsReq, StatsRes> {
+    let open: Box<dyn StatsOpener<StatsReq, StatsRes>> = Box::new(move |(req_ch, res_ch)| {
+        // Send one bogus request on open to establish prev_sc.
+        let mut prev_sc = StatsCtx::blank();
+        req_ch.send(StatsReq::Cluster(prev_sc.clone()))?;
+        match res_ch.recv()? {
+            StatsRes::Cluster(cur_sc, _) => prev_sc = cur_sc,
+            res => bail!("invalid response: {:?}", res),
+        }
+
+        let read: Box<dyn StatsReader<StatsReq, StatsRes>> =
+            Box::new(move |_args, (req_ch, res_ch)| {
+                req_ch.send(StatsReq::Cluster(prev_sc.clone()))?;
+                match res_ch.recv()? {
+                    StatsRes::Cluster(cur_sc, cluster_stats) => {
+                        prev_sc = cur_sc;
+                        cluster_stats.to_json()
+                    }
+                    res => bail!("invalid response: {:?}", res),
+                }
+            });
+        Ok(read)
+    });
+
+    // Reports the current tunables.
+    let tunables_open: Box<dyn StatsOpener<StatsReq, StatsRes>> = Box::new(move |_| {
+        let read: Box<dyn StatsReader<StatsReq, StatsRes>> =
+            Box::new(move |_args, (req_ch, res_ch)| {
+                req_ch.send(StatsReq::Tunables(TunablesUpdate::default()))?;
+                match res_ch.recv()? {
+                    StatsRes::Tunables(tunables) => tunables?.to_json(),
+                    res => bail!("invalid response: {:?}", res),
+                }
+            });
+        Ok(read)
+    });
+
+    // Applies the changes requested through the arguments, e.g.
+    // "slice_us_underutil=10000", and reports the resulting tunables.
+    // Changes take effect without restarting the scheduler and thus keep
+    // the load statistics of the domains. As a control request, it's only
+    // allowed for root and the scheduler's own UID.
+    let tunables_set: Box<dyn StatsReaderSend<StatsReq, StatsRes>> =
+        Box::new(move |args, (req_ch, res_ch)| {
+            let update =
+                TunablesUpdate::from_args(args).map_err(|e| e.context(StatsErrno(libc::EINVAL)))?;
+            req_ch.send(StatsReq::Tunables(update))?;
+            match res_ch.recv()? {
+                StatsRes::Tunables(tunables) => tunables
+                    .map_err(|e| e.context(StatsErrno(libc::EINVAL)))?
+                    .to_json(),
+                res => bail!("invalid response: {:?}", res),
+            }
         });
 
+    // Reports rusty's view of the task specified by the "pid" argument.
+    let task_open: Box<dyn StatsOpener<StatsReq, StatsRes>> = Box::new(move |_| {
+        let read: Box<dyn StatsReader<StatsReq, StatsRes>> =
+            Box::new(move |args, (req_ch, res_ch)| {
+                let pid = args
+                    .get("pid")
+                    .and_then(|v| v.parse::<u32>().ok())
+                    .ok_or_else(|| {
+                        anyhow!("missing or invalid pid").context(StatsErrno(libc::EINVAL))
+                    })?;
+                req_ch.send(StatsReq::Task(pid))?;
+                match res_ch.recv()? {
+                    StatsRes::Task(task) => task?.to_json(),
+                    res => bail!("invalid response: {:?}", res),
+                }
+            });
+        Ok(read)
+    });
+
     StatsServerData::new()
         .add_meta(DomainStats::meta())
         .add_meta(NodeStats::meta())
         .add_meta(ClusterStats::meta())
         .add_ops("top", StatsOps { open, close: None })
+        .add_meta(Tunables::meta())
+        .add_ops(
+            "tunables",
+            StatsOps {
+                open: tunables_open,
+                close: None,
+            },
+        )
+        .add_control("tunables_set", tunables_set)
+        .add_meta(TaskStats::meta())
+        .add_ops(
+            "task",
+            StatsOps {
+                open: task_open,
+                close: None,
+            },
+        )
+}
+
+/// Apply `settings`, a list of "KEY=VALUE" strings, to the running
+/// scheduler and print the resulting tunables. If empty, only print the
+/// current tunables.
+pub fn set_tunables(settings: &[String]) -> Result<()> {
+    let mut args = vec![];
+    for kv in settings.iter() {
+        match kv.split_once('=') {
+            Some((key, val)) => args.push((key.to_string(), val.to_string())),
+            None => bail!("invalid tunable {:?}, expected KEY=VALUE", kv),
+        }
+    }
+
+    let mut client = StatsClient::new().connect()?;
+    let tunables: Tunables = match args.is_empty() {
+        true => client.request("stats", vec![("target".into(), "tunables".into())])?,
+        false => client.request("tunables_set", args)?,
+    };
+    tunables.format(&mut std::io::stdout())
+}
+
+/// Print the running scheduler's view of the task `pid`.
+pub fn inspect_task(pid: u32) -> Result<()> {
+    let args = vec![
+        ("target".to_string(), "task".to_string()),
+        ("pid".to_string(), pid.to_string()),
+    ];
+
+    let mut client = StatsClient::new().connect()?;
+    let task: TaskStats = client.request("stats", args)?;
+    task.format(&mut std::io::stdout())
 }
 
 pub fn monitor(intv: Duration, shutdown: Arc<AtomicBool>) -> Result<()> {
@@ -273,3 +742,63 @@ pub fn monitor(intv: Duration, shutdown: Arc<AtomicBool>) -> Result<()> {
         },
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_args(args: &[(&str, &str)]) -> Result<TunablesUpdate> {
+        let args: BTreeMap<String, String> = args
+            .iter()
+            .map(|(key, val)| (key.to_string(), val.to_string()))
+            .collect();
+        TunablesUpdate::from_args(&args)
+    }
+
+    #[test]
+    fn test_from_args() {
+        let update = from_args(&[
+            ("slice_us_underutil", "20000"),
+            ("interval", "0.5"),
+            ("greedy_threshold", "3"),
+            ("park_under", "30"),
+            ("unpark_over", "60"),
+        ])
+        .unwrap();
+        assert_eq!(update.slice_us_underutil, Some(20000));
+        assert_eq!(update.slice_us_overutil, None);
+        assert_eq!(update.interval, Some(0.5));
+        assert_eq!(update.greedy_threshold, Some(3));
+        assert_eq!(update.park_under, Some(30.0));
+        assert_eq!(update.unpark_over, Some(60.0));
+
+        let update = from_args(&[]).unwrap();
+        assert_eq!(update.interval, None);
+        assert_eq!(update.park_under, None);
+
+        // Parking disabled, any unpark_over goes.
+        assert!(from_args(&[("park_under", "0"), ("unpark_over", "0")]).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        assert!(from_args(&[("no_such_tunable", "1")]).is_err());
+        assert!(from_args(&[("greedy_threshold", "foo")]).is_err());
+        assert!(from_args(&[("greedy_threshold", "-1")]).is_err());
+
+        assert!(from_args(&[("slice_us_underutil", "0")]).is_err());
+        assert!(from_args(&[("slice_us_overutil", &u64::MAX.to_string())]).is_err());
+
+        assert!(from_args(&[("interval", "0")]).is_err());
+        assert!(from_args(&[("tune_interval", "3600.1")]).is_err());
+        assert!(from_args(&[("tune_interval", "3600")]).is_ok());
+
+        assert!(from_args(&[("direct_greedy_under", "-0.1")]).is_err());
+        assert!(from_args(&[("kick_greedy_under", "100.1")]).is_err());
+
+        assert!(from_args(&[("park_under", "60"), ("unpark_over", "60")]).is_err());
+        assert!(from_args(&[("park_under", "70"), ("unpark_over", "60")]).is_err());
+        assert!(TunablesUpdate::check_park(50.0, 75.0).is_ok());
+        assert!(TunablesUpdate::check_park(75.0, 50.0).is_err());
+    }
+}
@@ -4,6 +4,8 @@
 // GNU General Public License version 2.
 use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
 use ::fb_procfs as procfs;
 use anyhow::anyhow;
@@ -11,9 +13,19 @@ use anyhow::bail;
 use anyhow::Result;
 use scx_utils::Cpumask;
 
+use crate::config::DomainOverride;
 use crate::sub_or_zero;
 use crate::BpfSkel;
 use crate::DomainGroup;
+use crate::MAX_CPUS;
+
+// Minimum time between parking or unparking two domains, to let the load settle
+// onto the new set of active domains.
+const PARK_HOLD: Duration = Duration::from_secs(1);
+
+// Share of the task runs on a domain's CPUs which have to be latency critical
+// for the domain to get its frequency boosted, see --perf-boost-over.
+const PERF_BOOST_LAT_CRIT_RATIO: f64 = 0.5;
 
 fn calc_util(curr: &procfs::CpuStat, prev: &procfs::CpuStat) -> Result<f64> {
     match (curr, prev) {
@@ -68,15 +80,23 @@ fn calc_util(curr: &procfs::CpuStat, prev: &procfs::CpuStat) -> Result<f64> {
 pub struct Tuner {
     pub direct_greedy_mask: Cpumask,
     pub kick_greedy_mask: Cpumask,
+    pub perf_boost_mask: Cpumask,
     pub fully_utilized: bool,
     pub slice_ns: u64,
-    underutil_slice_ns: u64,
-    overutil_slice_ns: u64,
+    pub underutil_slice_ns: u64,
+    pub overutil_slice_ns: u64,
     dom_group: Arc<DomainGroup>,
-    direct_greedy_under: f64,
-    kick_greedy_under: f64,
+    pub direct_greedy_under: f64,
+    pub kick_greedy_under: f64,
+    pub park_under: f64,
+    pub unpark_over: f64,
+    pub perf_boost_over: f64,
+    pub parked_dom_mask: u64,
+    pub dom_overrides: BTreeMap<usize, DomainOverride>,
+    last_park_change: Instant,
     proc_reader: procfs::ProcReader,
     prev_cpu_stats: BTreeMap<u32, procfs::CpuStat>,
+    prev_cpu_runs: BTreeMap<usize, (u64, u64)>,
 }
 
 impl Tuner {
@@ -86,6 +106,8 @@ impl Tuner {
         kick_greedy_under: f64,
         underutil_slice_ns: u64,
         overutil_slice_ns: u64,
+        park_under: f64,
+        unpark_over: f64,
     ) -> Result<Self> {
         let proc_reader = procfs::ProcReader::new();
         let prev_cpu_stats = proc_reader
@@ -96,11 +118,19 @@ impl Tuner {
         Ok(Self {
             direct_greedy_mask: Cpumask::new(),
             kick_greedy_mask: Cpumask::new(),
+            perf_boost_mask: Cpumask::new(),
             fully_utilized: false,
             direct_greedy_under: direct_greedy_under / 100.0,
             kick_greedy_under: kick_greedy_under / 100.0,
+            park_under: park_under / 100.0,
+            unpark_over: unpark_over / 100.0,
+            perf_boost_over: 0.0,
+            parked_dom_mask: 0,
+            dom_overrides: BTreeMap::new(),
+            last_park_change: Instant::now(),
             proc_reader,
             prev_cpu_stats,
+            prev_cpu_runs: BTreeMap::new(),
             slice_ns: underutil_slice_ns,
             underutil_slice_ns,
             overutil_slice_ns,
@@ -108,12 +138,83 @@ impl Tuner {
         })
     }
 
+    fn dom_parked(&self, dom_id: usize) -> bool {
+        self.parked_dom_mask & (1 << dom_id) != 0
+    }
+
+    fn dom_parkable(&self, dom_id: usize) -> bool {
+        self.dom_overrides
+            .get(&dom_id)
+            .and_then(|ovr| ovr.park)
+            .unwrap_or(true)
+    }
+
+    /// Park or unpark at most one domain according to the utilization of the
+    /// active domains. The utilization of the parked domains is counted too
+    /// as their tasks are moving onto the active ones. The highest numbered
+    /// active domain is parked first and the lowest numbered parked domain is
+    /// unparked first.
+    fn update_parked(&mut self, dom_util_sum: &[f64]) {
+        if self.park_under <= 0.0 {
+            self.parked_dom_mask = 0;
+            return;
+        }
+
+        // Domains which can't be parked anymore are unparked right away.
+        for dom_id in self.dom_group.doms().keys() {
+            if !self.dom_parkable(*dom_id) {
+                self.parked_dom_mask &= !(1 << *dom_id);
+            }
+        }
+
+        if self.last_park_change.elapsed() < PARK_HOLD {
+            return;
+        }
+
+        let doms = self.dom_group.doms();
+        let util_sum: f64 = dom_util_sum.iter().sum();
+        let active_cpus: usize = doms
+            .iter()
+            .filter(|(dom_id, _)| !self.dom_parked(**dom_id))
+            .map(|(_, dom)| dom.weight())
+            .sum();
+        let util = match active_cpus {
+            0 => 1.0,
+            nr => util_sum / nr as f64,
+        };
+
+        let mut parked_dom_mask = self.parked_dom_mask;
+        if util > self.unpark_over {
+            if let Some(dom_id) = doms.keys().find(|dom_id| self.dom_parked(**dom_id)) {
+                parked_dom_mask &= !(1 << *dom_id);
+            }
+        } else if util < self.park_under {
+            let victim = doms.iter().rev().find(|(dom_id, dom)| {
+                !self.dom_parked(**dom_id) && self.dom_parkable(**dom_id) && dom.weight() > 0
+            });
+            if let Some((dom_id, dom)) = victim {
+                let nr_left = active_cpus - dom.weight();
+                if nr_left > 0 && util_sum / (nr_left as f64) < self.unpark_over {
+                    parked_dom_mask |= 1 << *dom_id;
+                }
+            }
+        }
+
+        if parked_dom_mask != self.parked_dom_mask {
+            self.parked_dom_mask = parked_dom_mask;
+            self.last_park_change = Instant::now();
+        }
+    }
+
     /// Apply a step in the Tuner by:
     ///
     /// 1. Recording CPU stats from procfs
     /// 2. Calculating current per-domain and host-wide utilization
-    /// 3. Updating direct_greedy_under and kick_greedy_under cpumasks according
-    ///    to the observed utilization
+    /// 3. Parking or unparking domains if --park-under is set
+    /// 4. Updating direct_greedy_under and kick_greedy_under cpumasks according
+    ///    to the observed utilization. Parked domains are left out.
+    /// 5. Boosting the frequency of the domains saturated by latency critical
+    ///    tasks if --perf-boost-over is set.
     pub fn step(&mut self, skel: &mut BpfSkel) -> Result<()> {
         let curr_cpu_stats = self
             .proc_reader
@@ -138,9 +239,27 @@ impl Tuner {
         avg_util /= self.dom_group.weight() as f64;
         self.fully_utilized = avg_util >= 0.99999;
 
+        self.update_parked(&dom_util_sum);
+
         self.direct_greedy_mask.clear_all();
         self.kick_greedy_mask.clear_all();
+        self.perf_boost_mask.clear_all();
         for (dom_id, dom) in self.dom_group.doms().iter() {
+            // The share of the task runs in the last interval which were of
+            // latency critical tasks.
+            let (mut nr_runs, mut nr_lat_crit_runs) = (0, 0);
+            for cpu in dom.mask().iter() {
+                let pcpuc = &skel.maps.bss_data.pcpu_ctx[cpu];
+                let cur = (pcpuc.nr_runs, pcpuc.nr_lat_crit_runs);
+                let prev = self.prev_cpu_runs.insert(cpu, cur).unwrap_or(cur);
+                nr_runs += sub_or_zero(&cur.0, &prev.0);
+                nr_lat_crit_runs += sub_or_zero(&cur.1, &prev.1);
+            }
+
+            if self.dom_parked(*dom_id) {
+                continue;
+            }
+
             // Calculate the domain avg util. If there are no active CPUs,
             // it doesn't really matter. Go with 0.0 as that's less likely
             // to confuse users.
@@ -149,9 +268,16 @@ impl Tuner {
                 nr => dom_util_sum[*dom_id] / nr as f64,
             };
 
-            let enable_direct =
-                self.direct_greedy_under > 0.99999 || util < self.direct_greedy_under;
-            let enable_kick = self.kick_greedy_under > 0.99999 || util < self.kick_greedy_under;
+            let ovr = self.dom_overrides.get(dom_id);
+            let direct_greedy_under = ovr
+                .and_then(|ovr| ovr.direct_greedy_under)
+                .map_or(self.direct_greedy_under, |v| v / 100.0);
+            let kick_greedy_under = ovr
+                .and_then(|ovr| ovr.kick_greedy_under)
+                .map_or(self.kick_greedy_under, |v| v / 100.0);
+
+            let enable_direct = direct_greedy_under > 0.99999 || util < direct_greedy_under;
+            let enable_kick = kick_greedy_under > 0.99999 || util < kick_greedy_under;
 
             if enable_direct {
                 self.direct_greedy_mask |= &dom.mask();
@@ -159,23 +285,33 @@ impl Tuner {
             if enable_kick {
                 self.kick_greedy_mask |= &dom.mask();
             }
+
+            // schedutil ramps up too slowly for bursts of latency critical
+            // tasks. Ask for a higher frequency while they saturate the
+            // domain. BPF drops it once the domain gets parked.
+            if self.perf_boost_over > 0.0
+                && util >= self.perf_boost_over
+                && nr_lat_crit_runs as f64 >= nr_runs as f64 * PERF_BOOST_LAT_CRIT_RATIO
+                && nr_runs > 0
+            {
+                self.perf_boost_mask |= &dom.mask();
+            }
         }
 
         let ti = &mut skel.maps.bss_data.tune_input;
-        let write_to_bpf = |target: &mut [u64; 8], mask: &Cpumask| {
-            let raw_slice = mask.as_raw_slice();
-            let (left, _) = target.split_at_mut(raw_slice.len());
-            left.clone_from_slice(raw_slice);
-        };
-
-        write_to_bpf(&mut ti.direct_greedy_cpumask, &self.direct_greedy_mask);
-        write_to_bpf(&mut ti.kick_greedy_cpumask, &self.kick_greedy_mask);
+        self.direct_greedy_mask
+            .write_to_bpf::<MAX_CPUS, _>(&mut ti.direct_greedy_cpumask)?;
+        self.kick_greedy_mask
+            .write_to_bpf::<MAX_CPUS, _>(&mut ti.kick_greedy_cpumask)?;
+        self.perf_boost_mask
+            .write_to_bpf::<MAX_CPUS, _>(&mut ti.perf_boost_cpumask)?;
         if self.fully_utilized {
             self.slice_ns = self.overutil_slice_ns;
         } else {
             self.slice_ns = self.underutil_slice_ns;
         }
         ti.slice_ns = self.slice_ns;
+        ti.parked_dom_mask = self.parked_dom_mask;
 
         ti.genn += 1;
 